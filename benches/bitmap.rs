@@ -0,0 +1,91 @@
+//! Scanning nearly full bitmaps for empty fields
+//!
+//! Compares [Bitmap::next_free] and [Bitmap::next_free_range], which inspect
+//! a whole chunk at once, with checking one field at a time. Run with
+//! `cargo bench --bench bitmap`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use tananfs::error::Error;
+use tananfs::structs::{Bitmap, Block, Superblock};
+
+/// Capacity of filesystem whose block bitmap is scanned, 16 Mi blocks
+const CAPACITY: u64 = 64 << 30;
+const BLOCK_SIZE: u32 = 4096;
+/// Fields between isolated empty ones in the second half of bitmap
+const GAP: u64 = 4096;
+/// Length of searched runs of empty fields
+const RUN: u64 = 16;
+const ROUNDS: u32 = 10;
+
+/// First empty field starting at `after`, checking one field at a time
+fn next_free_naive(bitmap: &Bitmap<Block>, after: u64) -> Option<u64> {
+    (after..bitmap.count).find(|&index| !bitmap.get(index).unwrap())
+}
+
+/// First of `length` consecutive empty fields starting at `after`, checking
+/// one field at a time
+fn next_free_range_naive(bitmap: &Bitmap<Block>, after: u64, length: u64) -> Option<u64> {
+    let mut run = 0;
+    for index in after..bitmap.count {
+        match bitmap.get(index).unwrap() {
+            true => run = 0,
+            false => run += 1,
+        }
+        if run == length {
+            return Some(index + 1 - length);
+        }
+    }
+    None
+}
+
+/// Average duration of a call of `scan`
+fn measure<T>(scan: impl Fn() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(scan());
+    }
+    start.elapsed() / ROUNDS
+}
+
+fn report(name: &str, naive: Duration, chunked: Duration) {
+    let speedup = naive.as_secs_f64() / chunked.as_secs_f64();
+    println!("{name:<20} {naive:>12.2?} {chunked:>12.2?} {speedup:>10.1}x");
+}
+
+fn main() -> Result<(), Error> {
+    let superblock = Superblock::new(CAPACITY, BLOCK_SIZE);
+    let mut bitmap = Bitmap::<Block>::new(&superblock);
+    let count = bitmap.count;
+    for index in 0..count {
+        bitmap.set(index, true)?;
+    }
+    for index in (count / 2..count).step_by(GAP as usize) {
+        bitmap.set(index, false)?;
+    }
+    for index in count - RUN..count {
+        bitmap.set(index, false)?;
+    }
+    assert_eq!(next_free_naive(&bitmap, 0), bitmap.next_free(0));
+    assert_eq!(
+        next_free_range_naive(&bitmap, 0, RUN),
+        bitmap.next_free_range(0, RUN)
+    );
+    println!("Bitmap of {count} fields, empty every {GAP} in second half");
+    println!(
+        "{:<20} {:>12} {:>12} {:>11}",
+        "scan", "per field", "per chunk", "speedup"
+    );
+    report(
+        "next_free",
+        measure(|| next_free_naive(&bitmap, 0)),
+        measure(|| bitmap.next_free(0)),
+    );
+    report(
+        "next_free_range",
+        measure(|| next_free_range_naive(&bitmap, 0, RUN)),
+        measure(|| bitmap.next_free_range(0, RUN)),
+    );
+    Ok(())
+}
@@ -0,0 +1,161 @@
+//! Issuing control commands to a mounted filesystem through its files
+
+use std::os::fd::AsRawFd;
+use std::process::ExitCode;
+
+use tananfs::error::Error;
+use tananfs::filesystem::control::{
+    Fragmentation, TANANFS_IOC_DROP_CACHES, TANANFS_IOC_FLUSH, TANANFS_IOC_FRAGMENTATION,
+};
+use tananfs::filesystem::fuse::{FS_IOC_GETFLAGS, FS_IOC_SETFLAGS};
+use tananfs::logging;
+use tananfs::structs::{FLAG_APPEND_ONLY, FLAG_IMMUTABLE, FLAG_NOCOMPRESS};
+
+/// Letters of inode flags, as printed by `lsattr`
+const FLAG_LETTERS: [(char, u32); 3] = [
+    ('i', FLAG_IMMUTABLE),
+    ('a', FLAG_APPEND_ONLY),
+    ('m', FLAG_NOCOMPRESS),
+];
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfsctl flush <path>");
+    println!("\ttananfsctl drop-caches <path>");
+    println!("\ttananfsctl fragmentation <file>...");
+    println!("\ttananfsctl compression <on|off> <file>...");
+    println!("\ttananfsctl flags <file> [+|-|=<flags>]");
+    println!();
+    println!("Paths are files or directories on a mounted filesystem.");
+    println!("Flags are i (immutable), a (append-only) and m (not compressed).");
+}
+
+/// Issue control command `cmd` without data on file at `path`
+fn command(path: &str, cmd: u32) -> Result<(), Error> {
+    let file = std::fs::File::open(path)?;
+    if unsafe { libc::ioctl(file.as_raw_fd(), cmd as _) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn fragmentation(path: &str) -> Result<Fragmentation, Error> {
+    let file = std::fs::File::open(path)?;
+    let mut bytes = Fragmentation::default().to_bytes();
+    if unsafe { libc::ioctl(file.as_raw_fd(), TANANFS_IOC_FRAGMENTATION as _, &mut bytes) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(Fragmentation::from_bytes(bytes))
+}
+
+fn get_flags(file: &std::fs::File) -> Result<u32, Error> {
+    let mut flags: libc::c_long = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS[0] as _, &mut flags) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(flags as u32)
+}
+
+fn set_flags(file: &std::fs::File, flags: u32) -> Result<(), Error> {
+    let flags = flags as libc::c_long;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS[0] as _, &flags) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Flags given as letters
+fn parse_flags(letters: &str) -> Result<u32, Error> {
+    letters.chars().try_fold(0, |flags, letter| {
+        let (_, flag) = FLAG_LETTERS
+            .iter()
+            .find(|(known, _)| *known == letter)
+            .ok_or(Error::InvalidArgument)?;
+        Ok(flags | flag)
+    })
+}
+
+/// Flags as letters, with `-` for those which are not set
+fn format_flags(flags: u32) -> String {
+    FLAG_LETTERS
+        .iter()
+        .map(|(letter, flag)| if flags & flag != 0 { *letter } else { '-' })
+        .collect()
+}
+
+/// Print flags of file at `path`, changing them first with `change`, as
+/// `+flags`, `-flags` or `=flags`
+fn flags(path: &str, change: Option<&str>) -> Result<(), Error> {
+    let file = std::fs::File::open(path)?;
+    let mut flags = get_flags(&file)?;
+    if let Some(change) = change {
+        let mut letters = change.chars();
+        let operator = letters.next();
+        let given = parse_flags(letters.as_str())?;
+        flags = match operator {
+            Some('+') => flags | given,
+            Some('-') => flags & !given,
+            Some('=') => given,
+            _ => return Err(Error::InvalidArgument),
+        };
+        set_flags(&file, flags)?;
+    }
+    println!("{} {path}", format_flags(flags));
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let paths = args.get(1..).unwrap_or_default();
+    match (args.first().map(String::as_str), paths) {
+        (Some("flush"), [path]) => command(path, TANANFS_IOC_FLUSH),
+        (Some("drop-caches"), [path]) => command(path, TANANFS_IOC_DROP_CACHES),
+        (Some("fragmentation"), [_, ..]) => {
+            for path in paths {
+                let fragmentation = fragmentation(path)?;
+                println!(
+                    "{path}: {} blocks in {} extents",
+                    fragmentation.blocks, fragmentation.extents
+                );
+            }
+            Ok(())
+        }
+        (Some("compression"), [state, files @ ..]) if !files.is_empty() => {
+            let change = match state.as_str() {
+                "on" => "-m",
+                "off" => "+m",
+                _ => return Err(Error::InvalidArgument),
+            };
+            files.iter().try_for_each(|path| flags(path, Some(change)))
+        }
+        (Some("flags"), [path]) => flags(path, None),
+        (Some("flags"), [path, change]) => flags(path, Some(change)),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() {
+        help();
+        return ExitCode::FAILURE;
+    }
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::InvalidArgument) => {
+            help();
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
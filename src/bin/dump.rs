@@ -0,0 +1,220 @@
+//! Dumping a filesystem into a portable archive and restoring it
+//!
+//! An archive does not depend on the layout of the filesystem, so restoring
+//! it onto a device formatted with other parameters, or by a version with a
+//! different on-disk format, migrates the filesystem. Mounted filesystems
+//! are dumped through their mount point.
+
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuser::FileType;
+use log::warn;
+use tananfs::error::Error;
+use tananfs::filesystem::Filesystem;
+
+use tananfs::devices::fence::{self, Access};
+use tananfs::filesystem::archive::{child_path, ArchiveReader, ArchiveWriter, Entry};
+use tananfs::filesystem::fuse::{FS_IOC_GETFLAGS, MODE_MASK_XATTR};
+use tananfs::filetypes::Owner;
+use tananfs::logging;
+use tananfs::structs::FLAGS_SUPPORTED;
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-dump <block device|mount point> <archive>");
+    println!("\ttananfs-dump --restore <archive> <block device>");
+    println!();
+    println!("Archive - stands for standard output or input. Restoring needs");
+    println!("an empty filesystem, created with any parameters.");
+}
+
+/// Mode mask of directory at `path` on a mounted filesystem
+fn mode_mask(path: &Path) -> Result<Option<u32>, Error> {
+    let path = CString::new(path.as_os_str().as_bytes()).or(Err(Error::InvalidArgument))?;
+    let name = CString::new(MODE_MASK_XATTR).or(Err(Error::InvalidArgument))?;
+    let mut value = [0u8; 16];
+    let length = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if length < 0 {
+        return match std::io::Error::last_os_error() {
+            error if matches!(error.raw_os_error(), Some(libc::ENODATA | libc::ENOTSUP)) => {
+                Ok(None)
+            }
+            error => Err(error.into()),
+        };
+    }
+    let value = std::str::from_utf8(&value[..length as usize])?;
+    match u32::from_str_radix(value.trim(), 8) {
+        Ok(mask) => Ok(Some(mask)),
+        Err(_) => Err(Error::Corruption),
+    }
+}
+
+/// Flags of opened file on a mounted filesystem, other than unsupported ones
+fn flags(file: &std::fs::File) -> Result<u32, Error> {
+    let mut flags: libc::c_long = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS[0] as _, &mut flags) } < 0 {
+        return match std::io::Error::last_os_error() {
+            // Directory copied from another filesystem
+            error if matches!(error.raw_os_error(), Some(libc::ENOTTY | libc::ENOTSUP)) => Ok(0),
+            error => Err(error.into()),
+        };
+    }
+    Ok(flags as u32 & FLAGS_SUPPORTED)
+}
+
+/// Write directory tree of filesystem mounted at `root` into `archive`
+///
+/// Quota limits are not reachable through the mount point and are left out.
+fn dump_mounted<W: Write>(root: &Path, archive: &mut ArchiveWriter<W>) -> Result<(), Error> {
+    let mut pending = vec![String::new()];
+    while let Some(path) = pending.pop() {
+        let full_path = root.join(&path);
+        let metadata = std::fs::symlink_metadata(&full_path)?;
+        let kind = match metadata.file_type() {
+            kind if kind.is_dir() => FileType::Directory,
+            kind if kind.is_file() => FileType::RegularFile,
+            _ => {
+                warn!("Skipping {path:?} of unsupported type");
+                continue;
+            }
+        };
+        let file = std::fs::File::open(&full_path)?;
+        let time =
+            |seconds: i64, nanoseconds: i64| Duration::new(seconds as u64, nanoseconds as u32);
+        let mut entry = Entry {
+            path,
+            kind,
+            mode: metadata.mode() & 0o7777,
+            owner: Owner {
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+            },
+            flags: flags(&file)?,
+            atime: time(metadata.atime(), metadata.atime_nsec()),
+            mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+            crtime: metadata
+                .created()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default(),
+            mode_mask: None,
+            size: 0,
+        };
+        if kind == FileType::RegularFile {
+            entry.size = metadata.len();
+            archive.entry(&entry, file)?;
+            continue;
+        }
+        entry.mode_mask = mode_mask(&full_path)?;
+        archive.entry(&entry, std::io::empty())?;
+        let mut names = std::fs::read_dir(&full_path)?
+            .map(|child| {
+                Ok(child?
+                    .file_name()
+                    .to_str()
+                    .ok_or(Error::Corruption)?
+                    .to_owned())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        names.sort_by(|a, b| b.cmp(a));
+        pending.extend(names.iter().map(|name| child_path(&entry.path, name)));
+    }
+    Ok(())
+}
+
+/// Write filesystem on device or mounted at `source` into `archive`
+fn dump<W: Write>(source: &str, archive: &mut ArchiveWriter<W>) -> Result<(), Error> {
+    if Path::new(source).is_dir() {
+        return dump_mounted(Path::new(source), archive);
+    }
+    let mut device = std::fs::File::open(source)?;
+    // Mounted filesystem keeps changes cached, dump it through mount point
+    fence::acquire(&device)?;
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let mut fs = Filesystem::load(Box::new(device), block_size)?;
+    fs.read_only = true;
+    Filesystem::dump(&Arc::new(Mutex::new(fs)), archive)
+}
+
+/// Restore archive read from `archive` onto filesystem on `device_path`
+fn restore(archive: impl Read, device_path: &str) -> Result<(), Error> {
+    let mut archive = ArchiveReader::new(archive)?;
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let fs = Filesystem::load(Box::new(device), block_size)?;
+    if fs.read_only {
+        return Err(Error::ReadOnly);
+    }
+    let totals = Filesystem::restore(&Arc::new(Mutex::new(fs)), &mut archive)?;
+    eprintln!("Restored {totals}");
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    match args {
+        [flag, archive, device_path] if flag == "--restore" => match archive.as_str() {
+            "-" => restore(std::io::stdin().lock(), device_path),
+            _ => restore(
+                std::io::BufReader::new(std::fs::File::open(archive)?),
+                device_path,
+            ),
+        },
+        [source, archive] => {
+            let writer: Box<dyn Write> = match archive.as_str() {
+                "-" => Box::new(std::io::stdout().lock()),
+                _ => Box::new(std::fs::File::create(archive)?),
+            };
+            let mut archive = ArchiveWriter::new(std::io::BufWriter::new(writer))?;
+            dump(source, &mut archive)?;
+            let totals = archive.totals;
+            archive.finish()?;
+            eprintln!("Dumped {totals}");
+            Ok(())
+        }
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !matches!(args.len(), 2 | 3) {
+        help();
+        return ExitCode::FAILURE;
+    }
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! Checking and repairing consistency of an unmounted filesystem
+
+use std::process::ExitCode;
+
+use tananfs::fsck::{self, EXIT_FAILED};
+use tananfs::logging;
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-fsck [--repair] <block device>");
+    println!();
+    println!("Without --repair, problems are only reported and nothing is written.");
+}
+
+fn main() -> ExitCode {
+    logging::init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let repair = args.iter().any(|arg| arg == "--repair");
+    args.retain(|arg| arg != "--repair");
+    let [device_path] = args.as_slice() else {
+        help();
+        return ExitCode::from(EXIT_FAILED);
+    };
+    match fsck::fsck(device_path, repair) {
+        Ok(status) => ExitCode::from(status),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(EXIT_FAILED)
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Discarding all free blocks of an unmounted filesystem on its device
+
+use std::process::ExitCode;
+
+use tananfs::error::Error;
+use tananfs::filesystem::Filesystem;
+
+use tananfs::devices::fence::{self, Access};
+use tananfs::logging;
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-fstrim <block device> [minimum blocks]");
+    println!();
+    println!("Runs of free blocks shorter than the minimum, 1 by default, are kept.");
+}
+
+/// Discard runs of at least `minimum` free blocks of filesystem on `device_path`
+fn fstrim(device_path: &str, minimum: u64) -> Result<(), Error> {
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let mut fs = Filesystem::load(Box::new(device), block_size)?;
+    let discarded = fs.trim(minimum)?;
+    println!(
+        "Discarded {discarded} free blocks, {} bytes",
+        discarded * block_size as u64
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(device_path) = args.first() else {
+        help();
+        return ExitCode::FAILURE;
+    };
+    let minimum = match args.get(1).map(|minimum| minimum.parse()) {
+        Some(Ok(minimum)) => minimum,
+        Some(Err(_)) => {
+            help();
+            return ExitCode::FAILURE;
+        }
+        None => 1,
+    };
+    match fstrim(device_path, minimum) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Creating a new filesystem on a device
+
+use std::process::ExitCode;
+
+use tananfs::{logging, mkfs};
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-mkfs [--force] <block device> [parameter=value|+option]...");
+    println!();
+    println!("Device holding a filesystem is formatted only with:");
+    println!("\t--force");
+    println!();
+    println!("Parameters of the new filesystem:");
+    println!("\tblock_size=<512|1024|2048|4096>, bytes_per_inode=<bytes>,");
+    println!("\tlabel=<name>, uuid=<uuid>|random (default), reserved=<percent>");
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let force = args.iter().any(|arg| arg == "--force");
+    args.retain(|arg| arg != "--force");
+    let Some(device_path) = args.first() else {
+        help();
+        return ExitCode::FAILURE;
+    };
+    match mkfs::mkfs(device_path, &args[1..], force) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
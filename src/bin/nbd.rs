@@ -0,0 +1,63 @@
+//! Exporting raw contents of a device to other hosts over NBD
+
+use std::io::{Seek, SeekFrom};
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+use log::warn;
+use tananfs::error::Error;
+use tananfs::nbd::{NbdServer, NBD_PORT};
+
+use tananfs::devices::fence::{self, Access};
+use tananfs::logging;
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-nbd <block device> [address]");
+    println!();
+    println!("Address defaults to 127.0.0.1:{NBD_PORT}. Clients are served one at a time,");
+    println!("and the device is exported read-only while others are reading it.");
+}
+
+/// Export device at `device_path` to clients connecting to `address`
+fn serve(device_path: &str, address: &str) -> Result<(), Error> {
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    let access = fence::acquire(&device)?;
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut server = NbdServer::new(device, size);
+    if access == Access::ReadOnly {
+        warn!("Exporting device read-only");
+        server = server.with_read_only();
+    }
+    server.serve(TcpListener::bind(address)?)
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(device_path) = args.first() else {
+        help();
+        return ExitCode::FAILURE;
+    };
+    let address = match args.get(1) {
+        Some(address) => address.clone(),
+        None => format!("127.0.0.1:{NBD_PORT}"),
+    };
+    match serve(device_path, &address) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Growing an unmounted filesystem after its device was enlarged, or
+//! shrinking it before the device is reduced
+
+use std::io::{Seek, SeekFrom};
+use std::process::ExitCode;
+
+use log::{error, info};
+use tananfs::error::Error;
+use tananfs::filesystem::Filesystem;
+
+use tananfs::devices::fence::{self, Access};
+use tananfs::devices::undo::{self, UndoDevice};
+use tananfs::logging;
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-resize [--force] <block device> [size in bytes]");
+    println!();
+    println!("Without size, filesystem grows to fill the whole device.");
+    println!("Filesystem shrinks to a smaller size only with:");
+    println!("\t--force");
+}
+
+/// Grow filesystem on `device_path` to `size` bytes or to the whole device,
+/// or shrink it to `size` bytes if `force` is set
+fn resize(device_path: &str, size: Option<u64>, force: bool) -> Result<(), Error> {
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let device_size = device.seek(SeekFrom::End(0))?;
+    let size = size.unwrap_or(device_size);
+    if size > device_size {
+        return Err(Error::InvalidArgument);
+    }
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let mut fs = Filesystem::load(Box::new(device.try_clone()?), block_size)?;
+    let added = match fs.grow(size) {
+        Err(Error::InvalidArgument) => {
+            return shrink(device_path, device, fs, block_size, size, force)
+        }
+        added => added?,
+    };
+    println!(
+        "Added {added} blocks, filesystem has {} blocks of {block_size} bytes",
+        { fs.superblock.block_count }
+    );
+    Ok(())
+}
+
+/// Shrink filesystem `fs` on `device` to `size` bytes, keeping overwritten
+/// data for `tananfs undo-format`
+fn shrink(
+    device_path: &str,
+    device: std::fs::File,
+    fs: Filesystem,
+    block_size: u32,
+    size: u64,
+    force: bool,
+) -> Result<(), Error> {
+    if !force {
+        error!("Not shrinking filesystem on {device_path} without --force");
+        return Err(Error::Cancelled);
+    }
+    let uuid = fs.superblock.uuid;
+    drop(fs);
+    let undo_path = undo::side_file(device_path)?;
+    info!("Saving overwritten data to {}", undo_path.display());
+    let device = UndoDevice::create(device, &undo_path, uuid)?;
+    let mut fs = Filesystem::load(Box::new(device), block_size)?;
+    let removed = fs.shrink(size)?;
+    println!(
+        "Removed {removed} blocks, filesystem has {} blocks of {block_size} bytes",
+        { fs.superblock.block_count }
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let force = args.iter().any(|arg| arg == "--force");
+    args.retain(|arg| arg != "--force");
+    let Some(device_path) = args.first() else {
+        help();
+        return ExitCode::FAILURE;
+    };
+    let size = match args.get(1).map(|size| size.parse()) {
+        Some(Ok(size)) => Some(size),
+        Some(Err(_)) => {
+            help();
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    match resize(device_path, size, force) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Inspecting and changing parameters of an unmounted filesystem
+
+use std::process::ExitCode;
+
+use tananfs::{logging, tune};
+
+fn help() {
+    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
+    println!("{}", env!("CARGO_PKG_DESCRIPTION"));
+    println!("{}", env!("CARGO_PKG_AUTHORS"));
+    println!();
+    println!("Usage:");
+    println!("\ttananfs-tune <block device> [parameter=value|+option|-option]...");
+    println!();
+    println!("Without changes, current parameters are printed.");
+    println!();
+    println!("Tunable parameters:");
+    println!("\tlabel=<name>, uuid=<uuid>|random, reserved=<percent>,");
+    println!("\tmax_mounts=<count>, mounts=<count>, feature=+<feature>|-<feature>");
+    println!();
+    println!("Features which can be changed:");
+    println!("\tbackups, quota, journal_history, dir_index (only enabled)");
+    println!();
+    println!("Default mount options:");
+    println!("\tnoatime, strictatime, compress, -casefold");
+}
+
+fn main() -> ExitCode {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "error");
+    }
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(device_path) = args.first() else {
+        help();
+        return ExitCode::FAILURE;
+    };
+    match tune::tune(device_path, &args[1..]) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
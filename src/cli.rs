@@ -0,0 +1,251 @@
+//! Command line of the driver, split into subcommands
+//!
+//! Every [Command] names its positional arguments and the flags it accepts,
+//! so arguments are checked against it and its `--help` is printed from the
+//! same description. Flags may come anywhere among positional arguments,
+//! with a value given either as the next argument or after `=`, and those
+//! given more than once keep every value. Arguments following `--` are
+//! positional even if they start with a dash.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use log::error;
+
+use crate::Error;
+
+/// Flag accepted by a [Command]
+#[derive(Debug, Clone, Copy)]
+pub struct Flag {
+    /// Name including dashes, such as `--yes` or `-o`
+    pub name: &'static str,
+    /// Placeholder of the value taken by flag, if it takes one
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+/// Subcommand with its arguments
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub summary: &'static str,
+    /// Names of positional arguments, optional ones in brackets and the last
+    /// one followed by `...` if it may repeat
+    pub arguments: &'static [&'static str],
+    pub flags: &'static [Flag],
+}
+
+/// Arguments given to a [Command]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arguments {
+    /// Values of flags by name, empty for flags without values
+    flags: BTreeMap<&'static str, Vec<String>>,
+    positional: Vec<String>,
+    /// Whether `--help` was given
+    pub help: bool,
+}
+
+impl Command {
+    /// Number of positional arguments which must be given
+    fn required(&self) -> usize {
+        self.arguments
+            .iter()
+            .filter(|argument| !argument.starts_with('['))
+            .count()
+    }
+
+    /// Whether positional arguments beyond the named ones are accepted
+    fn variadic(&self) -> bool {
+        self.arguments
+            .last()
+            .is_some_and(|argument| argument.ends_with("..."))
+    }
+
+    /// Check `args` following the name of command against it
+    pub fn parse(&self, args: &[String]) -> Result<Arguments, Error> {
+        let mut parsed = Arguments::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.positional.extend(args.by_ref().cloned());
+                break;
+            }
+            if arg == "--help" || arg == "-h" {
+                parsed.help = true;
+                continue;
+            }
+            if !arg.starts_with('-') || arg.len() == 1 {
+                parsed.positional.push(arg.clone());
+                continue;
+            }
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let Some(flag) = self.flags.iter().find(|flag| flag.name == name) else {
+                error!("Unknown flag {name} of command {}", self.name);
+                return Err(Error::InvalidArgument);
+            };
+            let values = parsed.flags.entry(flag.name).or_default();
+            match (flag.value, inline) {
+                (None, None) => {}
+                (Some(_), Some(value)) => values.push(value),
+                (Some(_), None) => match args.next() {
+                    Some(value) => values.push(value.clone()),
+                    None => {
+                        error!("No value given for {name}");
+                        return Err(Error::InvalidArgument);
+                    }
+                },
+                (None, Some(_)) => {
+                    error!("Flag {name} takes no value");
+                    return Err(Error::InvalidArgument);
+                }
+            }
+        }
+        if parsed.help {
+            return Ok(parsed);
+        }
+        let count = parsed.positional.len();
+        if count < self.required() || (count > self.arguments.len() && !self.variadic()) {
+            error!(
+                "Command {} takes arguments {}",
+                self.name,
+                self.arguments.join(" ")
+            );
+            return Err(Error::InvalidArgument);
+        }
+        Ok(parsed)
+    }
+
+    /// Print usage of command with its flags
+    pub fn help(&self, program: &str) {
+        println!("{}", self.summary);
+        println!();
+        println!("Usage:");
+        println!(
+            "\t{program} {} [flags] {}",
+            self.name,
+            self.arguments.join(" ")
+        );
+        if self.flags.is_empty() {
+            return;
+        }
+        println!();
+        println!("Flags:");
+        let flag = |flag: &Flag| match flag.value {
+            Some(value) => format!("{} <{value}>", flag.name),
+            None => flag.name.to_string(),
+        };
+        let width = self.flags.iter().map(|f| flag(f).len()).max().unwrap_or(0);
+        for f in self.flags {
+            println!("\t{:width$}  {}", flag(f), f.help);
+        }
+    }
+}
+
+impl Arguments {
+    /// Whether flag `name` was given
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// Last value given to flag `name`
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).last().map(String::as_str)
+    }
+
+    /// Every value given to flag `name`, in order
+    pub fn values(&self, name: &str) -> &[String] {
+        self.flags.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Last value given to flag `name`, parsed
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, Error> {
+        self.value(name)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    error!("Invalid value {value} of {name}");
+                    Error::InvalidArgument
+                })
+            })
+            .transpose()
+    }
+
+    /// Positional argument at `index`
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(String::as_str)
+    }
+
+    /// Positional arguments from `index` on
+    pub fn rest(&self, index: usize) -> &[String] {
+        self.positional.get(index..).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Flag};
+    use crate::Error;
+
+    const COMMAND: Command = Command {
+        name: "tune",
+        summary: "Change parameters",
+        arguments: &["<device>", "[change]..."],
+        flags: &[
+            Flag {
+                name: "--yes",
+                value: None,
+                help: "Do not ask",
+            },
+            Flag {
+                name: "-o",
+                value: Some("options"),
+                help: "Options",
+            },
+        ],
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_flags_and_arguments() {
+        let parsed = COMMAND
+            .parse(&args(&[
+                "-o",
+                "ro",
+                "disk",
+                "--yes",
+                "label=x",
+                "-o=noatime",
+                "--",
+                "-mounts",
+            ]))
+            .unwrap();
+        assert!(parsed.flag("--yes") && !parsed.help);
+        assert_eq!(parsed.values("-o"), ["ro", "noatime"]);
+        assert_eq!(parsed.value("-o"), Some("noatime"));
+        assert_eq!(parsed.get(0), Some("disk"));
+        assert_eq!(parsed.rest(1), ["label=x", "-mounts"]);
+        assert_eq!(parsed.parse::<u64>("--size").unwrap(), None);
+        assert!(matches!(
+            parsed.parse::<u64>("-o"),
+            Err(Error::InvalidArgument)
+        ));
+
+        assert!(COMMAND.parse(&args(&["--help"])).unwrap().help);
+        for invalid in [
+            &[][..],
+            &["disk", "--force"],
+            &["disk", "-o"],
+            &["--yes=no", "disk"],
+        ] {
+            assert!(matches!(
+                COMMAND.parse(&args(invalid)),
+                Err(Error::InvalidArgument)
+            ));
+        }
+    }
+}
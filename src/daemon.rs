@@ -0,0 +1,178 @@
+//! Running the driver in the background and stopping it on signals
+//!
+//! With `--daemon`, the driver forks once the filesystem is loaded, so errors
+//! of loading still reach the terminal, and the child continues in a new
+//! session detached from it, recording its process ID in a pid file. In the
+//! background or not, stop signals are blocked in every thread and awaited by
+//! a single one, which unmounts the filesystem like `fusermount -u` would.
+//! The FUSE session then ends as if the user unmounted it, writing back and
+//! flushing everything before the driver exits. A second signal, sent while
+//! a busy filesystem is still being unmounted, writes back delayed writes,
+//! flushes it and exits at once.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+
+use log::{error, info, warn};
+
+use crate::filesystem::FuseFs;
+use crate::Error;
+
+/// Signals stopping the driver
+pub const STOP_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Set of [STOP_SIGNALS]
+fn stop_signals() -> libc::sigset_t {
+    let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe { libc::sigemptyset(&mut set) };
+    for signal in STOP_SIGNALS {
+        unsafe { libc::sigaddset(&mut set, signal) };
+    }
+    set
+}
+
+/// Block stop signals in the calling thread and every thread it spawns later,
+/// so they are only received by [wait_signal]
+pub fn block_signals() -> Result<(), Error> {
+    let set = stop_signals();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        code => Err(std::io::Error::from_raw_os_error(code).into()),
+    }
+}
+
+/// Wait for a blocked stop signal, returning its number
+pub fn wait_signal() -> Result<libc::c_int, Error> {
+    let set = stop_signals();
+    let mut signal = 0;
+    match unsafe { libc::sigwait(&set, &mut signal) } {
+        0 => Ok(signal),
+        code => Err(std::io::Error::from_raw_os_error(code).into()),
+    }
+}
+
+/// Detach from terminal into the background, writing process ID of the child
+/// to `pid_file`
+///
+/// The parent exits without running destructors, leaving the filesystem to
+/// the child. It must be called before any thread is spawned, as only the
+/// calling one continues in the child. Working directory is changed to the
+/// root, so paths used later must be absolute.
+pub fn daemonize(pid_file: Option<&Path>) -> Result<(), Error> {
+    log::logger().flush();
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error().into()),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    std::env::set_current_dir("/")?;
+    let null = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for descriptor in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(std::os::fd::AsRawFd::as_raw_fd(&null), descriptor) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    if let Some(pid_file) = pid_file {
+        std::fs::write(pid_file, format!("{}\n", std::process::id()))?;
+    }
+    info!("Running in background as process {}", std::process::id());
+    Ok(())
+}
+
+/// Unmount filesystem at `mount_path` served by this process, lazily if
+/// files on it are still open
+pub fn unmount(mount_path: &Path) -> Result<(), Error> {
+    for lazy in [false, true] {
+        for program in ["fusermount3", "fusermount"] {
+            let mut command = Command::new(program);
+            command.arg("-u").arg("-q");
+            if lazy {
+                command.arg("-z");
+            }
+            let status = command
+                .arg(mount_path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            match status {
+                Ok(status) if status.success() => return Ok(()),
+                // Program exists but failed, the other one would fail as well
+                Ok(_) => break,
+                Err(_) => continue,
+            }
+        }
+        // Without fusermount, only privileged users unmount
+        let path =
+            CString::new(mount_path.as_os_str().as_bytes()).map_err(|_| Error::InvalidArgument)?;
+        let flags = if lazy { libc::MNT_DETACH } else { 0 };
+        if unsafe { libc::umount2(path.as_ptr(), flags) } == 0 {
+            return Ok(());
+        }
+        if !lazy {
+            warn!(
+                "Filesystem at {} is busy, unmounting lazily",
+                mount_path.display()
+            );
+        }
+    }
+    error!("Failed to unmount filesystem at {}", mount_path.display());
+    Err(Error::Busy)
+}
+
+/// Thread unmounting filesystem at `mount_path` on the first stop signal,
+/// and writing back delayed writes through `mirror` of its mount, flushing
+/// it and exiting at once on the second one
+pub fn handle_signals(mut mirror: FuseFs, mount_path: PathBuf) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut stopping = false;
+        loop {
+            let signal = match wait_signal() {
+                Ok(signal) => signal,
+                Err(e) => return error!("Failed to wait for signals: {e}"),
+            };
+            if !stopping {
+                info!("Received signal {signal}, unmounting filesystem");
+                stopping = true;
+                if let Err(e) = unmount(&mount_path) {
+                    error!("Failed to unmount on signal {signal}: {e}");
+                }
+                continue;
+            }
+            warn!("Received signal {signal} again, exiting without unmounting");
+            if let Err(e) = mirror.unmount() {
+                error!("Failed to flush filesystem: {e}");
+            }
+            log::logger().flush();
+            std::process::exit(1);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_signals, wait_signal};
+
+    #[test]
+    fn wait_for_blocked_signal() {
+        // Signals raised by a thread are pending for that thread only
+        std::thread::spawn(|| {
+            block_signals().unwrap();
+            for signal in [libc::SIGTERM, libc::SIGHUP] {
+                assert_eq!(unsafe { libc::raise(signal) }, 0);
+                assert_eq!(wait_signal().unwrap(), signal);
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
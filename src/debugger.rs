@@ -1,61 +1,458 @@
-#![allow(dead_code)]
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 
-use error::Error;
-use filesystem::Filesystem;
+use fuser::FileType;
+use tananfs::error::Error;
+use tananfs::filesystem::{Filesystem, LockFilesystem, MAX_PATH_DEPTH};
+use tananfs::structs::Inode;
 
-mod error;
-mod filesystem;
-mod filetypes;
-mod structs;
+use tananfs::devices::fence::{self, Access};
+use tananfs::filetypes::{Directory, FileOperations, RegularFile};
+
+const EXIT_SUCCESS: u8 = 0;
+const EXIT_COMMAND_FAILED: u8 = 1;
+const EXIT_FAILED: u8 = 2;
 
 fn prompt(separator: &str) -> Option<Vec<String>> {
-    use std::io::Write;
     let mut line = String::new();
     print!("{separator}");
     std::io::stdout().flush().unwrap();
     match std::io::stdin().read_line(&mut line) {
-        Ok(_) => Some(line.trim().split(' ').map(str::to_string).collect()),
-        Err(_) => None,
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.split_whitespace().map(str::to_string).collect()),
     }
 }
 
-fn execute(cmd: &[String]) -> Result<(), Box<dyn std::error::Error>> {
-    let args = std::env::args().skip(1).collect::<Vec<_>>();
-    let mut dev = std::fs::File::options()
-        .read(true)
-        .write(false)
-        .open(args.get(0).unwrap_or(&"/tmp/fakefs".to_owned()))?;
-    let block_size = Filesystem::detect_existing(&mut dev)?.unwrap();
-    let mut fs = Filesystem::load(Box::new(dev), block_size)?;
+/// Commands of a script separated by newlines or semicolons, skipping empty
+/// ones and comments starting with `#`
+fn parse_script(script: &str) -> Vec<Vec<String>> {
+    script
+        .split(['\n', ';'])
+        .map(|cmd| {
+            cmd.split_whitespace()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .filter(|cmd| cmd.first().is_some_and(|word| !word.starts_with('#')))
+        .collect()
+}
+
+fn help() {
+    println!("Usage: debugger [options] [--device] <block device>");
+    println!();
+    println!("--read-only             open device without writing (default)");
+    println!("--write                 allow commands marked with --write");
+    println!("--block-size <bytes>    use block size instead of detecting it");
+    println!("-c <commands>           execute commands and exit");
+    println!("-f <script>             execute commands from script and exit");
+    println!();
+    println!("Commands given with -c are separated by semicolons, and ones in script");
+    println!("by newlines. Execution stops at the first failing command, with exit");
+    println!("status 1, or 2 if the device could not be opened.");
+    println!();
+    println!("s                       print superblock");
+    println!("b [index]               print block bitmap or block");
+    println!("i [index]               print inode bitmap or inode");
+    println!("bb <index> <0|1>        set block bitmap bit (--write)");
+    println!("ib <index> <0|1>        set inode bitmap bit (--write)");
+    println!("ip <index> <field> <n>  patch inode field (--write)");
+    println!("bw <index> <offset> <hex>  write bytes into block (--write)");
+    println!("f                       flush changes to device (--write)");
+    println!("ls <path>               list directory entries");
+    println!("stat <path>             print inode of file");
+    println!("cat <path>              print contents of file");
+    println!("tree [path]             print directory tree");
+}
+
+/// Print entries of directory, or only the name of any other file
+fn list(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<(), Error> {
+    let index = Filesystem::resolve(fs, path)?;
+    let children = match Directory::load(fs, index) {
+        Ok(directory) => directory.children.clone(),
+        Err(Error::NotDirectory) => {
+            println!("{index:>8} {path}");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    for child in children {
+        match fs.lock_fs()?.load_inode(child.inode) {
+            Ok(inode) => println!(
+                "{:>8} {:>10} {:>12} {}",
+                child.inode,
+                format!("{:?}", inode.r#type),
+                { inode.size },
+                child.name
+            ),
+            Err(e) => println!("{:>8} {e:>23} {}", child.inode, child.name),
+        }
+    }
+    Ok(())
+}
+
+/// Print directory tree under `index`, skipping directories already visited
+fn tree(
+    fs: &Arc<Mutex<Filesystem>>,
+    index: u64,
+    depth: u64,
+    visited: &mut BTreeSet<u64>,
+) -> Result<(), Error> {
+    if !visited.insert(index) || depth > MAX_PATH_DEPTH {
+        return Ok(());
+    }
+    let children = Directory::load(fs, index)?.children.clone();
+    for child in children {
+        let indent = "  ".repeat(depth as usize);
+        let kind = fs
+            .lock_fs()?
+            .load_inode(child.inode)
+            .map(|inode| inode.r#type);
+        match kind {
+            Ok(FileType::Directory) => {
+                println!("{indent}{}/", child.name);
+                if let Err(e) = tree(fs, child.inode, depth + 1, visited) {
+                    println!("{indent}  ({e})");
+                }
+            }
+            Ok(_) => println!("{indent}{}", child.name),
+            Err(e) => println!("{indent}{} ({e})", child.name),
+        }
+    }
+    Ok(())
+}
+
+/// Bytes written as pairs of hexadecimal digits
+fn parse_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(Error::InvalidArgument);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| Error::InvalidArgument))
+        .collect()
+}
+
+/// Set numeric `field` of `inode`, with `metadata.N` for its metadata slots
+fn patch(inode: &mut Inode, field: &str, value: &str) -> Result<(), Error> {
+    let value: u64 = value.parse().map_err(|_| Error::InvalidArgument)?;
+    let narrow = |value: u64| u32::try_from(value).map_err(|_| Error::InvalidArgument);
+    match field {
+        "mode" => inode.mode = u16::try_from(value).map_err(|_| Error::InvalidArgument)?,
+        "size" => inode.size = value,
+        "uid" => inode.uid = narrow(value)?,
+        "gid" => inode.gid = narrow(value)?,
+        "atime" => inode.atime = value,
+        "ctime" => inode.ctime = value,
+        "mtime" => inode.mtime = value,
+        "dtime" => inode.dtime = value,
+        "crtime" => inode.crtime = value,
+        "block_count" => inode.block_count = value,
+        "first_block" => inode.first_block = value,
+        "last_block" => inode.last_block = value,
+        "flags" => inode.flags = narrow(value)?,
+        _ => {
+            let slot = field
+                .strip_prefix("metadata.")
+                .and_then(|slot| slot.parse::<usize>().ok())
+                .ok_or(Error::NotFound)?;
+            let mut metadata = inode.metadata;
+            *metadata.get_mut(slot).ok_or(Error::OutOfBounds)? = value;
+            inode.metadata = metadata;
+        }
+    }
+    Ok(())
+}
+
+/// Set bit of block or inode bitmap, keeping free counters in agreement
+fn set_bit(fs: &mut Filesystem, blocks: bool, index: u64, value: &str) -> Result<(), Error> {
+    let value = match value {
+        "0" => false,
+        "1" => true,
+        _ => return Err(Error::InvalidArgument),
+    };
+    let current = match blocks {
+        true => fs.blocks.get(index)?,
+        false => fs.inodes.get(index)?,
+    };
+    if current == value {
+        return Ok(());
+    }
+    let superblock = &mut fs.superblock;
+    match (blocks, value) {
+        (true, true) => superblock.blocks_free -= 1,
+        (true, false) => superblock.blocks_free += 1,
+        (false, true) => superblock.inodes_free -= 1,
+        (false, false) => superblock.inodes_free += 1,
+    }
+    match blocks {
+        true => fs.blocks.set(index, value),
+        false => fs.inodes.set(index, value),
+    }
+}
+
+fn execute(fs: &Arc<Mutex<Filesystem>>, cmd: &[String]) -> Result<(), Box<dyn std::error::Error>> {
     if cmd.is_empty() {
         return Ok(());
     }
+    let argument = |position: usize| cmd.get(position).ok_or(Error::InvalidArgument);
+    match cmd[0].as_str() {
+        "ls" => list(fs, argument(1)?)?,
+        "stat" => {
+            let index = Filesystem::resolve(fs, argument(1)?)?;
+            println!["{}", fs.lock_fs()?.load_inode(index)?];
+        }
+        "cat" => {
+            let mut file = RegularFile::load(fs, Filesystem::resolve(fs, argument(1)?)?)?;
+            let size = file.inode.size;
+            std::io::stdout().write_all(&file.read(0, size)?)?;
+            println!();
+        }
+        "tree" => {
+            let path = cmd.get(1).map_or("/", String::as_str);
+            println!("{path}");
+            tree(fs, Filesystem::resolve(fs, path)?, 1, &mut BTreeSet::new())?;
+        }
+        _ => execute_raw(&mut *fs.lock_fs()?, cmd)?,
+    }
+    Ok(())
+}
+
+fn execute_raw(fs: &mut Filesystem, cmd: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let writes = ["bb", "ib", "ip", "bw", "f"];
+    if fs.read_only && writes.contains(&cmd[0].as_str()) {
+        return Err(Error::ReadOnly.into());
+    }
+    let argument = |position: usize| cmd.get(position).ok_or(Error::InvalidArgument);
     match cmd[0].as_str() {
         "s" => println!["{}", fs.superblock],
         "b" => {
             if cmd.len() == 2 {
-                println!["{}", fs.load_block(cmd[1].parse()?, false)?];
+                println!["{}", fs.load_block_unverified(cmd[1].parse()?)?];
             } else {
                 println!["{}", fs.blocks]
             }
         }
         "i" => {
             if cmd.len() == 2 {
-                println!["{}", fs.load_inode(cmd[1].parse()?)?];
+                println!["{}", fs.load_inode_unverified(cmd[1].parse()?)?];
             } else {
                 println!["{}", fs.inodes]
             }
         }
-        _ => {}
+        "bb" => set_bit(fs, true, argument(1)?.parse()?, argument(2)?)?,
+        "ib" => set_bit(fs, false, argument(1)?.parse()?, argument(2)?)?,
+        "ip" => {
+            let mut inode = fs.load_inode_unverified(argument(1)?.parse()?)?;
+            patch(&mut inode, argument(2)?, argument(3)?)?;
+            fs.flush_inode(&inode)?;
+        }
+        "bw" => {
+            let mut block = fs.load_block_unverified(argument(1)?.parse()?)?;
+            let offset: usize = argument(2)?.parse()?;
+            let bytes = parse_hex(argument(3)?)?;
+            block
+                .data
+                .get_mut(offset..offset + bytes.len())
+                .ok_or(Error::OutOfBounds)?
+                .copy_from_slice(&bytes);
+            fs.flush_block(&block)?;
+        }
+        "f" => fs.force_flush()?,
+        "h" | "help" => help(),
+        _ => {
+            help();
+            return Err(Error::InvalidArgument.into());
+        }
     }
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    loop {
-        let cmd = prompt(">> ").unwrap_or_default();
-        if let Err(e) = execute(&cmd) {
+/// Parsed command line arguments
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Arguments {
+    device: Option<String>,
+    block_size: Option<u32>,
+    write: bool,
+    commands: Option<String>,
+    script: Option<String>,
+    help: bool,
+}
+
+impl Arguments {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(Error::InvalidArgument);
+            match arg.as_str() {
+                "--write" => parsed.write = true,
+                "--read-only" => parsed.write = false,
+                "-h" | "--help" => parsed.help = true,
+                "--block-size" => {
+                    let block_size: u32 = value()?.parse().or(Err(Error::InvalidArgument))?;
+                    if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+                        return Err(Error::InvalidArgument);
+                    }
+                    parsed.block_size = Some(block_size);
+                }
+                "-c" => parsed.commands = Some(value()?),
+                "-f" => parsed.script = Some(value()?),
+                "--device" => parsed.device = Some(value()?),
+                _ if arg.starts_with('-') => return Err(Error::InvalidArgument),
+                _ if parsed.device.is_none() => parsed.device = Some(arg),
+                _ => return Err(Error::InvalidArgument),
+            }
+        }
+        if parsed.commands.is_some() && parsed.script.is_some() {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(parsed)
+    }
+}
+
+/// Execute commands of `script`, returning whether all of them succeeded
+fn run_script(fs: &Arc<Mutex<Filesystem>>, script: &str) -> bool {
+    for cmd in parse_script(script) {
+        if cmd[0] == "q" {
+            break;
+        }
+        if let Err(e) = execute(fs, &cmd) {
+            eprintln!("{}: {e}", cmd.join(" "));
+            return false;
+        }
+    }
+    true
+}
+
+fn debugger(args: Arguments) -> Result<u8, Box<dyn std::error::Error>> {
+    let script = match (args.commands, args.script) {
+        (Some(commands), _) => Some(commands),
+        (_, Some(path)) => Some(std::fs::read_to_string(path)?),
+        (None, None) => None,
+    };
+    let device = args.device.ok_or(Error::InvalidArgument)?;
+    let mut dev = std::fs::File::options()
+        .read(true)
+        .write(args.write)
+        .open(device)?;
+    if args.write && fence::acquire(&dev)? != Access::ReadWrite {
+        return Err(Error::Busy.into());
+    }
+    let block_size = match args.block_size {
+        Some(block_size) => block_size,
+        None => Filesystem::detect_existing(&mut dev)?.ok_or(Error::NotFound)?,
+    };
+    let mut fs = Filesystem::load(Box::new(dev), block_size)?;
+    fs.read_only |= !args.write;
+    let fs = Arc::new(Mutex::new(fs));
+    let status = match script {
+        Some(script) if !run_script(&fs, &script) => EXIT_COMMAND_FAILED,
+        Some(_) => EXIT_SUCCESS,
+        None => {
+            while let Some(cmd) = prompt(">> ") {
+                if cmd.first().is_some_and(|cmd| cmd == "q") {
+                    break;
+                }
+                if let Err(e) = execute(&fs, &cmd) {
+                    eprintln!("{e}");
+                }
+            }
+            EXIT_SUCCESS
+        }
+    };
+    // Changes of commands preceding a failed one are kept
+    fs.lock_fs()?.force_flush()?;
+    Ok(status)
+}
+
+fn main() -> ExitCode {
+    let args = match Arguments::parse(std::env::args().skip(1)) {
+        Ok(args) if !args.help && args.device.is_some() => args,
+        Ok(args) => {
+            help();
+            return ExitCode::from(if args.help { EXIT_SUCCESS } else { EXIT_FAILED });
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
+    match debugger(args) {
+        Ok(status) => ExitCode::from(status),
+        Err(e) => {
             eprintln!("{e}");
+            ExitCode::from(EXIT_FAILED)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_hex, parse_script, patch, Arguments};
+    use tananfs::structs::Inode;
+    use tananfs::Error;
+
+    #[test]
+    fn hex_and_inode_patches() {
+        assert_eq!(parse_hex("00ff7A").unwrap(), vec![0x00, 0xff, 0x7a]);
+        assert!(matches!(parse_hex("abc"), Err(Error::InvalidArgument)));
+        assert!(matches!(parse_hex("zz"), Err(Error::InvalidArgument)));
+
+        let mut inode = Inode::default();
+        patch(&mut inode, "size", "4096").unwrap();
+        patch(&mut inode, "metadata.2", "7").unwrap();
+        assert_eq!(({ inode.size }, { inode.metadata[2] }), (4096, 7));
+        assert!(matches!(
+            patch(&mut inode, "uid", "4294967296"),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            patch(&mut inode, "metadata.99", "1"),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(
+            patch(&mut inode, "colour", "1"),
+            Err(Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn script_commands() {
+        let script = "s; i 1\n# comment\n\n  ls  /a ;; ip 5 size 7 ";
+        assert_eq!(
+            parse_script(script),
+            vec![
+                vec!["s"],
+                vec!["i", "1"],
+                vec!["ls", "/a"],
+                vec!["ip", "5", "size", "7"],
+            ]
+        );
+        assert!(parse_script(" ; \n").is_empty());
+    }
+
+    #[test]
+    fn arguments() {
+        let parse = |args: &[&str]| Arguments::parse(args.iter().map(|arg| arg.to_string()));
+        let args = parse(&["--write", "--block-size", "1024", "-c", "s", "/dev/x"]).unwrap();
+        assert_eq!(
+            args,
+            Arguments {
+                device: Some("/dev/x".into()),
+                block_size: Some(1024),
+                write: true,
+                commands: Some("s".into()),
+                ..Default::default()
+            }
+        );
+        let args = parse(&["--write", "--read-only", "--device", "/dev/x"]).unwrap();
+        assert!(!args.write);
+        assert_eq!(args.device.as_deref(), Some("/dev/x"));
+        assert!(parse(&["--block-size", "1000", "/dev/x"]).is_err());
+        assert!(parse(&["--device"]).is_err());
+        assert!(parse(&["-c", "s", "-f", "script", "/dev/x"]).is_err());
+        assert!(parse(&["/dev/x", "/dev/y"]).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Block device spanning several devices concatenated one after another
+//!
+//! A [ConcatDevice] maps a linear range of bytes onto its members in order,
+//! so a filesystem outgrows any single one of them. Transfers crossing the
+//! end of a member are split, each part going to the member holding it. The
+//! superblock in the first member records sizes of all members, so mounting
+//! them in a different order or with one missing is refused.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::filesystem::BlockDevice;
+
+#[derive(Debug)]
+pub struct ConcatDevice<D: BlockDevice> {
+    /// Members in order, with offsets where they start
+    members: Vec<(u64, D)>,
+    size: u64,
+    position: u64,
+}
+
+impl<D: BlockDevice> ConcatDevice<D> {
+    /// Device spanning `members` in order
+    pub fn new(members: Vec<D>) -> std::io::Result<Self> {
+        let mut size = 0;
+        let mut spanned = Vec::with_capacity(members.len());
+        for mut member in members {
+            let start = size;
+            size += member.seek(SeekFrom::End(0))?;
+            spanned.push((start, member));
+        }
+        Ok(Self {
+            members: spanned,
+            size,
+            position: 0,
+        })
+    }
+
+    /// Sizes of members in order
+    pub fn sizes(&self) -> Vec<u64> {
+        let ends = self.members.iter().skip(1).map(|(start, _)| *start);
+        self.members
+            .iter()
+            .zip(ends.chain(std::iter::once(self.size)))
+            .map(|((start, _), end)| end - start)
+            .collect()
+    }
+
+    /// Member holding current position, positioned at it, and bytes left in it
+    fn member(&mut self) -> std::io::Result<Option<(&mut D, usize)>> {
+        if self.position >= self.size {
+            return Ok(None);
+        }
+        let index = self
+            .members
+            .partition_point(|(start, _)| *start <= self.position)
+            - 1;
+        let end = self
+            .members
+            .get(index + 1)
+            .map_or(self.size, |(start, _)| *start);
+        let (start, member) = &mut self.members[index];
+        member.seek(SeekFrom::Start(self.position - *start))?;
+        let left = (end - self.position).min(usize::MAX as u64) as usize;
+        Ok(Some((member, left)))
+    }
+}
+
+impl<D: BlockDevice> Read for ConcatDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((member, left)) = self.member()? else {
+            return Ok(0);
+        };
+        let length = buf.len().min(left);
+        let read = member.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<D: BlockDevice> Write for ConcatDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some((member, left)) = self.member()? else {
+            return match buf.is_empty() {
+                true => Ok(0),
+                false => Err(std::io::ErrorKind::WriteZero.into()),
+            };
+        };
+        let length = buf.len().min(left);
+        let written = member.write(&buf[..length])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for (_, member) in self.members.iter_mut() {
+            member.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Seek for ConcatDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl<D: BlockDevice + 'static> BlockDevice for ConcatDevice<D> {
+    /// Discard parts of the range on members holding them
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        let end = offset.saturating_add(length).min(self.size);
+        let sizes = self.sizes();
+        for ((start, member), size) in self.members.iter_mut().zip(sizes) {
+            let (from, to) = (offset.max(*start), end.min(*start + size));
+            if from < to {
+                member.discard(from - *start, to - from)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn barrier(&mut self) -> std::io::Result<()> {
+        for (_, member) in self.members.iter_mut() {
+            member.barrier()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        for (_, member) in self.members.iter_mut() {
+            member.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::ConcatDevice;
+    use crate::filesystem::{BlockDevice, Filesystem};
+    use crate::Error;
+
+    #[test]
+    fn span_members() {
+        let members = vec![
+            Cursor::new(vec![1u8; 100]),
+            Cursor::new(vec![2u8; 50]),
+            Cursor::new(vec![3u8; 100]),
+        ];
+        let mut device = ConcatDevice::new(members).unwrap();
+        assert_eq!(device.sizes(), [100, 50, 100]);
+        assert_eq!(device.seek(SeekFrom::End(0)).unwrap(), 250);
+        let mut buffer = [0u8; 60];
+        device.seek(SeekFrom::Start(95)).unwrap();
+        device.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer[..5], [1u8; 5]);
+        assert_eq!(buffer[5..55], [2u8; 50]);
+        assert_eq!(buffer[55..], [3u8; 5]);
+
+        device.seek(SeekFrom::Start(140)).unwrap();
+        device.write_all(&[4u8; 20]).unwrap();
+        assert!(device.write_all(&[4u8; 100]).is_err());
+        let members: Vec<_> = device.members.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(members[1].get_ref()[40..], [4u8; 10]);
+        assert_eq!(members[2].get_ref()[..10], [4u8; 10]);
+        assert_eq!(members[2].get_ref()[10..], [4u8; 90]);
+    }
+
+    #[test]
+    fn validate_members() {
+        let members = vec![
+            Cursor::new(vec![0u8; 1_000_000]),
+            Cursor::new(vec![0u8; 500_000]),
+        ];
+        let device = ConcatDevice::new(members).unwrap();
+        let sizes = device.sizes();
+        let fs = Filesystem::new(Box::new(device), 1_500_000, 512)
+            .with_members(&sizes)
+            .unwrap();
+        assert!(fs.check_members(&sizes).is_ok());
+        assert!(fs.check_members(&[500_000, 1_000_000]).is_err());
+        assert!(fs.check_members(&[1_000_000]).is_err());
+
+        let fs = Filesystem::new(Box::new(Cursor::new(vec![0u8; 1_000_000])), 1_000_000, 512);
+        assert!(fs.check_members(&[1_000_000]).is_ok());
+        assert!(fs.check_members(&sizes).is_err());
+        assert!(matches!(
+            fs.with_members(&[1; 9]),
+            Err(Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn discard_across_members() {
+        let members = vec![Cursor::new(vec![1u8; 100]), Cursor::new(vec![2u8; 100])];
+        let mut device = ConcatDevice::new(members).unwrap();
+        device.discard(90, 20).unwrap();
+        device.discard(190, 50).unwrap();
+        let members: Vec<_> = device.members.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(members[0].get_ref()[..90], [1u8; 90]);
+        assert_eq!(members[0].get_ref()[90..], [0u8; 10]);
+        assert_eq!(members[1].get_ref()[..10], [0u8; 10]);
+        assert_eq!(members[1].get_ref()[10..90], [2u8; 80]);
+        assert_eq!(members[1].get_ref()[90..], [0u8; 10]);
+    }
+}
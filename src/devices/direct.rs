@@ -0,0 +1,294 @@
+//! Block device accessed with `O_DIRECT`, bypassing the page cache of the host
+//!
+//! The filesystem keeps its own cache of inodes and blocks, so letting the
+//! host cache the device as well holds everything in memory twice. With
+//! `O_DIRECT` every transfer must start at, and span whole, logical sectors
+//! of the device from a buffer aligned to them. A [DirectDevice] widens each
+//! read and write to the sectors it touches, moving data through a single
+//! aligned buffer which is reused by all transfers. Writes covering sectors
+//! only partially read them first, so the rest of their contents is kept.
+
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+
+use crate::filesystem::BlockDevice;
+
+/// Alignment of transfers to image files, covering sectors of common devices
+pub const DIRECT_ALIGNMENT: usize = 4096;
+/// Largest transfer through the aligned buffer, longer ones are split
+pub const DIRECT_MAX_TRANSFER: usize = 1 << 20;
+
+/// Zeroed heap buffer aligned to sectors of the device
+struct AlignedBuffer {
+    data: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is exclusively owned, like a `Vec<u8>`
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(size: usize, alignment: usize) -> Self {
+        let layout = Layout::from_size_align(size, alignment).expect("valid sector alignment");
+        let data = unsafe { alloc::alloc_zeroed(layout) };
+        if data.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { data, layout }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.data, self.layout) }
+    }
+}
+
+impl std::fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AlignedBuffer({} bytes)", self.layout.size())
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectDevice {
+    file: File,
+    /// Transfers start at and span multiples of this many bytes
+    alignment: usize,
+    buffer: AlignedBuffer,
+    size: u64,
+    position: u64,
+}
+
+impl DirectDevice {
+    /// Switch opened `file` to `O_DIRECT`, keeping its locks
+    ///
+    /// Fails if the filesystem holding an image file does not support it.
+    pub fn new(file: File) -> std::io::Result<Self> {
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let alignment = match Self::logical_sector(&file)? {
+            0 => DIRECT_ALIGNMENT,
+            sector => sector,
+        };
+        Self::with_alignment(file, alignment)
+    }
+
+    /// Device transferring data of `file` in multiples of `alignment` bytes
+    fn with_alignment(file: File, alignment: usize) -> std::io::Result<Self> {
+        let size = file.metadata()?.len();
+        let size = match size {
+            0 => (&file).seek(SeekFrom::End(0))?,
+            size => size,
+        };
+        Ok(Self {
+            file,
+            alignment,
+            buffer: AlignedBuffer::new(DIRECT_MAX_TRANSFER.max(alignment), alignment),
+            size,
+            position: 0,
+        })
+    }
+
+    /// Logical sector size of a block device, zero for image files
+    fn logical_sector(file: &File) -> std::io::Result<usize> {
+        if !file.metadata()?.file_type().is_block_device() {
+            return Ok(0);
+        }
+        let mut value: libc::c_int = 0;
+        match unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut value) } {
+            0 => Ok(value as usize),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    /// Sectors touching up to `length` bytes at current position, as their
+    /// start and length, limited to the aligned buffer
+    fn sectors(&self, length: usize) -> (u64, usize) {
+        let alignment = self.alignment as u64;
+        let start = self.position - self.position % alignment;
+        let end = (self.position + length as u64)
+            .div_ceil(alignment)
+            .saturating_mul(alignment)
+            .min(start + self.buffer.layout.size() as u64);
+        (start, (end - start) as usize)
+    }
+
+    /// Fill `range` of aligned buffer with sectors starting at `position`,
+    /// leaving zeros past the end of the device
+    fn read_sectors(&mut self, position: u64, range: Range<usize>) -> std::io::Result<()> {
+        let buffer = &mut self.buffer.as_mut_slice()[range];
+        let mut read = 0;
+        while read < buffer.len() {
+            match self
+                .file
+                .read_at(&mut buffer[read..], position + read as u64)?
+            {
+                0 => break,
+                bytes => read += bytes,
+            }
+        }
+        buffer[read..].fill(0);
+        Ok(())
+    }
+}
+
+impl Read for DirectDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = buf
+            .len()
+            .min(self.size.saturating_sub(self.position) as usize);
+        if length == 0 {
+            return Ok(0);
+        }
+        let (start, sectors) = self.sectors(length);
+        self.read_sectors(start, 0..sectors)?;
+        let offset = (self.position - start) as usize;
+        let read = length.min(sectors - offset);
+        buf[..read].copy_from_slice(&self.buffer.as_mut_slice()[offset..offset + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for DirectDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = buf
+            .len()
+            .min(self.size.saturating_sub(self.position) as usize);
+        if length == 0 {
+            return match buf.is_empty() {
+                true => Ok(0),
+                false => Err(std::io::ErrorKind::WriteZero.into()),
+            };
+        }
+        let (start, sectors) = self.sectors(length);
+        let offset = (self.position - start) as usize;
+        let written = length.min(sectors - offset);
+        let alignment = self.alignment;
+        // Keep contents of sectors the write covers only partially
+        let (head, tail) = (
+            !offset.is_multiple_of(alignment),
+            !(offset + written).is_multiple_of(alignment),
+        );
+        if head {
+            self.read_sectors(start, 0..alignment)?;
+        }
+        let last = sectors - alignment;
+        if tail && (last > 0 || !head) {
+            self.read_sectors(start + last as u64, last..sectors)?;
+        }
+        let buffer = self.buffer.as_mut_slice();
+        buffer[offset..offset + written].copy_from_slice(&buf[..written]);
+        self.file.write_all_at(&buffer[..sectors], start)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for DirectDevice {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl BlockDevice for DirectDevice {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        super::discard::discard(&self.file, offset, length)
+    }
+
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.file.sync_all()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::DirectDevice;
+
+    /// Device over a temporary image file of `size` bytes filled with ones,
+    /// without `O_DIRECT` which temporary filesystems may not support
+    fn device(size: u64, alignment: usize) -> DirectDevice {
+        let file = tempfile();
+        file.set_len(size).unwrap();
+        let mut device = DirectDevice::with_alignment(file, alignment).unwrap();
+        device.write_all(&vec![1u8; size as usize]).unwrap();
+        device.seek(SeekFrom::Start(0)).unwrap();
+        device
+    }
+
+    fn tempfile() -> std::fs::File {
+        let path = std::env::temp_dir().join(format!(
+            "tananfs-direct-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        file
+    }
+
+    #[test]
+    fn unaligned_transfers() {
+        let mut device = device(4096, 512);
+        device.seek(SeekFrom::Start(500)).unwrap();
+        device.write_all(&[2u8; 600]).unwrap();
+        let mut buffer = vec![0u8; 4096];
+        device.seek(SeekFrom::Start(0)).unwrap();
+        device.read_exact(&mut buffer).unwrap();
+        assert!(buffer[..500].iter().all(|&byte| byte == 1));
+        assert!(buffer[500..1100].iter().all(|&byte| byte == 2));
+        assert!(buffer[1100..].iter().all(|&byte| byte == 1));
+        device.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(device.read(&mut buffer).unwrap(), 10);
+        assert!(device.write_all(&[3u8; 20]).is_err());
+    }
+
+    #[test]
+    fn transfers_longer_than_buffer() {
+        let size = (super::DIRECT_MAX_TRANSFER * 2 + 1000) as u64;
+        let mut device = device(size, 4096);
+        let data: Vec<u8> = (0..size - 100).map(|byte| byte as u8).collect();
+        device.seek(SeekFrom::Start(50)).unwrap();
+        device.write_all(&data).unwrap();
+        let mut buffer = vec![0u8; size as usize];
+        device.seek(SeekFrom::Start(0)).unwrap();
+        device.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer[..50], &[1u8; 50]);
+        assert_eq!(&buffer[50..size as usize - 50], &data[..]);
+        assert_eq!(&buffer[size as usize - 50..], &[1u8; 50]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Telling the backing device which of its ranges no longer hold data
+//!
+//! Flash memory erases whole pages before writing them again, so a drive
+//! which knows a range is unused erases it in the background and spreads
+//! wear over more cells. Block devices are told with `BLKDISCARD`, while
+//! image files get holes punched into them, giving the space back to the
+//! filesystem holding the image. Either way the range reads as zeros, or as
+//! anything at all on drives which do not guarantee zeros after a discard.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+
+/// `_IO(0x12, 119)` of `linux/fs.h`, missing from [libc]
+const BLKDISCARD: libc::Ioctl = 0x1277;
+
+/// Discard `length` bytes of `device` starting at `offset`
+///
+/// Devices and filesystems which cannot discard fail with `EOPNOTSUPP`.
+pub fn discard(device: &File, offset: u64, length: u64) -> std::io::Result<()> {
+    if length == 0 {
+        return Ok(());
+    }
+    let result = match device.metadata()?.file_type().is_block_device() {
+        true => {
+            let range: [u64; 2] = [offset, length];
+            unsafe { libc::ioctl(device.as_raw_fd(), BLKDISCARD, &range) }
+        }
+        false => unsafe {
+            libc::fallocate(
+                device.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                length as libc::off_t,
+            )
+        },
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    use super::discard;
+
+    #[test]
+    fn punch_hole() {
+        let path = std::env::temp_dir().join(format!("tananfs-discard-{}", std::process::id()));
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[7u8; 1 << 20]).unwrap();
+        file.sync_all().unwrap();
+        let allocated = file.metadata().unwrap().blocks();
+        match discard(&file, 1 << 18, 1 << 19) {
+            Ok(()) => {
+                assert_eq!(file.metadata().unwrap().len(), 1 << 20);
+                assert!(file.metadata().unwrap().blocks() < allocated);
+                let mut contents = Vec::new();
+                file.seek(SeekFrom::Start(0)).unwrap();
+                file.read_to_end(&mut contents).unwrap();
+                assert!(contents[..1 << 18].iter().all(|&byte| byte == 7));
+                assert!(contents[1 << 18..3 << 18].iter().all(|&byte| byte == 0));
+                assert!(contents[3 << 18..].iter().all(|&byte| byte == 7));
+            }
+            // Temporary directory may be on a filesystem without holes
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+//! Block device injecting failures into transfers, for robustness testing
+//!
+//! A [FaultyDevice] passes transfers to the underlying device, except those
+//! matched by one of its [Fault]s, which fail instead. A fault covers a range
+//! of offsets of the device and happens with some probability, so error paths
+//! of flushing and the journal are exercised either at a chosen spot or
+//! scattered over the whole device. Faults are given as a comma-separated
+//! list of `kind[@start[-end]][:probability]`, for example
+//! `torn-write@1M-2M:0.01,flush-error:0.1`, with offsets in bytes or with
+//! units and the probability defaulting to certainty.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use super::mem::parse_size;
+use crate::filesystem::BlockDevice;
+use crate::stress::Random;
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Read fails without reading anything
+    ReadError,
+    /// Read returns only a part of requested bytes
+    ShortRead,
+    /// Write fails without writing anything
+    WriteError,
+    /// Write stores only a part of its bytes before failing, as on power loss
+    TornWrite,
+    /// Flush fails, leaving written data where it is
+    FlushError,
+}
+
+impl FaultKind {
+    const NAMES: [(FaultKind, &'static str); 5] = [
+        (FaultKind::ReadError, "read-error"),
+        (FaultKind::ShortRead, "short-read"),
+        (FaultKind::WriteError, "write-error"),
+        (FaultKind::TornWrite, "torn-write"),
+        (FaultKind::FlushError, "flush-error"),
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// Transfers touching these offsets fail, flushes ignore it
+    pub range: Range<u64>,
+    /// Chance of a matching transfer failing, from zero to one
+    pub probability: f64,
+}
+
+impl FromStr for Fault {
+    type Err = Error;
+
+    /// Fault as `kind[@start[-end]][:probability]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, probability) = match s.split_once(':') {
+            Some((s, probability)) => (
+                s,
+                probability
+                    .parse()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or(Error::InvalidArgument)?,
+            ),
+            None => (s, 1.0),
+        };
+        let (name, range) = match s.split_once('@') {
+            Some((name, range)) => {
+                let range = match range.split_once('-') {
+                    Some((start, end)) => parse_size(start)?..parse_size(end)?,
+                    None => parse_size(range)?..parse_size(range)? + 1,
+                };
+                if range.is_empty() {
+                    return Err(Error::InvalidArgument);
+                }
+                (name, range)
+            }
+            None => (s, 0..u64::MAX),
+        };
+        let (kind, _) = FaultKind::NAMES
+            .iter()
+            .find(|(_, known)| *known == name)
+            .ok_or(Error::InvalidArgument)?;
+        Ok(Self {
+            kind: *kind,
+            range,
+            probability,
+        })
+    }
+}
+
+/// Faults listed in `spec`, separated by commas
+pub fn parse_faults(spec: &str) -> Result<Vec<Fault>, Error> {
+    spec.split(',')
+        .filter(|fault| !fault.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct FaultyDevice<D: BlockDevice> {
+    device: D,
+    faults: Vec<Fault>,
+    random: Random,
+    /// Number of faults which happened
+    injected: u64,
+    position: u64,
+}
+
+impl<D: BlockDevice> FaultyDevice<D> {
+    /// Device failing transfers of `device` matched by `faults`, with a
+    /// random seed which is logged so a run can be repeated
+    pub fn new(device: D, faults: Vec<Fault>) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        info!("Fault injection seed {seed}");
+        Self {
+            device,
+            faults,
+            random: Random::new(seed),
+            injected: 0,
+            position: 0,
+        }
+    }
+
+    /// Decide which transfers fail starting from `seed`
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = Random::new(seed);
+        self
+    }
+
+    /// Number of faults which happened so far
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    /// First of faults of `kinds` hitting a transfer of `length` bytes at
+    /// current position
+    fn fault(&mut self, kinds: &[FaultKind], length: usize) -> Option<FaultKind> {
+        let transfer = self.position..self.position.saturating_add(length.max(1) as u64);
+        for index in 0..self.faults.len() {
+            let fault = &self.faults[index];
+            if !kinds.contains(&fault.kind)
+                || (fault.kind != FaultKind::FlushError
+                    && (fault.range.start >= transfer.end || transfer.start >= fault.range.end))
+            {
+                continue;
+            }
+            let (kind, probability) = (fault.kind, fault.probability);
+            if (self.random.below(1 << 24) as f64) < probability * (1 << 24) as f64 {
+                self.injected += 1;
+                warn!("Injecting {kind:?} at {}", self.position);
+                return Some(kind);
+            }
+        }
+        None
+    }
+}
+
+fn injected() -> std::io::Error {
+    std::io::Error::from_raw_os_error(libc::EIO)
+}
+
+impl<D: BlockDevice> Read for FaultyDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let kinds = [FaultKind::ReadError, FaultKind::ShortRead];
+        let length = match self.fault(&kinds, buf.len()) {
+            Some(FaultKind::ReadError) => return Err(injected()),
+            Some(_) if buf.len() > 1 => 1 + self.random.below(buf.len() as u64 - 1) as usize,
+            _ => buf.len(),
+        };
+        self.device.seek(SeekFrom::Start(self.position))?;
+        let read = self.device.read(&mut buf[..length])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<D: BlockDevice> Write for FaultyDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let kinds = [FaultKind::WriteError, FaultKind::TornWrite];
+        let fault = self.fault(&kinds, buf.len());
+        if fault == Some(FaultKind::WriteError) {
+            return Err(injected());
+        }
+        self.device.seek(SeekFrom::Start(self.position))?;
+        if fault == Some(FaultKind::TornWrite) {
+            let torn = self.random.below(buf.len() as u64) as usize;
+            self.device.write_all(&buf[..torn])?;
+            return Err(injected());
+        }
+        let written = self.device.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.fault(&[FaultKind::FlushError], 0) {
+            Some(_) => Err(injected()),
+            None => self.device.flush(),
+        }
+    }
+}
+
+impl<D: BlockDevice> Seek for FaultyDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.device.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+impl<D: BlockDevice + 'static> BlockDevice for FaultyDevice<D> {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        self.device.discard(offset, length)
+    }
+
+    fn copies(&self) -> usize {
+        self.device.copies()
+    }
+
+    fn select_copy(&mut self, copy: Option<usize>) {
+        self.device.select_copy(copy)
+    }
+
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.device.barrier()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.device.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::{parse_faults, Fault, FaultKind, FaultyDevice};
+
+    #[test]
+    fn parse_spec() {
+        let faults = parse_faults("torn-write@1K-2K:0.5,flush-error,read-error@4096").unwrap();
+        assert_eq!(
+            faults,
+            [
+                Fault {
+                    kind: FaultKind::TornWrite,
+                    range: 1024..2048,
+                    probability: 0.5
+                },
+                Fault {
+                    kind: FaultKind::FlushError,
+                    range: 0..u64::MAX,
+                    probability: 1.0
+                },
+                Fault {
+                    kind: FaultKind::ReadError,
+                    range: 4096..4097,
+                    probability: 1.0
+                },
+            ]
+        );
+        assert!(parse_faults("bit-flip").is_err());
+        assert!(parse_faults("read-error:2").is_err());
+        assert!(parse_faults("read-error@2K-1K").is_err());
+    }
+
+    #[test]
+    fn inject_faults() {
+        let faults = parse_faults("read-error@100,torn-write@200-300,short-read@500-600").unwrap();
+        let device = Cursor::new(vec![0u8; 1000]);
+        let mut device = FaultyDevice::new(device, faults).with_seed(7);
+        let mut buffer = [0u8; 50];
+        device.seek(SeekFrom::Start(60)).unwrap();
+        assert!(device.read_exact(&mut buffer).is_err());
+        device.seek(SeekFrom::Start(0)).unwrap();
+        device.read_exact(&mut buffer).unwrap();
+
+        // Torn writes store a prefix of their data
+        device.seek(SeekFrom::Start(190)).unwrap();
+        assert!(device.write_all(&[1u8; 20]).is_err());
+        device.seek(SeekFrom::Start(400)).unwrap();
+        device.write_all(&[1u8; 20]).unwrap();
+        let contents = device.device.get_ref();
+        let torn = contents[190..210].iter().filter(|&&byte| byte == 1).count();
+        assert!(torn < 20 && contents[190..190 + torn].iter().all(|&byte| byte == 1));
+        assert_eq!(contents[400..420], [1u8; 20]);
+
+        // Short reads are completed by reading again
+        device.seek(SeekFrom::Start(500)).unwrap();
+        let short = device.read(&mut buffer).unwrap();
+        assert!((1..50).contains(&short));
+        device.seek(SeekFrom::Start(500)).unwrap();
+        device.read_exact(&mut buffer).unwrap();
+        assert!(device.flush().is_ok());
+        assert!(device.injected() > 3);
+    }
+
+    #[test]
+    fn probability() {
+        let faults = parse_faults("write-error:0.25").unwrap();
+        let mut device = FaultyDevice::new(Cursor::new(vec![0u8; 100]), faults).with_seed(3);
+        let failed = (0..1000)
+            .filter(|_| device.seek(SeekFrom::Start(0)).is_ok() && device.write(&[1]).is_err())
+            .count();
+        assert!((150..350).contains(&failed));
+        assert_eq!(device.injected(), failed as u64);
+    }
+}
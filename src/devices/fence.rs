@@ -0,0 +1,79 @@
+//! Advisory locks fencing off other instances writing to the same device
+//!
+//! Two drivers caching metadata of one image overwrite each other's bitmaps
+//! and inodes without noticing. A writer holds an exclusive `flock` on the
+//! device, and a reader a shared one, so a second instance learns about the
+//! first before touching the device. Tools following the udev convention of
+//! locking block devices (such as `mkfs`) are fenced off the same way.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+
+use log::warn;
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// No other instance uses the device
+    ReadWrite,
+    /// Other instances only read from the device
+    ReadOnly,
+}
+
+/// Lock `device` for writing, or for reading if others hold it shared
+///
+/// Fails with [Error::Busy] if another instance writes to the device. The
+/// lock is held until every handle of the opened device is closed.
+pub fn acquire(device: &File) -> Result<Access, Error> {
+    if try_lock(device, libc::LOCK_EX)? {
+        return Ok(Access::ReadWrite);
+    }
+    if try_lock(device, libc::LOCK_SH)? {
+        warn!("Device is in use by another reader, falling back to read-only");
+        return Ok(Access::ReadOnly);
+    }
+    Err(Error::Busy)
+}
+
+/// Take lock of `operation` without blocking, returning `false` if it is held
+fn try_lock(device: &File, operation: libc::c_int) -> Result<bool, Error> {
+    match unsafe { libc::flock(device.as_raw_fd(), operation | libc::LOCK_NB) } {
+        0 => Ok(true),
+        _ => match std::io::Error::last_os_error() {
+            error if error.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(false),
+            error => Err(error.into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{acquire, Access};
+    use crate::Error;
+
+    #[test]
+    fn exclusive_writer() {
+        let path = std::env::temp_dir().join(format!("tananfs-fence-{}", std::process::id()));
+        let open = || {
+            std::fs::File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+        let writer = open();
+        assert_eq!(acquire(&writer).unwrap(), Access::ReadWrite);
+        assert!(matches!(acquire(&open()), Err(Error::Busy)));
+        drop(writer);
+
+        let reader = open();
+        assert!(super::try_lock(&reader, libc::LOCK_SH).unwrap());
+        assert_eq!(acquire(&open()).unwrap(), Access::ReadOnly);
+        drop(reader);
+        assert_eq!(acquire(&open()).unwrap(), Access::ReadWrite);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! Sector sizes of the backing device, used to lay out new filesystems
+//!
+//! Writes smaller than a physical sector, or straddling two of them, make the
+//! drive read, modify and write back whole sectors. Aligning the block region
+//! to the physical sector and optimal IO size keeps every block within a
+//! single sector group, even when the block size is smaller than the sector.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+/// Largest alignment of the block region, protecting small images from waste
+pub const MAX_ALIGNMENT: u32 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Smallest unit the device writes without read-modify-write
+    pub physical_sector: u32,
+    /// Preferred IO size reported by the device, zero if unknown
+    pub optimal_io: u32,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            physical_sector: 512,
+            optimal_io: 0,
+        }
+    }
+}
+
+impl Geometry {
+    /// Query geometry of a block device, or of the filesystem holding an image file
+    pub fn detect(device: &File) -> std::io::Result<Self> {
+        let metadata = device.metadata()?;
+        if !metadata.file_type().is_block_device() {
+            return Ok(Self {
+                optimal_io: metadata.blksize() as u32,
+                ..Self::default()
+            });
+        }
+        let query = |request| -> std::io::Result<u32> {
+            let mut value: libc::c_uint = 0;
+            match unsafe { libc::ioctl(device.as_raw_fd(), request, &mut value) } {
+                0 => Ok(value),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        };
+        Ok(Self {
+            physical_sector: query(libc::BLKPBSZGET)?,
+            optimal_io: query(libc::BLKIOOPT)?,
+        })
+    }
+
+    /// Alignment of the block region in bytes
+    pub fn alignment(&self) -> u32 {
+        [self.physical_sector, self.optimal_io]
+            .into_iter()
+            .filter(|size| size.is_power_of_two() && *size <= MAX_ALIGNMENT)
+            .fold(512, u32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Geometry;
+
+    #[test]
+    fn alignment() {
+        let geometry = |physical_sector, optimal_io| Geometry {
+            physical_sector,
+            optimal_io,
+        };
+        assert_eq!(Geometry::default().alignment(), 512);
+        assert_eq!(geometry(4096, 0).alignment(), 4096);
+        assert_eq!(geometry(4096, 65536).alignment(), 65536);
+        assert_eq!(geometry(512, 786432).alignment(), 512);
+        assert_eq!(geometry(4096, 1 << 24).alignment(), 4096);
+    }
+}
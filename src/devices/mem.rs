@@ -0,0 +1,190 @@
+//! Block device kept in memory, used for scratch filesystems and tests
+//!
+//! A [MemBlockDevice] has a fixed capacity, and writes past its end fail as
+//! they would on a real device. Its contents are lost once it is dropped,
+//! unless a snapshot file is set with [MemBlockDevice::with_snapshot], which
+//! they are then saved to, and which [MemBlockDevice::load] starts from.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+
+use crate::filesystem::BlockDevice;
+use crate::Error;
+
+#[derive(Debug)]
+pub struct MemBlockDevice {
+    data: Vec<u8>,
+    position: u64,
+    /// File contents are saved to when the device is dropped
+    snapshot: Option<PathBuf>,
+}
+
+impl MemBlockDevice {
+    /// Zeroed device of `capacity` bytes
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            data: vec![0; capacity as usize],
+            position: 0,
+            snapshot: None,
+        }
+    }
+
+    /// Device holding contents of snapshot file at `path`
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        info!("Loading memory device from {}", path.display());
+        Ok(Self {
+            data: std::fs::read(path)?,
+            position: 0,
+            snapshot: None,
+        })
+    }
+
+    /// Save contents to file at `path` when the device is dropped
+    pub fn with_snapshot(mut self, path: &Path) -> Self {
+        self.snapshot = Some(path.to_owned());
+        self
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Write contents to snapshot file, replacing it only once they are
+    /// completely written
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(path) = &self.snapshot else {
+            return Ok(());
+        };
+        info!("Saving memory device to {}", path.display());
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(&self.data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)
+    }
+}
+
+impl Read for MemBlockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self.position.min(self.capacity()) as usize;
+        let read = buf.len().min(self.data.len() - start);
+        buf[..read].copy_from_slice(&self.data[start..start + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemBlockDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = self.position.min(self.capacity()) as usize;
+        let written = buf.len().min(self.data.len() - start);
+        if written == 0 && !buf.is_empty() {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.data[start..start + written].copy_from_slice(&buf[..written]);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemBlockDevice {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.capacity().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        let start = offset.min(self.capacity()) as usize;
+        let end = offset.saturating_add(length).min(self.capacity()) as usize;
+        self.data[start..end].fill(0);
+        Ok(())
+    }
+
+    /// Contents are only saved to file when device is dropped
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemBlockDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            error!("Error saving memory device: {e}");
+        }
+    }
+}
+
+/// Parse size in bytes, optionally followed by a binary unit such as `64M`
+pub fn parse_size(value: &str) -> Result<u64, Error> {
+    let (number, shift) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => {
+            let shift = match unit.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(Error::InvalidArgument),
+            };
+            (&value[..index], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = number.parse().map_err(|_| Error::InvalidArgument)?;
+    number
+        .checked_shl(shift)
+        .filter(|bytes| bytes >> shift == number)
+        .ok_or(Error::InvalidArgument)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::{parse_size, MemBlockDevice};
+
+    #[test]
+    fn fixed_capacity_and_snapshot() {
+        let path = std::env::temp_dir().join(format!("tananfs-mem-{}", std::process::id()));
+        let mut device = MemBlockDevice::new(1000).with_snapshot(&path);
+        device.seek(SeekFrom::Start(990)).unwrap();
+        assert!(device.write_all(&[7u8; 20]).is_err());
+        device.seek(SeekFrom::End(-10)).unwrap();
+        let mut buffer = [0u8; 20];
+        assert_eq!(device.read(&mut buffer).unwrap(), 10);
+        assert_eq!(buffer[..10], [7u8; 10]);
+        drop(device);
+
+        let mut device = MemBlockDevice::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(device.capacity(), 1000);
+        device.seek(SeekFrom::Start(985)).unwrap();
+        device.read_exact(&mut buffer[..15]).unwrap();
+        assert_eq!(buffer[..15], [0, 0, 0, 0, 0, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
+        drop(device);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sizes_with_units() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64M").unwrap(), 64 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("20000000T").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! Block device mirrored on two underlying devices, like RAID 1
+//!
+//! A [MirrorDevice] writes everything to both devices and reads from the
+//! primary one, falling back to the secondary when a read fails, and then
+//! rewriting what it read over the failing part of the primary. A device
+//! failing a write is dropped from the mirror, which goes on degraded with
+//! the other one until it is mounted again. Devices may differ after a crash
+//! or after one was dropped, so [MirrorDevice::resync] copies the primary over
+//! the secondary wherever they differ before the filesystem is loaded.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::{info, warn};
+
+use crate::filesystem::BlockDevice;
+use crate::Error;
+
+/// Bytes compared between devices at once during resynchronization
+pub const MIRROR_RESYNC_CHUNK: usize = 1 << 20;
+
+const NAMES: [&str; 2] = ["primary", "secondary"];
+
+#[derive(Debug)]
+pub struct MirrorDevice<D: BlockDevice> {
+    /// Primary and secondary device
+    devices: [D; 2],
+    /// Devices which failed a write and no longer hold current contents
+    failed: [bool; 2],
+    /// Bytes available on both devices
+    size: u64,
+    /// Device serving all reads without falling back, to verify its contents
+    selected: Option<usize>,
+    position: u64,
+}
+
+impl<D: BlockDevice> MirrorDevice<D> {
+    /// Mirror of `primary` on `secondary`, as large as the smaller of them
+    pub fn new(mut primary: D, mut secondary: D) -> std::io::Result<Self> {
+        let sizes = [
+            primary.seek(SeekFrom::End(0))?,
+            secondary.seek(SeekFrom::End(0))?,
+        ];
+        if sizes[0] != sizes[1] {
+            warn!(
+                "Mirrored devices differ in size, using {} bytes",
+                sizes[0].min(sizes[1])
+            );
+        }
+        Ok(Self {
+            devices: [primary, secondary],
+            failed: [false; 2],
+            size: sizes[0].min(sizes[1]),
+            selected: None,
+            position: 0,
+        })
+    }
+
+    /// Whether a device was dropped from the mirror
+    pub fn degraded(&self) -> bool {
+        self.failed.contains(&true)
+    }
+
+    /// Copy contents of primary over the secondary where they differ, or of
+    /// the secondary over parts of primary which fail to read, returning
+    /// number of bytes copied
+    pub fn resync(&mut self) -> Result<u64, Error> {
+        info!("Resynchronizing {} bytes of mirrored devices", self.size);
+        let mut buffers = [
+            vec![0u8; MIRROR_RESYNC_CHUNK],
+            vec![0u8; MIRROR_RESYNC_CHUNK],
+        ];
+        let mut copied = 0;
+        let mut position = 0;
+        while position < self.size {
+            let length = MIRROR_RESYNC_CHUNK.min((self.size - position) as usize);
+            let [primary, secondary] = &mut buffers;
+            let (primary, secondary) = (&mut primary[..length], &mut secondary[..length]);
+            let (source, target) = match (
+                read_at(&mut self.devices[0], position, primary),
+                read_at(&mut self.devices[1], position, secondary),
+            ) {
+                (Ok(()), Ok(())) if primary == secondary => {
+                    position += length as u64;
+                    continue;
+                }
+                (Ok(()), _) => (0, 1),
+                (Err(e), Ok(())) => {
+                    warn!("Restoring unreadable primary at {position} from secondary: {e}");
+                    (1, 0)
+                }
+                (Err(e), Err(_)) => return Err(e.into()),
+            };
+            let data = &buffers[source][..length];
+            let device = &mut self.devices[target];
+            let written = device
+                .seek(SeekFrom::Start(position))
+                .and_then(|_| device.write_all(data));
+            match written {
+                Ok(()) => copied += length as u64,
+                Err(e) => {
+                    self.fail(target, e)?;
+                    break;
+                }
+            }
+            position += length as u64;
+        }
+        self.flush()?;
+        info!("Resynchronized mirrored devices, copied {copied} bytes");
+        Ok(copied)
+    }
+
+    /// Drop device `index` from the mirror after it failed with `error`,
+    /// returning the error if no device is left
+    fn fail(&mut self, index: usize, error: std::io::Error) -> std::io::Result<()> {
+        warn!("Dropping {} device from mirror: {error}", NAMES[index]);
+        self.failed[index] = true;
+        match self.failed {
+            [true, true] => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Fill `buf` with contents of `device` at `position`
+fn read_at<D: BlockDevice>(device: &mut D, position: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    device.seek(SeekFrom::Start(position))?;
+    device.read_exact(buf)
+}
+
+impl<D: BlockDevice> Read for MirrorDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let length = buf
+            .len()
+            .min(self.size.saturating_sub(self.position) as usize);
+        let buf = &mut buf[..length];
+        if let Some(index) = self.selected {
+            read_at(&mut self.devices[index], self.position, buf)?;
+            self.position += length as u64;
+            return Ok(length);
+        }
+        let result = match self.failed[0] {
+            true => Err(std::io::ErrorKind::NotConnected.into()),
+            false => read_at(&mut self.devices[0], self.position, buf),
+        };
+        if let Err(e) = result {
+            if self.failed[1] {
+                return Err(e);
+            }
+            warn!("Reading at {} from secondary: {e}", self.position);
+            read_at(&mut self.devices[1], self.position, buf)?;
+            if !self.failed[0] {
+                let repaired = self.devices[0]
+                    .seek(SeekFrom::Start(self.position))
+                    .and_then(|_| self.devices[0].write_all(buf));
+                if let Err(e) = repaired {
+                    warn!("Failed to repair primary at {}: {e}", self.position);
+                }
+            }
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+}
+
+impl<D: BlockDevice> Write for MirrorDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() && self.position >= self.size {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        let length = buf.len().min((self.size - self.position) as usize);
+        for index in 0..self.devices.len() {
+            if self.failed[index] {
+                continue;
+            }
+            let device = &mut self.devices[index];
+            let written = device
+                .seek(SeekFrom::Start(self.position))
+                .and_then(|_| device.write_all(&buf[..length]));
+            if let Err(e) = written {
+                self.fail(index, e)?;
+            }
+        }
+        self.position += length as u64;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for index in 0..self.devices.len() {
+            if self.failed[index] {
+                continue;
+            }
+            if let Err(e) = self.devices[index].flush() {
+                self.fail(index, e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Seek for MirrorDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
+impl<D: BlockDevice + 'static> BlockDevice for MirrorDevice<D> {
+    /// Discard on both devices, as a failed discard leaves contents intact
+    /// and does not drop the device from the mirror
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        let length = length.min(self.size.saturating_sub(offset));
+        let mut result = Ok(());
+        for index in 0..self.devices.len() {
+            if self.failed[index] {
+                continue;
+            }
+            if let Err(e) = self.devices[index].discard(offset, length) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Devices which did not fail a write
+    fn copies(&self) -> usize {
+        self.failed.iter().filter(|&&failed| !failed).count()
+    }
+
+    fn select_copy(&mut self, copy: Option<usize>) {
+        self.selected = copy.and_then(|copy| {
+            (0..self.devices.len())
+                .filter(|&index| !self.failed[index])
+                .nth(copy)
+        });
+    }
+
+    /// Wait for both devices, dropping one which fails like a failed write
+    fn barrier(&mut self) -> std::io::Result<()> {
+        for index in 0..self.devices.len() {
+            if self.failed[index] {
+                continue;
+            }
+            if let Err(e) = self.devices[index].barrier() {
+                self.fail(index, e)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        for index in 0..self.devices.len() {
+            if self.failed[index] {
+                continue;
+            }
+            if let Err(e) = self.devices[index].sync() {
+                self.fail(index, e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::MirrorDevice;
+    use crate::filesystem::BlockDevice;
+
+    /// Device of `size` bytes failing every transfer
+    #[derive(Debug)]
+    struct Broken(Cursor<Vec<u8>>);
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+    }
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Broken {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl BlockDevice for Broken {}
+
+    #[test]
+    fn writes_to_both_and_resyncs() {
+        let primary = Cursor::new(vec![1u8; 3 << 20]);
+        let secondary = Cursor::new(vec![1u8; (3 << 20) + 10]);
+        let mut mirror = MirrorDevice::new(primary, secondary).unwrap();
+        assert_eq!(mirror.seek(SeekFrom::End(0)).unwrap(), 3 << 20);
+        mirror.seek(SeekFrom::Start(100)).unwrap();
+        mirror.write_all(&[2u8; 50]).unwrap();
+        assert!(mirror.write_all(&[2u8; 4 << 20]).is_err());
+        assert_eq!(mirror.resync().unwrap(), 0);
+
+        // Primary wins over a secondary which missed a write
+        mirror.devices[1].get_mut()[(2 << 20) + 5] = 3;
+        assert_eq!(mirror.resync().unwrap(), 1 << 20);
+        let [primary, secondary] = &mirror.devices;
+        assert_eq!(primary.get_ref()[..3 << 20], secondary.get_ref()[..3 << 20]);
+        assert_eq!(primary.get_ref()[100..150], [2u8; 50]);
+        assert!(!mirror.degraded());
+    }
+
+    #[test]
+    fn fall_back_to_secondary() {
+        let primary: Box<dyn BlockDevice> = Box::new(Broken(Cursor::new(vec![0u8; 4096])));
+        let secondary: Box<dyn BlockDevice> = Box::new(Cursor::new(vec![5u8; 4096]));
+        let mut mirror = MirrorDevice::new(primary, secondary).unwrap();
+        let mut buffer = [0u8; 16];
+        mirror.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [5u8; 16]);
+        mirror.seek(SeekFrom::Start(8)).unwrap();
+        mirror.write_all(&[6u8; 4]).unwrap();
+        assert!(mirror.degraded());
+        mirror.seek(SeekFrom::Start(4)).unwrap();
+        mirror.read_exact(&mut buffer[..8]).unwrap();
+        assert_eq!(buffer[..8], [5, 5, 5, 5, 6, 6, 6, 6]);
+        assert_eq!(mirror.resync().unwrap(), 0);
+    }
+}
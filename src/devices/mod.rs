@@ -0,0 +1,12 @@
+pub mod concat;
+pub mod direct;
+pub mod discard;
+pub mod faulty;
+pub mod fence;
+pub mod geometry;
+pub mod mem;
+pub mod mirror;
+pub mod overlay;
+pub mod recording;
+pub mod signature;
+pub mod undo;
@@ -0,0 +1,87 @@
+//! Read-only view of a block device with writes laid over its contents
+//!
+//! An [OverlayDevice] serves reads from the underlying device, patched by a
+//! list of writes which are never written to it. Later writes in the list
+//! take precedence over earlier ones, so a rewound journal can be mounted
+//! without touching the device.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::filesystem::BlockDevice;
+
+#[derive(Debug)]
+pub struct OverlayDevice<D: BlockDevice> {
+    device: D,
+    /// Positions and data of writes in order of application
+    patches: Vec<(u64, Vec<u8>)>,
+    position: u64,
+}
+
+impl<D: BlockDevice> OverlayDevice<D> {
+    pub fn new(device: D, patches: Vec<(u64, Vec<u8>)>) -> Self {
+        Self {
+            device,
+            patches,
+            position: 0,
+        }
+    }
+}
+
+impl<D: BlockDevice> Read for OverlayDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.device.seek(SeekFrom::Start(self.position))?;
+        let read = self.device.read(buf)?;
+        let (start, end) = (self.position, self.position + read as u64);
+        for (position, data) in self.patches.iter() {
+            let from = start.max(*position);
+            let to = end.min(position + data.len() as u64);
+            if from < to {
+                buf[(from - start) as usize..(to - start) as usize]
+                    .copy_from_slice(&data[(from - position) as usize..(to - position) as usize]);
+            }
+        }
+        self.position = end;
+        Ok(read)
+    }
+}
+
+impl<D: BlockDevice> Write for OverlayDevice<D> {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> Seek for OverlayDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = self.device.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+impl<D: BlockDevice + 'static> BlockDevice for OverlayDevice<D> {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::OverlayDevice;
+
+    #[test]
+    fn later_patches_win() {
+        let device = Cursor::new(vec![1u8; 16]);
+        let patches = vec![(2, vec![2u8; 6]), (4, vec![3u8; 2]), (14, vec![4u8; 4])];
+        let mut overlay = OverlayDevice::new(device, patches);
+        let mut buffer = [0u8; 16];
+        overlay.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 1, 2, 2, 3, 3, 2, 2, 1, 1, 1, 1, 1, 1, 4, 4]);
+        overlay.seek(SeekFrom::Start(5)).unwrap();
+        let mut buffer = [0u8; 2];
+        overlay.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [3, 2]);
+        assert!(overlay.write_all(&[0]).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Block device recording its writes for crash simulation
+//!
+//! Every write passing through a [RecordingDevice] is appended to a shared
+//! [WriteLog] in the order it was issued. Truncating a copy of the log at an
+//! arbitrary point and replaying it onto a fresh buffer yields the device
+//! image as it would look after a crash at that point.
+
+use std::io::{Read, Seek, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::filesystem::BlockDevice;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteRecord {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteLog {
+    records: Vec<WriteRecord>,
+}
+
+#[derive(Debug)]
+pub struct RecordingDevice<D: BlockDevice> {
+    device: D,
+    log: Arc<Mutex<WriteLog>>,
+}
+
+impl WriteLog {
+    /// Number of recorded writes
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Recorded writes in issue order
+    pub fn records(&self) -> &[WriteRecord] {
+        &self.records
+    }
+
+    /// Keep only the first `len` writes, simulating a crash after them
+    pub fn truncate(&mut self, len: usize) {
+        self.records.truncate(len);
+    }
+
+    /// Apply recorded writes in order to a zeroed image of `capacity` bytes
+    pub fn replay(&self, capacity: u64) -> Vec<u8> {
+        let mut image = vec![0u8; capacity as usize];
+        for record in self.records.iter() {
+            let start = record.offset as usize;
+            let end = (start + record.data.len()).min(image.len());
+            if start < end {
+                image[start..end].copy_from_slice(&record.data[..end - start]);
+            }
+        }
+        image
+    }
+}
+
+impl<D: BlockDevice> RecordingDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            log: Arc::default(),
+        }
+    }
+
+    /// Shared handle to the log, remaining valid after the device is moved
+    pub fn log(&self) -> Arc<Mutex<WriteLog>> {
+        self.log.clone()
+    }
+}
+
+impl<D: BlockDevice> Read for RecordingDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.device.read(buf)
+    }
+}
+
+impl<D: BlockDevice> Write for RecordingDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let offset = self.device.stream_position()?;
+        let written = self.device.write(buf)?;
+        if let Ok(mut log) = self.log.lock() {
+            log.records.push(WriteRecord {
+                offset,
+                data: buf[..written].to_vec(),
+            });
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.device.flush()
+    }
+}
+
+impl<D: BlockDevice> Seek for RecordingDevice<D> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.device.seek(pos)
+    }
+}
+
+impl<D: BlockDevice + 'static> BlockDevice for RecordingDevice<D> {
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.device.barrier()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.device.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::RecordingDevice;
+    use crate::{
+        filesystem::{Filesystem, ROOT_INODE},
+        filetypes::{Directory, FileOperations, Owner, RegularFile},
+    };
+
+    const CAPACITY: u64 = 1_000_000;
+
+    #[test]
+    fn replay_after_crash() {
+        let device = RecordingDevice::new(Cursor::new(vec![0u8; CAPACITY as usize]));
+        let log = device.log();
+        let fs = Filesystem::new(Box::new(device), CAPACITY, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        fs.lock().unwrap().force_flush().unwrap();
+        let formatted = log.lock().unwrap().len();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[7u8; 3_000]).unwrap();
+        drop(file);
+        fs.lock().unwrap().force_flush().unwrap();
+        let log = log.lock().unwrap().clone();
+        assert!(log.len() > formatted);
+        // Every crash after formatting leaves a loadable filesystem
+        for crash_point in formatted..=log.len() {
+            let mut truncated = log.clone();
+            truncated.truncate(crash_point);
+            let mut image = Cursor::new(truncated.replay(CAPACITY));
+            let block_size = Filesystem::detect_existing(&mut image).unwrap();
+            assert_eq!(block_size, Some(512));
+            assert!(Filesystem::load(Box::new(image), 512).is_ok());
+        }
+        let image = Cursor::new(log.replay(CAPACITY));
+        let fs = Arc::new(Mutex::new(Filesystem::load(Box::new(image), 512).unwrap()));
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len(), 1);
+        let mut file = RegularFile::load(&fs, root.children[0].inode).unwrap();
+        assert_eq!(file.read(0, 3_000).unwrap(), vec![7u8; 3_000]);
+    }
+}
@@ -0,0 +1,66 @@
+//! Signatures of data already present on a device about to be formatted
+//!
+//! Creating a filesystem overwrites whatever the device held before. Known
+//! signatures of other filesystems and partition tables are looked up at
+//! their usual positions, and any other nonzero byte near the start of the
+//! device counts as unknown data, so formatting can ask for confirmation.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::Error;
+
+/// Bytes at the start of device checked for unknown data
+pub const SCANNED_BYTES: u64 = 64 << 10;
+
+/// Name, position and magic bytes of well known signatures
+const SIGNATURES: &[(&str, u64, &[u8])] = &[
+    ("LUKS encrypted volume", 0, b"LUKS\xBA\xBE"),
+    ("XFS filesystem", 0, b"XFSB"),
+    ("NTFS filesystem", 3, b"NTFS    "),
+    ("FAT filesystem", 54, b"FAT"),
+    ("FAT32 filesystem", 82, b"FAT32"),
+    ("ext2/3/4 filesystem", 1080, &[0x53, 0xEF]),
+    ("swap space", 4086, b"SWAPSPACE2"),
+    ("ISO 9660 filesystem", 32769, b"CD001"),
+    ("Btrfs filesystem", 65600, b"_BHRfS_M"),
+    ("partition table", 510, &[0x55, 0xAA]),
+];
+
+/// Description of data found on `device`, if it is not empty
+pub fn detect<D: Read + Seek + ?Sized>(device: &mut D) -> Result<Option<&'static str>, Error> {
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut head = vec![0u8; size.min(SCANNED_BYTES + 4096) as usize];
+    device.seek(SeekFrom::Start(0))?;
+    device.read_exact(&mut head)?;
+    for (name, position, magic) in SIGNATURES {
+        let start = *position as usize;
+        if head.get(start..start + magic.len()) == Some(*magic) {
+            return Ok(Some(name));
+        }
+    }
+    let scanned = head.len().min(SCANNED_BYTES as usize);
+    match head[..scanned].iter().any(|&byte| byte != 0) {
+        true => Ok(Some("unknown data")),
+        false => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::detect;
+
+    #[test]
+    fn known_signatures() {
+        let mut device = Cursor::new(vec![0u8; 100_000]);
+        assert_eq!(detect(&mut device).unwrap(), None);
+        device.get_mut()[1080..1082].copy_from_slice(&[0x53, 0xEF]);
+        assert_eq!(detect(&mut device).unwrap(), Some("ext2/3/4 filesystem"));
+        let mut device = Cursor::new(vec![0u8; 100_000]);
+        device.get_mut()[20_000] = 1;
+        assert_eq!(detect(&mut device).unwrap(), Some("unknown data"));
+        let mut device = Cursor::new(vec![0u8; 1_000]);
+        assert_eq!(detect(&mut device).unwrap(), None);
+    }
+}
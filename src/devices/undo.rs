@@ -0,0 +1,416 @@
+//! Side file keeping data overwritten by formatting a device
+//!
+//! While a new filesystem is mounted for the first time, an [UndoDevice]
+//! saves the original contents of every range before it is overwritten for
+//! the first time, up to [UNDO_MAX_SIZE] bytes. Within [UNDO_WINDOW] of
+//! formatting, [undo_format] writes the saved contents back, as long as the
+//! device still holds the filesystem created alongside the side file.
+//!
+//! Side files are kept in a directory only their owner can modify, created
+//! without following symbolic links, and readable only by their owner. Side
+//! files owned by someone else or accessible to others are never replayed,
+//! so no other user can choose what is written to the device.
+
+use std::collections::BTreeMap;
+use std::fs::{DirBuilder, File, Metadata, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+
+use super::fence::{self, Access};
+use crate::filesystem::{BlockDevice, Filesystem};
+use crate::structs::Superblock;
+use crate::Error;
+
+/// Magic signature of the side file
+pub const UNDO_MAGIC: u64 = 0x6F646E55616E6154;
+/// Time after formatting during which it can be undone
+pub const UNDO_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// Largest side file, exceeding it makes formatting impossible to undo
+pub const UNDO_MAX_SIZE: u64 = 256 << 20;
+
+/// Identification of the formatted device, stored at the start of side file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    /// Seconds since epoch when the device was formatted
+    created: u64,
+    device_size: u64,
+    /// Identifier of the new filesystem
+    uuid: [u8; 16],
+}
+
+impl Header {
+    const SIZE: usize = 40;
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&UNDO_MAGIC.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.created.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.device_size.to_le_bytes());
+        bytes[24..40].copy_from_slice(&self.uuid);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, Error> {
+        if u64::from_le_bytes(bytes[0..8].try_into()?) != UNDO_MAGIC {
+            return Err(Error::Corruption);
+        }
+        Ok(Self {
+            created: u64::from_le_bytes(bytes[8..16].try_into()?),
+            device_size: u64::from_le_bytes(bytes[16..24].try_into()?),
+            uuid: bytes[24..40].try_into()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct UndoDevice<D: BlockDevice> {
+    device: D,
+    /// Side file, closed and removed once it grows too large
+    file: Option<File>,
+    path: PathBuf,
+    /// Ranges already saved to the side file, by start
+    saved: BTreeMap<u64, u64>,
+    size: u64,
+}
+
+/// Fail unless file or directory belongs to the current user and nobody
+/// else has any of `denied` permissions for it
+fn check_private(path: &Path, metadata: &Metadata, denied: u32) -> Result<(), Error> {
+    let owner = unsafe { libc::geteuid() };
+    if metadata.uid() != owner || metadata.mode() & denied != 0 {
+        error!(
+            "Refusing {} owned by {} with mode {:o}",
+            path.display(),
+            metadata.uid(),
+            metadata.mode() & 0o7777
+        );
+        return Err(Error::NotPermitted);
+    }
+    Ok(())
+}
+
+/// Path of the side file of device at `device_path`
+///
+/// Side files are kept in `TANANFS_UNDO_DIR`, or a directory of the current
+/// user in the temporary directory, created if missing. Either must be a
+/// directory no other user can write to.
+pub fn side_file(device_path: &str) -> Result<PathBuf, Error> {
+    let directory = match std::env::var_os("TANANFS_UNDO_DIR") {
+        Some(directory) => PathBuf::from(directory),
+        None => {
+            let directory =
+                std::env::temp_dir().join(format!("tananfs-undo-{}", unsafe { libc::geteuid() }));
+            match DirBuilder::new().mode(0o700).create(&directory) {
+                Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+                _ => directory,
+            }
+        }
+    };
+    let metadata = std::fs::symlink_metadata(&directory)?;
+    if !metadata.is_dir() {
+        return Err(Error::NotDirectory);
+    }
+    check_private(&directory, &metadata, 0o022)?;
+    let device_path = std::fs::canonicalize(device_path)?;
+    let name = device_path.to_string_lossy().replace('/', "_");
+    Ok(directory.join(format!("tananfs-undo{name}")))
+}
+
+impl<D: BlockDevice> UndoDevice<D> {
+    /// Start saving original contents of `device` about to get filesystem `uuid`
+    pub fn create(mut device: D, path: &Path, uuid: [u8; 16]) -> Result<Self, Error> {
+        let device_size = device.seek(SeekFrom::End(0))?;
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // Side file of an earlier formatting is replaced, never followed
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)?;
+        file.write_all(
+            &Header {
+                created,
+                device_size,
+                uuid,
+            }
+            .to_bytes(),
+        )?;
+        Ok(Self {
+            device,
+            file: Some(file),
+            path: path.to_path_buf(),
+            saved: BTreeMap::new(),
+            size: Header::SIZE as u64,
+        })
+    }
+
+    /// Parts of range not saved yet
+    fn unsaved(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let mut ranges = Vec::new();
+        let mut position = start;
+        let first = self
+            .saved
+            .range(..=start)
+            .next_back()
+            .map_or(start, |(&from, _)| from);
+        for (&from, &to) in self.saved.range(first..end) {
+            if from > position {
+                ranges.push((position, from));
+            }
+            position = position.max(to);
+            if position >= end {
+                break;
+            }
+        }
+        if position < end {
+            ranges.push((position, end));
+        }
+        ranges
+    }
+
+    /// Mark range as saved, merging it with adjacent ones
+    fn mark_saved(&mut self, mut start: u64, mut end: u64) {
+        if let Some((&from, &to)) = self.saved.range(..=start).next_back() {
+            if to >= start {
+                start = from;
+                end = end.max(to);
+            }
+        }
+        while let Some((&from, &to)) = self.saved.range(start..=end).next() {
+            self.saved.remove(&from);
+            end = end.max(to);
+        }
+        self.saved.insert(start, end);
+    }
+
+    /// Append original contents of range to the side file
+    fn save(&mut self, start: u64, end: u64) -> std::io::Result<()> {
+        self.size += 16 + end - start;
+        if self.size > UNDO_MAX_SIZE {
+            warn!("Overwritten data exceeds {UNDO_MAX_SIZE} bytes, formatting can no longer be undone");
+            self.file = None;
+            return std::fs::remove_file(&self.path);
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        let mut data = vec![0u8; (end - start) as usize];
+        self.device.seek(SeekFrom::Start(start))?;
+        self.device.read_exact(&mut data)?;
+        file.write_all(&start.to_le_bytes())?;
+        file.write_all(&(data.len() as u64).to_le_bytes())?;
+        file.write_all(&data)?;
+        file.flush()
+    }
+}
+
+impl<D: BlockDevice> Read for UndoDevice<D> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.device.read(buf)
+    }
+}
+
+impl<D: BlockDevice> Write for UndoDevice<D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.file.is_none() {
+            return self.device.write(buf);
+        }
+        let position = self.device.stream_position()?;
+        let device_size = self.device.seek(SeekFrom::End(0))?;
+        let end = (position + buf.len() as u64).min(device_size);
+        for (from, to) in self.unsaved(position, end) {
+            self.save(from, to)?;
+            self.mark_saved(from, to);
+        }
+        self.device.seek(SeekFrom::Start(position))?;
+        self.device.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+        self.device.flush()
+    }
+}
+
+impl<D: BlockDevice> Seek for UndoDevice<D> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.device.seek(pos)
+    }
+}
+
+/// Discards are ignored, as saved contents would not cover discarded ranges
+impl<D: BlockDevice + 'static> BlockDevice for UndoDevice<D> {
+    fn barrier(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+        self.device.barrier()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+        self.device.sync()
+    }
+}
+
+/// Restore contents of device at `device_path` from before it was formatted
+pub fn undo_format(device_path: &str) -> Result<(), Error> {
+    let path = side_file(device_path)?;
+    let mut device = File::options().read(true).write(true).open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    restore(&mut device, &path)?;
+    std::fs::remove_file(&path)?;
+    info!("Restored contents of {device_path} from {}", path.display());
+    Ok(())
+}
+
+/// Write contents saved in side file at `path` back to `device`
+pub(crate) fn restore<D: BlockDevice>(device: &mut D, path: &Path) -> Result<(), Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => Error::NotPermitted,
+            _ => Error::NotFound,
+        })?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(Error::NotPermitted);
+    }
+    check_private(path, &metadata, 0o077)?;
+    let mut file = BufReader::new(file);
+    let mut raw = [0u8; Header::SIZE];
+    file.read_exact(&mut raw)?;
+    let header = Header::from_bytes(&raw)?;
+    let age = SystemTime::now()
+        .duration_since(UNIX_EPOCH + Duration::from_secs(header.created))
+        .unwrap_or_default();
+    if age > UNDO_WINDOW {
+        warn!(
+            "Formatting was {} hours ago, it can no longer be undone",
+            age.as_secs() / 3600
+        );
+        return Err(Error::NotFound);
+    }
+    if device.seek(SeekFrom::End(0))? != header.device_size {
+        return Err(Error::Incompatible);
+    }
+    // Anything else on the device now must not be overwritten with stale data
+    let block_size = Filesystem::detect_existing(device)?.ok_or(Error::Incompatible)?;
+    if Superblock::load(device, block_size)?.uuid != header.uuid {
+        return Err(Error::Incompatible);
+    }
+    let mut record = [0u8; 16];
+    loop {
+        match file.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let position = u64::from_le_bytes(record[0..8].try_into()?);
+        let mut data = vec![0u8; u64::from_le_bytes(record[8..16].try_into()?) as usize];
+        file.read_exact(&mut data)?;
+        device.seek(SeekFrom::Start(position))?;
+        device.write_all(&data)?;
+    }
+    device.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::Permissions;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::{Arc, Mutex};
+
+    use super::{restore, UndoDevice};
+    use crate::filesystem::Filesystem;
+    use crate::filetypes::Owner;
+    use crate::Error;
+
+    const CAPACITY: u64 = 1_000_000;
+
+    #[test]
+    fn saved_ranges() {
+        let path = std::env::temp_dir().join(format!("tananfs-undo-ranges-{}", std::process::id()));
+        let mut device = UndoDevice::create(Cursor::new(vec![0u8; 100]), &path, [0; 16]).unwrap();
+        device.mark_saved(10, 20);
+        device.mark_saved(30, 40);
+        assert_eq!(device.unsaved(5, 45), vec![(5, 10), (20, 30), (40, 45)]);
+        assert_eq!(device.unsaved(12, 18), vec![]);
+        device.mark_saved(20, 30);
+        assert_eq!(device.saved.len(), 1);
+        assert_eq!(device.unsaved(0, 50), vec![(0, 10), (40, 50)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn undo_format() {
+        let path = std::env::temp_dir().join(format!("tananfs-undo-{}", std::process::id()));
+        let original: Vec<u8> = (0..CAPACITY).map(|i| (i % 251) as u8).collect();
+        let uuid = [7u8; 16];
+        let device = UndoDevice::create(Cursor::new(original.clone()), &path, uuid).unwrap();
+        let fs = Filesystem::new(Box::new(device), CAPACITY, 512).with_uuid(uuid);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut device = Arc::into_inner(fs).unwrap().into_inner().unwrap().device;
+        device.seek(SeekFrom::Start(0)).unwrap();
+        let mut formatted = vec![0u8; CAPACITY as usize];
+        device.read_exact(&mut formatted).unwrap();
+        assert_ne!(formatted, original);
+
+        let formatted_copy = formatted.clone();
+        let mut formatted = Cursor::new(formatted);
+        restore(&mut formatted, &path).unwrap();
+        assert_eq!(formatted.into_inner(), original);
+
+        // Side file others could read or modify, or a link to it, is not replayed
+        let mut formatted = Cursor::new(formatted_copy);
+        let link = path.with_extension("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(matches!(
+            restore(&mut formatted, &link),
+            Err(Error::NotPermitted)
+        ));
+        std::fs::remove_file(&link).unwrap();
+        std::fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            restore(&mut formatted, &path),
+            Err(Error::NotPermitted)
+        ));
+        std::fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        // Side file does not belong to a different filesystem
+        let mut other = Cursor::new(vec![0u8; CAPACITY as usize]);
+        let mut fs = Filesystem::new(
+            Box::new(Cursor::new(vec![0u8; CAPACITY as usize])),
+            CAPACITY,
+            512,
+        );
+        fs.force_flush().unwrap();
+        fs.device.seek(SeekFrom::Start(0)).unwrap();
+        fs.device.read_exact(other.get_mut()).unwrap();
+        assert!(restore(&mut other, &path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
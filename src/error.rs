@@ -12,6 +12,18 @@ pub enum Error {
     NotFound,
     NullBlock,
     DirectoryNotEmpty,
+    NotDirectory,
+    IsDirectory,
+    TooManyLinks,
+    NameTooLong,
+    Corruption,
+    ReadOnly,
+    Incompatible,
+    Busy,
+    InvalidArgument,
+    Cancelled,
+    NotPermitted,
+    QuotaExceeded,
     Io(std::io::Error),
     Utf8(std::str::Utf8Error),
     SliceIndexing(std::array::TryFromSliceError),
@@ -31,6 +43,18 @@ impl Display for Error {
             NotFound => write!(f, "not found"),
             NullBlock => write!(f, "null block"),
             DirectoryNotEmpty => write!(f, "directory not empty"),
+            NotDirectory => write!(f, "not a directory"),
+            IsDirectory => write!(f, "is a directory"),
+            TooManyLinks => write!(f, "too many links"),
+            NameTooLong => write!(f, "name too long"),
+            Corruption => write!(f, "corrupted data"),
+            ReadOnly => write!(f, "read-only filesystem"),
+            Incompatible => write!(f, "incompatible filesystem features"),
+            Busy => write!(f, "device is in use"),
+            InvalidArgument => write!(f, "invalid argument"),
+            Cancelled => write!(f, "operation cancelled"),
+            NotPermitted => write!(f, "operation not permitted"),
+            QuotaExceeded => write!(f, "disk quota exceeded"),
             Io(e) => write!(f, "{e}"),
             Utf8(e) => write!(f, "{e}"),
             SliceIndexing(e) => write!(f, "{e}"),
@@ -70,6 +94,16 @@ impl<T> From<std::sync::PoisonError<T>> for Error {
     }
 }
 
+/// Errors of the filesystem reach [std::io] traits with their error number
+impl From<Error> for std::io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io(e) => e,
+            e => Self::from_raw_os_error(e.into()),
+        }
+    }
+}
+
 impl From<Error> for libc::c_int {
     fn from(value: Error) -> Self {
         use libc::*;
@@ -85,6 +119,18 @@ impl From<Error> for libc::c_int {
             NotFound => ENOENT,
             NullBlock => ESPIPE,
             DirectoryNotEmpty => ENOTEMPTY,
+            NotDirectory => ENOTDIR,
+            IsDirectory => EISDIR,
+            TooManyLinks => EMLINK,
+            NameTooLong => ENAMETOOLONG,
+            Corruption => EIO,
+            ReadOnly => EROFS,
+            Incompatible => EOPNOTSUPP,
+            Busy => EBUSY,
+            InvalidArgument => EINVAL,
+            Cancelled => ECANCELED,
+            NotPermitted => EPERM,
+            QuotaExceeded => EDQUOT,
             Io(_) => EIO,
             Utf8(_) => EBADMSG,
             SliceIndexing(_) => ENOBUFS,
@@ -0,0 +1,498 @@
+//! Portable archive of a filesystem's directory tree
+//!
+//! An archive starts with [ARCHIVE_MAGIC] and [ARCHIVE_VERSION], followed by
+//! a record for every directory and regular file, each after its parent
+//! directory, and by quota limits. A record starts with its tag, and holds
+//! the path relative to the root, attributes and contents of files. Numbers
+//! are big-endian and nothing depends on the block size or layout of the
+//! filesystem, so an archive is restored onto a filesystem created with any
+//! parameters, and remains readable once the on-disk format changes.
+//!
+//! Restoring creates entries with the owners they had, and sets their modes,
+//! flags and timestamps once all contents are written, so neither mode masks
+//! nor immutable files get in the way. Quota limits are set last, as usage
+//! of the original may already exceed them.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use fuser::FileType;
+use log::{info, warn};
+
+use super::{Filesystem, LockFilesystem, QuotaKind, ROOT_INODE};
+use crate::filetypes::{Directory, FileOperations, Owner, RegularFile, Walk};
+use crate::structs::Inode;
+use crate::Error;
+
+/// First bytes of every archive
+pub const ARCHIVE_MAGIC: [u8; 8] = *b"TANANDMP";
+/// Version of the archive format, increased on incompatible changes
+pub const ARCHIVE_VERSION: u32 = 1;
+/// Bytes of file contents copied at once
+const CHUNK_SIZE: u64 = 1 << 20;
+
+const TAG_END: u8 = 0;
+const TAG_DIRECTORY: u8 = 1;
+const TAG_FILE: u8 = 2;
+const TAG_QUOTA: u8 = 3;
+
+/// Directory or regular file in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the root, empty for the root itself
+    pub path: String,
+    pub kind: FileType,
+    /// Permission bits
+    pub mode: u32,
+    pub owner: Owner,
+    pub flags: u32,
+    pub atime: Duration,
+    pub mtime: Duration,
+    pub crtime: Duration,
+    /// Mode mask of directories
+    pub mode_mask: Option<u32>,
+    /// Bytes of contents following a regular file
+    pub size: u64,
+}
+
+impl Entry {
+    /// Entry at `path` with attributes of [Inode]
+    fn from_inode(path: String, inode: &Inode) -> Self {
+        Self {
+            path,
+            kind: inode.r#type,
+            mode: inode.mode as u32,
+            owner: Owner::from(inode),
+            flags: inode.flags,
+            atime: Duration::new(inode.atime, inode.atime_nsec),
+            mtime: Duration::new(inode.mtime, inode.mtime_nsec),
+            crtime: Duration::new(inode.crtime, inode.crtime_nsec),
+            mode_mask: None,
+            size: match inode.r#type {
+                FileType::Directory => 0,
+                _ => inode.size,
+            },
+        }
+    }
+}
+
+/// Single record of an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Entry(Entry),
+    /// Block and inode limits of a user or group
+    Quota(QuotaKind, u32, u64, u64),
+}
+
+/// Numbers of entries and bytes of contents in an archive
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Totals {
+    pub directories: u64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Totals {
+    fn count(&mut self, entry: &Entry) {
+        match entry.kind {
+            FileType::Directory => self.directories += 1,
+            _ => self.files += 1,
+        }
+        self.bytes += entry.size;
+    }
+}
+
+impl Display for Totals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} directories, {} files, {} bytes",
+            self.directories, self.files, self.bytes
+        )
+    }
+}
+
+/// Writer of records into an archive
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    pub totals: Totals,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        writer.write_all(&ARCHIVE_MAGIC)?;
+        writer.write_all(&ARCHIVE_VERSION.to_be_bytes())?;
+        Ok(Self {
+            writer,
+            totals: Totals::default(),
+        })
+    }
+
+    /// Write entry followed by exactly `size` bytes of its `contents`
+    pub fn entry(&mut self, entry: &Entry, contents: impl Read) -> Result<(), Error> {
+        let tag = match entry.kind {
+            FileType::Directory => TAG_DIRECTORY,
+            FileType::RegularFile => TAG_FILE,
+            _ => return Err(Error::InvalidArgument),
+        };
+        let path = entry.path.as_bytes();
+        self.writer.write_all(&[tag])?;
+        self.writer.write_all(&(path.len() as u32).to_be_bytes())?;
+        self.writer.write_all(path)?;
+        for value in [entry.mode, entry.owner.uid, entry.owner.gid, entry.flags] {
+            self.writer.write_all(&value.to_be_bytes())?;
+        }
+        for time in [entry.atime, entry.mtime, entry.crtime] {
+            self.writer.write_all(&time.as_secs().to_be_bytes())?;
+            self.writer.write_all(&time.subsec_nanos().to_be_bytes())?;
+        }
+        if tag == TAG_DIRECTORY {
+            let mask = entry.mode_mask.unwrap_or(u32::MAX);
+            self.writer.write_all(&mask.to_be_bytes())?;
+        } else {
+            self.writer.write_all(&entry.size.to_be_bytes())?;
+            let copied = std::io::copy(&mut contents.take(entry.size), &mut self.writer)?;
+            if copied != entry.size {
+                return Err(Error::InsufficientBytes);
+            }
+        }
+        self.totals.count(entry);
+        Ok(())
+    }
+
+    /// Write limits of a user or group
+    pub fn quota(
+        &mut self,
+        kind: QuotaKind,
+        id: u32,
+        blocks: u64,
+        inodes: u64,
+    ) -> Result<(), Error> {
+        self.writer.write_all(&[TAG_QUOTA, kind as u8])?;
+        self.writer.write_all(&id.to_be_bytes())?;
+        self.writer.write_all(&blocks.to_be_bytes())?;
+        self.writer.write_all(&inodes.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Mark the end of archive, returning the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.writer.write_all(&[TAG_END])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reader of records from an archive
+///
+/// Contents of a regular file are read from the reader itself after its
+/// record, and skipped if left unread.
+pub struct ArchiveReader<R: Read> {
+    reader: R,
+    /// Unread bytes of the current file's contents
+    remaining: u64,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0u8; ARCHIVE_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != ARCHIVE_MAGIC {
+            return Err(Error::InvalidArgument);
+        }
+        let mut archive = Self {
+            reader,
+            remaining: 0,
+        };
+        if archive.read_u32()? != ARCHIVE_VERSION {
+            return Err(Error::Incompatible);
+        }
+        Ok(archive)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0u8; N];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_time(&mut self) -> Result<Duration, Error> {
+        let seconds = self.read_u64()?;
+        let nanoseconds = self.read_u32()?;
+        if nanoseconds >= 1_000_000_000 {
+            return Err(Error::Corruption);
+        }
+        Ok(Duration::new(seconds, nanoseconds))
+    }
+
+    /// Next record, or [None] at the end of archive
+    pub fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        let skipped = std::io::copy(
+            &mut (&mut self.reader).take(self.remaining),
+            &mut std::io::sink(),
+        )?;
+        if skipped != std::mem::take(&mut self.remaining) {
+            return Err(Error::InsufficientBytes);
+        }
+        let [tag] = self.read_array()?;
+        let kind = match tag {
+            TAG_END => return Ok(None),
+            TAG_DIRECTORY => FileType::Directory,
+            TAG_FILE => FileType::RegularFile,
+            TAG_QUOTA => {
+                let [kind] = self.read_array()?;
+                let kind = QuotaKind::try_from(kind as u32)?;
+                let id = self.read_u32()?;
+                let (blocks, inodes) = (self.read_u64()?, self.read_u64()?);
+                return Ok(Some(Record::Quota(kind, id, blocks, inodes)));
+            }
+            _ => return Err(Error::Corruption),
+        };
+        let mut path = vec![0u8; self.read_u32()? as usize];
+        self.reader.read_exact(&mut path)?;
+        let mut entry = Entry {
+            path: std::str::from_utf8(&path)?.to_owned(),
+            kind,
+            mode: self.read_u32()?,
+            owner: Owner {
+                uid: self.read_u32()?,
+                gid: self.read_u32()?,
+            },
+            flags: self.read_u32()?,
+            atime: self.read_time()?,
+            mtime: self.read_time()?,
+            crtime: self.read_time()?,
+            mode_mask: None,
+            size: 0,
+        };
+        match kind {
+            FileType::Directory => {
+                entry.mode_mask = Some(self.read_u32()?).filter(|&mask| mask != u32::MAX);
+            }
+            _ => {
+                entry.size = self.read_u64()?;
+                self.remaining = entry.size;
+            }
+        }
+        Ok(Some(Record::Entry(entry)))
+    }
+}
+
+impl<R: Read> Read for ArchiveReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let length = buffer.len().min(self.remaining as usize);
+        let read = self.reader.read(&mut buffer[..length])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Path of child `name` of directory at `parent`
+pub fn child_path(parent: &str, name: &str) -> String {
+    match parent {
+        "" => name.to_owned(),
+        _ => format!("{parent}/{name}"),
+    }
+}
+
+impl Filesystem {
+    /// Write directory tree and quota limits of filesystem into `archive`
+    pub fn dump<W: Write>(
+        fs: &Arc<Mutex<Filesystem>>,
+        archive: &mut ArchiveWriter<W>,
+    ) -> Result<(), Error> {
+        for walked in Walk::new(fs, ROOT_INODE) {
+            let (path, index, kind) = walked?;
+            let inode = fs.lock_fs()?.load_inode(index)?;
+            let mut entry = Entry::from_inode(path, &inode);
+            if kind != FileType::Directory {
+                let file = RegularFile::load(fs, index)?;
+                archive.entry(&entry, file)?;
+                continue;
+            }
+            entry.mode_mask = Directory::load(fs, index)?.mode_mask();
+            archive.entry(&entry, std::io::empty())?;
+        }
+        for (kind, id, quota) in fs.lock_fs()?.quotas.entries() {
+            if quota.block_limit > 0 || quota.inode_limit > 0 {
+                archive.quota(kind, id, quota.block_limit, quota.inode_limit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Recreate directory tree of `archive` in the empty filesystem
+    pub fn restore<R: Read>(
+        fs: &Arc<Mutex<Filesystem>>,
+        archive: &mut ArchiveReader<R>,
+    ) -> Result<Totals, Error> {
+        if !Directory::load(fs, ROOT_INODE)?.children.is_empty() {
+            return Err(Error::DirectoryNotEmpty);
+        }
+        let mut totals = Totals::default();
+        let mut indices = BTreeMap::new();
+        let mut entries = Vec::new();
+        let mut quotas = Vec::new();
+        while let Some(record) = archive.next_record()? {
+            let entry = match record {
+                Record::Entry(entry) => entry,
+                Record::Quota(kind, id, blocks, inodes) => {
+                    quotas.push((kind, id, blocks, inodes));
+                    continue;
+                }
+            };
+            let index = match entry.path.rsplit_once('/') {
+                _ if entry.path.is_empty() => ROOT_INODE,
+                split => {
+                    let (parent, name) = split.unwrap_or(("", &entry.path));
+                    let parent = *indices.get(parent).ok_or(Error::Corruption)?;
+                    restore_entry(fs, archive, parent, name, &entry)?
+                }
+            };
+            totals.count(&entry);
+            indices.insert(entry.path.clone(), index);
+            entries.push((index, entry));
+        }
+        for (index, entry) in entries.iter() {
+            if let Some(mask) = entry.mode_mask {
+                let mut directory = Directory::load(fs, *index)?;
+                directory.set_mode_mask(Some(mask));
+                directory.flush()?;
+            }
+        }
+        let mut session = Filesystem::session(fs)?;
+        for (index, entry) in entries {
+            session.change_owner(index, entry.owner)?;
+            let mut inode = session.load_inode(index)?;
+            inode.mode = entry.mode as u16;
+            inode.flags = entry.flags;
+            inode.set_atime(entry.atime);
+            inode.set_mtime(entry.mtime);
+            inode.set_crtime(entry.crtime);
+            session.stage_inode(inode);
+        }
+        session.commit()?;
+        let mut fs_handle = fs.lock_fs()?;
+        if !quotas.is_empty() && !fs_handle.quotas.enabled() {
+            warn!(
+                "Dropping {} quota limits unsupported by filesystem",
+                quotas.len()
+            );
+            quotas.clear();
+        }
+        for (kind, id, blocks, inodes) in quotas {
+            fs_handle.quotas.set_limits(kind, id, blocks, inodes)?;
+        }
+        fs_handle.force_flush()?;
+        info!("Restored {totals}");
+        Ok(totals)
+    }
+}
+
+/// Create `entry` named `name` in directory `parent`, returning its inode
+fn restore_entry<R: Read>(
+    fs: &Arc<Mutex<Filesystem>>,
+    archive: &mut ArchiveReader<R>,
+    parent: u64,
+    name: &str,
+    entry: &Entry,
+) -> Result<u64, Error> {
+    // Permissions are set once contents are written
+    let mode = 0o700;
+    if entry.kind == FileType::Directory {
+        return Ok(Directory::new(fs, parent, name, mode, entry.owner)?
+            .inode
+            .index);
+    }
+    let mut file = RegularFile::new(fs, parent, name, mode, entry.owner)?;
+    let mut buffer = Vec::new();
+    for offset in (0..entry.size).step_by(CHUNK_SIZE as usize) {
+        buffer.resize(CHUNK_SIZE.min(entry.size - offset) as usize, 0);
+        archive.read_exact(&mut buffer)?;
+        file.write(offset, &buffer)?;
+    }
+    Ok(file.inode.index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{ArchiveReader, ArchiveWriter};
+    use crate::filesystem::{Filesystem, LockFilesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+    use crate::Error;
+
+    fn filesystem(block_size: u32) -> Arc<Mutex<Filesystem>> {
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 4_000_000, block_size);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        fs
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let source = filesystem(512);
+        let owner = Owner { uid: 7, gid: 8 };
+        let mut directory = Directory::new(&source, ROOT_INODE, "a", 0o750, owner).unwrap();
+        directory.set_mode_mask(Some(0o027));
+        let parent = directory.inode.index;
+        drop(directory);
+        let contents: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let mut file = RegularFile::new(&source, parent, "f", 0o640, owner).unwrap();
+        file.write(0, &contents).unwrap();
+        drop(file);
+        RegularFile::new(&source, ROOT_INODE, "empty", 0o444, Owner::default()).unwrap();
+        let mut inode = source.lock_fs().unwrap().load_inode(parent).unwrap();
+        inode.set_mtime(Duration::new(1_000_000, 5));
+        source.lock_fs().unwrap().flush_inode(&inode).unwrap();
+
+        let mut archive = ArchiveWriter::new(Vec::new()).unwrap();
+        Filesystem::dump(&source, &mut archive).unwrap();
+        assert_eq!((archive.totals.directories, archive.totals.files), (2, 2));
+        let bytes = archive.finish().unwrap();
+
+        let target = filesystem(2048);
+        let mut archive = ArchiveReader::new(Cursor::new(bytes.clone())).unwrap();
+        let totals = Filesystem::restore(&target, &mut archive).unwrap();
+        assert_eq!(totals.bytes, contents.len() as u64);
+        let directory = Directory::find(&target, ROOT_INODE, "a").unwrap();
+        let directory = Directory::load(&target, directory).unwrap();
+        assert_eq!(directory.mode_mask(), Some(0o027));
+        assert_eq!(
+            ({ directory.inode.mode }, { directory.inode.uid }),
+            (0o750, 7)
+        );
+        assert_eq!({ directory.inode.mtime }, 1_000_000);
+        let index = Directory::find(&target, directory.inode.index, "f").unwrap();
+        drop(directory);
+        let mut file = RegularFile::load(&target, index).unwrap();
+        assert_eq!(file.read(0, 10_000).unwrap(), contents);
+        assert_eq!(({ file.inode.mode }, { file.inode.gid }), (0o640, 8));
+        drop(file);
+
+        // Restoring needs an empty filesystem
+        let mut archive = ArchiveReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(matches!(
+            Filesystem::restore(&target, &mut archive),
+            Err(Error::DirectoryNotEmpty)
+        ));
+        // Truncated archive is detected
+        let mut archive = ArchiveReader::new(Cursor::new(&bytes[..bytes.len() - 1])).unwrap();
+        assert!(Filesystem::restore(&filesystem(1024), &mut archive).is_err());
+    }
+}
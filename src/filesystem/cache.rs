@@ -1,182 +1,354 @@
+//! Least recently used cache of inodes and blocks
+//!
+//! Every access gives a cached line the next generation of the cache, so
+//! lines ordered by generation are ordered from the least to the most
+//! recently used one. Once cached lines hold more bytes than the budget of
+//! the cache, clean ones are evicted starting with the least recently used.
+//! Modified lines are not ordered with them, and are evicted only after the
+//! next flush writes them back.
+
 use log::debug;
 use std::{
-    collections::{BTreeMap, BinaryHeap},
-    time::Instant,
+    collections::BTreeMap,
+    io::{Seek, Write},
 };
 
 use crate::{
     error::Error,
-    structs::{Block, Inode, PermanentIndexed},
+    structs::{Block, Inode, PermanentIndexed, Superblock},
 };
 
-use super::{Filesystem, LRU_MAX_ENTRIES};
+use super::CACHE_MAX_BYTES;
+
+/// Largest number of modified blocks with consecutive indices written together
+const WRITE_RUN_MAX_BLOCKS: usize = 256;
+
+/// Cached inode or block with given index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Inode(u64),
+    Block(u64),
+}
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Cache {
     pub(super) inodes: BTreeMap<u64, CacheLine<Inode>>,
     pub(super) blocks: BTreeMap<u64, CacheLine<Block>>,
+    /// Clean cached lines by generation of their last access
+    recency: BTreeMap<u64, Key>,
+    /// Generation of the next accessed line
+    generation: u64,
+    /// Bytes held by cached lines
+    bytes: usize,
+    /// Bytes cached lines may hold before clean ones are evicted
+    pub(super) budget: usize,
+    /// Lookups of inodes and blocks which were cached
+    pub(super) hits: u64,
+    /// Lookups of inodes and blocks which were not cached
+    pub(super) misses: u64,
 }
 
 #[derive(Debug)]
 pub struct CacheLine<T: Clone> {
     pub(super) value: T,
     pub(super) modified: bool,
-    pub(super) atime: Instant,
-}
-
-#[derive(Debug)]
-pub enum LruLine {
-    Inode(Instant, u64),
-    Block(Instant, u64),
+    /// Generation of the last access
+    generation: u64,
 }
 
-impl LruLine {
-    fn duration(&self) -> u64 {
-        let last = match self {
-            Self::Inode(d, _) => *d,
-            Self::Block(d, _) => *d,
-        };
-        (Instant::now() - last).as_millis() as u64
+impl Default for Cache {
+    fn default() -> Self {
+        Self::with_budget(CACHE_MAX_BYTES)
     }
 }
 
 impl Cache {
-    pub fn prune(&mut self) -> Result<(), Error> {
-        if self.inodes.len() + self.blocks.len() <= LRU_MAX_ENTRIES {
-            return Ok(());
+    pub fn with_budget(budget: usize) -> Self {
+        Self {
+            inodes: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            recency: BTreeMap::new(),
+            generation: 0,
+            bytes: 0,
+            budget,
+            hits: 0,
+            misses: 0,
         }
-        debug!("Pruning LRU cache");
-        let mut lru = BinaryHeap::<LruLine>::with_capacity(self.inodes.len() + self.blocks.len());
-        self.inodes
-            .values()
-            .filter(|v| !v.modified)
-            .for_each(|v| lru.push(v.lru_line()));
-        self.blocks
-            .values()
-            .filter(|v| !v.modified)
-            .for_each(|v| lru.push(v.lru_line()));
-        lru.into_sorted_vec()
-            .iter()
-            .skip(LRU_MAX_ENTRIES)
-            .for_each(|item| match *item {
-                LruLine::Inode(_, index) => {
-                    debug!("Pruning inode {index} from cache");
-                    _ = self.inodes.remove(&index)
+    }
+
+    /// Cached lines hold more bytes than the budget
+    pub fn over_budget(&self) -> bool {
+        self.bytes > self.budget
+    }
+
+    /// Evict least recently used clean lines until cached ones fit in budget
+    pub fn evict(&mut self) {
+        while self.over_budget() {
+            let Some((_, key)) = self.recency.pop_first() else {
+                debug!("Cache exceeds its budget with modified lines");
+                return;
+            };
+            match key {
+                Key::Inode(index) => {
+                    debug!("Evicting inode {index} from cache");
+                    self.remove_inode(index)
                 }
-                LruLine::Block(_, index) => {
-                    debug!("Pruning block {index} from cache");
-                    _ = self.blocks.remove(&index)
+                Key::Block(index) => {
+                    debug!("Evicting block {index} from cache");
+                    self.remove_block(index)
                 }
-            });
-        Ok(())
+            }
+        }
+    }
+
+    /// Drop every line which is not modified
+    pub fn clear(&mut self) {
+        while let Some((_, key)) = self.recency.pop_first() {
+            match key {
+                Key::Inode(index) => self.remove_inode(index),
+                Key::Block(index) => self.remove_block(index),
+            }
+        }
+    }
+
+    /// Bytes held by cached inodes
+    pub fn inode_bytes(&self) -> usize {
+        self.inodes.values().map(|line| line.footprint()).sum()
+    }
+
+    /// Bytes held by cached blocks, including their data
+    pub fn block_bytes(&self) -> usize {
+        self.blocks.values().map(|line| line.footprint()).sum()
+    }
+
+    /// Move line from its `previous` generation to the next one, making it
+    /// the most recently used, and order it with clean lines unless `modified`
+    fn touch(&mut self, previous: Option<u64>, key: Key, modified: bool) -> u64 {
+        if let Some(previous) = previous {
+            self.recency.remove(&previous);
+        }
+        let generation = self.generation;
+        self.generation += 1;
+        if !modified {
+            self.recency.insert(generation, key);
+        }
+        generation
+    }
+
+    /// Block is cached, without making it the most recently used one
+    pub fn contains_block(&self, index: u64) -> bool {
+        self.blocks.contains_key(&index)
     }
 
     pub fn get_inode(&mut self, index: u64) -> Option<Inode> {
-        if let Some(line) = self.inodes.get_mut(&index) {
-            debug!("Fetching inode {index} from cache");
-            line.atime = Instant::now();
-            Some(*line.get())
-        } else {
+        let Some(line) = self.inodes.get(&index) else {
             debug!("Missing inode {index} in cache");
-            None
-        }
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        debug!("Fetching inode {index} from cache");
+        let (previous, modified) = (line.generation, line.modified);
+        let generation = self.touch(Some(previous), Key::Inode(index), modified);
+        let line = self.inodes.get_mut(&index)?;
+        line.generation = generation;
+        Some(line.value)
     }
 
     pub fn get_block(&mut self, index: u64) -> Option<Block> {
-        if let Some(line) = self.blocks.get_mut(&index) {
-            debug!("Fetching block {index} from cache");
-            line.atime = Instant::now();
-            Some(line.get().clone())
-        } else {
+        self.borrow_block(index).cloned()
+    }
+
+    /// Cached block borrowed instead of cloned, made the most recently used one
+    pub fn borrow_block(&mut self, index: u64) -> Option<&Block> {
+        let Some(line) = self.blocks.get(&index) else {
             debug!("Missing block {index} in cache");
-            None
-        }
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        debug!("Fetching block {index} from cache");
+        let (previous, modified) = (line.generation, line.modified);
+        let generation = self.touch(Some(previous), Key::Block(index), modified);
+        let line = self.blocks.get_mut(&index)?;
+        line.generation = generation;
+        Some(&line.value)
+    }
+
+    /// Cache inode as loaded from the block device
+    pub fn insert_inode(&mut self, inode: &Inode) {
+        let index = inode.index;
+        debug!("Adding inode {index} to cache");
+        self.store_inode(inode, false);
+        self.evict();
+    }
+
+    /// Cache block as loaded from the block device
+    pub fn insert_block(&mut self, block: &Block) {
+        let index = block.index;
+        debug!("Adding block {index} to cache");
+        self.store_block(block, false);
+        self.evict();
+    }
+
+    /// Cache modified inode, to be written back on the next flush
+    pub fn write_inode(&mut self, inode: &Inode) {
+        let index = inode.index;
+        debug!("Updating inode {index} in cache");
+        self.store_inode(inode, true);
     }
 
-    pub fn set_inode(&mut self, inode: &Inode) {
+    /// Cache modified block, to be written back on the next flush
+    pub fn write_block(&mut self, block: &Block) {
+        let index = block.index;
+        debug!("Updating block {index} in cache");
+        self.store_block(block, true);
+    }
+
+    fn store_inode(&mut self, inode: &Inode, modified: bool) {
         let index = inode.index;
-        if let Some(line) = self.inodes.get_mut(&index) {
-            debug!("Updating inode {index} in cache");
-            line.update(inode);
-        } else {
-            debug!("Adding inode {index} to cache");
-            self.inodes.insert(index, CacheLine::new(inode));
+        let previous = self.inodes.get(&index).map(|line| line.generation);
+        let line = CacheLine {
+            value: *inode,
+            modified,
+            generation: self.touch(previous, Key::Inode(index), modified),
+        };
+        self.bytes += line.footprint();
+        if let Some(replaced) = self.inodes.insert(index, line) {
+            self.bytes -= replaced.footprint();
         }
     }
 
-    pub fn set_block(&mut self, block: &Block) {
+    fn store_block(&mut self, block: &Block, modified: bool) {
         let index = block.index;
-        if let Some(line) = self.blocks.get_mut(&index) {
-            debug!("Updating block {index} in cache");
-            line.update(block);
-        } else {
-            debug!("Adding block {index} to cache");
-            self.blocks.insert(index, CacheLine::new(block));
+        let previous = self.blocks.get(&index).map(|line| line.generation);
+        let line = CacheLine {
+            value: block.clone(),
+            modified,
+            generation: self.touch(previous, Key::Block(index), modified),
+        };
+        self.bytes += line.footprint();
+        if let Some(replaced) = self.blocks.insert(index, line) {
+            self.bytes -= replaced.footprint();
         }
     }
-}
 
-impl<T: Clone + PartialEq> CacheLine<T> {
-    pub fn new(value: &T) -> Self {
-        Self {
-            value: value.clone(),
-            modified: false,
-            atime: Instant::now(),
+    /// Drop cached inode, discarding its modifications
+    pub fn remove_inode(&mut self, index: u64) {
+        if let Some(line) = self.inodes.remove(&index) {
+            self.recency.remove(&line.generation);
+            self.bytes -= line.footprint();
         }
     }
 
-    pub fn get(&mut self) -> &T {
-        self.atime = Instant::now();
-        &self.value
+    /// Drop cached block, discarding its modifications
+    pub fn remove_block(&mut self, index: u64) {
+        if let Some(line) = self.blocks.remove(&index) {
+            self.recency.remove(&line.generation);
+            self.bytes -= line.footprint();
+        }
     }
 
-    pub fn update(&mut self, value: &T) {
-        if &self.value != value {
-            self.atime = Instant::now();
-            self.modified = true;
-            self.value = value.clone()
+    /// Write modified inodes and blocks to block device, in order of their
+    /// indices so that writes to the device are sequential, and writing blocks
+    /// with consecutive indices together
+    pub fn write_back<D: Write + Seek>(
+        &mut self,
+        block_device: &mut D,
+        superblock: &Superblock,
+    ) -> Result<(), Error> {
+        let inodes = self.inodes.iter_mut().filter(|(_, line)| line.modified);
+        for (&index, line) in inodes {
+            line.value.flush(block_device, superblock)?;
+            line.modified = false;
+            self.recency.insert(line.generation, Key::Inode(index));
         }
+        let mut run: Vec<&Block> = Vec::new();
+        let blocks = self.blocks.values().filter(|line| line.modified);
+        for block in blocks.map(|line| &line.value) {
+            let adjacent = run.last().is_some_and(|last| last.index + 1 == block.index);
+            if !adjacent || run.len() == WRITE_RUN_MAX_BLOCKS {
+                Block::flush_run(block_device, superblock, &run)?;
+                run.clear();
+            }
+            run.push(block);
+        }
+        Block::flush_run(block_device, superblock, &run)?;
+        let blocks = self.blocks.iter_mut().filter(|(_, line)| line.modified);
+        for (&index, line) in blocks {
+            line.modified = false;
+            self.recency.insert(line.generation, Key::Block(index));
+        }
+        Ok(())
     }
 }
 
 impl CacheLine<Inode> {
-    fn lru_line(&self) -> LruLine {
-        LruLine::Inode(self.atime, self.value.index)
+    /// Bytes held by cached inode
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<u64>() + std::mem::size_of::<Self>()
     }
 }
 
 impl CacheLine<Block> {
-    fn flush(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
-        self.modified = false;
-        self.value.flush(&mut fs.device, &fs.superblock)
+    /// Bytes held by cached block, including its data
+    fn footprint(&self) -> usize {
+        std::mem::size_of::<u64>() + std::mem::size_of::<Self>() + self.value.data.capacity()
     }
+}
 
-    fn lru_line(&self) -> LruLine {
-        LruLine::Block(self.atime, self.value.index)
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{Cache, CacheLine};
+    use crate::structs::{Block, Inode, Superblock};
+
+    /// Bytes held by a cached block of 512 bytes
+    const LINE: usize = std::mem::size_of::<u64>() + std::mem::size_of::<CacheLine<Block>>() + 512;
+
+    fn block(index: u64) -> Block {
+        Block {
+            index,
+            data: vec![0; 512],
+            compress: true,
+        }
     }
-}
 
-impl PartialEq for LruLine {
-    fn eq(&self, other: &Self) -> bool {
-        self.duration() == other.duration()
+    fn cached_blocks(cache: &Cache) -> Vec<u64> {
+        cache.blocks.keys().copied().collect()
     }
-}
 
-impl PartialOrd for LruLine {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        use std::cmp::Ordering::*;
-        if self.duration() > other.duration() {
-            Some(Greater)
-        } else {
-            Some(Less)
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = Cache::with_budget(3 * LINE);
+        for index in 0..3 {
+            cache.insert_block(&block(index));
         }
+        assert!(cache.get_block(0).is_some());
+        cache.insert_block(&block(3));
+        assert_eq!(cached_blocks(&cache), [0, 2, 3]);
+        cache.insert_inode(&Inode::default());
+        assert_eq!(cached_blocks(&cache), [0, 3]);
+        assert!(cache.inodes.contains_key(&0));
+        assert!(!cache.over_budget());
     }
-}
-
-impl Eq for LruLine {}
 
-impl Ord for LruLine {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).expect("unexpected none")
+    #[test]
+    fn keep_modified_until_written_back() {
+        let mut cache = Cache::with_budget(2 * LINE);
+        cache.write_block(&block(0));
+        cache.write_block(&block(1));
+        cache.insert_block(&block(2));
+        // Only the clean block can be evicted
+        assert_eq!(cached_blocks(&cache), [0, 1]);
+        cache.write_block(&block(2));
+        assert!(cache.over_budget());
+        let superblock = Superblock::new(1_000_000, 512);
+        let mut device = Cursor::new(vec![0u8; 1_000_000]);
+        cache.write_back(&mut device, &superblock).unwrap();
+        cache.evict();
+        assert_eq!(cached_blocks(&cache), [1, 2]);
+        assert!(!cache.over_budget());
     }
 }
@@ -0,0 +1,611 @@
+//! Consistency check of allocation bitmaps against the directory tree
+//!
+//! Every inode reachable from the root directory, or from the directory of
+//! snapshots recorded in the superblock, must be allocated and linked
+//! exactly once and found through the index of its directory, every block
+//! held by a file must be allocated and held by no other file, unless it is
+//! in a chain shared by as many clones as recorded, and nothing else may be
+//! allocated. Free counters of the superblock must agree with the bitmaps,
+//! and usage charged to quotas with the files of every user and group. Files
+//! unlinked while still open are allocated without being reachable until
+//! they are reclaimed from the orphan list, so the check is only meaningful
+//! while there are none.
+//!
+//! Repairing unlinks entries referring to inodes which cannot be loaded, to
+//! inodes linked elsewhere already and to files whose blocks are free,
+//! unreadable or held by an earlier file. Bitmaps are then rebuilt from what
+//! remains reachable, releasing orphaned inodes and blocks, and holders of
+//! shared chains are counted anew, before directories are rewritten and quota
+//! usage is counted anew. A damaged directory of snapshots is dropped along
+//! with them, while a damaged root directory cannot be repaired.
+//!
+//! A filesystem which was not unmounted cleanly gets a quicker pass when it
+//! is mounted, taking time independent of the number of files: free counters
+//! of the superblock are recounted from bitmaps, which are flushed in the
+//! same transaction but may be newer after a failed flush, and the root
+//! directory is loaded, so a filesystem without one is not mounted at all.
+//! Files unlinked while open are reclaimed from the orphan list beforehand,
+//! unless the filesystem is mounted read-only, while a damaged list is
+//! dropped, leaving its inodes to a full check.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use fuser::FileType;
+use log::{error, info, warn};
+
+use super::{Filesystem, LockFilesystem, QuotaKind, RESERVED_INODE, ROOT_INODE};
+use crate::filetypes::{Directory, FileOperations, RawByteFile};
+use crate::structs::Inode;
+use crate::Error;
+
+/// Findings of a consistency check
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Inodes reachable from the root directory
+    pub inodes: u64,
+    /// Blocks held by reachable inodes
+    pub blocks: u64,
+    /// Descriptions of violated invariants
+    pub problems: Vec<String>,
+    /// Blocks and inodes held by every user and group
+    pub usage: BTreeMap<(QuotaKind, u32), (u64, u64)>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn problem(&mut self, problem: String) {
+        warn!("Consistency check: {problem}");
+        self.problems.push(problem);
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "inodes {}", self.inodes)?;
+        writeln!(f, "blocks {}", self.blocks)?;
+        write!(f, "problems {}", self.problems.len())?;
+        for problem in self.problems.iter() {
+            write!(f, "\n{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes restoring consistency, collected while walking the tree
+#[derive(Debug, Default)]
+struct Repairs {
+    /// Inodes which remain reachable
+    inodes: BTreeSet<u64>,
+    /// Blocks held by reachable inodes
+    blocks: BTreeSet<u64>,
+    /// Files found holding every chain recorded as shared, by its first block
+    chains: BTreeMap<u64, u64>,
+    /// Names to unlink from every directory
+    unlink: BTreeMap<u64, BTreeSet<String>>,
+    /// Directories referring to a wrong parent, with the right one
+    reparent: BTreeMap<u64, u64>,
+    /// Directories whose index has to be rebuilt
+    rewrite: BTreeSet<u64>,
+    /// Whether root directory is damaged, leaving nothing to repair against
+    root_damaged: bool,
+    /// Whether directory of snapshots is damaged and has to be dropped
+    drop_snapshots: bool,
+}
+
+/// Directory waiting to be walked, with the entry linking it, or with
+/// [RESERVED_INODE] as parent if it is linked nowhere
+struct Pending {
+    index: u64,
+    parent: u64,
+    name: String,
+}
+
+impl Filesystem {
+    /// Walk directory tree and check it against bitmaps and superblock
+    pub fn check(fs: &Arc<Mutex<Filesystem>>) -> Result<Report, Error> {
+        Ok(walk(fs)?.0)
+    }
+
+    /// Check filesystem and restore its consistency, returning the problems found
+    pub fn repair(fs: &Arc<Mutex<Filesystem>>) -> Result<Report, Error> {
+        let (report, repairs) = walk(fs)?;
+        if report.is_clean() {
+            return Ok(report);
+        }
+        if repairs.root_damaged {
+            return Err(Error::Corruption);
+        }
+        info!("Repairing {} problems", report.problems.len());
+        let mut fs_handle = fs.lock_fs()?;
+        let inode_count = fs_handle.superblock.total_inodes();
+        for index in fs_handle.superblock.inode_indexes() {
+            fs_handle
+                .inodes
+                .set(index, repairs.inodes.contains(&index))?;
+        }
+        let block_count = fs_handle.superblock.block_count;
+        for index in 0..block_count {
+            fs_handle
+                .blocks
+                .set(index, repairs.blocks.contains(&index))?;
+        }
+        fs_handle.superblock.inodes_free = inode_count - fs_handle.inodes.count_set();
+        fs_handle.superblock.blocks_free = block_count - fs_handle.blocks.count_set();
+        fs_handle.shares.recount(&repairs.chains);
+        if repairs.drop_snapshots {
+            fs_handle.superblock.snapshot_inode = RESERVED_INODE;
+        }
+        drop(fs_handle);
+
+        let directories: BTreeSet<u64> = repairs
+            .unlink
+            .keys()
+            .chain(repairs.reparent.keys())
+            .chain(repairs.rewrite.iter())
+            .copied()
+            .collect();
+        for index in directories {
+            let mut directory = Directory::load(fs, index)?;
+            if let Some(names) = repairs.unlink.get(&index) {
+                directory.children.retain(|c| !names.contains(&c.name));
+                let mut fs_handle = fs.lock_fs()?;
+                let mut subdirectories = 0;
+                for child in directory.children.iter() {
+                    if fs_handle.load_inode(child.inode)?.r#type == FileType::Directory {
+                        subdirectories += 1;
+                    }
+                }
+                directory.inode.metadata[3] = subdirectories;
+            }
+            if let Some(&parent) = repairs.reparent.get(&index) {
+                directory.inode.metadata[0] = parent;
+            }
+            directory.flush()?;
+        }
+
+        // Usage may have changed while rewriting directories
+        let usage = walk(fs)?.0.usage;
+        let mut fs_handle = fs.lock_fs()?;
+        if fs_handle.quotas.enabled() {
+            let charged: Vec<_> = fs_handle
+                .quotas
+                .entries()
+                .map(|(kind, id, _)| (kind, id))
+                .collect();
+            for key in charged.into_iter().chain(usage.keys().copied()) {
+                let (blocks, inodes) = usage.get(&key).copied().unwrap_or_default();
+                fs_handle.quotas.update(key, |quota| {
+                    quota.blocks = blocks;
+                    quota.inodes = inodes;
+                });
+            }
+        }
+        fs_handle.force_flush()?;
+        Ok(report)
+    }
+}
+
+impl Filesystem {
+    /// Recount free inodes and blocks and verify root directory of a
+    /// filesystem which was not unmounted cleanly, returning the problems found
+    ///
+    /// Fails with [Error::Corruption] if root directory cannot be loaded.
+    pub fn recover(fs: &Arc<Mutex<Filesystem>>) -> Result<Report, Error> {
+        let mut report = Report::default();
+        let allocated = fs.lock_fs()?.inodes.get(ROOT_INODE)?;
+        let root = match allocated {
+            true => Directory::load(fs, ROOT_INODE).map(|_| ()),
+            false => Err(Error::NotFound),
+        };
+        if let Err(e) = root {
+            error!("Root directory cannot be loaded: {e}");
+            return Err(Error::Corruption);
+        }
+        let read_only = fs.lock_fs()?.read_only;
+        let reclaimed = match read_only {
+            true => Ok(0),
+            false => Filesystem::reclaim_orphans(fs),
+        };
+        match reclaimed {
+            Ok(_) => {}
+            Err(Error::Corruption) => {
+                report.problem("orphan list is damaged".to_string());
+                fs.lock_fs()?.superblock.orphan_inode = RESERVED_INODE;
+            }
+            Err(e) => return Err(e),
+        }
+        let mut fs = fs.lock_fs()?;
+        let inodes_free = fs.superblock.total_inodes() - fs.inodes.count_set();
+        let blocks_free = fs.superblock.block_count - fs.blocks.count_set();
+        let (counted_inodes, counted_blocks) =
+            (fs.superblock.inodes_free, fs.superblock.blocks_free);
+        if counted_inodes != inodes_free {
+            report.problem(format!(
+                "superblock counts {counted_inodes} free inodes instead of {inodes_free}"
+            ));
+        }
+        if counted_blocks != blocks_free {
+            report.problem(format!(
+                "superblock counts {counted_blocks} free blocks instead of {blocks_free}"
+            ));
+        }
+        report.inodes = fs.superblock.total_inodes() - inodes_free;
+        report.blocks = fs.superblock.block_count - blocks_free;
+        if !report.is_clean() {
+            fs.superblock.inodes_free = inodes_free;
+            fs.superblock.blocks_free = blocks_free;
+            fs.force_flush()?;
+        }
+        Ok(report)
+    }
+}
+
+/// Walk directory tree, reporting problems and collecting repairs
+fn walk(fs: &Arc<Mutex<Filesystem>>) -> Result<(Report, Repairs), Error> {
+    let mut report = Report::default();
+    let mut repairs = Repairs {
+        inodes: BTreeSet::from([RESERVED_INODE, ROOT_INODE]),
+        ..Default::default()
+    };
+    let mut directories = vec![Pending {
+        index: ROOT_INODE,
+        parent: ROOT_INODE,
+        name: String::new(),
+    }];
+    let snapshots = fs.lock_fs()?.superblock.snapshot_inode;
+    if snapshots != RESERVED_INODE {
+        // Walked after the whole tree, so inodes it shares with the tree are unlinked from it
+        directories.insert(
+            0,
+            Pending {
+                index: snapshots,
+                parent: RESERVED_INODE,
+                name: String::new(),
+            },
+        );
+    }
+    while let Some(pending) = directories.pop() {
+        let parent = pending.index;
+        if pending.parent == RESERVED_INODE && !repairs.inodes.insert(parent) {
+            report.problem(format!(
+                "directory of snapshots {parent} is linked in the tree"
+            ));
+            repairs.drop_snapshots = true;
+            continue;
+        }
+        let directory = match Directory::load(fs, parent) {
+            Ok(directory) => directory,
+            Err(e) => {
+                report.problem(format!("directory {parent} cannot be read: {e}"));
+                repairs.unlink_child(pending.parent, pending.name, parent);
+                continue;
+            }
+        };
+        if !claim(fs, &directory.inode, &mut repairs, &mut report)? {
+            repairs.unlink_child(pending.parent, pending.name, parent);
+            if parent != ROOT_INODE {
+                continue;
+            }
+        }
+        let indexed = directory.indexed()?;
+        for child in directory.children.iter() {
+            if indexed && Directory::find(fs, parent, &child.name).ok() != Some(child.inode) {
+                report.problem(format!(
+                    "entry {:?} in {parent} is missing from its index",
+                    child.name
+                ));
+                repairs.rewrite.insert(parent);
+            }
+            if !repairs.inodes.insert(child.inode) {
+                report.problem(format!(
+                    "inode {} is linked more than once, last as {:?} in {parent}",
+                    child.inode, child.name
+                ));
+                repairs
+                    .unlink
+                    .entry(parent)
+                    .or_default()
+                    .insert(child.name.clone());
+                continue;
+            }
+            let inode = match fs.lock_fs()?.load_inode(child.inode) {
+                Ok(inode) => inode,
+                Err(e) => {
+                    report.problem(format!(
+                        "entry {:?} in {parent} refers to inode {} which cannot be loaded: {e}",
+                        child.name, child.inode
+                    ));
+                    repairs.unlink_child(parent, child.name.clone(), child.inode);
+                    continue;
+                }
+            };
+            if inode.r#type == FileType::Directory {
+                let referred = inode.metadata[0];
+                if referred != parent {
+                    report.problem(format!(
+                        "directory {} in {parent} refers to parent {referred}",
+                        child.inode
+                    ));
+                    repairs.reparent.insert(child.inode, parent);
+                }
+                // Blocks of directories are claimed once they are read
+                directories.push(Pending {
+                    index: child.inode,
+                    parent,
+                    name: child.name.clone(),
+                });
+            } else if !claim(fs, &inode, &mut repairs, &mut report)? {
+                repairs.unlink_child(parent, child.name.clone(), child.inode);
+            }
+        }
+    }
+    report.inodes = repairs.inodes.len() as u64 - 1;
+    report.blocks = repairs.blocks.len() as u64;
+
+    let fs = fs.lock_fs()?;
+    if fs.quotas.enabled() {
+        for &block in fs.quotas.blocks.iter() {
+            if !repairs.blocks.insert(block) {
+                report.problem(format!("block {block} of quota file is held twice"));
+            }
+        }
+        check_quotas(&fs, &mut report);
+    }
+    for &block in fs.shares.blocks.iter() {
+        if !repairs.blocks.insert(block) {
+            report.problem(format!("block {block} of share table is held twice"));
+        }
+    }
+    let length = fs.superblock.inode_chunk_blocks();
+    for chunk in 0..fs.superblock.inode_chunk_count as usize {
+        let first = fs.superblock.inode_chunk(chunk)?;
+        for block in first..first + length {
+            if !repairs.blocks.insert(block) {
+                report.problem(format!(
+                    "block {block} of chunk {chunk} of inodes is held twice"
+                ));
+            }
+        }
+    }
+    for (first, holders) in fs.shares.entries() {
+        let found = repairs.chains.get(&first).copied().unwrap_or_default();
+        if found != holders {
+            report.problem(format!(
+                "chain at block {first} is shared by {holders} files, but held by {found}"
+            ));
+        }
+    }
+    for index in fs.superblock.inode_indexes() {
+        match (fs.inodes.get(index)?, repairs.inodes.contains(&index)) {
+            (true, false) => report.problem(format!("inode {index} is allocated but unreachable")),
+            (false, true) => report.problem(format!("inode {index} is reachable but free")),
+            _ => {}
+        }
+    }
+    for index in 0..fs.superblock.block_count {
+        if fs.blocks.get(index)? && !repairs.blocks.contains(&index) {
+            report.problem(format!("block {index} is allocated but held by no file"));
+        }
+    }
+    let (inodes_free, blocks_free) = (fs.superblock.inodes_free, fs.superblock.blocks_free);
+    if inodes_free != fs.superblock.total_inodes() - fs.inodes.count_set() {
+        report.problem(format!("superblock counts {inodes_free} free inodes"));
+    }
+    if blocks_free != fs.superblock.block_count - fs.blocks.count_set() {
+        report.problem(format!("superblock counts {blocks_free} free blocks"));
+    }
+    Ok((report, repairs))
+}
+
+impl Repairs {
+    /// Unlink entry whose inode is not reachable through it
+    fn unlink_child(&mut self, parent: u64, name: String, index: u64) {
+        if index == ROOT_INODE {
+            self.root_damaged = true;
+            return;
+        }
+        self.inodes.remove(&index);
+        if parent == RESERVED_INODE {
+            self.drop_snapshots = true;
+            return;
+        }
+        self.unlink.entry(parent).or_default().insert(name);
+    }
+}
+
+/// Claim blocks of inode and charge them with inode to its owner
+///
+/// Returns false, claiming nothing, if any of them is free, cannot be read
+/// or is held by an inode claimed earlier.
+fn claim(
+    fs: &Arc<Mutex<Filesystem>>,
+    inode: &Inode,
+    repairs: &mut Repairs,
+    report: &mut Report,
+) -> Result<bool, Error> {
+    let index = inode.index;
+    let mut fs_handle = fs.lock_fs()?;
+    let file = RawByteFile::load_locked(&fs_handle, fs, *inode);
+    let blocks = match file.blocks_locked(&mut fs_handle) {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            report.problem(format!("blocks of inode {index} cannot be listed: {e}"));
+            return Ok(false);
+        }
+    };
+    // Later holders of a shared chain claim nothing the first one did not
+    let first = blocks.first().copied();
+    if let Some(holders) = first.and_then(|first| repairs.chains.get_mut(&first)) {
+        if blocks.iter().all(|block| repairs.blocks.contains(block)) {
+            *holders += 1;
+            charge(&mut report.usage, inode, blocks.len() as u64);
+            return Ok(true);
+        }
+    }
+    let mut claimable = true;
+    for &block in blocks.iter() {
+        if !fs_handle.blocks.get(block)? {
+            report.problem(format!("block {block} of inode {index} is not allocated"));
+            claimable = false;
+        }
+        if repairs.blocks.contains(&block) {
+            report.problem(format!("block {block} of inode {index} is held twice"));
+            claimable = false;
+        }
+    }
+    if claimable {
+        repairs.blocks.extend(blocks.iter().copied());
+        if let Some(first) = first.filter(|&first| fs_handle.shares.is_shared(first)) {
+            repairs.chains.insert(first, 1);
+        }
+        charge(&mut report.usage, inode, blocks.len() as u64);
+    }
+    Ok(claimable)
+}
+
+/// Add inode and its `blocks` to usage of its user and group
+fn charge(usage: &mut BTreeMap<(QuotaKind, u32), (u64, u64)>, inode: &Inode, blocks: u64) {
+    for key in [(QuotaKind::User, inode.uid), (QuotaKind::Group, inode.gid)] {
+        let (held, inodes) = usage.entry(key).or_default();
+        *held += blocks;
+        *inodes += 1;
+    }
+}
+
+/// Compare usage charged to quotas with usage found in the directory tree
+fn check_quotas(fs: &Filesystem, report: &mut Report) {
+    let charged: BTreeMap<_, _> = fs
+        .quotas
+        .entries()
+        .map(|(kind, id, quota)| ((kind, id), (quota.blocks, quota.inodes)))
+        .filter(|(_, charged)| *charged != (0, 0))
+        .collect();
+    let keys: BTreeSet<_> = charged.keys().chain(report.usage.keys()).copied().collect();
+    for key in keys {
+        let (blocks, inodes) = charged.get(&key).copied().unwrap_or_default();
+        let held = report.usage.get(&key).copied().unwrap_or_default();
+        if (blocks, inodes) != held {
+            report.problem(format!(
+                "{key:?} is charged {blocks} blocks and {inodes} inodes, but holds {} and {}",
+                held.0, held.1
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+
+    fn filesystem() -> Arc<Mutex<Filesystem>> {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        fs
+    }
+
+    #[test]
+    fn consistent_tree() {
+        let fs = filesystem();
+        let dir = Directory::new(&fs, ROOT_INODE, "dir", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let mut file = RegularFile::new(&fs, dir, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[3u8; 50_000]).unwrap();
+        drop(file);
+        let report = Filesystem::check(&fs).unwrap();
+        assert!(report.is_clean(), "{report}");
+        assert_eq!(report.inodes, 3);
+
+        // Leaked block
+        let index = fs.lock().unwrap().acquire_block(Owner::default()).unwrap();
+        let report = Filesystem::check(&fs).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        fs.lock()
+            .unwrap()
+            .release_block(index, Owner::default())
+            .unwrap();
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+    }
+
+    #[test]
+    fn recover_after_crash() {
+        let fs = filesystem();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[1u8; 5_000]).unwrap();
+        drop(file);
+        assert!(Filesystem::recover(&fs).unwrap().is_clean());
+        {
+            let mut fs = fs.lock().unwrap();
+            fs.superblock.blocks_free += 3;
+            fs.superblock.inodes_free -= 1;
+        }
+        assert_eq!(Filesystem::recover(&fs).unwrap().problems.len(), 2);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+
+        fs.lock().unwrap().inodes.set(ROOT_INODE, false).unwrap();
+        assert!(matches!(
+            Filesystem::recover(&fs),
+            Err(crate::Error::Corruption)
+        ));
+    }
+
+    #[test]
+    fn repair_damage() {
+        let fs = filesystem();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "kept", 0o640, Owner::default()).unwrap();
+        file.write(0, &[1u8; 5_000]).unwrap();
+        let kept = file.inode.index;
+        drop(file);
+        let mut file =
+            RegularFile::new(&fs, ROOT_INODE, "shared", 0o640, Owner::default()).unwrap();
+        file.write(0, &[2u8; 5_000]).unwrap();
+        let shared = file.inode.index;
+        drop(file);
+        {
+            let mut fs = fs.lock().unwrap();
+            // Orphaned block and inode, wrong free counters
+            fs.acquire_block(Owner::default()).unwrap();
+            fs.acquire_inode(Owner::default()).unwrap();
+            fs.superblock.blocks_free += 7;
+            // Second file starts with a block of the first one
+            let first = fs.load_inode(kept).unwrap().first_block;
+            let mut inode = fs.load_inode(shared).unwrap();
+            inode.last_block = first;
+            inode.first_block = first;
+            inode.block_count = 1;
+            inode.metadata[1] = crate::structs::NULL_BLOCK;
+            fs.flush_inode(&inode).unwrap();
+        }
+        // Dangling entry
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        root.add_child("dangling", 500).unwrap();
+        drop(root);
+        let report = Filesystem::check(&fs).unwrap();
+        assert!(!report.is_clean());
+
+        let repaired = Filesystem::repair(&fs).unwrap();
+        assert_eq!(repaired.problems, report.problems);
+        let report = Filesystem::check(&fs).unwrap();
+        assert!(report.is_clean(), "{report}");
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let names: Vec<_> = root.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["kept"]);
+        drop(root);
+        let data = RegularFile::load(&fs, kept)
+            .unwrap()
+            .read(0, 5_000)
+            .unwrap();
+        assert_eq!(data, vec![1u8; 5_000]);
+    }
+}
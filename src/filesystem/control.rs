@@ -0,0 +1,154 @@
+//! Control commands of the filesystem, issued as `ioctl` calls on its files
+//!
+//! Besides `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` of `chattr` and `lsattr`,
+//! which also turn compression of a single file off and on with
+//! [FLAG_NOCOMPRESS](crate::structs::FLAG_NOCOMPRESS), the driver answers
+//! commands of its own, numbered like those of the kernel under type `t`.
+//! Any file or directory inside the mount may be used to flush the whole
+//! filesystem or drop its caches, while fragmentation is reported for the
+//! file itself. The `tananfsctl` tool issues them from the command line.
+
+use std::mem::size_of;
+
+use super::FuseFs;
+use crate::Error;
+
+/// Type of control commands, shared by all of them
+const IOC_TYPE: u32 = b't' as u32;
+const IOC_NONE: u32 = 0;
+const IOC_READ: u32 = 2;
+
+/// Number of a control command, as the `_IO` and `_IOR` macros of the kernel
+const fn ioc(direction: u32, number: u32, size: usize) -> u32 {
+    direction << 30 | (size as u32) << 16 | IOC_TYPE << 8 | number
+}
+
+/// Write back delayed writes and flush cache to the device
+pub const TANANFS_IOC_FLUSH: u32 = ioc(IOC_NONE, 1, 0);
+/// Flush and drop all cached inodes and blocks, for privileged users only
+pub const TANANFS_IOC_DROP_CACHES: u32 = ioc(IOC_NONE, 2, 0);
+/// Read [Fragmentation] of file
+pub const TANANFS_IOC_FRAGMENTATION: u32 = ioc(IOC_READ, 3, size_of::<Fragmentation>());
+/// Commands answered by [FuseFs::control]
+pub const TANANFS_IOC_COMMANDS: [u32; 3] = [
+    TANANFS_IOC_FLUSH,
+    TANANFS_IOC_DROP_CACHES,
+    TANANFS_IOC_FRAGMENTATION,
+];
+
+/// Layout of blocks holding data of a file
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Fragmentation {
+    /// Blocks holding data, without tables indexing them
+    pub blocks: u64,
+    /// Runs of consecutive blocks, one for a file which is not fragmented
+    pub extents: u64,
+}
+
+impl Fragmentation {
+    /// Layout of blocks with given indices, in order of their position in file
+    pub fn of(blocks: &[u64]) -> Self {
+        let breaks = blocks.windows(2).filter(|pair| pair[0] + 1 != pair[1]);
+        Self {
+            blocks: blocks.len() as u64,
+            extents: (breaks.count() as u64 + 1).min(blocks.len() as u64),
+        }
+    }
+
+    /// Bytes in native order, as exchanged with the kernel
+    pub fn to_bytes(self) -> [u8; size_of::<Self>()] {
+        let mut bytes = [0; size_of::<Self>()];
+        bytes[..8].copy_from_slice(&self.blocks.to_ne_bytes());
+        bytes[8..].copy_from_slice(&self.extents.to_ne_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: [u8; size_of::<Self>()]) -> Self {
+        let (blocks, extents) = bytes.split_at(8);
+        Self {
+            blocks: u64::from_ne_bytes(blocks.try_into().unwrap_or_default()),
+            extents: u64::from_ne_bytes(extents.try_into().unwrap_or_default()),
+        }
+    }
+}
+
+impl FuseFs {
+    /// Run control command `cmd` on inode `ino` for user `uid`, returning
+    /// data of its reply
+    pub(crate) fn control(&mut self, uid: u32, ino: u64, cmd: u32) -> Result<Vec<u8>, Error> {
+        match cmd {
+            TANANFS_IOC_FLUSH => {
+                self.write_back_all()?;
+                self.fs_handle()?.force_flush()?;
+                Ok(Vec::new())
+            }
+            TANANFS_IOC_DROP_CACHES => {
+                if uid != 0 {
+                    return Err(Error::NotPermitted);
+                }
+                self.write_back_all()?;
+                let mut fs = self.fs_handle()?;
+                fs.force_flush()?;
+                fs.cache.clear();
+                Ok(Vec::new())
+            }
+            TANANFS_IOC_FRAGMENTATION => {
+                self.write_back(ino)?;
+                let fragmentation = self.session()?.fragmentation(ino)?;
+                Ok(fragmentation.to_bytes().to_vec())
+            }
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{FileOperations, Filesystem, FuseFs, Owner, RegularFile, ROOT_INODE};
+
+    use super::{
+        Fragmentation, TANANFS_IOC_DROP_CACHES, TANANFS_IOC_FLUSH, TANANFS_IOC_FRAGMENTATION,
+    };
+
+    #[test]
+    fn run_control_commands() {
+        assert_eq!(TANANFS_IOC_FLUSH, 0x7401);
+        assert_eq!(TANANFS_IOC_FRAGMENTATION, 0x8010_7403);
+        assert_eq!(
+            Fragmentation::of(&[4, 5, 6, 9, 10, 2]),
+            Fragmentation {
+                blocks: 6,
+                extents: 3
+            }
+        );
+        assert_eq!(Fragmentation::of(&[]), Fragmentation::default());
+
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o644, Owner::default()).unwrap();
+        file.write(0, &[1u8; 2000]).unwrap();
+        file.flush().unwrap();
+        let index = file.inode.index;
+        drop(file);
+
+        let mut fuse_fs = FuseFs::new(fs.clone());
+        let reply = fuse_fs
+            .control(1000, index, TANANFS_IOC_FRAGMENTATION)
+            .unwrap();
+        let fragmentation = Fragmentation::from_bytes(reply.try_into().unwrap());
+        assert_eq!(fragmentation.blocks, 4);
+        assert!((1..=fragmentation.blocks).contains(&fragmentation.extents));
+
+        assert!(fuse_fs
+            .control(1000, index, TANANFS_IOC_DROP_CACHES)
+            .is_err());
+        fuse_fs.control(0, index, TANANFS_IOC_DROP_CACHES).unwrap();
+        assert_eq!(fs.lock().unwrap().memory_usage().block_cache, 0);
+        fuse_fs.control(1000, index, TANANFS_IOC_FLUSH).unwrap();
+    }
+}
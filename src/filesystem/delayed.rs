@@ -0,0 +1,121 @@
+//! Delayed allocation of blocks for data written through a mount
+//!
+//! Writes to a regular file are buffered in memory as a single dirty range
+//! per file, growing while each write continues or overlaps it. Blocks are
+//! allocated only when the range is written back, all at once, so a file
+//! written by many small appends ends up in a few contiguous runs instead of
+//! being interleaved with other files written at the same time.
+
+use std::collections::BTreeMap;
+
+/// Largest dirty range of a single file, written back once exceeded
+pub const DELAYED_FILE_BYTES: usize = 4 << 20;
+/// Largest amount of dirty data of all files, written back once exceeded
+pub const DELAYED_TOTAL_BYTES: usize = 32 << 20;
+
+/// Data written to a file starting at `offset`, not yet on the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DirtyRange {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl DirtyRange {
+    /// Offset following the last written byte
+    pub fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct DelayedWrites {
+    /// Dirty range per inode
+    ranges: BTreeMap<u64, DirtyRange>,
+    /// Bytes held by all dirty ranges
+    bytes: usize,
+}
+
+impl DelayedWrites {
+    /// Merge write into dirty range of inode, starting one if there is none
+    ///
+    /// Returns false without buffering anything if the write neither continues
+    /// nor overlaps the existing range, which must be written back first.
+    pub fn buffer(&mut self, ino: u64, offset: u64, data: &[u8]) -> bool {
+        let range = self.ranges.entry(ino).or_insert_with(|| DirtyRange {
+            offset,
+            data: Vec::new(),
+        });
+        if offset < range.offset || offset > range.end() {
+            return false;
+        }
+        let start = (offset - range.offset) as usize;
+        let overlap = data.len().min(range.data.len() - start);
+        range.data[start..start + overlap].copy_from_slice(&data[..overlap]);
+        range.data.extend_from_slice(&data[overlap..]);
+        self.bytes += data.len() - overlap;
+        true
+    }
+
+    /// Remove dirty range of inode to write it back
+    pub fn take(&mut self, ino: u64) -> Option<DirtyRange> {
+        let range = self.ranges.remove(&ino)?;
+        self.bytes -= range.data.len();
+        Some(range)
+    }
+
+    /// Inodes having a dirty range
+    pub fn inodes(&self) -> Vec<u64> {
+        self.ranges.keys().copied().collect()
+    }
+
+    /// Size of file holding `size` bytes on the device once its range is written back
+    pub fn size(&self, ino: u64, size: u64) -> u64 {
+        self.ranges
+            .get(&ino)
+            .map_or(size, |range| size.max(range.end()))
+    }
+
+    /// Bytes held by dirty range of inode
+    pub fn file_bytes(&self, ino: u64) -> usize {
+        self.ranges.get(&ino).map_or(0, |range| range.data.len())
+    }
+
+    /// Bytes held by all dirty ranges
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Memory held by all dirty ranges
+    pub fn memory_usage(&self) -> usize {
+        self.ranges
+            .values()
+            .map(|range| std::mem::size_of::<DirtyRange>() + range.data.capacity())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DelayedWrites, DirtyRange};
+
+    #[test]
+    fn merge_writes() {
+        let mut delayed = DelayedWrites::default();
+        assert!(delayed.buffer(2, 100, &[1; 50]));
+        assert!(delayed.buffer(2, 150, &[2; 50]));
+        assert!(delayed.buffer(2, 120, &[3; 100]));
+        assert!(!delayed.buffer(2, 99, &[4]));
+        assert!(!delayed.buffer(2, 221, &[4]));
+        assert!(delayed.buffer(3, 0, &[5; 10]));
+        assert_eq!(delayed.bytes(), 130);
+        assert_eq!(delayed.size(2, 150), 220);
+        assert_eq!(delayed.size(2, 300), 300);
+        assert_eq!(delayed.size(4, 300), 300);
+        assert_eq!(delayed.inodes(), vec![2, 3]);
+        let mut data = vec![1; 20];
+        data.extend([3; 100]);
+        assert_eq!(delayed.take(2), Some(DirtyRange { offset: 100, data }));
+        assert_eq!(delayed.take(2), None);
+        assert_eq!(delayed.bytes(), 10);
+    }
+}
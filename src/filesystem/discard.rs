@@ -0,0 +1,134 @@
+//! Discarding free blocks on the backing device
+//!
+//! With mount option `discard`, blocks released between two flushes are
+//! remembered and discarded once the flush freeing them is committed, so a
+//! crash never leaves a block in use whose contents were already discarded.
+//! Blocks allocated again before the flush are skipped, and the rest are
+//! merged into runs so the device is told about as few ranges as possible.
+//! [Filesystem::trim] discards every free block at once instead, like
+//! `fstrim`, for filesystems mounted without the option.
+
+use log::{debug, info, warn};
+
+use super::Filesystem;
+use crate::Error;
+
+impl Filesystem {
+    /// Discard `count` blocks starting at block `start`
+    fn discard_blocks(&mut self, start: u64, count: u64) -> std::io::Result<()> {
+        let block_size = self.superblock.block_size as u64;
+        let offset = self.superblock.block_region_start() + start * block_size;
+        debug!("Discard blocks {start} to {}", start + count - 1);
+        self.device.discard(offset, count * block_size)
+    }
+
+    /// Discard blocks released since the last flush which are still free
+    ///
+    /// Failures are only logged, as discarding never changes what the
+    /// filesystem holds.
+    pub(super) fn discard_released(&mut self) {
+        let released = std::mem::take(&mut self.released);
+        let mut run: Option<(u64, u64)> = None;
+        let free = released
+            .into_iter()
+            .filter(|&index| self.blocks.get(index).is_ok_and(|used| !used));
+        let mut runs = Vec::new();
+        for index in free {
+            run = match run {
+                Some((start, count)) if start + count == index => Some((start, count + 1)),
+                Some(previous) => {
+                    runs.push(previous);
+                    Some((index, 1))
+                }
+                None => Some((index, 1)),
+            };
+        }
+        runs.extend(run);
+        for (start, count) in runs {
+            if let Err(e) = self.discard_blocks(start, count) {
+                warn!("Failed to discard released blocks: {e}");
+                return;
+            }
+        }
+    }
+
+    /// Discard every run of at least `minimum` free blocks, returning the
+    /// number of discarded blocks
+    pub fn trim(&mut self, minimum: u64) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.force_flush()?;
+        let mut discarded = 0;
+        let mut after = 0;
+        while let Some(start) = self.blocks.next_free(after) {
+            let end = self
+                .blocks
+                .next_occupied(start)
+                .unwrap_or(self.superblock.block_count);
+            if end - start >= minimum.max(1) {
+                self.discard_blocks(start, end - start)?;
+                discarded += end - start;
+            }
+            after = end;
+        }
+        info!("Discarded {discarded} free blocks");
+        Ok(discarded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, LockFilesystem, ROOT_INODE};
+    use crate::filetypes::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    };
+    use crate::structs::MountOptions;
+
+    /// Filesystem with `options` whose removed file filled 20 blocks with sevens
+    fn removed_file(options: MountOptions) -> Filesystem {
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs = Filesystem::new(Box::new(device), 1 << 20, 1024).with_options(options);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o644, Owner::default()).unwrap();
+        file.write(0, &[7u8; 20 * 1024]).unwrap();
+        file.flush().unwrap();
+        drop(file);
+        fs.lock_fs().unwrap().force_flush().unwrap();
+        Directory::load(&fs, ROOT_INODE)
+            .unwrap()
+            .remove_child(DirectoryChildIdentifier::Name("file"))
+            .unwrap();
+        fs.lock_fs().unwrap().force_flush().unwrap();
+        Arc::into_inner(fs).unwrap().into_inner().unwrap()
+    }
+
+    /// Number of blocks on the device whose data, following the pointer to the
+    /// next block, is still filled with sevens
+    fn sevens(fs: &mut Filesystem) -> usize {
+        let start = fs.superblock.block_region_start();
+        let mut contents = Vec::new();
+        fs.device.seek(SeekFrom::Start(start)).unwrap();
+        fs.device.read_to_end(&mut contents).unwrap();
+        contents
+            .chunks(1024)
+            .filter(|block| block[8..].iter().all(|&byte| byte == 7))
+            .count()
+    }
+
+    #[test]
+    fn discard_on_flush() {
+        let mut fs = removed_file(MountOptions::DISCARD);
+        assert!(fs.released.is_empty());
+        assert_eq!(sevens(&mut fs), 0);
+
+        let mut fs = removed_file(MountOptions::default());
+        assert_eq!(sevens(&mut fs), 20);
+        assert!(fs.trim(1).unwrap() > 20);
+        assert_eq!(sevens(&mut fs), 0);
+    }
+}
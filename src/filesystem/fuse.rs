@@ -1,32 +1,85 @@
-use fuser::FileType;
+use fuser::{FileType, TimeOrNow};
 use log::{debug, error, info, warn};
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::{
     error::Error,
     filesystem::ROOT_INODE,
-    filetypes::{Directory, FileOperations, RegularFile},
+    filetypes::{
+        timestamp_now, Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    },
+    structs::{Inode, FLAGS_SUPPORTED, FLAG_APPEND_ONLY, FLAG_IMMUTABLE},
 };
 
-use super::FuseFs;
+use super::control::TANANFS_IOC_COMMANDS;
+use super::{invalidation, stats, FuseFs, QuotaKind, CONTROL_INODE, STATS_INODE};
+
+/// Extended attribute holding directory mode mask as an octal number
+pub const MODE_MASK_XATTR: &str = "user.tananfs.mode_mask";
+/// Read-only extended attribute of root directory holding filesystem health
+const HEALTH_XATTR: &str = "user.tananfs.health";
+/// Read-only extended attribute of root directory holding memory usage in bytes
+const MEMORY_XATTR: &str = "user.tananfs.memory";
+/// Prefix of extended attributes of root directory holding quotas, followed
+/// by `user.<uid>` or `group.<gid>`
+const QUOTA_XATTR: &str = "user.tananfs.quota.";
+/// Commands of `chattr` and `lsattr`, with `long` and `int` sized argument
+pub const FS_IOC_GETFLAGS: [u32; 2] = [0x80086601, 0x80046601];
+pub const FS_IOC_SETFLAGS: [u32; 2] = [0x40086602, 0x40046602];
+
+/// Time requested by kernel as duration since epoch, clamping earlier times to it
+fn since_epoch(time: TimeOrNow, now: Duration) -> Duration {
+    match time {
+        TimeOrNow::SpecificTime(time) => time.duration_since(UNIX_EPOCH).unwrap_or_default(),
+        TimeOrNow::Now => now,
+    }
+}
+
+/// User or group whose quota is held by extended attribute `name` of root directory
+fn quota_xattr(ino: u64, name: &std::ffi::OsStr) -> Option<(QuotaKind, u32)> {
+    if ino != ROOT_INODE {
+        return None;
+    }
+    let (kind, id) = name.to_str()?.strip_prefix(QUOTA_XATTR)?.split_once('.')?;
+    let kind = match kind {
+        "user" => QuotaKind::User,
+        "group" => QuotaKind::Group,
+        _ => return None,
+    };
+    Some((kind, id.parse().ok()?))
+}
+
+/// Block and inode limits written to a quota extended attribute
+fn quota_limits(value: &[u8]) -> Option<(u64, u64)> {
+    let value = std::str::from_utf8(value).ok()?;
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [blocks, inodes] => Some((blocks.parse().ok()?, inodes.parse().ok()?)),
+        _ => None,
+    }
+}
 
 impl fuser::Filesystem for FuseFs {
     fn init(
         &mut self,
         _req: &fuser::Request<'_>,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
         info!("Initializing filesystem");
-        if self.fs_handle()?.inodes.get(ROOT_INODE)? {
+        if let Err(e) = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC) {
+            warn!("Kernel does not support capabilities {e:#x}");
+        }
+        invalidation::serve(Some(self.mount));
+        if !self.read_only {
+            self.watch_invalidations()?;
+        }
+        self.notify_kernel()?;
+        if self.read_only {
+            info!("Mounted as read-only mirror");
+        } else if self.fs_handle()?.inodes.get(ROOT_INODE)? {
             debug!("Reusing existing root directory");
         } else {
-            self.fs_handle()?.inodes.set(0, true)?;
-            debug!(
-                "Skipped inode 0, current is {}",
-                self.fs_handle()?.inodes.next_free(0).unwrap()
-            );
-            Directory::new(&self.filesystem, ROOT_INODE, "root", 0o750)?;
-            info!("Root directory created");
+            error!("Root directory is missing, repair filesystem with fsck or create it with mkfs");
+            return Err(Error::Corruption.into());
         }
         self.fs_handle()?.force_flush()?;
         debug!("Success");
@@ -34,6 +87,7 @@ impl fuser::Filesystem for FuseFs {
     }
 
     fn access(&mut self, _req: &fuser::Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let _timer = self.time("access", || format!("inode {ino} with mask {mask}"));
         info!("Accessing inode {ino} with mask {mask}");
         reply.ok();
         debug!("Success");
@@ -43,40 +97,31 @@ impl fuser::Filesystem for FuseFs {
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
+        let _timer = self.time("readdir", || format!("directory {ino} at offset {offset}"));
         info!("Reading directory {ino} with offset {offset}");
-        let inner = || -> Result<(), Error> {
-            match Directory::load(&self.filesystem, ino) {
-                Ok(dir) => {
-                    if offset == 0 {
-                        let parent = dir.inode.metadata[0];
-                        let _ = reply.add(ino, 0, fuser::FileType::Directory, ".");
-                        let _ = reply.add(parent, 1, fuser::FileType::Directory, "..");
-                        debug!("Listed parent and self inode");
-                    }
-                    for (index, child) in dir.children.iter().skip(offset as usize).enumerate() {
-                        let inode = self.fs_handle()?.load_inode(ino)?;
-                        debug!("Listed child inode {}", child.name);
-                        if reply.add(ino, offset + index as i64 + 3, inode.r#type, &child.name) {
-                            debug!("Buffer full");
-                            break;
-                        }
-                    }
-                    reply.ok();
-                    debug!("Success");
-                    Ok(())
-                }
-                Err(e) => {
-                    warn!("Error: {e}");
-                    reply.error(e.into());
-                    Ok(())
-                }
+        if offset == 0 {
+            if let Err(e) = self.rewind_directory(ino, fh) {
+                warn!("Error: {e}");
+                return reply.error(e.into());
             }
+        }
+        let Some(snapshot) = self.directories.get(&fh) else {
+            warn!("Directory handle {fh} is not open");
+            return reply.error(libc::EBADF);
         };
-        inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
+        for (index, entry) in snapshot.entries.iter().enumerate().skip(offset as usize) {
+            debug!("Listed child inode {}", entry.name);
+            if reply.add(entry.inode, index as i64 + 1, entry.kind, &entry.name) {
+                debug!("Buffer full");
+                break;
+            }
+        }
+        reply.ok();
+        debug!("Success");
     }
 
     fn lookup(
@@ -86,15 +131,19 @@ impl fuser::Filesystem for FuseFs {
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEntry,
     ) {
+        let _timer = self.time("lookup", || format!("{name:?} in directory {parent}"));
         info!("Lookup {name:?} in directory with inode {parent}");
         let inner = || -> Result<(), Error> {
-            let dir = Directory::load(&self.filesystem, parent)?;
             let name = name.to_string_lossy();
-            match dir.get_child_inode(crate::filetypes::DirectoryChildIdentifier::Name(&name)) {
+            if let Some(child) = stats::control_lookup(parent, &name) {
+                reply.entry(&Duration::from_secs(0), &self.attrs(child)?, 0);
+                debug!("Success");
+                return Ok(());
+            }
+            match Directory::find(&self.filesystem, parent, &name) {
                 Ok(child) => {
-                    drop(dir);
-                    let inode = self.fs_handle()?.load_inode(child)?;
-                    let attrs = inode.attrs(&self.fs_handle()?.superblock);
+                    let attrs = self.attrs(child)?;
+                    self.remember(attrs.ino)?;
                     reply.entry(&Duration::from_secs(0), &attrs, 0);
                     debug!("Loaded attributes");
                     debug!("Success");
@@ -117,12 +166,22 @@ impl fuser::Filesystem for FuseFs {
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("rmdir", || format!("{name:?} in directory {parent}"));
         info!("Remove directory {name:?} with parent {parent}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
-            let mut dir = Directory::load(&self.filesystem, parent)?;
             let name = name.to_str().unwrap();
-            if let Err(e) = dir.remove_child(crate::filetypes::DirectoryChildIdentifier::Name(name))
-            {
+            let result = Directory::load(&self.filesystem, parent).and_then(|mut dir| {
+                match dir.child_type(DirectoryChildIdentifier::Name(name))? {
+                    FileType::Directory => dir.remove_child(DirectoryChildIdentifier::Name(name)),
+                    _ => Err(Error::NotDirectory),
+                }
+            });
+            let result = result.and_then(|()| self.synchronous(true));
+            if let Err(e) = result {
                 warn!("Error: {e}");
                 reply.error(e.into());
                 Ok(())
@@ -139,19 +198,39 @@ impl fuser::Filesystem for FuseFs {
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
+        let _timer = self.time("read", || {
+            format!("{size} bytes of file {ino} at offset {offset}")
+        });
         info!("Read {size} bytes from file {ino:?} with offset {offset}");
+        if ino == STATS_INODE {
+            let contents = match self.fs_handle() {
+                Ok(fs) => stats::stats_contents(&fs),
+                Err(e) => return reply.error(e.into()),
+            };
+            let start = (offset as usize).min(contents.len());
+            let end = (start + size as usize).min(contents.len());
+            reply.data(&contents[start..end]);
+            return debug!("Success");
+        }
+        let window = self.readahead.read(fh, offset as u64, size as u64);
+        let mut buffer = std::mem::take(&mut self.read_buffer);
         let inner = || -> Result<(), Error> {
-            match RegularFile::load(&self.filesystem, ino) {
-                Ok(mut file) => {
-                    let data = file.read(offset as u64, size as u64)?;
-                    reply.data(&data);
+            match self.write_back(ino).and_then(|_| {
+                self.session()?
+                    .read_file_into(ino, offset as u64, size as u64, &mut buffer)
+            }) {
+                Ok(()) => {
+                    reply.data(&buffer);
+                    if let Some(window) = window {
+                        self.read_ahead(fh, ino, offset as u64 + buffer.len() as u64, window);
+                    }
                     debug!("Success");
                     Ok(())
                 }
@@ -163,13 +242,14 @@ impl fuser::Filesystem for FuseFs {
             }
         };
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
+        self.read_buffer = buffer;
     }
 
     fn write(
         &mut self,
         _req: &fuser::Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
@@ -177,14 +257,27 @@ impl fuser::Filesystem for FuseFs {
         _lock_owner: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
+        let _timer = self.time("write", || {
+            format!("{} bytes to file {ino} at offset {offset}", data.len())
+        });
         info!(
             "Write {} bytes to file {ino:?} with offset {offset}",
             data.len()
         );
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
-            match RegularFile::load(&self.filesystem, ino) {
-                Ok(mut file) => {
-                    file.write(offset as u64, data)?;
+            let append = self
+                .files
+                .get(&fh)
+                .is_some_and(|flags| flags & libc::O_APPEND != 0);
+            match self
+                .write_file(ino, offset as u64, append, data)
+                .and_then(|()| self.synchronous(false))
+            {
+                Ok(()) => {
                     reply.written(data.len() as u32);
                     debug!("Success");
                     Ok(())
@@ -209,19 +302,20 @@ impl fuser::Filesystem for FuseFs {
         mode: i32,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("fallocate", || {
+            format!("{length} bytes of file {ino} at offset {offset}")
+        });
         info!("Allocate {length} bytes in file {ino:?} at offset {offset}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
-            match RegularFile::load(&self.filesystem, ino) {
-                Ok(mut file) => {
-                    let size = file.file.size as i64;
-                    let new_size = size - offset + length;
-                    if new_size > size {
-                        file.file.extend(new_size as u64)?;
-                    } else {
-                        file.file.shrink(new_size as u64)?;
-                    }
-                    file.modified = true;
-                    file.inode.mode = mode as u16;
+            match self
+                .allocate(ino, offset as u64, length as u64, mode)
+                .and_then(|()| self.synchronous(false))
+            {
+                Ok(()) => {
                     reply.ok();
                     debug!("Success");
                     Ok(())
@@ -236,18 +330,63 @@ impl fuser::Filesystem for FuseFs {
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
+    fn copy_file_range(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        let _timer = self.time("copy_file_range", || {
+            format!("{len} bytes of file {ino_in} to file {ino_out}")
+        });
+        info!("Copy {len} bytes of file {ino_in:?} at offset {offset_in} to file {ino_out:?} at offset {offset_out}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
+        let inner = || -> Result<(), Error> {
+            let result = self
+                .copy_range(
+                    (ino_in, offset_in as u64),
+                    (ino_out, offset_out as u64),
+                    len,
+                )
+                .and_then(|copied| self.synchronous(false).map(|()| copied));
+            match result {
+                Ok(copied) => {
+                    reply.written(copied as u32);
+                    debug!("Success");
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                    Ok(())
+                }
+            }
+        };
+        inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
+    }
+
     fn getattr(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyAttr) {
+        let _timer = self.time("getattr", || format!("inode {ino}"));
         info!("Get attributes for inode {ino}");
         let inner = || -> Result<(), Error> {
-            let inode = match self.fs_handle()?.load_inode(ino) {
-                Ok(inode) => inode,
+            let attrs = match self.attrs(ino) {
+                Ok(attrs) => attrs,
                 Err(e) => {
                     warn!("Error: {e}");
                     reply.error(e.into());
                     return Ok(());
                 }
             };
-            let attrs = inode.attrs(&self.fs_handle()?.superblock);
             reply.attr(&Duration::from_secs(0), &attrs);
             debug!("Success");
             Ok(())
@@ -257,25 +396,47 @@ impl fuser::Filesystem for FuseFs {
 
     fn setattr(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<std::time::SystemTime>,
         _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
+        crtime: Option<std::time::SystemTime>,
         _chgtime: Option<std::time::SystemTime>,
         _bkuptime: Option<std::time::SystemTime>,
         flags: Option<u32>,
         reply: fuser::ReplyAttr,
     ) {
+        let _timer = self.time("setattr", || format!("inode {ino}"));
         info!("Set attributes for inode {ino}");
+        if stats::is_control(ino) {
+            warn!("Unable to modify control files");
+            return reply.error(libc::EACCES);
+        }
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
-            let mut inode = match self.fs_handle()?.load_inode(ino) {
+            // Delayed writes are resized along with the file
+            if size.is_some() {
+                if let Err(e) = self.write_back(ino) {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                    return Ok(());
+                }
+            }
+            let mut session = self.session()?;
+            let mut inode = match session.load_inode(ino).and_then(|inode| {
+                inode.check_modifiable()?;
+                inode.check_attributes(req.uid(), uid, gid, mode)?;
+                Ok(inode)
+            }) {
                 Ok(inode) => inode,
                 Err(e) => {
                     warn!("Error: {e}");
@@ -287,37 +448,95 @@ impl fuser::Filesystem for FuseFs {
                 debug!("Setting mode to {mode:0o}");
                 inode.mode = mode as u16;
             }
-            if let Some(uid) = uid {
-                debug!("Setting uid to {uid}");
-                inode.uid = uid;
-            }
-            if let Some(gid) = gid {
-                debug!("Setting gid to {gid}");
-                inode.gid = gid;
-            }
+            let owner = Owner {
+                uid: uid.unwrap_or(inode.uid),
+                gid: gid.unwrap_or(inode.gid),
+            };
             if let Some(flags) = flags {
                 debug!("Setting flags to {flags}");
             }
-            self.fs_handle()?.flush_inode(&inode)?;
-            debug!("Flushing inode");
-            reply.attr(
-                &Duration::new(0, 0),
-                &inode.attrs(&self.fs_handle()?.superblock),
-            );
+            let now = timestamp_now();
+            if let Some(atime) = atime {
+                debug!("Setting access time to {atime:?}");
+                inode.set_atime(since_epoch(atime, now));
+            }
+            if let Some(mtime) = mtime {
+                debug!("Setting modification time to {mtime:?}");
+                inode.set_mtime(since_epoch(mtime, now));
+            }
+            if let Some(crtime) = crtime {
+                debug!("Setting creation time to {crtime:?}");
+                inode.set_crtime(since_epoch(TimeOrNow::SpecificTime(crtime), now));
+            }
+            inode.set_ctime(match ctime {
+                Some(ctime) => since_epoch(TimeOrNow::SpecificTime(ctime), now),
+                None => now,
+            });
+            session.stage_inode(inode);
+            if uid.is_some() || gid.is_some() {
+                debug!("Setting owner to {}:{}", owner.uid, owner.gid);
+                if let Err(e) = session.change_owner(ino, owner) {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                    return Ok(());
+                }
+            }
+            if let Some(size) = size {
+                debug!("Setting size to {size}");
+                if let Err(e) = session.resize_file(ino, size) {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                    return Ok(());
+                }
+            }
+            let mut attrs = session.attrs(ino)?;
+            attrs.size = self.delayed.lock()?.size(ino, attrs.size);
+            session.commit()?;
+            debug!("Flushed inode");
+            if let Err(e) = self.synchronous(false) {
+                warn!("Error: {e}");
+                reply.error(e.into());
+                return Ok(());
+            }
+            reply.attr(&Duration::new(0, 0), &attrs);
             debug!("Success");
             Ok(())
         };
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
-    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        info!("Open file {ino}");
+    fn open(&mut self, _req: &fuser::Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let _timer = self.time("open", || format!("file {ino} with flags {flags:#o}"));
+        info!("Open file {ino} with flags {flags:#o}");
+        if ino == STATS_INODE {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                warn!("Unable to modify control files");
+                return reply.error(libc::EACCES);
+            }
+            let fh = self.next_handle;
+            self.next_handle += 1;
+            reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO);
+            return debug!("Success");
+        }
         let inner = || -> Result<(), Error> {
-            match self.fs_handle()?.load_inode(ino) {
+            let inode = self.fs_handle()?.load_inode(ino);
+            match inode {
                 Ok(inode) => {
                     if inode.r#type == FileType::RegularFile {
-                        reply.opened(0, fuser::consts::FOPEN_DIRECT_IO);
-                        debug!("Success");
+                        match self.open_file(ino, flags) {
+                            Ok(fh) => {
+                                reply.opened(fh, self.file_open_flags(flags));
+                                debug!("Success");
+                            }
+                            Err(e) => {
+                                warn!("Error: {e}");
+                                reply.error(e.into());
+                            }
+                        }
+                        Ok(())
+                    } else if inode.r#type == FileType::Directory {
+                        warn!("Unable to open directory as a file");
+                        reply.error(Error::IsDirectory.into());
                         Ok(())
                     } else {
                         warn!("Unable to open non-regular file");
@@ -335,6 +554,32 @@ impl fuser::Filesystem for FuseFs {
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
+    fn release(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _timer = self.time("release", || format!("file {ino} with handle {fh}"));
+        info!("Release file {ino} with handle {fh}");
+        self.files.remove(&fh);
+        self.readahead.release(fh);
+        match self.write_back(ino) {
+            Ok(()) => {
+                reply.ok();
+                debug!("Success");
+            }
+            Err(e) => {
+                error!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
+
     fn opendir(
         &mut self,
         _req: &fuser::Request<'_>,
@@ -342,17 +587,26 @@ impl fuser::Filesystem for FuseFs {
         _flags: i32,
         reply: fuser::ReplyOpen,
     ) {
+        let _timer = self.time("opendir", || format!("directory {ino}"));
         info!("Open directory {ino}");
         let inner = || -> Result<(), Error> {
-            match self.fs_handle()?.load_inode(ino) {
-                Ok(inode) => {
-                    if inode.r#type == FileType::Directory {
-                        reply.opened(0, fuser::consts::FOPEN_DIRECT_IO);
+            let inode = match ino {
+                CONTROL_INODE => Ok(FileType::Directory),
+                _ => self.fs_handle()?.load_inode(ino).map(|inode| inode.r#type),
+            };
+            match inode {
+                Ok(kind) => {
+                    if kind == FileType::Directory {
+                        let entries = self.snapshot_directory(ino)?;
+                        let fh = self.next_handle;
+                        self.next_handle += 1;
+                        self.directories.insert(fh, entries);
+                        reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO);
                         debug!("Success");
                         Ok(())
                     } else {
                         warn!("Unable to open file as a directory");
-                        reply.error(libc::EACCES);
+                        reply.error(Error::NotDirectory.into());
                         Ok(())
                     }
                 }
@@ -366,9 +620,24 @@ impl fuser::Filesystem for FuseFs {
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
-    fn mknod(
+    fn releasedir(
         &mut self,
         _req: &fuser::Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _timer = self.time("releasedir", || format!("directory {ino} with handle {fh}"));
+        info!("Release directory {ino} with handle {fh}");
+        self.directories.remove(&fh);
+        reply.ok();
+        debug!("Success");
+    }
+
+    fn mknod(
+        &mut self,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
@@ -376,11 +645,19 @@ impl fuser::Filesystem for FuseFs {
         _rdev: u32,
         reply: fuser::ReplyEntry,
     ) {
+        let _timer = self.time("mknod", || format!("{name:?} in directory {parent}"));
         info!("Make node {name:?} in parent directory {parent}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
             let name = name.to_str().unwrap();
-            match RegularFile::new(&self.filesystem, parent, name, mode) {
+            match RegularFile::new(&self.filesystem, parent, name, mode, req.into())
+                .and_then(|file| self.synchronous(true).map(|()| file))
+            {
                 Ok(file) => {
+                    self.remember(file.inode.index)?;
                     reply.entry(
                         &Duration::from_secs(0),
                         &file.inode.attrs(&self.fs_handle()?.superblock),
@@ -401,18 +678,26 @@ impl fuser::Filesystem for FuseFs {
 
     fn mkdir(
         &mut self,
-        _req: &fuser::Request<'_>,
+        req: &fuser::Request<'_>,
         parent: u64,
         name: &std::ffi::OsStr,
         mode: u32,
         _umask: u32,
         reply: fuser::ReplyEntry,
     ) {
+        let _timer = self.time("mkdir", || format!("{name:?} in directory {parent}"));
         info!("Make directory {name:?} in parent directory {parent}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
             let name = name.to_str().unwrap();
-            match Directory::new(&self.filesystem, parent, name, mode) {
+            match Directory::new(&self.filesystem, parent, name, mode, req.into())
+                .and_then(|dir| self.synchronous(true).map(|()| dir))
+            {
                 Ok(dir) => {
+                    self.remember(dir.inode.index)?;
                     reply.entry(
                         &Duration::from_secs(0),
                         &dir.inode.attrs(&self.fs_handle()?.superblock),
@@ -438,12 +723,37 @@ impl fuser::Filesystem for FuseFs {
         name: &std::ffi::OsStr,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("unlink", || format!("{name:?} in directory {parent}"));
         info!("Unlink {name:?} from parent directory {parent}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
             let name = name.to_str().unwrap();
             match Directory::load(&self.filesystem, parent) {
                 Ok(mut dir) => {
-                    match dir.remove_child(crate::filetypes::DirectoryChildIdentifier::Name(name)) {
+                    if let Ok(FileType::Directory) =
+                        dir.child_type(DirectoryChildIdentifier::Name(name))
+                    {
+                        warn!("Unable to unlink a directory");
+                        reply.error(Error::IsDirectory.into());
+                        return Ok(());
+                    }
+                    let referenced = match dir.get_child_inode(DirectoryChildIdentifier::Name(name))
+                    {
+                        Ok(child) if self.references.lock()?.referenced(child) => Some(child),
+                        _ => None,
+                    };
+                    let result = match referenced {
+                        Some(child) => dir
+                            .detach_child(DirectoryChildIdentifier::Inode(child))
+                            .and_then(|child| self.release_unlinked(child)),
+                        None => dir.remove_child(DirectoryChildIdentifier::Name(name)),
+                    };
+                    drop(dir);
+                    let result = result.and_then(|_| self.synchronous(true));
+                    match result {
                         Err(e) => reply.error(e.into()),
                         Ok(_) => {
                             reply.ok();
@@ -462,13 +772,27 @@ impl fuser::Filesystem for FuseFs {
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
+    fn forget(&mut self, _req: &fuser::Request<'_>, ino: u64, nlookup: u64) {
+        let _timer = self.time("forget", || format!("inode {ino}"));
+        info!("Forget {nlookup} lookups of inode {ino}");
+        self.forget_lookups(ino, nlookup)
+            .unwrap_or_else(|e| error!("Unexpected error: {e}"));
+    }
+
+    fn batch_forget(&mut self, _req: &fuser::Request<'_>, nodes: &[fuser::fuse_forget_one]) {
+        let _timer = self.time("forget", || format!("{} inodes", nodes.len()));
+        info!("Forget lookups of {} inodes", nodes.len());
+        for node in nodes {
+            self.forget_lookups(node.nodeid, node.nlookup)
+                .unwrap_or_else(|e| error!("Unexpected error: {e}"));
+        }
+    }
+
     fn destroy(&mut self) {
         info!("Destroying filesystem");
-        let inner = || -> Result<(), Error> {
-            self.fs_handle()?.force_flush()?;
-            Ok(())
-        };
-        inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
+        self.unmount()
+            .unwrap_or_else(|e| error!("Unexpected error: {e}"));
+        invalidation::serve(None);
     }
 
     fn rename(
@@ -478,20 +802,33 @@ impl fuser::Filesystem for FuseFs {
         name: &std::ffi::OsStr,
         newparent: u64,
         newname: &std::ffi::OsStr,
-        _flags: u32,
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("rename", || {
+            format!("{name:?} in directory {parent} to {newname:?} in directory {newparent}")
+        });
         info!("Rename {name:?} to {newname:?}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
         let inner = || -> Result<(), Error> {
             let name = name.to_str().unwrap();
             let new_name = newname.to_str().unwrap();
-            match Directory::load(&self.filesystem, parent)?.transfer_child(
-                crate::filetypes::DirectoryChildIdentifier::Name(name),
-                newparent,
-                new_name,
-            ) {
-                Ok(()) => {
+            let result = self.move_entry(parent, name, newparent, new_name, flags);
+            match result {
+                Ok(replaced) => {
+                    if let Some(replaced) = replaced {
+                        self.release_unlinked(replaced)?;
+                    }
+                    if let Err(e) = self.synchronous(true) {
+                        warn!("Error: {e}");
+                        reply.error(e.into());
+                        return Ok(());
+                    }
                     reply.ok();
+                    debug!("Success");
                     Ok(())
                 }
                 Err(e) => {
@@ -512,9 +849,10 @@ impl fuser::Filesystem for FuseFs {
         _lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("flush", || format!("inode {ino}"));
         info!("Filesystem flush requested for inode {ino}");
         let inner = || -> Result<(), Error> {
-            match self.fs_handle()?.flush() {
+            match self.write_back(ino).and_then(|_| self.fs_handle()?.flush()) {
                 Ok(()) => {
                     debug!("Success");
                     reply.ok();
@@ -534,12 +872,18 @@ impl fuser::Filesystem for FuseFs {
         _req: &fuser::Request<'_>,
         ino: u64,
         _fh: u64,
-        _datasync: bool,
+        datasync: bool,
         reply: fuser::ReplyEmpty,
     ) {
+        let _timer = self.time("fsync", || format!("inode {ino}"));
         info!("Filesystem flush requested for inode {ino}");
         let inner = || -> Result<(), Error> {
-            match self.fs_handle()?.force_flush() {
+            // Flushing syncs data of the device, and a barrier its metadata as well
+            let result = self.write_back(ino).and_then(|_| match datasync {
+                true => self.fs_handle()?.force_flush(),
+                false => self.fs_handle()?.barrier(),
+            });
+            match result {
                 Ok(()) => {
                     debug!("Success");
                     reply.ok();
@@ -554,23 +898,345 @@ impl fuser::Filesystem for FuseFs {
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
 
-    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-        info!("Get filesystem statistics");
+    fn ioctl(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        let _timer = self.time("ioctl", || format!("command {cmd:#x} for inode {ino}"));
+        info!("Control command {cmd:#x} for inode {ino}");
         let inner = || -> Result<(), Error> {
-            let spb = &self.fs_handle()?.superblock;
-            let padded_block_size = spb.block_size - 8;
-            reply.statfs(
-                spb.block_count,
-                spb.blocks_free,
-                spb.blocks_free,
-                spb.inode_count - spb.inodes_free,
-                spb.inodes_free,
-                padded_block_size,
-                u16::MAX as u32,
-                padded_block_size,
-            );
+            if FS_IOC_GETFLAGS.contains(&cmd) {
+                match self.session()?.load_inode(ino) {
+                    Ok(inode) => {
+                        let flags = (inode.flags as u64).to_le_bytes();
+                        reply.ioctl(0, &flags[..(out_size as usize).min(flags.len())]);
+                        debug!("Success");
+                    }
+                    Err(e) => {
+                        warn!("Error: {e}");
+                        reply.error(e.into());
+                    }
+                }
+                return Ok(());
+            }
+            if TANANFS_IOC_COMMANDS.contains(&cmd) {
+                match self.control(req.uid(), ino, cmd) {
+                    Ok(data) => {
+                        reply.ioctl(0, &data[..(out_size as usize).min(data.len())]);
+                        debug!("Success");
+                    }
+                    Err(e) => {
+                        warn!("Error: {e}");
+                        reply.error(e.into());
+                    }
+                }
+                return Ok(());
+            }
+            if !FS_IOC_SETFLAGS.contains(&cmd) {
+                warn!("Unsupported control command {cmd:#x}");
+                reply.error(libc::ENOTTY);
+                return Ok(());
+            }
+            let mut set_flags = || -> Result<(), Error> {
+                self.writable()?;
+                let raw = in_data.get(..4).ok_or(Error::InvalidArgument)?;
+                let flags = u32::from_le_bytes(raw.try_into()?);
+                debug!("Setting flags to {flags:#x}");
+                if flags & !FLAGS_SUPPORTED != 0 {
+                    return Err(Error::Incompatible);
+                }
+                // Delayed writes would no longer be permitted once file is protected
+                self.write_back(ino)?;
+                let mut session = self.session()?;
+                let mut inode = session.load_inode(ino)?;
+                if flags == inode.flags {
+                    return Ok(());
+                }
+                // Only privileged users may protect files or lift the protection
+                let protection = (flags ^ inode.flags) & (FLAG_IMMUTABLE | FLAG_APPEND_ONLY);
+                if req.uid() != 0 && (protection != 0 || req.uid() != inode.uid) {
+                    return Err(Error::NotPermitted);
+                }
+                if session.superblock.inode_size() < std::mem::size_of::<Inode>() as u64 {
+                    return Err(Error::Incompatible);
+                }
+                inode.flags = flags;
+                inode.set_ctime(timestamp_now());
+                session.stage_inode(inode);
+                session.commit()
+            };
+            match set_flags() {
+                Ok(()) => {
+                    reply.ioctl(0, &[]);
+                    debug!("Success");
+                }
+                Err(e) => {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                }
+            }
             Ok(())
         };
         inner().unwrap_or_else(|e| error!("Unexpected error: {e}"));
     }
+
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        let _timer = self.time("statfs", || "filesystem".to_owned());
+        info!("Get filesystem statistics");
+        match self.usage() {
+            Ok(usage) => {
+                reply.statfs(
+                    usage.blocks,
+                    usage.blocks_free,
+                    usage.blocks_available,
+                    usage.files,
+                    usage.files_free,
+                    usage.block_size,
+                    usage.name_length,
+                    usage.block_size,
+                );
+                debug!("Success");
+            }
+            Err(e) => {
+                warn!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _timer = self.time("setxattr", || format!("{name:?} of inode {ino}"));
+        info!("Set extended attribute {name:?} of inode {ino}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
+        if let Some((kind, id)) = quota_xattr(ino, name) {
+            let result = match quota_limits(value) {
+                None => Err(Error::InvalidArgument),
+                Some(_) if req.uid() != 0 => Err(Error::NotPermitted),
+                Some((blocks, inodes)) => self.fs_handle().and_then(|mut fs_handle| {
+                    fs_handle.quotas.set_limits(kind, id, blocks, inodes)?;
+                    fs_handle.flush()
+                }),
+            };
+            return match result {
+                Ok(()) => {
+                    reply.ok();
+                    debug!("Success");
+                }
+                Err(e) => {
+                    warn!("Error: {e}");
+                    reply.error(e.into());
+                }
+            };
+        }
+        if name != MODE_MASK_XATTR {
+            warn!("Unsupported extended attribute");
+            return reply.error(libc::ENOTSUP);
+        }
+        let Some(mask) = std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| u32::from_str_radix(value.trim(), 8).ok())
+        else {
+            warn!("Invalid mode mask {value:?}");
+            return reply.error(libc::EINVAL);
+        };
+        let inner = || -> Result<(), Error> {
+            if self.fs_handle()?.load_inode(ino)?.r#type != FileType::Directory {
+                return Err(Error::NotDirectory);
+            }
+            let mut dir = Directory::load(&self.filesystem, ino)?;
+            debug!("Setting mode mask to {mask:0o}");
+            dir.set_mode_mask(Some(mask));
+            dir.flush()
+        };
+        match inner().and_then(|()| self.synchronous(false)) {
+            Ok(()) => {
+                reply.ok();
+                debug!("Success");
+            }
+            Err(e) => {
+                warn!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _timer = self.time("getxattr", || format!("{name:?} of inode {ino}"));
+        info!("Get extended attribute {name:?} of inode {ino}");
+        let inner = || -> Result<Option<String>, Error> {
+            if name == HEALTH_XATTR && ino == ROOT_INODE {
+                return Ok(Some(self.fs_handle()?.health.state().to_string()));
+            }
+            if name == MEMORY_XATTR && ino == ROOT_INODE {
+                return Ok(Some(self.memory_usage()?.to_string()));
+            }
+            if let Some((kind, id)) = quota_xattr(ino, name) {
+                let fs_handle = self.fs_handle()?;
+                let enabled = fs_handle.quotas.enabled();
+                return Ok(enabled.then(|| fs_handle.quotas.get(kind, id).to_string()));
+            }
+            if name != MODE_MASK_XATTR
+                || self.fs_handle()?.load_inode(ino)?.r#type != FileType::Directory
+            {
+                return Ok(None);
+            }
+            let mask = Directory::load(&self.filesystem, ino)?.mode_mask();
+            Ok(mask.map(|mask| format!("{mask:04o}")))
+        };
+        match inner() {
+            Ok(Some(value)) => {
+                if size == 0 {
+                    reply.size(value.len() as u32);
+                } else if (size as usize) < value.len() {
+                    reply.error(libc::ERANGE);
+                } else {
+                    reply.data(value.as_bytes());
+                }
+                debug!("Success");
+            }
+            Ok(None) => reply.error(libc::ENODATA),
+            Err(e) => {
+                warn!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn listxattr(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let _timer = self.time("listxattr", || format!("inode {ino}"));
+        info!("List extended attributes of inode {ino}");
+        let inner = || -> Result<Vec<u8>, Error> {
+            let mut names = Vec::new();
+            if ino == ROOT_INODE {
+                for name in [HEALTH_XATTR, MEMORY_XATTR] {
+                    names.extend_from_slice(name.as_bytes());
+                    names.push(0);
+                }
+                for (kind, id, _) in self.fs_handle()?.quotas.entries() {
+                    let kind = match kind {
+                        QuotaKind::User => "user",
+                        QuotaKind::Group => "group",
+                    };
+                    names.extend_from_slice(format!("{QUOTA_XATTR}{kind}.{id}").as_bytes());
+                    names.push(0);
+                }
+            }
+            if self.fs_handle()?.load_inode(ino)?.r#type == FileType::Directory
+                && Directory::load(&self.filesystem, ino)?
+                    .mode_mask()
+                    .is_some()
+            {
+                names.extend_from_slice(MODE_MASK_XATTR.as_bytes());
+                names.push(0);
+            }
+            Ok(names)
+        };
+        match inner() {
+            Ok(names) => {
+                if size == 0 {
+                    reply.size(names.len() as u32);
+                } else if (size as usize) < names.len() {
+                    reply.error(libc::ERANGE);
+                } else {
+                    reply.data(&names);
+                }
+                debug!("Success");
+            }
+            Err(e) => {
+                warn!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
+
+    fn removexattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        name: &std::ffi::OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let _timer = self.time("removexattr", || format!("{name:?} of inode {ino}"));
+        info!("Remove extended attribute {name:?} of inode {ino}");
+        if let Err(e) = self.writable() {
+            warn!("Error: {e}");
+            return reply.error(e.into());
+        }
+        let inner = || -> Result<bool, Error> {
+            if let Some((kind, id)) = quota_xattr(ino, name) {
+                if req.uid() != 0 {
+                    return Err(Error::NotPermitted);
+                }
+                let mut fs_handle = self.fs_handle()?;
+                let quota = fs_handle.quotas.get(kind, id);
+                if quota.block_limit == 0 && quota.inode_limit == 0 {
+                    return Ok(false);
+                }
+                fs_handle.quotas.set_limits(kind, id, 0, 0)?;
+                fs_handle.flush()?;
+                return Ok(true);
+            }
+            if name != MODE_MASK_XATTR
+                || self.fs_handle()?.load_inode(ino)?.r#type != FileType::Directory
+            {
+                return Ok(false);
+            }
+            let mut dir = Directory::load(&self.filesystem, ino)?;
+            if dir.mode_mask().is_none() {
+                return Ok(false);
+            }
+            dir.set_mode_mask(None);
+            dir.flush()?;
+            Ok(true)
+        };
+        let result = inner().and_then(|removed| {
+            if removed {
+                self.synchronous(false)?;
+            }
+            Ok(removed)
+        });
+        match result {
+            Ok(true) => {
+                reply.ok();
+                debug!("Success");
+            }
+            Ok(false) => reply.error(libc::ENODATA),
+            Err(e) => {
+                warn!("Error: {e}");
+                reply.error(e.into());
+            }
+        }
+    }
 }
@@ -0,0 +1,123 @@
+//! Health of a filesystem, worsened by device and checksum errors
+//!
+//! The first failed read or checksum mismatch marks the filesystem degraded.
+//! Once too many errors accumulate, or a flush fails to reach the device, the
+//! filesystem stops accepting modifications to avoid making the damage worse.
+
+use std::fmt::Display;
+
+use log::{error, warn};
+
+use crate::Error;
+
+/// Number of read errors after which the filesystem becomes read-only
+pub const MAX_READ_ERRORS: u64 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    #[default]
+    Clean,
+    /// Some data could not be read or failed verification
+    Degraded,
+    /// Modifications are rejected due to errors
+    ReadOnly,
+}
+
+impl Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Clean => write!(f, "clean"),
+            Self::Degraded => write!(f, "degraded"),
+            Self::ReadOnly => write!(f, "read-only"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HealthMonitor {
+    state: Health,
+    /// Number of failed reads and checksum mismatches
+    errors: u64,
+}
+
+impl HealthMonitor {
+    pub fn state(&self) -> Health {
+        self.state
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Account result of reading from the device
+    pub fn check_read<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(Error::Io(_) | Error::Corruption) = result {
+            self.errors += 1;
+            match self.errors >= MAX_READ_ERRORS {
+                true => self.transition(Health::ReadOnly),
+                false => self.transition(Health::Degraded),
+            }
+        }
+        result
+    }
+
+    /// Account result of writing to the device
+    pub fn check_write<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(Error::Io(_)) = result {
+            self.errors += 1;
+            self.transition(Health::ReadOnly);
+        }
+        result
+    }
+
+    /// Worsen the state, never recovering from errors while mounted
+    fn transition(&mut self, state: Health) {
+        if state <= self.state {
+            return;
+        }
+        match state {
+            Health::ReadOnly => error!(
+                "Filesystem health changed from {} to {state} after {} errors",
+                self.state, self.errors
+            ),
+            _ => warn!(
+                "Filesystem health changed from {} to {state} after {} errors",
+                self.state, self.errors
+            ),
+        }
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Health, HealthMonitor, MAX_READ_ERRORS};
+    use crate::Error;
+
+    fn io_error() -> Result<(), Error> {
+        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into())
+    }
+
+    #[test]
+    fn transitions() {
+        let mut health = HealthMonitor::default();
+        assert!(health.check_read(Err::<(), _>(Error::NotFound)).is_err());
+        assert!(health.check_read(Ok(())).is_ok());
+        assert_eq!(health.state(), Health::Clean);
+        assert!(health.check_read(Err::<(), _>(Error::Corruption)).is_err());
+        assert_eq!(health.state(), Health::Degraded);
+        for _ in 1..MAX_READ_ERRORS - 1 {
+            assert!(health.check_read(io_error()).is_err());
+        }
+        assert_eq!(health.state(), Health::Degraded);
+        assert!(health.check_read(io_error()).is_err());
+        assert_eq!(health.state(), Health::ReadOnly);
+
+        let mut health = HealthMonitor::default();
+        assert!(health.check_write(io_error()).is_err());
+        assert_eq!(health.state(), Health::ReadOnly);
+        assert!(health.check_read(Err::<(), _>(Error::Corruption)).is_err());
+        assert_eq!(health.state(), Health::ReadOnly);
+        assert_eq!(health.errors(), 2);
+    }
+}
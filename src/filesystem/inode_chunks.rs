@@ -0,0 +1,155 @@
+//! Chunks of inodes allocated from the block region
+//!
+//! The inode table is sized by the bytes-per-inode ratio chosen when
+//! formatting, so a filesystem holding many small files may run out of inodes
+//! while it still has plenty of free blocks. With [INCOMPAT_INODE_CHUNKS],
+//! a full table is followed by chunks of [INODE_CHUNK_SIZE] inodes, each
+//! taking a run of consecutive blocks whose first block holds the bitmap of
+//! its inodes. First blocks of chunks are recorded in the superblock, and
+//! indexes of their inodes follow all indexes of the inode bitmap, so inodes
+//! of the table keep their indexes. Chunks are never freed, and their blocks
+//! have no checksums, as inodes carry their own.
+
+use log::info;
+
+use super::Filesystem;
+use crate::structs::{INCOMPAT_INODE_CHUNKS, INODE_CHUNK_SIZE, MAX_INODE_CHUNKS};
+use crate::Error;
+
+impl Filesystem {
+    /// Index of the first free inode of the table or its chunks
+    pub(super) fn free_inode(&self) -> Option<u64> {
+        let index = self.inodes.next_free(0)?;
+        match index < self.superblock.inode_count {
+            true => Some(index),
+            false => self.inodes.next_free(self.superblock.first_chunk_inode()),
+        }
+    }
+
+    /// Allocate another chunk of inodes, returning index of its first inode
+    ///
+    /// Fails with [Error::OutOfMemory] if the filesystem has no chunks, has
+    /// as many as the superblock records or no run of free blocks fits one.
+    pub(super) fn add_inode_chunk(&mut self) -> Result<u64, Error> {
+        let chunk = self.superblock.inode_chunk_count as usize;
+        if self.superblock.incompat_flags & INCOMPAT_INODE_CHUNKS == 0 || chunk >= MAX_INODE_CHUNKS
+        {
+            return Err(Error::OutOfMemory);
+        }
+        let length = self.superblock.inode_chunk_blocks();
+        let hint = self.superblock.block_allocation_hint;
+        if self.blocks.next_free_range(hint, length).is_none()
+            && self.blocks.next_free_range(0, length).is_none()
+        {
+            return Err(Error::OutOfMemory);
+        }
+        let blocks = self.allocate_blocks(length)?;
+        debug_assert!(blocks.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        for &index in blocks.iter() {
+            // Stale copy of a previous owner must not overwrite it
+            self.cache.remove_block(index);
+        }
+        let mut chunks = self.superblock.inode_chunks;
+        chunks[chunk] = blocks[0];
+        self.superblock.inode_chunks = chunks;
+        self.superblock.inode_chunk_count += 1;
+        self.superblock.inodes_free += INODE_CHUNK_SIZE;
+        let position = self.superblock.inode_chunk_bitmap(chunk)?;
+        self.inodes.extend(position);
+        let first = self.superblock.first_chunk_inode() + chunk as u64 * INODE_CHUNK_SIZE;
+        info!(
+            "Allocated chunk {chunk} of inodes {first} to {} at block {}",
+            first + INODE_CHUNK_SIZE - 1,
+            blocks[0]
+        );
+        Ok(first)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+    use crate::structs::{Superblock, INODE_CHUNK_SIZE};
+    use crate::Error;
+
+    fn filesystem(chunks: bool) -> Arc<Mutex<Filesystem>> {
+        let mut superblock = Superblock::with_inode_ratio(4_000_000, 512, 512, 400_000);
+        superblock
+            .set_feature("inode_chunks", chunks, 4_000_000)
+            .unwrap();
+        // Room to grow into
+        let dev = Cursor::new(vec![0u8; 6_000_000]);
+        let fs = Filesystem::from_superblock(Box::new(dev), superblock);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        fs
+    }
+
+    #[test]
+    fn allocate_inode_chunks() {
+        let fs = filesystem(false);
+        let inode_count = fs.lock().unwrap().superblock.inode_count;
+        let mut created = 0;
+        let error = loop {
+            match RegularFile::new(
+                &fs,
+                ROOT_INODE,
+                &created.to_string(),
+                0o640,
+                Owner::default(),
+            ) {
+                Ok(_) => created += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, Error::OutOfMemory));
+        assert_eq!(created, inode_count - 2);
+
+        let fs = filesystem(true);
+        let files = inode_count + 10;
+        for name in 0..files {
+            let mut file =
+                RegularFile::new(&fs, ROOT_INODE, &name.to_string(), 0o640, Owner::default())
+                    .unwrap();
+            file.write(0, &name.to_le_bytes()).unwrap();
+        }
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        fs.force_flush().unwrap();
+        assert_eq!({ fs.superblock.inode_chunk_count }, 1);
+        let first = fs.superblock.first_chunk_inode();
+        assert!(fs.inodes.get(first).unwrap());
+        assert!(!fs.inodes.get(first - 1).unwrap());
+        assert_eq!(
+            { fs.superblock.inodes_free },
+            inode_count + INODE_CHUNK_SIZE - files - 2
+        );
+
+        let fs = Arc::new(Mutex::new(Filesystem::load(fs.device, 512).unwrap()));
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len() as u64, files);
+        for child in root.children.iter() {
+            let mut file = RegularFile::load(&fs, child.inode).unwrap();
+            assert_eq!(
+                file.read(0, 8).unwrap(),
+                child.name.parse::<u64>().unwrap().to_le_bytes()
+            );
+        }
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert_eq!(fs.lock().unwrap().scrub().unwrap().damaged, 0);
+
+        // Bitmap of the chunk moves along with the block region
+        drop(root);
+        fs.lock().unwrap().grow(6_000_000).unwrap();
+        RegularFile::new(&fs, ROOT_INODE, "grown", 0o640, Owner::default()).unwrap();
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        fs.force_flush().unwrap();
+        let fs = Arc::new(Mutex::new(Filesystem::load(fs.device, 512).unwrap()));
+        let index = Directory::find(&fs, ROOT_INODE, "grown").unwrap();
+        assert_eq!(index, first + 12);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,63 @@
+//! Notifications about modified inodes and directory entries
+//!
+//! Every flushed inode is reported with [Filesystem::invalidate](super::Filesystem::invalidate),
+//! and every name added to or removed from a directory with
+//! [Filesystem::invalidate_entry](super::Filesystem::invalidate_entry). Each
+//! registered callback then learns that its cached view of them is stale,
+//! along with the FUSE mount which made the change, if any. The kernel already
+//! knows about changes made through its own mount, so they only concern other
+//! mounts and writers using the library directly.
+//!
+//! Callbacks run while the filesystem is locked, so they must not lock it again.
+
+use std::cell::Cell;
+use std::fmt::Debug;
+
+/// Cached view of the filesystem which became stale
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    /// Attributes and contents of an inode
+    Inode(u64),
+    /// Name in a directory, added, removed or pointing to another inode
+    Entry { parent: u64, name: String },
+}
+
+type Callback = Box<dyn Fn(Option<usize>, &Invalidation) + Send>;
+
+thread_local! {
+    /// Mount whose FUSE requests are served by this thread
+    static ORIGIN: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Attribute further changes made by this thread to FUSE mount `mount`, or to none
+pub(crate) fn serve(mount: Option<usize>) {
+    ORIGIN.set(mount);
+}
+
+#[derive(Default)]
+pub struct Invalidations {
+    callbacks: Vec<Callback>,
+}
+
+impl Invalidations {
+    /// Call `callback` with the mount making every change, and what it invalidated
+    pub fn register(&mut self, callback: impl Fn(Option<usize>, &Invalidation) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Notify all registered callbacks about a change made by this thread
+    pub fn notify(&self, invalidation: Invalidation) {
+        let origin = ORIGIN.get();
+        for callback in self.callbacks.iter() {
+            callback(origin, &invalidation);
+        }
+    }
+}
+
+impl Debug for Invalidations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Invalidations")
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
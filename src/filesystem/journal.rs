@@ -0,0 +1,803 @@
+//! Write-ahead journal of metadata updates
+//!
+//! Every [force flush](super::Filesystem::force_flush) collects its writes of
+//! superblock, bitmaps, inodes and blocks into a [Transaction]. The transaction
+//! is first written to the journal region following the superblock, committed
+//! by writing the journal header, and only then written in place. Once all
+//! writes are in place, the header is cleared. Each of these steps waits for
+//! a [barrier](BlockDevice::barrier), so the device cannot reorder them.
+//! Blocks of a transaction too large for the journal are committed first, in
+//! as many parts as needed, while metadata exceeding it is rejected.
+//!
+//! A crash before the header is written leaves the previous state intact, and
+//! a crash after it is repaired by replaying the journal on [load](super::Filesystem::load),
+//! so bitmaps never disagree with inodes and blocks referring to them.
+//!
+//! Filesystems with [INCOMPAT_JOURNAL_HISTORY] split the journal in half. The
+//! second half is a ring of undo records: before a transaction is committed,
+//! data it overwrites is kept there under the transaction's sequence number.
+//! Undoing the newest transactions in reverse order [rewinds](rewind) the
+//! device to the state after an earlier one, as long as its successors are
+//! still in the ring.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::{debug, error, info, warn};
+
+use super::BlockDevice;
+use crate::structs::{ChecksumAlgorithm, Superblock, INCOMPAT_JOURNAL_HISTORY};
+use crate::Error;
+
+/// Magic signature of a committed journal header
+pub const JOURNAL_MAGIC: u64 = 0x4C4E4A6E616E6154;
+/// Magic signature of the index of undo records following the journal header
+pub const HISTORY_MAGIC: u64 = 0x54534A6E616E6154;
+/// Granularity of comparison with data already on the device
+pub const JOURNAL_CHUNK: usize = 64;
+/// Bytes preceding data of every journal record: position and length
+const RECORD_HEADER: u64 = 16;
+
+/// Header stored in the first block of the journal region
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    magic: u64,
+    records: u64,
+    length: u64,
+    checksum: u32,
+}
+
+impl Header {
+    const SIZE: usize = 28;
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.records.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.length.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, Error> {
+        Ok(Self {
+            magic: u64::from_le_bytes(bytes[0..8].try_into()?),
+            records: u64::from_le_bytes(bytes[8..16].try_into()?),
+            length: u64::from_le_bytes(bytes[16..24].try_into()?),
+            checksum: u32::from_le_bytes(bytes[24..28].try_into()?),
+        })
+    }
+}
+
+/// Location of undo records of a single transaction in the history ring
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    sequence: u64,
+    offset: u64,
+    records: u64,
+    length: u64,
+    checksum: u32,
+}
+
+impl Entry {
+    const SIZE: usize = 36;
+
+    fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.records.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.length.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            sequence: u64::from_le_bytes(bytes[0..8].try_into()?),
+            offset: u64::from_le_bytes(bytes[8..16].try_into()?),
+            records: u64::from_le_bytes(bytes[16..24].try_into()?),
+            length: u64::from_le_bytes(bytes[24..32].try_into()?),
+            checksum: u32::from_le_bytes(bytes[32..36].try_into()?),
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Index of the history ring, stored in the journal header block after [Header]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct History {
+    /// Sequence number of the last committed transaction
+    pub sequence: u64,
+    /// Undo records of the last transactions, oldest first
+    entries: Vec<Entry>,
+}
+
+impl History {
+    /// Bytes of magic, sequence and number of entries
+    const SIZE: usize = 20;
+
+    /// Largest number of entries fitting in the journal header block
+    fn max_entries(superblock: &Superblock) -> usize {
+        (superblock.block_size as usize - Header::SIZE - Self::SIZE) / Entry::SIZE
+    }
+
+    fn position(superblock: &Superblock) -> u64 {
+        superblock.journal_region_start() + Header::SIZE as u64
+    }
+
+    /// Sequence number of the earliest transaction the device can be rewound to
+    pub fn oldest(&self) -> u64 {
+        self.entries
+            .first()
+            .map_or(self.sequence, |entry| entry.sequence - 1)
+    }
+
+    fn load<D: Read + Seek>(device: &mut D, superblock: &Superblock) -> Result<Self, Error> {
+        let mut bytes = vec![0u8; superblock.block_size as usize - Header::SIZE];
+        device.seek(SeekFrom::Start(Self::position(superblock)))?;
+        device.read_exact(&mut bytes)?;
+        if u64::from_le_bytes(bytes[0..8].try_into()?) != HISTORY_MAGIC {
+            return Ok(Self::default());
+        }
+        let sequence = u64::from_le_bytes(bytes[8..16].try_into()?);
+        let count = u32::from_le_bytes(bytes[16..20].try_into()?) as usize;
+        if count > Self::max_entries(superblock) {
+            warn!("Discarding journal history with {count} entries");
+            return Ok(Self {
+                sequence,
+                entries: Vec::new(),
+            });
+        }
+        let entries = (0..count)
+            .map(|entry| {
+                let start = Self::SIZE + entry * Entry::SIZE;
+                Entry::from_bytes(&bytes[start..start + Entry::SIZE])
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { sequence, entries })
+    }
+
+    fn flush<D: BlockDevice>(&self, device: &mut D, superblock: &Superblock) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(Self::SIZE + self.entries.len() * Entry::SIZE);
+        bytes.extend_from_slice(&HISTORY_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in self.entries.iter() {
+            bytes.extend_from_slice(&entry.to_bytes());
+        }
+        device.seek(SeekFrom::Start(Self::position(superblock)))?;
+        device.write_all(&bytes)?;
+        device.barrier()?;
+        Ok(())
+    }
+
+    /// Keep undo records of the next transaction in the ring, evicting the oldest ones
+    fn remember<D: BlockDevice>(
+        &mut self,
+        device: &mut D,
+        superblock: &Superblock,
+        undo: &Transaction,
+    ) -> Result<(), Error> {
+        self.sequence += 1;
+        let length = undo.length();
+        let area = history_capacity(superblock);
+        if length > area {
+            warn!(
+                "Transaction {} exceeds journal history, forgetting earlier transactions",
+                self.sequence
+            );
+            self.entries.clear();
+            return self.flush(device, superblock);
+        }
+        let mut offset = self.entries.last().map_or(0, Entry::end);
+        if offset + length > area {
+            offset = 0;
+        }
+        // Ring is written in order, so anything older than an overwritten entry is gone too
+        if let Some(last) = self
+            .entries
+            .iter()
+            .rposition(|entry| entry.offset < offset + length && entry.end() > offset)
+        {
+            self.entries.drain(..=last);
+        }
+        if self.entries.len() >= Self::max_entries(superblock) {
+            self.entries.remove(0);
+        }
+        let payload = undo.to_bytes();
+        device.seek(SeekFrom::Start(history_start(superblock) + offset))?;
+        device.write_all(&payload)?;
+        self.entries.push(Entry {
+            sequence: self.sequence,
+            offset,
+            records: undo.writes.len() as u64,
+            length,
+            checksum: checksum(&payload),
+        });
+        self.flush(device, superblock)
+    }
+
+    /// Undo records of a single transaction
+    fn read<D: Read + Seek>(
+        device: &mut D,
+        superblock: &Superblock,
+        entry: &Entry,
+    ) -> Result<Transaction, Error> {
+        let mut payload = vec![0u8; entry.length as usize];
+        device.seek(SeekFrom::Start(history_start(superblock) + entry.offset))?;
+        device.read_exact(&mut payload)?;
+        if checksum(&payload) != entry.checksum {
+            warn!("Undo records of transaction {} are damaged", entry.sequence);
+            return Err(Error::Corruption);
+        }
+        Transaction::from_bytes(&payload, entry.records)
+    }
+}
+
+/// Writes collected by a single flush, applied to the device atomically
+#[derive(Debug, Default)]
+pub(crate) struct Transaction {
+    position: u64,
+    writes: Vec<(u64, Vec<u8>)>,
+}
+
+impl Write for Transaction {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writes.push((self.position, buf.to_vec()));
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Transaction {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+            SeekFrom::End(_) => return Err(std::io::ErrorKind::Unsupported.into()),
+        };
+        Ok(self.position)
+    }
+}
+
+impl Transaction {
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Size of transaction's records in the journal
+    fn length(&self) -> u64 {
+        self.writes
+            .iter()
+            .map(|(_, data)| RECORD_HEADER + data.len() as u64)
+            .sum()
+    }
+
+    /// Drop parts of writes equal to data already on the device
+    ///
+    /// Bitmaps and the superblock are always written whole, but only a few of
+    /// their bytes change between two flushes.
+    pub fn prune<D: Read + Seek>(&mut self, device: &mut D) -> Result<(), Error> {
+        let mut pruned = Vec::with_capacity(self.writes.len());
+        for (position, data) in self.writes.drain(..) {
+            let mut existing = vec![0u8; data.len()];
+            device.seek(SeekFrom::Start(position))?;
+            device.read_exact(&mut existing)?;
+            let mut run: Option<usize> = None;
+            for (chunk, start) in (0..data.len()).step_by(JOURNAL_CHUNK).enumerate() {
+                let end = (start + JOURNAL_CHUNK).min(data.len());
+                let changed = data[start..end] != existing[start..end];
+                match (changed, run) {
+                    (true, None) => run = Some(chunk * JOURNAL_CHUNK),
+                    (false, Some(from)) => {
+                        pruned.push((position + from as u64, data[from..start].to_vec()));
+                        run = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(from) = run {
+                pruned.push((position + from as u64, data[from..].to_vec()));
+            }
+        }
+        self.writes = pruned;
+        Ok(())
+    }
+
+    /// Data currently on the device at positions of all writes
+    pub fn undo<D: Read + Seek>(&self, device: &mut D) -> Result<Self, Error> {
+        let mut writes = Vec::with_capacity(self.writes.len());
+        for (position, data) in self.writes.iter() {
+            let mut existing = vec![0u8; data.len()];
+            device.seek(SeekFrom::Start(*position))?;
+            device.read_exact(&mut existing)?;
+            writes.push((*position, existing));
+        }
+        Ok(Self {
+            position: 0,
+            writes,
+        })
+    }
+
+    /// Split off writes at or after `position`
+    pub fn split_off(&mut self, position: u64) -> Self {
+        let (after, before) = self.writes.drain(..).partition(|(p, _)| *p >= position);
+        self.writes = before;
+        Self {
+            position: 0,
+            writes: after,
+        }
+    }
+
+    /// Split into transactions whose records fit in `capacity` bytes of the journal
+    ///
+    /// Writes too large for a single transaction are cut into several.
+    fn chunks(self, capacity: u64) -> Vec<Self> {
+        let largest = capacity.saturating_sub(RECORD_HEADER).max(1) as usize;
+        let mut chunks = vec![Self::default()];
+        for (position, data) in self.writes {
+            for (offset, part) in data.chunks(largest).enumerate() {
+                let last = chunks.last_mut().expect("chunks are never empty");
+                if !last.is_empty() && last.length() + RECORD_HEADER + part.len() as u64 > capacity
+                {
+                    chunks.push(Self::default());
+                }
+                let last = chunks.last_mut().expect("chunks are never empty");
+                last.writes
+                    .push((position + (offset * largest) as u64, part.to_vec()));
+            }
+        }
+        chunks.retain(|chunk| !chunk.is_empty());
+        chunks
+    }
+
+    /// Write all records in place
+    fn apply<D: Write + Seek>(&self, device: &mut D) -> Result<(), Error> {
+        for (position, data) in self.writes.iter() {
+            device.seek(SeekFrom::Start(*position))?;
+            device.write_all(data)?;
+        }
+        device.flush()?;
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.length() as usize);
+        for (position, data) in self.writes.iter() {
+            bytes.extend_from_slice(&position.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], records: u64) -> Result<Self, Error> {
+        let mut writes = Vec::with_capacity(records as usize);
+        let mut offset = 0;
+        for _ in 0..records {
+            let header = bytes
+                .get(offset..offset + RECORD_HEADER as usize)
+                .ok_or(Error::Corruption)?;
+            let position = u64::from_le_bytes(header[0..8].try_into()?);
+            let length = u64::from_le_bytes(header[8..16].try_into()?) as usize;
+            offset += RECORD_HEADER as usize;
+            let data = bytes
+                .get(offset..offset + length)
+                .ok_or(Error::Corruption)?;
+            writes.push((position, data.to_vec()));
+            offset += length;
+        }
+        Ok(Self {
+            position: 0,
+            writes,
+        })
+    }
+}
+
+fn has_history(superblock: &Superblock) -> bool {
+    superblock.journal_blocks > 0 && superblock.incompat_flags & INCOMPAT_JOURNAL_HISTORY != 0
+}
+
+/// Space for records in the journal and history ring, following the header block
+fn records_capacity(superblock: &Superblock) -> u64 {
+    superblock
+        .journal_size()
+        .saturating_sub(superblock.block_size as u64)
+}
+
+/// Space for records in the journal, following the header block
+fn capacity(superblock: &Superblock) -> u64 {
+    match has_history(superblock) {
+        true => records_capacity(superblock) / 2,
+        false => records_capacity(superblock),
+    }
+}
+
+/// Space for undo records in the history ring, following the journal records
+fn history_capacity(superblock: &Superblock) -> u64 {
+    records_capacity(superblock) - capacity(superblock)
+}
+
+fn history_start(superblock: &Superblock) -> u64 {
+    superblock.journal_region_start() + superblock.block_size as u64 + capacity(superblock)
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    ChecksumAlgorithm::Crc32c.checksummer().checksum(data)
+}
+
+/// Write journal header, waiting until it is stored durably
+///
+/// The header commits or clears a transaction, so records preceding it must
+/// reach the device first, and writes in place may only follow it.
+fn write_header<D: BlockDevice>(
+    device: &mut D,
+    superblock: &Superblock,
+    header: Header,
+) -> Result<(), Error> {
+    device.seek(SeekFrom::Start(superblock.journal_region_start()))?;
+    device.write_all(&header.to_bytes())?;
+    device.barrier()?;
+    Ok(())
+}
+
+/// Write transaction to the journal and commit it
+/// Returns false if it does not fit in the journal
+pub(crate) fn log<D: BlockDevice>(
+    device: &mut D,
+    superblock: &Superblock,
+    transaction: &Transaction,
+) -> Result<bool, Error> {
+    let length = transaction.length();
+    if length > capacity(superblock) {
+        return Ok(false);
+    }
+    debug!(
+        "Journaling {} writes in {length} bytes",
+        transaction.writes.len()
+    );
+    let payload = transaction.to_bytes();
+    device.seek(SeekFrom::Start(
+        superblock.journal_region_start() + superblock.block_size as u64,
+    ))?;
+    device.write_all(&payload)?;
+    device.barrier()?;
+    write_header(
+        device,
+        superblock,
+        Header {
+            magic: JOURNAL_MAGIC,
+            records: transaction.writes.len() as u64,
+            length,
+            checksum: checksum(&payload),
+        },
+    )?;
+    Ok(true)
+}
+
+/// Atomically write transaction to the device through the journal
+pub(crate) fn commit(
+    device: &mut Box<dyn BlockDevice>,
+    superblock: &Superblock,
+    mut transaction: Transaction,
+) -> Result<(), Error> {
+    if superblock.journal_blocks == 0 {
+        return transaction.apply(device);
+    }
+    transaction.prune(device)?;
+    if transaction.is_empty() {
+        return Ok(());
+    }
+    let blocks = match transaction.length() > capacity(superblock) {
+        true => transaction.split_off(superblock.block_region_start()),
+        false => Transaction::default(),
+    };
+    if transaction.length() > capacity(superblock) {
+        error!(
+            "Metadata of {} bytes exceeds journal of {} bytes",
+            transaction.length(),
+            capacity(superblock)
+        );
+        return Err(Error::OutOfMemory);
+    }
+    if has_history(superblock) {
+        let mut undo = transaction.undo(device)?;
+        undo.writes.extend(blocks.undo(device)?.writes);
+        History::load(device, superblock)?.remember(device, superblock, &undo)?;
+    }
+    // Data is committed before metadata referring to it, in as many
+    // transactions as needed for each to fit in the journal
+    let chunks = blocks.chunks(capacity(superblock));
+    if !chunks.is_empty() {
+        warn!(
+            "Transaction exceeds journal, committing blocks in {} parts",
+            chunks.len()
+        );
+    }
+    for chunk in chunks.iter().chain(std::iter::once(&transaction)) {
+        if !log(device, superblock, chunk)? {
+            return Err(Error::OutOfMemory);
+        }
+        chunk.apply(device)?;
+        device.barrier()?;
+        write_header(device, superblock, Header::default())?;
+    }
+    Ok(())
+}
+
+/// Committed but unfinished transaction, [Error::Corruption] if it was torn
+fn committed<D: Read + Seek>(
+    device: &mut D,
+    superblock: &Superblock,
+) -> Result<Option<Transaction>, Error> {
+    let mut raw = [0u8; Header::SIZE];
+    device.seek(SeekFrom::Start(superblock.journal_region_start()))?;
+    device.read_exact(&mut raw)?;
+    let header = Header::from_bytes(&raw)?;
+    if header.magic != JOURNAL_MAGIC {
+        return Ok(None);
+    }
+    if header.length > capacity(superblock) {
+        warn!("Discarding journal with invalid length {}", header.length);
+        return Err(Error::Corruption);
+    }
+    let mut payload = vec![0u8; header.length as usize];
+    device.seek(SeekFrom::Start(
+        superblock.journal_region_start() + superblock.block_size as u64,
+    ))?;
+    device.read_exact(&mut payload)?;
+    if checksum(&payload) != header.checksum {
+        warn!("Discarding journal with checksum mismatch");
+        return Err(Error::Corruption);
+    }
+    Transaction::from_bytes(&payload, header.records).map(Some)
+}
+
+/// Write committed but unfinished transaction in place
+/// Returns number of replayed writes
+pub(crate) fn replay<D: BlockDevice>(
+    device: &mut D,
+    superblock: &Superblock,
+) -> Result<u64, Error> {
+    if superblock.journal_blocks == 0 {
+        return Ok(0);
+    }
+    let transaction = match committed(device, superblock) {
+        Ok(Some(transaction)) => transaction,
+        Ok(None) => return Ok(0),
+        Err(Error::Corruption) => {
+            write_header(device, superblock, Header::default())?;
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let records = transaction.writes.len() as u64;
+    info!("Replaying {records} journaled writes");
+    transaction.apply(device)?;
+    device.barrier()?;
+    write_header(device, superblock, Header::default())?;
+    Ok(records)
+}
+
+/// Index of undo records kept in the journal
+pub(crate) fn history<D: Read + Seek>(
+    device: &mut D,
+    superblock: &Superblock,
+) -> Result<History, Error> {
+    if !has_history(superblock) {
+        return Err(Error::Incompatible);
+    }
+    History::load(device, superblock)
+}
+
+/// Forget undo records, so the device cannot be rewound past this point
+pub(crate) fn forget<D: BlockDevice>(device: &mut D, superblock: &Superblock) -> Result<(), Error> {
+    if !has_history(superblock) {
+        return Ok(());
+    }
+    let mut history = History::load(device, superblock)?;
+    history.entries.clear();
+    history.flush(device, superblock)
+}
+
+/// Writes bringing the device to its state after transaction `sequence`
+///
+/// They are returned in order of application instead of being applied, so
+/// the device itself is never modified. A committed but unfinished
+/// transaction comes first, followed by undo records from the newest to the
+/// oldest transaction. Returns [Error::NotFound] if `sequence` is in the
+/// future or undo records of a later transaction are no longer kept.
+pub(crate) fn rewind<D: Read + Seek>(
+    device: &mut D,
+    superblock: &Superblock,
+    sequence: u64,
+) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    let history = history(device, superblock)?;
+    if sequence > history.sequence || sequence < history.oldest() {
+        return Err(Error::NotFound);
+    }
+    let mut writes = match committed(device, superblock) {
+        Ok(Some(transaction)) => transaction.writes,
+        Ok(None) | Err(Error::Corruption) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut expected = history.sequence;
+    for entry in history.entries.iter().rev() {
+        if entry.sequence <= sequence {
+            break;
+        }
+        if entry.sequence != expected {
+            return Err(Error::NotFound);
+        }
+        writes.extend(History::read(device, superblock, entry)?.writes);
+        expected -= 1;
+    }
+    debug!(
+        "Rewinding {} writes to transaction {sequence}",
+        writes.len()
+    );
+    Ok(writes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{Transaction, JOURNAL_CHUNK};
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+    use crate::Error;
+
+    #[test]
+    fn prune_unchanged() {
+        let mut device = Cursor::new(vec![0u8; 4096]);
+        let mut transaction = Transaction::default();
+        transaction.seek(SeekFrom::Start(100)).unwrap();
+        let mut data = vec![0u8; 10 * JOURNAL_CHUNK];
+        data[0] = 1;
+        data[3 * JOURNAL_CHUNK + 5] = 1;
+        data[4 * JOURNAL_CHUNK] = 1;
+        transaction.write_all(&data).unwrap();
+        transaction.write_all(&[0u8; 16]).unwrap();
+        transaction.prune(&mut device).unwrap();
+        assert_eq!(
+            transaction
+                .writes
+                .iter()
+                .map(|(p, d)| (*p, d.len()))
+                .collect::<Vec<_>>(),
+            vec![
+                (100, JOURNAL_CHUNK),
+                (100 + 3 * JOURNAL_CHUNK as u64, 2 * JOURNAL_CHUNK)
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_committed_transaction() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        fs.force_flush().unwrap();
+        let index = fs.acquire_block(Owner::default()).unwrap();
+        let inode = fs.acquire_inode(Owner::default()).unwrap();
+
+        // Crash after commit, before any write in place
+        let mut transaction = Transaction::default();
+        fs.superblock.flush(&mut transaction).unwrap();
+        fs.inodes.flush(&mut transaction).unwrap();
+        fs.blocks.flush(&mut transaction).unwrap();
+        transaction.prune(&mut fs.device).unwrap();
+        assert!(super::log(&mut fs.device, &fs.superblock, &transaction).unwrap());
+        let (superblock, device) = (fs.superblock, fs.device);
+
+        // Loading read-only leaves the journal for a read-write mount
+        let fs = Filesystem::load_read_only(device, 512).unwrap();
+        assert!(fs.read_only);
+        assert!(!fs.blocks.get(index).unwrap());
+        let fs = Filesystem::load(fs.device, 512).unwrap();
+        assert!(fs.blocks.get(index).unwrap());
+        assert!(fs.inodes.get(inode).unwrap());
+        assert_eq!({ fs.superblock.blocks_free }, { superblock.blocks_free });
+        assert_eq!({ fs.superblock.inodes_free }, { superblock.inodes_free });
+
+        // Journal is cleared once replayed
+        let mut device = fs.device;
+        assert_eq!(super::replay(&mut device, &fs.superblock).unwrap(), 0);
+    }
+
+    #[test]
+    fn discard_torn_transaction() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        fs.force_flush().unwrap();
+        let index = fs.acquire_block(Owner::default()).unwrap();
+        let mut transaction = Transaction::default();
+        fs.blocks.flush(&mut transaction).unwrap();
+        transaction.prune(&mut fs.device).unwrap();
+        assert!(super::log(&mut fs.device, &fs.superblock, &transaction).unwrap());
+
+        // Corrupt the last record, as if its write never reached the device
+        let end = fs.superblock.journal_region_start()
+            + fs.superblock.block_size as u64
+            + transaction.length();
+        fs.device.seek(SeekFrom::Start(end - 1)).unwrap();
+        fs.device.write_all(&[0xFF]).unwrap();
+
+        let fs = Filesystem::load(fs.device, 512).unwrap();
+        assert!(!fs.blocks.get(index).unwrap());
+    }
+
+    #[test]
+    fn rewind_to_earlier_transaction() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        let index = file.inode.index;
+        file.write(0, &[1u8; 1_000]).unwrap();
+        drop(file);
+        fs.lock().unwrap().force_flush().unwrap();
+        let (_, sequence) = fs.lock().unwrap().journal_history().unwrap();
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        file.write(0, &[2u8; 1_000]).unwrap();
+        drop(file);
+        fs.lock().unwrap().force_flush().unwrap();
+
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        let (oldest, last) = fs.journal_history().unwrap();
+        assert!(oldest < sequence && sequence < last);
+        let past = Filesystem::load_at(fs.device, 512, sequence).unwrap();
+        assert!(past.read_only);
+        let past = Arc::new(Mutex::new(past));
+        {
+            let root = Directory::load(&past, ROOT_INODE).unwrap();
+            assert_eq!(root.children[0].inode, index);
+            let mut file = RegularFile::load(&past, index).unwrap();
+            assert_eq!(file.read(0, 1_000).unwrap(), vec![1u8; 1_000]);
+        }
+
+        let device = Arc::into_inner(past).unwrap().into_inner().unwrap().device;
+        assert!(matches!(
+            Filesystem::load_at(device, 512, last + 1),
+            Err(Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn commit_oversized_transaction() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        let index = file.inode.index;
+        file.write(0, &data).unwrap();
+        drop(file);
+        fs.lock().unwrap().force_flush().unwrap();
+
+        let device = Arc::into_inner(fs).unwrap().into_inner().unwrap().device;
+        let fs = Arc::new(Mutex::new(Filesystem::load(device, 512).unwrap()));
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!(file.read(0, data.len() as u64).unwrap(), data);
+
+        // Every part fits in the journal, including writes larger than it
+        let mut transaction = Transaction::default();
+        transaction.write_all(&data[..1_000]).unwrap();
+        transaction.write_all(&data).unwrap();
+        let chunks = transaction.chunks(10_000);
+        assert!(chunks.iter().all(|chunk| chunk.length() <= 10_000));
+        let mut device = Cursor::new(vec![0u8; 101_000]);
+        chunks
+            .iter()
+            .for_each(|chunk| chunk.apply(&mut device).unwrap());
+        assert_eq!(&device.get_ref()[1_000..], &data[..]);
+    }
+}
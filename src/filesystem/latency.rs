@@ -0,0 +1,209 @@
+//! Latencies of FUSE operations, telling where a slow mount spends its time
+//!
+//! Every handler of [FuseFs] is measured from the moment the kernel request
+//! arrives until it is answered, and the duration is recorded in a histogram
+//! of its operation with buckets growing by powers of two. Medians and 99th
+//! percentiles estimated from them are published with the rest of
+//! [Statistics](super::Statistics). Operations taking longer than the
+//! threshold set by `--slow-op-ms` are logged along with their arguments,
+//! while the debug log of the allocator, the cache and the device shows what
+//! they waited for.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use super::{Filesystem, FuseFs, LockFilesystem};
+
+/// Buckets of a histogram, the one at index `i` holding durations shorter
+/// than `2^i` microseconds and the last one all longer
+const BUCKETS: usize = 32;
+
+/// Durations of a single operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; BUCKETS],
+            count: 0,
+            sum: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += duration;
+    }
+
+    /// Number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Total of recorded durations
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Upper bound of durations of `quantile` of recorded ones, between 0 and 1
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(1 << bucket);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Histograms of operations which were performed at least once
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Latencies {
+    operations: BTreeMap<&'static str, Histogram>,
+}
+
+impl Latencies {
+    pub fn record(&mut self, operation: &'static str, duration: Duration) {
+        self.operations
+            .entry(operation)
+            .or_default()
+            .record(duration);
+    }
+
+    pub fn get(&self, operation: &str) -> Option<&Histogram> {
+        self.operations.get(operation)
+    }
+}
+
+/// Medians and 99th percentiles as a summary in the text exposition format
+/// of Prometheus
+impl Display for Latencies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.operations.is_empty() {
+            return Ok(());
+        }
+        let name = "tananfs_operation_duration_seconds";
+        writeln!(f, "# HELP {name} Time taken to answer FUSE operations")?;
+        writeln!(f, "# TYPE {name} summary")?;
+        for (operation, histogram) in &self.operations {
+            for quantile in [0.5, 0.99] {
+                writeln!(
+                    f,
+                    "{name}{{operation=\"{operation}\",quantile=\"{quantile}\"}} {:.6}",
+                    histogram.quantile(quantile).as_secs_f64()
+                )?;
+            }
+            writeln!(
+                f,
+                "{name}_sum{{operation=\"{operation}\"}} {:.6}",
+                histogram.sum().as_secs_f64()
+            )?;
+            writeln!(
+                f,
+                "{name}_count{{operation=\"{operation}\"}} {}",
+                histogram.count()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measurement of an operation, recorded when dropped
+pub(crate) struct OperationTimer<F: FnOnce() -> String> {
+    filesystem: Arc<Mutex<Filesystem>>,
+    operation: &'static str,
+    /// Description of arguments, only formatted for slow operations
+    arguments: Option<F>,
+    slow: Option<Duration>,
+    start: Instant,
+}
+
+impl<F: FnOnce() -> String> Drop for OperationTimer<F> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if self.slow.is_some_and(|slow| elapsed >= slow) {
+            if let Some(arguments) = self.arguments.take() {
+                warn!(
+                    "Slow {} of {} took {} ms",
+                    self.operation,
+                    arguments(),
+                    elapsed.as_millis()
+                );
+            }
+        }
+        if let Ok(mut fs) = self.filesystem.lock_fs() {
+            fs.statistics.latencies.record(self.operation, elapsed);
+        }
+    }
+}
+
+impl FuseFs {
+    /// Measure `operation` until the returned timer is dropped, which must
+    /// happen while filesystem is not locked
+    pub(crate) fn time<F: FnOnce() -> String>(
+        &self,
+        operation: &'static str,
+        arguments: F,
+    ) -> OperationTimer<F> {
+        OperationTimer {
+            filesystem: self.filesystem.clone(),
+            operation,
+            arguments: Some(arguments),
+            slow: self.slow_threshold,
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::{Filesystem, FuseFs};
+
+    use super::Histogram;
+
+    #[test]
+    fn estimate_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), Duration::ZERO);
+        for _ in 0..98 {
+            histogram.record(Duration::from_micros(100));
+        }
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(1));
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(128));
+        assert_eq!(histogram.quantile(0.99), Duration::from_micros(4096));
+        assert_eq!(histogram.quantile(1.0), Duration::from_micros(1 << 20));
+
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        let fuse_fs = FuseFs::new(fs.clone()).with_slow_threshold(Duration::ZERO);
+        drop(fuse_fs.time("lookup", || "name".to_owned()));
+        let statistics = fs.lock().unwrap().statistics();
+        assert_eq!(statistics.latencies.get("lookup").unwrap().count(), 1);
+        let text = statistics.to_string();
+        assert!(text.contains("# TYPE tananfs_operation_duration_seconds summary\n"));
+        assert!(text.contains("_count{operation=\"lookup\"} 1\n"));
+    }
+}
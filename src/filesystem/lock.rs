@@ -0,0 +1,99 @@
+//! Locking discipline for the shared [Filesystem]
+//!
+//! The filesystem mutex is the only lock in the driver, so the single way to
+//! deadlock is for a thread to lock it again while already holding it, e.g. by
+//! calling [Directory::load](crate::filetypes::Directory) or
+//! [RegularFile::load](crate::filetypes::RegularFile) with a live guard.
+//! All acquisitions go through [LockFilesystem::lock_fs], which remembers the
+//! locks held by the current thread and turns such re-entry into
+//! [Error::ThreadSync] instead of hanging forever.
+//!
+//! Rules for callers:
+//! - never hold a [FilesystemGuard] across calls into `filetypes` constructors,
+//!   loaders or removers, as they lock on their own
+//! - drop loaded files and directories (which flush on [Drop]) only after the
+//!   guard is released
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::error;
+
+use super::Filesystem;
+use crate::Error;
+
+thread_local! {
+    /// Addresses of filesystem mutexes held by the current thread
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+pub trait LockFilesystem {
+    /// Lock the filesystem, failing if the current thread already holds it
+    fn lock_fs(&self) -> Result<FilesystemGuard<'_>, Error>;
+}
+
+#[derive(Debug)]
+pub struct FilesystemGuard<'a> {
+    guard: MutexGuard<'a, Filesystem>,
+    address: usize,
+}
+
+impl LockFilesystem for Mutex<Filesystem> {
+    fn lock_fs(&self) -> Result<FilesystemGuard<'_>, Error> {
+        let address = self as *const Self as usize;
+        if HELD.with(|held| held.borrow().contains(&address)) {
+            error!("Filesystem lock is already held by this thread");
+            return Err(Error::ThreadSync);
+        }
+        let mut guard = self.lock()?;
+        guard.accesses = guard.accesses.wrapping_add(1);
+        HELD.with(|held| held.borrow_mut().push(address));
+        Ok(FilesystemGuard { guard, address })
+    }
+}
+
+impl LockFilesystem for Arc<Mutex<Filesystem>> {
+    fn lock_fs(&self) -> Result<FilesystemGuard<'_>, Error> {
+        self.as_ref().lock_fs()
+    }
+}
+
+impl Deref for FilesystemGuard<'_> {
+    type Target = Filesystem;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for FilesystemGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl Drop for FilesystemGuard<'_> {
+    fn drop(&mut self) {
+        HELD.with(|held| held.borrow_mut().retain(|&address| address != self.address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::LockFilesystem;
+    use crate::{error::Error, filesystem::Filesystem};
+
+    #[test]
+    fn reentrant_lock() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        let guard = fs.lock_fs().unwrap();
+        assert!(matches!(fs.lock_fs(), Err(Error::ThreadSync)));
+        drop(guard);
+        assert!(fs.lock_fs().is_ok());
+    }
+}
@@ -1,68 +1,1003 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::io::{Read, Seek, Write};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use fuser::{FileAttr, FileType};
+use log::{debug, error, info, warn};
 
+use crate::devices::overlay::OverlayDevice;
+use crate::filetypes::{
+    bytes_per_block, timestamp_now, Directory, DirectoryChildIdentifier, FileOperations, Owner,
+    RegularFile, MAX_NAME_LENGTH,
+};
 use crate::structs::*;
 use crate::Error;
 
+pub mod archive;
 mod cache;
-mod fuse;
+mod check;
+pub mod control;
+mod delayed;
+mod discard;
+pub mod fuse;
+pub mod health;
+mod inode_chunks;
+mod invalidation;
+mod journal;
+mod latency;
+mod lock;
+mod notifier;
+mod orphans;
+mod paths;
+mod quota;
+mod readahead;
+mod references;
+mod resize;
+mod scrub;
+mod session;
+mod shares;
+mod snapshots;
+mod stats;
+mod sync;
 
 use cache::Cache;
+pub use control::Fragmentation;
+use delayed::{DelayedWrites, DELAYED_FILE_BYTES, DELAYED_TOTAL_BYTES};
+use health::{Health, HealthMonitor};
+use invalidation::{Invalidation, Invalidations};
+use journal::Transaction;
+pub use latency::{Histogram, Latencies};
+pub use lock::{FilesystemGuard, LockFilesystem};
+pub use notifier::Notifier;
+pub use paths::{DEFAULT_DIRECTORY_MODE, DEFAULT_FILE_MODE};
+pub use quota::QuotaKind;
+pub(crate) use quota::Quotas;
+use readahead::ReadAhead;
+use references::References;
+pub use scrub::{ScrubReport, Scrubber, SCRUB_BATCH_BLOCKS, SCRUB_PAUSE};
+pub(crate) use session::Session;
+use shares::Shares;
+pub use snapshots::Change;
+pub use stats::{Statistics, CONTROL_DIRECTORY, CONTROL_INODE, STATS_FILE, STATS_INODE};
+pub use sync::SyncMode;
 
-pub trait BlockDevice: Read + Write + Seek + Debug {}
+pub trait BlockDevice: Read + Write + Seek + Debug + Send {
+    /// Tell the device that `length` bytes at `offset` hold nothing worth
+    /// keeping, which devices unable to reclaim space ignore
+    fn discard(&mut self, _offset: u64, _length: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Number of copies of contents the device keeps, such as mirrored devices
+    fn copies(&self) -> usize {
+        1
+    }
+
+    /// Serve reads only from copy numbered `copy`, or from any of them if `None`
+    fn select_copy(&mut self, _copy: Option<usize>) {}
+
+    /// Wait until written contents are stored durably, which devices without
+    /// a volatile cache do by flushing
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+
+    /// Wait until written contents are stored durably, except for metadata
+    /// of the backing file not needed to read them back, like `fdatasync`
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl BlockDevice for std::fs::File {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        crate::devices::discard::discard(self, offset, length)
+    }
+
+    fn barrier(&mut self) -> std::io::Result<()> {
+        self.sync_all()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+}
+
+impl BlockDevice for Box<dyn BlockDevice> {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        (**self).discard(offset, length)
+    }
+
+    fn copies(&self) -> usize {
+        (**self).copies()
+    }
+
+    fn select_copy(&mut self, copy: Option<usize>) {
+        (**self).select_copy(copy)
+    }
+
+    fn barrier(&mut self) -> std::io::Result<()> {
+        (**self).barrier()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        (**self).sync()
+    }
+}
 
-impl BlockDevice for std::fs::File {}
+/// In-memory device, used by tests and stress runs
+impl BlockDevice for std::io::Cursor<Vec<u8>> {
+    fn discard(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        let data = self.get_mut();
+        let start = offset.min(data.len() as u64) as usize;
+        let end = offset.saturating_add(length).min(data.len() as u64) as usize;
+        data[start..end].fill(0);
+        Ok(())
+    }
+
+    /// Memory holds contents for as long as it exists
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
 
 pub const DIRTY_PAGE_MAX_SECONDS: Duration = Duration::from_millis(1000);
-pub const LRU_MAX_ENTRIES: usize = 131072;
+/// Bytes cached inodes and blocks may hold before clean ones are evicted
+pub const CACHE_MAX_BYTES: usize = 64 << 20;
+/// Largest number of blocks prefetched after a sequential read
+pub const READAHEAD_BLOCKS: u64 = 64;
+/// Inode 0 is never allocated, as FUSE does not accept it as a node id
+pub const RESERVED_INODE: u64 = 0;
 pub const ROOT_INODE: u64 = 1;
 pub const FORCE_FLUSH_ALWAYS: bool = false;
+pub const MAX_DIRECTORY_ENTRIES: u64 = 1 << 20;
+pub const MAX_PATH_DEPTH: u64 = 256;
+/// Most bytes copied by a single `copy_file_range` which does not clone a file
+pub const COPY_RANGE_BYTES: u64 = 1 << 20;
+
+/// Upper bounds protecting the filesystem from pathological directory trees
+///
+/// Limits apply to entries being added, so directories which exceed them,
+/// such as those created before they were lowered, stay readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of children in a single directory
+    pub directory_entries: u64,
+    /// Maximum number of directories between root and any of its descendants,
+    /// and of components traversed while resolving a single path
+    pub path_depth: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            directory_entries: MAX_DIRECTORY_ENTRIES,
+            path_depth: MAX_PATH_DEPTH,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Filesystem {
-    pub(crate) superblock: Superblock,
-    pub(crate) inodes: Bitmap<Inode>,
-    pub(crate) blocks: Bitmap<Block>,
+    pub superblock: Superblock,
+    pub inodes: Bitmap<Inode>,
+    pub blocks: Bitmap<Block>,
     pub(crate) device: Box<dyn BlockDevice>,
     pub(crate) cache: Cache,
     pub(crate) last_flush: Option<Instant>,
+    /// Longest time changes are kept in cache before they are flushed
+    pub(crate) flush_interval: Duration,
+    pub(crate) limits: Limits,
+    pub(crate) invalidations: Invalidations,
+    pub(crate) health: HealthMonitor,
+    /// Reject modifications, as filesystem was made by a newer version or device is shared
+    pub read_only: bool,
+    pub(crate) options: MountOptions,
+    /// Usage and limits of users and groups, for filesystems keeping them
+    pub(crate) quotas: Quotas,
+    /// Number of files holding every shared chain of blocks
+    pub(crate) shares: Shares,
+    /// Blocks released since the last flush, discarded once it is committed
+    pub(crate) released: BTreeSet<u64>,
+    /// Number of times the filesystem was locked, telling the scrubber whether it is idle
+    pub(crate) accesses: u64,
+    /// Counters of operations since the filesystem was loaded
+    pub(crate) statistics: Statistics,
 }
 
 #[derive(Debug)]
 pub struct FuseFs {
     pub(crate) filesystem: Arc<Mutex<Filesystem>>,
+    /// Kernel references to inodes across all mounts of the filesystem
+    pub(crate) references: Arc<Mutex<References>>,
+    /// Identifier of this mount in shared references
+    pub(crate) mount: usize,
+    /// Reject all operations modifying the filesystem
+    pub(crate) read_only: bool,
+    /// Bypass kernel page cache for regular files, unless opened otherwise
+    pub(crate) direct_io: bool,
+    /// Listings of open directories, captured by opendir
+    pub(crate) directories: BTreeMap<u64, DirectorySnapshot>,
+    /// Number of invalidations per inode, reported by writers outside of FUSE
+    pub(crate) invalidated: Arc<Mutex<BTreeMap<u64, u64>>>,
+    /// Invalidation of kernel caches of this mount
+    pub(crate) notifier: Option<Notifier>,
+    /// Open flags of regular file handles
+    pub(crate) files: BTreeMap<u64, i32>,
+    /// Data written through this mount whose blocks are not allocated yet
+    pub(crate) delayed: Arc<Mutex<DelayedWrites>>,
+    /// Sequential reads of regular file handles
+    pub(crate) readahead: ReadAhead,
+    /// Data of the last read, whose allocation is reused by the next one
+    pub(crate) read_buffer: Vec<u8>,
+    /// Handle of the next opened file or directory
+    pub(crate) next_handle: u64,
+    /// Duration after which operations are logged as slow
+    pub(crate) slow_threshold: Option<Duration>,
+    /// Changes passing a barrier before they are answered
+    pub(crate) sync: SyncMode,
+}
+
+/// Usage of filesystem as reported by `statfs`, counting blocks by bytes of
+/// file contents they hold
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub blocks: u64,
+    /// Free blocks left for file contents
+    pub blocks_free: u64,
+    /// Free blocks left for file contents of unprivileged users
+    pub blocks_available: u64,
+    /// Inodes, used or not
+    pub files: u64,
+    pub files_free: u64,
+    /// Bytes of file contents held by a block
+    pub block_size: u32,
+    /// Longest name of a new directory entry
+    pub name_length: u32,
+}
+
+/// Bytes of memory held by caches and in-memory structures
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub block_cache: usize,
+    pub inode_cache: usize,
+    pub bitmaps: usize,
+    /// Listings of open directories
+    pub directories: usize,
+    /// Written data waiting for allocation of its blocks
+    pub delayed_writes: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.block_cache + self.inode_cache + self.bitmaps + self.directories + self.delayed_writes
+    }
+}
+
+impl std::fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "block_cache {}", self.block_cache)?;
+        writeln!(f, "inode_cache {}", self.inode_cache)?;
+        writeln!(f, "bitmaps {}", self.bitmaps)?;
+        writeln!(f, "directories {}", self.directories)?;
+        writeln!(f, "delayed_writes {}", self.delayed_writes)?;
+        write!(f, "total {}", self.total())
+    }
+}
+
+/// Directory listing served by readdir
+#[derive(Debug, Clone)]
+pub(crate) struct DirectorySnapshot {
+    /// Invalidation count of directory when listing was captured
+    pub generation: u64,
+    pub entries: Vec<DirectoryEntry>,
+}
+
+/// Entry of a directory listing served by readdir
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DirectoryEntry {
+    pub inode: u64,
+    pub kind: fuser::FileType,
+    pub name: String,
 }
 
 impl FuseFs {
-    fn fs_handle(&self) -> Result<MutexGuard<Filesystem>, Error> {
-        if let Ok(fs) = self.filesystem.lock() {
-            Ok(fs)
+    pub fn new(filesystem: Arc<Mutex<Filesystem>>) -> Self {
+        let mut references = References::default();
+        let mount = references.register();
+        Self {
+            filesystem,
+            references: Arc::new(Mutex::new(references)),
+            mount,
+            read_only: false,
+            direct_io: true,
+            directories: BTreeMap::new(),
+            invalidated: Arc::default(),
+            notifier: None,
+            files: BTreeMap::new(),
+            delayed: Arc::default(),
+            readahead: ReadAhead::default(),
+            read_buffer: Vec::new(),
+            next_handle: 1,
+            slow_threshold: None,
+            sync: SyncMode::Async,
+        }
+    }
+
+    /// Read-only mount of the same filesystem, sharing its cache, references
+    /// and delayed writes
+    pub fn mirror(&self) -> Result<Self, Error> {
+        let mount = self.references.lock()?.register();
+        Ok(Self {
+            filesystem: self.filesystem.clone(),
+            references: self.references.clone(),
+            mount,
+            read_only: true,
+            direct_io: self.direct_io,
+            directories: BTreeMap::new(),
+            invalidated: self.invalidated.clone(),
+            notifier: None,
+            files: BTreeMap::new(),
+            delayed: self.delayed.clone(),
+            readahead: ReadAhead::new(self.readahead.blocks),
+            read_buffer: Vec::new(),
+            next_handle: 1,
+            slow_threshold: self.slow_threshold,
+            sync: self.sync,
+        })
+    }
+
+    /// Drop kernel references of this mount, write back delayed writes and
+    /// flush the filesystem, marking it unmounted unless this is a mirror
+    pub fn unmount(&mut self) -> Result<(), Error> {
+        let released = self.references.lock()?.release(self.mount);
+        self.write_back_all()?;
+        for ino in released {
+            self.reclaim(ino)?;
+        }
+        // Filesystem stays mounted while a mirror of it is unmounted
+        match self.read_only {
+            true => self.fs_handle()?.force_flush(),
+            false => self.fs_handle()?.mark_unmounted(),
+        }
+    }
+
+    /// Let kernel page cache serve regular files opened without `O_DIRECT`
+    pub fn with_page_cache(mut self) -> Self {
+        self.direct_io = false;
+        self
+    }
+
+    /// Tell the kernel about changes made outside of this mount through `notifier`
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Prefetch up to `blocks` blocks after sequential reads of regular files
+    pub fn with_readahead(mut self, blocks: u64) -> Self {
+        self.readahead.blocks = blocks;
+        self
+    }
+
+    /// Log operations taking at least `threshold` along with their arguments
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Flags of reply to opening a regular file with `flags`
+    ///
+    /// Cached pages are kept between opens only while changes made outside of
+    /// this mount reach the kernel through the notifier, otherwise they would
+    /// go stale.
+    fn file_open_flags(&self, flags: i32) -> u32 {
+        if self.direct_io || flags & libc::O_DIRECT != 0 {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else if self.notifier.as_ref().is_some_and(Notifier::is_attached) {
+            fuser::consts::FOPEN_KEEP_CACHE
         } else {
-            Err(Error::ThreadSync)
+            0
+        }
+    }
+
+    /// Prefetch up to `window` blocks of file following sequential read through
+    /// handle which ended at `end`, unless filesystem is mounted with `noreadahead`
+    ///
+    /// Read-ahead only warms the cache, so its errors are left for the reads
+    /// of prefetched blocks to report.
+    fn read_ahead(&mut self, fh: u64, ino: u64, end: u64, window: u64) {
+        let linked = self.session().and_then(|mut session| {
+            match session.options.contains(MountOptions::NOREADAHEAD) {
+                true => Ok(0),
+                false => session.read_ahead(ino, end, window),
+            }
+        });
+        match linked {
+            Ok(0) => {}
+            Ok(linked) => self.readahead.prefetched(fh, linked),
+            Err(e) => debug!("Read-ahead of file {ino} failed: {e}"),
+        }
+    }
+
+    /// Fail if mount or filesystem does not allow modifications, or errors made it read-only
+    fn writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let fs = self.fs_handle()?;
+        if fs.read_only || fs.health.state() == Health::ReadOnly {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn fs_handle(&self) -> Result<FilesystemGuard<'_>, Error> {
+        self.filesystem.lock_fs()
+    }
+
+    fn session(&self) -> Result<Session<'_>, Error> {
+        Filesystem::session(&self.filesystem)
+    }
+
+    /// Count a reference to inode handed to the kernel
+    fn remember(&self, ino: u64) -> Result<(), Error> {
+        self.references.lock()?.remember(self.mount, ino);
+        Ok(())
+    }
+
+    /// Drop kernel references to inode and reclaim it if it was orphaned
+    fn forget_lookups(&self, ino: u64, nlookup: u64) -> Result<(), Error> {
+        let last = self.references.lock()?.forget(self.mount, ino, nlookup);
+        if last {
+            self.reclaim(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Reclaim unlinked inode now, or once no mount refers to it
+    fn release_unlinked(&self, ino: u64) -> Result<(), Error> {
+        let mut references = self.references.lock()?;
+        if references.referenced(ino) {
+            debug!("Inode {ino} is still referenced, deferring reclamation");
+            references.orphan(ino);
+            drop(references);
+            return self.fs_handle()?.add_orphan(ino);
+        }
+        drop(references);
+        self.reclaim(ino)
+    }
+
+    /// Register handle of opened regular file, truncating it for `O_TRUNC`
+    fn open_file(&mut self, ino: u64, flags: i32) -> Result<u64, Error> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            self.writable()?;
+            let inode = self.fs_handle()?.load_inode(ino)?;
+            match flags & (libc::O_APPEND | libc::O_TRUNC) {
+                libc::O_APPEND => inode.check_appendable()?,
+                _ => inode.check_modifiable()?,
+            }
+            if flags & libc::O_TRUNC != 0 {
+                debug!("Truncating file {ino}");
+                self.delayed.lock()?.take(ino);
+                let mut session = self.session()?;
+                session.resize_file(ino, 0)?;
+                session.commit()?;
+            }
+        }
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(fh, flags);
+        Ok(fh)
+    }
+
+    /// Attributes of inode, with size including its delayed writes
+    fn attrs(&self, ino: u64) -> Result<FileAttr, Error> {
+        if stats::is_control(ino) {
+            let block_size = self.fs_handle()?.superblock.block_size;
+            return Ok(stats::control_attrs(ino, block_size));
+        }
+        let mut attrs = self.session()?.attrs(ino)?;
+        attrs.size = self.delayed.lock()?.size(ino, attrs.size);
+        Ok(attrs)
+    }
+
+    /// Copy up to `length` bytes of regular file `source` at `source_offset`
+    /// into `target` at `target_offset`, returning the number of copied bytes
+    ///
+    /// Copying `source` from its start over the start of a file no larger
+    /// than it clones it instead, sharing its blocks until either file is
+    /// modified, so the copy takes the same time regardless of file size.
+    fn copy_range(
+        &mut self,
+        (source, source_offset): (u64, u64),
+        (target, target_offset): (u64, u64),
+        length: u64,
+    ) -> Result<u64, Error> {
+        self.write_back(source)?;
+        self.write_back(target)?;
+        let mut session = self.session()?;
+        let (from, to) = (session.load_inode(source)?, session.load_inode(target)?);
+        let size = from.size;
+        let whole = source_offset == 0 && target_offset == 0 && length >= size;
+        // Replies count at most 4 GiB, the rest of a clone is asked for again
+        let length = length
+            .min(size.saturating_sub(source_offset))
+            .min(u32::MAX as u64);
+        if length == 0 {
+            return Ok(0);
+        }
+        if whole && source != target && to.size <= size {
+            debug!("Cloning inode {source} into {target}");
+            session.clone_file(source, target)?;
+            session.commit()?;
+            return Ok(length);
+        }
+        // Files sharing their blocks already hold the same contents
+        let shared = from.block_count > 0 && from.first_block == to.first_block;
+        if shared && source_offset == target_offset && from.size == to.size {
+            return Ok(length);
+        }
+        let data = session.read_file(source, source_offset, length.min(COPY_RANGE_BYTES))?;
+        session.write_file(target, target_offset, &data)?;
+        session.commit()?;
+        Ok(data.len() as u64)
+    }
+
+    /// Acquire blocks of regular file for `length` bytes at `offset`, as
+    /// `fallocate` does with `mode`
+    ///
+    /// With `FALLOC_FL_KEEP_SIZE`, blocks past the end of the file are
+    /// reserved without changing its size, and appends fill them before
+    /// acquiring new ones. Punching holes and other modes are not supported.
+    fn allocate(&mut self, ino: u64, offset: u64, length: u64, mode: i32) -> Result<(), Error> {
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(Error::Incompatible);
+        }
+        let end = match offset.checked_add(length) {
+            Some(end) if length > 0 => end,
+            _ => return Err(Error::InvalidArgument),
+        };
+        self.write_back(ino)?;
+        let mut session = self.session()?;
+        session.allocate_file(ino, end, mode & libc::FALLOC_FL_KEEP_SIZE != 0)?;
+        session.commit()
+    }
+
+    /// Write data to regular file at `offset`, or at its end if `append` is set
+    ///
+    /// Unless filesystem is mounted with `nodelalloc`, data is kept in memory
+    /// and its blocks are allocated when it is [written back](Self::write_back).
+    /// Writes leaving a hole, of filesystems keeping quotas, or for which there
+    /// may not be enough free blocks are not delayed, so that their errors are
+    /// reported by the write itself.
+    fn write_file(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        append: bool,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let mut session = Filesystem::session(&self.filesystem)?;
+        let mut inode = session.load_inode(ino)?;
+        let mut delayed = self.delayed.lock()?;
+        let size = delayed.size(ino, inode.size);
+        let offset = if append { size } else { offset };
+        let bytes_per_block = bytes_per_block(session.superblock.block_size);
+        let delay = !session.options.contains(MountOptions::NODELALLOC)
+            && !session.quotas.enabled()
+            && inode.r#type == FileType::RegularFile
+            && offset <= size
+            && ((delayed.bytes() + data.len()) as u64).div_ceil(bytes_per_block)
+                <= session.blocks_available(Owner::from(&inode));
+        if delay {
+            inode.check_write(offset, size)?;
+            if delayed.buffer(ino, offset, data) {
+                let now = timestamp_now();
+                inode.set_atime(now);
+                inode.set_mtime(now);
+                session.stage_inode(inode);
+                session.statistics.count_write(data.len());
+                session.commit()?;
+                drop(delayed);
+                if self.delayed.lock()?.file_bytes(ino) >= DELAYED_FILE_BYTES {
+                    self.write_back(ino)?;
+                }
+                if self.delayed.lock()?.bytes() >= DELAYED_TOTAL_BYTES {
+                    self.write_back_all()?;
+                }
+                return Ok(());
+            }
+        }
+        drop(delayed);
+        drop(session);
+        self.write_back(ino)?;
+        let mut session = self.session()?;
+        session.write_file(ino, offset, data)?;
+        session.statistics.count_write(data.len());
+        session.commit()
+    }
+
+    /// Write delayed data of inode to its file, allocating its blocks at once
+    fn write_back(&mut self, ino: u64) -> Result<(), Error> {
+        let Some(range) = self.delayed.lock()?.take(ino) else {
+            return Ok(());
+        };
+        debug!(
+            "Write back {} delayed bytes of inode {ino}",
+            range.data.len()
+        );
+        let mut session = self.session()?;
+        session.write_file(ino, range.offset, &range.data)?;
+        session.commit()
+    }
+
+    /// Write delayed data of all inodes to their files
+    fn write_back_all(&mut self) -> Result<(), Error> {
+        let inodes = self.delayed.lock()?.inodes();
+        for ino in inodes {
+            self.write_back(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Move `name` in directory `parent` to `new_name` in `new_parent` as
+    /// `renameat2` with `flags` does, returning inode of the replaced entry
+    fn move_entry(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+        flags: u32,
+    ) -> Result<Option<u64>, Error> {
+        // Exchanging entries and whiteouts are not supported
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let mut directory = Directory::load(&self.filesystem, parent)?;
+        if flags & libc::RENAME_NOREPLACE != 0 {
+            let target = match new_parent == parent {
+                true => directory.lookup(new_name),
+                false => Directory::load(&self.filesystem, new_parent)?.lookup(new_name),
+            };
+            match target {
+                Ok(_) => return Err(Error::NameOrInodeDuplicate),
+                Err(Error::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        directory.transfer_child(DirectoryChildIdentifier::Name(name), new_parent, new_name)
+    }
+
+    /// Keep track of inodes modified outside of this mount, once per shared filesystem
+    fn watch_invalidations(&self) -> Result<(), Error> {
+        let invalidated = self.invalidated.clone();
+        let mount = self.mount;
+        self.fs_handle()?
+            .invalidations
+            .register(move |origin, invalidation| {
+                let Invalidation::Inode(index) = invalidation else {
+                    return;
+                };
+                if origin == Some(mount) {
+                    return;
+                }
+                if let Ok(mut invalidated) = invalidated.lock() {
+                    *invalidated.entry(*index).or_default() += 1;
+                }
+            });
+        Ok(())
+    }
+
+    /// Forward changes made outside of this mount to the kernel, once it is mounted
+    fn notify_kernel(&self) -> Result<(), Error> {
+        let Some(notifier) = self.notifier.clone() else {
+            return Ok(());
+        };
+        notifier.attach()?;
+        let mount = self.mount;
+        self.fs_handle()?
+            .invalidations
+            .register(move |origin, invalidation| {
+                if origin != Some(mount) {
+                    notifier.send(invalidation);
+                }
+            });
+        Ok(())
+    }
+
+    /// Number of times inode was modified outside of FUSE
+    fn generation(&self, ino: u64) -> Result<u64, Error> {
+        Ok(self.invalidated.lock()?.get(&ino).copied().unwrap_or(0))
+    }
+
+    /// Capture listing of directory, starting with `.` and `..` entries
+    fn snapshot_directory(&self, ino: u64) -> Result<DirectorySnapshot, Error> {
+        let generation = self.generation(ino)?;
+        if ino == CONTROL_INODE {
+            return Ok(DirectorySnapshot {
+                generation,
+                entries: stats::control_entries(),
+            });
+        }
+        let dir = Directory::load(&self.filesystem, ino)?;
+        let mut entries = vec![(ino, ".".to_owned()), (dir.parent(), "..".to_owned())];
+        entries.extend(dir.children.iter().map(|c| (c.inode, c.name.clone())));
+        drop(dir);
+        let mut session = self.session()?;
+        let entries = entries
+            .into_iter()
+            .map(|(inode, name)| {
+                Ok(DirectoryEntry {
+                    inode,
+                    kind: session.load_inode(inode)?.r#type,
+                    name,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(DirectorySnapshot {
+            generation,
+            entries,
+        })
+    }
+
+    /// Capture listing of directory handle again if directory was invalidated since
+    fn rewind_directory(&mut self, ino: u64, fh: u64) -> Result<(), Error> {
+        let generation = self.generation(ino)?;
+        if let Some(snapshot) = self.directories.get(&fh) {
+            if snapshot.generation != generation {
+                debug!("Directory {ino} was invalidated, refreshing listing");
+                let snapshot = self.snapshot_directory(ino)?;
+                self.directories.insert(fh, snapshot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Usage reported by `statfs`, without blocks delayed writes are yet to take
+    fn usage(&self) -> Result<StatFs, Error> {
+        let fs = self.fs_handle()?;
+        let block_size = bytes_per_block(fs.superblock.block_size);
+        let delayed = (self.delayed.lock()?.bytes() as u64).div_ceil(block_size);
+        let free = fs.superblock.blocks_free.saturating_sub(delayed);
+        let available = free.saturating_sub(fs.reserved_blocks());
+        Ok(StatFs {
+            blocks: fs.superblock.block_count,
+            blocks_free: fs.data_blocks(free),
+            blocks_available: fs.data_blocks(available),
+            files: fs.superblock.total_inodes(),
+            files_free: fs.superblock.inodes_free,
+            block_size: block_size as u32,
+            name_length: MAX_NAME_LENGTH as u32,
+        })
+    }
+
+    /// Memory held by filesystem and listings of directories opened through this mount
+    fn memory_usage(&self) -> Result<MemoryUsage, Error> {
+        let directories = self
+            .directories
+            .values()
+            .flat_map(|snapshot| snapshot.entries.iter())
+            .map(|entry| std::mem::size_of::<DirectoryEntry>() + entry.name.capacity())
+            .sum();
+        let delayed_writes = self.delayed.lock()?.memory_usage();
+        Ok(MemoryUsage {
+            directories,
+            delayed_writes,
+            ..self.fs_handle()?.memory_usage()
+        })
+    }
+
+    /// Release data and inode of an unlinked file or empty directory
+    fn reclaim(&self, ino: u64) -> Result<(), Error> {
+        debug!("Reclaim orphaned inode {ino}");
+        let kind = {
+            let mut fs = self.fs_handle()?;
+            fs.remove_orphan(ino)?;
+            fs.load_inode(ino)?.r#type
+        };
+        match kind {
+            fuser::FileType::Directory => Directory::load(&self.filesystem, ino)?.remove_empty(),
+            _ => RegularFile::load(&self.filesystem, ino)?.remove(),
         }
     }
 }
 
 impl Filesystem {
-    pub(crate) fn new(device: Box<dyn BlockDevice>, capacity: u64, block_size: u32) -> Self {
-        let superblock = Superblock::new(capacity, block_size);
+    pub fn new(device: Box<dyn BlockDevice>, capacity: u64, block_size: u32) -> Self {
+        Self::new_aligned(device, capacity, block_size, block_size)
+    }
+
+    /// New filesystem with block region aligned to `alignment` bytes of the device
+    pub fn new_aligned(
+        device: Box<dyn BlockDevice>,
+        capacity: u64,
+        block_size: u32,
+        alignment: u32,
+    ) -> Self {
+        Self::from_superblock(
+            device,
+            Superblock::new_aligned(capacity, block_size, alignment),
+        )
+    }
+
+    /// New filesystem laid out as described by a new `superblock`
+    pub fn from_superblock(device: Box<dyn BlockDevice>, superblock: Superblock) -> Self {
+        let mut superblock = superblock;
+        let block_size = superblock.block_size;
         assert!(block_size.is_power_of_two() && (512..=4096).contains(&block_size));
+        let mut inodes = Bitmap::<Inode>::new(&superblock);
+        inodes
+            .set(RESERVED_INODE, true)
+            .expect("inode bitmap of new filesystem is empty");
+        superblock.inodes_free -= 1;
         Self {
+            quotas: Quotas::new(&superblock),
+            shares: Shares::default(),
+            released: BTreeSet::new(),
+            accesses: 0,
+            statistics: Statistics::default(),
             superblock,
-            inodes: Bitmap::<Inode>::new(&superblock),
+            inodes,
             blocks: Bitmap::<Block>::new(&superblock),
             device,
             cache: Cache::default(),
             last_flush: None,
+            flush_interval: DIRTY_PAGE_MAX_SECONDS,
+            limits: Limits::default(),
+            invalidations: Invalidations::default(),
+            health: HealthMonitor::default(),
+            read_only: false,
+            options: superblock.default_options(),
+        }
+    }
+
+    /// Create root directory of a new filesystem and write it to its block device
+    pub fn format(fs: &Arc<Mutex<Self>>, owner: Owner) -> Result<(), Error> {
+        if fs.lock_fs()?.inodes.get(ROOT_INODE)? {
+            return Err(Error::DoubleAcquire);
+        }
+        Directory::new(fs, ROOT_INODE, "root", 0o750, owner)?;
+        info!("Root directory created");
+        fs.lock_fs()?.force_flush()
+    }
+
+    /// Select checksum algorithm of a newly created filesystem
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.superblock.checksum_algorithm = algorithm as u8;
+        self
+    }
+
+    /// Record default mount options of a newly created filesystem
+    ///
+    /// Option `compress` compresses data blocks with LZ4, which needs their
+    /// checksums to tell compressed blocks apart.
+    pub fn with_options(mut self, options: MountOptions) -> Self {
+        self.superblock.default_options = options.bits();
+        self.options = options;
+        if options.contains(MountOptions::COMPRESS) {
+            if let Err(e) = self.superblock.set_compression(CompressionAlgorithm::Lz4) {
+                warn!("Blocks are not compressed without block checksums: {e}");
+            }
+        }
+        self
+    }
+
+    /// Assign unique identifier to a newly created filesystem
+    pub fn with_uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.superblock.uuid = uuid;
+        self
+    }
+
+    /// Record sizes of devices a newly created filesystem spans, in order
+    pub fn with_members(mut self, sizes: &[u64]) -> Result<Self, Error> {
+        self.superblock.set_members(sizes)?;
+        Ok(self)
+    }
+
+    /// Check that the filesystem is loaded from the devices it spans, given
+    /// their sizes in order
+    pub fn check_members(&self, sizes: &[u64]) -> Result<(), Error> {
+        let members = self.superblock.members();
+        match members.is_empty() {
+            true if sizes.len() <= 1 => Ok(()),
+            false if members == sizes => Ok(()),
+            _ => {
+                error!("Filesystem spans devices of sizes {members:?}, not {sizes:?}");
+                Err(Error::InvalidArgument)
+            }
+        }
+    }
+
+    /// Bound directory trees grown on this mount by `limits`
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Limit bytes held by cached inodes and blocks
+    pub fn with_cache_size(mut self, bytes: usize) -> Self {
+        self.cache.budget = bytes;
+        self
+    }
+
+    /// Flush changes kept in cache at least every `interval`
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Enable `options` for this mount only, on top of default ones
+    /// recorded in superblock
+    pub fn with_mount_options(mut self, options: MountOptions) -> Self {
+        self.options = MountOptions::from_bits(self.options.bits() | options.bits());
+        self
+    }
+
+    /// Keep quotas of users and groups on a newly created filesystem
+    pub(crate) fn with_quotas(mut self) -> Self {
+        self.superblock.incompat_flags |= INCOMPAT_QUOTA;
+        self.quotas = Quotas::new(&self.superblock);
+        self
+    }
+
+    /// Count a mount of the filesystem, warning once it should be checked
+    pub fn count_mount(&mut self) {
+        self.superblock.mount_count = self.superblock.mount_count.saturating_add(1);
+        let (count, max) = (self.superblock.mount_count, self.superblock.max_mount_count);
+        if max > 0 && count >= max {
+            warn!("Filesystem was mounted {count} times without being checked");
+        }
+    }
+
+    /// Record in superblock that filesystem is mounted read-write, returning
+    /// whether it was unmounted cleanly before
+    ///
+    /// The state is written at once, so it is found on the device if the
+    /// driver stops without [mark_unmounted](Self::mark_unmounted). A
+    /// filesystem which was not unmounted cleanly should be [recovered](Self::recover).
+    pub fn mark_mounted(&mut self) -> Result<bool, Error> {
+        let clean = self.superblock.is_clean();
+        if !clean {
+            warn!("Filesystem was not unmounted cleanly or is mounted elsewhere");
+            match self.superblock.incompat_flags & INCOMPAT_JOURNAL {
+                _ if self.read_only => {
+                    warn!("Check it with fsck, or mount it read-write to replay its journal")
+                }
+                0 => warn!("Check it with fsck, as it has no journal to replay"),
+                _ => info!("Journal of filesystem was replayed"),
+            }
+        }
+        if self.read_only {
+            return Ok(clean);
+        }
+        self.superblock.state = STATE_MOUNTED;
+        self.force_flush()?;
+        Ok(clean)
+    }
+
+    /// Flush filesystem and record in superblock that it was unmounted cleanly
+    ///
+    /// A filesystem which ran into errors stays marked as mounted, so its next
+    /// mount suggests checking it.
+    pub fn mark_unmounted(&mut self) -> Result<(), Error> {
+        if self.health.state() == Health::Clean {
+            self.superblock.state = STATE_CLEAN;
+        } else {
+            warn!(
+                "Filesystem is {}, not marking it clean",
+                self.health.state()
+            );
         }
+        self.force_flush()
     }
 
     /// Returns block size of an existing filesystem on `device` by checking magic signature
-    pub(crate) fn detect_existing(device: &mut dyn BlockDevice) -> Result<Option<u32>, Error> {
+    pub fn detect_existing(device: &mut dyn BlockDevice) -> Result<Option<u32>, Error> {
         for pow in 9..=12 {
             let block_size = u64::pow(2, pow);
             device.seek(std::io::SeekFrom::Start(block_size + 0x38))?;
@@ -73,35 +1008,128 @@ impl Filesystem {
                 return Ok(Some(block_size as u32));
             }
         }
+        if let Some(backup) = Superblock::load_backup(device)? {
+            let block_size = backup.block_size;
+            warn!("Detected existing filesystem with block size {block_size} by backup superblock");
+            return Ok(Some(block_size));
+        }
         Ok(None)
     }
 
     /// Load filesystem from a block device
-    pub(crate) fn load(device: Box<dyn BlockDevice>, block_size: u32) -> Result<Self, Error> {
+    pub fn load(device: Box<dyn BlockDevice>, block_size: u32) -> Result<Self, Error> {
+        Self::load_with(device, block_size, false)
+    }
+
+    /// Load filesystem from a block device without writing to it, as another
+    /// instance may be using it
+    ///
+    /// The journal is not replayed, so transactions which were not applied
+    /// are missing until the filesystem is mounted read-write.
+    pub fn load_read_only(device: Box<dyn BlockDevice>, block_size: u32) -> Result<Self, Error> {
+        Self::load_with(device, block_size, true)
+    }
+
+    fn load_with(
+        device: Box<dyn BlockDevice>,
+        block_size: u32,
+        read_only: bool,
+    ) -> Result<Self, Error> {
         let mut device = device;
+        let mut superblock = Superblock::load(&mut device, block_size)?;
+        let writable = superblock.check_compatibility()? && !read_only;
+        if !writable {
+            warn!("Not replaying journal of a read-only filesystem");
+        } else if journal::replay(&mut device, &superblock)? > 0 {
+            superblock = Superblock::load(&mut device, block_size)?;
+        }
+        Self::load_bitmaps(device, superblock, writable)
+    }
+
+    /// Load filesystem from a block device as it was after journal transaction `sequence`
+    ///
+    /// The device is only read, and changes are rejected as on a read-only filesystem.
+    pub fn load_at(
+        device: Box<dyn BlockDevice>,
+        block_size: u32,
+        sequence: u64,
+    ) -> Result<Self, Error> {
+        let mut device = device;
+        let superblock = Superblock::load(&mut device, block_size)?;
+        superblock.check_compatibility()?;
+        let patches = journal::rewind(&mut device, &superblock, sequence)?;
+        info!("Rewinding filesystem to journal transaction {sequence}");
+        let mut device: Box<dyn BlockDevice> = Box::new(OverlayDevice::new(device, patches));
         let superblock = Superblock::load(&mut device, block_size)?;
+        Self::load_bitmaps(device, superblock, false)
+    }
+
+    /// Sequence numbers of the earliest and the last transaction the filesystem can be loaded at
+    pub(crate) fn journal_history(&mut self) -> Result<(u64, u64), Error> {
+        let history = journal::history(&mut self.device, &self.superblock)?;
+        Ok((history.oldest(), history.sequence))
+    }
+
+    /// Forget undo records of the journal, so the filesystem cannot be rewound past this point
+    pub(crate) fn forget_history(&mut self) -> Result<(), Error> {
+        journal::forget(&mut self.device, &self.superblock)
+    }
+
+    /// Load bitmaps of filesystem described by `superblock`
+    fn load_bitmaps(
+        mut device: Box<dyn BlockDevice>,
+        superblock: Superblock,
+        writable: bool,
+    ) -> Result<Self, Error> {
+        let spanned: u64 = superblock.members().iter().sum();
+        if device.seek(std::io::SeekFrom::End(0))? < spanned {
+            error!("Filesystem spans {spanned} bytes of devices missing some of them");
+            return Err(Error::InvalidArgument);
+        }
+        let checksum = superblock.checksum_algorithm()?;
+        debug!("Using {checksum} checksums");
+        let options = superblock.default_options();
+        debug!("Using default mount options {options}");
+        superblock.check_inode_chunks()?;
         let mut bitmaps = (
             Bitmap::<Inode>::new(&superblock),
             Bitmap::<Block>::new(&superblock),
         );
         bitmaps.0.load(&mut device)?;
         bitmaps.1.load(&mut device)?;
-        Ok(Self {
+        let mut fs = Self {
             superblock,
             inodes: bitmaps.0,
             blocks: bitmaps.1,
             device,
             cache: Cache::default(),
             last_flush: None,
-        })
+            flush_interval: DIRTY_PAGE_MAX_SECONDS,
+            limits: Limits::default(),
+            invalidations: Invalidations::default(),
+            health: HealthMonitor::default(),
+            read_only: !writable,
+            options,
+            quotas: Quotas::default(),
+            shares: Shares::default(),
+            released: BTreeSet::new(),
+            accesses: 0,
+            statistics: Statistics::default(),
+        };
+        fs.load_quotas()?;
+        fs.load_shares()?;
+        Ok(fs)
     }
 
     /// Flush filesystem changes to cache and periodically call [`Self::force_flush`]
+    ///
+    /// Cache exceeding its budget is flushed right away, so that its modified
+    /// lines can be evicted.
     pub(crate) fn flush(&mut self) -> Result<(), Error> {
         debug!("Invoking filesystem flush");
-        if !FORCE_FLUSH_ALWAYS {
+        if !FORCE_FLUSH_ALWAYS && !self.cache.over_budget() {
             if let Some(last) = self.last_flush {
-                if Instant::now().duration_since(last) < DIRTY_PAGE_MAX_SECONDS {
+                if Instant::now().duration_since(last) < self.flush_interval {
                     return Ok(());
                 }
             }
@@ -111,94 +1139,252 @@ impl Filesystem {
     }
 
     /// Force flush filesystem changes to its block device
-    pub(crate) fn force_flush(&mut self) -> Result<(), Error> {
-        info!("Flushing filesystem to disk");
-        self.flush_cache()?;
-        self.superblock.flush(&mut self.device)?;
-        self.inodes.flush(&mut self.device)?;
-        self.blocks.flush(&mut self.device)?;
-        self.last_flush = Some(Instant::now());
-        Ok(())
-    }
-
-    fn flush_cache(&mut self) -> Result<(), Error> {
-        debug!("Flushing cache to disk");
-        for inode in self.cache.inodes.values_mut() {
-            if inode.modified {
-                inode.value.flush(&mut self.device, &self.superblock)?;
-                inode.modified = false;
-            }
+    ///
+    /// Once errors made the filesystem read-only, changes are kept in cache
+    /// instead, so a failing device is not written any further.
+    pub fn force_flush(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            debug!("Not flushing read-only filesystem");
+            return Ok(());
         }
-        for block in self.cache.blocks.values_mut() {
-            if block.modified {
-                block.value.flush(&mut self.device, &self.superblock)?;
-                block.modified = false;
-            }
+        if self.health.state() == Health::ReadOnly {
+            warn!("Not flushing filesystem made read-only by errors");
+            return Ok(());
         }
-        self.cache.prune()?;
+        info!("Flushing filesystem to disk");
+        let mut transaction = Transaction::default();
+        self.flush_quotas(&mut transaction)?;
+        self.flush_shares(&mut transaction)?;
+        debug!("Flushing cache to disk");
+        self.cache.write_back(&mut transaction, &self.superblock)?;
+        self.superblock.flush(&mut transaction)?;
+        self.inodes.flush(&mut transaction)?;
+        self.blocks.flush(&mut transaction)?;
+        let committed = journal::commit(&mut self.device, &self.superblock, transaction);
+        self.health.check_write(committed)?;
+        self.health
+            .check_write(self.device.sync().map_err(Error::from))?;
+        self.statistics.flushes += 1;
+        self.discard_released();
+        self.cache.evict();
+        self.last_flush = Some(Instant::now());
         Ok(())
     }
 
-    /// Get index of first empty inode
-    pub(crate) fn acquire_inode(&mut self) -> Result<u64, Error> {
-        if let Some(index) = self.inodes.next_free(0) {
-            debug!("Acquire inode {index}");
-            if index >= self.superblock.inode_count {
-                return Err(Error::OutOfMemory);
-            }
-            self.superblock.inodes_free -= 1;
-            self.inodes.set(index, true)?;
-            self.flush()?;
-            Ok(index)
-        } else {
-            Err(Error::OutOfMemory)
-        }
+    /// Get index of first empty inode, charged to `owner`, allocating another
+    /// chunk of inodes if there is none
+    pub(crate) fn acquire_inode(&mut self, owner: Owner) -> Result<u64, Error> {
+        let index = match self.free_inode() {
+            Some(index) => index,
+            None => self.add_inode_chunk()?,
+        };
+        self.quotas.charge(owner, 0, 1)?;
+        debug!("Acquire inode {index}");
+        self.statistics.inodes_allocated += 1;
+        self.superblock.inodes_free -= 1;
+        self.inodes.set(index, true)?;
+        Ok(index)
     }
 
-    /// Release inode at index
-    pub(crate) fn release_inode(&mut self, index: u64) -> Result<(), Error> {
+    /// Release inode at index, charged to `owner`
+    pub(crate) fn release_inode(&mut self, index: u64, owner: Owner) -> Result<(), Error> {
         if self.inodes.get(index)? {
             debug!("Release inode {index}");
             self.superblock.inodes_free += 1;
             self.inodes.set(index, false)?;
-            self.flush()?;
+            self.quotas.release(owner, 0, 1);
             Ok(())
         } else {
             Err(Error::DoubleRelease)
         }
     }
 
-    /// Get index of first empty block
-    pub(crate) fn acquire_block(&mut self) -> Result<u64, Error> {
-        if let Some(index) = self.blocks.next_free(0) {
-            if index >= self.superblock.block_count {
-                return Err(Error::OutOfMemory);
+    /// Blocks kept free for privileged users and metadata
+    pub fn reserved_blocks(&self) -> u64 {
+        self.superblock.block_count * self.superblock.reserved_percent as u64 / 100
+    }
+
+    /// Free blocks `owner` may acquire for file contents
+    pub fn blocks_available(&self, owner: Owner) -> u64 {
+        match owner.uid {
+            0 => self.superblock.blocks_free,
+            _ => self
+                .superblock
+                .blocks_free
+                .saturating_sub(self.reserved_blocks()),
+        }
+    }
+
+    /// Blocks of file contents fitting into `blocks` free blocks, once the
+    /// tables indexing them are taken out
+    pub fn data_blocks(&self, blocks: u64) -> u64 {
+        if self.superblock.incompat_flags & INCOMPAT_BLOCK_TABLES == 0 {
+            return blocks;
+        }
+        let entries = self.superblock.block_size as u64 / size_of::<u64>() as u64;
+        blocks - blocks.div_ceil(entries + 1)
+    }
+
+    /// Refuse `count` blocks to `owner` if they would cut into reserved blocks
+    fn check_reserve(&self, count: u64, owner: Owner) -> Result<(), Error> {
+        if count > self.blocks_available(owner) {
+            info!(
+                "Only reserved blocks are left, refusing {count} to user {}",
+                owner.uid
+            );
+            return Err(Error::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Fail before anything is acquired if `count` blocks cannot be charged
+    /// to `owner`, counting reserved blocks only for `metadata`
+    pub(crate) fn check_space(
+        &self,
+        count: u64,
+        owner: Owner,
+        metadata: bool,
+    ) -> Result<(), Error> {
+        self.quotas.check(owner, count, 0)?;
+        if !metadata {
+            self.check_reserve(count, owner)?;
+        }
+        if count > self.superblock.blocks_free {
+            info!("Only {} free blocks are left, refusing {count}", {
+                self.superblock.blocks_free
+            });
+            return Err(Error::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Get index of next empty block, charged to `owner`
+    ///
+    /// Unprivileged owners cannot take reserved blocks.
+    pub(crate) fn acquire_block(&mut self, owner: Owner) -> Result<u64, Error> {
+        self.check_reserve(1, owner)?;
+        self.acquire_metadata_block(owner)
+    }
+
+    /// Get indices of `count` empty blocks, charged to `owner`
+    ///
+    /// Unprivileged owners cannot take reserved blocks.
+    pub(crate) fn acquire_blocks(&mut self, count: u64, owner: Owner) -> Result<Vec<u64>, Error> {
+        self.check_reserve(count, owner)?;
+        self.acquire_metadata_blocks(count, owner)
+    }
+
+    /// Get index of next empty block for metadata, charged to `owner`
+    ///
+    /// Reserved blocks may be taken, so that directories are still flushed,
+    /// and files removed, once unprivileged users have filled the disk.
+    pub(crate) fn acquire_metadata_block(&mut self, owner: Owner) -> Result<u64, Error> {
+        self.quotas.charge(owner, 1, 0)?;
+        let allocated = self.allocate_block();
+        if allocated.is_err() {
+            self.quotas.release(owner, 1, 0);
+        }
+        allocated
+    }
+
+    /// Get indices of `count` empty blocks for metadata, charged to `owner`
+    ///
+    /// Blocks form a contiguous run if one is free, so that files extended
+    /// by many blocks at once are read sequentially.
+    pub(crate) fn acquire_metadata_blocks(
+        &mut self,
+        count: u64,
+        owner: Owner,
+    ) -> Result<Vec<u64>, Error> {
+        self.quotas.charge(owner, count, 0)?;
+        let allocated = self.allocate_blocks(count);
+        if allocated.is_err() {
+            self.quotas.release(owner, count, 0);
+        }
+        allocated
+    }
+
+    /// Release block at index, charged to `owner`
+    pub(crate) fn release_block(&mut self, index: u64, owner: Owner) -> Result<(), Error> {
+        self.free_block(index)?;
+        self.quotas.release(owner, 1, 0);
+        Ok(())
+    }
+
+    /// Mark next empty block as used, without charging anyone
+    ///
+    /// Search starts after the last allocated block and wraps around, so
+    /// the occupied beginning of the block region is not scanned every time.
+    fn allocate_block(&mut self) -> Result<u64, Error> {
+        let hint = self.superblock.block_allocation_hint;
+        let index = self
+            .blocks
+            .next_free_wrapping(hint)
+            .ok_or(Error::OutOfMemory)?;
+        if index >= self.superblock.block_count {
+            return Err(Error::OutOfMemory);
+        }
+        debug!("Acquire block {index}");
+        self.statistics.blocks_allocated += 1;
+        self.superblock.blocks_free -= 1;
+        self.superblock.block_allocation_hint = index + 1;
+        self.blocks.set(index, true)?;
+        Ok(index)
+    }
+
+    /// Mark `count` empty blocks as used, without charging anyone
+    ///
+    /// The first free run of `count` blocks after the allocation hint is
+    /// taken, or else blocks are taken one by one wherever they are free.
+    fn allocate_blocks(&mut self, count: u64) -> Result<Vec<u64>, Error> {
+        if count > self.superblock.blocks_free {
+            return Err(Error::OutOfMemory);
+        }
+        let hint = self.superblock.block_allocation_hint;
+        let run = self
+            .blocks
+            .next_free_range(hint, count)
+            .or_else(|| self.blocks.next_free_range(0, count));
+        let Some(start) = run else {
+            let mut indices = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                match self.allocate_block() {
+                    Ok(index) => indices.push(index),
+                    Err(e) => {
+                        for index in indices {
+                            self.free_block(index)?;
+                        }
+                        return Err(e);
+                    }
+                }
             }
-            debug!("Acquire block {index}");
-            self.superblock.blocks_free -= 1;
+            return Ok(indices);
+        };
+        debug!("Acquire blocks {start} to {}", start + count - 1);
+        for index in start..start + count {
             self.blocks.set(index, true)?;
-            self.flush()?;
-            Ok(index)
-        } else {
-            Err(Error::OutOfMemory)
         }
+        self.statistics.blocks_allocated += count;
+        self.superblock.blocks_free -= count;
+        self.superblock.block_allocation_hint = start + count;
+        Ok((start..start + count).collect())
     }
 
-    /// Release inode at block
-    pub(crate) fn release_block(&mut self, index: u64) -> Result<(), Error> {
-        if self.blocks.get(index)? {
-            debug!("Release block {index}");
-            self.superblock.blocks_free += 1;
-            self.blocks.set(index, false)?;
-            self.flush()?;
-            Ok(())
-        } else {
-            Err(Error::DoubleRelease)
+    /// Mark block at index as empty, without releasing its charge
+    fn free_block(&mut self, index: u64) -> Result<(), Error> {
+        if !self.blocks.get(index)? {
+            return Err(Error::DoubleRelease);
         }
+        debug!("Release block {index}");
+        self.superblock.blocks_free += 1;
+        if self.options.contains(MountOptions::DISCARD) {
+            self.released.insert(index);
+        }
+        self.blocks.set(index, false)
     }
 
     /// Load inode with index
-    pub(crate) fn load_inode(&mut self, index: u64) -> Result<Inode, Error> {
+    pub fn load_inode(&mut self, index: u64) -> Result<Inode, Error> {
         if !self.inodes.get(index)? {
             return Err(Error::OutOfBounds);
         }
@@ -206,8 +1392,9 @@ impl Filesystem {
         if let Some(inode) = self.cache.get_inode(index) {
             Ok(inode)
         } else {
-            let inode = Inode::load(&mut self.device, &self.superblock, index)?;
-            self.cache.set_inode(&inode);
+            let inode = Inode::load(&mut self.device, &self.superblock, index);
+            let inode = self.health.check_read(inode)?;
+            self.cache.insert_inode(&inode);
             Ok(inode)
         }
     }
@@ -222,54 +1409,152 @@ impl Filesystem {
         if empty {
             debug!("Load empty block {index}");
             let block = Block::with_index(self, index)?;
-            self.cache.set_block(&block);
+            self.cache.write_block(&block);
             return Ok(block);
         }
         debug!("Load block {index}");
         if let Some(block) = self.cache.get_block(index) {
             Ok(block)
         } else {
-            let block = Block::load(&mut self.device, &self.superblock, index)?;
-            self.cache.set_block(&block);
+            let block = Block::load(&mut self.device, &self.superblock, index);
+            let block = self.health.check_read(block)?;
+            self.cache.insert_block(&block);
             Ok(block)
         }
     }
 
-    /// Flush inode
-    pub(crate) fn flush_inode(&mut self, inode: &Inode) -> Result<(), Error> {
+    /// Load inode with index even if it is free or its checksum does not match,
+    /// caching it as loaded so that changes to it are flushed
+    pub fn load_inode_unverified(&mut self, index: u64) -> Result<Inode, Error> {
+        if let Some(inode) = self.cache.get_inode(index) {
+            return Ok(inode);
+        }
+        let mut inode = Inode::load_unverified(&mut self.device, &self.superblock, index)?;
+        // Stored index may be damaged as well, while changes belong at `index`
+        inode.index = index;
+        self.cache.insert_inode(&inode);
+        Ok(inode)
+    }
+
+    /// Load block with index as [load_block](Self::load_block), borrowing it
+    /// from cache instead of cloning it if it is cached
+    pub(crate) fn borrow_block(&mut self, index: u64) -> Result<Cow<'_, Block>, Error> {
+        if !self.blocks.get(index)? {
+            return Err(Error::OutOfBounds);
+        }
+        if self.cache.contains_block(index) {
+            return self
+                .cache
+                .borrow_block(index)
+                .map(Cow::Borrowed)
+                .ok_or(Error::NotFound);
+        }
+        debug!("Load block {index}");
+        let block = Block::load(&mut self.device, &self.superblock, index);
+        let block = self.health.check_read(block)?;
+        self.cache.insert_block(&block);
+        Ok(Cow::Owned(block))
+    }
+
+    /// Cache `count` consecutive blocks starting with `first` in a single read
+    /// of the device, skipping free, already cached and corrupted ones
+    pub(crate) fn prefetch_blocks(&mut self, first: u64, count: u64) -> Result<(), Error> {
+        let count = count.min(self.superblock.block_count.saturating_sub(first));
+        if count == 0 {
+            return Ok(());
+        }
+        debug!("Prefetch {count} blocks starting with {first}");
+        let run = Block::load_run(&mut self.device, &self.superblock, first, count)?;
+        for block in run.into_iter().flatten() {
+            if self.blocks.get(block.index)? && !self.cache.contains_block(block.index) {
+                self.cache.insert_block(&block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load block with index even if it is free or its checksum does not match,
+    /// caching it as loaded so that changes to it are flushed
+    pub fn load_block_unverified(&mut self, index: u64) -> Result<Block, Error> {
+        if let Some(block) = self.cache.get_block(index) {
+            return Ok(block);
+        }
+        let block = Block::load_unverified(&mut self.device, &self.superblock, index)?;
+        self.cache.insert_block(&block);
+        Ok(block)
+    }
+
+    /// Mark inode as modified in cache and periodically flush filesystem
+    ///
+    /// Operations write their inodes after their blocks, so the filesystem is
+    /// consistent when it is flushed here.
+    pub fn flush_inode(&mut self, inode: &Inode) -> Result<(), Error> {
         let index = inode.index;
         debug!("Flush inode {index}");
-        self.cache.set_inode(inode);
+        self.cache.write_inode(inode);
+        self.invalidate(index);
         self.flush()?;
         Ok(())
     }
 
-    /// Flush block
-    pub(crate) fn flush_block(&mut self, block: &Block) -> Result<(), Error> {
+    /// Memory held by caches and bitmaps
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            block_cache: self.cache.block_bytes(),
+            inode_cache: self.cache.inode_bytes(),
+            bitmaps: self.inodes.memory_usage() + self.blocks.memory_usage(),
+            directories: 0,
+            delayed_writes: 0,
+        }
+    }
+
+    /// Report modified inode to registered callbacks
+    pub(crate) fn invalidate(&self, index: u64) {
+        debug!("Invalidate inode {index}");
+        self.invalidations.notify(Invalidation::Inode(index));
+    }
+
+    /// Report name added to or removed from directory to registered callbacks
+    pub(crate) fn invalidate_entry(&self, parent: u64, name: &str) {
+        debug!("Invalidate entry {name} of directory {parent}");
+        self.invalidations.notify(Invalidation::Entry {
+            parent,
+            name: name.to_owned(),
+        });
+    }
+
+    /// Mark block as modified in cache, to be written back on the next flush
+    pub fn flush_block(&mut self, block: &Block) -> Result<(), Error> {
         debug!("Flush block {}", &block.index);
-        self.cache.set_block(block);
-        self.flush()?;
+        self.cache.write_block(block);
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use fuser::FileType;
+    use std::borrow::Cow;
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
 
-    use super::{BlockDevice, Filesystem};
-
-    impl BlockDevice for Cursor<Vec<u8>> {}
+    use super::invalidation::{self, Invalidation};
+    use super::{health::Health, Filesystem, FuseFs, StatFs, RESERVED_INODE, ROOT_INODE};
+    use crate::filetypes::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    };
+    use crate::structs::{Block, MountOptions, Superblock};
+    use crate::Error;
 
     #[test]
     fn load_and_flush() {
         let dev = Cursor::new(vec![0u8; 10_000_000]);
         let mut fs = Filesystem::new(Box::new(dev), 10_000_000, 512);
-        assert![fs.acquire_block().is_ok()];
+        assert![fs.acquire_block(Owner::default()).is_ok()];
         assert!(fs.flush().is_ok());
         let dev = fs.device;
         let fs = Filesystem::load(dev, 512).unwrap();
-        assert_eq![fs.blocks.get(0).unwrap(), true];
+        assert![fs.blocks.get(0).unwrap()];
         assert_eq![fs.superblock.block_count - fs.superblock.blocks_free, 1];
     }
 
@@ -277,18 +1562,19 @@ mod tests {
     fn acquire_and_release_inode() {
         let dev = Cursor::new(vec![0u8; 10_000_000]);
         let mut fs = Filesystem::new(Box::new(dev), 10_000_000, 512);
-        assert_eq![fs.acquire_inode().unwrap(), 0];
-        assert_eq![fs.acquire_inode().unwrap(), 1];
-        assert_eq![fs.acquire_inode().unwrap(), 2];
-        assert![fs.release_inode(1).is_ok()];
-        assert![fs.release_inode(1).is_err()];
-        assert_eq![fs.acquire_inode().unwrap(), 1];
-        assert_eq![fs.acquire_inode().unwrap(), 3];
-        for index in 4..fs.superblock.inode_count {
-            assert_eq![fs.acquire_inode().unwrap(), index];
+        assert_eq![fs.acquire_inode(Owner::default()).unwrap(), ROOT_INODE];
+        assert_eq![fs.acquire_inode(Owner::default()).unwrap(), 2];
+        assert_eq![fs.acquire_inode(Owner::default()).unwrap(), 3];
+        assert![fs.release_inode(2, Owner::default()).is_ok()];
+        assert![fs.release_inode(2, Owner::default()).is_err()];
+        assert_eq![fs.acquire_inode(Owner::default()).unwrap(), 2];
+        assert_eq![fs.acquire_inode(Owner::default()).unwrap(), 4];
+        for index in 5..fs.superblock.inode_count {
+            assert_eq![fs.acquire_inode(Owner::default()).unwrap(), index];
         }
-        for index in 4..fs.superblock.inode_count {
-            assert![fs.release_inode(index).is_ok()];
+        assert_eq!({ fs.superblock.inodes_free }, 0);
+        for index in 5..fs.superblock.inode_count {
+            assert![fs.release_inode(index, Owner::default()).is_ok()];
         }
     }
 
@@ -296,18 +1582,740 @@ mod tests {
     fn acquire_and_release_block() {
         let dev = Cursor::new(vec![0u8; 10_000_000]);
         let mut fs = Filesystem::new(Box::new(dev), 10_000_000, 4096);
-        assert_eq![fs.acquire_block().unwrap(), 0];
-        assert_eq![fs.acquire_block().unwrap(), 1];
-        assert_eq![fs.acquire_block().unwrap(), 2];
-        assert![fs.release_block(0).is_ok()];
-        assert![fs.release_block(0).is_err()];
-        assert_eq![fs.acquire_block().unwrap(), 0];
-        assert_eq![fs.acquire_block().unwrap(), 3];
-        for index in 4..fs.superblock.block_count {
-            assert_eq![fs.acquire_block().unwrap(), index];
+        assert_eq![fs.acquire_block(Owner::default()).unwrap(), 0];
+        assert_eq![fs.acquire_block(Owner::default()).unwrap(), 1];
+        assert_eq![fs.acquire_block(Owner::default()).unwrap(), 2];
+        assert![fs.release_block(0, Owner::default()).is_ok()];
+        assert![fs.release_block(0, Owner::default()).is_err()];
+        // Released block is reused only once search wraps around
+        for index in 3..fs.superblock.block_count {
+            assert_eq![fs.acquire_block(Owner::default()).unwrap(), index];
         }
+        assert_eq![fs.acquire_block(Owner::default()).unwrap(), 0];
+        assert!(fs.acquire_block(Owner::default()).is_err());
+        assert![fs.release_block(5, Owner::default()).is_ok()];
+        assert_eq![fs.acquire_block(Owner::default()).unwrap(), 5];
         for index in 4..fs.superblock.block_count {
-            assert![fs.release_block(index).is_ok()];
+            assert![fs.release_block(index, Owner::default()).is_ok()];
+        }
+    }
+
+    #[test]
+    fn write_back_marked_blocks() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let index = fs.acquire_block(Owner::default()).unwrap();
+        fs.force_flush().unwrap();
+        let mut block = fs.load_block(index, true).unwrap();
+        block.data.fill(0xAB);
+        fs.flush_block(&block).unwrap();
+        let stored = |fs: &mut Filesystem| {
+            Block::load_unverified(&mut fs.device, &fs.superblock, index)
+                .unwrap()
+                .data
+        };
+        // Flushing a block only marks it modified in cache
+        assert_eq!(stored(&mut fs), [0u8; 512]);
+        assert!(fs.cache.blocks[&index].modified);
+        fs.force_flush().unwrap();
+        assert!(!fs.cache.blocks[&index].modified);
+        assert_eq!(stored(&mut fs), [0xAB; 512]);
+    }
+
+    #[test]
+    fn borrow_cached_blocks() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let index = fs.acquire_block(Owner::default()).unwrap();
+        let mut block = fs.load_block(index, true).unwrap();
+        block.data.fill(0xCD);
+        fs.flush_block(&block).unwrap();
+        fs.force_flush().unwrap();
+        assert!(
+            matches!(fs.borrow_block(index), Ok(Cow::Borrowed(borrowed)) if *borrowed == block)
+        );
+        fs.cache.remove_block(index);
+        assert!(matches!(fs.borrow_block(index), Ok(Cow::Owned(owned)) if owned == block));
+        assert!(fs.cache.contains_block(index));
+        assert!(matches!(
+            fs.borrow_block(index + 1),
+            Err(Error::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn acquire_contiguous_blocks() {
+        let dev = Cursor::new(vec![0u8; 10_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 10_000_000, 4096);
+        let owner = Owner::default();
+        for _ in 0..10 {
+            fs.acquire_block(owner).unwrap();
+        }
+        for index in [2, 4, 5, 7, 8, 9] {
+            fs.release_block(index, owner).unwrap();
+        }
+        fs.superblock.block_allocation_hint = 0;
+        assert_eq!(fs.acquire_blocks(3, owner).unwrap(), vec![7, 8, 9]);
+        assert_eq!({ fs.superblock.block_allocation_hint }, 10);
+        // Without a long enough run, blocks are taken wherever they are free
+        let count = fs.superblock.blocks_free;
+        let indices = fs.acquire_blocks(count, owner).unwrap();
+        assert_eq!(indices[0], 10);
+        assert_eq!(indices[count as usize - 3..], [2, 4, 5]);
+        assert_eq!({ fs.superblock.blocks_free }, 0);
+        assert!(matches!(
+            fs.acquire_blocks(1, owner),
+            Err(Error::OutOfMemory)
+        ));
+    }
+
+    #[test]
+    fn clean_unmount() {
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(device), 1 << 20, 1024)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        assert!(fs.superblock.is_clean());
+        assert!(fs.mark_mounted().unwrap());
+        // State is on the device while mounted, as if the driver was killed
+        let mut fs = Filesystem::load(fs.device, 1024).unwrap();
+        assert!(!fs.superblock.is_clean());
+        fs.mark_unmounted().unwrap();
+        let mut fs = Filesystem::load(fs.device, 1024).unwrap();
+        assert!(fs.superblock.is_clean());
+
+        assert!(fs.mark_mounted().unwrap());
+        let _ = fs.health.check_read::<()>(Err(Error::Corruption));
+        fs.mark_unmounted().unwrap();
+        let mut fs = Filesystem::load(fs.device, 1024).unwrap();
+        assert!(!fs.superblock.is_clean());
+
+        // Errors which made it read-only stop writes to the device
+        let failed = std::io::Error::from(std::io::ErrorKind::WriteZero);
+        let _ = fs.health.check_write::<()>(Err(failed.into()));
+        let flushes = fs.statistics.flushes;
+        fs.force_flush().unwrap();
+        assert_eq!(fs.statistics.flushes, flushes);
+    }
+
+    #[test]
+    fn reserved_blocks() {
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(device), 1 << 20, 1024)));
+        let user = Owner {
+            uid: 1000,
+            gid: 1000,
+        };
+        Filesystem::format(&fs, user).unwrap();
+        fs.lock()
+            .unwrap()
+            .superblock
+            .set_reserved_percent(10)
+            .unwrap();
+        let reserved = fs.lock().unwrap().reserved_blocks();
+        assert!(reserved > 0);
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "fill", 0o644, user).unwrap();
+        let mut size = 0;
+        while file.write(size, &[7u8; 1024]).is_ok() {
+            size += 1024;
+        }
+        drop(file);
+        {
+            let fs = fs.lock().unwrap();
+            assert!(fs.blocks_available(user) < 2);
+            assert!(fs.superblock.blocks_free >= reserved);
+        }
+        // Privileged users still write, and directories still flush
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "root", 0o644, Owner::default()).unwrap();
+        file.write(0, &[7u8; 4096]).unwrap();
+        drop(file);
+        Directory::load(&fs, ROOT_INODE)
+            .unwrap()
+            .remove_child(DirectoryChildIdentifier::Name("fill"))
+            .unwrap();
+        assert!(fs.lock().unwrap().blocks_available(user) > 100);
+    }
+
+    #[test]
+    fn statfs_after_create_and_delete() {
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(device), 1 << 20, 1024)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        fs.lock()
+            .unwrap()
+            .superblock
+            .set_reserved_percent(5)
+            .unwrap();
+        let fuse_fs = FuseFs::new(fs.clone());
+        let before = fuse_fs.usage().unwrap();
+        assert_eq!(before.files, { fs.lock().unwrap().superblock.inode_count });
+        assert_eq!(before.block_size, 1016);
+        assert_eq!(before.name_length, 255);
+        assert!(before.blocks_available < before.blocks_free);
+        assert!(before.blocks_free <= before.blocks);
+
+        let mut files = Vec::new();
+        for name in ["a", "b", "c"] {
+            let mut file =
+                RegularFile::new(&fs, ROOT_INODE, name, 0o644, Owner::default()).unwrap();
+            file.write(0, &[7u8; 10_000]).unwrap();
+            files.push(file.inode.index);
+        }
+        let during = fuse_fs.usage().unwrap();
+        // Blocks of files are counted in units of 512 bytes, as `du` expects
+        let inode = fs.lock().unwrap().load_inode(files[0]).unwrap();
+        let attrs = inode.attrs(&fs.lock().unwrap().superblock);
+        assert_eq!(attrs.blocks, { inode.block_count } * 2);
+        assert_eq!(during.files, before.files);
+        assert_eq!(during.files_free, before.files_free - 3);
+        assert!(during.blocks_free + 30 <= before.blocks_free);
+        // Reserved blocks are the same, up to rounding of the tables indexing them
+        let reserved = |usage: StatFs| usage.blocks_free - usage.blocks_available;
+        assert!(reserved(during).abs_diff(reserved(before)) <= 1);
+
+        for name in ["a", "b", "c"] {
+            Directory::load(&fs, ROOT_INODE)
+                .unwrap()
+                .remove_child(DirectoryChildIdentifier::Name(name))
+                .unwrap();
+        }
+        assert_eq!(fuse_fs.usage().unwrap(), before);
+    }
+
+    #[test]
+    fn reclaim_forgotten_orphan() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        Directory::new(
+            &fuse_fs.filesystem,
+            ROOT_INODE,
+            "root",
+            0o750,
+            Owner::default(),
+        )
+        .unwrap();
+        let mut file = RegularFile::new(
+            &fuse_fs.filesystem,
+            ROOT_INODE,
+            "file",
+            0o640,
+            Owner::default(),
+        )
+        .unwrap();
+        file.write(0, b"still readable").unwrap();
+        let ino = file.inode.index;
+        drop(file);
+        fuse_fs.remember(ino).unwrap();
+        let mirror = fuse_fs.mirror().unwrap();
+        mirror.remember(ino).unwrap();
+        Directory::load(&fuse_fs.filesystem, ROOT_INODE)
+            .unwrap()
+            .detach_child(DirectoryChildIdentifier::Inode(ino))
+            .unwrap();
+        fuse_fs.release_unlinked(ino).unwrap();
+        fuse_fs.forget_lookups(ino, 1).unwrap();
+        let mut file = RegularFile::load(&fuse_fs.filesystem, ino).unwrap();
+        assert_eq!(file.read(0, 14).unwrap(), b"still readable");
+        drop(file);
+        mirror.forget_lookups(ino, 1).unwrap();
+        assert!(!fuse_fs.references.lock().unwrap().referenced(ino));
+        assert!(!fuse_fs.filesystem.lock().unwrap().inodes.get(ino).unwrap());
+    }
+
+    #[test]
+    fn directory_snapshot() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = &fuse_fs.filesystem;
+        Directory::new(fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let dir = Directory::new(fs, ROOT_INODE, "dir", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let file = RegularFile::new(fs, dir, "file", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let snapshot = fuse_fs.snapshot_directory(dir).unwrap();
+        let listing: Vec<_> = snapshot
+            .entries
+            .iter()
+            .map(|e| (e.inode, e.kind, e.name.as_str()))
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                (dir, FileType::Directory, "."),
+                (ROOT_INODE, FileType::Directory, ".."),
+                (file, FileType::RegularFile, "file"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_flags() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = &fuse_fs.filesystem;
+        Directory::new(fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let dir = Directory::new(fs, ROOT_INODE, "dir", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        for name in ["a", "b"] {
+            RegularFile::new(fs, ROOT_INODE, name, 0o640, Owner::default()).unwrap();
         }
+        let lookup = |parent, name| Directory::load(fs, parent).unwrap().lookup(name);
+        let a = lookup(ROOT_INODE, "a").unwrap();
+        let b = lookup(ROOT_INODE, "b").unwrap();
+
+        let noreplace = libc::RENAME_NOREPLACE;
+        assert!(matches!(
+            fuse_fs.move_entry(ROOT_INODE, "a", ROOT_INODE, "b", noreplace),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        assert_eq!(lookup(ROOT_INODE, "b").unwrap(), b);
+        assert!(matches!(
+            fuse_fs.move_entry(ROOT_INODE, "a", ROOT_INODE, "b", libc::RENAME_EXCHANGE),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            fuse_fs.move_entry(ROOT_INODE, "a", dir, "a", 1 << 7),
+            Err(Error::InvalidArgument)
+        ));
+        assert_eq!(lookup(ROOT_INODE, "a").unwrap(), a);
+
+        let replaced = fuse_fs.move_entry(ROOT_INODE, "a", dir, "c", noreplace);
+        assert_eq!(replaced.unwrap(), None);
+        assert_eq!(lookup(dir, "c").unwrap(), a);
+        assert!(matches!(
+            fuse_fs.move_entry(ROOT_INODE, "b", dir, "c", noreplace),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        let replaced = fuse_fs.move_entry(ROOT_INODE, "b", dir, "c", 0);
+        assert_eq!(replaced.unwrap(), Some(a));
+        assert_eq!(lookup(dir, "c").unwrap(), b);
+    }
+
+    #[test]
+    fn open_with_truncate() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = &fuse_fs.filesystem;
+        Directory::new(fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let mut file = RegularFile::new(fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[1; 2_000]).unwrap();
+        let ino = file.inode.index;
+        drop(file);
+        let size = |fuse_fs: &FuseFs| fuse_fs.session().unwrap().attrs(ino).unwrap().size;
+        fuse_fs
+            .open_file(ino, libc::O_RDONLY | libc::O_TRUNC)
+            .unwrap();
+        assert_eq!(size(&fuse_fs), 2_000);
+        let mut mirror = fuse_fs.mirror().unwrap();
+        assert!(matches!(
+            mirror.open_file(ino, libc::O_WRONLY | libc::O_TRUNC),
+            Err(Error::ReadOnly)
+        ));
+        let fh = fuse_fs
+            .open_file(ino, libc::O_WRONLY | libc::O_TRUNC | libc::O_APPEND)
+            .unwrap();
+        assert_eq!(size(&fuse_fs), 0);
+        assert_eq!(fuse_fs.files[&fh] & libc::O_APPEND, libc::O_APPEND);
+    }
+
+    #[test]
+    fn preallocate_blocks() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = fuse_fs.filesystem.clone();
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[0; 50]).unwrap();
+        FileOperations::flush(&mut file).unwrap();
+        let ino = file.inode.index;
+        drop(file);
+        let blocks = |fuse_fs: &FuseFs| {
+            let file = RegularFile::load(&fuse_fs.filesystem, ino).unwrap();
+            let mut blocks = file.file.blocks_locked(&mut fs.lock().unwrap()).unwrap();
+            // Without tables indexing them
+            blocks.truncate(file.file.block_count as usize);
+            blocks
+        };
+
+        // Reserved blocks do not change file's size
+        fuse_fs
+            .allocate(ino, 1_000, 4_000, libc::FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        let reserved = blocks(&fuse_fs);
+        assert_eq!(reserved.len(), 10);
+        assert_eq!(fuse_fs.attrs(ino).unwrap().size, 50);
+        let blocks_free = { fs.lock().unwrap().superblock.blocks_free };
+
+        // Appends fill reserved blocks
+        for line in 1..60 {
+            fuse_fs.write_file(ino, 0, true, &[line; 50]).unwrap();
+        }
+        fuse_fs.write_back_all().unwrap();
+        assert_eq!(blocks(&fuse_fs), reserved);
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+        let data = fuse_fs.session().unwrap().read_file(ino, 0, 3_000).unwrap();
+        assert!(data.chunks(50).zip(0..).all(|(line, i)| line == [i; 50]));
+
+        // Without it, the file is extended with zeros
+        fuse_fs.allocate(ino, 5_500, 500, 0).unwrap();
+        assert_eq!(fuse_fs.attrs(ino).unwrap().size, 6_000);
+        assert_eq!(blocks(&fuse_fs)[..10], reserved);
+        let data = fuse_fs
+            .session()
+            .unwrap()
+            .read_file(ino, 3_000, 3_000)
+            .unwrap();
+        assert_eq!(data, [0; 3_000]);
+        assert!(matches!(
+            fuse_fs.allocate(ino, 0, 100, libc::FALLOC_FL_PUNCH_HOLE),
+            Err(Error::Incompatible)
+        ));
+
+        // Truncating the file releases blocks reserved past its end
+        fuse_fs
+            .allocate(ino, 0, 20_000, libc::FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        fuse_fs.session().unwrap().resize_file(ino, 6_000).unwrap();
+        assert_eq!(blocks(&fuse_fs).len(), 40);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        let mut session = fuse_fs.session().unwrap();
+        session.resize_file(ino, 1_000).unwrap();
+        session.commit().unwrap();
+        assert_eq!(blocks(&fuse_fs).len(), 2);
+    }
+
+    #[test]
+    fn delayed_writes() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = fuse_fs.filesystem.clone();
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let files: Vec<u64> = ["first", "second"]
+            .into_iter()
+            .map(|name| {
+                let file = RegularFile::new(&fs, ROOT_INODE, name, 0o640, Owner::default());
+                file.unwrap().inode.index
+            })
+            .collect();
+        let blocks_free = { fs.lock().unwrap().superblock.blocks_free };
+        // Appends to both files interleave, but their blocks do not
+        for line in 0..100 {
+            for &ino in files.iter() {
+                fuse_fs.write_file(ino, 0, true, &[line; 50]).unwrap();
+            }
+        }
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+        assert_eq!(fuse_fs.attrs(files[0]).unwrap().size, 5_000);
+        assert_eq!(fuse_fs.session().unwrap().attrs(files[0]).unwrap().size, 0);
+        // Read-only mirror sees data buffered through the primary mount
+        let mut mirror = fuse_fs.mirror().unwrap();
+        assert_eq!(mirror.attrs(files[1]).unwrap().size, 5_000);
+        mirror.write_back(files[1]).unwrap();
+        assert_eq!(
+            fuse_fs.session().unwrap().attrs(files[1]).unwrap().size,
+            5_000
+        );
+        drop(mirror);
+        fuse_fs.write_back_all().unwrap();
+        for &ino in files.iter() {
+            let mut file = RegularFile::load(&fs, ino).unwrap();
+            let blocks = file.file.blocks_locked(&mut fs.lock().unwrap()).unwrap();
+            // First block was acquired when file was created
+            let appended = &blocks[1..file.file.block_count as usize];
+            assert!(appended.windows(2).all(|pair| pair[1] == pair[0] + 1));
+            let data = file.read(0, 5_000).unwrap();
+            assert!(data.chunks(50).zip(0..).all(|(line, i)| line == [i; 50]));
+        }
+        // Without delayed allocation, blocks are acquired by the write itself
+        fs.lock().unwrap().options = MountOptions::NODELALLOC;
+        fuse_fs
+            .write_file(files[0], 5_000, false, &[1; 1_000])
+            .unwrap();
+        assert!(fuse_fs.delayed.lock().unwrap().inodes().is_empty());
+        assert_eq!(
+            fuse_fs.session().unwrap().attrs(files[0]).unwrap().size,
+            6_000
+        );
+    }
+
+    #[test]
+    fn copy_file_ranges() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = fuse_fs.filesystem.clone();
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let files: Vec<u64> = ["source", "target"]
+            .into_iter()
+            .map(|name| {
+                let file = RegularFile::new(&fs, ROOT_INODE, name, 0o640, Owner::default());
+                file.unwrap().inode.index
+            })
+            .collect();
+        let data: Vec<u8> = (0..20_000).map(|i| i as u8).collect();
+        fuse_fs.write_file(files[0], 0, false, &data).unwrap();
+        fuse_fs.write_back_all().unwrap();
+        let blocks_free = { fs.lock().unwrap().superblock.blocks_free };
+        // Whole file is cloned without acquiring any blocks
+        let copied = fuse_fs.copy_range((files[0], 0), (files[1], 0), 1 << 30);
+        assert_eq!(copied.unwrap(), 20_000);
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+        let read = |ino| {
+            RegularFile::load(&fs, ino)
+                .unwrap()
+                .read(0, 30_000)
+                .unwrap()
+        };
+        assert_eq!(read(files[1]), data);
+        // Parts of a file are copied
+        let copied = fuse_fs.copy_range((files[0], 0), (files[1], 0), 1_000);
+        assert_eq!(copied.unwrap(), 1_000);
+        assert_eq!(read(files[1]), data);
+        let copied = fuse_fs.copy_range((files[0], 100), (files[1], 19_000), 5_000);
+        assert_eq!(copied.unwrap(), 5_000);
+        let mut expected = data.clone();
+        expected.truncate(19_000);
+        expected.extend_from_slice(&data[100..5_100]);
+        assert_eq!(read(files[1]), expected);
+        assert_eq!(read(files[0]), data);
+        let copied = fuse_fs.copy_range((files[0], 30_000), (files[1], 0), 10);
+        assert_eq!(copied.unwrap(), 0);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+    }
+
+    #[test]
+    fn invalidated_directory_snapshot() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        fuse_fs.watch_invalidations().unwrap();
+        let fs = fuse_fs.filesystem.clone();
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let snapshot = fuse_fs.snapshot_directory(ROOT_INODE).unwrap();
+        fuse_fs.directories.insert(1, snapshot);
+        fuse_fs.rewind_directory(ROOT_INODE, 1).unwrap();
+        assert_eq!(fuse_fs.directories[&1].entries.len(), 2);
+        RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        assert!(fuse_fs.mirror().unwrap().generation(ROOT_INODE).unwrap() > 0);
+        fuse_fs.rewind_directory(ROOT_INODE, 1).unwrap();
+        assert_eq!(fuse_fs.directories[&1].entries.len(), 3);
+
+        // Changes made through the mount itself are known to its kernel
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let recorded = entries.clone();
+        fs.lock()
+            .unwrap()
+            .invalidations
+            .register(move |origin, invalidation| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((origin, invalidation.clone()));
+            });
+        invalidation::serve(Some(fuse_fs.mount));
+        let generation = fuse_fs.generation(ROOT_INODE).unwrap();
+        Directory::new(&fs, ROOT_INODE, "dir", 0o750, Owner::default()).unwrap();
+        invalidation::serve(None);
+        assert_eq!(fuse_fs.generation(ROOT_INODE).unwrap(), generation);
+        assert!(entries.lock().unwrap().contains(&(
+            Some(fuse_fs.mount),
+            Invalidation::Entry {
+                parent: ROOT_INODE,
+                name: "dir".to_owned()
+            }
+        )));
+    }
+
+    #[test]
+    fn corrupted_inode_degrades_health() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = fuse_fs.filesystem.clone();
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let mut fs_handle = fs.lock().unwrap();
+        fs_handle.force_flush().unwrap();
+        fs_handle.cache.remove_inode(ROOT_INODE);
+        let position = fs_handle.superblock.inode_position(ROOT_INODE).unwrap();
+        fs_handle.device.seek(SeekFrom::Start(position)).unwrap();
+        fs_handle.device.write_all(&[0xFF; 8]).unwrap();
+        assert!(matches!(
+            fs_handle.load_inode(ROOT_INODE),
+            Err(Error::Corruption)
+        ));
+        assert_eq!(fs_handle.health.state(), Health::Degraded);
+        drop(fs_handle);
+        assert!(fuse_fs.writable().is_ok());
+        fs.lock()
+            .unwrap()
+            .health
+            .check_write(Err::<(), _>(Error::Io(
+                std::io::ErrorKind::WriteZero.into(),
+            )))
+            .unwrap_err();
+        assert!(matches!(fuse_fs.writable(), Err(Error::ReadOnly)));
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        let owner = Owner {
+            uid: 1000,
+            gid: 100,
+        };
+        Filesystem::format(&fs, owner).unwrap();
+        assert!(matches!(
+            Filesystem::format(&fs, owner),
+            Err(Error::DoubleAcquire)
+        ));
+        let fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        let superblock = fs.superblock;
+        assert_eq!({ superblock.inodes_free }, superblock.inode_count - 2);
+        assert_eq!(fs.inodes.count_set(), 2);
+        assert_eq!(
+            { superblock.blocks_free },
+            superblock.block_count - fs.blocks.count_set()
+        );
+        let mut fs = Filesystem::load(fs.device, 512).unwrap();
+        let counters =
+            |s: &Superblock| (s.inode_count, s.inodes_free, s.block_count, s.blocks_free);
+        assert_eq!(counters(&fs.superblock), counters(&superblock));
+        assert_eq!(fs.inodes.count_set(), 2);
+        assert!(fs.inodes.get(RESERVED_INODE).unwrap());
+        let root = fs.load_inode(ROOT_INODE).unwrap();
+        assert_eq!((root.uid, root.gid), (1000, 100));
+        assert_eq!(root.r#type, FileType::Directory);
+    }
+
+    #[test]
+    fn page_cache_open_flags() {
+        use super::Notifier;
+        use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        assert_eq!(fuse_fs.file_open_flags(libc::O_RDONLY), FOPEN_DIRECT_IO);
+        let fuse_fs = fuse_fs.with_page_cache();
+        assert_eq!(fuse_fs.file_open_flags(libc::O_RDONLY), 0);
+        let fuse_fs = fuse_fs.with_notifier(Notifier::new().unwrap());
+        assert_eq!(fuse_fs.file_open_flags(libc::O_RDONLY), 0);
+        let device = std::fs::File::options()
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let fuse_fs = fuse_fs.with_notifier(Notifier::with_device(device));
+        assert_eq!(fuse_fs.file_open_flags(libc::O_RDONLY), FOPEN_KEEP_CACHE);
+        assert_eq!(
+            fuse_fs.file_open_flags(libc::O_RDWR | libc::O_DIRECT),
+            FOPEN_DIRECT_IO
+        );
+        assert_eq!(fuse_fs.mirror().unwrap().file_open_flags(libc::O_RDONLY), 0);
+    }
+
+    #[test]
+    fn backup_superblock_recovery() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 1024);
+        fs.acquire_inode(Owner::default()).unwrap();
+        fs.force_flush().unwrap();
+        let superblock = fs.superblock;
+        assert_eq!(superblock.backup_positions().len(), 2);
+        assert!(superblock.backup_positions()[1] >= superblock.block_region_end());
+
+        // Damage checksum of primary superblock
+        fs.device.seek(SeekFrom::Start(1024)).unwrap();
+        fs.device.write_all(&[0xFF; 8]).unwrap();
+        let mut fs = Filesystem::load(fs.device, 1024).unwrap();
+        assert_eq!({ fs.superblock.inodes_free }, { superblock.inodes_free });
+
+        // Wipe primary superblock, including its magic signature
+        fs.device.seek(SeekFrom::Start(1024)).unwrap();
+        fs.device.write_all(&[0; 1024]).unwrap();
+        assert_eq!(
+            Filesystem::detect_existing(fs.device.as_mut()).unwrap(),
+            Some(1024)
+        );
+        let mut fs = Filesystem::load(fs.device, 1024).unwrap();
+        fs.force_flush().unwrap();
+        let primary = Superblock::load(&mut fs.device, 1024).unwrap();
+        assert!({ primary.checksum } != 0);
+        assert_eq!({ primary.inodes_free }, { superblock.inodes_free });
+
+        // Backups are resynced on flush
+        fs.acquire_inode(Owner::default()).unwrap();
+        fs.force_flush().unwrap();
+        for position in superblock.backup_positions() {
+            fs.device.seek(SeekFrom::Start(position + 8)).unwrap();
+            let mut inodes_free = [0u8; 8];
+            fs.device.read_exact(&mut inodes_free).unwrap();
+            assert_eq!(u64::from_le_bytes(inodes_free), superblock.inodes_free - 1);
+        }
+    }
+
+    #[test]
+    fn memory_usage() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let mut fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        let fs = fuse_fs.filesystem.clone();
+        let empty = fuse_fs.memory_usage().unwrap();
+        assert_eq!(empty.block_cache + empty.inode_cache + empty.directories, 0);
+        assert!(empty.bitmaps > 0);
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[1; 2_000]).unwrap();
+        drop(file);
+        let snapshot = fuse_fs.snapshot_directory(ROOT_INODE).unwrap();
+        fuse_fs.directories.insert(1, snapshot);
+        let usage = fuse_fs.memory_usage().unwrap();
+        assert!(usage.block_cache >= 4 * 512);
+        assert!(usage.inode_cache > 0);
+        assert!(usage.directories > 0);
+        assert_eq!(usage.bitmaps, empty.bitmaps);
+        assert_eq!(
+            usage.total(),
+            usage.block_cache
+                + usage.inode_cache
+                + usage.bitmaps
+                + usage.directories
+                + usage.delayed_writes
+        );
+    }
+
+    #[test]
+    fn format_compatibility() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 1024);
+        fs.superblock.version += 1;
+        fs.force_flush().unwrap();
+        let fs = Filesystem::load(fs.device, 1024).unwrap();
+        assert!(fs.read_only);
+        let fuse_fs = FuseFs::new(Arc::new(Mutex::new(fs)));
+        assert!(matches!(fuse_fs.writable(), Err(Error::ReadOnly)));
+        let mut fs = Arc::into_inner(fuse_fs.filesystem)
+            .unwrap()
+            .into_inner()
+            .unwrap();
+
+        fs.read_only = false;
+        fs.superblock.incompat_flags |= 1 << 31;
+        fs.force_flush().unwrap();
+        assert!(matches!(
+            Filesystem::load(fs.device, 1024),
+            Err(Error::Incompatible)
+        ));
     }
 }
@@ -0,0 +1,187 @@
+//! Invalidation of kernel caches of a FUSE mount
+//!
+//! Regular files kept in the kernel page cache between opens go stale once
+//! they are modified without the kernel knowing, through another mount or the
+//! library. A [Notifier] forwards such [Invalidation]s to the kernel as
+//! `FUSE_NOTIFY_INVAL_INODE` and `FUSE_NOTIFY_INVAL_ENTRY` messages.
+//!
+//! The FUSE session does not expose its device, so the notifier remembers
+//! devices open when it is created, and attaches to a duplicate of the single
+//! one opened since, once the filesystem is mounted. Messages are written by
+//! a separate thread, as the kernel may wait for requests of the mount to
+//! finish before handling them. Once the kernel rejects a message, or the
+//! filesystem is unmounted, the notifier detaches, and the page cache must no
+//! longer be kept between opens.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info, warn};
+
+use super::invalidation::Invalidation;
+use crate::Error;
+
+/// Codes of notifications in the error field of their header
+const FUSE_NOTIFY_INVAL_INODE: i32 = 2;
+const FUSE_NOTIFY_INVAL_ENTRY: i32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    sender: Sender<Invalidation>,
+    /// Invalidations waiting for the mount, taken by the writing thread
+    receiver: Arc<Mutex<Option<Receiver<Invalidation>>>>,
+    /// FUSE devices open before the mount
+    known: Arc<BTreeSet<RawFd>>,
+    attached: Arc<AtomicBool>,
+}
+
+/// Descriptors of FUSE devices open in this process
+fn fuse_devices() -> Result<BTreeSet<RawFd>, Error> {
+    let mut devices = BTreeSet::new();
+    for entry in std::fs::read_dir("/proc/self/fd")? {
+        let entry = entry?;
+        let is_fuse =
+            std::fs::read_link(entry.path()).is_ok_and(|target| target.as_os_str() == "/dev/fuse");
+        if let (true, Some(fd)) = (is_fuse, entry.file_name().to_str()) {
+            devices.extend(fd.parse::<RawFd>().ok());
+        }
+    }
+    Ok(devices)
+}
+
+/// Notification message sent to the kernel
+fn message(invalidation: &Invalidation) -> Vec<u8> {
+    let mut body = Vec::new();
+    let code = match invalidation {
+        Invalidation::Inode(index) => {
+            body.extend_from_slice(&index.to_ne_bytes());
+            // Whole contents along with attributes
+            body.extend_from_slice(&0i64.to_ne_bytes());
+            body.extend_from_slice(&0i64.to_ne_bytes());
+            FUSE_NOTIFY_INVAL_INODE
+        }
+        Invalidation::Entry { parent, name } => {
+            body.extend_from_slice(&parent.to_ne_bytes());
+            body.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            body.extend_from_slice(&0u32.to_ne_bytes());
+            body.extend_from_slice(name.as_bytes());
+            body.push(0);
+            FUSE_NOTIFY_INVAL_ENTRY
+        }
+    };
+    let mut bytes = Vec::with_capacity(16 + body.len());
+    bytes.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+    bytes.extend_from_slice(&code.to_ne_bytes());
+    // Notifications answer no request
+    bytes.extend_from_slice(&0u64.to_ne_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+impl Notifier {
+    /// Notifier of a filesystem about to be mounted
+    pub fn new() -> Result<Self, Error> {
+        let (sender, receiver) = channel();
+        Ok(Self {
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            known: Arc::new(fuse_devices()?),
+            attached: Arc::default(),
+        })
+    }
+
+    /// Whether invalidations reach the kernel
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Relaxed)
+    }
+
+    /// Forward invalidations to the FUSE device opened since the notifier
+    /// was created, doing nothing after the first attempt
+    pub fn attach(&self) -> Result<(), Error> {
+        let Some(receiver) = self.receiver.lock()?.take() else {
+            return Ok(());
+        };
+        let opened: Vec<RawFd> = fuse_devices()?.difference(&self.known).copied().collect();
+        let [fd] = opened[..] else {
+            warn!(
+                "Found {} new FUSE devices, kernel caches will not be invalidated",
+                opened.len()
+            );
+            return Ok(());
+        };
+        // Duplicate stays valid, and refers to the same mount, after the session closes its own
+        let device = File::from(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?);
+        self.attach_device(device, receiver);
+        Ok(())
+    }
+
+    /// Notifier writing invalidations to `device`
+    #[cfg(test)]
+    pub(crate) fn with_device(device: File) -> Self {
+        let notifier = Self::new().unwrap();
+        let receiver = notifier.receiver.lock().unwrap().take().unwrap();
+        notifier.attach_device(device, receiver);
+        notifier
+    }
+
+    /// Write invalidations received from `receiver` to `device`
+    fn attach_device(&self, mut device: File, receiver: Receiver<Invalidation>) {
+        info!("Forwarding invalidations to kernel caches");
+        self.attached.store(true, Ordering::Relaxed);
+        let attached = self.attached.clone();
+        std::thread::spawn(move || {
+            for invalidation in receiver {
+                debug!("Invalidate kernel cache of {invalidation:?}");
+                match device.write(&message(&invalidation)) {
+                    Ok(_) => {}
+                    // Kernel does not cache it
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                    Err(e) if e.raw_os_error() == Some(libc::ENODEV) => break,
+                    Err(e) => {
+                        warn!("Kernel rejected invalidation of {invalidation:?}: {e}");
+                        break;
+                    }
+                }
+            }
+            attached.store(false, Ordering::Relaxed);
+        });
+    }
+
+    /// Queue invalidation for the kernel
+    pub(crate) fn send(&self, invalidation: &Invalidation) {
+        let _ = self.sender.send(invalidation.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message, Invalidation, Notifier};
+
+    #[test]
+    fn notification_messages() {
+        let inode = message(&Invalidation::Inode(7));
+        assert_eq!(inode.len(), 40);
+        assert_eq!(inode[0..4], 40u32.to_ne_bytes());
+        assert_eq!(inode[4..8], 2i32.to_ne_bytes());
+        assert_eq!(inode[16..24], 7u64.to_ne_bytes());
+        let entry = message(&Invalidation::Entry {
+            parent: 1,
+            name: "file".to_owned(),
+        });
+        assert_eq!(entry.len(), 16 + 16 + 5);
+        assert_eq!(entry[4..8], 3i32.to_ne_bytes());
+        assert_eq!(entry[24..28], 4u32.to_ne_bytes());
+        assert_eq!(&entry[32..], b"file\0");
+
+        // Nothing was mounted, so there is no device to attach to
+        let notifier = Notifier::new().unwrap();
+        notifier.attach().unwrap();
+        assert!(!notifier.is_attached());
+        notifier.attach().unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! On-disk list of inodes unlinked while still open
+//!
+//! An inode unlinked while the kernel still refers to it keeps its data until
+//! the last reference is forgotten, which a crash prevents from ever
+//! happening. Such inodes are therefore linked into a list whose head is kept
+//! in the superblock, each pointing to the next one with the first slot of
+//! its metadata, which held its parent before it lost its last entry. The
+//! list is written in the same transaction as the unlink, an inode leaves it
+//! once it is reclaimed, and those left behind by a crash are reclaimed while
+//! recovering the filesystem on its next mount.
+
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info};
+
+use super::{Filesystem, LockFilesystem, RESERVED_INODE};
+use crate::filetypes::{Directory, FileOperations, RegularFile};
+use crate::Error;
+
+impl Filesystem {
+    /// Inodes on the orphan list, from the most recently added one
+    ///
+    /// Fails with [Error::Corruption] if the list holds an inode which is
+    /// free or still linked, or loops.
+    pub(crate) fn orphans(&mut self) -> Result<Vec<u64>, Error> {
+        let mut orphans = Vec::new();
+        let mut index = self.superblock.orphan_inode;
+        while index != RESERVED_INODE {
+            if orphans.len() as u64 >= self.superblock.total_inodes() || !self.inodes.get(index)? {
+                return Err(Error::Corruption);
+            }
+            let inode = self.load_inode(index)?;
+            if inode.dtime == u64::MAX {
+                return Err(Error::Corruption);
+            }
+            orphans.push(index);
+            index = inode.metadata[0];
+        }
+        Ok(orphans)
+    }
+
+    /// Add unlinked inode to the orphan list
+    pub(crate) fn add_orphan(&mut self, index: u64) -> Result<(), Error> {
+        debug!("Add inode {index} to orphan list");
+        let mut inode = self.load_inode(index)?;
+        inode.metadata[0] = self.superblock.orphan_inode;
+        self.flush_inode(&inode)?;
+        self.superblock.orphan_inode = index;
+        Ok(())
+    }
+
+    /// Remove inode from the orphan list, if it is on it
+    pub(crate) fn remove_orphan(&mut self, index: u64) -> Result<(), Error> {
+        let mut previous = RESERVED_INODE;
+        let mut current = self.superblock.orphan_inode;
+        while current != RESERVED_INODE && current != index {
+            previous = current;
+            current = self.load_inode(current)?.metadata[0];
+        }
+        if current == RESERVED_INODE {
+            return Ok(());
+        }
+        debug!("Remove inode {index} from orphan list");
+        let next = self.load_inode(index)?.metadata[0];
+        match previous {
+            RESERVED_INODE => self.superblock.orphan_inode = next,
+            _ => {
+                let mut inode = self.load_inode(previous)?;
+                inode.metadata[0] = next;
+                self.flush_inode(&inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Release data and inodes left on the orphan list by a crash,
+    /// returning their number
+    pub(crate) fn reclaim_orphans(fs: &Arc<Mutex<Filesystem>>) -> Result<u64, Error> {
+        let orphans = fs.lock_fs()?.orphans()?;
+        for &index in &orphans {
+            debug!("Reclaim orphaned inode {index}");
+            let kind = fs.lock_fs()?.load_inode(index)?.r#type;
+            match kind {
+                fuser::FileType::Directory => Directory::load(fs, index)?.remove_empty()?,
+                _ => RegularFile::load(fs, index)?.remove()?,
+            }
+        }
+        if !orphans.is_empty() {
+            info!("Reclaimed {} orphaned inodes", orphans.len());
+            let mut fs = fs.lock_fs()?;
+            fs.superblock.orphan_inode = RESERVED_INODE;
+            fs.force_flush()?;
+        }
+        Ok(orphans.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, FuseFs, RESERVED_INODE, ROOT_INODE};
+    use crate::filetypes::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    };
+
+    #[test]
+    fn reclaim_orphans_after_crash() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let fuse_fs = FuseFs::new(fs.clone());
+        let mut orphans = Vec::new();
+        for name in ["a", "b", "c"] {
+            let mut file =
+                RegularFile::new(&fs, ROOT_INODE, name, 0o640, Owner::default()).unwrap();
+            file.write(0, &[7u8; 3000]).unwrap();
+            orphans.push(file.inode.index);
+        }
+        let free = fs.lock().unwrap().superblock.inodes_free;
+        for &ino in &orphans {
+            fuse_fs.remember(ino).unwrap();
+            Directory::load(&fs, ROOT_INODE)
+                .unwrap()
+                .detach_child(DirectoryChildIdentifier::Inode(ino))
+                .unwrap();
+            fuse_fs.release_unlinked(ino).unwrap();
+        }
+        orphans.reverse();
+        assert_eq!(fs.lock().unwrap().orphans().unwrap(), orphans);
+
+        fuse_fs.forget_lookups(orphans[1], 1).unwrap();
+        assert_eq!(
+            fs.lock().unwrap().orphans().unwrap(),
+            vec![orphans[0], orphans[2]]
+        );
+
+        // Crash before the kernel forgets the rest
+        assert!(Filesystem::recover(&fs).unwrap().is_clean());
+        let fs = fs.lock().unwrap();
+        assert_eq!({ fs.superblock.orphan_inode }, RESERVED_INODE);
+        assert_eq!({ fs.superblock.inodes_free }, free + 3);
+        assert!(!fs.inodes.get(orphans[0]).unwrap());
+    }
+}
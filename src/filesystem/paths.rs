@@ -0,0 +1,240 @@
+//! Access to files by their paths, without mounting the filesystem
+//!
+//! Paths are resolved through directories starting from the root, whether or
+//! not they begin with a slash. Empty components are skipped, and `.` and
+//! `..` are resolved as entries of directories, so tools such as backups,
+//! migrations or tests work on an image the same way they would through a
+//! mount point. Paths with more components than the depth of
+//! [Limits](super::Limits) are rejected before any directory is read, so a
+//! crafted image cannot keep a resolver walking indefinitely.
+
+use std::sync::{Arc, Mutex};
+
+use fuser::FileType;
+
+use super::{Filesystem, LockFilesystem, ROOT_INODE};
+use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+use crate::Error;
+
+/// Mode of regular files created by [Filesystem::write]
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+/// Mode of directories created by [Filesystem::create_dir_all]
+pub const DEFAULT_DIRECTORY_MODE: u32 = 0o755;
+
+/// Names of directories along `path`
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Check that resolving `path` traverses at most as many directories as limited
+fn check_depth(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<(), Error> {
+    let limit = fs.lock_fs()?.limits.path_depth;
+    match components(path).count() as u64 > limit {
+        true => Err(Error::NameTooLong),
+        false => Ok(()),
+    }
+}
+
+impl Filesystem {
+    /// Inode of `path`, resolved through directories from the root
+    pub fn resolve(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<u64, Error> {
+        check_depth(fs, path)?;
+        components(path).try_fold(ROOT_INODE, |index, name| Directory::find(fs, index, name))
+    }
+
+    /// Inode of directory containing last component of `path`, and its name
+    fn resolve_parent<'a>(
+        fs: &Arc<Mutex<Filesystem>>,
+        path: &'a str,
+    ) -> Result<(u64, &'a str), Error> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        match name {
+            "" | "." | ".." => Err(Error::InvalidArgument),
+            name => Ok((Self::resolve(fs, parent)?, name)),
+        }
+    }
+
+    /// Open regular file at `path`
+    pub fn open(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<RegularFile, Error> {
+        RegularFile::load(fs, Self::resolve(fs, path)?)
+    }
+
+    /// Open directory at `path`
+    pub fn open_dir(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<Directory, Error> {
+        Directory::load(fs, Self::resolve(fs, path)?)
+    }
+
+    /// Whole contents of regular file at `path`
+    pub fn read(fs: &Arc<Mutex<Filesystem>>, path: &str) -> Result<Vec<u8>, Error> {
+        let mut file = Self::open(fs, path)?;
+        let size = file.inode.size;
+        file.read(0, size)
+    }
+
+    /// Replace contents of regular file at `path` with `data`, creating it
+    /// for `owner` if it does not exist
+    pub fn write(
+        fs: &Arc<Mutex<Filesystem>>,
+        path: &str,
+        data: &[u8],
+        owner: Owner,
+    ) -> Result<(), Error> {
+        let mut file = match Self::resolve(fs, path) {
+            Ok(index) => {
+                let mut session = Filesystem::session(fs)?;
+                session.resize_file(index, 0)?;
+                session.commit()?;
+                RegularFile::load(fs, index)?
+            }
+            Err(Error::NotFound) => Self::create(fs, path, DEFAULT_FILE_MODE, owner)?,
+            Err(e) => return Err(e),
+        };
+        file.write(0, data)?;
+        file.flush()
+    }
+
+    /// Create empty regular file at `path` in an existing directory
+    pub fn create(
+        fs: &Arc<Mutex<Filesystem>>,
+        path: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<RegularFile, Error> {
+        let (parent, name) = Self::resolve_parent(fs, path)?;
+        RegularFile::new(fs, parent, name, mode, owner)
+    }
+
+    /// Create directory at `path` in an existing directory
+    pub fn create_dir(
+        fs: &Arc<Mutex<Filesystem>>,
+        path: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<Directory, Error> {
+        let (parent, name) = Self::resolve_parent(fs, path)?;
+        Directory::new(fs, parent, name, mode, owner)
+    }
+
+    /// Create directory at `path` along with its missing ancestors, returning
+    /// its inode
+    pub fn create_dir_all(
+        fs: &Arc<Mutex<Filesystem>>,
+        path: &str,
+        owner: Owner,
+    ) -> Result<u64, Error> {
+        check_depth(fs, path)?;
+        let mut index = ROOT_INODE;
+        for name in components(path) {
+            index = match Directory::find(fs, index, name) {
+                Ok(child) => child,
+                Err(Error::NotFound) => {
+                    Directory::new(fs, index, name, DEFAULT_DIRECTORY_MODE, owner)?
+                        .inode
+                        .index
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        match fs.lock_fs()?.load_inode(index)?.r#type {
+            FileType::Directory => Ok(index),
+            _ => Err(Error::NotDirectory),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+    use crate::Error;
+
+    #[test]
+    fn access_by_path() {
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 4_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let owner = Owner { uid: 7, gid: 8 };
+
+        let index = Filesystem::create_dir_all(&fs, "/a/b/", owner).unwrap();
+        assert_eq!(
+            Filesystem::create_dir_all(&fs, "a//b", owner).unwrap(),
+            index
+        );
+        let parent = Filesystem::resolve(&fs, "a").unwrap();
+        assert_eq!(Filesystem::resolve(&fs, "/a/b/..").unwrap(), parent);
+        assert_eq!(Filesystem::resolve(&fs, "/").unwrap(), ROOT_INODE);
+
+        let contents: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        Filesystem::write(&fs, "/a/b/c.txt", &contents, owner).unwrap();
+        assert_eq!(Filesystem::read(&fs, "a/b/c.txt").unwrap(), contents);
+        Filesystem::write(&fs, "/a/b/c.txt", b"short", owner).unwrap();
+        assert_eq!(Filesystem::read(&fs, "/a/./b/c.txt").unwrap(), b"short");
+        let file = Filesystem::open(&fs, "/a/b/c.txt").unwrap();
+        assert_eq!(({ file.inode.uid }, { file.inode.size }), (7, 5));
+        drop(file);
+        let directory = Filesystem::open_dir(&fs, "/a/b").unwrap();
+        assert_eq!(directory.children.len(), 1);
+        drop(directory);
+
+        assert!(matches!(
+            Filesystem::read(&fs, "/a/missing"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            Filesystem::read(&fs, "/a/b"),
+            Err(Error::IsDirectory)
+        ));
+        assert!(matches!(
+            Filesystem::create_dir_all(&fs, "/a/b/c.txt/d", owner),
+            Err(Error::NotDirectory)
+        ));
+        assert!(matches!(
+            Filesystem::create_dir(&fs, "/a/b", 0o700, owner),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        assert!(matches!(
+            Filesystem::create(&fs, "/a/..", 0o600, owner),
+            Err(Error::InvalidArgument)
+        ));
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let directory = Directory::new(&fs, ROOT_INODE, "a", 0o750, Owner::default()).unwrap();
+        let parent = directory.inode.index;
+        drop(directory);
+        let file = RegularFile::new(&fs, parent, "f", 0o640, Owner::default()).unwrap();
+        let index = file.inode.index;
+        drop(file);
+        assert_eq!(Filesystem::resolve(&fs, "/").unwrap(), ROOT_INODE);
+        assert_eq!(Filesystem::resolve(&fs, "/a").unwrap(), parent);
+        assert_eq!(Filesystem::resolve(&fs, "/a/f").unwrap(), index);
+        assert_eq!(Filesystem::resolve(&fs, "a/../a//f").unwrap(), index);
+        assert!(matches!(
+            Filesystem::resolve(&fs, "/a/g"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            Filesystem::resolve(&fs, "/a/f/g"),
+            Err(Error::NotDirectory)
+        ));
+
+        fs.lock().unwrap().limits.path_depth = 4;
+        assert_eq!(Filesystem::resolve(&fs, "a/../a/f").unwrap(), index);
+        assert!(matches!(
+            Filesystem::resolve(&fs, "a/../a/../a"),
+            Err(Error::NameTooLong)
+        ));
+        assert!(matches!(
+            Filesystem::create_dir_all(&fs, "b/c/d/e/f", Owner::default()),
+            Err(Error::NameTooLong)
+        ));
+    }
+}
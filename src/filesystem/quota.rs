@@ -0,0 +1,430 @@
+//! Disk quotas of users and groups
+//!
+//! Filesystems with [INCOMPAT_QUOTA] track blocks and inodes held by every
+//! user and group. They are charged to the owner of a file when acquired for
+//! it and released with it, and acquiring them beyond a hard limit fails with
+//! [Error::QuotaExceeded]. Usage and limits are kept in memory and written on
+//! every flush to the file of [RESERVED_INODE], which is never linked into the
+//! directory tree and whose blocks are charged to no one.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info};
+
+use super::journal::Transaction;
+use super::{Filesystem, LockFilesystem, RESERVED_INODE};
+use crate::filetypes::{bytes_per_block, get_next_block, set_next_block, Owner};
+use crate::structs::{Block, Inode, PermanentIndexed, Superblock, INCOMPAT_QUOTA, NULL_BLOCK};
+use crate::Error;
+
+/// Bytes of a single quota record in the quota file
+const RECORD_SIZE: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QuotaKind {
+    User = 0,
+    Group = 1,
+}
+
+impl TryFrom<u32> for QuotaKind {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::User),
+            1 => Ok(Self::Group),
+            _ => Err(Error::Corruption),
+        }
+    }
+}
+
+/// Usage and hard limits of a single user or group
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub blocks: u64,
+    pub inodes: u64,
+    /// Most blocks held at once, zero if unlimited
+    pub block_limit: u64,
+    /// Most inodes held at once, zero if unlimited
+    pub inode_limit: u64,
+}
+
+impl Quota {
+    /// Whether holding `blocks` and `inodes` more would exceed a limit
+    fn exceeded_by(&self, blocks: u64, inodes: u64) -> bool {
+        (self.block_limit > 0 && blocks > 0 && self.blocks + blocks > self.block_limit)
+            || (self.inode_limit > 0 && inodes > 0 && self.inodes + inodes > self.inode_limit)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "blocks {}", self.blocks)?;
+        writeln!(f, "block_limit {}", self.block_limit)?;
+        writeln!(f, "inodes {}", self.inodes)?;
+        write!(f, "inode_limit {}", self.inode_limit)
+    }
+}
+
+/// Quotas of all users and groups holding anything or having limits
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Quotas {
+    /// Whether filesystem keeps quotas at all
+    enabled: bool,
+    entries: BTreeMap<(QuotaKind, u32), Quota>,
+    /// Blocks of the quota file, in order
+    pub(crate) blocks: Vec<u64>,
+    /// Whether entries changed since they were last written
+    modified: bool,
+}
+
+impl Quotas {
+    /// Empty quotas of a new filesystem described by `superblock`
+    pub fn new(superblock: &Superblock) -> Self {
+        let enabled = superblock.incompat_flags & INCOMPAT_QUOTA != 0;
+        Self {
+            enabled,
+            modified: enabled,
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Quota of user or group `id`
+    pub fn get(&self, kind: QuotaKind, id: u32) -> Quota {
+        self.entries.get(&(kind, id)).copied().unwrap_or_default()
+    }
+
+    /// Quotas of all users and groups holding anything or having limits
+    pub fn entries(&self) -> impl Iterator<Item = (QuotaKind, u32, Quota)> + '_ {
+        self.entries
+            .iter()
+            .map(|(&(kind, id), &quota)| (kind, id, quota))
+    }
+
+    /// Set hard limits of user or group `id`, zero for unlimited
+    pub fn set_limits(
+        &mut self,
+        kind: QuotaKind,
+        id: u32,
+        block_limit: u64,
+        inode_limit: u64,
+    ) -> Result<(), Error> {
+        if !self.enabled {
+            return Err(Error::Incompatible);
+        }
+        debug!("Set {kind:?} {id} limits to {block_limit} blocks and {inode_limit} inodes");
+        self.update((kind, id), |quota| {
+            quota.block_limit = block_limit;
+            quota.inode_limit = inode_limit;
+        });
+        Ok(())
+    }
+
+    /// Check whether `blocks` and `inodes` may be charged to user and group
+    /// of `owner`, without charging them
+    pub fn check(&self, owner: Owner, blocks: u64, inodes: u64) -> Result<(), Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if Self::keys(owner)
+            .iter()
+            .any(|&(kind, id)| self.get(kind, id).exceeded_by(blocks, inodes))
+        {
+            info!("Quota of {owner:?} exceeded by {blocks} blocks and {inodes} inodes");
+            return Err(Error::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Charge `blocks` and `inodes` to user and group of `owner`
+    ///
+    /// Fails without charging anything if either would exceed its limit.
+    pub fn charge(&mut self, owner: Owner, blocks: u64, inodes: u64) -> Result<(), Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.check(owner, blocks, inodes)?;
+        for key in Self::keys(owner) {
+            self.update(key, |quota| {
+                quota.blocks += blocks;
+                quota.inodes += inodes;
+            });
+        }
+        Ok(())
+    }
+
+    /// Release `blocks` and `inodes` charged to user and group of `owner`
+    pub fn release(&mut self, owner: Owner, blocks: u64, inodes: u64) {
+        if !self.enabled {
+            return;
+        }
+        for key in Self::keys(owner) {
+            self.update(key, |quota| {
+                quota.blocks = quota.blocks.saturating_sub(blocks);
+                quota.inodes = quota.inodes.saturating_sub(inodes);
+            });
+        }
+    }
+
+    /// Move `blocks` and `inodes` charged to `from` over to `to`
+    pub fn transfer(
+        &mut self,
+        from: Owner,
+        to: Owner,
+        blocks: u64,
+        inodes: u64,
+    ) -> Result<(), Error> {
+        if from == to {
+            return Ok(());
+        }
+        self.release(from, blocks, inodes);
+        self.charge(to, blocks, inodes).inspect_err(|_| {
+            // Limits of the previous owner never block giving its usage back
+            for key in Self::keys(from) {
+                self.update(key, |quota| {
+                    quota.blocks += blocks;
+                    quota.inodes += inodes;
+                });
+            }
+        })
+    }
+
+    fn keys(owner: Owner) -> [(QuotaKind, u32); 2] {
+        [(QuotaKind::User, owner.uid), (QuotaKind::Group, owner.gid)]
+    }
+
+    /// Apply `change` to quota under `key`, forgetting it once empty
+    pub(super) fn update(&mut self, key: (QuotaKind, u32), change: impl FnOnce(&mut Quota)) {
+        let quota = self.entries.entry(key).or_default();
+        change(quota);
+        if quota.is_empty() {
+            self.entries.remove(&key);
+        }
+        self.modified = true;
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.entries.len() * RECORD_SIZE);
+        for (&(kind, id), quota) in self.entries.iter() {
+            bytes.extend_from_slice(&(kind as u32).to_le_bytes());
+            bytes.extend_from_slice(&id.to_le_bytes());
+            for value in [
+                quota.blocks,
+                quota.inodes,
+                quota.block_limit,
+                quota.inode_limit,
+            ] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if !bytes.len().is_multiple_of(RECORD_SIZE) {
+            return Err(Error::Corruption);
+        }
+        for record in bytes.chunks_exact(RECORD_SIZE) {
+            let field = |n: usize| -> Result<u64, Error> {
+                Ok(u64::from_le_bytes(
+                    record[8 + n * 8..16 + n * 8].try_into()?,
+                ))
+            };
+            let kind = QuotaKind::try_from(u32::from_le_bytes(record[0..4].try_into()?))?;
+            let id = u32::from_le_bytes(record[4..8].try_into()?);
+            let quota = Quota {
+                blocks: field(0)?,
+                inodes: field(1)?,
+                block_limit: field(2)?,
+                inode_limit: field(3)?,
+            };
+            self.entries.insert((kind, id), quota);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem {
+    /// Start or stop keeping quotas of an existing filesystem
+    ///
+    /// Usage is counted by [Filesystem::check], so quotas can only be started
+    /// on a consistent filesystem.
+    pub(crate) fn set_quotas(fs: &Arc<Mutex<Filesystem>>, enabled: bool) -> Result<(), Error> {
+        if fs.lock_fs()?.quotas.enabled == enabled {
+            return Ok(());
+        }
+        if !enabled {
+            let mut fs = fs.lock_fs()?;
+            info!("Removing quota file");
+            for index in std::mem::take(&mut fs.quotas.blocks) {
+                fs.free_block(index)?;
+            }
+            fs.quotas = Quotas::default();
+            fs.superblock.incompat_flags &= !INCOMPAT_QUOTA;
+            return Ok(());
+        }
+        let report = Filesystem::check(fs)?;
+        if !report.is_clean() {
+            return Err(Error::Corruption);
+        }
+        let mut fs = fs.lock_fs()?;
+        info!("Counting usage of {} users and groups", report.usage.len());
+        fs.superblock.incompat_flags |= INCOMPAT_QUOTA;
+        fs.quotas = Quotas::new(&fs.superblock);
+        for (key, (blocks, inodes)) in report.usage {
+            fs.quotas.update(key, |quota| {
+                quota.blocks = blocks;
+                quota.inodes = inodes;
+            });
+        }
+        Ok(())
+    }
+
+    /// Read quotas from the quota file, if filesystem keeps them
+    pub(super) fn load_quotas(&mut self) -> Result<(), Error> {
+        if self.superblock.incompat_flags & INCOMPAT_QUOTA == 0 {
+            return Ok(());
+        }
+        let inode = Inode::load(&mut self.device, &self.superblock, RESERVED_INODE)?;
+        let mut data = Vec::with_capacity(inode.size as usize);
+        let mut index = inode.first_block;
+        while index != NULL_BLOCK {
+            if self.quotas.blocks.len() as u64 >= inode.block_count {
+                return Err(Error::Corruption);
+            }
+            let block = Block::load(&mut self.device, &self.superblock, index)?;
+            data.extend_from_slice(&block.data[8..]);
+            self.quotas.blocks.push(index);
+            index = get_next_block(&block);
+        }
+        data.truncate(inode.size as usize);
+        self.quotas.enabled = true;
+        self.quotas.load_bytes(&data)?;
+        debug!("Loaded {} quotas", self.quotas.entries.len());
+        Ok(())
+    }
+
+    /// Write modified quotas to the quota file as part of `transaction`
+    ///
+    /// Blocks of the quota file bypass the cache, as nothing else reads them.
+    pub(super) fn flush_quotas(&mut self, transaction: &mut Transaction) -> Result<(), Error> {
+        if !self.quotas.enabled || !self.quotas.modified {
+            return Ok(());
+        }
+        let data = self.quotas.to_bytes();
+        let per_block = bytes_per_block(self.superblock.block_size) as usize;
+        let needed = data.len().div_ceil(per_block);
+        debug!("Flushing quotas to {needed} blocks");
+        while self.quotas.blocks.len() < needed {
+            let index = self.allocate_block()?;
+            self.quotas.blocks.push(index);
+        }
+        while self.quotas.blocks.len() > needed {
+            let index = self.quotas.blocks.pop().expect("quota file has blocks");
+            self.free_block(index)?;
+        }
+        let blocks = self.quotas.blocks.clone();
+        for (n, &index) in blocks.iter().enumerate() {
+            let mut block = Block::with_index(self, index)?;
+            let next = blocks.get(n + 1).copied().unwrap_or(NULL_BLOCK);
+            set_next_block(&mut block, next);
+            let chunk = &data[n * per_block..data.len().min((n + 1) * per_block)];
+            block.data[8..8 + chunk.len()].copy_from_slice(chunk);
+            block.flush(transaction, &self.superblock)?;
+            // Stale copy of a previous owner must not overwrite it
+            self.cache.remove_block(index);
+        }
+        let inode = Inode {
+            index: RESERVED_INODE,
+            size: data.len() as u64,
+            block_count: needed as u64,
+            metadata: [NULL_BLOCK; 5],
+            first_block: blocks.first().copied().unwrap_or(NULL_BLOCK),
+            last_block: blocks.last().copied().unwrap_or(NULL_BLOCK),
+            ..Default::default()
+        };
+        inode.flush(transaction, &self.superblock)?;
+        self.quotas.modified = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::QuotaKind;
+    use crate::filesystem::{Filesystem, LockFilesystem, ROOT_INODE};
+    use crate::filetypes::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    };
+    use crate::Error;
+
+    #[test]
+    fn hard_limits() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512).with_quotas();
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let user = Owner {
+            uid: 1000,
+            gid: 100,
+        };
+        fs.lock_fs()
+            .unwrap()
+            .quotas
+            .set_limits(QuotaKind::User, 1000, 8, 2)
+            .unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, user).unwrap();
+        file.write(0, &[1u8; 2_000]).unwrap();
+        drop(file);
+        Directory::new(&fs, ROOT_INODE, "dir", 0o750, user).unwrap();
+        let created = RegularFile::new(&fs, ROOT_INODE, "other", 0o640, user);
+        assert!(matches!(created, Err(Error::QuotaExceeded)));
+        let mut file = RegularFile::load(&fs, 2).unwrap();
+        let written = file.write(2_000, &[1u8; 4_000]);
+        assert!(matches!(written, Err(Error::QuotaExceeded)));
+        drop(file);
+        // Blocks the failed write would grow the file by are charged together
+        let quota = fs.lock_fs().unwrap().quotas.get(QuotaKind::Group, 100);
+        assert_eq!((quota.blocks, quota.inodes), (6, 2));
+        assert_eq!(quota.block_limit, 0);
+
+        // Usage survives remounting and is released with files
+        let device = {
+            let mut fs_handle = fs.lock_fs().unwrap();
+            fs_handle.force_flush().unwrap();
+            std::mem::replace(&mut fs_handle.device, Box::new(Cursor::new(Vec::new())))
+        };
+        let charged = fs.lock_fs().unwrap().quotas.get(QuotaKind::User, 1000);
+        let fs = Arc::new(Mutex::new(Filesystem::load(device, 512).unwrap()));
+        assert_eq!(
+            fs.lock_fs().unwrap().quotas.get(QuotaKind::User, 1000),
+            charged
+        );
+        Directory::load(&fs, ROOT_INODE)
+            .unwrap()
+            .remove_child(DirectoryChildIdentifier::Name("file"))
+            .unwrap();
+        assert_eq!(
+            fs.lock_fs()
+                .unwrap()
+                .quotas
+                .get(QuotaKind::User, 1000)
+                .inodes,
+            1
+        );
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+    }
+}
@@ -0,0 +1,97 @@
+//! Read-ahead of regular files read sequentially through a mount
+//!
+//! Each file handle remembers where its last read ended. A read continuing
+//! from there, or starting a handle at the beginning of the file, is
+//! sequential, and is followed by caching the next blocks of the file in a
+//! single read of the device. The window of prefetched blocks is halved down
+//! to the part of it the file actually occupied, and doubles back up to the
+//! configured size while whole windows are used, so fragmented files are not
+//! read over and over again.
+
+use std::collections::BTreeMap;
+
+use super::READAHEAD_BLOCKS;
+
+/// Position and window of sequential reads through a file handle
+#[derive(Debug, Clone, Copy)]
+struct Stream {
+    /// Offset following the last read byte
+    end: u64,
+    /// Blocks prefetched after the next sequential read
+    window: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct ReadAhead {
+    /// Largest window, with zero disabling read-ahead
+    pub blocks: u64,
+    streams: BTreeMap<u64, Stream>,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new(READAHEAD_BLOCKS)
+    }
+}
+
+impl ReadAhead {
+    pub fn new(blocks: u64) -> Self {
+        Self {
+            blocks,
+            streams: BTreeMap::new(),
+        }
+    }
+
+    /// Record read of `size` bytes at `offset` through handle, returning the
+    /// number of blocks to prefetch after it if it is sequential
+    pub fn read(&mut self, fh: u64, offset: u64, size: u64) -> Option<u64> {
+        let blocks = self.blocks;
+        let stream = self.streams.entry(fh).or_insert(Stream {
+            end: 0,
+            window: blocks,
+        });
+        let sequential = offset == stream.end;
+        stream.end = offset + size;
+        (sequential && blocks > 0).then_some(stream.window)
+    }
+
+    /// Adjust window of handle to `linked` of prefetched blocks belonging to
+    /// the file, doubling it if all of them did
+    pub fn prefetched(&mut self, fh: u64, linked: u64) {
+        if let Some(stream) = self.streams.get_mut(&fh) {
+            stream.window = match linked == stream.window {
+                true => stream.window * 2,
+                false => linked.max(1),
+            }
+            .min(self.blocks);
+        }
+    }
+
+    /// Forget released handle
+    pub fn release(&mut self, fh: u64) {
+        self.streams.remove(&fh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadAhead;
+
+    #[test]
+    fn detect_sequential_reads() {
+        let mut readahead = ReadAhead::new(8);
+        assert_eq!(readahead.read(1, 0, 100), Some(8));
+        assert_eq!(readahead.read(1, 100, 100), Some(8));
+        assert_eq!(readahead.read(2, 50, 100), None);
+        assert_eq!(readahead.read(1, 500, 100), None);
+        assert_eq!(readahead.read(1, 600, 100), Some(8));
+        readahead.prefetched(1, 3);
+        assert_eq!(readahead.read(1, 700, 100), Some(3));
+        readahead.prefetched(1, 3);
+        assert_eq!(readahead.read(1, 800, 100), Some(6));
+        readahead.prefetched(1, 6);
+        assert_eq!(readahead.read(1, 900, 100), Some(8));
+        readahead.release(1);
+        assert_eq!(readahead.read(1, 1_000, 100), None);
+    }
+}
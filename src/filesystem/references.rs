@@ -0,0 +1,97 @@
+//! Kernel references to inodes, shared by all mounts of a [Filesystem](super::Filesystem)
+//!
+//! Every mount counts lookups handed to its kernel connection separately, so
+//! an inode unlinked through one mount stays allocated while any other mount
+//! still refers to it.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+pub(crate) struct References {
+    /// Number of kernel references per inode and mount, acquired by lookups
+    lookups: BTreeMap<(u64, usize), u64>,
+    /// Unlinked inodes whose reclamation waits for the kernel to forget them
+    orphans: BTreeSet<u64>,
+    /// Identifier of the next registered mount
+    mounts: usize,
+}
+
+impl References {
+    /// Identifier for a new mount
+    pub fn register(&mut self) -> usize {
+        self.mounts += 1;
+        self.mounts - 1
+    }
+
+    /// Count a reference to inode handed to the kernel by mount
+    pub fn remember(&mut self, mount: usize, ino: u64) {
+        *self.lookups.entry((ino, mount)).or_default() += 1;
+    }
+
+    /// Check if any mount still refers to inode
+    pub fn referenced(&self, ino: u64) -> bool {
+        self.lookups
+            .range((ino, usize::MIN)..=(ino, usize::MAX))
+            .next()
+            .is_some()
+    }
+
+    /// Defer reclamation of inode until all mounts forget it
+    pub fn orphan(&mut self, ino: u64) {
+        self.orphans.insert(ino);
+    }
+
+    /// Drop references of mount to inode
+    /// Returns true if it was the last reference to an orphaned inode
+    pub fn forget(&mut self, mount: usize, ino: u64, nlookup: u64) -> bool {
+        let Some(count) = self.lookups.get_mut(&(ino, mount)) else {
+            return false;
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return false;
+        }
+        self.lookups.remove(&(ino, mount));
+        !self.referenced(ino) && self.orphans.remove(&ino)
+    }
+
+    /// Drop all references of an unmounted mount
+    /// Returns orphaned inodes no longer referenced by any mount
+    pub fn release(&mut self, mount: usize) -> Vec<u64> {
+        self.lookups.retain(|&(_, m), _| m != mount);
+        let released: Vec<u64> = self
+            .orphans
+            .iter()
+            .copied()
+            .filter(|&ino| !self.referenced(ino))
+            .collect();
+        for ino in &released {
+            self.orphans.remove(ino);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::References;
+
+    #[test]
+    fn orphan_outlives_other_mounts() {
+        let mut references = References::default();
+        let primary = references.register();
+        let mirror = references.register();
+        references.remember(primary, 5);
+        references.remember(mirror, 5);
+        references.orphan(5);
+        assert!(!references.forget(primary, 5, 1));
+        assert!(references.referenced(5));
+        assert!(references.forget(mirror, 5, 1));
+        assert!(!references.forget(mirror, 5, 1));
+        references.remember(primary, 6);
+        references.remember(mirror, 6);
+        references.orphan(6);
+        assert!(references.release(mirror).is_empty());
+        assert_eq!(references.release(primary), vec![6]);
+    }
+}
@@ -0,0 +1,192 @@
+//! Growing an unmounted filesystem onto a larger device, or shrinking it
+//!
+//! Inode, checksum and block regions are placed after both bitmaps, so a
+//! larger block bitmap and checksum region move all of them towards the end
+//! of device. Regions are moved starting with the last one, each copied from
+//! its end, so nothing is overwritten before it is copied. The number of
+//! inodes stays the same, and added blocks are free. Undo records of the
+//! journal refer to positions in the old layout, so they are forgotten.
+//!
+//! Shrinking removes free blocks from the end of block region, and fails if
+//! any of them is in use, as blocks are never relocated. Smaller regions move
+//! towards the start of device, starting with the first one, each copied from
+//! its start.
+//!
+//! Regions are moved in place without journaling, so an interrupted resize
+//! leaves the filesystem unusable, unless overwritten data was kept in an
+//! [undo side file](crate::devices::undo).
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::{error, info};
+
+use super::{BlockDevice, Filesystem};
+use crate::Error;
+
+/// Bytes copied at once while moving a region
+const MOVE_CHUNK: u64 = 1 << 20;
+
+impl Filesystem {
+    /// Grow filesystem to fill `device_size` bytes of its device, returning
+    /// the number of added blocks
+    pub fn grow(&mut self, device_size: u64) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let old = self.superblock;
+        let grown = old.grown(device_size)?;
+        let added = grown.block_count - old.block_count;
+        if added == 0 {
+            return Ok(0);
+        }
+        info!(
+            "Growing filesystem from {} to {} blocks",
+            { old.block_count },
+            { grown.block_count }
+        );
+        self.force_flush()?;
+        let regions = old.relocatable_regions().into_iter();
+        for ((from, length), (to, _)) in regions.zip(grown.relocatable_regions()).rev() {
+            move_region(&mut self.device, from, to, length)?;
+        }
+        self.superblock = grown;
+        self.blocks.grow(grown.block_count);
+        self.inodes.relocate(&grown);
+        self.force_flush()?;
+        self.forget_history()?;
+        Ok(added)
+    }
+
+    /// Shrink filesystem to fit in `device_size` bytes of its device,
+    /// returning the number of removed blocks
+    pub fn shrink(&mut self, device_size: u64) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // Cached blocks reach the device before their regions move
+        self.force_flush()?;
+        let old = self.superblock;
+        let shrunk = old.shrunk(device_size)?;
+        if let Some(index) = self.blocks.next_occupied(shrunk.block_count) {
+            error!(
+                "Block {index} is in use, filesystem cannot shrink below {} blocks",
+                index + 1
+            );
+            return Err(Error::OutOfMemory);
+        }
+        let removed = old.block_count - shrunk.block_count;
+        info!(
+            "Shrinking filesystem from {} to {} blocks",
+            { old.block_count },
+            { shrunk.block_count }
+        );
+        let regions = old.relocatable_regions().into_iter();
+        for ((from, _), (to, length)) in regions.zip(shrunk.relocatable_regions()) {
+            move_region(&mut self.device, from, to, length)?;
+        }
+        self.superblock = shrunk;
+        self.blocks.shrink(shrunk.block_count);
+        self.inodes.relocate(&shrunk);
+        self.force_flush()?;
+        self.forget_history()?;
+        Ok(removed)
+    }
+}
+
+/// Copy `length` bytes at `from` to `to`, in the order which overwrites
+/// nothing before it is copied
+fn move_region(
+    device: &mut Box<dyn BlockDevice>,
+    from: u64,
+    to: u64,
+    length: u64,
+) -> Result<(), Error> {
+    if to == from {
+        return Ok(());
+    }
+    let mut buffer = vec![0u8; MOVE_CHUNK as usize];
+    let mut starts: Vec<u64> = (0..length).step_by(MOVE_CHUNK as usize).collect();
+    if to > from {
+        starts.reverse();
+    }
+    for start in starts {
+        let chunk = &mut buffer[..(MOVE_CHUNK.min(length - start)) as usize];
+        device.seek(SeekFrom::Start(from + start))?;
+        device.read_exact(chunk)?;
+        device.seek(SeekFrom::Start(to + start))?;
+        device.write_all(chunk)?;
+    }
+    device.barrier()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner, RegularFile};
+    use crate::Error;
+
+    #[test]
+    fn grow_keeps_files() {
+        let dev = Cursor::new(vec![0u8; 8_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 4_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "data", 0o640, Owner::default()).unwrap();
+        let index = file.inode.index;
+        file.write(0, &content).unwrap();
+        drop(file);
+
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        let (blocks, free) = (fs.superblock.block_count, fs.superblock.blocks_free);
+        let added = fs.grow(8_000_000).unwrap();
+        assert!(added > blocks);
+        assert_eq!(fs.grow(8_000_000).unwrap(), 0);
+        assert!(matches!(fs.grow(4_000_000), Err(Error::InvalidArgument)));
+        let (oldest, last) = fs.journal_history().unwrap();
+        assert_eq!(oldest, last);
+
+        let fs = Filesystem::load(fs.device, 512).unwrap();
+        assert_eq!({ fs.superblock.block_count }, blocks + added);
+        assert_eq!({ fs.superblock.blocks_free }, free + added);
+        let fs = Arc::new(Mutex::new(fs));
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert_eq!(Directory::find(&fs, ROOT_INODE, "data").unwrap(), index);
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!(file.read(0, content.len() as u64).unwrap(), content);
+    }
+
+    #[test]
+    fn shrink_keeps_files() {
+        let dev = Cursor::new(vec![0u8; 8_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 8_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "data", 0o640, Owner::default()).unwrap();
+        let index = file.inode.index;
+        file.write(0, &content).unwrap();
+        drop(file);
+
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        let (blocks, free) = (fs.superblock.block_count, fs.superblock.blocks_free);
+        // Blocks of the file would not fit
+        let small = fs.superblock.block_region_start() + 100 * 512;
+        assert!(matches!(fs.shrink(small), Err(Error::OutOfMemory)));
+        assert!(matches!(fs.shrink(8_000_000), Err(Error::InvalidArgument)));
+        let removed = fs.shrink(4_000_000).unwrap();
+        assert!(removed > 0 && removed < blocks);
+
+        let fs = Filesystem::load(fs.device, 512).unwrap();
+        assert_eq!({ fs.superblock.block_count }, blocks - removed);
+        assert_eq!({ fs.superblock.blocks_free }, free - removed);
+        assert!(fs.superblock.block_region_end() <= 4_000_000);
+        let fs = Arc::new(Mutex::new(fs));
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert_eq!(Directory::find(&fs, ROOT_INODE, "data").unwrap(), index);
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!(file.read(0, content.len() as u64).unwrap(), content);
+    }
+}
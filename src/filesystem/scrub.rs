@@ -0,0 +1,308 @@
+//! Background verification of block checksums
+//!
+//! Data nobody reads may rot unnoticed until the only good copy is gone. A
+//! [Scrubber] walks allocated blocks in small batches, verifying the stored
+//! checksum of every copy the device keeps. A block damaged on one side of a
+//! mirror is rewritten from the copy which still matches, while a block with
+//! no good copy left is logged and worsens the health of the filesystem. A
+//! batch runs only if nothing else locked the filesystem during the pause
+//! before it, so scrubbing keeps out of the way of other work. Once all blocks
+//! are verified, the time is recorded in the superblock and the next pass
+//! starts after the scrub interval.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use super::health::Health;
+use super::{Filesystem, LockFilesystem};
+use crate::filetypes::timestamp_now;
+use crate::structs::{Block, ChecksumAlgorithm, PermanentIndexed};
+use crate::Error;
+
+/// Blocks verified by a single batch of background scrubbing
+pub const SCRUB_BATCH_BLOCKS: u64 = 256;
+/// Pause before every batch, during which the filesystem must stay unused
+pub const SCRUB_PAUSE: Duration = Duration::from_secs(1);
+
+/// Outcome of verifying blocks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Blocks whose copies were verified
+    pub verified: u64,
+    /// Blocks with a damaged copy rewritten from a good one
+    pub repaired: u64,
+    /// Blocks without a single good copy
+    pub damaged: u64,
+}
+
+impl Filesystem {
+    /// Whether blocks have checksums to verify
+    fn scrubbable(&self) -> bool {
+        self.superblock.block_checksums != 0
+            && self
+                .superblock
+                .checksum_algorithm()
+                .is_ok_and(|algorithm| algorithm != ChecksumAlgorithm::None)
+    }
+
+    /// Verify every copy of block `index`, rewriting damaged ones from a good one
+    fn scrub_block(&mut self, index: u64, report: &mut ScrubReport) -> Result<(), Error> {
+        let (mut good, mut bad) = (None, 0);
+        for copy in 0..self.device.copies() {
+            self.device.select_copy(Some(copy));
+            match Block::load(&mut self.device, &self.superblock, index) {
+                Ok(block) => good = good.or(Some(block)),
+                Err(Error::Io(_) | Error::Corruption) => bad += 1,
+                Err(e) => {
+                    self.device.select_copy(None);
+                    return Err(e);
+                }
+            }
+        }
+        self.device.select_copy(None);
+        report.verified += 1;
+        match good {
+            _ if bad == 0 => {}
+            Some(block) if !self.read_only && self.health.state() != Health::ReadOnly => {
+                warn!("Repairing block {index} from its good copy");
+                block.flush(&mut self.device, &self.superblock)?;
+                report.repaired += 1;
+            }
+            _ => {
+                error!("Block {index} has no good copy left");
+                report.damaged += 1;
+                // Repeated damage turns the filesystem read-only like failed reads do
+                let _ = self.health.check_read::<()>(Err(Error::Corruption));
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify up to `count` allocated blocks starting at block `start`,
+    /// returning where the next batch starts or `None` if all were verified
+    ///
+    /// Cached blocks are skipped, as they are either verified when loaded or
+    /// not written yet.
+    pub fn scrub_batch(
+        &mut self,
+        start: u64,
+        count: u64,
+        report: &mut ScrubReport,
+    ) -> Result<Option<u64>, Error> {
+        if !self.scrubbable() {
+            return Err(Error::Incompatible);
+        }
+        let mut next = start;
+        for _ in 0..count {
+            let Some(index) = self.blocks.next_occupied(next) else {
+                return Ok(None);
+            };
+            // Inodes in chunks carry their own checksums
+            if !self.cache.contains_block(index) && !self.superblock.in_inode_chunk(index) {
+                self.scrub_block(index, report)?;
+            }
+            next = index + 1;
+        }
+        Ok(Some(next))
+    }
+
+    /// Record that scrubbing verified all blocks with `report`
+    fn finish_scrub(&mut self, report: &ScrubReport) -> Result<(), Error> {
+        info!(
+            "Scrubbed {} blocks, repaired {} and found {} damaged",
+            report.verified, report.repaired, report.damaged
+        );
+        if self.read_only {
+            return Ok(());
+        }
+        self.superblock.last_scrub = timestamp_now().as_secs();
+        self.force_flush()
+    }
+
+    /// Verify all allocated blocks at once
+    pub fn scrub(&mut self) -> Result<ScrubReport, Error> {
+        let mut report = ScrubReport::default();
+        let mut next = Some(0);
+        while let Some(start) = next {
+            next = self.scrub_batch(start, SCRUB_BATCH_BLOCKS, &mut report)?;
+        }
+        self.finish_scrub(&report)?;
+        Ok(report)
+    }
+
+    /// Time since scrubbing last verified all blocks
+    pub fn since_scrub(&self) -> Duration {
+        timestamp_now().saturating_sub(Duration::from_secs(self.superblock.last_scrub))
+    }
+}
+
+/// Thread scrubbing a filesystem in the background, stopped when dropped
+#[derive(Debug)]
+pub struct Scrubber {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Scrub filesystem every `interval` while it is idle
+    pub fn spawn(fs: Arc<Mutex<Filesystem>>, interval: Duration) -> Result<Self, Error> {
+        if !fs.lock_fs()?.scrubbable() {
+            error!("Filesystem without block checksums cannot be scrubbed");
+            return Err(Error::Incompatible);
+        }
+        info!("Scrubbing filesystem every {} seconds", interval.as_secs());
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let stopped = stop.clone();
+        let thread = std::thread::spawn(move || {
+            if let Err(e) = scrub_while_idle(&fs, interval, &stopped) {
+                error!("Scrubbing stopped: {e}");
+            }
+        });
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        if let Ok(mut stopped) = stopped.lock() {
+            *stopped = true;
+        }
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Wait for `duration` unless `stop` is signalled first, returning whether it was
+fn wait(stop: &(Mutex<bool>, Condvar), duration: Duration) -> Result<bool, Error> {
+    let (stopped, condvar) = stop;
+    let (stopped, _) =
+        condvar.wait_timeout_while(stopped.lock()?, duration, |stopped| !*stopped)?;
+    Ok(*stopped)
+}
+
+/// Run batches of scrubbing whenever a pass is due and the filesystem idle
+fn scrub_while_idle(
+    fs: &Arc<Mutex<Filesystem>>,
+    interval: Duration,
+    stop: &(Mutex<bool>, Condvar),
+) -> Result<(), Error> {
+    let mut report = ScrubReport::default();
+    // Block the pass in progress continues from
+    let mut position = None;
+    // End of the last pass, which read-only filesystems cannot record
+    let mut finished: Option<Instant> = None;
+    let mut seen: u64 = 0;
+    let mut pause = SCRUB_PAUSE;
+    while !wait(stop, pause)? {
+        let mut fs = fs.lock_fs()?;
+        let idle = fs.accesses == seen.wrapping_add(1);
+        let since = finished.map_or_else(|| fs.since_scrub(), |finished| finished.elapsed());
+        pause = SCRUB_PAUSE;
+        if position.is_none() && since < interval {
+            pause = interval - since;
+        } else if idle {
+            let start = position.unwrap_or_default();
+            position = fs.scrub_batch(start, SCRUB_BATCH_BLOCKS, &mut report)?;
+            if position.is_none() {
+                fs.finish_scrub(&std::mem::take(&mut report))?;
+                finished = Some(Instant::now());
+            }
+        }
+        seen = fs.accesses;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::Scrubber;
+    use crate::devices::mirror::MirrorDevice;
+    use crate::filesystem::health::Health;
+    use crate::filesystem::{Filesystem, LockFilesystem, ROOT_INODE};
+    use crate::filetypes::{FileOperations, Owner, RegularFile};
+    use crate::structs::ChecksumAlgorithm;
+    use crate::Error;
+
+    /// Image of filesystem holding a file of 10 blocks, with positions of
+    /// the first and last of them
+    fn image_with_file() -> (Vec<u8>, u64, u64) {
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(device), 1 << 20, 1024)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o644, Owner::default()).unwrap();
+        file.write(0, &[7u8; 10 * 1024]).unwrap();
+        file.flush().unwrap();
+        let blocks = (file.inode.first_block, file.inode.last_block);
+        drop(file);
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        fs.force_flush().unwrap();
+        let start = fs.superblock.block_region_start();
+        let mut data = Vec::new();
+        fs.device.rewind().unwrap();
+        fs.device.read_to_end(&mut data).unwrap();
+        (data, start + blocks.0 * 1024, start + blocks.1 * 1024)
+    }
+
+    #[test]
+    fn repair_from_mirror() {
+        let (image, first, last) = image_with_file();
+        let (mut primary, mut secondary) = (image.clone(), image.clone());
+        primary[first as usize + 100..][..7].copy_from_slice(b"bit rot");
+        secondary[last as usize + 100..][..7].copy_from_slice(b"bit rot");
+        let mirror = MirrorDevice::new(Cursor::new(primary), Cursor::new(secondary)).unwrap();
+        let mut fs = Filesystem::load(Box::new(mirror), 1024).unwrap();
+        assert_eq!({ fs.superblock.last_scrub }, 0);
+        let report = fs.scrub().unwrap();
+        assert_eq!((report.repaired, report.damaged), (2, 0));
+        assert!(report.verified >= 10);
+        assert!(fs.since_scrub() < Duration::from_secs(60));
+        let report = fs.scrub().unwrap();
+        assert_eq!((report.repaired, report.damaged), (0, 0));
+
+        // Without a mirror, damage is only reported
+        let (mut image, first, _) = image_with_file();
+        image[first as usize + 100] ^= 1;
+        let mut fs = Filesystem::load(Box::new(Cursor::new(image)), 1024).unwrap();
+        let report = fs.scrub().unwrap();
+        assert_eq!((report.repaired, report.damaged), (0, 1));
+        assert_eq!(fs.health.state(), Health::Degraded);
+    }
+
+    #[test]
+    fn scrub_in_background() {
+        let (image, _, _) = image_with_file();
+        let fs = Filesystem::load(Box::new(Cursor::new(image)), 1024).unwrap();
+        let fs = Arc::new(Mutex::new(fs));
+        let scrubber = Scrubber::spawn(fs.clone(), Duration::from_secs(3600)).unwrap();
+        // Locking without `lock_fs` leaves the filesystem idle for the scrubber
+        let started = Instant::now();
+        while fs.lock().unwrap().superblock.last_scrub == 0 {
+            assert!(started.elapsed() < Duration::from_secs(30));
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        drop(scrubber);
+        let report = fs.lock_fs().unwrap().scrub().unwrap();
+        assert_eq!((report.repaired, report.damaged), (0, 0));
+
+        let device = Cursor::new(vec![0u8; 1 << 20]);
+        let fs =
+            Filesystem::new(Box::new(device), 1 << 20, 1024).with_checksum(ChecksumAlgorithm::None);
+        assert!(matches!(
+            Scrubber::spawn(Arc::new(Mutex::new(fs)), Duration::ZERO),
+            Err(Error::Incompatible)
+        ));
+    }
+}
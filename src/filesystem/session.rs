@@ -0,0 +1,342 @@
+//! Batched access to the shared [Filesystem] under a single lock
+//!
+//! A [Session] keeps the filesystem locked for its whole lifetime, so a FUSE
+//! operation touching several inodes and blocks pays for one lock instead of
+//! one per call. The same rules as for [FilesystemGuard] apply: `filetypes`
+//! loaders lock on their own and must not be called while a session is alive.
+//!
+//! Inode changes are staged in the session and flushed together by
+//! [Session::commit], so a failing operation leaves no partially updated
+//! inodes behind. Only staged inodes and the quotas of owner changes are
+//! discarded with an uncommitted session: data written to blocks and blocks
+//! acquired or released in the bitmap along the way stay as they are.
+
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use fuser::{FileAttr, FileType};
+use log::{debug, warn};
+
+use super::{Filesystem, FilesystemGuard, Fragmentation, LockFilesystem};
+use crate::filetypes::{Owner, RawByteFile, RegularFile};
+use crate::structs::Inode;
+use crate::Error;
+
+#[derive(Debug)]
+pub(crate) struct Session<'a> {
+    fs: FilesystemGuard<'a>,
+    handle: &'a Arc<Mutex<Filesystem>>,
+    /// Inodes modified during the session, flushed on commit
+    dirty: BTreeMap<u64, Inode>,
+    /// Blocks moved from quotas of one owner to another along with the inode
+    /// holding them, moved back unless the session is committed
+    transfers: Vec<(Owner, Owner, u64)>,
+}
+
+impl Filesystem {
+    /// Lock the filesystem for a batch of operations
+    pub(crate) fn session(fs: &Arc<Mutex<Filesystem>>) -> Result<Session<'_>, Error> {
+        Ok(Session {
+            fs: fs.lock_fs()?,
+            handle: fs,
+            dirty: BTreeMap::new(),
+            transfers: Vec::new(),
+        })
+    }
+}
+
+impl Session<'_> {
+    /// Load [Inode] with given index, preferring its staged version
+    pub fn load_inode(&mut self, index: u64) -> Result<Inode, Error> {
+        match self.dirty.get(&index) {
+            Some(inode) => Ok(*inode),
+            None => self.fs.load_inode(index),
+        }
+    }
+
+    /// Stage [Inode] to be flushed on [commit](Self::commit)
+    pub fn stage_inode(&mut self, inode: Inode) {
+        self.dirty.insert(inode.index, inode);
+    }
+
+    /// Attributes of [Inode] with given index
+    pub fn attrs(&mut self, index: u64) -> Result<FileAttr, Error> {
+        let inode = self.load_inode(index)?;
+        Ok(inode.attrs(&self.fs.superblock))
+    }
+
+    /// Open regular file with given [Inode] index
+    fn file(&mut self, index: u64) -> Result<RegularFile, Error> {
+        let inode = self.load_inode(index)?;
+        if inode.r#type == FileType::Directory {
+            return Err(Error::IsDirectory);
+        }
+        Ok(RegularFile::from_inode_locked(&self.fs, self.handle, inode))
+    }
+
+    /// Read up to `size` bytes from regular file starting at `offset`
+    pub fn read_file(&mut self, index: u64, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let mut file = self.file(index)?;
+        file.read_locked(&mut self.fs, offset, size)
+    }
+
+    /// Read up to `size` bytes from regular file starting at `offset` into
+    /// `buffer`, reusing its allocation
+    pub fn read_file_into(
+        &mut self,
+        index: u64,
+        offset: u64,
+        size: u64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut file = self.file(index)?;
+        file.read_into_locked(&mut self.fs, offset, size, buffer)?;
+        self.fs.statistics.count_read(buffer.len());
+        Ok(())
+    }
+
+    /// Prefetch up to `count` blocks of regular file following byte before
+    /// `end`, returning the number of prefetched blocks belonging to it
+    pub fn read_ahead(&mut self, index: u64, end: u64, count: u64) -> Result<u64, Error> {
+        let mut file = self.file(index)?;
+        file.file.read_ahead_locked(&mut self.fs, end, count)
+    }
+
+    /// Write data to regular file starting at `offset` and stage its inode
+    pub fn write_file(&mut self, index: u64, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let mut file = self.file(index)?;
+        file.write_locked(&mut self.fs, offset, data)?;
+        file.sync_inode();
+        self.stage_inode(file.inode);
+        Ok(())
+    }
+
+    /// Extend or shrink regular file to `size` bytes and stage its inode
+    pub fn resize_file(&mut self, index: u64, size: u64) -> Result<(), Error> {
+        let mut file = self.file(index)?;
+        file.inode.check_modifiable()?;
+        if size > file.file.size {
+            file.file.extend_locked(&mut self.fs, size)?;
+        } else {
+            file.file.shrink_locked(&mut self.fs, size)?;
+        }
+        file.sync_inode();
+        self.stage_inode(file.inode);
+        Ok(())
+    }
+
+    /// Acquire blocks of regular file for its first `end` bytes, extending
+    /// it to them unless `keep_size` is set, and stage its inode
+    pub fn allocate_file(&mut self, index: u64, end: u64, keep_size: bool) -> Result<(), Error> {
+        let mut file = self.file(index)?;
+        file.inode.check_modifiable()?;
+        file.file.preallocate_locked(&mut self.fs, end)?;
+        if !keep_size && end > file.file.size {
+            file.file.extend_locked(&mut self.fs, end)?;
+        }
+        file.sync_inode();
+        self.stage_inode(file.inode);
+        Ok(())
+    }
+
+    /// Make regular file `target` a clone of regular file `source` and stage its inode
+    pub fn clone_file(&mut self, source: u64, target: u64) -> Result<(), Error> {
+        if source == target {
+            return Err(Error::InvalidArgument);
+        }
+        let source = self.file(source)?;
+        let mut target = self.file(target)?;
+        if source.inode.r#type != FileType::RegularFile
+            || target.inode.r#type != FileType::RegularFile
+        {
+            return Err(Error::InvalidArgument);
+        }
+        target.inode.check_modifiable()?;
+        self.fs.clone_blocks(&source.file, &mut target.file)?;
+        target.sync_inode();
+        self.stage_inode(target.inode);
+        Ok(())
+    }
+
+    /// Change owner of inode with given index and stage it, moving its
+    /// blocks and itself over to quotas of the new owner
+    pub fn change_owner(&mut self, index: u64, owner: Owner) -> Result<(), Error> {
+        let mut inode = self.load_inode(index)?;
+        let previous = Owner::from(&inode);
+        if previous == owner {
+            return Ok(());
+        }
+        if self.fs.quotas.enabled() {
+            let file = RawByteFile::load_locked(&self.fs, self.handle, inode);
+            let blocks = file.blocks_locked(&mut self.fs)?.len() as u64;
+            self.fs.quotas.transfer(previous, owner, blocks, 1)?;
+            self.transfers.push((previous, owner, blocks));
+        }
+        inode.uid = owner.uid;
+        inode.gid = owner.gid;
+        self.stage_inode(inode);
+        Ok(())
+    }
+
+    /// Layout of blocks holding data of inode with given index
+    pub fn fragmentation(&mut self, index: u64) -> Result<Fragmentation, Error> {
+        let inode = self.load_inode(index)?;
+        let file = RawByteFile::load_locked(&self.fs, self.handle, inode);
+        let mut blocks = file.blocks_locked(&mut self.fs)?;
+        blocks.truncate(file.block_count as usize);
+        Ok(Fragmentation::of(&blocks))
+    }
+
+    /// Flush all staged inodes and release the lock
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.transfers.clear();
+        let dirty = std::mem::take(&mut self.dirty);
+        debug!("Commit {} staged inodes", dirty.len());
+        for inode in dirty.values() {
+            self.fs.flush_inode(inode)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        if !self.dirty.is_empty() {
+            warn!("Discarding {} uncommitted inodes", self.dirty.len());
+        }
+        // Usage moved to the new owner always fits the limits of the previous one
+        while let Some((previous, owner, blocks)) = self.transfers.pop() {
+            if let Err(e) = self.fs.quotas.transfer(owner, previous, blocks, 1) {
+                warn!("Unable to return quota of uncommitted owner change: {e}");
+            }
+        }
+    }
+}
+
+impl Deref for Session<'_> {
+    type Target = Filesystem;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fs
+    }
+}
+
+impl DerefMut for Session<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.fs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{
+        error::Error,
+        filesystem::{Filesystem, LockFilesystem, QuotaKind, ROOT_INODE},
+        filetypes::{Directory, FileOperations, Owner, RegularFile},
+    };
+
+    /// Filesystem with root directory and an empty regular file
+    fn filesystem() -> (Arc<Mutex<Filesystem>>, u64) {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let index = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        (fs, index)
+    }
+
+    #[test]
+    fn read_and_write_in_session() {
+        let (fs, index) = filesystem();
+        let data: Vec<u8> = (0..2_000).map(|i| i as u8).collect();
+        {
+            let mut session = Filesystem::session(&fs).unwrap();
+            assert!(matches!(fs.lock_fs(), Err(Error::ThreadSync)));
+            session.write_file(index, 0, &data).unwrap();
+            assert_eq!(session.attrs(index).unwrap().size, 2_000);
+            assert_eq!(session.read_file(index, 100, 50).unwrap(), &data[100..150]);
+            session.commit().unwrap();
+        }
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!(file.read(0, 2_000).unwrap(), data);
+    }
+
+    #[test]
+    fn read_ahead_caches_following_blocks() {
+        let (fs, index) = filesystem();
+        let data = vec![7u8; 5_000];
+        RegularFile::load(&fs, index)
+            .unwrap()
+            .write(0, &data)
+            .unwrap();
+        let mut session = Filesystem::session(&fs).unwrap();
+        session.force_flush().unwrap();
+        let cached: Vec<u64> = session.cache.blocks.keys().copied().collect();
+        for block in cached {
+            session.cache.remove_block(block);
+        }
+        assert_eq!(session.read_file(index, 0, 100).unwrap(), &data[..100]);
+        let cached = session.cache.blocks.len();
+        assert_eq!(session.read_ahead(index, 100, 4).unwrap(), 4);
+        assert_eq!(session.cache.blocks.len(), cached + 4);
+        // Next block is already cached
+        assert_eq!(session.read_ahead(index, 100, 4).unwrap(), 0);
+        assert_eq!(session.read_ahead(index, 4_900, 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn uncommitted_inodes_are_discarded() {
+        let (fs, index) = filesystem();
+        let mut session = Filesystem::session(&fs).unwrap();
+        let mut inode = session.load_inode(index).unwrap();
+        inode.mode = 0o600;
+        session.stage_inode(inode);
+        assert_eq!(session.attrs(index).unwrap().perm, 0o600);
+        drop(session);
+        let mut session = Filesystem::session(&fs).unwrap();
+        assert_eq!(session.attrs(index).unwrap().perm, 0o640);
+        session.stage_inode(inode);
+        session.commit().unwrap();
+        assert_eq!(
+            { fs.lock().unwrap().load_inode(index).unwrap().mode },
+            0o600
+        );
+    }
+
+    #[test]
+    fn uncommitted_owner_change_keeps_quotas() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512).with_quotas();
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        file.write(0, &[1u8; 2_000]).unwrap();
+        let index = file.inode.index;
+        drop(file);
+        let user = Owner {
+            uid: 1000,
+            gid: 100,
+        };
+        let usage = |fs: &Filesystem| {
+            let quota = fs.quotas.get(QuotaKind::User, 1000);
+            (quota.blocks, quota.inodes)
+        };
+        let mut session = Filesystem::session(&fs).unwrap();
+        session.change_owner(index, user).unwrap();
+        assert_eq!(usage(&session), (5, 1));
+        drop(session);
+        assert_eq!(usage(&fs.lock().unwrap()), (0, 0));
+        let mut session = Filesystem::session(&fs).unwrap();
+        session.change_owner(index, user).unwrap();
+        session.commit().unwrap();
+        assert_eq!(usage(&fs.lock().unwrap()), (5, 1));
+        assert_eq!({ fs.lock().unwrap().load_inode(index).unwrap().uid }, 1000);
+    }
+}
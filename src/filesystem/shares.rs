@@ -0,0 +1,321 @@
+//! Chains of blocks shared by cloned files
+//!
+//! A clone of a regular file takes over the chain of blocks and tables of its
+//! source instead of copying them, so cloning is instant regardless of size.
+//! Filesystems with [INCOMPAT_REFLINK] count files holding every shared chain
+//! by its first block. A file holding a shared chain copies it to blocks of
+//! its own before it is first modified, and a removed one only drops its
+//! reference, leaving the blocks to the last file holding them. Every file is
+//! charged for its blocks as if it held a copy, so changing one never exceeds
+//! a quota. Counts are kept in memory and written on every flush to the share
+//! table, a chain of blocks recorded in the superblock and charged to no one.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, info};
+
+use super::journal::Transaction;
+use super::Filesystem;
+use crate::filetypes::{bytes_per_block, get_next_block, set_next_block, RawByteFile};
+use crate::structs::{Block, PermanentIndexed, INCOMPAT_REFLINK, NULL_BLOCK};
+use crate::Error;
+
+/// Bytes of a single record of the share table
+const RECORD_SIZE: usize = 16;
+
+/// Number of files holding every shared chain of blocks
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Shares {
+    /// Files holding chain starting at a block, for chains held by at least two
+    holders: BTreeMap<u64, u64>,
+    /// Blocks of the share table, in order
+    pub(crate) blocks: Vec<u64>,
+    /// Whether counts changed since they were last written
+    modified: bool,
+}
+
+impl Shares {
+    /// Whether chain starting at `first_block` is held by more than one file
+    pub fn is_shared(&self, first_block: u64) -> bool {
+        self.holders.contains_key(&first_block)
+    }
+
+    /// Shared chains by their first block, with the number of files holding them
+    pub fn entries(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.holders
+            .iter()
+            .map(|(&first, &holders)| (first, holders))
+    }
+
+    /// Count another file holding chain starting at `first_block`
+    fn share(&mut self, first_block: u64) {
+        *self.holders.entry(first_block).or_insert(1) += 1;
+        self.modified = true;
+    }
+
+    /// Stop counting a file holding chain starting at `first_block`
+    ///
+    /// Returns false, changing nothing, if the chain was not shared, in which
+    /// case its blocks belong to the file alone.
+    pub fn release(&mut self, first_block: u64) -> bool {
+        let Some(holders) = self.holders.get_mut(&first_block) else {
+            return false;
+        };
+        *holders -= 1;
+        if *holders < 2 {
+            self.holders.remove(&first_block);
+        }
+        self.modified = true;
+        true
+    }
+
+    /// Replace counts with those found while checking the filesystem
+    pub(super) fn recount(&mut self, holders: &BTreeMap<u64, u64>) {
+        self.holders = holders
+            .iter()
+            .filter(|(_, &holders)| holders > 1)
+            .map(|(&first, &holders)| (first, holders))
+            .collect();
+        self.modified = true;
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.holders.len() * RECORD_SIZE);
+        for (&first, &holders) in self.holders.iter() {
+            bytes.extend_from_slice(&first.to_le_bytes());
+            bytes.extend_from_slice(&holders.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if !bytes.len().is_multiple_of(RECORD_SIZE) {
+            return Err(Error::Corruption);
+        }
+        for record in bytes.chunks_exact(RECORD_SIZE) {
+            let first = u64::from_le_bytes(record[..8].try_into()?);
+            let holders = u64::from_le_bytes(record[8..].try_into()?);
+            if holders < 2 {
+                return Err(Error::Corruption);
+            }
+            self.holders.insert(first, holders);
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem {
+    /// Make regular file `target` a clone of regular file `source`, holding
+    /// the same blocks until either of them is modified
+    pub fn clone_file(fs: &Arc<Mutex<Filesystem>>, source: u64, target: u64) -> Result<(), Error> {
+        let mut session = Filesystem::session(fs)?;
+        session.clone_file(source, target)?;
+        session.commit()
+    }
+
+    /// Make `target` hold the blocks of `source`, charging them to its owner
+    ///
+    /// Blocks `target` held before are released first. Files whose contents
+    /// are kept in their inode share nothing, so they are copied instead.
+    pub(crate) fn clone_blocks(
+        &mut self,
+        source: &RawByteFile,
+        target: &mut RawByteFile,
+    ) -> Result<(), Error> {
+        target.shrink_locked(self, 0)?;
+        if source.first_block == NULL_BLOCK {
+            if let Some(contents) = &source.inline {
+                target.inline = Some(contents.clone());
+                target.size = source.size;
+            }
+            return Ok(());
+        }
+        let blocks = source.blocks_locked(self)?.len() as u64;
+        self.quotas.charge(target.owner, blocks, 0)?;
+        if self.superblock.incompat_flags & INCOMPAT_REFLINK == 0 {
+            info!("Enabling clones sharing blocks");
+            self.superblock.incompat_flags |= INCOMPAT_REFLINK;
+        }
+        debug!("Clone {blocks} blocks starting at {}", source.first_block);
+        self.shares.share(source.first_block);
+        target.first_block = source.first_block;
+        target.last_block = source.last_block;
+        target.block_count = source.block_count;
+        target.size = source.size;
+        target.tables = source.tables;
+        target.inline = None;
+        target.block_map.clear();
+        target.cursor.reset();
+        Ok(())
+    }
+
+    /// Read counts of shared chains from the share table, if filesystem has one
+    pub(super) fn load_shares(&mut self) -> Result<(), Error> {
+        if self.superblock.incompat_flags & INCOMPAT_REFLINK == 0 {
+            return Ok(());
+        }
+        let size = self.superblock.share_table_size as usize;
+        let per_block = bytes_per_block(self.superblock.block_size) as usize;
+        let mut data = Vec::with_capacity(size);
+        let mut index = self.superblock.share_table_block;
+        while index != NULL_BLOCK {
+            if self.shares.blocks.len() >= size.div_ceil(per_block) {
+                return Err(Error::Corruption);
+            }
+            let block = Block::load(&mut self.device, &self.superblock, index)?;
+            data.extend_from_slice(&block.data[8..]);
+            self.shares.blocks.push(index);
+            index = get_next_block(&block);
+        }
+        if data.len() < size {
+            return Err(Error::Corruption);
+        }
+        data.truncate(size);
+        self.shares.load_bytes(&data)?;
+        debug!("Loaded {} shared chains", self.shares.holders.len());
+        Ok(())
+    }
+
+    /// Write modified counts of shared chains to the share table as part of
+    /// `transaction`
+    ///
+    /// Blocks of the share table bypass the cache, as nothing else reads them.
+    pub(super) fn flush_shares(&mut self, transaction: &mut Transaction) -> Result<(), Error> {
+        if !self.shares.modified {
+            return Ok(());
+        }
+        let data = self.shares.to_bytes();
+        let per_block = bytes_per_block(self.superblock.block_size) as usize;
+        let needed = data.len().div_ceil(per_block);
+        debug!("Flushing shared chains to {needed} blocks");
+        while self.shares.blocks.len() < needed {
+            let index = self.allocate_block()?;
+            self.shares.blocks.push(index);
+        }
+        while self.shares.blocks.len() > needed {
+            let index = self.shares.blocks.pop().expect("share table has blocks");
+            self.free_block(index)?;
+        }
+        let blocks = self.shares.blocks.clone();
+        for (n, &index) in blocks.iter().enumerate() {
+            let mut block = Block::with_index(self, index)?;
+            let next = blocks.get(n + 1).copied().unwrap_or(NULL_BLOCK);
+            set_next_block(&mut block, next);
+            let chunk = &data[n * per_block..data.len().min((n + 1) * per_block)];
+            block.data[8..8 + chunk.len()].copy_from_slice(chunk);
+            block.flush(transaction, &self.superblock)?;
+            // Stale copy of a previous owner must not overwrite it
+            self.cache.remove_block(index);
+        }
+        self.superblock.share_table_block = blocks.first().copied().unwrap_or(NULL_BLOCK);
+        self.superblock.share_table_size = data.len() as u64;
+        self.shares.modified = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::Shares;
+    use crate::filesystem::{Filesystem, LockFilesystem, QuotaKind, ROOT_INODE};
+    use crate::filetypes::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile,
+    };
+    use crate::Error;
+
+    fn read_all(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Vec<u8> {
+        let mut file = RegularFile::load(fs, index).unwrap();
+        let size = file.inode.size;
+        file.read(0, size).unwrap()
+    }
+
+    #[test]
+    fn count_holders() {
+        let mut shares = Shares::default();
+        assert!(!shares.release(7));
+        shares.share(7);
+        shares.share(7);
+        assert_eq!(shares.entries().collect::<Vec<_>>(), [(7, 3)]);
+        let mut loaded = Shares::default();
+        loaded.load_bytes(&shares.to_bytes()).unwrap();
+        assert_eq!(loaded.entries().collect::<Vec<_>>(), [(7, 3)]);
+        assert!(shares.release(7));
+        assert!(shares.release(7));
+        assert!(!shares.is_shared(7));
+        assert!(loaded.load_bytes(&[1; 15]).is_err());
+    }
+
+    #[test]
+    fn clone_and_modify() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512).with_quotas();
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let user = Owner {
+            uid: 1000,
+            gid: 100,
+        };
+        let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut source = RegularFile::new(&fs, ROOT_INODE, "source", 0o644, user).unwrap();
+        source.write(0, &contents).unwrap();
+        source.flush().unwrap();
+        let source = source.inode.index;
+        let target = RegularFile::new(&fs, ROOT_INODE, "target", 0o644, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let blocks = |uid| {
+            fs.lock_fs()
+                .unwrap()
+                .quotas
+                .get(QuotaKind::User, uid)
+                .blocks
+        };
+        let free = fs.lock_fs().unwrap().superblock.blocks_free;
+        let charged = blocks(0);
+        Filesystem::clone_file(&fs, source, target).unwrap();
+        assert_eq!({ fs.lock_fs().unwrap().superblock.blocks_free }, free);
+        assert_eq!(read_all(&fs, target), contents);
+        // Both owners are charged as if they held a copy
+        assert_eq!(blocks(0) - charged, blocks(1000));
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+
+        // Counts survive remounting
+        let device = {
+            let mut fs_handle = fs.lock_fs().unwrap();
+            fs_handle.force_flush().unwrap();
+            std::mem::replace(&mut fs_handle.device, Box::new(Cursor::new(Vec::new())))
+        };
+        let fs = Arc::new(Mutex::new(Filesystem::load(device, 512).unwrap()));
+        assert_eq!(fs.lock_fs().unwrap().shares.entries().count(), 1);
+
+        // Modified clone copies the chain, leaving its source as it was
+        let mut file = RegularFile::load(&fs, target).unwrap();
+        file.write(10, b"changed").unwrap();
+        file.flush().unwrap();
+        drop(file);
+        assert_eq!(read_all(&fs, source), contents);
+        let mut changed = contents.clone();
+        changed[10..17].copy_from_slice(b"changed");
+        assert_eq!(read_all(&fs, target), changed);
+        assert_eq!(fs.lock_fs().unwrap().shares.entries().count(), 0);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+
+        // Removing one of the files keeps blocks held by the other
+        Filesystem::clone_file(&fs, source, target).unwrap();
+        Directory::load(&fs, ROOT_INODE)
+            .unwrap()
+            .remove_child(DirectoryChildIdentifier::Name("source"))
+            .unwrap();
+        assert_eq!(read_all(&fs, target), contents);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert!(matches!(
+            Filesystem::clone_file(&fs, ROOT_INODE, target),
+            Err(Error::IsDirectory)
+        ));
+    }
+}
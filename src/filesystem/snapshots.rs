@@ -0,0 +1,426 @@
+//! Snapshots of the directory tree
+//!
+//! A snapshot is a copy of the directory tree taken at one point, kept in a
+//! directory recorded in the superblock, which is linked nowhere and so
+//! cannot be reached from the root. Every snapshot is a subdirectory of it
+//! named after the snapshot, whose regular files are clones of the ones they
+//! were copied from, holding the same blocks until either of them is modified
+//! on filesystems with [INCOMPAT_REFLINK](crate::structs::INCOMPAT_REFLINK).
+//!
+//! Rolling back to a snapshot swaps the inode of the root directory with the
+//! one of the snapshot, so both are rewritten in a single transaction while
+//! nothing is copied. The snapshot then holds the tree from before the
+//! rollback, and rolling back to it once more undoes the rollback.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use fuser::FileType;
+use log::{info, warn};
+
+use super::{Filesystem, LockFilesystem, ROOT_INODE};
+use crate::filetypes::{
+    timestamp_now, Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile, Walk,
+};
+use crate::structs::{INCOMPAT_SNAPSHOTS, NULL_BLOCK};
+use crate::Error;
+
+/// Bytes of contents compared at once
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Name of the directory holding snapshots, shown only in its own body
+const SNAPSHOTS_NAME: &str = "snapshots";
+
+/// Difference between two directory trees, by path relative to their roots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    /// Contents or attributes other than access time changed
+    Modified(String),
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Modified(path) => path,
+        }
+    }
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = match self {
+            Change::Added(_) => 'A',
+            Change::Removed(_) => 'D',
+            Change::Modified(_) => 'M',
+        };
+        write!(f, "{sign} /{}", self.path())
+    }
+}
+
+impl Filesystem {
+    /// Copy directory tree into a new snapshot named `name`, returning inode
+    /// of its root
+    pub fn create_snapshot(fs: &Arc<Mutex<Filesystem>>, name: &str) -> Result<u64, Error> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(Error::InvalidArgument);
+        }
+        let snapshots = Filesystem::snapshot_directory(fs, true)?;
+        if Directory::load(fs, snapshots)?.lookup(name).is_ok() {
+            return Err(Error::NameOrInodeDuplicate);
+        }
+        let owner = Owner::from(&fs.lock_fs()?.load_inode(ROOT_INODE)?);
+        // Permissions are set once the whole tree is copied
+        let root = Directory::new(fs, snapshots, name, 0o700, owner)?
+            .inode
+            .index;
+        match copy_tree(fs, root) {
+            Ok(copied) => {
+                fs.lock_fs()?.force_flush()?;
+                info!("Created snapshot {name} of {copied} files");
+                Ok(root)
+            }
+            Err(e) => {
+                warn!("Removing incomplete snapshot {name}");
+                Filesystem::delete_snapshot(fs, name)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Names of snapshots, ordered by name
+    pub fn snapshots(fs: &Arc<Mutex<Filesystem>>) -> Result<Vec<String>, Error> {
+        let snapshots = Filesystem::snapshot_directory(fs, false)?;
+        if snapshots == 0 {
+            return Ok(Vec::new());
+        }
+        let directory = Directory::load(fs, snapshots)?;
+        let mut names: Vec<String> = directory.children.iter().map(|c| c.name.clone()).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Inode of the root of snapshot `name`
+    pub fn snapshot(fs: &Arc<Mutex<Filesystem>>, name: &str) -> Result<u64, Error> {
+        match Filesystem::snapshot_directory(fs, false)? {
+            0 => Err(Error::NotFound),
+            snapshots => Directory::load(fs, snapshots)?
+                .get_child_inode(DirectoryChildIdentifier::Name(name)),
+        }
+    }
+
+    /// Remove snapshot `name` along with its tree
+    pub fn delete_snapshot(fs: &Arc<Mutex<Filesystem>>, name: &str) -> Result<(), Error> {
+        let root = Filesystem::snapshot(fs, name)?;
+        let snapshots = Filesystem::snapshot_directory(fs, false)?;
+        let mut directory = Directory::load(fs, snapshots)?;
+        directory.detach_child(DirectoryChildIdentifier::Inode(root))?;
+        directory.flush()?;
+        drop(directory);
+        Directory::load(fs, root)?.remove()?;
+        fs.lock_fs()?.force_flush()?;
+        info!("Deleted snapshot {name}");
+        Ok(())
+    }
+
+    /// Replace directory tree with the one of snapshot `name`, which takes
+    /// the replaced tree over
+    pub fn rollback(fs: &Arc<Mutex<Filesystem>>, name: &str) -> Result<(), Error> {
+        let snapshot = Filesystem::snapshot(fs, name)?;
+        let children = |index| -> Result<Vec<(String, u64)>, Error> {
+            let directory = Directory::load(fs, index)?;
+            Ok(directory
+                .children
+                .iter()
+                .map(|c| (c.name.clone(), c.inode))
+                .collect())
+        };
+        let (current, restored) = (children(ROOT_INODE)?, children(snapshot)?);
+        let mut fs_handle = fs.lock_fs()?;
+        let (root, copy) = (
+            fs_handle.load_inode(ROOT_INODE)?,
+            fs_handle.load_inode(snapshot)?,
+        );
+        let now = timestamp_now();
+        // Each inode keeps its index and parent, taking over everything else
+        let mut inodes = vec![];
+        for (mut inode, kept) in [(copy, root), (root, copy)] {
+            inode.index = kept.index;
+            inode.metadata[0] = kept.metadata[0];
+            inode.set_ctime(now);
+            inodes.push(inode);
+        }
+        for (entries, parent) in [(&restored, ROOT_INODE), (&current, snapshot)] {
+            for (_, index) in entries.iter() {
+                let mut inode = fs_handle.load_inode(*index)?;
+                if inode.r#type == FileType::Directory {
+                    inode.metadata[0] = parent;
+                    inodes.push(inode);
+                }
+            }
+        }
+        // Written to cache only, so none of them is flushed before the others
+        for inode in inodes.iter() {
+            fs_handle.cache.write_inode(inode);
+            fs_handle.invalidate(inode.index);
+        }
+        for (name, _) in current.iter().chain(restored.iter()) {
+            fs_handle.invalidate_entry(ROOT_INODE, name);
+            fs_handle.invalidate_entry(snapshot, name);
+        }
+        fs_handle.force_flush()?;
+        info!("Rolled back to snapshot {name}");
+        Ok(())
+    }
+
+    /// Compare subtrees of directories `from` and `to`, returning changes
+    /// made to the first one to get the second one, ordered by path
+    pub fn diff(fs: &Arc<Mutex<Filesystem>>, from: u64, to: u64) -> Result<Vec<Change>, Error> {
+        let (before, after) = (paths(fs, from)?, paths(fs, to)?);
+        let mut changes = Vec::new();
+        for (path, &index) in before.iter() {
+            match after.get(path) {
+                None => changes.push(Change::Removed(path.clone())),
+                Some(&other) if differs(fs, index, other)? => {
+                    changes.push(Change::Modified(path.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for path in after.keys().filter(|path| !before.contains_key(*path)) {
+            changes.push(Change::Added(path.clone()));
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(changes)
+    }
+
+    /// Inode of the directory holding snapshots, creating it if `create` is
+    /// set, or zero if there is none
+    fn snapshot_directory(fs: &Arc<Mutex<Filesystem>>, create: bool) -> Result<u64, Error> {
+        let mut fs_handle = fs.lock_fs()?;
+        let index = fs_handle.superblock.snapshot_inode;
+        if index != 0 || !create {
+            return Ok(index);
+        }
+        let owner = Owner::from(&fs_handle.load_inode(ROOT_INODE)?);
+        drop(fs_handle);
+        // Referring to the root keeps depth of paths in snapshots bounded
+        let directory = Directory::new_unlinked(fs, ROOT_INODE, SNAPSHOTS_NAME, 0o700, owner)?;
+        let index = directory.inode.index;
+        drop(directory);
+        let mut fs_handle = fs.lock_fs()?;
+        fs_handle.superblock.snapshot_inode = index;
+        fs_handle.superblock.incompat_flags |= INCOMPAT_SNAPSHOTS;
+        Ok(index)
+    }
+}
+
+/// Copy directory tree into empty directory `root`, returning number of copied entries
+fn copy_tree(fs: &Arc<Mutex<Filesystem>>, root: u64) -> Result<u64, Error> {
+    let mut indices = BTreeMap::from([(String::new(), root)]);
+    let mut copies = vec![(ROOT_INODE, root, FileType::Directory)];
+    for walked in Walk::new(fs, ROOT_INODE).skip(1) {
+        let (path, index, kind) = walked?;
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+        let parent = *indices.get(parent).ok_or(Error::Corruption)?;
+        let owner = Owner::from(&fs.lock_fs()?.load_inode(index)?);
+        let copy = match kind {
+            FileType::Directory => Directory::new(fs, parent, name, 0o700, owner)?.inode.index,
+            _ => {
+                RegularFile::new(fs, parent, name, 0o700, owner)?
+                    .inode
+                    .index
+            }
+        };
+        indices.insert(path, copy);
+        copies.push((index, copy, kind));
+    }
+    for &(index, copy, kind) in copies.iter() {
+        if kind != FileType::Directory {
+            continue;
+        }
+        if let Some(mask) = Directory::load(fs, index)?.mode_mask() {
+            let mut directory = Directory::load(fs, copy)?;
+            directory.set_mode_mask(Some(mask));
+            directory.flush()?;
+        }
+    }
+    let mut session = Filesystem::session(fs)?;
+    for &(index, copy, kind) in copies.iter() {
+        let source = session.load_inode(index)?;
+        if kind == FileType::RegularFile {
+            session.clone_file(index, copy)?;
+        }
+        session.change_owner(copy, Owner::from(&source))?;
+        let mut inode = session.load_inode(copy)?;
+        inode.mode = source.mode;
+        inode.flags = source.flags;
+        inode.atime = source.atime;
+        inode.atime_nsec = source.atime_nsec;
+        inode.mtime = source.mtime;
+        inode.mtime_nsec = source.mtime_nsec;
+        inode.crtime = source.crtime;
+        inode.crtime_nsec = source.crtime_nsec;
+        session.stage_inode(inode);
+    }
+    session.commit()?;
+    Ok(copies.len() as u64 - 1)
+}
+
+/// Inodes of every path in subtree of directory `index`
+fn paths(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<BTreeMap<String, u64>, Error> {
+    let mut paths = BTreeMap::new();
+    for walked in Walk::new(fs, index) {
+        let (path, index, _) = walked?;
+        paths.insert(path, index);
+    }
+    Ok(paths)
+}
+
+/// Whether inodes `a` and `b` differ in contents or attributes other than
+/// access time, while modification time of directories only follows their entries
+fn differs(fs: &Arc<Mutex<Filesystem>>, a: u64, b: u64) -> Result<bool, Error> {
+    let (a, b) = {
+        let mut fs_handle = fs.lock_fs()?;
+        (fs_handle.load_inode(a)?, fs_handle.load_inode(b)?)
+    };
+    if a.r#type != b.r#type
+        || a.mode != b.mode
+        || Owner::from(&a) != Owner::from(&b)
+        || a.flags != b.flags
+    {
+        return Ok(true);
+    }
+    if a.r#type == FileType::Directory {
+        return Ok(a.metadata[4] != b.metadata[4]);
+    }
+    if a.size != b.size || (a.mtime, a.mtime_nsec) != (b.mtime, b.mtime_nsec) {
+        return Ok(true);
+    }
+    // Clones hold the same chain until either of them is modified
+    if a.first_block == b.first_block && a.first_block != NULL_BLOCK {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (
+        RegularFile::load(fs, a.index)?,
+        RegularFile::load(fs, b.index)?,
+    );
+    for offset in (0..a.inode.size).step_by(CHUNK_SIZE as usize) {
+        if a.read(offset, CHUNK_SIZE)? != b.read(offset, CHUNK_SIZE)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::Change;
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, DirectoryChildIdentifier, FileOperations, Owner};
+    use crate::structs::{Superblock, INCOMPAT_SNAPSHOTS};
+    use crate::Error;
+
+    fn filesystem() -> Arc<Mutex<Filesystem>> {
+        let mut superblock = Superblock::new(4_000_000, 512);
+        superblock.set_feature("reflink", true, 4_000_000).unwrap();
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Filesystem::from_superblock(Box::new(dev), superblock);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        fs
+    }
+
+    fn reload(fs: Arc<Mutex<Filesystem>>) -> Arc<Mutex<Filesystem>> {
+        let mut fs = Arc::into_inner(fs).unwrap().into_inner().unwrap();
+        fs.force_flush().unwrap();
+        Arc::new(Mutex::new(Filesystem::load(fs.device, 512).unwrap()))
+    }
+
+    #[test]
+    fn snapshot_diff_and_rollback() {
+        let fs = filesystem();
+        let owner = Owner::default();
+        Filesystem::create_dir_all(&fs, "/docs/old", owner).unwrap();
+        Filesystem::write(&fs, "/docs/a", b"first", owner).unwrap();
+        Filesystem::write(&fs, "/b", &[7; 3000], owner).unwrap();
+        assert!(Filesystem::snapshots(&fs).unwrap().is_empty());
+        assert!(matches!(
+            Filesystem::create_snapshot(&fs, "a/b"),
+            Err(Error::InvalidArgument)
+        ));
+
+        let snapshot = Filesystem::create_snapshot(&fs, "before").unwrap();
+        assert!(fs.lock().unwrap().superblock.incompat_flags & INCOMPAT_SNAPSHOTS != 0);
+        assert!(matches!(
+            Filesystem::create_snapshot(&fs, "before"),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        assert_eq!(Filesystem::snapshots(&fs).unwrap(), ["before"]);
+        assert!(Filesystem::diff(&fs, snapshot, ROOT_INODE)
+            .unwrap()
+            .is_empty());
+        // Snapshot is not reachable from the root
+        assert_eq!(Directory::load(&fs, ROOT_INODE).unwrap().children.len(), 2);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+
+        Filesystem::write(&fs, "/docs/a", b"second", owner).unwrap();
+        Filesystem::write(&fs, "/c", b"new", owner).unwrap();
+        Filesystem::open_dir(&fs, "/docs")
+            .unwrap()
+            .remove_child(DirectoryChildIdentifier::Name("old"))
+            .unwrap();
+        let changes = Filesystem::diff(&fs, snapshot, ROOT_INODE).unwrap();
+        // Directories gaining or losing entries are not modified themselves
+        let expected = [
+            Change::Added("c".to_owned()),
+            Change::Modified("docs/a".to_owned()),
+            Change::Removed("docs/old".to_owned()),
+        ];
+        assert_eq!(changes, expected);
+        assert_eq!(changes[0].to_string(), "A /c");
+
+        let fs = reload(fs);
+        Filesystem::rollback(&fs, "before").unwrap();
+        assert_eq!(Filesystem::read(&fs, "/docs/a").unwrap(), b"first");
+        assert_eq!(Filesystem::read(&fs, "/b").unwrap(), [7; 3000]);
+        assert!(Filesystem::resolve(&fs, "/docs/old").is_ok());
+        assert!(matches!(
+            Filesystem::resolve(&fs, "/c"),
+            Err(Error::NotFound)
+        ));
+        let fs = reload(fs);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        // Snapshot took the replaced tree over, so rolling back again undoes the rollback
+        let snapshot = Filesystem::snapshot(&fs, "before").unwrap();
+        assert_eq!(
+            Filesystem::diff(&fs, ROOT_INODE, snapshot).unwrap().len(),
+            3
+        );
+        Filesystem::rollback(&fs, "before").unwrap();
+        assert_eq!(Filesystem::read(&fs, "/c").unwrap(), b"new");
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+
+        let free = fs.lock().unwrap().superblock.blocks_free;
+        Filesystem::delete_snapshot(&fs, "before").unwrap();
+        assert!(Filesystem::snapshots(&fs).unwrap().is_empty());
+        assert!(fs.lock().unwrap().superblock.blocks_free > free);
+        let fs = reload(fs);
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert_eq!(Filesystem::read(&fs, "/docs/a").unwrap(), b"second");
+
+        // Damaged directory of snapshots is dropped by repair
+        Filesystem::create_snapshot(&fs, "again").unwrap();
+        let file = Filesystem::resolve(&fs, "/c").unwrap();
+        fs.lock().unwrap().superblock.snapshot_inode = file;
+        assert!(!Filesystem::repair(&fs).unwrap().is_clean());
+        assert!(Filesystem::check(&fs).unwrap().is_clean());
+        assert!(Filesystem::snapshots(&fs).unwrap().is_empty());
+        assert_eq!(Filesystem::read(&fs, "/c").unwrap(), b"new");
+    }
+}
@@ -0,0 +1,232 @@
+//! Counters of operations, observed by operators of a long-running mount
+//!
+//! [Statistics] are counted by the filesystem as it serves reads and writes,
+//! allocates inodes and blocks and flushes, under the same lock as the rest
+//! of its state, while cache hits and misses are counted by the cache,
+//! errors by the health monitor and latencies of operations by the driver. A snapshot is read from the virtual file
+//! `/.tananfs/stats` inside the mount and, with `--metrics-listen`, from an
+//! HTTP endpoint, both in the text format of Prometheus.
+//!
+//! The control directory `/.tananfs` is answered by the driver without
+//! touching the device. It is not listed in the root directory and shadows
+//! an entry of the same name stored there.
+
+use std::fmt::Display;
+
+use fuser::{FileAttr, FileType};
+
+use super::{DirectoryEntry, Filesystem, Latencies, ROOT_INODE};
+use crate::filetypes::timestamp_now;
+
+/// Name of control directory in root directory
+pub const CONTROL_DIRECTORY: &str = ".tananfs";
+/// Name of file holding statistics in control directory
+pub const STATS_FILE: &str = "stats";
+/// Node ids of virtual files, above indices of any inode
+pub const CONTROL_INODE: u64 = u64::MAX - 1;
+pub const STATS_INODE: u64 = u64::MAX - 2;
+
+/// Counters since the filesystem was loaded
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Statistics {
+    /// Reads of regular files served
+    pub reads: u64,
+    pub bytes_read: u64,
+    /// Writes to regular files accepted
+    pub writes: u64,
+    pub bytes_written: u64,
+    /// Inodes and blocks found in cache
+    pub cache_hits: u64,
+    /// Inodes and blocks loaded from the device
+    pub cache_misses: u64,
+    pub inodes_allocated: u64,
+    pub blocks_allocated: u64,
+    /// Flushes of the filesystem to the device
+    pub flushes: u64,
+    /// Failed transfers and checksum mismatches
+    pub errors: u64,
+    /// Durations of FUSE operations
+    pub latencies: Latencies,
+}
+
+impl Statistics {
+    pub(super) fn count_read(&mut self, bytes: usize) {
+        self.reads += 1;
+        self.bytes_read += bytes as u64;
+    }
+
+    pub(super) fn count_write(&mut self, bytes: usize) {
+        self.writes += 1;
+        self.bytes_written += bytes as u64;
+    }
+
+    /// Name, description and value of every counter
+    fn counters(&self) -> [(&str, &str, u64); 10] {
+        [
+            ("reads", "Reads of regular files served", self.reads),
+            (
+                "read_bytes",
+                "Bytes read from regular files",
+                self.bytes_read,
+            ),
+            ("writes", "Writes to regular files accepted", self.writes),
+            (
+                "written_bytes",
+                "Bytes written to regular files",
+                self.bytes_written,
+            ),
+            (
+                "cache_hits",
+                "Inodes and blocks found in cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses",
+                "Inodes and blocks loaded from the device",
+                self.cache_misses,
+            ),
+            (
+                "inode_allocations",
+                "Inodes allocated",
+                self.inodes_allocated,
+            ),
+            (
+                "block_allocations",
+                "Blocks allocated",
+                self.blocks_allocated,
+            ),
+            ("flushes", "Flushes to the device", self.flushes),
+            (
+                "errors",
+                "Failed transfers and checksum mismatches",
+                self.errors,
+            ),
+        ]
+    }
+}
+
+/// Counters in the text exposition format of Prometheus
+impl Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, help, value) in self.counters() {
+            writeln!(f, "# HELP tananfs_{name}_total {help}")?;
+            writeln!(f, "# TYPE tananfs_{name}_total counter")?;
+            writeln!(f, "tananfs_{name}_total {value}")?;
+        }
+        write!(f, "{}", self.latencies)
+    }
+}
+
+impl Filesystem {
+    /// Snapshot of counters, including those of cache and health monitor
+    pub fn statistics(&self) -> Statistics {
+        Statistics {
+            cache_hits: self.cache.hits,
+            cache_misses: self.cache.misses,
+            errors: self.health.errors(),
+            ..self.statistics.clone()
+        }
+    }
+}
+
+/// Virtual node named `name` in directory `parent`
+pub(super) fn control_lookup(parent: u64, name: &str) -> Option<u64> {
+    match (parent, name) {
+        (ROOT_INODE, CONTROL_DIRECTORY) => Some(CONTROL_INODE),
+        (CONTROL_INODE, STATS_FILE) => Some(STATS_INODE),
+        _ => None,
+    }
+}
+
+/// Whether node `ino` is a virtual one
+pub(super) fn is_control(ino: u64) -> bool {
+    matches!(ino, CONTROL_INODE | STATS_INODE)
+}
+
+/// Entries of control directory
+pub(super) fn control_entries() -> Vec<DirectoryEntry> {
+    [
+        (CONTROL_INODE, FileType::Directory, "."),
+        (ROOT_INODE, FileType::Directory, ".."),
+        (STATS_INODE, FileType::RegularFile, STATS_FILE),
+    ]
+    .into_iter()
+    .map(|(inode, kind, name)| DirectoryEntry {
+        inode,
+        kind,
+        name: name.to_string(),
+    })
+    .collect()
+}
+
+/// Attributes of virtual node `ino`, readable by everyone and owned by root
+///
+/// Statistics change all the time, so their file is always empty and its
+/// reads are served without the page cache.
+pub(super) fn control_attrs(ino: u64, block_size: u32) -> FileAttr {
+    let now = std::time::UNIX_EPOCH + timestamp_now();
+    let (kind, perm, nlink) = match ino {
+        CONTROL_INODE => (FileType::Directory, 0o555, 2),
+        _ => (FileType::RegularFile, 0o444, 1),
+    };
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind,
+        perm,
+        nlink,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: block_size,
+        flags: 0,
+    }
+}
+
+/// Contents of statistics file
+pub(super) fn stats_contents(fs: &Filesystem) -> Vec<u8> {
+    fs.statistics().to_string().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{FileOperations, Filesystem, Owner, RegularFile, ROOT_INODE};
+
+    #[test]
+    fn count_operations() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let before = fs.lock().unwrap().statistics();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "file", 0o644, Owner::default()).unwrap();
+        file.write(0, &[1u8; 2000]).unwrap();
+        file.flush().unwrap();
+        let index = file.inode.index;
+        drop(file);
+        fs.lock().unwrap().force_flush().unwrap();
+        let mut buffer = Vec::new();
+        Filesystem::session(&fs)
+            .unwrap()
+            .read_file_into(index, 100, 1000, &mut buffer)
+            .unwrap();
+
+        let after = fs.lock().unwrap().statistics();
+        assert_eq!(after.inodes_allocated, before.inodes_allocated + 1);
+        assert!(after.blocks_allocated >= before.blocks_allocated + 4);
+        assert_eq!(after.flushes, before.flushes + 1);
+        assert_eq!((after.reads, after.bytes_read), (1, 1000));
+        assert!(after.cache_hits > 0);
+        assert_eq!(after.errors, 0);
+        let text = after.to_string();
+        assert!(text.contains("# TYPE tananfs_read_bytes_total counter\n"));
+        assert!(text.contains("\ntananfs_read_bytes_total 1000\n"));
+    }
+}
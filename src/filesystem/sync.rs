@@ -0,0 +1,149 @@
+//! Synchronous mounts, replying to changes only once the device stores them
+//!
+//! Changes are normally kept in cache and flushed periodically, so a crash
+//! loses those of the last flush interval. Every flush ends by waiting until
+//! the device stores written data, as `fdatasync` does for image files, and a
+//! [barrier](Filesystem::barrier) waits for metadata of the image as well. With
+//! `-o dirsync`, every change of a directory, such as creating, removing or
+//! renaming its entries, passes a barrier before the kernel is answered, and
+//! with `-o sync` every other change of files and their attributes as well.
+
+use super::{Filesystem, FuseFs};
+use crate::Error;
+
+/// Changes which pass a barrier before they are answered
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SyncMode {
+    /// None, flushing them periodically
+    #[default]
+    Async,
+    /// Changes of directories, with `dirsync`
+    DirSync,
+    /// All changes, with `sync`
+    Sync,
+}
+
+impl Filesystem {
+    /// Flush filesystem and wait until its device stores it durably
+    pub fn barrier(&mut self) -> Result<(), Error> {
+        self.force_flush()?;
+        if !self.read_only {
+            self.device.barrier()?;
+        }
+        Ok(())
+    }
+}
+
+impl FuseFs {
+    /// Pass a barrier before answering changes selected by `mode`
+    pub fn with_sync(mut self, mode: SyncMode) -> Self {
+        self.sync = mode;
+        self
+    }
+
+    /// Pass a barrier after a change if mount is synchronous for it, with
+    /// `directory` set for changes of directory entries
+    pub(crate) fn synchronous(&mut self, directory: bool) -> Result<(), Error> {
+        match self.sync {
+            SyncMode::Sync => {}
+            SyncMode::DirSync if directory => {}
+            _ => return Ok(()),
+        }
+        self.write_back_all()?;
+        self.fs_handle()?.barrier()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::SyncMode;
+    use crate::{BlockDevice, FileOperations, Filesystem, FuseFs, Owner, RegularFile, ROOT_INODE};
+
+    /// Memory device counting syncs and barriers
+    #[derive(Debug)]
+    struct Counting(Cursor<Vec<u8>>, Arc<[AtomicUsize; 2]>);
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Counting {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl BlockDevice for Counting {
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.1[0].fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn barrier(&mut self) -> std::io::Result<()> {
+            self.1[1].fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_synchronous_changes() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let flushes = || fs.lock().unwrap().statistics().flushes;
+
+        let mut fuse_fs = FuseFs::new(fs.clone()).with_sync(SyncMode::DirSync);
+        let before = flushes();
+        fuse_fs.synchronous(false).unwrap();
+        assert_eq!(flushes(), before);
+        fuse_fs.synchronous(true).unwrap();
+        assert_eq!(flushes(), before + 1);
+
+        let mut fuse_fs = fuse_fs.with_sync(SyncMode::Sync);
+        let file = RegularFile::new(&fs, ROOT_INODE, "file", 0o644, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        fuse_fs.write_file(file, 0, false, b"data").unwrap();
+        let before = flushes();
+        fuse_fs.synchronous(false).unwrap();
+        assert!(flushes() > before);
+        assert_eq!(fuse_fs.memory_usage().unwrap().delayed_writes, 0);
+    }
+
+    #[test]
+    fn sync_device_after_flush() {
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let dev = Counting(Cursor::new(vec![0u8; 1_000_000]), counts.clone());
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let syncs = counts[0].load(Ordering::Relaxed);
+        assert!(syncs > 0);
+        fs.lock().unwrap().force_flush().unwrap();
+        assert_eq!(counts[0].load(Ordering::Relaxed), syncs + 1);
+        // Journal orders its own writes with barriers
+        let barriers = counts[1].load(Ordering::Relaxed);
+        assert!(barriers > 0);
+        fs.lock().unwrap().barrier().unwrap();
+        assert_eq!(counts[0].load(Ordering::Relaxed), syncs + 2);
+        assert_eq!(counts[1].load(Ordering::Relaxed), barriers + 1);
+    }
+}
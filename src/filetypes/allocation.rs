@@ -0,0 +1,181 @@
+//! Rollback of inodes and blocks acquired by operations that may still fail
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error};
+
+use super::Owner;
+use crate::filesystem::LockFilesystem;
+use crate::{Error, Filesystem};
+
+/// Inode acquired for a file under construction
+///
+/// Released on drop unless [committed](Self::commit), so a failed creation does
+/// not leak entries of the inode table. The filesystem must not be locked when
+/// an uncommitted allocation is dropped. New files start without blocks, which
+/// are later acquired through a [BlockAllocation].
+#[derive(Debug)]
+pub(crate) struct InodeAllocation<'a> {
+    fs: &'a Arc<Mutex<Filesystem>>,
+    index: u64,
+    /// Owner charged for the inode
+    owner: Owner,
+    committed: bool,
+}
+
+impl<'a> InodeAllocation<'a> {
+    pub fn acquire(fs: &'a Arc<Mutex<Filesystem>>, owner: Owner) -> Result<Self, Error> {
+        let index = fs.lock_fs()?.acquire_inode(owner)?;
+        Ok(Self {
+            fs,
+            index,
+            owner,
+            committed: false,
+        })
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Keep the inode allocated once the file is fully created
+    pub fn commit(mut self) -> u64 {
+        self.committed = true;
+        self.index
+    }
+}
+
+impl Drop for InodeAllocation<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        debug!("Roll back allocation of inode {}", self.index);
+        if let Err(e) = self
+            .fs
+            .lock_fs()
+            .and_then(|mut fs_handle| fs_handle.release_inode(self.index, self.owner))
+        {
+            error!("Failed to release inode {}: {e}", self.index);
+        }
+    }
+}
+
+/// Blocks acquired for a file but not yet linked into it
+///
+/// Holds the locked filesystem while the blocks are prepared and releases them
+/// on drop unless [committed](Self::commit), so a failed write does not leak
+/// blocks or their quota charges.
+#[derive(Debug)]
+pub(crate) struct BlockAllocation<'a> {
+    fs: &'a mut Filesystem,
+    indices: Vec<u64>,
+    /// Owner charged for the blocks
+    owner: Owner,
+    committed: bool,
+}
+
+impl<'a> BlockAllocation<'a> {
+    /// Acquire `count` blocks, taking reserved ones as well for `metadata`
+    pub fn acquire(
+        fs: &'a mut Filesystem,
+        count: u64,
+        owner: Owner,
+        metadata: bool,
+    ) -> Result<Self, Error> {
+        let indices = match metadata {
+            true => fs.acquire_metadata_blocks(count, owner)?,
+            false => fs.acquire_blocks(count, owner)?,
+        };
+        Ok(Self {
+            fs,
+            indices,
+            owner,
+            committed: false,
+        })
+    }
+
+    pub fn indices(&self) -> &[u64] {
+        &self.indices
+    }
+
+    /// Keep the blocks allocated once they are linked into the file
+    pub fn commit(mut self) -> Vec<u64> {
+        self.committed = true;
+        std::mem::take(&mut self.indices)
+    }
+}
+
+impl Deref for BlockAllocation<'_> {
+    type Target = Filesystem;
+
+    fn deref(&self) -> &Filesystem {
+        self.fs
+    }
+}
+
+impl DerefMut for BlockAllocation<'_> {
+    fn deref_mut(&mut self) -> &mut Filesystem {
+        self.fs
+    }
+}
+
+impl Drop for BlockAllocation<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        for &index in self.indices.iter() {
+            debug!("Roll back allocation of block {index}");
+            if let Err(e) = self.fs.release_block(index, self.owner) {
+                error!("Failed to release block {index}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::{BlockAllocation, InodeAllocation};
+    use crate::filesystem::Filesystem;
+    use crate::filetypes::Owner;
+
+    #[test]
+    fn rollback_unless_committed() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        let inodes_free = { fs.lock().unwrap().superblock.inodes_free };
+        let index = InodeAllocation::acquire(&fs, Owner::default())
+            .unwrap()
+            .index();
+        assert!(!fs.lock().unwrap().inodes.get(index).unwrap());
+        assert_eq!({ fs.lock().unwrap().superblock.inodes_free }, inodes_free);
+        let index = InodeAllocation::acquire(&fs, Owner::default())
+            .unwrap()
+            .commit();
+        assert!(fs.lock().unwrap().inodes.get(index).unwrap());
+    }
+
+    #[test]
+    fn blocks_rollback_unless_committed() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let mut fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let blocks_free = fs.superblock.blocks_free;
+        let indices = BlockAllocation::acquire(&mut fs, 3, Owner::default(), false)
+            .unwrap()
+            .indices()
+            .to_vec();
+        assert_eq!(indices.len(), 3);
+        assert!(indices.iter().all(|&index| !fs.blocks.get(index).unwrap()));
+        assert_eq!({ fs.superblock.blocks_free }, blocks_free);
+        let indices = BlockAllocation::acquire(&mut fs, 2, Owner::default(), true)
+            .unwrap()
+            .commit();
+        assert!(indices.iter().all(|&index| fs.blocks.get(index).unwrap()));
+        assert_eq!({ fs.superblock.blocks_free }, blocks_free - 2);
+    }
+}
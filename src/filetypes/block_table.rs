@@ -0,0 +1,269 @@
+//! Tables of block indices for direct lookup of a file's n-th block
+//!
+//! Blocks of a file form a chain, each referencing the next one, so reaching
+//! the n-th block takes n block loads. Regular files on filesystems with
+//! [INCOMPAT_BLOCK_TABLES] additionally keep indices of their blocks in a
+//! single indirect table, holding the first blocks, and a double indirect
+//! table of such tables, holding the following ones. Blocks beyond both are
+//! reached by following the chain from the last block in tables.
+
+use fuser::FileType;
+
+use crate::{
+    structs::{Block, Inode, INCOMPAT_BLOCK_TABLES, NULL_BLOCK},
+    Error, Filesystem,
+};
+
+use super::{BlockTables, Owner, RawByteFile, BYTES_IN_U64};
+
+impl BlockTables {
+    /// Empty tables of a new file, if filesystem supports them
+    pub(crate) fn new(fs: &Filesystem) -> Option<Self> {
+        (fs.superblock.incompat_flags & INCOMPAT_BLOCK_TABLES != 0).then_some(Self {
+            single: NULL_BLOCK,
+            double: NULL_BLOCK,
+        })
+    }
+
+    /// Tables of file with given [Inode], kept in its metadata
+    pub(crate) fn load(fs: &Filesystem, inode: &Inode) -> Option<Self> {
+        if inode.r#type == FileType::Directory {
+            return None;
+        }
+        let metadata = inode.metadata;
+        Self::new(fs).map(|_| Self {
+            single: metadata[1],
+            double: metadata[2],
+        })
+    }
+
+    /// Update [Inode]'s table pointers
+    pub(crate) fn update_inode(&self, inode: &mut Inode) {
+        inode.metadata[1] = self.single;
+        inode.metadata[2] = self.double;
+    }
+
+    /// Indices of blocks holding the tables themselves
+    pub(crate) fn blocks(&self, fs: &mut Filesystem) -> Result<Vec<u64>, Error> {
+        let mut blocks = Vec::new();
+        if self.single != NULL_BLOCK {
+            blocks.push(self.single);
+        }
+        if self.double != NULL_BLOCK {
+            blocks.push(self.double);
+            let double = fs.load_block(self.double, false)?;
+            blocks.extend(
+                (0..entries(fs))
+                    .map(|slot| get_entry(&double, slot))
+                    .filter(|&table| table != NULL_BLOCK),
+            );
+        }
+        Ok(blocks)
+    }
+}
+
+/// Number of block indices in a single table
+fn entries(fs: &Filesystem) -> u64 {
+    fs.superblock.block_size as u64 / BYTES_IN_U64 as u64
+}
+
+/// Number of tables indexing a file of `blocks` blocks
+pub(super) fn table_count(fs: &Filesystem, blocks: u64) -> u64 {
+    let entries = entries(fs);
+    match blocks {
+        0 => 0,
+        _ if blocks <= entries => 1,
+        _ => 2 + (blocks - entries).min(entries * entries).div_ceil(entries),
+    }
+}
+
+fn get_entry(table: &Block, slot: u64) -> u64 {
+    let start = slot as usize * BYTES_IN_U64;
+    let mut raw = [0u8; BYTES_IN_U64];
+    raw.copy_from_slice(&table.data[start..start + BYTES_IN_U64]);
+    u64::from_le_bytes(raw)
+}
+
+fn set_entry(table: &mut Block, slot: u64, index: u64) {
+    let start = slot as usize * BYTES_IN_U64;
+    table.data[start..start + BYTES_IN_U64].copy_from_slice(&index.to_le_bytes());
+}
+
+/// Acquire a table with every entry set to [NULL_BLOCK], charged to `owner`
+fn new_table(fs: &mut Filesystem, owner: Owner) -> Result<u64, Error> {
+    let index = fs.acquire_block(owner)?;
+    let initialized = fs.load_block(index, true).and_then(|mut table| {
+        table.data.fill(0xFF);
+        fs.flush_block(&table)
+    });
+    if let Err(e) = initialized {
+        fs.release_block(index, owner)?;
+        return Err(e);
+    }
+    Ok(index)
+}
+
+impl RawByteFile {
+    /// Index of file's n-th block in tables, [NULL_BLOCK] if not recorded
+    pub(super) fn table_lookup(&self, fs: &mut Filesystem, position: u64) -> Result<u64, Error> {
+        let Some(tables) = self.tables else {
+            return Ok(NULL_BLOCK);
+        };
+        let entries = entries(fs);
+        let (table, slot) = if position < entries {
+            (tables.single, position)
+        } else if position - entries < entries * entries && tables.double != NULL_BLOCK {
+            let position = position - entries;
+            let double = fs.load_block(tables.double, false)?;
+            (get_entry(&double, position / entries), position % entries)
+        } else {
+            return Ok(NULL_BLOCK);
+        };
+        if table == NULL_BLOCK {
+            return Ok(NULL_BLOCK);
+        }
+        Ok(get_entry(&fs.load_block(table, false)?, slot))
+    }
+
+    /// Closest block at or before n-th one with a known index, as its position and index
+    pub(super) fn table_nearest(
+        &self,
+        fs: &mut Filesystem,
+        position: u64,
+    ) -> Result<(u64, u64), Error> {
+        let entries = entries(fs);
+        let position = position.min(entries + entries * entries - 1);
+        match self.table_lookup(fs, position)? {
+            NULL_BLOCK => Ok((0, self.first_block)),
+            index => Ok((position, index)),
+        }
+    }
+
+    /// Record index of file's n-th block, acquiring tables as needed
+    pub(super) fn table_store(
+        &mut self,
+        fs: &mut Filesystem,
+        position: u64,
+        index: u64,
+    ) -> Result<(), Error> {
+        let (entries, owner) = (entries(fs), self.owner);
+        let Some(tables) = self.tables.as_mut() else {
+            return Ok(());
+        };
+        let (table, slot) = if position < entries {
+            if tables.single == NULL_BLOCK {
+                tables.single = new_table(fs, owner)?;
+            }
+            (tables.single, position)
+        } else if position - entries < entries * entries {
+            let position = position - entries;
+            if tables.double == NULL_BLOCK {
+                tables.double = new_table(fs, owner)?;
+            }
+            let mut double = fs.load_block(tables.double, false)?;
+            let mut table = get_entry(&double, position / entries);
+            if table == NULL_BLOCK {
+                table = new_table(fs, owner)?;
+                set_entry(&mut double, position / entries, table);
+                fs.flush_block(&double)?;
+            }
+            (table, position % entries)
+        } else {
+            return Ok(());
+        };
+        let mut table = fs.load_block(table, false)?;
+        set_entry(&mut table, slot, index);
+        fs.flush_block(&table)
+    }
+
+    /// Release tables holding only blocks at or after n-th one
+    ///
+    /// Entries of remaining tables past the file's end are left as they are,
+    /// as they are overwritten when the file grows again.
+    pub(super) fn table_truncate(
+        &mut self,
+        fs: &mut Filesystem,
+        position: u64,
+    ) -> Result<(), Error> {
+        let (entries, owner) = (entries(fs), self.owner);
+        let Some(tables) = self.tables.as_mut() else {
+            return Ok(());
+        };
+        if tables.double != NULL_BLOCK {
+            let start = position.saturating_sub(entries);
+            let mut double = fs.load_block(tables.double, false)?;
+            for slot in start.div_ceil(entries)..entries {
+                let table = get_entry(&double, slot);
+                if table != NULL_BLOCK {
+                    fs.release_block(table, owner)?;
+                    set_entry(&mut double, slot, NULL_BLOCK);
+                }
+            }
+            if start == 0 {
+                fs.release_block(tables.double, owner)?;
+                tables.double = NULL_BLOCK;
+            } else {
+                fs.flush_block(&double)?;
+            }
+        }
+        if tables.single != NULL_BLOCK && position == 0 {
+            fs.release_block(tables.single, owner)?;
+            tables.single = NULL_BLOCK;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Seek, SeekFrom},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        filetypes::{Owner, RawByteFile},
+        structs::NULL_BLOCK,
+        Filesystem,
+    };
+
+    #[test]
+    fn lookup_and_release() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        let blocks_free = fs.lock().unwrap().superblock.blocks_free;
+        let mut file = RawByteFile::new_regular(&fs, Owner::default()).unwrap();
+        assert!(file.tables.is_some());
+        // Spans single indirect table and three tables of double indirect one
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        file.write(&data).unwrap();
+        let tables = file.tables.unwrap();
+        assert_ne!(tables.single, NULL_BLOCK);
+        assert_ne!(tables.double, NULL_BLOCK);
+        {
+            let mut fs_handle = fs.lock().unwrap();
+            for position in [0, 63, 64, 127, 128, file.block_count - 2] {
+                let index = file.table_lookup(&mut fs_handle, position).unwrap();
+                assert_ne!(index, NULL_BLOCK);
+                let walked = file.get_nth_block_locked(&mut fs_handle, position).unwrap();
+                assert_eq!(walked.index, index);
+            }
+            assert_eq!(
+                blocks_free - fs_handle.superblock.blocks_free,
+                file.block_count + 5
+            );
+        }
+        let mut buffer = vec![0u8; 1000];
+        file.seek(SeekFrom::Start(80_000)).unwrap();
+        file.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &data[80_000..81_000]);
+
+        file.shrink(30_000).unwrap();
+        assert_eq!(file.block_count, 60);
+        assert_eq!(file.tables.unwrap().double, NULL_BLOCK);
+        file.shrink(0).unwrap();
+        assert_eq!(file.tables.unwrap().single, NULL_BLOCK);
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+    }
+}
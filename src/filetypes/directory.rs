@@ -1,6 +1,9 @@
-use super::{helpers::*, DirectoryChildIdentifier, FileOperations, RawByteFile, RegularFile};
-use super::{Directory, DirectoryChild};
-use crate::filesystem::ROOT_INODE;
+use super::{
+    allocation::InodeAllocation, directory_index, helpers::*, DirectoryChildIdentifier,
+    FileOperations, Owner, RawByteFile, RegularFile, MAX_NAME_LENGTH,
+};
+use super::{Directory, DirectoryChild, NameLength, BYTES_IN_U64};
+use crate::filesystem::{LockFilesystem, ROOT_INODE};
 use crate::structs::{Inode, NULL_BLOCK};
 use crate::{Error, Filesystem};
 
@@ -9,6 +12,40 @@ use log::{debug, error};
 use std::sync::{Arc, Mutex};
 
 impl Directory {
+    /// Inode of parent directory, with root directory being its own parent
+    pub fn parent(&self) -> u64 {
+        match self.inode.index {
+            ROOT_INODE => ROOT_INODE,
+            _ => self.inode.metadata[0],
+        }
+    }
+
+    /// Resolve name to inode, including `.` and `..` entries
+    pub fn lookup(&self, name: &str) -> Result<u64, Error> {
+        match name {
+            "." => Ok(self.inode.index),
+            ".." => Ok(self.parent()),
+            _ => self.get_child_inode(DirectoryChildIdentifier::Name(name)),
+        }
+    }
+
+    /// Permission bits cleared from children created in this directory
+    pub fn mode_mask(&self) -> Option<u32> {
+        match self.inode.metadata[4] {
+            NULL_BLOCK => None,
+            mask => Some(mask as u32),
+        }
+    }
+
+    /// Set or clear mode mask, which new subdirectories inherit
+    pub fn set_mode_mask(&mut self, mask: Option<u32>) {
+        self.inode.metadata[4] = match mask {
+            Some(mask) => (mask & 0o7777) as u64,
+            None => NULL_BLOCK,
+        };
+        self.modified = true;
+    }
+
     pub fn get_child_inode(&self, child: DirectoryChildIdentifier) -> Result<u64, Error> {
         Ok(match child {
             DirectoryChildIdentifier::Name(name) => {
@@ -21,7 +58,27 @@ impl Directory {
         })
     }
 
+    /// Reject names exceeding limit of new directory entries
+    pub(super) fn validate_name(name: &str) -> Result<(), Error> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(Error::NameTooLong);
+        }
+        Ok(())
+    }
+
+    /// Type of child's inode
+    pub fn child_type(&self, child: DirectoryChildIdentifier) -> Result<FileType, Error> {
+        let child = self.get_child_inode(child)?;
+        Ok(self.file.filesystem.lock_fs()?.load_inode(child)?.r#type)
+    }
+
     pub fn add_child(&mut self, name: &str, inode: u64) -> Result<(), Error> {
+        Self::validate_name(name)?;
+        self.inode.check_appendable()?;
+        let limit = self.file.filesystem.lock_fs()?.limits.directory_entries;
+        if self.children.len() as u64 >= limit {
+            return Err(Error::TooManyLinks);
+        }
         self.modified = true;
         let index = self.inode.index;
         debug!(
@@ -33,6 +90,7 @@ impl Directory {
             name: name.to_owned(),
         };
         if !self.children.contains(&child) {
+            self.changed.push(child.name.clone());
             self.children.push(child);
             Ok(())
         } else {
@@ -40,6 +98,142 @@ impl Directory {
         }
     }
 
+    /// Count directories between root and directory with inode `index`
+    pub fn depth(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<u64, Error> {
+        let mut fs_handle = fs.lock_fs()?;
+        let limit = fs_handle.limits.path_depth;
+        let mut current = index;
+        let mut depth = 0;
+        while current != ROOT_INODE {
+            if depth > limit {
+                return Err(Error::NameTooLong);
+            }
+            current = fs_handle.load_inode(current)?.metadata[0];
+            depth += 1;
+        }
+        Ok(depth)
+    }
+
+    /// Check whether directory with inode `index` lies in the subtree of `ancestor`
+    fn is_ancestor(fs: &Arc<Mutex<Filesystem>>, ancestor: u64, index: u64) -> Result<bool, Error> {
+        let mut fs_handle = fs.lock_fs()?;
+        let limit = fs_handle.limits.path_depth;
+        let mut current = index;
+        let mut depth = 0;
+        while current != ROOT_INODE {
+            if current == ancestor {
+                return Ok(true);
+            }
+            if depth > limit {
+                return Err(Error::NameTooLong);
+            }
+            current = fs_handle.load_inode(current)?.metadata[0];
+            depth += 1;
+        }
+        Ok(ancestor == ROOT_INODE)
+    }
+
+    /// Fail unless no directory lies more than `height` levels below this one
+    fn check_height(&self, height: u64) -> Result<(), Error> {
+        if self.inode.metadata[3] == 0 {
+            return Ok(());
+        }
+        if height == 0 {
+            return Err(Error::NameTooLong);
+        }
+        let fs = &self.file.filesystem;
+        for child in &self.children {
+            if fs.lock_fs()?.load_inode(child.inode)?.r#type == FileType::Directory {
+                Directory::load(fs, child.inode)?.check_height(height - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create directory referring to `parent` without being linked into it,
+    /// reachable only through an inode recorded elsewhere
+    pub(crate) fn new_unlinked(
+        fs: &Arc<Mutex<Filesystem>>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<Self, Error> {
+        Self::create(fs, parent, name, mode, owner, false)
+    }
+
+    fn create(
+        fs: &Arc<Mutex<Filesystem>>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        owner: Owner,
+        linked: bool,
+    ) -> Result<Self, Error> {
+        let now = timestamp_now();
+        if Directory::depth(fs, parent)? >= fs.lock_fs()?.limits.path_depth {
+            return Err(Error::NameTooLong);
+        }
+        let children_count = 0u64;
+        let (mut mode, mut owner, mut mode_mask) = (mode, owner, None);
+        let root = parent == ROOT_INODE && !fs.lock_fs()?.inodes.get(ROOT_INODE)?;
+        let mut parent_dir = if root {
+            debug!("Root directory, skip adding to parent");
+            None
+        } else {
+            let parent_dir = Directory::load(fs, parent)?;
+            mode_mask = parent_dir.mode_mask();
+            mode &= !mode_mask.unwrap_or(0);
+            if parent_dir.inode.mode as u32 & libc::S_ISGID != 0 {
+                mode |= libc::S_ISGID;
+            }
+            owner = owner.inherit(&parent_dir.inode);
+            Some(parent_dir).filter(|_| linked)
+        };
+        let allocation = InodeAllocation::acquire(fs, owner)?;
+        let file = RawByteFile::new_directory(fs, owner)?;
+        let mut inode = Inode {
+            index: allocation.index(),
+            mode: mode as u16,
+            r#type: FileType::Directory,
+            size: 0,
+            uid: owner.uid,
+            gid: owner.gid,
+            dtime: u64::MAX,
+            block_count: 1,
+            metadata: [
+                parent,
+                children_count,
+                name.len() as u64,
+                0,
+                mode_mask.map_or(NULL_BLOCK, u64::from),
+            ],
+            checksum: 0,
+            __padding_1: Default::default(),
+            first_block: file.first_block,
+            last_block: file.last_block,
+            ..Default::default()
+        };
+        inode.set_created(now);
+        fs.lock_fs()?.flush_inode(&inode)?;
+        // Link into parent only once the inode is written
+        if let Some(parent_dir) = parent_dir.as_mut() {
+            parent_dir.add_child(name, inode.index)?;
+            parent_dir.count_subdirectory(FileType::Directory, true);
+        }
+        drop(parent_dir);
+        allocation.commit();
+        Ok(Self {
+            inode,
+            file,
+            name: name.to_owned(),
+            children: Vec::new(),
+            changed: Vec::new(),
+            modified: true,
+            removed: false,
+        })
+    }
+
     pub fn remove_empty(mut self) -> Result<(), Error> {
         let index = self.inode.index;
         debug!("Remove empty directory {} with inode {index}", self.name);
@@ -47,37 +241,190 @@ impl Directory {
             return Err(Error::DirectoryNotEmpty);
         }
         RawByteFile::remove(&self.file.filesystem, self.inode.index)?;
-        let mut fs_handle = self.file.filesystem.lock()?;
-        fs_handle.release_inode(self.inode.index)?;
+        let mut fs_handle = self.file.filesystem.lock_fs()?;
+        fs_handle.release_inode(self.inode.index, Owner::from(&self.inode))?;
         self.removed = true;
         Ok(())
     }
 
+    /// Move child under `new_name` in directory `new_parent`, replacing an existing entry
+    /// Returns inode of the replaced entry, which is detached but still allocated
     pub fn transfer_child(
         &mut self,
         child: DirectoryChildIdentifier,
         new_parent: u64,
         new_name: &str,
-    ) -> Result<(), Error> {
+    ) -> Result<Option<u64>, Error> {
+        Self::validate_name(new_name)?;
         let index = self.inode.index;
         let child = self.get_child_inode(child)?;
         debug!(
             "Transfer child with inode {child} from directory with inode {index} to {new_parent}"
         );
-        if new_parent == self.inode.index {
-            match self.children.iter_mut().find(|c| c.inode == child) {
-                Some(child) => child.name = new_name.into(),
-                None => unreachable!(),
+        let fs = self.file.filesystem.clone();
+        self.inode.check_modifiable()?;
+        fs.lock_fs()?.load_inode(child)?.check_modifiable()?;
+        if new_parent == index {
+            let replaced = self.replacement_target(new_name, child)?;
+            if replaced.is_some_and(|r| r.index == child) {
+                return Ok(None);
             }
-        } else {
-            let mut new_parent = Directory::load(&self.file.filesystem, new_parent)?;
-            new_parent.add_child(new_name, child)?;
-            self.children.retain(|c| c.inode != child);
+            // Rewrite both entries in a single directory flush
+            self.forget_names(child);
+            self.changed.push(new_name.to_owned());
+            self.children
+                .retain(|c| c.inode != child || c.name == new_name);
+            match self.children.iter_mut().find(|c| c.name == new_name) {
+                Some(entry) => entry.inode = child,
+                None => self.children.push(DirectoryChild {
+                    inode: child,
+                    name: new_name.into(),
+                }),
+            }
+            if let Some(replaced) = replaced {
+                self.count_subdirectory(replaced.r#type, false);
+            }
+            self.modified = true;
+            self.flush()?;
+            if let Some(replaced) = replaced {
+                Self::detach_inode(&fs, replaced.index)?;
+            }
+            return Ok(replaced.map(|r| r.index));
+        }
+        if Self::is_ancestor(&fs, child, new_parent)? {
+            return Err(Error::InvalidArgument);
+        }
+        let mut inode = fs.lock_fs()?.load_inode(child)?;
+        if inode.r#type == FileType::Directory {
+            // Moved subtree ends up as deep as directories created there would
+            let limit = fs.lock_fs()?.limits.path_depth;
+            let height = limit
+                .checked_sub(Self::depth(&fs, new_parent)? + 1)
+                .ok_or(Error::NameTooLong)?;
+            Directory::load(&fs, child)?.check_height(height)?;
+        }
+        let mut target = Directory::load(&fs, new_parent)?;
+        let replaced = target.replacement_target(new_name, child)?;
+        if replaced.is_some_and(|r| r.index == child) {
+            return Ok(None);
+        }
+        // Link into the new parent before unlinking from the old one, so an
+        // interrupted rename leaves the child reachable from at least one of them
+        match target.children.iter_mut().find(|c| c.name == new_name) {
+            Some(entry) => {
+                entry.inode = child;
+                target.changed.push(new_name.to_owned());
+                target.modified = true;
+            }
+            None => target.add_child(new_name, child)?,
         }
+        if let Some(replaced) = replaced {
+            target.count_subdirectory(replaced.r#type, false);
+        }
+        target.count_subdirectory(inode.r#type, true);
+        target.flush()?;
+        drop(target);
+        inode.metadata[0] = new_parent;
+        fs.lock_fs()?.flush_inode(&inode)?;
+        self.forget_names(child);
+        self.children.retain(|c| c.inode != child);
+        self.count_subdirectory(inode.r#type, false);
         self.modified = true;
+        self.flush()?;
+        if let Some(replaced) = replaced {
+            Self::detach_inode(&fs, replaced.index)?;
+        }
+        Ok(replaced.map(|r| r.index))
+    }
+
+    /// Inode currently named `name`, checking that `child` may replace it
+    fn replacement_target(&self, name: &str, child: u64) -> Result<Option<Inode>, Error> {
+        let Some(existing) = self.children.iter().find(|c| c.name == name) else {
+            return Ok(None);
+        };
+        let mut fs_handle = self.file.filesystem.lock_fs()?;
+        let target = fs_handle.load_inode(existing.inode)?;
+        if existing.inode == child {
+            return Ok(Some(target));
+        }
+        // Replacing an entry unlinks the inode it referred to
+        self.inode.check_modifiable()?;
+        target.check_modifiable()?;
+        let source = fs_handle.load_inode(child)?;
+        match (source.r#type, target.r#type) {
+            (FileType::Directory, FileType::Directory) if target.metadata[1] != 0 => {
+                return Err(Error::DirectoryNotEmpty)
+            }
+            (FileType::Directory, FileType::Directory) => {}
+            (_, FileType::Directory) => return Err(Error::IsDirectory),
+            (FileType::Directory, _) => return Err(Error::NotDirectory),
+            _ => {}
+        }
+        Ok(Some(target))
+    }
+
+    /// Write name and entries into an empty file
+    fn write_body(&mut self) -> Result<(), Error> {
+        let width = NameLength::of(&*self.file.filesystem.lock_fs()?);
+        self.file.write(self.name.as_bytes())?;
+        if self.indexed()? {
+            self.flush_indexed(width)?;
+        } else {
+            for child in self.children.iter() {
+                child.flush(&mut self.file, width)?;
+            }
+        }
         Ok(())
     }
 
+    /// Keep count of child directories in inode metadata after linking or unlinking a child
+    fn count_subdirectory(&mut self, kind: FileType, linked: bool) {
+        if kind != FileType::Directory {
+            return;
+        }
+        let count = self.inode.metadata[3];
+        self.inode.metadata[3] = if linked {
+            count + 1
+        } else {
+            count.saturating_sub(1)
+        };
+        self.modified = true;
+    }
+
+    /// Remember names of child with inode `index` as changed, before its entries are removed
+    fn forget_names(&mut self, index: u64) {
+        let names = self.children.iter().filter(|c| c.inode == index);
+        self.changed.extend(names.map(|c| c.name.clone()));
+    }
+
+    /// Mark inode as deleted while keeping it and its data allocated
+    fn detach_inode(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<Inode, Error> {
+        let mut fs_handle = fs.lock_fs()?;
+        let mut inode = fs_handle.load_inode(index)?;
+        inode.dtime = timestamp_now().as_secs();
+        fs_handle.flush_inode(&inode)?;
+        Ok(inode)
+    }
+
+    /// Remove child's entry while keeping its inode and data allocated
+    pub fn detach_child(&mut self, child: DirectoryChildIdentifier) -> Result<u64, Error> {
+        let index = self.inode.index;
+        let child = self.get_child_inode(child)?;
+        debug!("Detach child with inode {child} from directory with inode {index}");
+        self.inode.check_modifiable()?;
+        self.file
+            .filesystem
+            .lock_fs()?
+            .load_inode(child)?
+            .check_modifiable()?;
+        let inode = Self::detach_inode(&self.file.filesystem, child)?;
+        self.forget_names(child);
+        self.children.retain(|c| c.inode != child);
+        self.count_subdirectory(inode.r#type, false);
+        self.modified = true;
+        Ok(child)
+    }
+
     pub fn remove_child(&mut self, child: DirectoryChildIdentifier) -> Result<(), Error> {
         self.modified = true;
         let index = self.inode.index;
@@ -86,7 +433,9 @@ impl Directory {
             "Remove child with inode {index} from directory {} with inode {index}",
             self.name
         );
-        let inode = self.file.filesystem.lock()?.load_inode(child)?;
+        self.inode.check_modifiable()?;
+        let inode = self.file.filesystem.lock_fs()?.load_inode(child)?;
+        inode.check_modifiable()?;
         match inode.r#type {
             FileType::RegularFile => {
                 RegularFile::load(&self.file.filesystem, inode.index)?.remove()?;
@@ -94,96 +443,101 @@ impl Directory {
             FileType::Directory => {
                 Directory::load(&self.file.filesystem, inode.index)?.remove_empty()?;
             }
-            _ => return Err(Error::NullBlock),
+            other => {
+                error!("Inode {} has unsupported type {other:?}", { inode.index });
+                return Err(Error::Corruption);
+            }
         }
+        self.forget_names(inode.index);
         self.children.retain(|c| c.inode != inode.index);
+        self.count_subdirectory(inode.r#type, false);
         Ok(())
     }
 }
 
 impl FileOperations for Directory {
-    fn new(fs: &Arc<Mutex<Filesystem>>, parent: u64, name: &str, mode: u32) -> Result<Self, Error> {
-        let now = timestamp_now();
-        let inode = fs.lock()?.acquire_inode()?;
-        let children_count = 0u64;
-        let file = RawByteFile::new(fs)?;
-        if parent == ROOT_INODE && inode == ROOT_INODE {
-            debug!("Root directory, skip adding to parent");
-        } else {
-            Directory::load(fs, parent)?.add_child(name, inode)?;
-        }
-        let inode = Inode {
-            index: inode,
-            mode: mode as u16,
-            r#type: FileType::Directory,
-            size: 0,
-            uid: 0,
-            gid: 0,
-            atime: now,
-            ctime: now,
-            mtime: now,
-            dtime: u64::MAX,
-            block_count: 1,
-            metadata: [
-                parent,
-                children_count,
-                name.as_bytes().len() as u64,
-                NULL_BLOCK,
-                NULL_BLOCK,
-            ],
-            __padding_1: Default::default(),
-            first_block: file.first_block,
-            last_block: file.last_block,
-        };
-        fs.lock()?.flush_inode(&inode)?;
-        Ok(Self {
-            inode,
-            file,
-            name: name.to_owned(),
-            children: Vec::new(),
-            modified: true,
-            removed: false,
-        })
+    fn new(
+        fs: &Arc<Mutex<Filesystem>>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<Self, Error> {
+        Self::create(fs, parent, name, mode, owner, true)
     }
 
     fn load(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<Self, Error> {
         debug!("Load directory with inode {index}");
-        let mut fs_handle = fs.lock()?;
+        let mut fs_handle = fs.lock_fs()?;
         let inode = fs_handle.load_inode(index)?;
+        if inode.r#type != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
         let children_count = inode.metadata[1];
         let name_len = inode.metadata[2] as usize;
+        let width = NameLength::of(&fs_handle);
         drop(fs_handle);
         let mut file = RawByteFile::load(fs, inode)?;
+        // Count is trusted only as far as the body could hold that many
+        // entries, while directories above the limit of new entries still load
+        if children_count > file.size / (BYTES_IN_U64 + width.size()) as u64 {
+            error!("Directory {index} claims more children than it holds");
+            return Err(Error::Corruption);
+        }
         let name = read_string(&mut file, name_len)?;
+        directory_index::skip(&mut file, children_count)?;
         let mut children = Vec::<DirectoryChild>::with_capacity(children_count as usize);
         for _ in 0..children_count {
-            children.push(DirectoryChild::read(&mut file)?);
+            children.push(DirectoryChild::read(&mut file, width)?);
         }
-        Ok(Self {
+        let mut directory = Self {
             inode,
             file,
             name,
             children,
+            changed: Vec::new(),
             modified: false,
             removed: false,
-        })
+        };
+        // Images formatted before subdirectories were counted
+        if inode.metadata[3] == NULL_BLOCK {
+            let mut fs_handle = fs.lock_fs()?;
+            let mut count = 0;
+            for child in &directory.children {
+                if fs_handle.load_inode(child.inode)?.r#type == FileType::Directory {
+                    count += 1;
+                }
+            }
+            directory.inode.metadata[3] = count;
+            directory.modified = true;
+        }
+        Ok(directory)
     }
 
     fn flush(&mut self) -> Result<(), Error> {
         let index = self.inode.index;
         debug!("Flush directory {} with inode {index}", self.name);
-        self.file.cursor.reset();
-        self.file.write(self.name.as_bytes())?;
-        for child in self.children.iter() {
-            child.flush(&mut self.file)?;
+        let fs = self.file.filesystem.clone();
+        // Write into new blocks, keeping the old body intact until inode refers to them
+        let new = RawByteFile::new_directory(&fs, self.file.owner)?;
+        let old = std::mem::replace(&mut self.file, new);
+        if let Err(e) = self.write_body() {
+            let new = std::mem::replace(&mut self.file, old);
+            new.release_locked(&mut *fs.lock_fs()?)?;
+            return Err(e);
         }
         self.file.update_inode(&mut self.inode);
-        self.inode.mtime = timestamp_now();
+        self.inode.set_mtime(timestamp_now());
         self.inode.block_count = self.file.block_count;
         self.inode.size = self.file.cursor.position();
         self.inode.metadata[1] = self.children.len() as u64;
-        self.inode.metadata[2] = self.name.as_bytes().len() as u64;
-        self.file.filesystem.lock()?.flush_inode(&self.inode)?;
+        self.inode.metadata[2] = self.name.len() as u64;
+        let mut fs_handle = fs.lock_fs()?;
+        fs_handle.flush_inode(&self.inode)?;
+        for name in self.changed.drain(..) {
+            fs_handle.invalidate_entry(index, &name);
+        }
+        old.release_locked(&mut fs_handle)?;
         self.modified = false;
         Ok(())
     }
@@ -191,24 +545,44 @@ impl FileOperations for Directory {
     fn remove(mut self) -> Result<(), Error> {
         let index = self.inode.index;
         debug!(
-            "Recursively remove directory {} with inode {index}",
+            "Remove directory {} with inode {index} and its subtree",
             self.name
         );
-        self.modified = true;
-        let mut fs_handle = self.file.filesystem.lock()?;
-        for child in &self.children {
-            let inode = fs_handle.load_inode(child.inode)?;
+        let fs = self.file.filesystem.clone();
+        // Directories along the current path with their children left to remove
+        let mut worklist = vec![(
+            index,
+            self.children.iter().map(|c| c.inode).collect::<Vec<_>>(),
+        )];
+        while let Some((directory, pending)) = worklist.last_mut() {
+            let directory = *directory;
+            let Some(child) = pending.pop() else {
+                worklist.pop();
+                if directory != index {
+                    let mut dir = Directory::load(&fs, directory)?;
+                    dir.children.clear();
+                    dir.remove_empty()?;
+                }
+                continue;
+            };
+            let inode = fs.lock_fs()?.load_inode(child)?;
             match inode.r#type {
                 FileType::RegularFile => {
-                    RegularFile::load(&self.file.filesystem, inode.index)?.remove()?;
+                    RegularFile::load(&fs, child)?.remove()?;
                 }
                 FileType::Directory => {
-                    Directory::load(&self.file.filesystem, inode.index)?.remove()?;
+                    let dir = Directory::load(&fs, child)?;
+                    let children: Vec<u64> = dir.children.iter().map(|c| c.inode).collect();
+                    drop(dir);
+                    worklist.push((child, children));
+                }
+                other => {
+                    error!("Inode {child} has unsupported type {other:?}");
+                    return Err(Error::Corruption);
                 }
-                _ => unreachable!(),
             }
         }
-        drop(fs_handle);
+        self.children.clear();
         self.remove_empty()?;
         Ok(())
     }
@@ -226,3 +600,472 @@ impl Drop for Directory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile, MAX_NAME_LENGTH,
+    };
+    use crate::{
+        error::Error,
+        filesystem::{Filesystem, ROOT_INODE},
+        structs::{FLAG_APPEND_ONLY, FLAG_IMMUTABLE},
+    };
+    use fuser::FileType;
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    fn filesystem() -> Arc<Mutex<Filesystem>> {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        fs
+    }
+
+    #[test]
+    fn directory_entries_limit() {
+        let fs = filesystem();
+        fs.lock().unwrap().limits.directory_entries = 3;
+        for name in ["a", "b", "c"] {
+            assert!(Directory::new(&fs, ROOT_INODE, name, 0o750, Owner::default()).is_ok());
+        }
+        assert!(matches!(
+            Directory::new(&fs, ROOT_INODE, "d", 0o750, Owner::default()),
+            Err(Error::TooManyLinks)
+        ));
+        // Lowering the limit leaves larger directories readable and removable
+        fs.lock().unwrap().limits.directory_entries = 1;
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len(), 3);
+        root.remove_child(DirectoryChildIdentifier::Name("a"))
+            .unwrap();
+        assert!(matches!(root.add_child("e", 100), Err(Error::TooManyLinks)));
+    }
+
+    #[test]
+    fn path_depth_limit() {
+        let fs = filesystem();
+        fs.lock().unwrap().limits.path_depth = 4;
+        let mut parent = ROOT_INODE;
+        for depth in 1..=4 {
+            let dir = Directory::new(&fs, parent, "nested", 0o750, Owner::default()).unwrap();
+            parent = dir.inode.index;
+            drop(dir);
+            assert_eq!(Directory::depth(&fs, parent).unwrap(), depth);
+        }
+        assert!(matches!(
+            Directory::new(&fs, parent, "nested", 0o750, Owner::default()),
+            Err(Error::NameTooLong)
+        ));
+        // Moved directories keep their subtree within the limit as well
+        let branch = Directory::new(&fs, ROOT_INODE, "branch", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        Directory::new(&fs, branch, "leaf", 0o750, Owner::default()).unwrap();
+        let third = Directory::load(&fs, parent).unwrap().parent();
+        let second = Directory::load(&fs, third).unwrap().parent();
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        for target in [parent, third] {
+            assert!(matches!(
+                root.transfer_child(DirectoryChildIdentifier::Name("branch"), target, "branch"),
+                Err(Error::NameTooLong)
+            ));
+        }
+        root.transfer_child(DirectoryChildIdentifier::Name("branch"), second, "branch")
+            .unwrap();
+        assert_eq!(Directory::depth(&fs, branch).unwrap(), 3);
+    }
+
+    #[test]
+    fn setgid_directory_inheritance() {
+        let fs = filesystem();
+        let staff = Owner { uid: 1000, gid: 50 };
+        let user = Owner {
+            uid: 1001,
+            gid: 1001,
+        };
+        let shared = Directory::new(&fs, ROOT_INODE, "shared", 0o2770, staff)
+            .unwrap()
+            .inode
+            .index;
+        let file = RegularFile::new(&fs, shared, "file", 0o640, user).unwrap();
+        assert_eq!(({ file.inode.uid }, { file.inode.gid }), (1001, 50));
+        let dir = Directory::new(&fs, shared, "dir", 0o750, user).unwrap();
+        assert_eq!(({ dir.inode.uid }, { dir.inode.gid }), (1001, 50));
+        assert_eq!({ dir.inode.mode }, 0o2750);
+        let plain = Directory::new(&fs, ROOT_INODE, "plain", 0o750, staff)
+            .unwrap()
+            .inode
+            .index;
+        let file = RegularFile::new(&fs, plain, "file", 0o640, user).unwrap();
+        assert_eq!(({ file.inode.uid }, { file.inode.gid }), (1001, 1001));
+    }
+
+    #[test]
+    fn mode_mask_inheritance() {
+        let fs = filesystem();
+        let project = {
+            let mut dir =
+                Directory::new(&fs, ROOT_INODE, "project", 0o775, Owner::default()).unwrap();
+            dir.set_mode_mask(Some(0o007));
+            dir.inode.index
+        };
+        let file = RegularFile::new(&fs, project, "file", 0o666, Owner::default()).unwrap();
+        assert_eq!({ file.inode.mode }, 0o660);
+        let dir = Directory::new(&fs, project, "dir", 0o777, Owner::default()).unwrap();
+        assert_eq!({ dir.inode.mode }, 0o770);
+        assert_eq!(dir.mode_mask(), Some(0o007));
+        let plain = Directory::new(&fs, ROOT_INODE, "plain", 0o777, Owner::default()).unwrap();
+        assert_eq!(({ plain.inode.mode }, plain.mode_mask()), (0o777, None));
+    }
+
+    #[test]
+    fn type_and_name_errors() {
+        let fs = filesystem();
+        let file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        assert!(matches!(
+            Directory::load(&fs, file),
+            Err(Error::NotDirectory)
+        ));
+        assert!(matches!(
+            RegularFile::load(&fs, ROOT_INODE),
+            Err(Error::IsDirectory)
+        ));
+        let longest = "x".repeat(MAX_NAME_LENGTH);
+        assert!(RegularFile::new(&fs, ROOT_INODE, &longest, 0o640, Owner::default()).is_ok());
+        let long = "x".repeat(MAX_NAME_LENGTH + 1);
+        assert!(matches!(
+            RegularFile::new(&fs, ROOT_INODE, &long, 0o640, Owner::default()),
+            Err(Error::NameTooLong)
+        ));
+        assert!(matches!(
+            Directory::find(&fs, ROOT_INODE, &long),
+            Err(Error::NameTooLong)
+        ));
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert!(matches!(
+            root.transfer_child(DirectoryChildIdentifier::Name("file"), ROOT_INODE, &long),
+            Err(Error::NameTooLong)
+        ));
+        assert_eq!(
+            root.child_type(DirectoryChildIdentifier::Name("file"))
+                .unwrap(),
+            FileType::RegularFile
+        );
+    }
+
+    #[test]
+    fn failed_creation_rollback() {
+        let fs = filesystem();
+        let file = RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let inodes_free = { fs.lock().unwrap().superblock.inodes_free };
+        assert!(matches!(
+            RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        assert!(matches!(
+            Directory::new(&fs, ROOT_INODE, "file", 0o750, Owner::default()),
+            Err(Error::NameOrInodeDuplicate)
+        ));
+        assert!(matches!(
+            RegularFile::new(&fs, file, "nested", 0o640, Owner::default()),
+            Err(Error::NotDirectory)
+        ));
+        assert_eq!({ fs.lock().unwrap().superblock.inodes_free }, inodes_free);
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len(), 1);
+    }
+
+    #[test]
+    fn immutable_and_append_only_flags() {
+        let fs = filesystem();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "log", 0o640, Owner::default()).unwrap();
+        file.write(0, b"first\n").unwrap();
+        file.inode.flags = FLAG_APPEND_ONLY;
+        file.write(6, b"second\n").unwrap();
+        assert!(matches!(file.write(0, b"x"), Err(Error::NotPermitted)));
+        file.inode.flags = FLAG_IMMUTABLE;
+        assert!(matches!(file.write(13, b"x"), Err(Error::NotPermitted)));
+        let index = file.inode.index;
+        drop(file);
+
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let renamed = root.transfer_child(DirectoryChildIdentifier::Name("log"), ROOT_INODE, "old");
+        assert!(matches!(renamed, Err(Error::NotPermitted)));
+        let removed = root.remove_child(DirectoryChildIdentifier::Name("log"));
+        assert!(matches!(removed, Err(Error::NotPermitted)));
+        assert_eq!(
+            root.get_child_inode(DirectoryChildIdentifier::Name("log"))
+                .unwrap(),
+            index
+        );
+
+        // Entries may be added to append-only directories, but not removed
+        root.inode.flags = FLAG_APPEND_ONLY;
+        drop(root);
+        RegularFile::new(&fs, ROOT_INODE, "new", 0o640, Owner::default()).unwrap();
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let removed = root.remove_child(DirectoryChildIdentifier::Name("new"));
+        assert!(matches!(removed, Err(Error::NotPermitted)));
+        root.inode.flags = FLAG_IMMUTABLE;
+        drop(root);
+        let created = RegularFile::new(&fs, ROOT_INODE, "other", 0o640, Owner::default());
+        assert!(matches!(created, Err(Error::NotPermitted)));
+    }
+
+    #[test]
+    fn rewrite_into_new_blocks() {
+        let fs = filesystem();
+        RegularFile::new(&fs, ROOT_INODE, "file", 0o640, Owner::default()).unwrap();
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let (first_block, blocks_free) = (root.file.first_block, {
+            fs.lock().unwrap().superblock.blocks_free
+        });
+        root.modified = true;
+        root.flush().unwrap();
+        assert_ne!(root.file.first_block, first_block);
+        assert!(!fs.lock().unwrap().blocks.get(first_block).unwrap());
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+
+        // Failed rewrite keeps previous body
+        let mut taken = Vec::new();
+        while let Ok(block) = fs.lock().unwrap().acquire_block(Owner::default()) {
+            taken.push(block);
+        }
+        root.add_child("other", 42).unwrap();
+        assert!(matches!(root.flush(), Err(Error::OutOfMemory)));
+        root.modified = false;
+        drop(root);
+        for block in taken {
+            fs.lock()
+                .unwrap()
+                .release_block(block, Owner::default())
+                .unwrap();
+        }
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(root.children[0].name, "file");
+    }
+
+    #[test]
+    fn rename_replaces_existing_entry() {
+        let fs = filesystem();
+        let a = RegularFile::new(&fs, ROOT_INODE, "a", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let b = RegularFile::new(&fs, ROOT_INODE, "b", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let replaced = root
+            .transfer_child(DirectoryChildIdentifier::Name("a"), ROOT_INODE, "b")
+            .unwrap();
+        assert_eq!(replaced, Some(b));
+        assert_eq!(root.children.len(), 1);
+        assert_eq!(
+            root.get_child_inode(DirectoryChildIdentifier::Name("b"))
+                .unwrap(),
+            a
+        );
+        drop(root);
+        let dtime = fs.lock().unwrap().load_inode(b).unwrap().dtime;
+        assert_ne!(dtime, u64::MAX);
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(root.children.len(), 1);
+    }
+
+    #[test]
+    fn rename_into_own_subtree() {
+        let fs = filesystem();
+        let mkdir = |parent, name| {
+            Directory::new(&fs, parent, name, 0o750, Owner::default())
+                .unwrap()
+                .inode
+                .index
+        };
+        let outer = mkdir(ROOT_INODE, "outer");
+        let inner = mkdir(outer, "inner");
+        let deepest = mkdir(inner, "deepest");
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let name = DirectoryChildIdentifier::Name("outer");
+        for target in [outer, inner, deepest] {
+            assert!(matches!(
+                root.transfer_child(name.clone(), target, "moved"),
+                Err(Error::InvalidArgument)
+            ));
+        }
+        drop(root);
+        let mut dir = Directory::load(&fs, inner).unwrap();
+        dir.transfer_child(DirectoryChildIdentifier::Name("deepest"), ROOT_INODE, "up")
+            .unwrap();
+        drop(dir);
+        assert_eq!(
+            { Directory::load(&fs, deepest).unwrap().inode.metadata[0] },
+            ROOT_INODE
+        );
+    }
+
+    #[test]
+    fn rename_across_directories() {
+        let fs = filesystem();
+        let mkdir = |parent, name| {
+            Directory::new(&fs, parent, name, 0o750, Owner::default())
+                .unwrap()
+                .inode
+                .index
+        };
+        let source = mkdir(ROOT_INODE, "source");
+        let target = mkdir(ROOT_INODE, "target");
+        let moved = mkdir(source, "moved");
+        let empty = mkdir(target, "empty");
+        let full = mkdir(target, "full");
+        mkdir(full, "child");
+        RegularFile::new(&fs, target, "file", 0o640, Owner::default()).unwrap();
+        let mut dir = Directory::load(&fs, source).unwrap();
+        let name = DirectoryChildIdentifier::Name("moved");
+        assert!(matches!(
+            dir.transfer_child(name.clone(), target, "full"),
+            Err(Error::DirectoryNotEmpty)
+        ));
+        assert!(matches!(
+            dir.transfer_child(name.clone(), target, "file"),
+            Err(Error::NotDirectory)
+        ));
+        assert_eq!(
+            dir.transfer_child(name, target, "empty").unwrap(),
+            Some(empty)
+        );
+        assert!(dir.children.is_empty());
+        drop(dir);
+        let target_dir = Directory::load(&fs, target).unwrap();
+        assert_eq!(target_dir.children.len(), 3);
+        assert_eq!(
+            target_dir
+                .get_child_inode(DirectoryChildIdentifier::Name("empty"))
+                .unwrap(),
+            moved
+        );
+        assert_eq!(
+            { Directory::load(&fs, moved).unwrap().inode.metadata[0] },
+            target
+        );
+        let file = RegularFile::new(&fs, source, "file", 0o640, Owner::default()).unwrap();
+        drop(file);
+        let mut dir = Directory::load(&fs, source).unwrap();
+        assert!(matches!(
+            dir.transfer_child(DirectoryChildIdentifier::Name("file"), target, "empty"),
+            Err(Error::IsDirectory)
+        ));
+    }
+
+    #[test]
+    fn dot_entries_lookup() {
+        let fs = filesystem();
+        let top = Directory::new(&fs, ROOT_INODE, "top", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let nested = Directory::new(&fs, top, "nested", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert_eq!(
+            (root.lookup(".").unwrap(), root.lookup("..").unwrap()),
+            (1, 1)
+        );
+        assert_eq!(root.lookup("top").unwrap(), top);
+        drop(root);
+        let dir = Directory::load(&fs, top).unwrap();
+        assert_eq!(dir.lookup("..").unwrap(), ROOT_INODE);
+        drop(dir);
+        let dir = Directory::load(&fs, nested).unwrap();
+        assert_eq!(
+            (dir.lookup(".").unwrap(), dir.lookup("..").unwrap()),
+            (nested, top)
+        );
+        assert!(matches!(dir.lookup("missing"), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn subdirectory_link_counts() {
+        let fs = filesystem();
+        let nlink = |index| {
+            let mut fs_handle = fs.lock().unwrap();
+            let inode = fs_handle.load_inode(index).unwrap();
+            inode.attrs(&fs_handle.superblock).nlink
+        };
+        let mkdir = |parent, name| {
+            Directory::new(&fs, parent, name, 0o750, Owner::default())
+                .unwrap()
+                .inode
+                .index
+        };
+        let a = mkdir(ROOT_INODE, "a");
+        let b = mkdir(ROOT_INODE, "b");
+        mkdir(a, "nested");
+        let file = RegularFile::new(&fs, a, "file", 0o640, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        assert_eq!((nlink(ROOT_INODE), nlink(a), nlink(b)), (4, 3, 2));
+        assert_eq!(nlink(file), 1);
+        let mut dir = Directory::load(&fs, a).unwrap();
+        dir.transfer_child(DirectoryChildIdentifier::Name("nested"), b, "nested")
+            .unwrap();
+        drop(dir);
+        assert_eq!((nlink(a), nlink(b)), (2, 3));
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        root.remove_child(DirectoryChildIdentifier::Name("a"))
+            .unwrap_err();
+        let mut dir = Directory::load(&fs, a).unwrap();
+        dir.remove_child(DirectoryChildIdentifier::Inode(file))
+            .unwrap();
+        drop(dir);
+        root.remove_child(DirectoryChildIdentifier::Name("a"))
+            .unwrap();
+        drop(root);
+        assert_eq!(nlink(ROOT_INODE), 3);
+    }
+
+    #[test]
+    fn remove_deep_subtree() {
+        let fs = filesystem();
+        let (inodes_free, blocks_free) = {
+            let fs_handle = fs.lock().unwrap();
+            (
+                fs_handle.superblock.inodes_free,
+                fs_handle.superblock.blocks_free,
+            )
+        };
+        let top = Directory::new(&fs, ROOT_INODE, "top", 0o750, Owner::default())
+            .unwrap()
+            .inode
+            .index;
+        let mut parent = top;
+        for _ in 0..64 {
+            RegularFile::new(&fs, parent, "file", 0o640, Owner::default()).unwrap();
+            parent = Directory::new(&fs, parent, "nested", 0o750, Owner::default())
+                .unwrap()
+                .inode
+                .index;
+        }
+        Directory::load(&fs, top).unwrap().remove().unwrap();
+        let fs_handle = fs.lock().unwrap();
+        assert_eq!({ fs_handle.superblock.inodes_free }, inodes_free);
+        assert_eq!({ fs_handle.superblock.blocks_free }, blocks_free);
+    }
+}
@@ -1,48 +1,99 @@
 use crate::{
-    filetypes::{helpers::*, BYTES_IN_U16, BYTES_IN_U64},
-    Error,
+    filetypes::{helpers::*, BYTES_IN_U16, BYTES_IN_U32, BYTES_IN_U64},
+    structs::INCOMPAT_WIDE_NAMES,
+    Error, Filesystem,
 };
 
-use super::{DirectoryChild, RawByteFile};
+use super::{DirectoryChild, NameLength, RawByteFile, MAX_NAME_LENGTH};
+
+impl NameLength {
+    /// Encoding of name lengths in directory entries of filesystem
+    pub(crate) fn of(fs: &Filesystem) -> Self {
+        match fs.superblock.incompat_flags & INCOMPAT_WIDE_NAMES {
+            0 => Self::Narrow,
+            _ => Self::Wide,
+        }
+    }
+
+    /// Bytes of encoded length
+    pub(crate) fn size(self) -> usize {
+        match self {
+            Self::Narrow => BYTES_IN_U16,
+            Self::Wide => BYTES_IN_U32,
+        }
+    }
+
+    /// Longest name which may be read from an entry, as older filesystems
+    /// accepted names up to the limit of their length field
+    fn limit(self) -> usize {
+        match self {
+            Self::Narrow => u16::MAX as usize,
+            Self::Wide => MAX_NAME_LENGTH,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<usize, Error> {
+        let length = match self {
+            Self::Narrow => u16::from_be_bytes(bytes[..BYTES_IN_U16].try_into()?) as usize,
+            Self::Wide => u32::from_be_bytes(bytes[..BYTES_IN_U32].try_into()?) as usize,
+        };
+        if length > self.limit() {
+            return Err(Error::Corruption);
+        }
+        Ok(length)
+    }
+
+    fn encode(self, length: usize) -> Vec<u8> {
+        match self {
+            Self::Narrow => (length as u16).to_be_bytes().to_vec(),
+            Self::Wide => (length as u32).to_be_bytes().to_vec(),
+        }
+    }
+}
 
 impl DirectoryChild {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        if bytes.len() < BYTES_IN_U64 + BYTES_IN_U16 {
+    /// Bytes of entry's inode and name length preceding its name
+    pub(crate) fn header_size(width: NameLength) -> usize {
+        BYTES_IN_U64 + width.size()
+    }
+
+    /// Length of name in entry starting with `header`
+    pub(crate) fn name_length(header: &[u8], width: NameLength) -> Result<usize, Error> {
+        if header.len() < Self::header_size(width) {
             return Err(Error::InsufficientBytes);
         }
-        let mut inode = [0; BYTES_IN_U64];
-        let mut name_length = [0; BYTES_IN_U16];
-        inode.copy_from_slice(&bytes[0..BYTES_IN_U64]);
-        name_length.copy_from_slice(&bytes[BYTES_IN_U64..BYTES_IN_U64 + BYTES_IN_U16]);
-        let inode = u64::from_be_bytes(inode);
-        let name_length = u16::from_be_bytes(name_length) as usize;
-        if bytes.len() < BYTES_IN_U64 + BYTES_IN_U16 + name_length {
+        width.decode(&header[BYTES_IN_U64..])
+    }
+
+    pub fn from_bytes(bytes: &[u8], width: NameLength) -> Result<Self, Error> {
+        let name_length = Self::name_length(bytes, width)?;
+        let header = Self::header_size(width);
+        if bytes.len() < header + name_length {
             return Err(Error::InsufficientBytes);
         }
-        let name = std::str::from_utf8(
-            &bytes[BYTES_IN_U64 + BYTES_IN_U16..BYTES_IN_U64 + BYTES_IN_U16 + name_length],
-        )?
-        .to_owned();
+        let inode = u64::from_be_bytes(bytes[0..BYTES_IN_U64].try_into()?);
+        let name = std::str::from_utf8(&bytes[header..header + name_length])?.to_owned();
         Ok(Self { inode, name })
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes: Vec<u8> = vec![0; BYTES_IN_U64 + BYTES_IN_U16 + self.name.len()];
-        bytes[0..BYTES_IN_U64].copy_from_slice(&self.inode.to_be_bytes());
-        bytes[BYTES_IN_U64..BYTES_IN_U64 + BYTES_IN_U16]
-            .copy_from_slice(&(self.name.len() as u16).to_be_bytes());
-        bytes[BYTES_IN_U64 + BYTES_IN_U16..].copy_from_slice(self.name.as_bytes());
+    pub fn as_bytes(&self, width: NameLength) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::header_size(width) + self.name.len());
+        bytes.extend_from_slice(&self.inode.to_be_bytes());
+        bytes.extend_from_slice(&width.encode(self.name.len()));
+        bytes.extend_from_slice(self.name.as_bytes());
         bytes
     }
 
-    pub fn read(file: &mut RawByteFile) -> Result<Self, Error> {
-        let inode = read_u64(file)?;
-        let name = read_sized_string(file)?;
+    pub fn read(file: &mut RawByteFile, width: NameLength) -> Result<Self, Error> {
+        let mut header = vec![0u8; Self::header_size(width)];
+        file.read(&mut header)?;
+        let name = read_string(file, Self::name_length(&header, width)?)?;
+        let inode = u64::from_be_bytes(header[0..BYTES_IN_U64].try_into()?);
         Ok(Self { inode, name })
     }
 
-    pub fn flush(&self, file: &mut RawByteFile) -> Result<(), Error> {
-        file.write(&self.as_bytes())
+    pub fn flush(&self, file: &mut RawByteFile, width: NameLength) -> Result<(), Error> {
+        file.write(&self.as_bytes(width))
     }
 }
 
@@ -54,7 +105,8 @@ impl PartialEq for DirectoryChild {
 
 #[cfg(test)]
 mod tests {
-    use super::DirectoryChild;
+    use super::{DirectoryChild, NameLength, MAX_NAME_LENGTH};
+    use crate::Error;
 
     #[test]
     fn byte_conversion() {
@@ -62,9 +114,62 @@ mod tests {
             inode: 420,
             name: "foobar.exe".into(),
         };
-        let bytes = dc.as_bytes();
-        let dc1 = DirectoryChild::from_bytes(&bytes).unwrap();
-        assert_eq!(dc1.inode, dc.inode);
-        assert_eq!(dc1.name, dc.name);
+        for width in [NameLength::Narrow, NameLength::Wide] {
+            let bytes = dc.as_bytes(width);
+            assert_eq!(bytes.len(), 8 + width.size() + 10);
+            let dc1 = DirectoryChild::from_bytes(&bytes, width).unwrap();
+            assert_eq!(dc1.inode, dc.inode);
+            assert_eq!(dc1.name, dc.name);
+        }
+    }
+
+    #[test]
+    fn random_round_trips() {
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let length = (next() % (MAX_NAME_LENGTH as u64 / 2)) as usize;
+            let mut name = String::new();
+            while name.len() < length {
+                name.extend(char::from_u32((next() % 0x3000) as u32 + 1));
+            }
+            let child = DirectoryChild {
+                inode: next(),
+                name,
+            };
+            let width = [NameLength::Narrow, NameLength::Wide][(next() % 2) as usize];
+            let mut bytes = child.as_bytes(width);
+            let parsed = DirectoryChild::from_bytes(&bytes, width).unwrap();
+            assert_eq!((parsed.inode, &parsed.name), (child.inode, &child.name));
+            // Any truncation is detected instead of yielding a shorter name
+            let cut = (next() % bytes.len() as u64) as usize;
+            assert!(matches!(
+                DirectoryChild::from_bytes(&bytes[..cut], width),
+                Err(Error::InsufficientBytes)
+            ));
+            // Random bytes decode to an error or to a name within limits
+            for byte in bytes.iter_mut().skip(8) {
+                *byte = next() as u8;
+            }
+            if let Ok(parsed) = DirectoryChild::from_bytes(&bytes, width) {
+                assert!(parsed.name.len() <= width.limit());
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_length_rejected() {
+        let mut bytes = vec![0u8; 12];
+        bytes[8..12].copy_from_slice(&(MAX_NAME_LENGTH as u32 + 1).to_be_bytes());
+        bytes.resize(12 + MAX_NAME_LENGTH + 1, b'x');
+        assert!(matches!(
+            DirectoryChild::from_bytes(&bytes, NameLength::Wide),
+            Err(Error::Corruption)
+        ));
     }
 }
@@ -0,0 +1,290 @@
+//! Hashed index of entries in large directories
+//!
+//! Entries of a directory are stored one after another, so finding a single
+//! name takes reading all of them. On filesystems with
+//! [INCOMPAT_DIRECTORY_INDEX], a directory of at least [INDEX_THRESHOLD]
+//! entries is flushed with its entries grouped into buckets by hash of their
+//! name. The name of directory is then followed by [INDEX_MARKER], which no
+//! entry starts with, the number of buckets and a slot for each bucket: the
+//! block holding its first entry, the offset of that entry in block and the
+//! number of entries in bucket. Looking up a name reads only its bucket. A
+//! directory shrinking below the threshold is flushed in the flat format.
+
+use std::io::{Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+use fuser::FileType;
+
+use crate::{
+    filesystem::{LockFilesystem, ROOT_INODE},
+    structs::{Block, INCOMPAT_DIRECTORY_INDEX, NULL_BLOCK},
+    Error, Filesystem,
+};
+
+use super::{
+    helpers::*, Directory, DirectoryChild, FileOperations, NameLength, RawByteFile, BYTES_IN_U64,
+};
+
+/// Entries from which directories are indexed
+pub const INDEX_THRESHOLD: usize = 256;
+/// Average entries in a bucket of a new index
+const ENTRIES_PER_BUCKET: usize = 32;
+/// Value following name of an indexed directory in place of first entry's inode
+const INDEX_MARKER: u64 = NULL_BLOCK;
+/// Bytes of a bucket slot
+const SLOT_SIZE: usize = 16;
+
+/// Whether large directories on filesystem are indexed
+pub(super) fn supported(fs: &Filesystem) -> bool {
+    fs.superblock.incompat_flags & INCOMPAT_DIRECTORY_INDEX != 0
+}
+
+/// FNV-1a hash of name, stable across implementations
+fn hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Bucket of name in index of `buckets`, which is a power of two
+fn bucket(name: &str, buckets: u64) -> u64 {
+    hash(name) & (buckets - 1)
+}
+
+/// Skip index following name of a directory with `children` entries in
+/// `file`, returning whether there is one
+pub(super) fn skip(file: &mut RawByteFile, children: u64) -> Result<bool, Error> {
+    let start = file.cursor.position();
+    if children == 0 || read_u64(file)? != INDEX_MARKER {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(false);
+    }
+    let buckets = read_u64(file)?;
+    file.seek(SeekFrom::Current(buckets as i64 * SLOT_SIZE as i64))?;
+    Ok(true)
+}
+
+/// First entry and number of entries in a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    block: u64,
+    offset: u32,
+    count: u32,
+}
+
+impl Slot {
+    fn to_bytes(self) -> [u8; SLOT_SIZE] {
+        let mut bytes = [0u8; SLOT_SIZE];
+        bytes[0..8].copy_from_slice(&self.block.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.count.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; SLOT_SIZE]) -> Result<Self, Error> {
+        Ok(Self {
+            block: u64::from_be_bytes(bytes[0..8].try_into()?),
+            offset: u32::from_be_bytes(bytes[8..12].try_into()?),
+            count: u32::from_be_bytes(bytes[12..16].try_into()?),
+        })
+    }
+}
+
+/// Reader of bytes following the block chain from any block of a file
+struct ChainReader {
+    block: Block,
+    offset: usize,
+}
+
+impl ChainReader {
+    fn read(&mut self, fs: &mut Filesystem, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut total = 0;
+        while total < buffer.len() {
+            if self.offset == self.block.data.len() {
+                let next = get_next_block(&self.block);
+                if next == NULL_BLOCK {
+                    return Err(Error::OutOfBounds);
+                }
+                self.block = fs.load_block(next, false)?;
+                self.offset = BYTES_IN_U64;
+            }
+            let read = read_from_block(&self.block, self.offset, &mut buffer[total..]);
+            total += read;
+            self.offset += read;
+        }
+        Ok(())
+    }
+
+    fn read_child(
+        &mut self,
+        fs: &mut Filesystem,
+        width: NameLength,
+    ) -> Result<DirectoryChild, Error> {
+        let header = DirectoryChild::header_size(width);
+        let mut bytes = vec![0u8; header];
+        self.read(fs, &mut bytes)?;
+        bytes.resize(header + DirectoryChild::name_length(&bytes, width)?, 0);
+        self.read(fs, &mut bytes[header..])?;
+        DirectoryChild::from_bytes(&bytes, width)
+    }
+}
+
+impl Directory {
+    /// Whether directory is flushed with an index
+    pub(crate) fn indexed(&self) -> Result<bool, Error> {
+        let fs_handle = self.file.filesystem.lock_fs()?;
+        Ok(self.children.len() >= INDEX_THRESHOLD && supported(&fs_handle))
+    }
+
+    /// Write index followed by entries grouped into its buckets
+    pub(super) fn flush_indexed(&mut self, width: NameLength) -> Result<(), Error> {
+        let buckets = (self.children.len() / ENTRIES_PER_BUCKET).next_power_of_two() as u64;
+        let mut children: Vec<_> = self
+            .children
+            .iter()
+            .map(|child| (bucket(&child.name, buckets), child))
+            .collect();
+        children.sort_by_key(|(bucket, _)| *bucket);
+        self.file.write(&INDEX_MARKER.to_be_bytes())?;
+        self.file.write(&buckets.to_be_bytes())?;
+        let slots_start = self.file.cursor.position();
+        self.file.write(&vec![0u8; buckets as usize * SLOT_SIZE])?;
+        // Positions of buckets in file, resolved to blocks once all are written
+        let mut positions = vec![(0, 0, 0u32); buckets as usize];
+        for (bucket, child) in children {
+            let position = &mut positions[bucket as usize];
+            if position.2 == 0 {
+                (position.0, position.1) = (self.file.cursor.block(), self.file.cursor.byte());
+            }
+            position.2 += 1;
+            child.flush(&mut self.file, width)?;
+        }
+        let end = self.file.cursor.position();
+        let filesystem = self.file.filesystem.clone();
+        let blocks = self.file.blocks_locked(&mut *filesystem.lock_fs()?)?;
+        let slots: Vec<u8> = positions
+            .into_iter()
+            .map(|(block, offset, count)| Slot {
+                block: match count {
+                    0 => NULL_BLOCK,
+                    _ => blocks[block as usize],
+                },
+                offset: offset as u32,
+                count,
+            })
+            .flat_map(Slot::to_bytes)
+            .collect();
+        self.file.seek(SeekFrom::Start(slots_start))?;
+        self.file.write(&slots)?;
+        self.file.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// Resolve name to inode in directory with inode `index`, reading only
+    /// the bucket of name if directory is indexed
+    pub fn find(fs: &Arc<Mutex<Filesystem>>, index: u64, name: &str) -> Result<u64, Error> {
+        let mut fs_handle = fs.lock_fs()?;
+        let inode = fs_handle.load_inode(index)?;
+        if inode.r#type != FileType::Directory {
+            return Err(Error::NotDirectory);
+        }
+        match name {
+            "." => return Ok(index),
+            ".." if index == ROOT_INODE => return Ok(index),
+            ".." => return Ok(inode.metadata[0]),
+            _ => Directory::validate_name(name)?,
+        }
+        let width = NameLength::of(&fs_handle);
+        let mut file = RawByteFile::load_locked(&fs_handle, fs, inode);
+        file.seek(SeekFrom::Start(inode.metadata[2]))?;
+        let mut raw = [0u8; BYTES_IN_U64];
+        let indexed = inode.metadata[1] != 0 && {
+            file.read_locked(&mut fs_handle, &mut raw)?;
+            u64::from_be_bytes(raw) == INDEX_MARKER
+        };
+        if !indexed {
+            drop(fs_handle);
+            return Directory::load(fs, index)?.lookup(name);
+        }
+        file.read_locked(&mut fs_handle, &mut raw)?;
+        let buckets = u64::from_be_bytes(raw);
+        if !buckets.is_power_of_two() {
+            return Err(Error::Corruption);
+        }
+        let slot = bucket(name, buckets) * SLOT_SIZE as u64;
+        file.seek(SeekFrom::Current(slot as i64))?;
+        let mut raw = [0u8; SLOT_SIZE];
+        file.read_locked(&mut fs_handle, &mut raw)?;
+        let slot = Slot::from_bytes(&raw)?;
+        if slot.count == 0 {
+            return Err(Error::NotFound);
+        }
+        let mut reader = ChainReader {
+            block: fs_handle.load_block(slot.block, false)?,
+            offset: slot.offset as usize,
+        };
+        for _ in 0..slot.count {
+            let child = reader.read_child(&mut fs_handle, width)?;
+            if child.name == name {
+                return Ok(child.inode);
+            }
+        }
+        Err(Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::INDEX_THRESHOLD;
+    use crate::error::Error;
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner};
+
+    #[test]
+    fn indexed_lookup() {
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 4_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let count = INDEX_THRESHOLD as u64 * 2;
+        for child in 0..count {
+            root.add_child(&format!("child{child}"), 1000 + child)
+                .unwrap();
+        }
+        drop(root);
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert!(root.indexed().unwrap());
+        assert_eq!(root.children.len() as u64, count);
+        drop(root);
+        for child in 0..count {
+            let name = format!("child{child}");
+            assert_eq!(
+                Directory::find(&fs, ROOT_INODE, &name).unwrap(),
+                1000 + child
+            );
+        }
+        assert!(matches!(
+            Directory::find(&fs, ROOT_INODE, "missing"),
+            Err(Error::NotFound)
+        ));
+        assert_eq!(Directory::find(&fs, ROOT_INODE, "..").unwrap(), ROOT_INODE);
+
+        // Shrinking below threshold returns to flat format
+        let mut root = Directory::load(&fs, ROOT_INODE).unwrap();
+        root.children.truncate(INDEX_THRESHOLD - 1);
+        root.modified = true;
+        drop(root);
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        assert!(!root.indexed().unwrap());
+        assert_eq!(root.children.len(), INDEX_THRESHOLD - 1);
+        let last = root.children.last().unwrap().clone();
+        drop(root);
+        assert_eq!(
+            Directory::find(&fs, ROOT_INODE, &last.name).unwrap(),
+            last.inode
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use crate::{
     structs::{Block, NULL_BLOCK},
     Error,
 };
 
-use super::{RawByteFile, BYTES_IN_U16, BYTES_IN_U64};
+use super::{RawByteFile, BYTES_IN_U64};
 
 const EMPTY_BYTE_DATA: u8 = 0;
 
@@ -15,12 +15,6 @@ fn u64_from_bytes(bytes: &[u8]) -> u64 {
     u64::from_le_bytes(raw)
 }
 
-fn u16_from_bytes(bytes: &[u8]) -> u16 {
-    let mut raw = [0; BYTES_IN_U16];
-    raw.copy_from_slice(bytes);
-    u16::from_le_bytes(raw)
-}
-
 fn empty_block(size: u32) -> Vec<u8> {
     let mut empty_block = vec![EMPTY_BYTE_DATA; size as usize];
     empty_block[0..BYTES_IN_U64].copy_from_slice(&NULL_BLOCK.to_le_bytes());
@@ -46,16 +40,11 @@ pub fn get_next_block(block: &Block) -> u64 {
     u64_from_bytes(&block.data[0..BYTES_IN_U64])
 }
 
-pub fn timestamp_now() -> u64 {
+/// Current time since epoch, with nanoseconds
+pub fn timestamp_now() -> Duration {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
-        .map_or(0, |d| d.as_secs())
-}
-
-pub fn read_u16(file: &mut RawByteFile) -> Result<u16, Error> {
-    let mut raw = [0u8; BYTES_IN_U16];
-    file.read(&mut raw)?;
-    Ok(u16::from_be_bytes(raw))
+        .unwrap_or_default()
 }
 
 pub fn read_u64(file: &mut RawByteFile) -> Result<u64, Error> {
@@ -70,11 +59,6 @@ pub fn read_string(file: &mut RawByteFile, length: usize) -> Result<String, Erro
     Ok(std::str::from_utf8(&raw_string)?.to_owned())
 }
 
-pub fn read_sized_string(file: &mut RawByteFile) -> Result<String, Error> {
-    let length = read_u16(file)?;
-    read_string(file, length as usize)
-}
-
 pub fn write_to_block(block: &mut Block, offset: usize, buffer: &[u8]) -> usize {
     let write_bytes = usize::min(block.data.len() - offset, buffer.len());
     block.data[offset..offset + write_bytes].copy_from_slice(&buffer[..write_bytes]);
@@ -0,0 +1,201 @@
+//! Contents of small regular files kept inside their inode
+//!
+//! A file of a few bytes would otherwise occupy a whole block. On filesystems
+//! with [INCOMPAT_INLINE_DATA], a regular file without blocks keeps up to
+//! [INLINE_CAPACITY] bytes in inode fields which only files with blocks use:
+//! metadata slots after its parent, followed by first and last block index.
+//! Once it grows beyond that, its contents are moved into blocks.
+
+use log::debug;
+
+use crate::{
+    structs::{Inode, INCOMPAT_INLINE_DATA, NULL_BLOCK},
+    Error, Filesystem,
+};
+
+use super::{RawByteFile, BYTES_IN_U64};
+
+/// Bytes of file contents stored in an inode
+pub const INLINE_CAPACITY: usize = 6 * BYTES_IN_U64;
+
+/// Whether files on filesystem may keep contents in their inodes
+pub(super) fn supported(fs: &Filesystem) -> bool {
+    fs.superblock.incompat_flags & INCOMPAT_INLINE_DATA != 0
+}
+
+/// Contents of file kept in [Inode]
+pub(super) fn load(inode: &Inode) -> Vec<u8> {
+    let (metadata, first, last) = (inode.metadata, inode.first_block, inode.last_block);
+    let mut data: Vec<u8> = metadata[1..]
+        .iter()
+        .chain([first, last].iter())
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    data.truncate(inode.size.min(INLINE_CAPACITY as u64) as usize);
+    data
+}
+
+/// Store contents of file in [Inode]
+pub(super) fn store(inode: &mut Inode, data: &[u8]) {
+    let mut raw = [0u8; INLINE_CAPACITY];
+    raw[..data.len()].copy_from_slice(data);
+    let mut words = raw.chunks(BYTES_IN_U64).map(|chunk| {
+        let mut word = [0u8; BYTES_IN_U64];
+        word.copy_from_slice(chunk);
+        u64::from_le_bytes(word)
+    });
+    let mut metadata = inode.metadata;
+    for slot in metadata[1..].iter_mut() {
+        *slot = words.next().unwrap_or_default();
+    }
+    inode.metadata = metadata;
+    inode.first_block = words.next().unwrap_or_default();
+    inode.last_block = words.next().unwrap_or_default();
+}
+
+impl RawByteFile {
+    /// Read from contents kept in inode
+    pub(super) fn inline_read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let Some(data) = &self.inline else {
+            return Err(Error::NullBlock);
+        };
+        let start = self.cursor.position() as usize;
+        if start + buffer.len() > data.len() {
+            return Err(Error::OutOfBounds);
+        }
+        buffer.copy_from_slice(&data[start..start + buffer.len()]);
+        self.cursor.advance(buffer.len() as u64);
+        Ok(())
+    }
+
+    /// Write into contents kept in inode, returning `false` if they would not fit
+    pub(super) fn inline_write(&mut self, buffer: &[u8]) -> bool {
+        let Some(data) = self.inline.as_mut() else {
+            return false;
+        };
+        let start = self.cursor.position() as usize;
+        let end = start + buffer.len();
+        if end > INLINE_CAPACITY {
+            return false;
+        }
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buffer);
+        self.cursor.advance(buffer.len() as u64);
+        self.size = data.len() as u64;
+        true
+    }
+
+    /// Resize contents kept in inode, returning `false` if they would not fit
+    pub(super) fn inline_resize(&mut self, new_capacity: u64) -> bool {
+        let Some(data) = self.inline.as_mut() else {
+            return false;
+        };
+        if new_capacity > INLINE_CAPACITY as u64 {
+            return false;
+        }
+        data.resize(new_capacity as usize, 0);
+        self.size = new_capacity;
+        if self.cursor.position() > new_capacity {
+            self.cursor.reset();
+        }
+        true
+    }
+
+    /// Move contents kept in inode into blocks, keeping cursor's position
+    pub(super) fn spill_locked(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
+        let Some(data) = self.inline.take() else {
+            return Ok(());
+        };
+        debug!("Move {} bytes of inline data into blocks", data.len());
+        let position = self.cursor.position();
+        self.first_block = NULL_BLOCK;
+        self.last_block = NULL_BLOCK;
+        self.size = 0;
+        self.cursor.reset();
+        if let Err(e) = self.write_locked(fs, &data) {
+            if self.first_block != NULL_BLOCK {
+                self.shrink_locked(fs, 0)?;
+            }
+            self.size = data.len() as u64;
+            self.inline = Some(data);
+            self.cursor.set(position);
+            return Err(e);
+        }
+        self.cursor.set(position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Seek, SeekFrom},
+        sync::{Arc, Mutex},
+    };
+
+    use super::{load, store, INLINE_CAPACITY};
+    use crate::{
+        filesystem::ROOT_INODE,
+        filetypes::{Directory, FileOperations, Owner, RawByteFile, RegularFile},
+        structs::{Inode, NULL_BLOCK},
+        Filesystem,
+    };
+
+    #[test]
+    fn store_and_load() {
+        let mut inode = Inode {
+            size: 41,
+            ..Inode::default()
+        };
+        let data: Vec<u8> = (1..=41).collect();
+        store(&mut inode, &data);
+        assert_eq!({ inode.metadata[0] }, 0);
+        assert_eq!(load(&inode), data);
+    }
+
+    #[test]
+    fn spill_into_blocks() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        let blocks_free = fs.lock().unwrap().superblock.blocks_free;
+        let mut file = RawByteFile::new_regular(&fs, Owner::default()).unwrap();
+        file.write(b"key = value\n").unwrap();
+        file.extend(INLINE_CAPACITY as u64).unwrap();
+        assert_eq!(file.block_count, 0);
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+
+        file.seek(SeekFrom::Start(4)).unwrap();
+        file.write(&[b'X'; 100]).unwrap();
+        assert!(file.inline.is_none());
+        assert_eq!(file.block_count, 1);
+        assert_eq!(file.size, 104);
+        let mut buffer = vec![0u8; 8];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"key XXXX");
+        file.shrink(0).unwrap();
+        assert_eq!(file.first_block, NULL_BLOCK);
+        assert_eq!(file.inline, Some(Vec::new()));
+        assert_eq!({ fs.lock().unwrap().superblock.blocks_free }, blocks_free);
+    }
+
+    #[test]
+    fn regular_file_round_trip() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Filesystem::new(Box::new(dev), 1_000_000, 512);
+        let fs = Arc::new(Mutex::new(fs));
+        Directory::new(&fs, ROOT_INODE, "root", 0o750, Owner::default()).unwrap();
+        let mut file =
+            RegularFile::new(&fs, ROOT_INODE, "config", 0o640, Owner::default()).unwrap();
+        file.write(0, b"inline = true\n").unwrap();
+        let index = file.inode.index;
+        drop(file);
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!({ file.inode.block_count }, 0);
+        assert_eq!(file.read(0, 100).unwrap(), b"inline = true\n");
+        assert_eq!({ file.inode.metadata[0] }, ROOT_INODE);
+    }
+}
@@ -1,16 +1,31 @@
+mod allocation;
 mod block_cursor;
+mod block_table;
 mod directory;
 mod directory_child;
+mod directory_index;
 mod helpers;
+mod inline_data;
 mod raw_file;
 mod regular_file;
+mod walk;
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
 
 use crate::{filesystem::Filesystem, structs::Inode, Error};
 
+pub use walk::Walk;
+
+pub(crate) use helpers::{bytes_per_block, get_next_block, set_next_block, timestamp_now};
+
 const BYTES_IN_U64: usize = 8;
+const BYTES_IN_U32: usize = 4;
 const BYTES_IN_U16: usize = 2;
+/// Longest name of a new directory child in bytes, as `NAME_MAX` of POSIX
+pub const MAX_NAME_LENGTH: usize = 255;
 
 pub trait File: Sized {
     fn new(fs: &mut Filesystem, parent: u64) -> Result<Self, Error>;
@@ -24,20 +39,54 @@ pub struct RawByteFile {
     pub(crate) size: u64,
     pub(crate) cursor: BlockCursor,
     pub(crate) filesystem: Arc<Mutex<Filesystem>>,
+    /// Tables of block indices, for files of filesystems supporting them
+    pub(crate) tables: Option<BlockTables>,
+    /// Contents kept in inode, for small files of filesystems supporting it
+    pub(crate) inline: Option<Vec<u8>>,
+    /// Owner charged for blocks of the file
+    pub(crate) owner: Owner,
+    /// Whether file holds metadata, which may take reserved blocks
+    pub(crate) metadata: bool,
+    /// Indices of blocks by their position in file, resolved by earlier
+    /// lookups so that they do not walk the chain of blocks again
+    pub(crate) block_map: BTreeMap<u64, u64>,
+    /// Whether written blocks may be stored compressed
+    pub(crate) compress: bool,
+}
+
+/// Indirect tables of a file's block indices, kept in its [Inode]'s metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockTables {
+    /// Table holding indices of the first blocks
+    pub(crate) single: u64,
+    /// Table of tables holding indices of the following blocks
+    pub(crate) double: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct RegularFile {
-    pub(crate) inode: Inode,
+    pub inode: Inode,
     pub(crate) file: RawByteFile,
+    /// Offset of next [Read](std::io::Read) or [Write](std::io::Write),
+    /// moved by [Seek](std::io::Seek)
+    pub(crate) position: u64,
     pub(crate) modified: bool,
     pub(crate) removed: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct DirectoryChild {
-    pub(crate) inode: u64,
-    pub(crate) name: String,
+    pub inode: u64,
+    pub name: String,
+}
+
+/// Encoding of name length in directory entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameLength {
+    /// 16-bit length of filesystems without [INCOMPAT_WIDE_NAMES](crate::structs::INCOMPAT_WIDE_NAMES)
+    Narrow,
+    /// 32-bit length
+    Wide,
 }
 
 #[derive(Debug, Clone)]
@@ -48,14 +97,56 @@ pub enum DirectoryChildIdentifier<'a> {
 
 #[derive(Debug, Clone)]
 pub struct Directory {
-    pub(crate) inode: Inode,
+    pub inode: Inode,
     pub(crate) file: RawByteFile,
     pub(crate) name: String,
-    pub(crate) children: Vec<DirectoryChild>,
+    pub children: Vec<DirectoryChild>,
+    /// Names added or removed since the last flush, invalidated by it
+    pub(crate) changed: Vec<String>,
     pub(crate) modified: bool,
     pub(crate) removed: bool,
 }
 
+/// Credentials assigned to newly created files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Owner {
+    /// Owner of a file created inside directory with given [Inode],
+    /// taking over the group of setgid directories
+    pub fn inherit(self, parent: &Inode) -> Self {
+        if parent.mode as u32 & libc::S_ISGID != 0 {
+            Self {
+                gid: parent.gid,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+}
+
+impl From<&Inode> for Owner {
+    fn from(inode: &Inode) -> Self {
+        Self {
+            uid: inode.uid,
+            gid: inode.gid,
+        }
+    }
+}
+
+impl From<&fuser::Request<'_>> for Owner {
+    fn from(req: &fuser::Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BlockCursor {
     pub(crate) block_size: usize,
@@ -69,7 +160,13 @@ pub trait FileOperations
 where
     Self: Sized,
 {
-    fn new(fs: &Arc<Mutex<Filesystem>>, parent: u64, name: &str, mode: u32) -> Result<Self, Error>;
+    fn new(
+        fs: &Arc<Mutex<Filesystem>>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<Self, Error>;
     fn load(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<Self, Error>;
     fn flush(&mut self) -> Result<(), Error>;
     fn remove(self) -> Result<(), Error>;
@@ -1,21 +1,27 @@
+use fuser::FileType;
 use log::debug;
 use std::{
+    collections::BTreeMap,
     io::Seek,
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    structs::{Block, Inode, NULL_BLOCK},
+    filesystem::LockFilesystem,
+    structs::{Block, Inode, FLAG_NOCOMPRESS, NULL_BLOCK},
     Error, Filesystem,
 };
 
-use super::{helpers::*, BlockCursor, RawByteFile, BYTES_IN_U64};
+use super::{
+    allocation::BlockAllocation, block_table::table_count, helpers::*, inline_data, BlockCursor,
+    BlockTables, Owner, RawByteFile, BYTES_IN_U64,
+};
 
 impl RawByteFile {
-    /// Create an empty file with no allocated blocks
-    pub fn new(fs: &Arc<Mutex<Filesystem>>) -> Result<Self, Error> {
+    /// Create an empty file with no allocated blocks, charging its blocks to `owner`
+    pub fn new(fs: &Arc<Mutex<Filesystem>>, owner: Owner) -> Result<Self, Error> {
         debug!("Create a new raw byte file");
-        let fs_handle = fs.lock()?;
+        let fs_handle = fs.lock_fs()?;
         let cursor = BlockCursor::new(&fs_handle, (BYTES_IN_U64 as u32, 0));
         Ok(Self {
             first_block: NULL_BLOCK,
@@ -24,13 +30,40 @@ impl RawByteFile {
             size: 0,
             cursor,
             filesystem: fs.clone(),
+            tables: None,
+            inline: None,
+            owner,
+            metadata: false,
+            block_map: BTreeMap::new(),
+            compress: true,
         })
     }
 
+    /// Create an empty regular file, indexed by block tables and keeping its
+    /// contents in inode while they fit, if filesystem supports them
+    pub fn new_regular(fs: &Arc<Mutex<Filesystem>>, owner: Owner) -> Result<Self, Error> {
+        let mut file = Self::new(fs, owner)?;
+        let fs_handle = fs.lock_fs()?;
+        file.tables = BlockTables::new(&fs_handle);
+        file.inline = inline_data::supported(&fs_handle).then(Vec::new);
+        Ok(file)
+    }
+
+    /// Create an empty directory body, which may take reserved blocks
+    pub fn new_directory(fs: &Arc<Mutex<Filesystem>>, owner: Owner) -> Result<Self, Error> {
+        let mut file = Self::new(fs, owner)?;
+        file.metadata = true;
+        Ok(file)
+    }
+
     /// Create zero-initialized file with specified capacity
-    pub fn with_capacity(fs: &Arc<Mutex<Filesystem>>, capacity: u64) -> Result<Self, Error> {
+    pub fn with_capacity(
+        fs: &Arc<Mutex<Filesystem>>,
+        owner: Owner,
+        capacity: u64,
+    ) -> Result<Self, Error> {
         debug!("Create a new raw byte file with capacity {capacity}");
-        let mut file = Self::new(fs)?;
+        let mut file = Self::new(fs, owner)?;
         file.extend(capacity)?;
         assert_eq!(file.cursor.position(), 0);
         Ok(file)
@@ -38,29 +71,70 @@ impl RawByteFile {
 
     /// Load file for given [Inode]
     pub fn load(fs: &Arc<Mutex<Filesystem>>, inode: Inode) -> Result<Self, Error> {
+        let fs_handle = fs.lock_fs()?;
+        Ok(Self::load_locked(&fs_handle, fs, inode))
+    }
+
+    /// Load file for given [Inode] using an already locked filesystem
+    pub(crate) fn load_locked(
+        fs_handle: &Filesystem,
+        fs: &Arc<Mutex<Filesystem>>,
+        inode: Inode,
+    ) -> Self {
         let index = inode.index;
         debug!("Load raw byte file for inode {index}");
-        let fs_handle = fs.lock()?;
-        let cursor = BlockCursor::new(&fs_handle, (BYTES_IN_U64 as u32, 0));
-        Ok(Self {
+        let cursor = BlockCursor::new(fs_handle, (BYTES_IN_U64 as u32, 0));
+        let inline = inode.r#type != FileType::Directory
+            && inode.block_count == 0
+            && inline_data::supported(fs_handle);
+        if inline {
+            return Self {
+                first_block: NULL_BLOCK,
+                last_block: NULL_BLOCK,
+                block_count: 0,
+                size: inode.size,
+                cursor,
+                filesystem: fs.clone(),
+                tables: BlockTables::new(fs_handle),
+                inline: Some(inline_data::load(&inode)),
+                owner: Owner::from(&inode),
+                metadata: false,
+                block_map: BTreeMap::new(),
+                compress: inode.flags & FLAG_NOCOMPRESS == 0,
+            };
+        }
+        Self {
             first_block: inode.first_block,
             last_block: inode.last_block,
             block_count: inode.block_count,
             size: inode.size,
             cursor,
             filesystem: fs.clone(),
-        })
+            tables: BlockTables::load(fs_handle, &inode),
+            inline: None,
+            owner: Owner::from(&inode),
+            metadata: inode.r#type == FileType::Directory,
+            block_map: BTreeMap::new(),
+            compress: inode.flags & FLAG_NOCOMPRESS == 0,
+        }
     }
 
-    /// Bytes per block available for data
-    fn bytes_per_block(&self) -> Result<usize, Error> {
-        let fs = self.filesystem.lock()?;
-        Ok(bytes_per_block(fs.superblock.block_size) as usize)
+    /// Retrieve file's n-th [Block]
+    pub fn get_nth_block(&mut self, position: u64) -> Result<Block, Error> {
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.get_nth_block_locked(&mut fs_handle, position)
     }
 
-    /// Retrieve file's n-th [Block]
-    pub fn get_nth_block(&self, position: u64) -> Result<Block, Error> {
-        let mut fs = self.filesystem.lock()?;
+    /// Retrieve file's n-th [Block] using an already locked filesystem
+    ///
+    /// The chain is walked from the nearest block whose index is known, either
+    /// from [block map](Self::block_map) or tables, recording indices of walked blocks.
+    pub(crate) fn get_nth_block_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        position: u64,
+    ) -> Result<Block, Error> {
         if self.first_block == NULL_BLOCK {
             return Err(Error::NullBlock);
         }
@@ -71,8 +145,18 @@ impl RawByteFile {
         if position + 1 == self.block_count {
             return fs.load_block(self.last_block, false);
         };
-        let mut current_block = fs.load_block(self.first_block, false)?;
-        for current_index in 0..=position {
+        let mapped = self.block_map.range(..=position).next_back();
+        let (start, index) = match mapped {
+            Some((&start, &index)) if start == position => return fs.load_block(index, false),
+            Some((&start, &index)) => match self.table_nearest(fs, position)? {
+                (nearest, index) if nearest > start => (nearest, index),
+                _ => (start, index),
+            },
+            None => self.table_nearest(fs, position)?,
+        };
+        let mut current_block = fs.load_block(index, false)?;
+        for current_index in start..=position {
+            self.block_map.insert(current_index, current_block.index);
             if current_index == position {
                 return Ok(current_block);
             }
@@ -88,47 +172,122 @@ impl RawByteFile {
     /// Read contents of the file into an [u8] buffer
     /// Use [seek](Self::seek) to set starting position and adjust buffer's length for end position
     pub fn read(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.read_locked(&mut fs_handle, buffer)
+    }
+
+    /// Read contents of the file into an [u8] buffer using an already locked filesystem
+    pub(crate) fn read_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
         debug!("Read {} bytes from raw byte file", buffer.len());
+        if self.inline.is_some() {
+            return self.inline_read(buffer);
+        }
         if buffer.len() as u64 > self.size - self.cursor.position() {
             return Err(Error::OutOfBounds);
         }
-        let mut current_block = self.get_nth_block(self.cursor.block())?;
-        let mut total_read_bytes = 0;
+        let first_block = self.get_nth_block_locked(fs, self.cursor.block())?;
+        let mut total_read_bytes = read_from_block(&first_block, self.cursor.byte(), buffer);
+        self.cursor.advance(total_read_bytes as u64);
+        let mut next_block = get_next_block(&first_block);
+        // Following blocks are copied straight out of cache
         while total_read_bytes < buffer.len() {
+            let current_block = fs.borrow_block(next_block)?;
             let read = read_from_block(
                 &current_block,
                 self.cursor.byte(),
                 &mut buffer[total_read_bytes..],
             );
+            next_block = get_next_block(&current_block);
             total_read_bytes += read;
             self.cursor.advance(read as u64);
-            if total_read_bytes == buffer.len() {
-                break;
-            }
-            let mut fs_handle = self.filesystem.lock()?;
-            let next_block = get_next_block(&current_block);
-            current_block = fs_handle.load_block(next_block, false)?;
         }
         Ok(())
     }
 
+    /// Prefetch up to `count` blocks following the one holding byte before
+    /// `end` in a single read of the device, unless the next one is cached
+    /// Returns the number of prefetched blocks belonging to the file
+    pub(crate) fn read_ahead_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        end: u64,
+        count: u64,
+    ) -> Result<u64, Error> {
+        if self.inline.is_some() || self.first_block == NULL_BLOCK || end == 0 {
+            return Ok(0);
+        }
+        let position = (end - 1) / self.cursor.padded_block();
+        let count = count.min(self.block_count.saturating_sub(position + 1));
+        if count == 0 {
+            return Ok(0);
+        }
+        let first = get_next_block(&self.get_nth_block_locked(fs, position)?);
+        if fs.cache.contains_block(first) {
+            return Ok(0);
+        }
+        fs.prefetch_blocks(first, count)?;
+        // Blocks are acquired in contiguous runs, but the file may leave the
+        // prefetched one before its end
+        let (mut linked, mut index) = (0, first);
+        while linked < count && (first..first + count).contains(&index) {
+            index = get_next_block(&fs.load_block(index, false)?);
+            linked += 1;
+        }
+        debug!("Read ahead {linked} of {count} blocks of raw byte file");
+        Ok(linked)
+    }
+
     /// Write contents of an [u8] buffer into the file
     /// File will be extended if buffer exceeds its capacity
     /// Use [seek](Self::seek) to set starting position and adjust buffer's length for end position
     pub fn write(&mut self, buffer: &[u8]) -> Result<(), Error> {
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.write_locked(&mut fs_handle, buffer)
+    }
+
+    /// Write contents of an [u8] buffer into the file using an already locked filesystem
+    pub(crate) fn write_locked(&mut self, fs: &mut Filesystem, buffer: &[u8]) -> Result<(), Error> {
         debug!("Write {} bytes to raw byte file", buffer.len());
+        if self.inline_write(buffer) {
+            return Ok(());
+        }
+        let end = self.cursor.position() + buffer.len() as u64;
+        self.check_space(fs, end)?;
+        self.spill_locked(fs)?;
+        self.unshare_locked(fs)?;
         if self.first_block == NULL_BLOCK {
-            self.initialize()?;
+            self.initialize_locked(fs)?;
         }
         // Previous write filled last block and moved cursor to a
         // nonexistent next block
         if self.size > 0
-            && self.cursor.position() % self.cursor.padded_block() == 0
+            && self
+                .cursor
+                .position()
+                .is_multiple_of(self.cursor.padded_block())
             && self.cursor.position() == self.size
+            && self.cursor.block() == self.block_count
         {
-            self.append_block()?;
+            self.append_block(fs)?;
+        }
+        // Cursor's block stops being the last one once the file grows
+        let cursor_at_last = self.cursor.block() + 1 == self.block_count;
+        let previous_last_block = self.last_block;
+        // Blocks the file grows by are appended at once, to keep them contiguous
+        let blocks = end.div_ceil(self.cursor.padded_block());
+        if blocks > self.block_count {
+            self.append_blocks(fs, blocks - self.block_count)?;
         }
-        let mut current_block = self.get_nth_block(self.cursor.block())?;
+        let mut current_block = match cursor_at_last {
+            true => fs.load_block(previous_last_block, false)?,
+            false => self.get_nth_block_locked(fs, self.cursor.block())?,
+        };
         let mut total_written_bytes = 0;
         while total_written_bytes < buffer.len() {
             let written = write_to_block(
@@ -136,63 +295,130 @@ impl RawByteFile {
                 self.cursor.byte(),
                 &buffer[total_written_bytes..],
             );
+            current_block.compress = self.compress;
             total_written_bytes += written;
             self.cursor.advance(written as u64);
             if total_written_bytes == buffer.len() {
                 break;
             }
-            let mut fs_handle = self.filesystem.lock()?;
-            fs_handle.flush_block(&current_block)?;
-            drop(fs_handle);
+            fs.flush_block(&current_block)?;
             let next_block = if get_next_block(&current_block) == NULL_BLOCK {
-                self.append_block()?
+                self.append_block(fs)?
             } else {
                 get_next_block(&current_block)
             };
-            let mut fs_handle = self.filesystem.lock()?;
-            current_block = fs_handle.load_block(next_block, false)?;
+            current_block = fs.load_block(next_block, false)?;
         }
-        let mut fs_handle = self.filesystem.lock()?;
-        fs_handle.flush_block(&current_block)?;
+        fs.flush_block(&current_block)?;
         if self.cursor.position() > self.size {
             self.size = self.cursor.position();
         }
         Ok(())
     }
 
+    /// Blocks acquired by growing the file to `size` bytes, including its
+    /// tables and copies of blocks shared with its clones
+    fn blocks_needed(&self, fs: &Filesystem, size: u64) -> u64 {
+        let blocks = size
+            .max(self.size)
+            .div_ceil(bytes_per_block(fs.superblock.block_size))
+            .max(1);
+        let held = match self.first_block != NULL_BLOCK && !fs.shares.is_shared(self.first_block) {
+            true => self.block_count,
+            false => 0,
+        };
+        let tables = match self.tables {
+            Some(_) => table_count(fs, blocks).saturating_sub(table_count(fs, held)),
+            None => 0,
+        };
+        blocks.saturating_sub(held) + tables
+    }
+
+    /// Fail before modifying anything if there is no space to grow the file
+    /// to `size` bytes
+    fn check_space(&self, fs: &Filesystem, size: u64) -> Result<(), Error> {
+        fs.check_space(self.blocks_needed(fs, size), self.owner, self.metadata)
+    }
+
     /// Initialize first block if file is empty
     pub fn initialize(&mut self) -> Result<(), Error> {
-        let mut fs_handle = self.filesystem.lock()?;
-        let index = fs_handle.acquire_block()?;
-        let mut block = fs_handle.load_block(index, true)?;
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.initialize_locked(&mut fs_handle)
+    }
+
+    /// Initialize first block using an already locked filesystem
+    pub(crate) fn initialize_locked(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
+        let mut allocation = BlockAllocation::acquire(fs, 1, self.owner, self.metadata)?;
+        let index = allocation.indices()[0];
+        let mut block = allocation.load_block(index, true)?;
         set_next_block(&mut block, NULL_BLOCK);
-        fs_handle.flush_block(&block)?;
-        self.first_block = block.index;
-        self.last_block = block.index;
+        allocation.flush_block(&block)?;
+        allocation.commit();
+        self.first_block = index;
+        self.last_block = index;
         self.block_count = 1;
+        self.block_map = BTreeMap::from([(0, index)]);
         self.cursor.reset();
-        Ok(())
+        self.table_store(fs, 0, index)
     }
 
     /// Append an empty block to file's end
     /// File size and seeking cursor's position will be kept
-    fn append_block(&mut self) -> Result<u64, Error> {
-        let mut fs_handle = self.filesystem.lock()?;
-        let mut old_last_block = fs_handle.load_block(self.last_block, false)?;
-        let next_block: u64 = fs_handle.acquire_block()?;
-        set_next_block(&mut old_last_block, next_block);
-        fs_handle.flush_block(&old_last_block)?;
-        let mut new_last_block = fs_handle.load_block(next_block, true)?;
-        set_next_block(&mut new_last_block, NULL_BLOCK);
-        fs_handle.flush_block(&new_last_block)?;
-        self.last_block = next_block;
-        self.block_count += 1;
-        Ok(next_block)
+    fn append_block(&mut self, fs: &mut Filesystem) -> Result<u64, Error> {
+        self.append_blocks(fs, 1)?;
+        Ok(self.last_block)
+    }
+
+    /// Append `count` empty blocks to file's end, acquired together so they
+    /// are contiguous if possible
+    /// File size and seeking cursor's position will be kept
+    fn append_blocks(&mut self, fs: &mut Filesystem, count: u64) -> Result<(), Error> {
+        if count == 0 {
+            return Ok(());
+        }
+        let mut allocation = BlockAllocation::acquire(fs, count, self.owner, self.metadata)?;
+        // Terminate and chain new blocks before linking them, releasing them on failure
+        let indices = allocation.indices().to_vec();
+        self.link_blocks(&mut allocation, &indices)?;
+        allocation.commit();
+        for index in indices {
+            self.last_block = index;
+            self.block_count += 1;
+            self.block_map.insert(self.block_count - 1, index);
+            self.table_store(fs, self.block_count - 1, index)?;
+        }
+        Ok(())
+    }
+
+    /// Chain empty blocks at `indices` one after another, and after the last
+    /// block of file
+    fn link_blocks(&self, fs: &mut Filesystem, indices: &[u64]) -> Result<(), Error> {
+        for (position, &index) in indices.iter().enumerate() {
+            let mut block = fs.load_block(index, true)?;
+            let next = indices.get(position + 1).copied().unwrap_or(NULL_BLOCK);
+            set_next_block(&mut block, next);
+            fs.flush_block(&block)?;
+        }
+        let mut old_last_block = fs.load_block(self.last_block, false)?;
+        set_next_block(&mut old_last_block, indices[0]);
+        fs.flush_block(&old_last_block)
     }
 
     /// Extend the file to a new capacity with trailing zeros
     /// Seeking cursor's position will be kept
     pub fn extend(&mut self, new_capacity: u64) -> Result<(), Error> {
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.extend_locked(&mut fs_handle, new_capacity)
+    }
+
+    /// Extend the file to a new capacity using an already locked filesystem
+    pub(crate) fn extend_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        new_capacity: u64,
+    ) -> Result<(), Error> {
         if new_capacity < self.size {
             return Err(Error::InsufficientBytes);
         }
@@ -200,45 +426,77 @@ impl RawByteFile {
             "Extend raw byte file from {} to {new_capacity} bytes",
             self.size
         );
-        if self.first_block == NULL_BLOCK {
-            self.initialize()?;
-        }
-        let mut fs_handle = self.filesystem.lock()?;
-        let capacity_delta = new_capacity - self.size;
-        let bytes_per_block = bytes_per_block(fs_handle.superblock.block_size);
-        let mut last_block = fs_handle.load_block(self.last_block, false)?;
-        // New capacity fits into existing blocks
-        if new_capacity <= self.block_count * bytes_per_block {
-            assert_eq!(get_next_block(&last_block), NULL_BLOCK);
-            assert!(capacity_delta <= bytes_per_block);
-            empty_block_data(
-                &mut last_block,
-                (fs_handle.superblock.block_size as u64 - capacity_delta) as usize,
-            );
-            self.size = new_capacity;
-            fs_handle.flush_block(&last_block)?;
+        if self.inline_resize(new_capacity) {
             return Ok(());
         }
-        // New capacity exceeds existing blocks
+        self.check_space(fs, new_capacity)?;
+        self.spill_locked(fs)?;
+        self.unshare_locked(fs)?;
+        if self.first_block == NULL_BLOCK {
+            self.initialize_locked(fs)?;
+        }
+        let bytes_per_block = bytes_per_block(fs.superblock.block_size);
+        let held = self.block_count * bytes_per_block;
         let previous_cursor = self.cursor.position();
-        self.cursor.set(self.size);
-        let written = empty_block_data(&mut last_block, self.cursor.byte()) as u64;
-        let mut total_allocated_bytes = written;
-        fs_handle.flush_block(&last_block)?;
-        drop(fs_handle);
-        while total_allocated_bytes < capacity_delta {
-            self.append_block()?;
-            total_allocated_bytes += bytes_per_block;
+        // Block holding file's end may keep bytes of a larger size it was
+        // shrunk from, while preallocated blocks following it are empty
+        if self.size < held {
+            self.cursor.set(self.size);
+            let mut end_block = self.get_nth_block_locked(fs, self.cursor.block())?;
+            empty_block_data(&mut end_block, self.cursor.byte());
+            fs.flush_block(&end_block)?;
+        }
+        // New capacity exceeds existing blocks
+        if new_capacity > held {
+            self.append_blocks(fs, (new_capacity - held).div_ceil(bytes_per_block))?;
         }
         self.size = new_capacity;
         self.cursor.set(previous_cursor);
         Ok(())
     }
 
+    /// Acquire blocks for the first `end` bytes of the file without changing
+    /// its size, using an already locked filesystem
+    ///
+    /// Blocks past the end of the file stay linked to it until it is shrunk,
+    /// and writes appending to it fill them before acquiring new ones.
+    pub(crate) fn preallocate_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        end: u64,
+    ) -> Result<(), Error> {
+        let blocks = end.div_ceil(bytes_per_block(fs.superblock.block_size));
+        if blocks <= self.block_count {
+            return Ok(());
+        }
+        debug!(
+            "Preallocate {} blocks of raw byte file",
+            blocks - self.block_count
+        );
+        self.check_space(fs, end)?;
+        self.spill_locked(fs)?;
+        self.unshare_locked(fs)?;
+        if self.first_block == NULL_BLOCK {
+            self.initialize_locked(fs)?;
+        }
+        self.append_blocks(fs, blocks.saturating_sub(self.block_count))
+    }
+
     /// Shrink the file to a new capacity
     /// Seeking cursor's position will be kept only if it remains inside shrinked file,
     /// otherwise it is set to zero
     pub fn shrink(&mut self, new_capacity: u64) -> Result<(), Error> {
+        let filesystem = self.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.shrink_locked(&mut fs_handle, new_capacity)
+    }
+
+    /// Shrink the file to a new capacity using an already locked filesystem
+    pub(crate) fn shrink_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        new_capacity: u64,
+    ) -> Result<(), Error> {
         if new_capacity == self.size {
             return Ok(());
         }
@@ -249,21 +507,30 @@ impl RawByteFile {
             "Shrink raw byte file from {} to {new_capacity} bytes",
             self.size
         );
+        if self.inline_resize(new_capacity) {
+            return Ok(());
+        }
+        match new_capacity {
+            0 if self.detach_locked(fs)? => return Ok(()),
+            _ => self.unshare_locked(fs)?,
+        }
         let previous_cursor = self.cursor.position();
         self.cursor.set(new_capacity);
-        let last_block = self.get_nth_block(self.cursor.block())?;
-        let mut fs_handle = self.filesystem.lock()?;
+        let mut last_block = self.get_nth_block_locked(fs, self.cursor.block())?;
         let block_delta = self.block_count - (self.cursor.block() + 1);
         // Check if blocks have to be released
         if block_delta > 0 {
             let mut current_block = get_next_block(&last_block);
             for _ in 0..block_delta {
                 assert_ne!(current_block, NULL_BLOCK);
-                let block = fs_handle.load_block(current_block, false)?;
-                fs_handle.release_block(block.index)?;
+                let block = fs.load_block(current_block, false)?;
+                fs.release_block(block.index, self.owner)?;
                 self.block_count -= 1;
                 current_block = get_next_block(&block);
             }
+            // Released blocks must not be reached by following the chain
+            set_next_block(&mut last_block, NULL_BLOCK);
+            fs.flush_block(&last_block)?;
         }
         if new_capacity > 0 {
             self.size = new_capacity;
@@ -275,34 +542,148 @@ impl RawByteFile {
             }
         } else {
             assert_eq!(self.first_block, last_block.index);
-            fs_handle.release_block(self.first_block)?;
+            fs.release_block(self.first_block, self.owner)?;
             self.block_count -= 1;
             assert_eq!(self.block_count, 0);
             self.size = 0;
             self.first_block = NULL_BLOCK;
             self.last_block = NULL_BLOCK;
             self.cursor.reset();
+            // Only regular files have tables, and they keep small contents in inode
+            if self.tables.is_some() && inline_data::supported(fs) {
+                self.inline = Some(Vec::new());
+            }
+        }
+        // Released blocks must not be found by later lookups either
+        self.block_map.split_off(&self.block_count);
+        self.table_truncate(fs, self.block_count)
+    }
+
+    /// Copy blocks shared with clones of the file to blocks of its own, so
+    /// that modifying them leaves the clones as they are
+    fn unshare_locked(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
+        if self.first_block == NULL_BLOCK || !fs.shares.is_shared(self.first_block) {
+            return Ok(());
         }
+        debug!("Copy {} shared blocks of raw byte file", self.block_count);
+        let shared = self.blocks_locked(fs)?.len() as u64;
+        let mut copy = Self {
+            first_block: NULL_BLOCK,
+            last_block: NULL_BLOCK,
+            block_count: 0,
+            size: 0,
+            cursor: self.cursor.clone(),
+            filesystem: self.filesystem.clone(),
+            tables: self.tables.and(BlockTables::new(fs)),
+            inline: None,
+            owner: self.owner,
+            metadata: self.metadata,
+            block_map: BTreeMap::new(),
+            compress: self.compress,
+        };
+        // Blocks of the copy are acquired at once, then filled one by one
+        let copied = copy.extend_locked(fs, self.size).and_then(|_| {
+            let (mut from, mut to) = (self.first_block, copy.first_block);
+            for _ in 0..self.block_count.min(copy.block_count) {
+                let source = fs.load_block(from, false)?;
+                let mut block = fs.load_block(to, false)?;
+                block.data[BYTES_IN_U64..].copy_from_slice(&source.data[BYTES_IN_U64..]);
+                fs.flush_block(&block)?;
+                (from, to) = (get_next_block(&source), get_next_block(&block));
+            }
+            Ok(())
+        });
+        if let Err(e) = copied {
+            copy.shrink_locked(fs, 0)?;
+            return Err(e);
+        }
+        fs.shares.release(self.first_block);
+        fs.quotas.release(self.owner, shared, 0);
+        self.first_block = copy.first_block;
+        self.last_block = copy.last_block;
+        self.block_count = copy.block_count;
+        self.tables = copy.tables;
+        self.block_map = copy.block_map;
         Ok(())
     }
 
+    /// Let go of blocks shared with clones of the file, leaving it empty
+    ///
+    /// Returns false, changing nothing, if the file holds its blocks alone.
+    fn detach_locked(&mut self, fs: &mut Filesystem) -> Result<bool, Error> {
+        if self.first_block == NULL_BLOCK || !fs.shares.is_shared(self.first_block) {
+            return Ok(false);
+        }
+        let shared = self.blocks_locked(fs)?.len() as u64;
+        debug!("Release {shared} shared blocks of raw byte file");
+        fs.shares.release(self.first_block);
+        fs.quotas.release(self.owner, shared, 0);
+        self.first_block = NULL_BLOCK;
+        self.last_block = NULL_BLOCK;
+        self.block_count = 0;
+        self.size = 0;
+        self.cursor.reset();
+        self.block_map.clear();
+        if self.tables.is_some() {
+            self.tables = BlockTables::new(fs);
+            if inline_data::supported(fs) {
+                self.inline = Some(Vec::new());
+            }
+        }
+        Ok(true)
+    }
+
     /// Remove file for given [Inode] index
     pub fn remove(fs: &Arc<Mutex<Filesystem>>, inode: u64) -> Result<(), Error> {
         debug!("Remove raw byte file for inode {inode}");
-        let inode = {
-            let mut fs_handle = fs.lock()?;
-            fs_handle.load_inode(inode)?
-        };
-        let mut file = Self::load(fs, inode)?;
-        file.shrink(0)?;
+        let mut fs_handle = fs.lock_fs()?;
+        let inode = fs_handle.load_inode(inode)?;
+        let mut file = Self::load_locked(&fs_handle, fs, inode);
+        file.shrink_locked(&mut fs_handle, 0)?;
         assert_eq!(file.first_block, NULL_BLOCK);
         Ok(())
     }
 
+    /// Release all blocks held by file, using an already locked filesystem
+    pub(crate) fn release_locked(&self, fs: &mut Filesystem) -> Result<(), Error> {
+        for block in self.blocks_locked(fs)? {
+            fs.release_block(block, self.owner)?;
+        }
+        Ok(())
+    }
+
+    /// Indices of all blocks held by file, including its tables, using an already locked filesystem
+    ///
+    /// Fails with [Error::Corruption] if the chain of blocks disagrees with block count
+    pub(crate) fn blocks_locked(&self, fs: &mut Filesystem) -> Result<Vec<u64>, Error> {
+        let mut blocks = Vec::with_capacity(self.block_count as usize);
+        let mut index = self.first_block;
+        while index != NULL_BLOCK && (blocks.len() as u64) < self.block_count {
+            blocks.push(index);
+            index = get_next_block(&fs.load_block(index, false)?);
+        }
+        if blocks.len() as u64 != self.block_count
+            || blocks.last().unwrap_or(&NULL_BLOCK) != &self.last_block
+        {
+            return Err(Error::Corruption);
+        }
+        if let Some(tables) = self.tables {
+            blocks.extend(tables.blocks(fs)?);
+        }
+        Ok(blocks)
+    }
+
     /// Update [Inode]'s block pointers
     pub fn update_inode(&self, inode: &mut Inode) {
+        if let Some(data) = &self.inline {
+            inline_data::store(inode, data);
+            return;
+        }
         inode.first_block = self.first_block;
         inode.last_block = self.last_block;
+        if let Some(tables) = self.tables {
+            tables.update_inode(inode);
+        }
     }
 }
 
@@ -312,10 +693,7 @@ impl Seek for RawByteFile {
         Ok(match pos {
             std::io::SeekFrom::Start(bytes) => {
                 if bytes > self.size {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "out of bounds",
-                    ));
+                    return Err(std::io::Error::other("out of bounds"));
                 }
                 self.cursor.set(bytes)
             }
@@ -323,18 +701,12 @@ impl Seek for RawByteFile {
                 self.cursor.set(self.size);
                 if bytes > 0 {
                     if self.cursor.position() + bytes as u64 >= self.size {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "out of bounds",
-                        ));
+                        return Err(std::io::Error::other("out of bounds"));
                     }
                     self.cursor.advance(bytes as u64)
                 } else if bytes < 0 {
                     if self.cursor.position() as i64 + bytes < 0 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "out of bounds",
-                        ));
+                        return Err(std::io::Error::other("out of bounds"));
                     }
                     self.cursor.regress((-bytes) as u64)
                 } else {
@@ -344,18 +716,12 @@ impl Seek for RawByteFile {
             std::io::SeekFrom::Current(bytes) => {
                 if bytes > 0 {
                     if self.cursor.position() + bytes as u64 >= self.size {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "out of bounds",
-                        ));
+                        return Err(std::io::Error::other("out of bounds"));
                     }
                     self.cursor.advance(bytes as u64)
                 } else if bytes < 0 {
                     if self.cursor.position() as i64 + bytes < 0 {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "out of bounds",
-                        ));
+                        return Err(std::io::Error::other("out of bounds"));
                     }
                     self.cursor.regress((-bytes) as u64)
                 } else {
@@ -368,7 +734,7 @@ impl Seek for RawByteFile {
 
 #[cfg(test)]
 mod test {
-    use super::{Filesystem, RawByteFile};
+    use super::{get_next_block, Error, Filesystem, Owner, RawByteFile, NULL_BLOCK};
     use std::{
         io::{Cursor, Seek},
         sync::{Arc, Mutex},
@@ -379,7 +745,7 @@ mod test {
         let dev = Cursor::new(vec![0u8; 100_000]);
         let fs = Filesystem::new(Box::new(dev), 100_000, 512);
         let fs_handle = Arc::new(Mutex::new(fs));
-        let mut file = RawByteFile::with_capacity(&fs_handle, 10_000).unwrap();
+        let mut file = RawByteFile::with_capacity(&fs_handle, Owner::default(), 10_000).unwrap();
         assert_eq![file.seek(std::io::SeekFrom::Start(1_000)).unwrap(), 1_000];
         assert_eq![file.seek(std::io::SeekFrom::Current(111)).unwrap(), 1_111];
         assert_eq![file.seek(std::io::SeekFrom::Current(-50)).unwrap(), 1_061];
@@ -391,11 +757,38 @@ mod test {
     }
 
     #[test]
-    fn extend_and_shrink() {
+    fn fail_before_running_out() {
         let dev = Cursor::new(vec![0u8; 100_000]);
         let fs = Filesystem::new(Box::new(dev), 100_000, 512);
         let fs_handle = Arc::new(Mutex::new(fs));
-        let mut file = RawByteFile::new(&fs_handle).unwrap();
+        let mut file = RawByteFile::new_regular(&fs_handle, Owner::default()).unwrap();
+        file.write(&[1; 1_000]).unwrap();
+        let (block_count, blocks_free) = (file.block_count, {
+            fs_handle.lock().unwrap().superblock.blocks_free
+        });
+        let too_large = vec![2; (blocks_free as usize + 1) * 512];
+        assert!(matches!(file.write(&too_large), Err(Error::OutOfMemory)));
+        assert!(matches!(
+            file.extend(too_large.len() as u64),
+            Err(Error::OutOfMemory)
+        ));
+        assert_eq!((file.size, file.block_count), (1_000, block_count));
+        assert_eq!(
+            { fs_handle.lock().unwrap().superblock.blocks_free },
+            blocks_free
+        );
+        let mut contents = vec![0; 1_000];
+        file.seek(std::io::SeekFrom::Start(0)).unwrap();
+        file.read(&mut contents).unwrap();
+        assert_eq!(contents, [1; 1_000]);
+    }
+
+    #[test]
+    fn extend_and_shrink() {
+        let dev = Cursor::new(vec![0u8; 120_000]);
+        let fs = Filesystem::new(Box::new(dev), 120_000, 512);
+        let fs_handle = Arc::new(Mutex::new(fs));
+        let mut file = RawByteFile::new(&fs_handle, Owner::default()).unwrap();
         assert_eq!(file.block_count, 0);
         _ = file.extend(1024);
         assert_eq!(file.block_count, 3);
@@ -405,6 +798,8 @@ mod test {
         assert_eq!(file.block_count, 159);
         _ = file.shrink(2000);
         assert_eq!(file.block_count, 4);
+        let last_block = file.get_nth_block(3).unwrap();
+        assert_eq!(get_next_block(&last_block), NULL_BLOCK);
         _ = file.shrink(1800);
         assert_eq!(file.block_count, 4);
         _ = file.shrink(100);
@@ -414,7 +809,7 @@ mod test {
         _ = file.shrink(0);
         assert_eq!(file.block_count, 0);
         drop(file);
-        let mut file = RawByteFile::with_capacity(&fs_handle, 50_000).unwrap();
+        let mut file = RawByteFile::with_capacity(&fs_handle, Owner::default(), 50_000).unwrap();
         assert_eq!(file.block_count, 100);
         _ = file.extend(60_000);
         assert_eq!(file.block_count, 120);
@@ -428,6 +823,37 @@ mod test {
         assert_eq!(blocks_used, 0);
     }
 
+    #[test]
+    fn resolve_blocks_through_map() {
+        let dev = Cursor::new(vec![0u8; 120_000]);
+        let fs = Filesystem::new(Box::new(dev), 120_000, 512);
+        let fs_handle = Arc::new(Mutex::new(fs));
+        let mut file = RawByteFile::with_capacity(&fs_handle, Owner::default(), 50_000).unwrap();
+        assert_eq!(file.block_map.len(), 100);
+        file.block_map.clear();
+        let walked = file.get_nth_block(50).unwrap().index;
+        assert_eq!(
+            file.block_map.keys().copied().collect::<Vec<_>>(),
+            (0..=50).collect::<Vec<_>>()
+        );
+        // Walk continues from the nearest mapped block
+        file.block_map.remove(&0);
+        assert_eq!(file.get_nth_block(60).unwrap().index, walked + 10);
+        assert!(!file.block_map.contains_key(&0));
+        assert_eq!(file.get_nth_block(50).unwrap().index, walked);
+        file.shrink(20_000).unwrap();
+        assert_eq!(
+            file.block_map
+                .last_key_value()
+                .map(|(&position, _)| position),
+            Some(39)
+        );
+        file.extend(30_000).unwrap();
+        assert_eq!(file.block_map.len(), 59);
+        let last = file.get_nth_block(59).unwrap().index;
+        assert_eq!(file.block_map[&59], last);
+    }
+
     #[test]
     fn write_and_read() {
         let dev = Cursor::new(vec![0u8; 20_000_000]);
@@ -437,8 +863,12 @@ mod test {
             for write_buffer in (400..=100_000).step_by(2017) {
                 for read_buffer in (201..=write_buffer - 100).step_by(1013) {
                     for seek in (30..50).step_by(1013) {
-                        let mut file =
-                            RawByteFile::with_capacity(&fs_handle, capacity * 123).unwrap();
+                        let mut file = RawByteFile::with_capacity(
+                            &fs_handle,
+                            Owner::default(),
+                            capacity * 123,
+                        )
+                        .unwrap();
                         let buff = (1..=write_buffer)
                             .map(|v| (v / 504 + 1) as u8)
                             .collect::<Vec<u8>>();
@@ -460,7 +890,7 @@ mod test {
         let dev = Cursor::new(vec![0u8; 128_000_000]);
         let fs = Filesystem::new(Box::new(dev), 128_000_000, 512);
         let fs_handle = Arc::new(Mutex::new(fs));
-        let mut file: RawByteFile = RawByteFile::new(&fs_handle).unwrap();
+        let mut file: RawByteFile = RawByteFile::new(&fs_handle, Owner::default()).unwrap();
         for pow2 in 7..=25 {
             file.shrink(0).unwrap();
             let chunk_size = 2u64.pow(pow2) + 1000;
@@ -1,105 +1,220 @@
-use super::{helpers::*, FileOperations, RawByteFile, RegularFile};
+use super::{
+    allocation::InodeAllocation, helpers::*, FileOperations, Owner, RawByteFile, RegularFile,
+};
+use crate::filesystem::LockFilesystem;
 use crate::filetypes::Directory;
-use crate::structs::{Inode, NULL_BLOCK};
+use crate::structs::{Inode, MountOptions, NULL_BLOCK};
 use crate::{Error, Filesystem};
 
 use fuser::FileType;
 use log::{debug, error};
-use std::io::Seek;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex};
 
 impl RegularFile {
     pub fn read(&mut self, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
-        if self.file.seek(std::io::SeekFrom::Start(offset))? != offset {
+        let filesystem = self.file.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.read_locked(&mut fs_handle, offset, size)
+    }
+
+    /// Read file's contents using an already locked filesystem
+    pub(crate) fn read_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        self.read_into_locked(fs, offset, size, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Read file's contents into `buffer` using an already locked filesystem,
+    /// reusing its allocation and truncating it to the number of read bytes
+    pub(crate) fn read_into_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        offset: u64,
+        size: u64,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if offset > self.file.size {
+            return Err(Error::InsufficientBytes);
+        }
+        buffer.clear();
+        buffer.resize(size.min(self.file.size - offset) as usize, 0);
+        self.read_slice_locked(fs, offset, buffer)
+    }
+
+    /// Fill whole `buffer` with file's contents starting at `offset` using an
+    /// already locked filesystem
+    pub(crate) fn read_slice_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        if self.file.seek(SeekFrom::Start(offset))? != offset {
             return Err(Error::InsufficientBytes);
         };
-        let lookahead_size = self.file.size - self.file.cursor.current();
-        let mut buffer;
-        if size > lookahead_size {
-            buffer = vec![0; lookahead_size as usize];
-        } else {
-            buffer = vec![0; size as usize];
+        self.file.read_locked(fs, buffer)?;
+        self.access_locked(fs)
+    }
+
+    /// Update access time after a read if policy of the mount requires it,
+    /// flushing only the access time so unsynced changes of file stay unflushed
+    fn access_locked(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
+        let now = timestamp_now();
+        let update = match fs.options {
+            _ if fs.read_only => false,
+            options if options.contains(MountOptions::NOATIME) => false,
+            options if options.contains(MountOptions::STRICTATIME) => true,
+            _ => self.inode.atime_outdated(now),
+        };
+        if !update {
+            return Ok(());
         }
-        self.inode.atime = timestamp_now();
-        self.file.read(&mut buffer)?;
-        Ok(buffer)
+        self.inode.set_atime(now);
+        let mut inode = fs.load_inode(self.inode.index)?;
+        inode.set_atime(now);
+        fs.flush_inode(&inode)
     }
 
     pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let filesystem = self.file.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.write_locked(&mut fs_handle, offset, data)
+    }
+
+    /// Write file's contents using an already locked filesystem
+    pub(crate) fn write_locked(
+        &mut self,
+        fs: &mut Filesystem,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.inode.check_write(offset, self.file.size)?;
         self.modified = true;
-        if self.file.seek(std::io::SeekFrom::Start(offset))? != offset {
+        if self.file.seek(SeekFrom::Start(offset))? != offset {
             return Err(Error::InsufficientBytes);
         };
-        self.inode.atime = timestamp_now();
-        self.inode.mtime = timestamp_now();
-        self.file.write(data)?;
+        let now = timestamp_now();
+        self.inode.set_atime(now);
+        self.inode.set_mtime(now);
+        self.file.write_locked(fs, data)?;
         Ok(())
     }
 
+    /// Load file with given [Inode] index using an already locked filesystem
+    pub(crate) fn load_locked(
+        fs_handle: &mut Filesystem,
+        fs: &Arc<Mutex<Filesystem>>,
+        index: u64,
+    ) -> Result<Self, Error> {
+        let inode = fs_handle.load_inode(index)?;
+        if inode.r#type == FileType::Directory {
+            return Err(Error::IsDirectory);
+        }
+        Ok(Self::from_inode_locked(fs_handle, fs, inode))
+    }
+
+    /// Open file for an already loaded [Inode] using a locked filesystem
+    pub(crate) fn from_inode_locked(
+        fs_handle: &Filesystem,
+        fs: &Arc<Mutex<Filesystem>>,
+        inode: Inode,
+    ) -> Self {
+        let file = RawByteFile::load_locked(fs_handle, fs, inode);
+        Self {
+            inode,
+            file,
+            position: 0,
+            modified: false,
+            removed: false,
+        }
+    }
+
+    /// Copy file's size and block pointers into its [Inode] without flushing it
+    pub(crate) fn sync_inode(&mut self) {
+        self.modified = false;
+        self.file.update_inode(&mut self.inode);
+        self.inode.set_mtime(timestamp_now());
+        self.inode.block_count = self.file.block_count;
+        self.inode.size = self.file.size;
+    }
+
+    /// Flush file's [Inode] using an already locked filesystem
+    pub(crate) fn flush_locked(&mut self, fs: &mut Filesystem) -> Result<(), Error> {
+        let index = self.inode.index;
+        debug!("Flush regular file {index}");
+        self.sync_inode();
+        fs.flush_inode(&self.inode)
+    }
+
     pub fn remove(mut self) -> Result<(), Error> {
         RawByteFile::remove(&self.file.filesystem, self.inode.index)?;
-        let mut fs_handle = self.file.filesystem.lock()?;
-        fs_handle.release_inode(self.inode.index)?;
+        let mut fs_handle = self.file.filesystem.lock_fs()?;
+        fs_handle.release_inode(self.inode.index, Owner::from(&self.inode))?;
         self.removed = true;
         Ok(())
     }
 }
 
 impl FileOperations for RegularFile {
-    fn new(fs: &Arc<Mutex<Filesystem>>, parent: u64, name: &str, mode: u32) -> Result<Self, Error> {
+    fn new(
+        fs: &Arc<Mutex<Filesystem>>,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        owner: Owner,
+    ) -> Result<Self, Error> {
         let now = timestamp_now();
-        let inode = fs.lock()?.acquire_inode()?;
-        let file = RawByteFile::new(fs)?;
-        Directory::load(fs, parent)?.add_child(name, inode)?;
-        let inode = Inode {
-            index: inode,
+        let mut parent_dir = Directory::load(fs, parent)?;
+        let owner = owner.inherit(&parent_dir.inode);
+        let mode = mode & !parent_dir.mode_mask().unwrap_or(0);
+        let allocation = InodeAllocation::acquire(fs, owner)?;
+        let file = RawByteFile::new_regular(fs, owner)?;
+        let mut inode = Inode {
+            index: allocation.index(),
             mode: mode as u16,
             r#type: FileType::RegularFile,
             size: 0,
-            uid: 0,
-            gid: 0,
-            atime: now,
-            ctime: now,
-            mtime: now,
+            uid: owner.uid,
+            gid: owner.gid,
             dtime: u64::MAX,
-            block_count: 1,
+            block_count: file.block_count,
             metadata: [parent, NULL_BLOCK, NULL_BLOCK, NULL_BLOCK, NULL_BLOCK],
+            checksum: 0,
             __padding_1: Default::default(),
             first_block: file.first_block,
             last_block: file.last_block,
+            ..Default::default()
         };
-        fs.lock()?.flush_inode(&inode)?;
+        inode.set_created(now);
+        fs.lock_fs()?.flush_inode(&inode)?;
+        // Link into parent only once the inode is written
+        parent_dir.add_child(name, inode.index)?;
+        drop(parent_dir);
+        allocation.commit();
         Ok(Self {
             inode,
             file,
+            position: 0,
             modified: true,
             removed: false,
         })
     }
 
     fn load(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Result<Self, Error> {
-        let mut fs_handle = fs.lock()?;
-        let inode = fs_handle.load_inode(index)?;
-        drop(fs_handle);
-        let file = RawByteFile::load(fs, inode)?;
-        Ok(Self {
-            inode,
-            file,
-            modified: false,
-            removed: false,
-        })
+        let mut fs_handle = fs.lock_fs()?;
+        Self::load_locked(&mut fs_handle, fs, index)
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        self.modified = false;
-        let index = self.inode.index;
-        debug!("Flush regular file {index}");
-        self.file.update_inode(&mut self.inode);
-        self.inode.mtime = timestamp_now();
-        self.inode.block_count = self.file.block_count;
-        self.inode.size = self.file.size;
-        self.file.filesystem.lock()?.flush_inode(&self.inode)?;
-        Ok(())
+        let filesystem = self.file.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        self.flush_locked(&mut fs_handle)
     }
 
     fn remove(mut self) -> Result<(), Error> {
@@ -109,13 +224,66 @@ impl FileOperations for RegularFile {
         Directory::load(&self.file.filesystem, self.inode.metadata[0])?.remove_child(
             crate::filetypes::DirectoryChildIdentifier::Inode(self.inode.index),
         )?;
-        let mut fs_handle = self.file.filesystem.lock()?;
-        fs_handle.release_inode(self.inode.index)?;
+        let mut fs_handle = self.file.filesystem.lock_fs()?;
+        fs_handle.release_inode(self.inode.index, Owner::from(&self.inode))?;
         self.removed = true;
         Ok(())
     }
 }
 
+/// Reads advance from the start of the file, stopping at its end
+impl Read for RegularFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = (buf.len() as u64).min(self.file.size.saturating_sub(self.position));
+        if size == 0 {
+            return Ok(0);
+        }
+        let filesystem = self.file.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        let buffer = &mut buf[..size as usize];
+        self.read_slice_locked(&mut fs_handle, self.position, buffer)?;
+        self.position += size;
+        Ok(size as usize)
+    }
+}
+
+/// Writes past the end of the file extend it, filling the gap with zeros
+impl Write for RegularFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let filesystem = self.file.filesystem.clone();
+        let mut fs_handle = filesystem.lock_fs()?;
+        if self.position > self.file.size {
+            self.inode.check_write(self.position, self.file.size)?;
+            self.file.extend_locked(&mut fs_handle, self.position)?;
+            self.modified = true;
+        }
+        self.write_locked(&mut fs_handle, self.position, buf)?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    /// Flush file's [Inode], while its blocks are written back with the cache
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(FileOperations::flush(self)?)
+    }
+}
+
+/// Seeking past the end of the file is allowed, as with [std::fs::File]
+impl Seek for RegularFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.file.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        Ok(self.position)
+    }
+}
+
 impl Drop for RegularFile {
     fn drop(&mut self) {
         let index = self.inode.index;
@@ -123,8 +291,105 @@ impl Drop for RegularFile {
             debug!("Skip flushing for dropped regular file {index}");
             return;
         }
-        if let Err(e) = self.flush() {
+        if let Err(e) = FileOperations::flush(self) {
             error!("Error flushing dropped regular file {index}: {e}")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex};
+
+    use std::time::Duration;
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{timestamp_now, FileOperations, Owner, RegularFile};
+    use crate::structs::MountOptions;
+
+    #[test]
+    fn stream_contents() {
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 4_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "f", 0o644, Owner::default()).unwrap();
+        let lines: Vec<String> = (0..200).map(|i| format!("line {i}")).collect();
+        for line in &lines {
+            writeln!(file, "{line}").unwrap();
+        }
+        Write::flush(&mut file).unwrap();
+        let size = file.inode.size;
+        assert_eq!(file.stream_position().unwrap(), size);
+
+        file.rewind().unwrap();
+        let read: Vec<String> = BufReader::new(&mut file)
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read, lines);
+        assert_eq!(Read::read(&mut file, &mut [0u8; 10]).unwrap(), 0);
+
+        // Writing past the end leaves a gap of zeros
+        file.seek(SeekFrom::End(1000)).unwrap();
+        file.write_all(b"end").unwrap();
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(size)).unwrap();
+        std::io::copy(&mut file, &mut contents).unwrap();
+        assert_eq!(contents.len(), 1003);
+        assert!(contents[..1000].iter().all(|&byte| byte == 0));
+        assert_eq!(&contents[1000..], b"end");
+        assert!(file.seek(SeekFrom::Current(-5000)).is_err());
+        let index = file.inode.index;
+        drop(file);
+
+        let mut file = RegularFile::load(&fs, index).unwrap();
+        assert_eq!({ file.inode.size }, size + 1003);
+        let mut buffer = [0u8; 7];
+        file.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"line 0\n");
+    }
+
+    #[test]
+    fn atime_policies() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let mut file = RegularFile::new(&fs, ROOT_INODE, "f", 0o644, Owner::default()).unwrap();
+        file.write(0, b"contents").unwrap();
+        FileOperations::flush(&mut file).unwrap();
+        let index = file.inode.index;
+        drop(file);
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        // Whether a read updates access time `age` old of a file modified three days ago
+        let updates = |options: MountOptions, age: Duration| {
+            let now = timestamp_now();
+            let mut fs_handle = fs.lock().unwrap();
+            fs_handle.options = options;
+            let mut inode = fs_handle.load_inode(index).unwrap();
+            inode.set_mtime(now - 3 * day);
+            inode.set_ctime(now - 3 * day);
+            inode.set_atime(now - age);
+            fs_handle.flush_inode(&inode).unwrap();
+            drop(fs_handle);
+            let mut file = RegularFile::load(&fs, index).unwrap();
+            assert_eq!(file.read(0, 8).unwrap(), b"contents");
+            drop(file);
+            let atime = fs.lock().unwrap().load_inode(index).unwrap().atime;
+            atime != (now - age).as_secs()
+        };
+        let relatime = MountOptions::default();
+        assert!(updates(relatime, 2 * day));
+        assert!(updates(relatime, 4 * day));
+        assert!(!updates(relatime, Duration::from_secs(3600)));
+        assert!(updates(
+            MountOptions::STRICTATIME,
+            Duration::from_secs(3600)
+        ));
+        assert!(!updates(MountOptions::NOATIME, 4 * day));
+        let mut both = MountOptions::NOATIME;
+        both.set(MountOptions::STRICTATIME, true);
+        assert!(!updates(both, 4 * day));
+    }
+}
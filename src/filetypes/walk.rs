@@ -0,0 +1,161 @@
+//! Recursive traversal of a directory's subtree
+//!
+//! A [Walk] visits a directory and everything below it depth first, each
+//! directory before its children and children ordered by name, so every path
+//! is yielded after the one of its parent. Inodes linked more than once, such
+//! as directories of a damaged tree forming a loop, are visited only once.
+//! Entries which cannot be loaded are yielded as errors, and the walk goes on
+//! with the rest of the tree.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use fuser::FileType;
+use log::warn;
+
+use super::{Directory, FileOperations};
+use crate::filesystem::archive::child_path;
+use crate::filesystem::LockFilesystem;
+use crate::{Error, Filesystem};
+
+#[derive(Debug)]
+pub struct Walk {
+    fs: Arc<Mutex<Filesystem>>,
+    /// Paths, inodes and depths of entries left to visit, the next one last
+    pending: Vec<(String, u64, u64)>,
+    visited: BTreeSet<u64>,
+    /// Directories this deep are yielded without descending into them
+    max_depth: u64,
+}
+
+impl Directory {
+    /// Walk this directory and its subtree, yielding it with an empty path
+    /// followed by paths of its descendants relative to it
+    pub fn walk(&self) -> Walk {
+        Walk::new(&self.file.filesystem, self.inode.index)
+    }
+}
+
+impl Walk {
+    /// Walk subtree of directory with inode `index`
+    pub fn new(fs: &Arc<Mutex<Filesystem>>, index: u64) -> Self {
+        Self {
+            fs: fs.clone(),
+            pending: vec![(String::new(), index, 0)],
+            visited: BTreeSet::new(),
+            max_depth: u64::MAX,
+        }
+    }
+
+    /// Yield only entries at most `depth` directories below the walked one
+    pub fn with_max_depth(mut self, depth: u64) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Type of entry, queueing children of directories
+    fn visit(&mut self, path: &str, index: u64, depth: u64) -> Result<FileType, Error> {
+        let kind = self.fs.lock_fs()?.load_inode(index)?.r#type;
+        if kind == FileType::Directory && depth < self.max_depth {
+            let directory = Directory::load(&self.fs, index)?;
+            let mut children: Vec<_> = directory
+                .children
+                .iter()
+                .map(|child| (child_path(path, &child.name), child.inode, depth + 1))
+                .collect();
+            children.sort_by(|a, b| b.0.cmp(&a.0));
+            self.pending.extend(children);
+        }
+        Ok(kind)
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<(String, u64, FileType), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (path, index, depth) = self.pending.pop()?;
+            if !self.visited.insert(index) {
+                warn!("Skipping {path:?} linked more than once");
+                continue;
+            }
+            return Some(
+                self.visit(&path, index, depth)
+                    .map(|kind| (path, index, kind)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use fuser::FileType;
+
+    use crate::filesystem::{Filesystem, ROOT_INODE};
+    use crate::filetypes::{Directory, FileOperations, Owner};
+
+    #[test]
+    fn walk_subtree() {
+        let dev = Cursor::new(vec![0u8; 4_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 4_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let owner = Owner::default();
+        Filesystem::create_dir_all(&fs, "/b/d", owner).unwrap();
+        Filesystem::write(&fs, "/b/c", b"c", owner).unwrap();
+        Filesystem::write(&fs, "/a", b"a", owner).unwrap();
+
+        let root = Directory::load(&fs, ROOT_INODE).unwrap();
+        let entries: Vec<_> = root
+            .walk()
+            .map(|entry| {
+                let (path, index, kind) = entry.unwrap();
+                assert_eq!(Filesystem::resolve(&fs, &path).unwrap(), index);
+                (path, kind)
+            })
+            .collect();
+        let expected = [
+            ("", FileType::Directory),
+            ("a", FileType::RegularFile),
+            ("b", FileType::Directory),
+            ("b/c", FileType::RegularFile),
+            ("b/d", FileType::Directory),
+        ];
+        assert_eq!(
+            entries,
+            expected.map(|(path, kind)| (path.to_owned(), kind))
+        );
+        let shallow: Vec<_> = root
+            .walk()
+            .with_max_depth(1)
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(shallow, ["", "a", "b"]);
+        drop(root);
+
+        // Directory linked into its own subtree is visited once
+        let index = Filesystem::resolve(&fs, "/b").unwrap();
+        let mut directory = Filesystem::open_dir(&fs, "/b/d").unwrap();
+        directory.add_child("loop", index).unwrap();
+        directory.flush().unwrap();
+        drop(directory);
+        let entries: Vec<_> = Directory::load(&fs, index)
+            .unwrap()
+            .walk()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(entries, ["", "c", "d"]);
+
+        // Entries failing to load do not end the walk
+        let mut directory = Filesystem::open_dir(&fs, "/b/d").unwrap();
+        directory.add_child("broken", 900).unwrap();
+        directory.flush().unwrap();
+        drop(directory);
+        let entries: Vec<_> = Directory::load(&fs, ROOT_INODE).unwrap().walk().collect();
+        assert_eq!(entries.len(), 6);
+        assert!(entries[5].is_err());
+    }
+}
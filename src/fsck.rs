@@ -0,0 +1,52 @@
+//! Checking and repairing consistency of an unmounted filesystem
+//!
+//! Exit status follows `e2fsck`: 0 for a consistent filesystem, 1 when all
+//! found problems were repaired, 4 when problems are left and 8 when the
+//! check itself failed.
+
+use std::sync::{Arc, Mutex};
+
+use crate::devices::fence::{self, Access};
+use crate::filesystem::{Filesystem, LockFilesystem};
+use crate::Error;
+
+pub const EXIT_CLEAN: u8 = 0;
+pub const EXIT_REPAIRED: u8 = 1;
+pub const EXIT_UNCORRECTED: u8 = 4;
+pub const EXIT_FAILED: u8 = 8;
+
+/// Check filesystem on `device_path`, repairing it if `repair` is set
+pub fn fsck(device_path: &str, repair: bool) -> Result<u8, Error> {
+    let device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    // Mounted filesystem changes under the check and would undo repairs
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let mut device = device;
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let mut fs = Filesystem::load(Box::new(device), block_size)?;
+    if repair && fs.read_only {
+        return Err(Error::ReadOnly);
+    }
+    fs.read_only |= !repair;
+    let fs = Arc::new(Mutex::new(fs));
+    let report = match repair {
+        true => Filesystem::repair(&fs)?,
+        false => Filesystem::check(&fs)?,
+    };
+    println!("{report}");
+    let status = match (report.is_clean(), repair) {
+        (true, _) => EXIT_CLEAN,
+        (false, false) => return Ok(EXIT_UNCORRECTED),
+        (false, true) if Filesystem::check(&fs)?.is_clean() => EXIT_REPAIRED,
+        (false, true) => return Ok(EXIT_UNCORRECTED),
+    };
+    // Consistent filesystem needs no check on its next mount
+    if repair {
+        fs.lock_fs()?.mark_unmounted()?;
+    }
+    Ok(status)
+}
@@ -0,0 +1,44 @@
+//! Educational FUSE filesystem with little metadata
+//!
+//! A [Filesystem] is kept on any [BlockDevice], such as an image file or an
+//! in-memory [Cursor](std::io::Cursor), and shared between threads behind an
+//! `Arc<Mutex<_>>`. Regular files and directories are created, loaded and
+//! modified through [RegularFile] and [Directory], or the whole filesystem is
+//! mounted by handing a [FuseFs] to [fuser]. The binaries of this crate, the
+//! FUSE driver and its tools, are built on top of this library.
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use tananfs::{Directory, FileOperations, Filesystem, Owner, RegularFile, ROOT_INODE};
+//!
+//! # fn main() -> Result<(), tananfs::Error> {
+//! let device = std::io::Cursor::new(vec![0u8; 1 << 20]);
+//! let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(device), 1 << 20, 512)));
+//! Filesystem::format(&fs, Owner::default())?;
+//! let mut file = RegularFile::new(&fs, ROOT_INODE, "hello", 0o644, Owner::default())?;
+//! file.write(0, b"Hello, world!")?;
+//! file.flush()?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod cli;
+pub mod daemon;
+pub mod devices;
+pub mod error;
+pub mod filesystem;
+pub mod filetypes;
+pub mod fsck;
+pub mod logging;
+pub mod metrics;
+pub mod mkfs;
+pub mod mount;
+pub mod nbd;
+pub mod snapshot;
+pub mod stress;
+pub mod structs;
+pub mod tune;
+
+pub use error::Error;
+pub use filesystem::{BlockDevice, Filesystem, FuseFs, ROOT_INODE};
+pub use filetypes::{Directory, FileOperations, Owner, RegularFile};
@@ -0,0 +1,319 @@
+//! Logger collapsing repeated warnings and errors
+//!
+//! A failing block is usually hit by every retry and every operation touching
+//! it, each logging the same line. Consecutive identical messages are replaced
+//! by a single "last message repeated N times" line, and messages of the same
+//! class (equal up to numbers, such as inode indices) are limited per interval.
+//! Once an interval ends, pending repetitions and suppressed messages are
+//! reported and classes not seen since are forgotten, by the next message or
+//! a background thread if none follows. Messages below [Level::Warn] are
+//! passed through unchanged.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{Level, Log, Metadata, Record};
+
+/// Messages of a single class logged per [RATE_INTERVAL]
+pub const RATE_LIMIT: u32 = 10;
+pub const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Install [env_logger] behind deduplication
+pub fn init() {
+    let logger = env_logger::Builder::from_default_env().build();
+    let level = logger.filter();
+    let logger = Deduplicate::new(logger, RATE_LIMIT, RATE_INTERVAL);
+    log::set_boxed_logger(Box::new(logger))
+        .map(|()| log::set_max_level(level))
+        .expect("Logger already initialized");
+}
+
+#[derive(Debug)]
+pub struct Deduplicate<L: Log> {
+    inner: Arc<L>,
+    limit: u32,
+    interval: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Last logged message with its level and target
+    last: Option<(Level, String, String)>,
+    /// Number of times the last message was repeated since
+    repeated: u64,
+    /// When the last message was logged or its repetitions were last reported
+    reported: Option<Instant>,
+    /// Classes of messages logged during their current interval
+    classes: BTreeMap<String, Class>,
+    /// Process whose thread reports expired intervals, which is not
+    /// inherited when the driver forks into background
+    ticker: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Class {
+    level: Level,
+    target: String,
+    since: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl<L: Log> Deduplicate<L> {
+    pub fn new(inner: L, limit: u32, interval: Duration) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            limit,
+            interval,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+}
+
+impl<L: Log + 'static> Deduplicate<L> {
+    /// Report expired intervals every interval, even if nothing else is logged
+    fn spawn_ticker(&self) {
+        let (inner, interval) = (self.inner.clone(), self.interval);
+        let state = Arc::downgrade(&self.state);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let Ok(mut state) = state.lock() else {
+                return;
+            };
+            state.expire(&*inner, Instant::now(), interval);
+        });
+    }
+}
+
+impl State {
+    /// Report repetitions of the last message
+    fn flush_repeated(&mut self, inner: &impl Log) {
+        if let (Some((level, target, _)), repeated @ 1..) = (&self.last, self.repeated) {
+            emit(
+                inner,
+                *level,
+                target,
+                format_args!("last message repeated {repeated} times"),
+            );
+        }
+        self.repeated = 0;
+    }
+
+    /// Report repetitions and suppressed messages of intervals which ended,
+    /// forgetting classes not seen since
+    fn expire(&mut self, inner: &impl Log, now: Instant, interval: Duration) {
+        if self
+            .reported
+            .is_some_and(|reported| now.duration_since(reported) >= interval)
+        {
+            self.flush_repeated(inner);
+            self.reported = Some(now);
+        }
+        self.classes.retain(|name, class| {
+            let current = now.duration_since(class.since) < interval;
+            if !current {
+                flush_suppressed(inner, class.level, &class.target, name, class.suppressed);
+            }
+            current
+        });
+    }
+}
+
+fn emit(inner: &impl Log, level: Level, target: &str, message: std::fmt::Arguments) {
+    inner.log(
+        &Record::builder()
+            .level(level)
+            .target(target)
+            .args(message)
+            .build(),
+    );
+}
+
+/// Report messages suppressed by the rate limit of a class
+fn flush_suppressed(inner: &impl Log, level: Level, target: &str, class: &str, suppressed: u64) {
+    if suppressed > 0 {
+        emit(
+            inner,
+            level,
+            target,
+            format_args!("suppressed {suppressed} messages like: {class}"),
+        );
+    }
+}
+
+/// Class of a message, with every number replaced by `#`
+fn classify(message: &str) -> String {
+    let mut class = String::with_capacity(message.len());
+    for c in message.chars() {
+        if !c.is_ascii_digit() {
+            class.push(c);
+        } else if !class.ends_with('#') {
+            class.push('#');
+        }
+    }
+    class
+}
+
+impl<L: Log + 'static> Log for Deduplicate<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return self.inner.log(record);
+        };
+        if record.level() > Level::Warn {
+            return self.inner.log(record);
+        }
+        let process = std::process::id();
+        if state.ticker != Some(process) {
+            state.ticker = Some(process);
+            self.spawn_ticker();
+        }
+        let now = Instant::now();
+        state.expire(&*self.inner, now, self.interval);
+        let (level, target) = (record.level(), record.target());
+        let message = record.args().to_string();
+        if matches!(&state.last, Some((l, t, m)) if *l == level && t == target && *m == message) {
+            state.repeated += 1;
+            return;
+        }
+        state.flush_repeated(&*self.inner);
+
+        let name = classify(&message);
+        let class = state.classes.entry(name).or_insert_with(|| Class {
+            level,
+            target: target.to_owned(),
+            since: now,
+            logged: 0,
+            suppressed: 0,
+        });
+        if class.logged >= self.limit {
+            class.suppressed += 1;
+            return;
+        }
+        class.logged += 1;
+        self.inner.log(record);
+        state.last = Some((level, target.to_owned(), message));
+        state.reported = Some(now);
+    }
+
+    fn flush(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.flush_repeated(&*self.inner);
+            state.last = None;
+            for (name, class) in state.classes.iter_mut() {
+                let suppressed = std::mem::take(&mut class.suppressed);
+                flush_suppressed(&*self.inner, class.level, &class.target, name, suppressed);
+            }
+        }
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, thread, time::Duration};
+
+    use log::{Level, Log, Metadata, Record};
+
+    use super::Deduplicate;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Log for Recorder {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &Deduplicate<Recorder>, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn repeated_and_rate_limited() {
+        let logger = Deduplicate::new(Recorder::default(), 3, Duration::from_secs(3600));
+        for _ in 0..5 {
+            log(&logger, Level::Error, "Checksum mismatch for inode 7");
+        }
+        log(&logger, Level::Debug, "Load inode 7");
+        log(&logger, Level::Debug, "Load inode 7");
+        for index in 8..12 {
+            log(
+                &logger,
+                Level::Error,
+                &format!("Checksum mismatch for inode {index}"),
+            );
+        }
+        log(
+            &logger,
+            Level::Error,
+            "Filesystem lock is already held by this thread",
+        );
+        logger.flush();
+        assert_eq!(
+            *logger.inner.0.lock().unwrap(),
+            vec![
+                "Checksum mismatch for inode 7",
+                "Load inode 7",
+                "Load inode 7",
+                "last message repeated 4 times",
+                "Checksum mismatch for inode 8",
+                "Checksum mismatch for inode 9",
+                "Filesystem lock is already held by this thread",
+                "suppressed 2 messages like: Checksum mismatch for inode #",
+            ]
+        );
+    }
+
+    #[test]
+    fn report_expired_intervals() {
+        let logger = Deduplicate::new(Recorder::default(), 2, Duration::from_millis(100));
+        for index in 7..11 {
+            log(
+                &logger,
+                Level::Error,
+                &format!("Checksum mismatch for inode {index}"),
+            );
+        }
+        for _ in 0..3 {
+            log(&logger, Level::Warn, "Device stalled");
+        }
+        thread::sleep(Duration::from_millis(500));
+        assert!(logger.state.lock().unwrap().classes.is_empty());
+        assert_eq!(
+            *logger.inner.0.lock().unwrap(),
+            vec![
+                "Checksum mismatch for inode 7",
+                "Checksum mismatch for inode 8",
+                "Device stalled",
+                "last message repeated 2 times",
+                "suppressed 2 messages like: Checksum mismatch for inode #",
+            ]
+        );
+    }
+}
@@ -1,21 +1,193 @@
-#![allow(dead_code)]
-
-use filesystem::{Filesystem, FuseFs};
-use log::{error, info};
+use log::{error, info, warn};
 use std::{
+    io::{Seek, SeekFrom},
     os::unix::prelude::MetadataExt,
     sync::{Arc, Mutex},
 };
+use tananfs::filesystem::{
+    BlockDevice, Filesystem, FuseFs, Limits, LockFilesystem, Notifier, Scrubber, SyncMode,
+};
 
-use error::Error;
 use fuser::MountOption;
+use tananfs::error::Error;
+
+use tananfs::cli::{Arguments, Command, Flag};
+use tananfs::devices::concat::ConcatDevice;
+use tananfs::devices::direct::DirectDevice;
+use tananfs::devices::faulty::{self, Fault, FaultyDevice};
+use tananfs::devices::fence::{self, Access};
+use tananfs::devices::geometry::Geometry;
+use tananfs::devices::mem::{self, MemBlockDevice};
+use tananfs::devices::mirror::MirrorDevice;
+use tananfs::devices::signature;
+use tananfs::devices::undo::{self, UndoDevice};
+use tananfs::filetypes::Owner;
+use tananfs::mount::{self, HelperArguments, MountArguments};
+use tananfs::structs::{ChecksumAlgorithm, MountOptions, DEFAULT_BLOCK_SIZE};
+use tananfs::{daemon, fsck, logging, metrics, mkfs, snapshot, stress, tune};
+
+/// Flag without a value
+const fn switch(name: &'static str, help: &'static str) -> Flag {
+    Flag {
+        name,
+        value: None,
+        help,
+    }
+}
+
+/// Flag taking a value described by `value`
+const fn option(name: &'static str, value: &'static str, help: &'static str) -> Flag {
+    Flag {
+        name,
+        value: Some(value),
+        help,
+    }
+}
+
+const MOUNT: Command = Command {
+    name: "mount",
+    summary: "Mount filesystem on a device, creating it first if the device holds none",
+    arguments: &["[device]", "<directory>"],
+    flags: &[
+        option(
+            "-o",
+            "options",
+            "options of this mount, separated by commas",
+        ),
+        switch("--read-only", "mount read-only, same as -o ro"),
+        option(
+            "--block-size",
+            "bytes",
+            "block size of a new filesystem (default 4096)",
+        ),
+        option(
+            "--checksum",
+            "algorithm",
+            "checksum algorithm of a new filesystem",
+        ),
+        option(
+            "--options",
+            "options",
+            "default mount options of a new filesystem",
+        ),
+        switch(
+            "--yes",
+            "format a device holding other data without confirmation",
+        ),
+        switch("--direct", "bypass page cache of the host with O_DIRECT"),
+        option(
+            "--ram",
+            "size",
+            "volatile filesystem in memory instead of a device",
+        ),
+        option(
+            "--image",
+            "file",
+            "image file instead of a device, created sparse unless it exists",
+        ),
+        option("--size", "size", "size of a created image file"),
+        option(
+            "--scrub-interval",
+            "seconds",
+            "verify block checksums while idle, at most once per interval",
+        ),
+        option(
+            "--slow-op-ms",
+            "milliseconds",
+            "log operations taking longer, along with their arguments",
+        ),
+        option(
+            "--replica",
+            "device",
+            "second device mirroring the first one, resynchronized on mount",
+        ),
+        switch(
+            "--daemon",
+            "run in background, unmounting cleanly on SIGINT, SIGTERM or SIGHUP",
+        ),
+        option(
+            "--pid-file",
+            "file",
+            "file to write process ID of the driver to",
+        ),
+        option(
+            "--metrics-listen",
+            "address",
+            "serve statistics to Prometheus over HTTP on address, as host:port",
+        ),
+        option(
+            "--inject-faults",
+            "faults",
+            "fail transfers for testing, as kind[@start[-end]][:probability],...",
+        ),
+    ],
+};
+
+const MKFS: Command = Command {
+    name: "mkfs",
+    summary: "Create a new filesystem on a device",
+    arguments: &["<device>", "[parameter=value|+option]..."],
+    flags: &[
+        switch("--force", "format a device holding a filesystem"),
+        option(
+            "--block-size",
+            "bytes",
+            "block size, same as block_size=<bytes>",
+        ),
+        option("--label", "name", "label, same as label=<name>"),
+    ],
+};
+
+const FSCK: Command = Command {
+    name: "fsck",
+    summary: "Check consistency of an unmounted filesystem, exiting with status of e2fsck",
+    arguments: &["<device>"],
+    flags: &[switch(
+        "--repair",
+        "repair found problems instead of only reporting them",
+    )],
+};
+
+const INFO: Command = Command {
+    name: "info",
+    summary: "Print parameters and usage of a filesystem, which may be mounted",
+    arguments: &["<device>"],
+    flags: &[],
+};
 
-use crate::structs::DEFAULT_BLOCK_SIZE;
+const TUNE: Command = Command {
+    name: "tune",
+    summary: "Change parameters of an unmounted filesystem, or print them without changes",
+    arguments: &["<device>", "[parameter=value|+option|-option]..."],
+    flags: &[],
+};
+
+const SNAPSHOT: Command = Command {
+    name: "snapshot",
+    summary: "List, create, delete, compare or roll back to snapshots of an unmounted filesystem",
+    arguments: &[
+        "<device>",
+        "[list|create|delete|rollback|diff]",
+        "[snapshot]...",
+    ],
+    flags: &[],
+};
+
+const UNDO_FORMAT: Command = Command {
+    name: "undo-format",
+    summary: "Restore data overwritten by the last formatting of a device",
+    arguments: &["<device>"],
+    flags: &[],
+};
+
+const STRESS: Command = Command {
+    name: "stress",
+    summary: "Run concurrent operations against a filesystem in memory or an image file",
+    arguments: &["[memory|<new image file>]", "[threads]", "[seconds]"],
+    flags: &[],
+};
 
-mod error;
-mod filesystem;
-mod filetypes;
-mod structs;
+const COMMANDS: [Command; 8] = [MOUNT, MKFS, FSCK, INFO, TUNE, SNAPSHOT, UNDO_FORMAT, STRESS];
 
 fn help() {
     println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"),);
@@ -23,65 +195,652 @@ fn help() {
     println!("{}", env!("CARGO_PKG_AUTHORS"));
     println!();
     println!("Usage:");
-    println!("\ttananfs <block device> <directory> [block size]");
+    println!("\ttananfs <command> [flags] [arguments]");
+    println!("\ttananfs <command> --help");
+    println!("\ttananfs <device> <directory> (same as mount)");
+    println!();
+    println!("Commands:");
+    for command in COMMANDS {
+        println!("\t{:13}{}", command.name, command.summary);
+    }
+    println!();
+    println!("Checksum algorithms for new filesystems:");
+    println!("\tnone, crc32c (default), xxhash");
+    println!("\tall store 32 bits and differ only in speed");
+    println!();
+    println!("Default mount options, separated by commas for new filesystems:");
+    println!("\tnoatime, strictatime, compress, nodelalloc, noreadahead, discard");
+    println!();
+    println!("Options of a single mount, separated by commas after -o:");
+    println!("\tro, rw, allow_other, allow_root, auto_unmount, default_permissions,");
+    println!("\tdev, nodev, suid, nosuid, exec, noexec, sync, async, dirsync,");
+    println!("\tfsname=<name>, subtype=<name>, cache_size=<size>, flush_interval=<milliseconds>,");
+    println!("\treadahead=<blocks>, scrub_interval=<seconds>, slow_op_ms=<milliseconds>,");
+    println!("\tpage_cache, relatime, noatime, strictatime, nodelalloc, noreadahead, discard,");
+    println!("\tmirror=<directory>, sequence=<transaction>, max_entries=<entries>,");
+    println!("\tmax_depth=<directories>");
+    println!();
+    println!("Tunable parameters of unmounted filesystems:");
+    println!("\tlabel=<name>, uuid=<uuid>|random, reserved=<percent>,");
+    println!("\tmax_mounts=<count>, mounts=<count>,");
+    println!("\tfeature=+backups|-backups|+quota|-quota,");
+    println!("\tfeature=+journal_history|-journal_history|+dir_index,");
+    println!("\tfeature=+inode_chunks|-inode_chunks");
     println!();
     println!("Logging with RUST_LOG:");
     println!("\tnone, error (default), warn, info, debug, trace");
+    println!();
+    println!("Directory of side files for undoing formatting with TANANFS_UNDO_DIR:");
+    println!("\t<directory> writable only by its owner (default is tananfs-undo-<uid> in temporary directory)");
+    println!();
+    println!("File memory filesystem is restored from and saved to with TANANFS_RAM_SNAPSHOT:");
+    println!("\t<file>");
+    println!();
+    println!("Seed of injected failures with TANANFS_FAULT_SEED:");
+    println!("\t<number> (default is random)");
+}
+
+/// Wrap `device` to fail transfers matched by `faults`, if there are any
+fn inject_faults(
+    device: Box<dyn BlockDevice>,
+    faults: &[Fault],
+) -> Result<Box<dyn BlockDevice>, Error> {
+    if faults.is_empty() {
+        return Ok(device);
+    }
+    warn!("Injecting faults into device transfers");
+    let mut device = FaultyDevice::new(device, faults.to_vec());
+    if let Ok(value) = std::env::var("TANANFS_FAULT_SEED") {
+        device = device.with_seed(value.parse().map_err(|_| Error::InvalidArgument)?);
+    }
+    Ok(Box::new(device))
+}
+
+/// Backend of opened `device` followed by `members` it is concatenated with,
+/// bypassing page cache of the host if `direct`, mirrored on `replica` if
+/// given and failing transfers matched by `faults`
+fn backend(
+    device: std::fs::File,
+    members: Vec<std::fs::File>,
+    direct: bool,
+    replica: Option<std::fs::File>,
+    faults: &[Fault],
+) -> Result<Box<dyn BlockDevice>, Error> {
+    let open = |device| -> Result<Box<dyn BlockDevice>, Error> {
+        if !direct {
+            return Ok(Box::new(device));
+        }
+        info!("Accessing device with O_DIRECT");
+        Ok(Box::new(DirectDevice::new(device)?))
+    };
+    let device = match members.is_empty() {
+        true => open(device)?,
+        false => {
+            let members = std::iter::once(device)
+                .chain(members)
+                .map(open)
+                .collect::<Result<_, _>>()?;
+            Box::new(ConcatDevice::new(members)?)
+        }
+    };
+    let Some(replica) = replica else {
+        return inject_faults(device, faults);
+    };
+    let mut mirror = MirrorDevice::new(device, open(replica)?)?;
+    mirror.resync()?;
+    inject_faults(Box::new(mirror), faults)
+}
+
+/// Parameters of a filesystem created on mount
+#[derive(Debug, Clone, Copy)]
+struct NewFilesystem {
+    block_size: u32,
+    checksum: ChecksumAlgorithm,
+    options: MountOptions,
+}
+
+impl NewFilesystem {
+    /// Parameters given to `mount` command with `arguments`
+    fn parse(arguments: &Arguments) -> Result<Self, Error> {
+        let block_size = arguments
+            .parse("--block-size")?
+            .unwrap_or(DEFAULT_BLOCK_SIZE);
+        if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+            error!("Block size must be a power of two from 512 to 4096");
+            return Err(Error::InvalidArgument);
+        }
+        Ok(Self {
+            block_size,
+            checksum: arguments.parse("--checksum")?.unwrap_or_default(),
+            options: arguments.parse("--options")?.unwrap_or_default(),
+        })
+    }
+}
+
+/// Filesystem on a memory device of `capacity` bytes failing transfers
+/// matched by `faults`, and whether it existed in the snapshot file it is
+/// restored from
+fn ram_filesystem(
+    capacity: u64,
+    new: NewFilesystem,
+    faults: &[Fault],
+) -> Result<(Filesystem, bool), Error> {
+    // Running in background changes working directory
+    let snapshot = match std::env::var_os("TANANFS_RAM_SNAPSHOT") {
+        Some(path) => Some(std::path::absolute(path)?),
+        None => None,
+    };
+    let mut device = match &snapshot {
+        Some(path) if path.exists() => MemBlockDevice::load(path)?,
+        _ => MemBlockDevice::new(capacity),
+    };
+    if let Some(path) = &snapshot {
+        device = device.with_snapshot(path);
+    }
+    if let Some(block_size) = Filesystem::detect_existing(&mut device)? {
+        info!("Mounting existing filesystem in memory with block size {block_size}");
+        let device = inject_faults(Box::new(device), faults)?;
+        return Ok((Filesystem::load(device, block_size)?, true));
+    }
+    let NewFilesystem {
+        block_size,
+        checksum,
+        options,
+    } = new;
+    let capacity = device.capacity();
+    info!("Mounting new filesystem in {capacity} bytes of memory with block size {block_size}");
+    let fs = Filesystem::new(
+        inject_faults(Box::new(device), faults)?,
+        capacity,
+        block_size,
+    )
+    .with_checksum(checksum)
+    .with_options(options);
+    Ok((fs, false))
+}
+
+/// Create sparse image file at `path` of `size` bytes unless it exists,
+/// returning whether it was created
+fn create_image(path: &str, size: Option<u64>) -> Result<bool, Error> {
+    if std::path::Path::new(path).exists() {
+        return Ok(false);
+    }
+    let Some(size) = size else {
+        error!("Size of new image file {path} not given");
+        return Err(Error::InvalidArgument);
+    };
+    info!("Creating sparse image file {path} of {size} bytes");
+    let file = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    file.set_len(size)?;
+    Ok(true)
+}
+
+/// Ask user on terminal to confirm `question`
+fn confirm(question: &str) -> bool {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
-#[allow(unknown_lints, clippy::all, unused)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::panic::set_hook(Box::new(|info| {
         error!("Critical error: {info}");
     }));
 
-    let args: Vec<String> = std::env::args().collect();
-
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Called by mount(8) through a link, with arguments of its helpers
+    let helper = std::env::args()
+        .next()
+        .is_some_and(|program| program.ends_with("mount.tananfs"));
+    if helper && HelperArguments::parse(&args).is_ok_and(|helper| helper.verbose) {
+        std::env::set_var("RUST_LOG", "info");
+    }
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "error");
     }
-    env_logger::init();
+    logging::init();
 
-    let Some(blkdev_path) = args.get(1)  else {
+    if helper {
+        let status = mount_helper(&args);
+        log::logger().flush();
+        std::process::exit(status.into());
+    }
+    let Some(first) = args.first() else {
         help();
-        panic!("Block device path not provided")
+        return Err(Error::InvalidArgument.into());
     };
-    let Some(mount_path) = args.get(2)  else {
+    if matches!(first.as_str(), "help" | "--help" | "-h") {
         help();
-        panic!("Mount point not provided")
+        return Ok(());
+    }
+    // Without a command, arguments are those of mount
+    let (command, args) = match COMMANDS.iter().find(|command| command.name == first) {
+        Some(command) => (command, &args[1..]),
+        None => (&MOUNT, &args[..]),
+    };
+    let arguments = command.parse(args)?;
+    if arguments.help {
+        command.help("tananfs");
+        return Ok(());
+    }
+    let device = arguments.get(0).unwrap_or_default();
+    match command.name {
+        "mkfs" => {
+            let mut parameters = arguments.rest(1).to_vec();
+            if let Some(block_size) = arguments.value("--block-size") {
+                parameters.push(format!("block_size={block_size}"));
+            }
+            if let Some(label) = arguments.value("--label") {
+                parameters.push(format!("label={label}"));
+            }
+            mkfs::mkfs(device, &parameters, arguments.flag("--force"))?;
+        }
+        "fsck" => {
+            let status = fsck::fsck(device, arguments.flag("--repair")).unwrap_or_else(|e| {
+                eprintln!("{e}");
+                fsck::EXIT_FAILED
+            });
+            log::logger().flush();
+            std::process::exit(status.into());
+        }
+        "info" => tune::info(device)?,
+        "tune" => tune::tune(device, arguments.rest(1))?,
+        "snapshot" => snapshot::snapshot(device, arguments.get(1), arguments.rest(2))?,
+        "undo-format" => undo::undo_format(device)?,
+        "stress" => {
+            let target = arguments.get(0).unwrap_or("memory");
+            let threads = match arguments.get(1) {
+                Some(value) => value.parse().map_err(|_| Error::InvalidArgument)?,
+                None => stress::DEFAULT_THREADS,
+            };
+            let duration = match arguments.get(2) {
+                Some(value) => std::time::Duration::from_secs(
+                    value.parse().map_err(|_| Error::InvalidArgument)?,
+                ),
+                None => stress::DEFAULT_DURATION,
+            };
+            stress::stress(target, threads, duration)?;
+        }
+        _ => return mount_command(&arguments),
+    }
+    Ok(())
+}
+
+/// Mount filesystem as helper of mount(8) with `args`, returning its exit status
+fn mount_helper(args: &[String]) -> u8 {
+    let Ok(helper) = HelperArguments::parse(args) else {
+        println!("Usage:");
+        println!("\tmount.tananfs <device> <directory> [-sfnv] [-o options]");
+        return mount::EXIT_USAGE;
+    };
+    if helper.fake {
+        return mount::EXIT_SUCCESS;
+    }
+    let mounted = MOUNT
+        .parse(&helper.mount_args()[1..])
+        .map_err(|e| e.into())
+        .and_then(|arguments| mount_command(&arguments));
+    match mounted {
+        Ok(()) => mount::EXIT_SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            mount::EXIT_FAILURE
+        }
+    }
+}
+
+/// Mount filesystem as asked by `mount` command with `arguments`
+fn mount_command(arguments: &Arguments) -> Result<(), Box<dyn std::error::Error>> {
+    let confirmed = arguments.flag("--yes");
+    let direct = arguments.flag("--direct");
+    let ram = match arguments.value("--ram") {
+        Some(size) => Some(mem::parse_size(size)?),
+        None => None,
+    };
+    let size = match arguments.value("--size") {
+        Some(size) => Some(mem::parse_size(size)?),
+        None => None,
+    };
+    let replica_path = arguments.value("--replica");
+    let mut mount_arguments: MountArguments = arguments.values("-o").join(",").parse()?;
+    mount_arguments.read_only |= arguments.flag("--read-only");
+    if let Some(seconds) = arguments.parse("--scrub-interval")? {
+        mount_arguments.scrub_interval = Some(std::time::Duration::from_secs(seconds));
+    }
+    if let Some(milliseconds) = arguments.parse("--slow-op-ms")? {
+        mount_arguments.slow_op = Some(std::time::Duration::from_millis(milliseconds));
+    }
+    let service = Service {
+        daemon: arguments.flag("--daemon"),
+        pid_file: match arguments.value("--pid-file") {
+            Some(path) => Some(std::path::absolute(path)?),
+            None => None,
+        },
+        metrics_listen: arguments.value("--metrics-listen").map(str::to_owned),
+    };
+    let faults = match arguments.value("--inject-faults") {
+        Some(spec) => faulty::parse_faults(spec)?,
+        None => Vec::new(),
     };
+    let new = NewFilesystem::parse(arguments)?;
+    let image_path = arguments.value("--image");
+    // Memory and image file take place of the device
+    let replaced = ram.is_some() || image_path.is_some();
+    let (blkdev_path, mount_path) = match (arguments.rest(0), replaced) {
+        ([mount_path], true) => (image_path.unwrap_or("memory"), mount_path.as_str()),
+        ([blkdev_path, mount_path], false) => (blkdev_path.as_str(), mount_path.as_str()),
+        _ => {
+            MOUNT.help("tananfs");
+            return Err(Error::InvalidArgument.into());
+        }
+    };
+    let created = match image_path {
+        Some(image_path) => create_image(image_path, size)?,
+        None => false,
+    };
+
+    if let Some(capacity) = ram {
+        let (fs, existing) = ram_filesystem(capacity, new, &faults)?;
+        return mount(fs, existing, "memory", mount_path, mount_arguments, service);
+    }
 
+    let mut paths = blkdev_path.split(',');
     let mut device = std::fs::File::options()
         .read(true)
         .write(true)
-        .open(blkdev_path)?;
+        .open(paths.next().unwrap_or_default())?;
 
-    let blkdev_size = device.metadata()?.size();
+    let mut blkdev_size = device.metadata()?.size();
+    let mut access = fence::acquire(&device)?;
+    // Metadata of block devices holds no size, unlike their end
+    let mut member_sizes = vec![(&device).seek(SeekFrom::End(0))?];
+    let mut members = Vec::new();
+    for member_path in paths {
+        let member = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(member_path)?;
+        if fence::acquire(&member)? == Access::ReadOnly {
+            access = Access::ReadOnly;
+        }
+        info!("Concatenating device {member_path}");
+        member_sizes.push((&member).seek(SeekFrom::End(0))?);
+        members.push(member);
+    }
+    if !members.is_empty() {
+        blkdev_size = member_sizes.iter().sum();
+    }
+
+    let replica = match &replica_path {
+        Some(replica_path) => {
+            let replica = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .open(replica_path)?;
+            // Resynchronization writes to both devices
+            if access != Access::ReadWrite || fence::acquire(&replica)? != Access::ReadWrite {
+                error!("Cannot mirror devices used by another instance");
+                return Err(Error::Busy.into());
+            }
+            info!("Mirroring device {blkdev_path} on {replica_path}");
+            blkdev_size = blkdev_size.min(replica.metadata()?.size());
+            Some(replica)
+        }
+        None => None,
+    };
 
     let (block_size, existing) = match Filesystem::detect_existing(&mut device)? {
         Some(detected) => (detected, true),
-        None => (
-            args.get(3).map_or_else(
-                || DEFAULT_BLOCK_SIZE,
-                |value| value.parse().unwrap_or(DEFAULT_BLOCK_SIZE),
-            ),
-            false,
-        ),
+        None => (new.block_size, false),
     };
 
-    let mut fs = if existing {
+    let fs = if let Some(sequence) = mount_arguments.sequence {
+        if !existing {
+            error!("No filesystem to rewind on device {blkdev_path}");
+            return Err(Error::NotFound.into());
+        }
+        info!("Mounting filesystem {blkdev_path} after journal transaction {sequence} to {mount_path}");
+        let fs = Filesystem::load_at(
+            backend(device, members, direct, replica, &faults)?,
+            block_size,
+            sequence,
+        )?;
+        fs.check_members(&member_sizes)?;
+        fs
+    } else if existing {
         info!("Mounting existing filesystem {blkdev_path} to {mount_path} with block size {block_size}");
-        Filesystem::load(Box::new(device), block_size)?
+        let device = backend(device, members, direct, replica, &faults)?;
+        // Replaying the journal writes to a device others may be reading
+        let mut fs = match access == Access::ReadOnly || mount_arguments.read_only {
+            true => Filesystem::load_read_only(device, block_size)?,
+            false => Filesystem::load(device, block_size)?,
+        };
+        fs.check_members(&member_sizes)?;
+        if !fs.read_only {
+            fs.count_mount();
+        }
+        fs
+    } else if access == Access::ReadOnly {
+        error!("Cannot create new filesystem on device {blkdev_path} used by another instance");
+        return Err(Error::Busy.into());
     } else {
         info!("Mounting new filesystem {blkdev_path} to {mount_path} with block size {block_size} and capacity {blkdev_size}");
-        Filesystem::new(Box::new(device), blkdev_size, block_size)
+        let NewFilesystem {
+            checksum, options, ..
+        } = new;
+        info!("Using {checksum} checksums");
+        info!("Using default mount options {options}");
+        let geometry = Geometry::detect(&device).unwrap_or_default();
+        if block_size < geometry.physical_sector {
+            warn!(
+                "Block size {block_size} is smaller than physical sector size {}",
+                geometry.physical_sector
+            );
+        }
+        info!("Aligning data region to {} bytes", geometry.alignment());
+        if let Some(found) = signature::detect(&mut device)? {
+            warn!("Device {blkdev_path} contains {found}");
+            if !confirmed && !confirm(&format!("Overwrite {found} on {blkdev_path}?")) {
+                error!("Not formatting device {blkdev_path} without confirmation");
+                return Err(Error::Cancelled.into());
+            }
+        }
+        let uuid = tune::random_uuid()?;
+        let mut device = backend(device, members, direct, replica, &faults)?;
+        // A created image holds nothing to undo
+        if !created {
+            let undo_path = undo::side_file(blkdev_path)?;
+            info!("Saving overwritten data to {}", undo_path.display());
+            device = Box::new(UndoDevice::create(device, &undo_path, uuid)?);
+        }
+        Filesystem::new_aligned(device, blkdev_size, block_size, geometry.alignment())
+            .with_checksum(checksum)
+            .with_options(options)
+            .with_uuid(uuid)
+            .with_members(&member_sizes)?
     };
 
+    mount(
+        fs,
+        existing,
+        blkdev_path,
+        mount_path,
+        mount_arguments,
+        service,
+    )
+}
+
+/// How the driver runs while filesystem is mounted
+struct Service {
+    /// Detach into background once filesystem is loaded
+    daemon: bool,
+    pid_file: Option<std::path::PathBuf>,
+    /// Address of HTTP endpoint serving statistics
+    metrics_listen: Option<String>,
+}
+
+/// Serve filesystem from device at `blkdev_path` on `mount_path` until it
+/// is unmounted or the driver stopped by a signal, formatting it first unless
+/// it is `existing`, with options of this mount given by `arguments`
+fn mount(
+    mut fs: Filesystem,
+    existing: bool,
+    blkdev_path: &str,
+    mount_path: &str,
+    arguments: MountArguments,
+    service: Service,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Running in background changes working directory
+    let mount_path = std::path::absolute(mount_path)?;
+    if !mount_path.is_dir() {
+        error!("Mount point {} is not a directory", mount_path.display());
+        return Err(Error::NotDirectory.into());
+    }
+    if let Some(bytes) = arguments.cache_size {
+        info!("Limiting cache of inodes and blocks to {bytes} bytes");
+        fs = fs.with_cache_size(bytes);
+    }
+    if let Some(interval) = arguments.flush_interval {
+        info!("Flushing cached changes every {} ms", interval.as_millis());
+        fs = fs.with_flush_interval(interval);
+    }
+    if arguments.limits != Limits::default() {
+        info!(
+            "Limiting directories to {} entries and {} levels",
+            arguments.limits.directory_entries, arguments.limits.path_depth
+        );
+        fs = fs.with_limits(arguments.limits);
+    }
+    if arguments.options != MountOptions::default() {
+        info!("Enabling mount options {}", arguments.options);
+        fs = fs.with_mount_options(arguments.options);
+    }
+
     let fs_handle = Arc::new(Mutex::new(fs));
-    let fuse_fs = FuseFs {
-        filesystem: fs_handle.clone(),
+    if !existing {
+        let owner = unsafe {
+            Owner {
+                uid: libc::getuid(),
+                gid: libc::getgid(),
+            }
+        };
+        Filesystem::format(&fs_handle, owner)?;
+    }
+    let (mode, clean) = {
+        let mut fs = fs_handle.lock().map_err(|_| Error::ThreadSync)?;
+        // A new filesystem is written once before it becomes read-only
+        fs.read_only |= arguments.read_only;
+        let clean = fs.mark_mounted()?;
+        match fs.read_only {
+            true => (MountOption::RO, clean),
+            false => (MountOption::RW, clean),
+        }
+    };
+    // Nothing reaches the filesystem before it is mounted
+    if !clean {
+        info!("Recovering filesystem which was not unmounted cleanly");
+        let report = Filesystem::recover(&fs_handle)?;
+        if !report.is_clean() {
+            warn!(
+                "Corrected {} problems, check filesystem fully with fsck",
+                report.problems.len()
+            );
+        }
+    }
+    let mut fuse_fs = FuseFs::new(fs_handle.clone());
+    if arguments.page_cache {
+        info!("Serving regular files through kernel page cache");
+        fuse_fs = fuse_fs.with_page_cache();
+    }
+    if let Some(blocks) = arguments.readahead {
+        info!("Prefetching up to {blocks} blocks after sequential reads");
+        fuse_fs = fuse_fs.with_readahead(blocks);
+    }
+    if let Some(threshold) = arguments.slow_op {
+        info!(
+            "Logging operations taking {} ms or longer",
+            threshold.as_millis()
+        );
+        fuse_fs = fuse_fs.with_slow_threshold(threshold);
+    }
+    match arguments.sync {
+        SyncMode::Async => {}
+        SyncMode::DirSync => info!("Storing changes of directories before answering them"),
+        SyncMode::Sync => info!("Storing all changes before answering them"),
+    }
+    fuse_fs = fuse_fs.with_sync(arguments.sync);
+    let mirror_path = match &arguments.mirror {
+        Some(path) => Some(std::path::absolute(path)?),
+        None => None,
+    };
+    // Bound before detaching, so a taken address is reported
+    let metrics_listener = match &service.metrics_listen {
+        Some(address) => Some(std::net::TcpListener::bind(address).inspect_err(|e| {
+            error!("Failed to listen for metrics on {address}: {e}");
+        })?),
+        None => None,
+    };
+    // Signals are awaited by a single thread, and the rest inherit the mask
+    daemon::block_signals()?;
+    if service.daemon {
+        daemon::daemonize(service.pid_file.as_deref())?;
+    } else if let Some(pid_file) = &service.pid_file {
+        std::fs::write(pid_file, format!("{}\n", std::process::id()))?;
+    }
+    daemon::handle_signals(fuse_fs.mirror()?, mount_path.clone());
+    if let Some(listener) = metrics_listener {
+        metrics::spawn(fs_handle.clone(), listener);
+    }
+    let mut options = vec![
+        MountOption::FSName(blkdev_path.to_string()),
+        MountOption::Subtype("tananfs".to_string()),
+    ];
+    // Name and type given with -o replace those of the device
+    options.retain(|option| {
+        !arguments
+            .fuse
+            .iter()
+            .any(|given| std::mem::discriminant(given) == std::mem::discriminant(option))
+    });
+    options.extend(arguments.fuse);
+    let mirror = match mirror_path {
+        Some(mirror_path) => {
+            info!(
+                "Mounting read-only mirror of {blkdev_path} to {}",
+                mirror_path.display()
+            );
+            let notifier = Notifier::new()?;
+            let mirror_fs = fuse_fs.mirror()?.with_notifier(notifier.clone());
+            let mirror_options = [&[MountOption::RO], options.as_slice()].concat();
+            let session = fuser::spawn_mount2(mirror_fs, mirror_path, &mirror_options)?;
+            // Attached before the next mount opens its device
+            notifier.attach()?;
+            Some(session)
+        }
+        None => None,
     };
-    fuser::mount2(fuse_fs, mount_path, &[MountOption::RW])?;
+    fuse_fs = fuse_fs.with_notifier(Notifier::new()?);
+    let scrubber = match arguments.scrub_interval {
+        Some(interval) => Some(Scrubber::spawn(fs_handle.clone(), interval)?),
+        None => None,
+    };
+    options.insert(0, mode);
+    fuser::mount2(fuse_fs, &mount_path, &options)?;
+    drop(scrubber);
+    drop(mirror);
+    // Destroying the session does so as well, unless the kernel never asked
+    fs_handle.lock_fs()?.mark_unmounted()?;
+    if let Some(pid_file) = &service.pid_file {
+        let _ = std::fs::remove_file(pid_file);
+    }
+    log::logger().flush();
 
     Ok(())
 }
@@ -0,0 +1,133 @@
+//! HTTP endpoint serving statistics of a mounted filesystem to Prometheus
+//!
+//! With `--metrics-listen`, the driver answers `GET /metrics` with the same
+//! counters as the `/.tananfs/stats` file inside the mount. Requests are
+//! served one at a time by a single thread, each on its own connection, which
+//! is enough for a scraper polling every few seconds. The listener is bound
+//! before the driver detaches from the terminal, so a taken address is
+//! reported to the user.
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use crate::filesystem::{Filesystem, LockFilesystem};
+use crate::Error;
+
+/// Longest request head read from clients
+const MAX_REQUEST: usize = 8192;
+/// Time a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Content type of text exposition format of Prometheus
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Thread serving statistics of `fs` to clients connecting to `listener`
+pub fn spawn(fs: Arc<Mutex<Filesystem>>, listener: TcpListener) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        if let Err(e) = serve(&fs, listener) {
+            error!("Metrics endpoint stopped: {e}");
+        }
+    })
+}
+
+/// Serve statistics of `fs` to clients connecting to `listener` one after another
+pub fn serve(fs: &Arc<Mutex<Filesystem>>, listener: TcpListener) -> Result<(), Error> {
+    info!("Serving metrics on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept metrics client: {e}");
+                continue;
+            }
+        };
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        if let Err(e) = handle(fs, stream) {
+            warn!("Metrics client dropped: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Answer a single request and close the connection
+pub fn handle<S: Read + Write>(fs: &Arc<Mutex<Filesystem>>, mut stream: S) -> Result<(), Error> {
+    let request = read_head(&mut stream)?;
+    let line = request.lines().next().unwrap_or_default();
+    debug!("Metrics request {line:?}");
+    let (status, body) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET" | "HEAD", "/metrics" | "/", _] => {
+            let statistics = fs.lock_fs()?.statistics();
+            ("200 OK", statistics.to_string())
+        }
+        ["GET" | "HEAD", _, _] => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("400 Bad Request", "Bad request\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    if !line.starts_with("HEAD") {
+        stream.write_all(body.as_bytes())?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read request up to the empty line ending its head
+fn read_head<S: Read>(stream: &mut S) -> Result<String, Error> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 512];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST {
+            return Err(Error::InvalidArgument);
+        }
+        match stream.read(&mut buffer)? {
+            0 => break,
+            read => head.extend_from_slice(&buffer[..read]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    use crate::{Filesystem, Owner};
+
+    use super::spawn;
+
+    fn get(address: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let dev = Cursor::new(vec![0u8; 1_000_000]);
+        let fs = Arc::new(Mutex::new(Filesystem::new(Box::new(dev), 1_000_000, 512)));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        spawn(fs.clone(), listener);
+
+        let response = get(address, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let flushes = fs.lock().unwrap().statistics().flushes;
+        assert!(body.contains(&format!("\ntananfs_flushes_total {flushes}\n")));
+
+        assert!(get(address, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
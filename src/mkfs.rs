@@ -0,0 +1,111 @@
+//! Creating a new filesystem on a device
+//!
+//! Unlike formatting on first mount, parameters of the new filesystem can be
+//! chosen, and a device holding a recognized filesystem is left untouched
+//! unless formatting is forced.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info, warn};
+
+use crate::devices::fence::{self, Access};
+use crate::devices::geometry::Geometry;
+use crate::devices::signature;
+use crate::devices::undo::{self, UndoDevice};
+use crate::filesystem::Filesystem;
+use crate::filetypes::Owner;
+use crate::structs::{Superblock, DATA_PER_INODE, DEFAULT_BLOCK_SIZE, MAX_DATA_PER_INODE};
+use crate::tune;
+use crate::Error;
+
+/// Parsed value of `name=value` parameter among `parameters`, removing it
+fn take<T: std::str::FromStr>(
+    parameters: &mut Vec<String>,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    let prefix = format!("{name}=");
+    let Some(position) = parameters.iter().position(|p| p.starts_with(&prefix)) else {
+        return Ok(None);
+    };
+    let parameter = parameters.remove(position);
+    match parameter[prefix.len()..].parse() {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => Err(Error::InvalidArgument),
+    }
+}
+
+/// Create filesystem on `device_path` with `parameters`, as accepted by
+/// [tune::tune] with addition of block size and bytes per inode
+pub fn mkfs(device_path: &str, parameters: &[String], force: bool) -> Result<(), Error> {
+    let mut parameters = parameters.to_vec();
+    let block_size = take(&mut parameters, "block_size")?.unwrap_or(DEFAULT_BLOCK_SIZE);
+    if !block_size.is_power_of_two() || !(512..=4096).contains(&block_size) {
+        return Err(Error::InvalidArgument);
+    }
+    let bytes_per_inode = take(&mut parameters, "bytes_per_inode")?.unwrap_or(DATA_PER_INODE);
+    if !(block_size as u64..=MAX_DATA_PER_INODE).contains(&bytes_per_inode) {
+        return Err(Error::InvalidArgument);
+    }
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        error!("Cannot create new filesystem on device {device_path} used by another instance");
+        return Err(Error::Busy);
+    }
+    let device_size = device.seek(SeekFrom::End(0))?;
+    let found = match Filesystem::detect_existing(&mut device)? {
+        Some(_) => Some("tananfs filesystem"),
+        None => signature::detect(&mut device)?,
+    };
+    if let Some(found) = found {
+        if !force {
+            error!("Device {device_path} contains {found}, not formatting it without --force");
+            return Err(Error::Cancelled);
+        }
+        warn!("Overwriting {found} on {device_path}");
+    }
+    let geometry = Geometry::detect(&device).unwrap_or_default();
+    if block_size < geometry.physical_sector {
+        warn!(
+            "Block size {block_size} is smaller than physical sector size {}",
+            geometry.physical_sector
+        );
+    }
+    let mut superblock = Superblock::with_inode_ratio(
+        device_size,
+        block_size,
+        geometry.alignment(),
+        bytes_per_inode,
+    );
+    superblock.uuid = tune::random_uuid()?;
+    for parameter in parameters.iter() {
+        tune::apply(&mut superblock, parameter, device_size)?;
+    }
+    let undo_path = undo::side_file(device_path)?;
+    info!("Saving overwritten data to {}", undo_path.display());
+    let mut device = UndoDevice::create(device, &undo_path, superblock.uuid)?;
+    // Superblock of an old filesystem with larger blocks would be detected first
+    device.seek(SeekFrom::Start(0))?;
+    device.write_all(&[0; 4096 + std::mem::size_of::<Superblock>()])?;
+    let fs = Arc::new(Mutex::new(Filesystem::from_superblock(
+        Box::new(device),
+        superblock,
+    )));
+    let owner = unsafe {
+        Owner {
+            uid: libc::getuid(),
+            gid: libc::getgid(),
+        }
+    };
+    Filesystem::format(&fs, owner)?;
+    println!(
+        "Created filesystem {} with {} inodes and {} blocks of {block_size} bytes",
+        superblock.uuid(),
+        { superblock.inode_count },
+        { superblock.block_count }
+    );
+    Ok(())
+}
@@ -0,0 +1,384 @@
+//! Options of a mount given with `-o`, the way mount(8) passes them
+//!
+//! Options of the kernel and FUSE, such as `allow_other` or `nosuid`, are
+//! handed to [fuser], while the driver's own options tune a single mount:
+//! `cache_size` (in bytes, or with a K, M, G unit), `flush_interval` (in
+//! milliseconds), `readahead` (in blocks), `scrub_interval` (in seconds),
+//! `slow_op_ms` (in milliseconds), `page_cache`, `mirror` (a directory
+//! where the filesystem is also mounted read-only), `sequence` (a journal
+//! transaction whose state is mounted read-only), and `max_entries` and
+//! `max_depth` bounding entries of a directory and depth of new ones, while
+//! `sync` and `dirsync` are also handled by the driver. Mount options
+//! recorded in superblock may be enabled on top of default ones, except
+//! those changing how data is laid out. Options
+//! meant for mount(8) itself, such as `noauto` or `x-systemd.*`, are ignored,
+//! so the filesystem can be mounted from `/etc/fstab`.
+//!
+//! Running `mount -t tananfs` calls the `mount.tananfs` helper, a link to
+//! the driver, whose arguments are translated by [HelperArguments] into those
+//! mounting the filesystem in background, and which exits with a status of
+//! mount(8).
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use fuser::MountOption;
+use log::{error, warn};
+
+use crate::devices::mem::parse_size;
+use crate::filesystem::{Limits, SyncMode};
+use crate::structs::MountOptions;
+use crate::Error;
+
+/// Options consumed by mount(8) and its helpers, not by the driver
+const IGNORED: [&str; 8] = [
+    "defaults", "auto", "noauto", "user", "nouser", "users", "nofail", "_netdev",
+];
+
+/// Mount helper succeeded
+pub const EXIT_SUCCESS: u8 = 0;
+/// Helper was called with invalid arguments
+pub const EXIT_USAGE: u8 = 1;
+/// Driver failed to mount the filesystem
+pub const EXIT_FAILURE: u8 = 32;
+
+/// Options of a single mount
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountArguments {
+    /// Options handed to FUSE
+    pub fuse: Vec<MountOption>,
+    /// Mount options enabled on top of default ones
+    pub options: MountOptions,
+    /// Mount read-only with `ro`
+    pub read_only: bool,
+    /// Bytes held by cache of inodes and blocks
+    pub cache_size: Option<usize>,
+    /// Longest time changes are kept in cache
+    pub flush_interval: Option<Duration>,
+    /// Blocks prefetched after sequential reads
+    pub readahead: Option<u64>,
+    /// Time between verifications of block checksums while idle
+    pub scrub_interval: Option<Duration>,
+    /// Duration after which operations are logged as slow
+    pub slow_op: Option<Duration>,
+    /// Serve regular files through kernel page cache
+    pub page_cache: bool,
+    /// Directory of a read-only mirror mount sharing the cache
+    pub mirror: Option<PathBuf>,
+    /// Journal transaction after which filesystem is mounted read-only
+    pub sequence: Option<u64>,
+    /// Changes flushed to the device before they are answered, with `sync` or `dirsync`
+    pub sync: SyncMode,
+    /// Bounds of directory trees grown on this mount
+    pub limits: Limits,
+}
+
+impl MountArguments {
+    /// Apply a single option, given as `name` or `name=value`
+    fn apply(&mut self, option: &str) -> Result<(), Error> {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let number = || -> Result<u64, Error> {
+            value
+                .and_then(|value| value.parse().ok())
+                .ok_or(Error::InvalidArgument)
+        };
+        let fuse = match (name, value) {
+            (name, None) if IGNORED.contains(&name) => return Ok(()),
+            (name, _) if name.starts_with("x-") => return Ok(()),
+            ("ro", None) => {
+                self.read_only = true;
+                return Ok(());
+            }
+            ("rw", None) => {
+                self.read_only = false;
+                return Ok(());
+            }
+            ("cache_size", Some(value)) => {
+                self.cache_size = Some(parse_size(value)? as usize);
+                return Ok(());
+            }
+            ("flush_interval", Some(_)) => {
+                self.flush_interval = Some(Duration::from_millis(number()?));
+                return Ok(());
+            }
+            ("readahead", Some(_)) => {
+                self.readahead = Some(number()?);
+                return Ok(());
+            }
+            ("scrub_interval", Some(_)) => {
+                self.scrub_interval = Some(Duration::from_secs(number()?));
+                return Ok(());
+            }
+            ("slow_op_ms", Some(_)) => {
+                self.slow_op = Some(Duration::from_millis(number()?));
+                return Ok(());
+            }
+            ("page_cache", None) => {
+                self.page_cache = true;
+                return Ok(());
+            }
+            ("mirror", Some(value)) if !value.is_empty() => {
+                self.mirror = Some(PathBuf::from(value));
+                return Ok(());
+            }
+            ("sequence", Some(_)) => {
+                self.sequence = Some(number()?);
+                return Ok(());
+            }
+            ("max_entries", Some(_)) => {
+                self.limits.directory_entries = number()?;
+                return Ok(());
+            }
+            ("max_depth", Some(_)) => {
+                self.limits.path_depth = number()?;
+                return Ok(());
+            }
+            // Access times are updated as with relatime unless noatime or strictatime is given
+            ("relatime", None) => return Ok(()),
+            ("fsname", Some(value)) => MountOption::FSName(value.to_string()),
+            ("subtype", Some(value)) => MountOption::Subtype(value.to_string()),
+            ("allow_other", None) => MountOption::AllowOther,
+            ("allow_root", None) => MountOption::AllowRoot,
+            ("auto_unmount", None) => MountOption::AutoUnmount,
+            ("default_permissions", None) => MountOption::DefaultPermissions,
+            ("dev", None) => MountOption::Dev,
+            ("nodev", None) => MountOption::NoDev,
+            ("suid", None) => MountOption::Suid,
+            ("nosuid", None) => MountOption::NoSuid,
+            ("exec", None) => MountOption::Exec,
+            ("noexec", None) => MountOption::NoExec,
+            ("sync", None) => {
+                self.sync = SyncMode::Sync;
+                MountOption::Sync
+            }
+            ("async", None) => {
+                self.sync = SyncMode::Async;
+                MountOption::Async
+            }
+            ("dirsync", None) => {
+                self.sync = self.sync.max(SyncMode::DirSync);
+                MountOption::DirSync
+            }
+            // Layout of names and blocks is chosen for the whole filesystem
+            ("casefold", None) => {
+                error!("Mount option {name} is not supported");
+                return Err(Error::Incompatible);
+            }
+            ("compress", None) => {
+                error!("Mount option {name} is only set when formatting or with tune");
+                return Err(Error::InvalidArgument);
+            }
+            (name, None) => {
+                self.options.apply(name).inspect_err(|_| {
+                    error!("Unknown mount option {name}");
+                })?;
+                return Ok(());
+            }
+            (name, Some(_)) => {
+                error!("Unknown mount option {name}");
+                return Err(Error::InvalidArgument);
+            }
+        };
+        self.fuse.push(fuse);
+        Ok(())
+    }
+}
+
+/// Arguments of a mount helper, `device directory [-sfnv] [-o options]`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HelperArguments {
+    pub device: String,
+    pub mount_path: String,
+    /// Options checked to be valid, separated by commas
+    pub options: String,
+    /// Only check arguments, without mounting, with `-f`
+    pub fake: bool,
+    /// Log what is done, with `-v`
+    pub verbose: bool,
+}
+
+impl HelperArguments {
+    /// Parse arguments following the name of helper
+    ///
+    /// With `-s`, unknown options are dropped instead of failing, and with
+    /// `-n` or `-t`, which concern only mount(8), nothing changes.
+    pub fn parse(args: &[String]) -> Result<Self, Error> {
+        let mut helper = Self::default();
+        let (mut sloppy, mut options, mut positional) = (false, Vec::new(), Vec::new());
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flags = match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() => flags,
+                _ => {
+                    positional.push(arg.clone());
+                    continue;
+                }
+            };
+            for (index, flag) in flags.char_indices() {
+                match flag {
+                    's' => sloppy = true,
+                    'f' => helper.fake = true,
+                    'n' => {}
+                    'v' => helper.verbose = true,
+                    // Value follows either at once or as the next argument
+                    'o' | 't' => {
+                        let value = match &flags[index + 1..] {
+                            "" => args.next().ok_or(Error::InvalidArgument)?.clone(),
+                            value => value.to_string(),
+                        };
+                        if flag == 'o' {
+                            options.extend(value.split(',').map(str::to_string));
+                        }
+                        break;
+                    }
+                    _ => {
+                        error!("Unknown flag -{flag} of mount helper");
+                        return Err(Error::InvalidArgument);
+                    }
+                }
+            }
+        }
+        let [device, mount_path] = <[String; 2]>::try_from(positional).map_err(|_| {
+            error!("Mount helper takes a device and a directory");
+            Error::InvalidArgument
+        })?;
+        (helper.device, helper.mount_path) = (device, mount_path);
+        options.retain(|option| !option.is_empty());
+        if sloppy {
+            options.retain(|option| {
+                let valid = MountArguments::default().apply(option).is_ok();
+                if !valid {
+                    warn!("Ignoring mount option {option}");
+                }
+                valid
+            });
+        }
+        helper.options = options.join(",");
+        // Remaining invalid options fail here, reported as with the driver
+        helper.options.parse::<MountArguments>()?;
+        Ok(helper)
+    }
+
+    /// Arguments of the driver mounting filesystem in background
+    pub fn mount_args(&self) -> Vec<String> {
+        let mut args = vec!["mount".to_string(), "--daemon".to_string()];
+        if !self.options.is_empty() {
+            args.extend(["-o".to_string(), self.options.clone()]);
+        }
+        args.extend([
+            "--".to_string(),
+            self.device.clone(),
+            self.mount_path.clone(),
+        ]);
+        args
+    }
+}
+
+impl FromStr for MountArguments {
+    type Err = Error;
+
+    /// Comma-separated list of options, later ones overriding earlier ones
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut arguments = Self::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            arguments.apply(option)?;
+        }
+        Ok(arguments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use fuser::MountOption;
+
+    use super::{HelperArguments, MountArguments};
+    use crate::filesystem::{SyncMode, MAX_PATH_DEPTH};
+    use crate::structs::MountOptions;
+    use crate::Error;
+
+    #[test]
+    fn parse_fstab_options() {
+        let arguments: MountArguments =
+            "defaults,noauto,x-systemd.automount,allow_other,nosuid,ro,noatime,cache_size=16M,flush_interval=250,readahead=8,slow_op_ms=100,max_entries=1000,mirror=/mnt/mirror,sequence=7"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            arguments.fuse,
+            [MountOption::AllowOther, MountOption::NoSuid]
+        );
+        assert!(arguments.read_only);
+        assert_eq!(arguments.options, MountOptions::NOATIME);
+        assert_eq!(arguments.cache_size, Some(16 << 20));
+        assert_eq!(arguments.flush_interval, Some(Duration::from_millis(250)));
+        assert_eq!(arguments.readahead, Some(8));
+        assert_eq!(arguments.scrub_interval, None);
+        assert_eq!(arguments.slow_op, Some(Duration::from_millis(100)));
+        assert!(!arguments.page_cache);
+        assert_eq!(arguments.mirror, Some("/mnt/mirror".into()));
+        assert_eq!(arguments.sequence, Some(7));
+        assert_eq!(arguments.limits.directory_entries, 1000);
+        assert_eq!(arguments.limits.path_depth, MAX_PATH_DEPTH);
+
+        assert_eq!(
+            "ro,rw".parse::<MountArguments>().unwrap(),
+            MountArguments::default()
+        );
+        let sync = |options: &str| options.parse::<MountArguments>().unwrap().sync;
+        assert_eq!(sync("dirsync"), SyncMode::DirSync);
+        assert_eq!(sync("sync,dirsync"), SyncMode::Sync);
+        assert_eq!(sync("sync,async"), SyncMode::Async);
+        assert!(matches!(
+            "casefold".parse::<MountArguments>(),
+            Err(Error::Incompatible)
+        ));
+        for invalid in [
+            "compress",
+            "readahead=many",
+            "cache_size",
+            "colour=blue",
+            "bogus",
+        ] {
+            assert!(matches!(
+                invalid.parse::<MountArguments>(),
+                Err(Error::InvalidArgument | Error::NotFound)
+            ));
+        }
+    }
+
+    #[test]
+    fn translate_helper_arguments() {
+        let args = |args: &str| -> Vec<String> { args.split(' ').map(str::to_string).collect() };
+        let helper =
+            HelperArguments::parse(&args("/dev/sdb1 /mnt -o rw,noauto,allow_other -nv")).unwrap();
+        assert_eq!(helper.options, "rw,noauto,allow_other");
+        assert!(helper.verbose && !helper.fake);
+        assert_eq!(
+            helper.mount_args(),
+            args("mount --daemon -o rw,noauto,allow_other -- /dev/sdb1 /mnt")
+        );
+
+        let helper =
+            HelperArguments::parse(&args("-sf -t tananfs -obogus,ro image.img /mnt")).unwrap();
+        assert_eq!(helper.options, "ro");
+        assert!(helper.fake);
+        assert_eq!(
+            helper.mount_args(),
+            args("mount --daemon -o ro -- image.img /mnt")
+        );
+
+        for invalid in [
+            "/dev/sdb1 /mnt -o bogus",
+            "/dev/sdb1",
+            "/dev/sdb1 /mnt -x",
+            "/dev/sdb1 /mnt -o",
+        ] {
+            assert!(HelperArguments::parse(&args(invalid)).is_err());
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! Network Block Device server exporting raw contents of a device
+//!
+//! Other hosts attach the exported device with `nbd-client` and mount the
+//! filesystem on it with their own driver, without FUSE on the exporting
+//! host. The server speaks the fixed newstyle handshake, answering
+//! `NBD_OPT_EXPORT_NAME`, `NBD_OPT_INFO`, `NBD_OPT_GO` and `NBD_OPT_LIST`
+//! for its single export under any name, followed by simple replies to
+//! reads, writes, flushes and disconnects. Numbers are big-endian.
+//!
+//! Clients are served one at a time, so two hosts never cache and write the
+//! same filesystem at once, and the device is exported read-only whenever the
+//! exporting host does not hold it exclusively.
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use log::{debug, info, warn};
+
+use crate::filesystem::BlockDevice;
+use crate::Error;
+
+/// Default port of NBD servers
+pub const NBD_PORT: u16 = 10809;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTION_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_FUA: u16 = 1 << 3;
+const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_ABORT: u32 = 2;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_SERVER: u32 = 2;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const NBD_REP_ERR_INVALID: u32 = (1 << 31) + 3;
+const NBD_INFO_EXPORT: u16 = 0;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+/// Longest option data and request accepted from clients, 32 MiB
+const NBD_MAX_LENGTH: u32 = 32 << 20;
+
+#[derive(Debug)]
+pub struct NbdServer<D: BlockDevice> {
+    device: D,
+    /// Exported bytes of the device
+    size: u64,
+    read_only: bool,
+}
+
+/// Outcome of negotiating options with a client
+enum Negotiated {
+    Transmission,
+    Aborted,
+}
+
+impl<D: BlockDevice> NbdServer<D> {
+    /// Server exporting first `size` bytes of `device`
+    pub fn new(device: D, size: u64) -> Self {
+        Self {
+            device,
+            size,
+            read_only: false,
+        }
+    }
+
+    /// Reject writes of clients, announcing the export as read-only
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Serve clients connecting to `listener` one after another
+    pub fn serve(&mut self, listener: TcpListener) -> Result<(), Error> {
+        info!(
+            "Exporting {} bytes on {}",
+            self.size,
+            listener.local_addr()?
+        );
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let peer = stream.peer_addr()?;
+            info!("Serving client {peer}");
+            stream.set_nodelay(true)?;
+            match self.handle(stream) {
+                Ok(()) => info!("Client {peer} disconnected"),
+                Err(e) => warn!("Client {peer} dropped: {e}"),
+            }
+            self.device.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Negotiate with a single client and serve its requests until it
+    /// disconnects
+    pub fn handle<S: Read + Write>(&mut self, mut stream: S) -> Result<(), Error> {
+        stream.write_all(&NBD_MAGIC.to_be_bytes())?;
+        stream.write_all(&NBD_OPTION_MAGIC.to_be_bytes())?;
+        stream.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+        stream.flush()?;
+        let client_flags = read_u32(&mut stream)?;
+        let zeroes = client_flags & NBD_FLAG_C_NO_ZEROES == 0;
+        match self.negotiate(&mut stream, zeroes)? {
+            Negotiated::Transmission => self.transmit(&mut stream),
+            Negotiated::Aborted => Ok(()),
+        }
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        let flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_FUA;
+        match self.read_only {
+            true => flags | NBD_FLAG_READ_ONLY,
+            false => flags,
+        }
+    }
+
+    /// Answer options of client until it picks the export or aborts
+    fn negotiate<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+        zeroes: bool,
+    ) -> Result<Negotiated, Error> {
+        loop {
+            if read_u64(stream)? != NBD_OPTION_MAGIC {
+                return Err(Error::InvalidArgument);
+            }
+            let option = read_u32(stream)?;
+            let length = read_u32(stream)?;
+            if length > NBD_MAX_LENGTH {
+                return Err(Error::InvalidArgument);
+            }
+            let mut data = vec![0u8; length as usize];
+            stream.read_exact(&mut data)?;
+            debug!("Option {option} with {length} bytes of data");
+            match option {
+                NBD_OPT_EXPORT_NAME => {
+                    stream.write_all(&self.size.to_be_bytes())?;
+                    stream.write_all(&self.transmission_flags().to_be_bytes())?;
+                    if zeroes {
+                        stream.write_all(&[0u8; 124])?;
+                    }
+                    stream.flush()?;
+                    return Ok(Negotiated::Transmission);
+                }
+                NBD_OPT_ABORT => {
+                    reply_option(stream, option, NBD_REP_ACK, &[])?;
+                    return Ok(Negotiated::Aborted);
+                }
+                NBD_OPT_LIST => {
+                    reply_option(stream, option, NBD_REP_SERVER, &0u32.to_be_bytes())?;
+                    reply_option(stream, option, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    // Name length, name and number of requested information
+                    let name = data
+                        .get(..4)
+                        .map(|raw| u32::from_be_bytes(raw.try_into().unwrap()) as usize);
+                    if name.is_none_or(|name| data.len() < 4 + name + 2) {
+                        reply_option(stream, option, NBD_REP_ERR_INVALID, &[])?;
+                        continue;
+                    }
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend(self.size.to_be_bytes());
+                    info.extend(self.transmission_flags().to_be_bytes());
+                    reply_option(stream, option, NBD_REP_INFO, &info)?;
+                    reply_option(stream, option, NBD_REP_ACK, &[])?;
+                    if option == NBD_OPT_GO {
+                        return Ok(Negotiated::Transmission);
+                    }
+                }
+                _ => reply_option(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    /// Serve requests of client until it disconnects
+    fn transmit<S: Read + Write>(&mut self, stream: &mut S) -> Result<(), Error> {
+        let mut buffer = Vec::new();
+        loop {
+            if read_u32(stream)? != NBD_REQUEST_MAGIC {
+                return Err(Error::InvalidArgument);
+            }
+            let flags = read_u16(stream)?;
+            let command = read_u16(stream)?;
+            let handle = read_u64(stream)?;
+            let offset = read_u64(stream)?;
+            let length = read_u32(stream)?;
+            if length > NBD_MAX_LENGTH {
+                return Err(Error::InvalidArgument);
+            }
+            let in_bounds = offset
+                .checked_add(length as u64)
+                .is_some_and(|end| end <= self.size);
+            buffer.resize(length as usize, 0);
+            let error = match command {
+                NBD_CMD_READ if !in_bounds => libc::EINVAL,
+                NBD_CMD_READ => match self.read(offset, &mut buffer) {
+                    Ok(()) => {
+                        reply(stream, 0, handle, &buffer)?;
+                        continue;
+                    }
+                    Err(e) => errno(e),
+                },
+                NBD_CMD_WRITE => {
+                    // Data follows even requests which are rejected
+                    stream.read_exact(&mut buffer)?;
+                    match (self.read_only, in_bounds) {
+                        (true, _) => libc::EPERM,
+                        (false, false) => libc::ENOSPC,
+                        (false, true) => {
+                            let fua = flags & NBD_CMD_FLAG_FUA != 0;
+                            match self.write(offset, &buffer, fua) {
+                                Ok(()) => 0,
+                                Err(e) => errno(e),
+                            }
+                        }
+                    }
+                }
+                NBD_CMD_FLUSH => match self.device.flush() {
+                    Ok(()) => 0,
+                    Err(e) => errno(e.into()),
+                },
+                NBD_CMD_DISC => return Ok(()),
+                _ => libc::EINVAL,
+            };
+            reply(stream, error as u32, handle, &[])?;
+        }
+    }
+
+    fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        self.device.seek(std::io::SeekFrom::Start(offset))?;
+        self.device.read_exact(buffer)?;
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, making it durable before replying if `fua`
+    fn write(&mut self, offset: u64, data: &[u8], fua: bool) -> Result<(), Error> {
+        self.device.seek(std::io::SeekFrom::Start(offset))?;
+        self.device.write_all(data)?;
+        if fua {
+            self.device.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Error number sent to client for a failed request
+fn errno(error: Error) -> libc::c_int {
+    warn!("Request failed: {error}");
+    match libc::c_int::from(error) {
+        code @ (libc::EPERM | libc::EIO | libc::ENOMEM | libc::EINVAL | libc::ENOSPC) => code,
+        _ => libc::EIO,
+    }
+}
+
+fn reply_option<S: Write>(
+    stream: &mut S,
+    option: u32,
+    kind: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    stream.write_all(&NBD_REPLY_MAGIC.to_be_bytes())?;
+    stream.write_all(&option.to_be_bytes())?;
+    stream.write_all(&kind.to_be_bytes())?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn reply<S: Write>(stream: &mut S, error: u32, handle: u64, data: &[u8]) -> Result<(), Error> {
+    let mut header = Vec::with_capacity(16 + data.len());
+    header.extend(NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+    header.extend(error.to_be_bytes());
+    header.extend(handle.to_be_bytes());
+    header.extend(data);
+    stream.write_all(&header)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_u16<S: Read>(stream: &mut S) -> Result<u16, Error> {
+    let mut raw = [0u8; 2];
+    stream.read_exact(&mut raw)?;
+    Ok(u16::from_be_bytes(raw))
+}
+
+fn read_u32<S: Read>(stream: &mut S) -> Result<u32, Error> {
+    let mut raw = [0u8; 4];
+    stream.read_exact(&mut raw)?;
+    Ok(u32::from_be_bytes(raw))
+}
+
+fn read_u64<S: Read>(stream: &mut S) -> Result<u64, Error> {
+    let mut raw = [0u8; 8];
+    stream.read_exact(&mut raw)?;
+    Ok(u64::from_be_bytes(raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    fn option(stream: &mut UnixStream, option: u32, data: &[u8]) {
+        stream.write_all(&NBD_OPTION_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&option.to_be_bytes()).unwrap();
+        stream
+            .write_all(&(data.len() as u32).to_be_bytes())
+            .unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    /// Type and data of reply to option
+    fn option_reply(stream: &mut UnixStream, option: u32) -> (u32, Vec<u8>) {
+        assert_eq!(read_u64(stream).unwrap(), NBD_REPLY_MAGIC);
+        assert_eq!(read_u32(stream).unwrap(), option);
+        let kind = read_u32(stream).unwrap();
+        let mut data = vec![0u8; read_u32(stream).unwrap() as usize];
+        stream.read_exact(&mut data).unwrap();
+        (kind, data)
+    }
+
+    fn request(stream: &mut UnixStream, command: u16, handle: u64, offset: u64, length: u32) {
+        stream.write_all(&NBD_REQUEST_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&0u16.to_be_bytes()).unwrap();
+        stream.write_all(&command.to_be_bytes()).unwrap();
+        stream.write_all(&handle.to_be_bytes()).unwrap();
+        stream.write_all(&offset.to_be_bytes()).unwrap();
+        stream.write_all(&length.to_be_bytes()).unwrap();
+    }
+
+    /// Error of reply to request with `handle`
+    fn request_reply(stream: &mut UnixStream, handle: u64) -> u32 {
+        assert_eq!(read_u32(stream).unwrap(), NBD_SIMPLE_REPLY_MAGIC);
+        let error = read_u32(stream).unwrap();
+        assert_eq!(read_u64(stream).unwrap(), handle);
+        error
+    }
+
+    #[test]
+    fn negotiate_and_transmit() {
+        let (mut client, server_stream) = UnixStream::pair().unwrap();
+        let device = Cursor::new(vec![1u8; 8192]);
+        let server = std::thread::spawn(move || {
+            let mut server = NbdServer::new(device, 4096);
+            server.handle(server_stream).unwrap();
+            server.device.into_inner()
+        });
+
+        assert_eq!(read_u64(&mut client).unwrap(), NBD_MAGIC);
+        assert_eq!(read_u64(&mut client).unwrap(), NBD_OPTION_MAGIC);
+        assert_eq!(read_u16(&mut client).unwrap(), 3);
+        client.write_all(&3u32.to_be_bytes()).unwrap();
+        option(&mut client, 42, &[]);
+        assert_eq!(option_reply(&mut client, 42).0, NBD_REP_ERR_UNSUP);
+        option(&mut client, NBD_OPT_GO, &[0, 0, 0, 0, 0, 0]);
+        let (kind, info) = option_reply(&mut client, NBD_OPT_GO);
+        assert_eq!(kind, NBD_REP_INFO);
+        assert_eq!(&info[2..10], &4096u64.to_be_bytes());
+        assert_eq!(option_reply(&mut client, NBD_OPT_GO).0, NBD_REP_ACK);
+
+        request(&mut client, NBD_CMD_WRITE, 1, 100, 4);
+        client.write_all(&[2, 3, 4, 5]).unwrap();
+        assert_eq!(request_reply(&mut client, 1), 0);
+        request(&mut client, NBD_CMD_READ, 2, 98, 8);
+        assert_eq!(request_reply(&mut client, 2), 0);
+        let mut data = [0u8; 8];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(data, [1, 1, 2, 3, 4, 5, 1, 1]);
+        // Past end of export, even though the device is larger
+        request(&mut client, NBD_CMD_READ, 3, 4090, 8);
+        assert_eq!(request_reply(&mut client, 3), libc::EINVAL as u32);
+        request(&mut client, NBD_CMD_FLUSH, 4, 0, 0);
+        assert_eq!(request_reply(&mut client, 4), 0);
+        request(&mut client, NBD_CMD_DISC, 5, 0, 0);
+
+        let contents = server.join().unwrap();
+        assert_eq!(contents[100..104], [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn read_only_export() {
+        let (mut client, server_stream) = UnixStream::pair().unwrap();
+        let device = Cursor::new(vec![0u8; 1024]);
+        let server = std::thread::spawn(move || {
+            let mut server = NbdServer::new(device, 1024).with_read_only();
+            server.handle(server_stream).unwrap();
+        });
+        let mut handshake = [0u8; 18];
+        client.read_exact(&mut handshake).unwrap();
+        client.write_all(&0u32.to_be_bytes()).unwrap();
+        option(&mut client, NBD_OPT_EXPORT_NAME, b"any");
+        assert_eq!(read_u64(&mut client).unwrap(), 1024);
+        let flags = read_u16(&mut client).unwrap();
+        assert_ne!(flags & NBD_FLAG_READ_ONLY, 0);
+        client.read_exact(&mut [0u8; 124]).unwrap();
+        request(&mut client, NBD_CMD_WRITE, 9, 0, 2);
+        client.write_all(&[1, 2]).unwrap();
+        assert_eq!(request_reply(&mut client, 9), libc::EPERM as u32);
+        request(&mut client, NBD_CMD_DISC, 10, 0, 0);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Snapshots of an unmounted filesystem
+
+use std::sync::{Arc, Mutex};
+
+use crate::devices::fence::{self, Access};
+use crate::filesystem::{Filesystem, ROOT_INODE};
+use crate::Error;
+
+/// Run snapshot `action` with `names` on filesystem on `device_path`
+///
+/// Actions are `list`, the default, `create`, `delete` and `rollback` of a
+/// single snapshot, and `diff` printing changes made since a snapshot, to the
+/// current tree or to another snapshot.
+pub fn snapshot(device_path: &str, action: Option<&str>, names: &[String]) -> Result<(), Error> {
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let fs = Filesystem::load(Box::new(device), block_size)?;
+    let modifies = !matches!(action, None | Some("list" | "diff"));
+    if fs.read_only && modifies {
+        return Err(Error::ReadOnly);
+    }
+    let fs = Arc::new(Mutex::new(fs));
+    match (action.unwrap_or("list"), names) {
+        ("list", []) => {
+            for name in Filesystem::snapshots(&fs)? {
+                println!("{name}");
+            }
+        }
+        ("create", [name]) => {
+            Filesystem::create_snapshot(&fs, name)?;
+        }
+        ("delete", [name]) => Filesystem::delete_snapshot(&fs, name)?,
+        ("rollback", [name]) => Filesystem::rollback(&fs, name)?,
+        ("diff", [from, rest @ ..]) if rest.len() <= 1 => {
+            let from = Filesystem::snapshot(&fs, from)?;
+            let to = match rest.first() {
+                Some(to) => Filesystem::snapshot(&fs, to)?,
+                None => ROOT_INODE,
+            };
+            for change in Filesystem::diff(&fs, from, to)? {
+                println!("{change}");
+            }
+        }
+        _ => return Err(Error::InvalidArgument),
+    }
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Concurrent mixed operations against a fresh filesystem
+//!
+//! Every worker thread owns a directory, in which it creates, writes, reads,
+//! truncates and removes files and subdirectories, comparing contents read
+//! back with its own model of them. Between rounds of operations, workers
+//! are paused so the whole filesystem can be flushed and checked for
+//! consistency with [Filesystem::check].
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::devices::mem::MemBlockDevice;
+use crate::filesystem::{BlockDevice, Filesystem, LockFilesystem, ROOT_INODE};
+use crate::filetypes::{Directory, DirectoryChildIdentifier, FileOperations, Owner, RegularFile};
+use crate::Error;
+
+/// Capacity of filesystem under stress
+pub const STRESS_CAPACITY: u64 = 64 << 20;
+pub const STRESS_BLOCK_SIZE: u32 = 4096;
+pub const DEFAULT_THREADS: usize = 4;
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// Time between consistency checks
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// Most files kept by a single worker
+const MAX_FILES: usize = 16;
+/// Largest single write
+const MAX_WRITE: usize = 24 << 10;
+/// Largest file kept by a worker
+const MAX_FILE_SIZE: usize = 96 << 10;
+
+/// Run `threads` workers for `duration` against a filesystem in memory, or
+/// in a new image file at `target`, which is removed afterwards
+pub fn stress(target: &str, threads: usize, duration: Duration) -> Result<(), Error> {
+    let device: Box<dyn BlockDevice> = match target {
+        "memory" => Box::new(MemBlockDevice::new(STRESS_CAPACITY)),
+        path => {
+            let file = std::fs::File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)?;
+            file.set_len(STRESS_CAPACITY)?;
+            Box::new(file)
+        }
+    };
+    info!("Stressing filesystem in {target} with {threads} threads for {duration:?}");
+    let result = run(device, threads, duration);
+    if target != "memory" {
+        std::fs::remove_file(Path::new(target))?;
+    }
+    let (operations, checks) = result?;
+    println!("Operations: {operations}");
+    println!("Consistency checks: {checks}");
+    Ok(())
+}
+
+/// Stress filesystem on `device`, returning numbers of operations and checks
+fn run(
+    device: Box<dyn BlockDevice>,
+    threads: usize,
+    duration: Duration,
+) -> Result<(u64, u64), Error> {
+    let fs = Filesystem::new(device, STRESS_CAPACITY, STRESS_BLOCK_SIZE).with_quotas();
+    let fs = Arc::new(Mutex::new(fs));
+    Filesystem::format(&fs, Owner::default())?;
+    let gate = Arc::new(RwLock::new(()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let operations = Arc::new(AtomicU64::new(0));
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    info!("Stress seed {seed}");
+
+    let mut workers = Vec::with_capacity(threads);
+    for thread in 0..threads {
+        let directory = Directory::new(
+            &fs,
+            ROOT_INODE,
+            &format!("worker{thread}"),
+            0o750,
+            Owner::default(),
+        )?
+        .inode
+        .index;
+        let mut worker = Worker {
+            fs: fs.clone(),
+            directory,
+            random: Random::new(seed ^ (thread as u64 + 1).wrapping_mul(0x9E3779B97F4A7C15)),
+            files: BTreeMap::new(),
+            subdirectories: Vec::new(),
+            next_name: 0,
+        };
+        let (gate, stop, operations) = (gate.clone(), stop.clone(), operations.clone());
+        workers.push(std::thread::spawn(move || -> Result<(), Error> {
+            while !stop.load(Ordering::Relaxed) {
+                let _running = gate.read()?;
+                if let Err(e) = worker.step() {
+                    error!("Worker {thread} failed: {e}");
+                    stop.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                operations.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        }));
+    }
+
+    let deadline = Instant::now() + duration;
+    let mut checks = 0;
+    let mut result = Ok(());
+    while result.is_ok() && !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+        std::thread::sleep(CHECK_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+        let _paused = gate.write()?;
+        result = verify(&fs);
+        checks += 1;
+    }
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        let outcome = worker.join().map_err(|_| Error::ThreadSync)?;
+        result = result.and(outcome);
+    }
+    result?;
+    verify(&fs)?;
+    Ok((operations.load(Ordering::Relaxed), checks + 1))
+}
+
+/// Flush filesystem and check its consistency
+fn verify(fs: &Arc<Mutex<Filesystem>>) -> Result<(), Error> {
+    fs.lock_fs()?.force_flush()?;
+    let report = Filesystem::check(fs)?;
+    if !report.is_clean() {
+        error!("Inconsistent filesystem:\n{report}");
+        return Err(Error::Corruption);
+    }
+    Ok(())
+}
+
+/// Worker thread with expected contents of its files
+struct Worker {
+    fs: Arc<Mutex<Filesystem>>,
+    /// Inode of directory owned by worker
+    directory: u64,
+    random: Random,
+    files: BTreeMap<String, (u64, Vec<u8>)>,
+    subdirectories: Vec<String>,
+    next_name: u64,
+}
+
+impl Worker {
+    /// Perform a single random operation
+    fn step(&mut self) -> Result<(), Error> {
+        match self.random.below(10) {
+            0 | 1 if self.files.len() < MAX_FILES => self.create(),
+            2..=4 if !self.files.is_empty() => self.write(),
+            5 | 6 if !self.files.is_empty() => self.read(),
+            7 if !self.files.is_empty() => self.truncate(),
+            8 if !self.files.is_empty() => self.remove(),
+            9 => self.toggle_subdirectory(),
+            _ => Ok(()),
+        }
+    }
+
+    fn name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{prefix}{}", self.next_name)
+    }
+
+    /// Name, inode and expected contents of a random file
+    fn pick(&mut self) -> (String, u64, Vec<u8>) {
+        let nth = self.random.below(self.files.len() as u64) as usize;
+        let (name, (inode, data)) = self.files.iter().nth(nth).expect("files are not empty");
+        (name.clone(), *inode, data.clone())
+    }
+
+    fn create(&mut self) -> Result<(), Error> {
+        let name = self.name("file");
+        let file = RegularFile::new(&self.fs, self.directory, &name, 0o640, Owner::default())?;
+        self.files.insert(name, (file.inode.index, Vec::new()));
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        let (name, inode, mut data) = self.pick();
+        let offset = self.random.below(data.len() as u64 + 1) as usize;
+        let length = self.random.below(MAX_WRITE as u64) as usize + 1;
+        if offset + length > MAX_FILE_SIZE {
+            return Ok(());
+        }
+        let byte = self.random.next() as u8;
+        let buffer = vec![byte; length];
+        RegularFile::load(&self.fs, inode)?.write(offset as u64, &buffer)?;
+        data.resize(data.len().max(offset + length), 0);
+        data[offset..offset + length].copy_from_slice(&buffer);
+        self.files.insert(name, (inode, data));
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<(), Error> {
+        let (name, inode, data) = self.pick();
+        let read = RegularFile::load(&self.fs, inode)?.read(0, data.len() as u64 + 1)?;
+        if read != data {
+            error!(
+                "File {name} holds {} bytes differing from {} written",
+                read.len(),
+                data.len()
+            );
+            return Err(Error::Corruption);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> Result<(), Error> {
+        let (name, inode, mut data) = self.pick();
+        let size = self.random.below(data.len() as u64 + 1);
+        let mut file = RegularFile::load(&self.fs, inode)?;
+        file.file.shrink(size)?;
+        file.modified = true;
+        drop(file);
+        data.truncate(size as usize);
+        self.files.insert(name, (inode, data));
+        Ok(())
+    }
+
+    fn remove(&mut self) -> Result<(), Error> {
+        let (name, ..) = self.pick();
+        Directory::load(&self.fs, self.directory)?
+            .remove_child(DirectoryChildIdentifier::Name(&name))?;
+        self.files.remove(&name);
+        Ok(())
+    }
+
+    /// Remove a random subdirectory, or create one
+    fn toggle_subdirectory(&mut self) -> Result<(), Error> {
+        if !self.subdirectories.is_empty() && self.random.below(2) == 0 {
+            let nth = self.random.below(self.subdirectories.len() as u64) as usize;
+            let name = self.subdirectories.swap_remove(nth);
+            return Directory::load(&self.fs, self.directory)?
+                .remove_child(DirectoryChildIdentifier::Name(&name));
+        }
+        let name = self.name("dir");
+        Directory::new(&self.fs, self.directory, &name, 0o750, Owner::default())?;
+        self.subdirectories.push(name);
+        Ok(())
+    }
+}
+
+/// Xorshift generator, as stress runs need no stronger randomness
+#[derive(Debug)]
+pub(crate) struct Random(u64);
+
+impl Random {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number less than `bound`
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound.max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{run, STRESS_CAPACITY};
+    use crate::devices::mem::MemBlockDevice;
+
+    #[test]
+    fn short_run() {
+        let device = Box::new(MemBlockDevice::new(STRESS_CAPACITY));
+        let (operations, checks) = run(device, 3, Duration::from_millis(1500)).unwrap();
+        assert!(operations > 0);
+        assert!(checks > 1);
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Display;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::ops::Range;
 
 use super::*;
 use crate::Error;
@@ -9,14 +10,20 @@ pub const MINIMUM_SIZE: u64 = 1024;
 pub const BITS_IN_BYTE: u64 = 8;
 pub const BYTES_IN_USIZE: u64 = size_of::<usize>() as u64;
 pub const BITS_IN_USIZE: u64 = BYTES_IN_USIZE * BITS_IN_BYTE;
+/// Chunks of bitfield in every extension, holding bits of a chunk of inodes
+const EXTENSION_CHUNKS: usize = (INODE_CHUNK_SIZE / BITS_IN_USIZE) as usize;
 
 impl<T: AsBitmap> Bitmap<T> {
     /// Return empty bitmap with size as power of 2
+    /// All of it is flushed, as the device may hold anything in its place
     fn empty(count: u64, position: u64) -> Self {
         Self {
             bitfield: vec![0; Self::size_in_usize(count)],
             count,
             position,
+            dirty: (0..Self::size_in_usize(count)).collect(),
+            base_chunks: Self::size_in_usize(count),
+            extensions: Vec::new(),
             __type: PhantomData,
         }
     }
@@ -38,24 +45,28 @@ impl<T: AsBitmap> Bitmap<T> {
     }
 
     /// Modify occupancy
-    pub(crate) fn set(&mut self, index: u64, value: bool) -> Result<(), Error> {
+    pub fn set(&mut self, index: u64, value: bool) -> Result<(), Error> {
         if index >= self.count {
             return Err(Error::OutOfBounds);
         }
-        let row = index / BITS_IN_USIZE;
+        let row = (index / BITS_IN_USIZE) as usize;
         let col = index % BITS_IN_USIZE;
+        let previous = self.bitfield[row];
         if value {
             let mask = 1usize << col;
-            self.bitfield[row as usize] |= mask;
+            self.bitfield[row] |= mask;
         } else {
             let mask = !(1usize << col);
-            self.bitfield[row as usize] &= mask;
+            self.bitfield[row] &= mask;
+        }
+        if self.bitfield[row] != previous {
+            self.dirty.insert(row);
         }
         Ok(())
     }
 
     /// Get occupancy
-    pub(crate) fn get(&self, index: u64) -> Result<bool, Error> {
+    pub fn get(&self, index: u64) -> Result<bool, Error> {
         if index == NULL_BLOCK {
             return Err(Error::NullBlock);
         }
@@ -71,66 +82,197 @@ impl<T: AsBitmap> Bitmap<T> {
     /// Load bitmap from block device
     pub(crate) fn load<D: Read + Seek>(&mut self, block_device: &mut D) -> Result<(), Error> {
         block_device.seek(SeekFrom::Start(self.position))?;
-        self.load_content(block_device)?;
+        self.load_content(block_device, 0..self.base_chunks)?;
+        for (extension, position) in self.extensions.clone().into_iter().enumerate() {
+            let start = self.base_chunks + extension * EXTENSION_CHUNKS;
+            block_device.seek(SeekFrom::Start(position))?;
+            self.load_content(block_device, start..start + EXTENSION_CHUNKS)?;
+        }
+        self.dirty.clear();
         Ok(())
     }
 
-    /// Flush bitmap to block device
-    pub(crate) fn flush<D: Write + Seek>(&self, block_device: &mut D) -> Result<(), Error> {
-        block_device.seek(SeekFrom::Start(self.position))?;
-        self.flush_content(block_device)?;
+    /// Append [INODE_CHUNK_SIZE] empty fields, stored at `position` apart
+    /// from the rest of the bitmap
+    pub(crate) fn extend(&mut self, position: u64) {
+        let start = self.bitfield.len();
+        self.bitfield.resize(start + EXTENSION_CHUNKS, 0);
+        self.dirty.extend(start..self.bitfield.len());
+        self.extensions.push(position);
+        self.count = self.bitfield.len() as u64 * BITS_IN_USIZE;
+    }
+
+    /// Position of `chunk` of bitfield and the end of chunks stored after it
+    fn chunk_position(&self, chunk: usize) -> (u64, usize) {
+        match chunk.checked_sub(self.base_chunks) {
+            None => (
+                self.position + chunk as u64 * BYTES_IN_USIZE,
+                self.base_chunks,
+            ),
+            Some(extended) => (
+                self.extensions[extended / EXTENSION_CHUNKS]
+                    + (extended % EXTENSION_CHUNKS) as u64 * BYTES_IN_USIZE,
+                chunk - extended % EXTENSION_CHUNKS + EXTENSION_CHUNKS,
+            ),
+        }
+    }
+
+    /// Flush chunks modified since the last flush to block device
+    ///
+    /// Consecutive modified chunks are written together, so a flush after
+    /// a few allocations writes a few bytes instead of the whole bitmap.
+    pub(crate) fn flush<D: Write + Seek>(&mut self, block_device: &mut D) -> Result<(), Error> {
+        let mut chunks = self.dirty.iter().copied().peekable();
+        while let Some(start) = chunks.next() {
+            let (position, stored_end) = self.chunk_position(start);
+            let mut end = start + 1;
+            while end < stored_end && chunks.next_if_eq(&end).is_some() {
+                end += 1;
+            }
+            block_device.seek(SeekFrom::Start(position))?;
+            self.flush_content(block_device, start..end)?;
+        }
+        self.dirty.clear();
         Ok(())
     }
 
-    /// Load bitfield from block device
-    fn load_content<D: Read + Seek>(&mut self, block_device: &mut D) -> Result<(), Error> {
-        let buffer_size = Bitmap::<T>::size_in_bytes(self.count) as usize;
-        let mut buffer = vec![0u8; buffer_size];
+    /// Load `chunks` of bitfield from block device
+    fn load_content<D: Read + Seek>(
+        &mut self,
+        block_device: &mut D,
+        chunks: Range<usize>,
+    ) -> Result<(), Error> {
+        let mut buffer = vec![0u8; chunks.len() * 8];
         block_device.read_exact(&mut buffer)?;
-        for chunk in 0..Bitmap::<T>::size_in_usize(self.count) {
+        for (chunk, raw_chunk) in chunks.zip(buffer.chunks_exact(8)) {
             self.bitfield[chunk] = usize::from_le_bytes({
-                let mut raw_chunk = [0; 8];
-                raw_chunk.copy_from_slice(&buffer[chunk * 8..(chunk + 1) * 8]);
-                raw_chunk
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(raw_chunk);
+                bytes
             });
         }
         Ok(())
     }
 
-    /// Flush bitfield to block device
-    fn flush_content<D: Write + Seek>(&self, block_device: &mut D) -> Result<(), Error> {
-        let buffer_size = Bitmap::<T>::size_in_bytes(self.count) as usize;
-        let mut buffer = vec![0u8; buffer_size];
-        for chunk in 0..Bitmap::<T>::size_in_usize(self.count) {
-            buffer[chunk * 8..(chunk + 1) * 8].copy_from_slice(&self.bitfield[chunk].to_le_bytes());
+    /// Flush `chunks` of bitfield to block device
+    fn flush_content<D: Write + Seek>(
+        &self,
+        block_device: &mut D,
+        chunks: Range<usize>,
+    ) -> Result<(), Error> {
+        let mut buffer = vec![0u8; chunks.len() * 8];
+        for (chunk, raw_chunk) in chunks.zip(buffer.chunks_exact_mut(8)) {
+            raw_chunk.copy_from_slice(&self.bitfield[chunk].to_le_bytes());
         }
         block_device.write_all(&buffer)?;
         Ok(())
     }
 
-    /// Get index of first empty field starting at `after`
-    pub(crate) fn next_free(&self, after: u64) -> Option<u64> {
-        let after_chunk = after / BITS_IN_USIZE;
-        let after_bit = after % BITS_IN_USIZE;
-        for chunk in after_chunk as usize..self.bitfield.len() {
-            if self.bitfield[chunk] == usize::MAX {
-                continue;
-            }
-            for bit in after_bit..(BYTES_IN_USIZE * BITS_IN_BYTE) {
-                if self.bitfield[chunk] & 1usize << bit == 0 {
-                    let index = chunk as u64 * BITS_IN_USIZE + bit;
-                    return Some(index);
-                }
+    /// Extend bitmap to `count` fields, new ones being empty
+    pub(crate) fn grow(&mut self, count: u64) {
+        debug_assert!(count >= self.count && self.extensions.is_empty());
+        let previous = self.bitfield.len();
+        self.bitfield.resize(Self::size_in_usize(count), 0);
+        self.dirty.extend(previous..self.bitfield.len());
+        self.base_chunks = self.bitfield.len();
+        self.count = count;
+    }
+
+    /// Drop fields from `count` onwards, which must all be empty
+    pub(crate) fn shrink(&mut self, count: u64) {
+        debug_assert!(count <= self.count && self.extensions.is_empty());
+        let length = Self::size_in_usize(count);
+        self.bitfield.truncate(length);
+        self.dirty.retain(|&row| row < length);
+        self.base_chunks = length;
+        self.count = count;
+    }
+
+    /// Bytes held by bitfield in memory
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.bitfield.capacity() * BYTES_IN_USIZE as usize
+    }
+
+    /// Number of set fields
+    pub(crate) fn count_set(&self) -> u64 {
+        self.bitfield.iter().map(|c| c.count_ones() as u64).sum()
+    }
+
+    /// Get index of first field starting at `after` which is occupied if
+    /// `occupied` is set, or empty otherwise
+    ///
+    /// Whole chunks are inspected at once, with the wanted fields set in
+    /// a mask whose trailing zeros give the position of the first one.
+    fn next_matching(&self, after: u64, occupied: bool) -> Option<u64> {
+        let after_chunk = (after / BITS_IN_USIZE) as usize;
+        // Fields before `after` in its chunk are never matched
+        let mut skipped = (1usize << (after % BITS_IN_USIZE)) - 1;
+        for chunk in after_chunk..self.bitfield.len() {
+            let fields = match occupied {
+                true => self.bitfield[chunk],
+                false => !self.bitfield[chunk],
+            };
+            let matching = fields & !std::mem::take(&mut skipped);
+            if matching != 0 {
+                let index = chunk as u64 * BITS_IN_USIZE + matching.trailing_zeros() as u64;
+                return (index < self.count).then_some(index);
             }
         }
         None
     }
+
+    /// Get index of first empty field starting at `after`
+    pub fn next_free(&self, after: u64) -> Option<u64> {
+        self.next_matching(after, false)
+    }
+
+    /// Get index of first occupied field starting at `after`
+    pub(crate) fn next_occupied(&self, after: u64) -> Option<u64> {
+        self.next_matching(after, true)
+    }
+
+    /// Get index of first of `length` consecutive empty fields starting at
+    /// `after`
+    pub fn next_free_range(&self, after: u64, length: u64) -> Option<u64> {
+        let mut start = self.next_free(after)?;
+        loop {
+            let end = self.next_matching(start, true).unwrap_or(self.count);
+            if end - start >= length {
+                return Some(start);
+            }
+            start = self.next_free(end)?;
+        }
+    }
+
+    /// Get index of first empty field starting at `after`, continuing from
+    /// the beginning if there is none
+    pub(crate) fn next_free_wrapping(&self, after: u64) -> Option<u64> {
+        self.next_free(after).or_else(|| self.next_free(0))
+    }
 }
 
 impl Bitmap<Inode> {
-    /// Create new bitmap with all inodes inactive
+    /// Create new bitmap with all inodes inactive, extended by bitmaps of
+    /// chunks of inodes
     pub fn new(superblock: &Superblock) -> Self {
-        Self::empty(superblock.inode_count, superblock.bitmap_region_start())
+        let mut bitmap = Self::empty(superblock.inode_count, superblock.bitmap_region_start());
+        for chunk in 0..superblock.inode_chunk_count as usize {
+            let position = superblock
+                .inode_chunk_bitmap(chunk)
+                .expect("chunks of inodes are checked when loading superblock");
+            bitmap.extend(position);
+        }
+        bitmap
+    }
+
+    /// Follow bitmaps of chunks of inodes to their positions in `superblock`,
+    /// after block region moved
+    pub(crate) fn relocate(&mut self, superblock: &Superblock) {
+        for (chunk, position) in self.extensions.iter_mut().enumerate() {
+            *position = superblock
+                .inode_chunk_bitmap(chunk)
+                .expect("chunks of inodes are checked when loading superblock");
+        }
     }
 }
 
@@ -198,17 +340,17 @@ mod tests {
         assert!(bitmap.set(1000, true).is_ok());
         assert!(bitmap.set(10000, true).is_ok());
         assert!(bitmap.set(bitmap.count + 1, true).is_err());
-        assert_eq!(bitmap.get(10).unwrap(), false);
-        assert_eq!(bitmap.get(99).unwrap(), false);
-        assert_eq!(bitmap.get(100).unwrap(), true);
-        assert_eq!(bitmap.get(101).unwrap(), false);
-        assert_eq!(bitmap.get(999).unwrap(), false);
-        assert_eq!(bitmap.get(1000).unwrap(), true);
-        assert_eq!(bitmap.get(1001).unwrap(), false);
-        assert_eq!(bitmap.get(9999).unwrap(), false);
-        assert_eq!(bitmap.get(10000).unwrap(), true);
-        assert_eq!(bitmap.get(10001).unwrap(), false);
-        assert_eq!(bitmap.get(20000).unwrap(), false);
+        assert!(!bitmap.get(10).unwrap());
+        assert!(!bitmap.get(99).unwrap());
+        assert!(bitmap.get(100).unwrap());
+        assert!(!bitmap.get(101).unwrap());
+        assert!(!bitmap.get(999).unwrap());
+        assert!(bitmap.get(1000).unwrap());
+        assert!(!bitmap.get(1001).unwrap());
+        assert!(!bitmap.get(9999).unwrap());
+        assert!(bitmap.get(10000).unwrap());
+        assert!(!bitmap.get(10001).unwrap());
+        assert!(!bitmap.get(20000).unwrap());
         assert!(bitmap.get(bitmap.count + 1).is_err());
     }
 
@@ -229,14 +371,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn flush_modified_chunks() {
+        let superblock = Superblock::new(10_000_000, 512);
+        let mut bitmap = Bitmap::<Block>::new(&superblock);
+        let mut dev = Cursor::new(vec![0u8; superblock.inode_region_start() as usize]);
+        assert!(bitmap.load(&mut dev).is_ok());
+        bitmap.set(BITS_IN_USIZE * 2 + 1, true).unwrap();
+        // Setting a field to its current value leaves chunk unmodified
+        bitmap.set(BITS_IN_USIZE * 3, false).unwrap();
+        assert_eq!(bitmap.dirty.iter().copied().collect::<Vec<_>>(), vec![2]);
+        // Unmodified chunks are not written over
+        let position = bitmap.position as usize;
+        dev.get_mut()[position..position + 16].fill(0xFF);
+        assert!(bitmap.flush(&mut dev).is_ok());
+        assert!(bitmap.dirty.is_empty());
+        assert_eq!(&dev.get_ref()[position..position + 16], &[0xFF; 16]);
+        assert_eq!(dev.get_ref()[position + 16], 0b10);
+        assert_eq!(&dev.get_ref()[position + 17..position + 32], &[0; 15]);
+    }
+
     #[test]
     fn next_free() {
         let superblock = Superblock::new(10_000_000, 512);
         let mut bitmap = Bitmap::<Inode>::new(&superblock);
         for index in 0..BITS_IN_USIZE * 2 {
-            assert_eq!(bitmap.get(index).unwrap(), false);
+            assert!(!bitmap.get(index).unwrap());
             assert_eq!(bitmap.next_free(index), Some(index));
             assert!(bitmap.set(index, true).is_ok());
         }
     }
+
+    #[test]
+    fn next_free_wrapping() {
+        let superblock = Superblock::new(10_000_000, 512);
+        let mut bitmap = Bitmap::<Block>::new(&superblock);
+        bitmap.set(BITS_IN_USIZE + 3, true).unwrap();
+        // Offset within chunk applies only to the first one
+        for index in BITS_IN_USIZE - 2..BITS_IN_USIZE + 3 {
+            bitmap.set(index, true).unwrap();
+        }
+        assert_eq!(bitmap.next_free(5), Some(5));
+        assert_eq!(bitmap.next_free(BITS_IN_USIZE - 2), Some(BITS_IN_USIZE + 4));
+        for index in 0..10 {
+            bitmap.set(index, true).unwrap();
+        }
+        assert_eq!(bitmap.next_free(bitmap.count), None);
+        assert_eq!(bitmap.next_free_wrapping(bitmap.count), Some(10));
+        assert_eq!(
+            bitmap.next_free_wrapping(bitmap.count - 1),
+            Some(bitmap.count - 1)
+        );
+        // Fields past the count are never free
+        for index in 0..bitmap.count {
+            bitmap.set(index, true).unwrap();
+        }
+        assert_eq!(bitmap.next_free_wrapping(5), None);
+    }
+
+    #[test]
+    fn next_free_range() {
+        let superblock = Superblock::new(10_000_000, 512);
+        let mut bitmap = Bitmap::<Block>::new(&superblock);
+        for index in (0..BITS_IN_USIZE * 3 - 2).filter(|index| index % 10 != 0) {
+            bitmap.set(index, true).unwrap();
+        }
+        bitmap.set(BITS_IN_USIZE * 3 + 5, true).unwrap();
+        assert_eq!(bitmap.next_free_range(0, 1), Some(0));
+        assert_eq!(bitmap.next_free_range(1, 1), Some(10));
+        // Run crossing chunk boundary
+        assert_eq!(bitmap.next_free_range(0, 2), Some(BITS_IN_USIZE * 3 - 2));
+        assert_eq!(bitmap.next_free_range(0, 7), Some(BITS_IN_USIZE * 3 - 2));
+        assert_eq!(bitmap.next_free_range(0, 8), Some(BITS_IN_USIZE * 3 + 6));
+        let count = bitmap.count;
+        assert_eq!(bitmap.next_free_range(0, count), None);
+        bitmap.set(count - 1, true).unwrap();
+        assert_eq!(bitmap.next_free_range(count - 4, 3), Some(count - 4));
+        assert_eq!(bitmap.next_free_range(count - 3, 3), None);
+    }
 }
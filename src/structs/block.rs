@@ -1,20 +1,19 @@
 use bytemuck::Pod;
-use std::{fmt::Display, io::SeekFrom};
+use log::error;
+use std::{borrow::Cow, fmt::Display, io::SeekFrom};
 
 use super::*;
-use crate::{filesystem::Filesystem, Error};
-
-const LENGTH_AS_BYTES: usize = 2;
-const COUNT_AS_BYTES: usize = 4;
+use crate::{filesystem::Filesystem, filetypes::Owner, Error};
 
 impl AsBitmap for Block {}
 
 impl Block {
-    pub fn new(fs: &mut Filesystem) -> Result<Self, Error> {
-        let index = fs.acquire_block()?;
+    pub fn new(fs: &mut Filesystem, owner: Owner) -> Result<Self, Error> {
+        let index = fs.acquire_block(owner)?;
         Ok(Self {
             index,
             data: vec![0; fs.superblock.block_size as usize],
+            compress: true,
         })
     }
 
@@ -26,6 +25,89 @@ impl Block {
         Ok(Self {
             index,
             data: vec![0; fs.superblock.block_size as usize],
+            compress: true,
+        })
+    }
+
+    /// Bytes of block as stored on the device, and whether they are
+    /// compressed, as they are if the filesystem compresses blocks and they
+    /// shrink enough to fit a header
+    fn encode(&self, superblock: &Superblock) -> Result<(Cow<'_, [u8]>, bool), Error> {
+        if !self.compress {
+            return Ok((Cow::Borrowed(&self.data), false));
+        }
+        let algorithm = superblock.compression()?;
+        let limit = self.data.len() - COMPRESSION_HEADER_SIZE;
+        let Some(compressed) = algorithm.compress(&self.data, limit) else {
+            return Ok((Cow::Borrowed(&self.data), false));
+        };
+        let mut stored = Vec::with_capacity(self.data.len());
+        stored.extend([algorithm as u8, 0]);
+        stored.extend((compressed.len() as u16).to_le_bytes());
+        stored.extend(compressed);
+        stored.resize(self.data.len(), 0);
+        Ok((Cow::Owned(stored), true))
+    }
+
+    /// Block from bytes `stored` on the device and their `checksum`, if the
+    /// filesystem has them, or `None` if the checksum does not match
+    fn decode(
+        superblock: &Superblock,
+        index: u64,
+        stored: Vec<u8>,
+        checksum: Option<u32>,
+    ) -> Result<Option<Self>, Error> {
+        let Some(checksum) = checksum else {
+            return Ok(Some(Self {
+                index,
+                data: stored,
+                compress: true,
+            }));
+        };
+        let computed = Self::stored_checksum(superblock, &stored, false)?;
+        if checksum == computed {
+            return Ok(Some(Self {
+                index,
+                data: stored,
+                compress: true,
+            }));
+        }
+        if checksum != computed ^ COMPRESSED_BLOCK_TAG
+            || superblock.incompat_flags & INCOMPAT_COMPRESSION == 0
+        {
+            return Ok(None);
+        }
+        let Ok(algorithm) = CompressionAlgorithm::try_from(stored[0]) else {
+            return Ok(None);
+        };
+        let length = u16::from_le_bytes([stored[2], stored[3]]) as usize;
+        let payload = stored
+            .get(COMPRESSION_HEADER_SIZE..COMPRESSION_HEADER_SIZE + length)
+            .ok_or(Error::Corruption)?;
+        match algorithm.decompress(payload, stored.len()) {
+            Ok(data) => Ok(Some(Self {
+                index,
+                data,
+                compress: true,
+            })),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Checksum of bytes `stored` on the device, in checksum region of
+    /// [Superblock], tagged if they are `compressed`
+    fn stored_checksum(
+        superblock: &Superblock,
+        stored: &[u8],
+        compressed: bool,
+    ) -> Result<u32, Error> {
+        let checksum = superblock
+            .checksum_algorithm()?
+            .checksummer()
+            .checksum(stored);
+        Ok(match compressed {
+            true => checksum ^ COMPRESSED_BLOCK_TAG,
+            false => checksum,
         })
     }
 
@@ -53,6 +135,125 @@ impl Block {
             Ok(&data[end..])
         }
     }
+
+    /// Load block without verifying its checksum, to inspect or repair a damaged one
+    ///
+    /// Compressed blocks are decompressed only if their checksum matches,
+    /// otherwise bytes stored on the device are returned.
+    pub(crate) fn load_unverified<D: Read + Seek>(
+        block_device: &mut D,
+        superblock: &Superblock,
+        index: u64,
+    ) -> Result<Self, Error> {
+        let position = superblock.block_position(index)?;
+        block_device.seek(SeekFrom::Start(position))?;
+        let mut block_raw = vec![0u8; superblock.block_size as usize];
+        block_device.read_exact(&mut block_raw)?;
+        if superblock.incompat_flags & INCOMPAT_COMPRESSION != 0 {
+            if let Some(checksum) = Self::read_checksum(block_device, superblock, index)? {
+                let decoded = Self::decode(superblock, index, block_raw.clone(), Some(checksum));
+                if let Ok(Some(block)) = decoded {
+                    return Ok(block);
+                }
+            }
+        }
+        Ok(Self {
+            data: block_raw,
+            index,
+            compress: true,
+        })
+    }
+
+    /// Stored checksum of block, if filesystem has them
+    fn read_checksum<D: Read + Seek>(
+        block_device: &mut D,
+        superblock: &Superblock,
+        index: u64,
+    ) -> Result<Option<u32>, Error> {
+        let Some(position) = superblock.block_checksum_position(index)? else {
+            return Ok(None);
+        };
+        block_device.seek(SeekFrom::Start(position))?;
+        let mut checksum = [0u8; BLOCK_CHECKSUM_SIZE as usize];
+        block_device.read_exact(&mut checksum)?;
+        Ok(Some(u32::from_le_bytes(checksum)))
+    }
+
+    /// Write blocks with consecutive indices, ordered by them, in a single
+    /// write of the device, followed by a single write of their checksums
+    pub(crate) fn flush_run<D: Write + Seek>(
+        block_device: &mut D,
+        superblock: &Superblock,
+        blocks: &[&Self],
+    ) -> Result<(), Error> {
+        let Some(first) = blocks.first() else {
+            return Ok(());
+        };
+        debug_assert!(blocks
+            .iter()
+            .zip(first.index..)
+            .all(|(block, index)| block.index == index));
+        let position = superblock.block_position(first.index)?;
+        superblock.block_position(first.index + blocks.len() as u64 - 1)?;
+        let mut data = Vec::with_capacity(blocks.len() * superblock.block_size as usize);
+        let mut checksums = Vec::with_capacity(blocks.len() * BLOCK_CHECKSUM_SIZE as usize);
+        let checksum_position = superblock.block_checksum_position(first.index)?;
+        for block in blocks {
+            let (stored, compressed) = block.encode(superblock)?;
+            if checksum_position.is_some() {
+                let checksum = Self::stored_checksum(superblock, &stored, compressed)?;
+                checksums.extend(checksum.to_le_bytes());
+            }
+            data.extend_from_slice(&stored);
+        }
+        block_device.seek(SeekFrom::Start(position))?;
+        block_device.write_all(&data)?;
+        if let Some(position) = checksum_position {
+            block_device.seek(SeekFrom::Start(position))?;
+            block_device.write_all(&checksums)?;
+        }
+        Ok(())
+    }
+
+    /// Load `count` consecutive blocks starting with `first` in a single read
+    /// of the device, yielding `None` for ones whose checksum does not match
+    pub(crate) fn load_run<D: Read + Seek>(
+        block_device: &mut D,
+        superblock: &Superblock,
+        first: u64,
+        count: u64,
+    ) -> Result<Vec<Option<Self>>, Error> {
+        let position = superblock.block_position(first)?;
+        superblock.block_position(first + count - 1)?;
+        let block_size = superblock.block_size as usize;
+        block_device.seek(SeekFrom::Start(position))?;
+        let mut blocks_raw = vec![0u8; block_size * count as usize];
+        block_device.read_exact(&mut blocks_raw)?;
+        let mut checksums = vec![0u8; (BLOCK_CHECKSUM_SIZE * count) as usize];
+        let verify = match superblock.block_checksum_position(first)? {
+            Some(position) => {
+                block_device.seek(SeekFrom::Start(position))?;
+                block_device.read_exact(&mut checksums)?;
+                true
+            }
+            None => false,
+        };
+        let checksums = checksums.chunks_exact(BLOCK_CHECKSUM_SIZE as usize);
+        blocks_raw
+            .chunks_exact(block_size)
+            .zip(checksums)
+            .zip(first..)
+            .map(|((data, checksum), index)| {
+                let checksum =
+                    verify.then(|| u32::from_le_bytes(checksum.try_into().unwrap_or_default()));
+                let block = Self::decode(superblock, index, data.to_vec(), checksum)?;
+                if block.is_none() {
+                    error!("Checksum mismatch for block {index}");
+                }
+                Ok(block)
+            })
+            .collect()
+    }
 }
 
 impl PermanentIndexed for Block {
@@ -67,10 +268,14 @@ impl PermanentIndexed for Block {
         block_device.seek(SeekFrom::Start(position))?;
         let mut block_raw = vec![0u8; superblock.block_size as usize];
         block_device.read_exact(&mut block_raw)?;
-        Ok(Self {
-            data: block_raw,
-            index,
-        })
+        let checksum = Self::read_checksum(block_device, superblock, index)?;
+        match Self::decode(superblock, index, block_raw, checksum)? {
+            Some(block) => Ok(block),
+            None => {
+                error!("Checksum mismatch for block {index}");
+                Err(Error::Corruption)
+            }
+        }
     }
 
     fn flush<D: Write + Seek>(
@@ -79,8 +284,14 @@ impl PermanentIndexed for Block {
         superblock: &Superblock,
     ) -> Result<(), Self::Error> {
         let position = superblock.block_position(self.index)?;
+        let (stored, compressed) = self.encode(superblock)?;
         block_device.seek(SeekFrom::Start(position))?;
-        block_device.write_all(&self.data)?;
+        block_device.write_all(&stored)?;
+        if let Some(position) = superblock.block_checksum_position(self.index)? {
+            let checksum = Self::stored_checksum(superblock, &stored, compressed)?;
+            block_device.seek(SeekFrom::Start(position))?;
+            block_device.write_all(&checksum.to_le_bytes())?;
+        }
         Ok(())
     }
 }
@@ -122,3 +333,143 @@ impl Display for Block {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    use super::{Block, CompressionAlgorithm, PermanentIndexed, Superblock};
+    use crate::Error;
+
+    #[test]
+    fn checksum_mismatch() {
+        let superblock = Superblock::new(100_000, 512);
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_end() as usize]);
+        let mut block = Block {
+            index: 3,
+            data: vec![0u8; 512],
+            compress: true,
+        };
+        block.data[100] = 42;
+        assert!(block.flush(&mut dev, &superblock).is_ok());
+        assert_eq!(Block::load(&mut dev, &superblock, 3).unwrap(), block);
+        let position = superblock.block_position(3).unwrap() + 100;
+        dev.seek(SeekFrom::Start(position)).unwrap();
+        dev.write_all(&[43]).unwrap();
+        assert!(matches!(
+            Block::load(&mut dev, &superblock, 3),
+            Err(Error::Corruption)
+        ));
+    }
+
+    #[test]
+    fn compressed_blocks() {
+        let mut superblock = Superblock::new(100_000, 512);
+        superblock
+            .set_compression(CompressionAlgorithm::Lz4)
+            .unwrap();
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_end() as usize]);
+        let text = b"compressible text, compressible text. ";
+        let compressible = Block {
+            index: 3,
+            data: text.iter().copied().cycle().take(512).collect(),
+            compress: true,
+        };
+        let noise = Block {
+            index: 4,
+            data: (0..512)
+                .scan(0x2545_f491_u32, |state, _| {
+                    *state ^= *state << 13;
+                    *state ^= *state >> 17;
+                    *state ^= *state << 5;
+                    Some(*state as u8)
+                })
+                .collect(),
+            compress: true,
+        };
+        compressible.flush(&mut dev, &superblock).unwrap();
+        Block::flush_run(&mut dev, &superblock, &[&noise]).unwrap();
+        let stored = |dev: &Cursor<Vec<u8>>, index| {
+            let position = superblock.block_position(index).unwrap() as usize;
+            dev.get_ref()[position..position + 512].to_vec()
+        };
+        assert_eq!(stored(&dev, 3)[0], CompressionAlgorithm::Lz4 as u8);
+        assert_ne!(stored(&dev, 3), compressible.data);
+        assert_eq!(stored(&dev, 4), noise.data);
+        assert_eq!(Block::load(&mut dev, &superblock, 3).unwrap(), compressible);
+        assert_eq!(Block::load(&mut dev, &superblock, 4).unwrap(), noise);
+        let loaded = Block::load_run(&mut dev, &superblock, 3, 2).unwrap();
+        assert_eq!(loaded, [Some(compressible.clone()), Some(noise)]);
+        // Blocks of files with compression turned off are stored as they are
+        let uncompressed = Block {
+            index: 5,
+            compress: false,
+            ..compressible.clone()
+        };
+        uncompressed.flush(&mut dev, &superblock).unwrap();
+        assert_eq!(stored(&dev, 5), compressible.data);
+        assert_eq!(Block::load(&mut dev, &superblock, 5).unwrap(), uncompressed);
+
+        // Damaged payload is caught by the checksum before decompressing
+        let position = superblock.block_position(3).unwrap() + 10;
+        dev.seek(SeekFrom::Start(position)).unwrap();
+        dev.write_all(&[0xFF]).unwrap();
+        assert!(matches!(
+            Block::load(&mut dev, &superblock, 3),
+            Err(Error::Corruption)
+        ));
+        assert_ne!(
+            Block::load_unverified(&mut dev, &superblock, 3).unwrap(),
+            compressible
+        );
+    }
+
+    #[test]
+    fn flush_run_of_blocks() {
+        let superblock = Superblock::new(100_000, 512);
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_end() as usize]);
+        let blocks: Vec<Block> = (7..10)
+            .map(|index| Block {
+                index,
+                data: vec![index as u8; 512],
+                compress: true,
+            })
+            .collect();
+        let run: Vec<&Block> = blocks.iter().collect();
+        Block::flush_run(&mut dev, &superblock, &run).unwrap();
+        for block in &blocks {
+            assert_eq!(
+                &Block::load(&mut dev, &superblock, block.index).unwrap(),
+                block
+            );
+        }
+        let next = Block::load_unverified(&mut dev, &superblock, 10).unwrap();
+        assert_eq!(next.data, [0; 512]);
+    }
+
+    #[test]
+    fn load_run_of_blocks() {
+        let superblock = Superblock::new(100_000, 512);
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_end() as usize]);
+        let blocks: Vec<Block> = (2..6)
+            .map(|index| Block {
+                index,
+                data: vec![index as u8; 512],
+                compress: true,
+            })
+            .collect();
+        for block in &blocks {
+            block.flush(&mut dev, &superblock).unwrap();
+        }
+        let position = superblock.block_position(4).unwrap();
+        dev.seek(SeekFrom::Start(position)).unwrap();
+        dev.write_all(&[0]).unwrap();
+        let run = Block::load_run(&mut dev, &superblock, 2, 4).unwrap();
+        assert_eq!(run[0].as_ref(), Some(&blocks[0]));
+        assert_eq!(run[1].as_ref(), Some(&blocks[1]));
+        assert_eq!(run[2], None);
+        assert_eq!(run[3].as_ref(), Some(&blocks[3]));
+        let count = superblock.block_count;
+        assert!(Block::load_run(&mut dev, &superblock, count - 1, 2).is_err());
+    }
+}
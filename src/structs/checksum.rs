@@ -0,0 +1,262 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::Error;
+
+/// Integrity checksum of on-disk structures
+pub trait Checksummer {
+    fn checksum(&self, data: &[u8]) -> u32;
+}
+
+/// Checksum algorithm chosen when formatting, recorded in [Superblock](super::Superblock)
+///
+/// Every algorithm is stored in 32 bits, so they detect accidental damage
+/// equally well and differ only in speed; none of them protects against
+/// deliberate tampering.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChecksumAlgorithm {
+    /// No checksums, used by images formatted before checksums existed
+    None = 0,
+    #[default]
+    Crc32c = 1,
+    XxHash = 2,
+}
+
+struct NoChecksum;
+struct Crc32c;
+struct XxHash;
+
+impl ChecksumAlgorithm {
+    pub fn checksummer(self) -> &'static dyn Checksummer {
+        match self {
+            Self::None => &NoChecksum,
+            Self::Crc32c => &Crc32c,
+            Self::XxHash => &XxHash,
+        }
+    }
+}
+
+impl TryFrom<u8> for ChecksumAlgorithm {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::None,
+            1 => Self::Crc32c,
+            2 => Self::XxHash,
+            _ => return Err(Error::Corruption),
+        })
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Self::None,
+            "crc32c" => Self::Crc32c,
+            "xxhash" => Self::XxHash,
+            _ => return Err(Error::NotFound),
+        })
+    }
+}
+
+impl Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Crc32c => write!(f, "crc32c"),
+            Self::XxHash => write!(f, "xxhash"),
+        }
+    }
+}
+
+impl Checksummer for NoChecksum {
+    fn checksum(&self, _: &[u8]) -> u32 {
+        0
+    }
+}
+
+impl Checksummer for Crc32c {
+    fn checksum(&self, data: &[u8]) -> u32 {
+        crc32c(data)
+    }
+}
+
+impl Checksummer for XxHash {
+    fn checksum(&self, data: &[u8]) -> u32 {
+        xxh64(data, 0) as u32
+    }
+}
+
+/// Reflected Castagnoli polynomial
+const CRC32C_POLYNOMIAL: u32 = 0x82F6_3B78;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC32C_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// CRC-32C, using SSE 4.2 instructions when available
+pub fn crc32c(data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("sse4.2") {
+        return unsafe { crc32c_sse42(data) };
+    }
+    crc32c_table(data)
+}
+
+fn crc32c_table(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut crc = !0u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, *byte);
+    }
+    !crc
+}
+
+const XXH_PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const XXH_PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const XXH_PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const XXH_PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const XXH_PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn xxh64_round(accumulator: u64, input: u64) -> u64 {
+    accumulator
+        .wrapping_add(input.wrapping_mul(XXH_PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(XXH_PRIME_1)
+}
+
+fn xxh64_merge(accumulator: u64, value: u64) -> u64 {
+    (accumulator ^ xxh64_round(0, value))
+        .wrapping_mul(XXH_PRIME_1)
+        .wrapping_add(XXH_PRIME_4)
+}
+
+/// XXH64 hash with given seed
+pub fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes(bytes[..4].try_into().unwrap());
+    let mut stripes = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut lanes = [
+            seed.wrapping_add(XXH_PRIME_1).wrapping_add(XXH_PRIME_2),
+            seed.wrapping_add(XXH_PRIME_2),
+            seed,
+            seed.wrapping_sub(XXH_PRIME_1),
+        ];
+        for stripe in &mut stripes {
+            for (lane, word) in lanes.iter_mut().zip(stripe.chunks_exact(8)) {
+                *lane = xxh64_round(*lane, read_u64(word));
+            }
+        }
+        let mut hash = lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18));
+        for lane in lanes {
+            hash = xxh64_merge(hash, lane);
+        }
+        hash
+    } else {
+        seed.wrapping_add(XXH_PRIME_5)
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+    let mut tail = stripes.remainder();
+    while tail.len() >= 8 {
+        hash ^= xxh64_round(0, read_u64(tail));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(XXH_PRIME_1)
+            .wrapping_add(XXH_PRIME_4);
+        tail = &tail[8..];
+    }
+    if tail.len() >= 4 {
+        hash ^= (read_u32(tail) as u64).wrapping_mul(XXH_PRIME_1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(XXH_PRIME_2)
+            .wrapping_add(XXH_PRIME_3);
+        tail = &tail[4..];
+    }
+    for byte in tail {
+        hash ^= (*byte as u64).wrapping_mul(XXH_PRIME_5);
+        hash = hash.rotate_left(11).wrapping_mul(XXH_PRIME_1);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(XXH_PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(XXH_PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, crc32c_table, xxh64, ChecksumAlgorithm};
+
+    #[test]
+    fn crc32c_vectors() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        let data: Vec<u8> = (0..4096u32).map(|i| (i * 7) as u8).collect();
+        assert_eq!(crc32c(&data), crc32c_table(&data));
+    }
+
+    #[test]
+    fn xxh64_vectors() {
+        assert_eq!(xxh64(b"", 0), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"abc", 0), 0x44BC_2CF5_AD77_0999);
+    }
+
+    #[test]
+    fn algorithm_roundtrip() {
+        for algorithm in [
+            ChecksumAlgorithm::None,
+            ChecksumAlgorithm::Crc32c,
+            ChecksumAlgorithm::XxHash,
+        ] {
+            assert_eq!(
+                ChecksumAlgorithm::try_from(algorithm as u8).unwrap(),
+                algorithm
+            );
+            assert_eq!(
+                algorithm.to_string().parse::<ChecksumAlgorithm>().unwrap(),
+                algorithm
+            );
+        }
+        assert!(ChecksumAlgorithm::try_from(4).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::Error;
+
+/// Compression of data blocks, recorded in [Superblock](super::Superblock)
+/// and in the header of every compressed block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionAlgorithm {
+    /// Blocks are written as they are
+    #[default]
+    None = 0,
+    /// LZ4 block format, fast enough to keep up with the device
+    Lz4 = 1,
+}
+
+impl CompressionAlgorithm {
+    /// Compressed `data`, or `None` if it does not shrink below `limit` bytes
+    pub fn compress(self, data: &[u8], limit: usize) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Self::None => return None,
+            Self::Lz4 => lz4_compress(data),
+        };
+        (compressed.len() < limit).then_some(compressed)
+    }
+
+    /// Decompress `data` into exactly `size` bytes
+    pub fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::None => Err(Error::Corruption),
+            Self::Lz4 => lz4_decompress(data, size),
+        }
+    }
+}
+
+impl TryFrom<u8> for CompressionAlgorithm {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::None,
+            1 => Self::Lz4,
+            _ => return Err(Error::Corruption),
+        })
+    }
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Self::None,
+            "lz4" => Self::Lz4,
+            _ => return Err(Error::NotFound),
+        })
+    }
+}
+
+impl Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// Shortest match encoded by LZ4
+const LZ4_MIN_MATCH: usize = 4;
+/// Literals every LZ4 block ends with
+const LZ4_LAST_LITERALS: usize = 5;
+/// Matches start at least this many bytes before the end of LZ4 block
+const LZ4_MATCH_LIMIT: usize = 12;
+const LZ4_MAX_OFFSET: usize = u16::MAX as usize;
+const LZ4_HASH_BITS: u32 = 12;
+
+fn read_u32(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(data[position..position + 4].try_into().unwrap())
+}
+
+/// Length above a token's nibble, as a run of bytes ending below 255
+fn lz4_push_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+/// Sequence of `literals` followed by a match, if any, of `offset` and `length`
+fn lz4_push_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, length: usize) {
+    let matched = length.saturating_sub(LZ4_MIN_MATCH);
+    output.push(((literals.len().min(15) as u8) << 4) | matched.min(15) as u8);
+    if literals.len() >= 15 {
+        lz4_push_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    if length == 0 {
+        return;
+    }
+    output.extend((offset as u16).to_le_bytes());
+    if matched >= 15 {
+        lz4_push_length(output, matched - 15);
+    }
+}
+
+/// Compress `data` into an LZ4 block, finding matches greedily through a
+/// hash table of 4-byte sequences
+pub fn lz4_compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut table = vec![0usize; 1 << LZ4_HASH_BITS];
+    let (mut anchor, mut position) = (0, 0);
+    let limit = data.len().saturating_sub(LZ4_MATCH_LIMIT);
+    while position < limit {
+        let sequence = read_u32(data, position);
+        let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - LZ4_HASH_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], position + 1);
+        let Some(candidate) = candidate.checked_sub(1) else {
+            position += 1;
+            continue;
+        };
+        if position - candidate > LZ4_MAX_OFFSET || read_u32(data, candidate) != sequence {
+            position += 1;
+            continue;
+        }
+        let mut length = LZ4_MIN_MATCH;
+        while position + length < data.len() - LZ4_LAST_LITERALS
+            && data[candidate + length] == data[position + length]
+        {
+            length += 1;
+        }
+        lz4_push_sequence(
+            &mut output,
+            &data[anchor..position],
+            position - candidate,
+            length,
+        );
+        position += length;
+        anchor = position;
+    }
+    lz4_push_sequence(&mut output, &data[anchor..], 0, 0);
+    output
+}
+
+/// Length continuing a token's nibble of 15
+fn lz4_read_length(data: &[u8], position: &mut usize) -> Result<usize, Error> {
+    let mut length = 0;
+    loop {
+        let byte = *data.get(*position).ok_or(Error::Corruption)?;
+        *position += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decompress LZ4 block `data` into exactly `size` bytes
+pub fn lz4_decompress(data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::with_capacity(size);
+    let mut position = 0;
+    loop {
+        let token = *data.get(position).ok_or(Error::Corruption)?;
+        position += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += lz4_read_length(data, &mut position)?;
+        }
+        let end = position + literals;
+        if end > data.len() || output.len() + literals > size {
+            return Err(Error::Corruption);
+        }
+        output.extend_from_slice(&data[position..end]);
+        position = end;
+        if position == data.len() {
+            break;
+        }
+        let offset = data
+            .get(position..position + 2)
+            .map(|raw| u16::from_le_bytes([raw[0], raw[1]]) as usize)
+            .ok_or(Error::Corruption)?;
+        position += 2;
+        let mut length = (token & 15) as usize;
+        if length == 15 {
+            length += lz4_read_length(data, &mut position)?;
+        }
+        length += LZ4_MIN_MATCH;
+        if offset == 0 || offset > output.len() || output.len() + length > size {
+            return Err(Error::Corruption);
+        }
+        // Matches may overlap bytes they produce, so they are copied bytewise
+        let start = output.len() - offset;
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+    }
+    match output.len() == size {
+        true => Ok(output),
+        false => Err(Error::Corruption),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lz4_compress, lz4_decompress, CompressionAlgorithm};
+
+    #[test]
+    fn lz4_round_trip() {
+        let text = b"Text-heavy workloads compress well, text-heavy workloads compress well. ";
+        let repeated: Vec<u8> = text.iter().copied().cycle().take(4096).collect();
+        let noise: Vec<u8> = (0..4096)
+            .scan(0x2545_f491_u32, |state, _| {
+                *state ^= *state << 13;
+                *state ^= *state >> 17;
+                *state ^= *state << 5;
+                Some(*state as u8)
+            })
+            .collect();
+        let mut runs = vec![0u8; 300];
+        runs.extend([7u8; 700]);
+        for data in [&repeated[..], &noise[..], &runs[..], b"short", b""] {
+            let compressed = lz4_compress(data);
+            assert_eq!(lz4_decompress(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(lz4_compress(&repeated).len() < 400);
+        assert!(CompressionAlgorithm::Lz4.compress(&noise, 4092).is_none());
+        assert!(lz4_decompress(&lz4_compress(&repeated)[..40], 4096).is_err());
+        assert!(lz4_decompress(&[0x1F, 1, 0, 0], 20).is_err());
+    }
+
+    /// Block laid out by hand following the LZ4 block format
+    #[test]
+    fn lz4_reference_block() {
+        let compressed = [
+            0x1F, b'a', 0x01, 0x00, 0x08, 0x50, b'b', b'b', b'b', b'b', b'b',
+        ];
+        let mut expected = vec![b'a'; 28];
+        expected.extend(b"bbbbb");
+        assert_eq!(lz4_decompress(&compressed, 33).unwrap(), expected);
+    }
+}
@@ -1,25 +1,175 @@
 use super::*;
+use crate::Error;
 
 use fuser::{FileAttr, FileType};
+use log::error;
 use std::{
     fmt::Display,
     io::{Read, Seek, SeekFrom, Write},
-    time::{Duration, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// Age after which access time is updated even if file was not modified since
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Unit of blocks reported by `stat`, regardless of block size
+const STAT_BLOCK_SIZE: u64 = 512;
+
+/// Point in time from seconds and nanoseconds since epoch
+fn system_time(seconds: u64, nanoseconds: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::new(seconds, nanoseconds.min(999_999_999))
+}
+
 impl Inode {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+
+    /// Checksum of the inode using superblock's algorithm, covering only
+    /// bytes stored on filesystem
+    pub(crate) fn compute_checksum(&self, superblock: &Superblock) -> Result<u32, Error> {
+        let mut inode = *self;
+        inode.checksum = 0;
+        let checksummer = superblock.checksum_algorithm()?.checksummer();
+        let stored = superblock.inode_size() as usize;
+        Ok(checksummer.checksum(&inode.as_bytes()[..stored]))
+    }
+
+    /// Load inode without verifying its checksum, to inspect or repair a damaged one
+    pub(crate) fn load_unverified<D: Read + Seek>(
+        block_device: &mut D,
+        superblock: &Superblock,
+        index: u64,
+    ) -> Result<Self, Error> {
+        let position = superblock.inode_position(index)?;
+        block_device.seek(SeekFrom::Start(position))?;
+        let mut inode_raw = [0u8; std::mem::size_of::<Self>() / std::mem::size_of::<u8>()];
+        let stored = superblock.inode_size() as usize;
+        block_device.read_exact(&mut inode_raw[..stored])?;
+        let mut inode = unsafe { *(inode_raw.as_ptr() as *const Self) };
+        // Classic inodes have no creation timestamp
+        if stored < inode_raw.len() {
+            inode.crtime = inode.ctime;
+        }
+        Ok(inode)
+    }
+
+    /// Set last access timestamp to `time` since epoch
+    pub(crate) fn set_atime(&mut self, time: Duration) {
+        self.atime = time.as_secs();
+        self.atime_nsec = time.subsec_nanos();
+    }
+
+    /// Set last metadata modification timestamp to `time` since epoch
+    pub(crate) fn set_ctime(&mut self, time: Duration) {
+        self.ctime = time.as_secs();
+        self.ctime_nsec = time.subsec_nanos();
+    }
+
+    /// Whether access time is older than modification or `RELATIME_INTERVAL`
+    /// before `now`, so a read updates it under the default `relatime` policy
+    pub(crate) fn atime_outdated(&self, now: Duration) -> bool {
+        let atime = Duration::new(self.atime, self.atime_nsec);
+        atime <= Duration::new(self.mtime, self.mtime_nsec)
+            || atime <= Duration::new(self.ctime, self.ctime_nsec)
+            || now.saturating_sub(atime) >= RELATIME_INTERVAL
+    }
+
+    /// Set last data modification timestamp to `time` since epoch
+    pub(crate) fn set_mtime(&mut self, time: Duration) {
+        self.mtime = time.as_secs();
+        self.mtime_nsec = time.subsec_nanos();
+    }
+
+    /// Set creation timestamp to `time` since epoch
+    pub(crate) fn set_crtime(&mut self, time: Duration) {
+        self.crtime = time.as_secs();
+        self.crtime_nsec = time.subsec_nanos();
+    }
+
+    /// Fail unless file may be changed other than by appending to it,
+    /// or unlinked and renamed
+    pub(crate) fn check_modifiable(&self) -> Result<(), Error> {
+        match self.flags & (FLAG_IMMUTABLE | FLAG_APPEND_ONLY) {
+            0 => Ok(()),
+            _ => Err(Error::NotPermitted),
+        }
+    }
+
+    /// Fail unless data may be appended to file, or entries added to directory
+    pub(crate) fn check_appendable(&self) -> Result<(), Error> {
+        match self.flags & FLAG_IMMUTABLE {
+            0 => Ok(()),
+            _ => Err(Error::NotPermitted),
+        }
+    }
+
+    /// Fail unless data may be written at `offset` of file holding `size` bytes
+    pub(crate) fn check_write(&self, offset: u64, size: u64) -> Result<(), Error> {
+        self.check_appendable()?;
+        if self.flags & FLAG_APPEND_ONLY != 0 && offset != size {
+            return Err(Error::NotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Fail unless user `caller` may change owner to `uid` and `gid` and
+    /// permissions to `mode`: only `root` gives files away, and only the owner
+    /// or `root` changes their group or permissions
+    pub(crate) fn check_attributes(
+        &self,
+        caller: u32,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        mode: Option<u32>,
+    ) -> Result<(), Error> {
+        if caller == 0 {
+            return Ok(());
+        }
+        let uid = uid.is_some_and(|uid| uid != self.uid);
+        let gid = gid.is_some_and(|gid| gid != self.gid);
+        if uid || ((gid || mode.is_some()) && caller != self.uid) {
+            return Err(Error::NotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Set all timestamps of a newly created inode to `time` since epoch
+    pub(crate) fn set_created(&mut self, time: Duration) {
+        self.set_atime(time);
+        self.set_ctime(time);
+        self.set_mtime(time);
+        self.set_crtime(time);
+    }
+
+    /// Hard link count, with `.` and each subdirectory's `..` for directories
+    fn links(&self) -> u32 {
+        if self.dtime != u64::MAX {
+            return 0;
+        }
+        match self.r#type {
+            FileType::Directory if self.metadata[3] != NULL_BLOCK => 2 + self.metadata[3] as u32,
+            FileType::Directory => 2,
+            _ => 1,
+        }
+    }
+
     pub fn attrs(&self, superblock: &Superblock) -> FileAttr {
         FileAttr {
             ino: self.index,
             size: self.size,
-            blocks: self.block_count,
-            atime: UNIX_EPOCH + Duration::from_secs(self.atime),
-            mtime: UNIX_EPOCH + Duration::from_secs(self.mtime),
-            ctime: UNIX_EPOCH + Duration::from_secs(self.ctime),
-            crtime: UNIX_EPOCH + Duration::from_secs(self.ctime),
+            blocks: self.block_count * superblock.block_size as u64 / STAT_BLOCK_SIZE,
+            atime: system_time(self.atime, self.atime_nsec),
+            mtime: system_time(self.mtime, self.mtime_nsec),
+            ctime: system_time(self.ctime, self.ctime_nsec),
+            crtime: system_time(self.crtime, self.crtime_nsec),
             kind: self.r#type,
             perm: self.mode,
-            nlink: 0, // unimplemented
+            nlink: self.links(),
             uid: self.uid,
             gid: self.gid,
             rdev: 0, // unimplemented
@@ -39,11 +189,12 @@ impl PermanentIndexed for Inode {
         superblock: &Superblock,
         index: u64,
     ) -> Result<Self, Self::Error> {
-        let position = superblock.inode_position(index)?;
-        block_device.seek(SeekFrom::Start(position))?;
-        let mut inode_raw = [0u8; std::mem::size_of::<Self>() / std::mem::size_of::<u8>()];
-        block_device.read_exact(&mut inode_raw)?;
-        Ok(unsafe { *(inode_raw.as_ptr() as *const Self) })
+        let inode = Self::load_unverified(block_device, superblock, index)?;
+        if inode.checksum != inode.compute_checksum(superblock)? {
+            error!("Checksum mismatch for inode {index}");
+            return Err(Error::Corruption);
+        }
+        Ok(inode)
     }
 
     fn flush<D: Write + Seek>(
@@ -53,13 +204,10 @@ impl PermanentIndexed for Inode {
     ) -> Result<(), Self::Error> {
         let position = superblock.inode_position(self.index)?;
         block_device.seek(SeekFrom::Start(position))?;
-        let inode_raw = unsafe {
-            core::slice::from_raw_parts(
-                self as *const Self as *const u8,
-                std::mem::size_of::<Self>(),
-            )
-        };
-        block_device.write_all(inode_raw)?;
+        let mut inode = *self;
+        inode.checksum = self.compute_checksum(superblock)?;
+        let stored = superblock.inode_size() as usize;
+        block_device.write_all(&inode.as_bytes()[..stored])?;
         Ok(())
     }
 }
@@ -79,9 +227,17 @@ impl Default for Inode {
             dtime: 0,
             block_count: 0,
             metadata: [0; METADATA_IN_INODE],
+            checksum: 0,
             __padding_1: Default::default(),
             first_block: NULL_BLOCK,
             last_block: NULL_BLOCK,
+            atime_nsec: 0,
+            ctime_nsec: 0,
+            mtime_nsec: 0,
+            crtime_nsec: 0,
+            crtime: 0,
+            flags: 0,
+            __padding_2: [0; 100],
         }
     }
 }
@@ -98,6 +254,12 @@ impl PartialEq for Inode {
             && self.atime == other.atime
             && self.ctime == other.ctime
             && self.mtime == other.mtime
+            && self.crtime == other.crtime
+            && self.atime_nsec == other.atime_nsec
+            && self.ctime_nsec == other.ctime_nsec
+            && self.mtime_nsec == other.mtime_nsec
+            && self.crtime_nsec == other.crtime_nsec
+            && self.flags == other.flags
             && self.dtime == other.dtime
             && self.block_count == other.block_count
             && m1 == m2
@@ -114,11 +276,21 @@ impl Display for Inode {
         writeln!(f, "    size: {}", { self.size })?;
         writeln!(f, "    uid: {}", { self.uid })?;
         writeln!(f, "    gid: {}", { self.gid })?;
-        writeln!(f, "    atime: {}", { self.atime })?;
-        writeln!(f, "    ctime: {}", { self.ctime })?;
-        writeln!(f, "    mtime: {}", { self.mtime })?;
+        writeln!(f, "    atime: {}.{:09}", { self.atime }, {
+            self.atime_nsec
+        })?;
+        writeln!(f, "    ctime: {}.{:09}", { self.ctime }, {
+            self.ctime_nsec
+        })?;
+        writeln!(f, "    mtime: {}.{:09}", { self.mtime }, {
+            self.mtime_nsec
+        })?;
+        writeln!(f, "    crtime: {}.{:09}", { self.crtime }, {
+            self.crtime_nsec
+        })?;
         writeln!(f, "    dtime: {}", { self.dtime })?;
         writeln!(f, "    block_count: {}", { self.block_count })?;
+        writeln!(f, "    flags: {:#x}", { self.flags })?;
         writeln!(f, "    metadata: [")?;
         for chunk in self.metadata {
             writeln!(f, "        {chunk:0x}")?;
@@ -126,6 +298,7 @@ impl Display for Inode {
         writeln!(f, "    ]")?;
         writeln!(f, "    first_block: {}", { self.first_block })?;
         writeln!(f, "    last_block: {}", { self.last_block })?;
+        writeln!(f, "    checksum: {:08x}", { self.checksum })?;
         write!(f, "}}")?;
         Ok(())
     }
@@ -134,21 +307,105 @@ impl Display for Inode {
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use std::time::Duration;
 
     use super::{Inode, PermanentIndexed};
-    use crate::structs::Superblock;
+    use crate::structs::{ChecksumAlgorithm, Superblock, INCOMPAT_EXTENDED_INODES};
+    use crate::Error;
 
     #[test]
     fn size() {
-        assert_eq!(std::mem::size_of::<Inode>(), 128);
+        assert_eq!(std::mem::size_of::<Inode>(), 256);
+    }
+
+    #[test]
+    fn attribute_permissions() {
+        let inode = Inode {
+            uid: 1000,
+            gid: 100,
+            ..Default::default()
+        };
+        for (caller, uid, gid, mode, permitted) in [
+            (0, Some(1001), Some(101), Some(0o600), true),
+            (1000, Some(1000), Some(101), Some(0o600), true),
+            (1000, Some(1001), None, None, false),
+            (1001, None, Some(100), None, true),
+            (1001, None, Some(101), None, false),
+            (1001, None, None, Some(0o600), false),
+        ] {
+            let result = inode.check_attributes(caller, uid, gid, mode);
+            match permitted {
+                true => assert!(result.is_ok()),
+                false => assert!(matches!(result, Err(Error::NotPermitted))),
+            }
+        }
     }
 
     #[test]
     fn load_and_flush() {
         let superblock = Superblock::new(100_000, 4096);
         let mut dev = Cursor::new(vec![0u8; superblock.block_region_start() as usize]);
-        let inode = Inode::load(&mut dev, &superblock, 10);
-        assert!(inode.is_ok());
-        assert!(inode.unwrap().flush(&mut dev, &superblock).is_ok());
+        let inode = Inode {
+            index: 10,
+            ..Default::default()
+        };
+        assert!(inode.flush(&mut dev, &superblock).is_ok());
+        let loaded = Inode::load(&mut dev, &superblock, 10);
+        assert!(loaded.is_ok());
+        assert_eq!(loaded.unwrap(), inode);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::XxHash] {
+            let mut superblock = Superblock::new(100_000, 4096);
+            superblock.checksum_algorithm = algorithm as u8;
+            let mut dev = Cursor::new(vec![0u8; superblock.block_region_start() as usize]);
+            let inode = Inode {
+                index: 10,
+                size: 1234,
+                ..Default::default()
+            };
+            inode.flush(&mut dev, &superblock).unwrap();
+            assert!(Inode::load(&mut dev, &superblock, 10).is_ok());
+            let position = superblock.inode_position(10).unwrap() as usize;
+            dev.get_mut()[position + 11] ^= 0x01;
+            assert!(matches!(
+                Inode::load(&mut dev, &superblock, 10),
+                Err(Error::Corruption)
+            ));
+        }
+    }
+
+    #[test]
+    fn classic_inode() {
+        let mut superblock = Superblock::new(100_000, 4096);
+        superblock.incompat_flags &= !INCOMPAT_EXTENDED_INODES;
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_start() as usize]);
+        let mut inode = Inode {
+            index: 10,
+            ..Default::default()
+        };
+        inode.set_created(Duration::new(1_000, 500));
+        inode.flush(&mut dev, &superblock).unwrap();
+        let position = superblock.inode_position(11).unwrap() as usize;
+        assert_eq!(
+            position - superblock.inode_position(10).unwrap() as usize,
+            128
+        );
+        assert!(dev.get_ref()[position..position + 128]
+            .iter()
+            .all(|&b| b == 0));
+        let loaded = Inode::load(&mut dev, &superblock, 10).unwrap();
+        assert_eq!({ loaded.crtime }, 1_000);
+        assert_eq!({ loaded.atime_nsec }, 0);
+    }
+
+    #[test]
+    fn unchecked_inode() {
+        let mut superblock = Superblock::new(100_000, 4096);
+        superblock.checksum_algorithm = ChecksumAlgorithm::None as u8;
+        let mut dev = Cursor::new(vec![0u8; superblock.block_region_start() as usize]);
+        assert!(Inode::load(&mut dev, &superblock, 10).is_ok());
     }
 }
@@ -1,9 +1,13 @@
 mod bitmap;
 mod block;
+mod checksum;
+mod compression;
 mod inode;
+mod options;
 mod superblock;
 
 use std::{
+    collections::BTreeSet,
     io::{Read, Seek, Write},
     marker::PhantomData,
 };
@@ -11,12 +15,121 @@ use std::{
 use fuser::FileType;
 
 pub use bitmap::*;
+pub use checksum::{ChecksumAlgorithm, Checksummer};
+pub use compression::CompressionAlgorithm;
+pub use options::MountOptions;
 
 pub const METADATA_IN_INODE: usize = 5;
+/// Bytes of inodes on filesystems without [INCOMPAT_EXTENDED_INODES]
+pub const CLASSIC_INODE_SIZE: u64 = 128;
+/// Inode flag: file may not be changed, renamed or unlinked, as `FS_IMMUTABLE_FL`
+pub const FLAG_IMMUTABLE: u32 = 0x10;
+/// Inode flag: file may only be appended to, as `FS_APPEND_FL`
+pub const FLAG_APPEND_ONLY: u32 = 0x20;
+/// Inode flag: blocks of file are stored uncompressed, as `FS_NOCOMP_FL`
+pub const FLAG_NOCOMPRESS: u32 = 0x400;
+/// Inode flags known to this implementation
+pub const FLAGS_SUPPORTED: u32 = FLAG_IMMUTABLE | FLAG_APPEND_ONLY | FLAG_NOCOMPRESS;
 pub const DATA_PER_INODE: u64 = 4096;
+/// Largest capacity per inode of a new filesystem, as in `mke2fs`
+pub const MAX_DATA_PER_INODE: u64 = 1 << 26;
 pub const MAGIC_SIGNATURE: u64 = 0x2153466E616E6154;
 pub const NULL_BLOCK: u64 = u64::MAX;
 pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+/// Share of device capacity reserved for metadata journal
+pub const JOURNAL_FRACTION: u64 = 64;
+pub const MAX_JOURNAL_SIZE: u64 = 4 << 20;
+/// Bytes of checksum stored for every block
+pub const BLOCK_CHECKSUM_SIZE: u64 = 4;
+/// Bytes of header preceding compressed contents of a block
+pub const COMPRESSION_HEADER_SIZE: usize = 4;
+/// Checksums of compressed blocks are flipped by this mask, telling them
+/// apart from blocks stored as they are
+pub const COMPRESSED_BLOCK_TAG: u32 = 1 << 31;
+/// Copies of superblock stored at the end of device
+pub const BACKUP_SUPERBLOCKS: u8 = 2;
+/// Backup superblocks end at device size rounded down to a multiple of this
+pub const BACKUP_ALIGNMENT: u64 = 4096;
+/// Longest filesystem label in bytes
+pub const LABEL_SIZE: usize = 16;
+/// Most devices a filesystem spans, as recorded in its superblock
+pub const MAX_MEMBERS: usize = 8;
+/// Inodes in every chunk allocated from block region
+pub const INODE_CHUNK_SIZE: u64 = 1024;
+/// Most chunks of inodes allocated from block region, as recorded in superblock
+pub const MAX_INODE_CHUNKS: usize = 16;
+/// Largest percentage of blocks reserved for privileged users
+pub const MAX_RESERVED_PERCENT: u8 = 50;
+/// State of a filesystem cleanly unmounted, or never mounted
+pub const STATE_CLEAN: u8 = 0;
+/// State of a filesystem mounted read-write, left behind if it was not unmounted cleanly
+pub const STATE_MOUNTED: u8 = 1;
+/// Version of on-disk format written by this implementation
+pub const FORMAT_VERSION: u32 = 1;
+/// Compatible feature: backup superblocks at the end of device
+pub const COMPAT_BACKUP_SUPERBLOCKS: u32 = 1 << 0;
+/// Compatible features known to this implementation, others are ignored
+pub const COMPAT_SUPPORTED: u32 = COMPAT_BACKUP_SUPERBLOCKS;
+/// Incompatible feature: metadata journal after superblock
+pub const INCOMPAT_JOURNAL: u32 = 1 << 0;
+/// Incompatible feature: checksum region preceding block region
+pub const INCOMPAT_BLOCK_CHECKSUMS: u32 = 1 << 1;
+/// Incompatible feature: indirect tables of block indices in regular files
+pub const INCOMPAT_BLOCK_TABLES: u32 = 1 << 2;
+/// Incompatible feature: contents of small regular files in their inodes
+pub const INCOMPAT_INLINE_DATA: u32 = 1 << 3;
+/// Incompatible feature: undo records of past transactions in the journal
+pub const INCOMPAT_JOURNAL_HISTORY: u32 = 1 << 4;
+/// Incompatible feature: inodes with nanosecond and creation timestamps
+pub const INCOMPAT_EXTENDED_INODES: u32 = 1 << 5;
+/// Incompatible feature: block and inode usage of users and groups in reserved inode
+pub const INCOMPAT_QUOTA: u32 = 1 << 6;
+/// Incompatible feature: hashed index of entries in large directories
+pub const INCOMPAT_DIRECTORY_INDEX: u32 = 1 << 7;
+/// Incompatible feature: 32-bit name lengths in directory entries
+pub const INCOMPAT_WIDE_NAMES: u32 = 1 << 8;
+/// Incompatible feature: device concatenated from members recorded in superblock
+pub const INCOMPAT_SPANNED: u32 = 1 << 9;
+/// Incompatible feature: blocks compressed with a header, tagged by their checksums
+pub const INCOMPAT_COMPRESSION: u32 = 1 << 10;
+/// Incompatible feature: cloned files share chains of blocks until modified
+pub const INCOMPAT_REFLINK: u32 = 1 << 11;
+/// Incompatible feature: chunks of inodes allocated from block region once the table is full
+pub const INCOMPAT_INODE_CHUNKS: u32 = 1 << 12;
+/// Incompatible feature: snapshots of directory tree in directory recorded in superblock
+pub const INCOMPAT_SNAPSHOTS: u32 = 1 << 13;
+/// Incompatible features known to this implementation, others prevent mounting
+pub const INCOMPAT_SUPPORTED: u32 = INCOMPAT_JOURNAL
+    | INCOMPAT_BLOCK_CHECKSUMS
+    | INCOMPAT_BLOCK_TABLES
+    | INCOMPAT_INLINE_DATA
+    | INCOMPAT_JOURNAL_HISTORY
+    | INCOMPAT_EXTENDED_INODES
+    | INCOMPAT_QUOTA
+    | INCOMPAT_DIRECTORY_INDEX
+    | INCOMPAT_WIDE_NAMES
+    | INCOMPAT_SPANNED
+    | INCOMPAT_COMPRESSION
+    | INCOMPAT_REFLINK
+    | INCOMPAT_INODE_CHUNKS
+    | INCOMPAT_SNAPSHOTS;
+/// Names of incompatible features, as shown and changed by `tune`
+pub const INCOMPAT_NAMES: [(&str, u32); 14] = [
+    ("journal", INCOMPAT_JOURNAL),
+    ("block_checksums", INCOMPAT_BLOCK_CHECKSUMS),
+    ("block_tables", INCOMPAT_BLOCK_TABLES),
+    ("inline_data", INCOMPAT_INLINE_DATA),
+    ("journal_history", INCOMPAT_JOURNAL_HISTORY),
+    ("extended_inodes", INCOMPAT_EXTENDED_INODES),
+    ("quota", INCOMPAT_QUOTA),
+    ("dir_index", INCOMPAT_DIRECTORY_INDEX),
+    ("wide_names", INCOMPAT_WIDE_NAMES),
+    ("spanned", INCOMPAT_SPANNED),
+    ("compression", INCOMPAT_COMPRESSION),
+    ("reflink", INCOMPAT_REFLINK),
+    ("inode_chunks", INCOMPAT_INODE_CHUNKS),
+    ("snapshots", INCOMPAT_SNAPSHOTS),
+];
 
 pub(crate) trait PermanentIndexed: Sized {
     type Error;
@@ -38,56 +151,127 @@ pub trait AsBitmap {}
 #[repr(C, packed)]
 pub struct Superblock {
     /// Total count of inodes in the filesystem
-    pub(crate) inode_count: u64,
+    pub inode_count: u64,
     /// Count of free inodes in the filesystem
-    pub(crate) inodes_free: u64,
+    pub inodes_free: u64,
     /// Total count of blocks in the filesystem
-    pub(crate) block_count: u64,
+    pub block_count: u64,
     /// Count of free blocks in the filesystem
-    pub(crate) blocks_free: u64,
+    pub blocks_free: u64,
     /// Block size in bytes
     pub(crate) block_size: u32,
+    /// Raw [ChecksumAlgorithm] of inodes and blocks
+    pub(crate) checksum_algorithm: u8,
+    /// Alignment of block region in bytes, zero for block size
+    pub(crate) data_alignment: u32,
+    /// Size of metadata journal in blocks, zero for filesystems without it
+    pub(crate) journal_blocks: u32,
+    /// Nonzero if checksum region of blocks precedes block region
+    pub(crate) block_checksums: u8,
+    /// CRC32C of superblock with this field set to zero, zero if not computed
+    pub(crate) checksum: u32,
+    /// Number of backup copies at the end of device
+    pub(crate) backup_superblocks: u8,
     #[doc(hidden)]
-    pub(crate) __padding_1: [u8; 20],
+    pub(crate) __padding_1: [u8; 5],
     /// Magic signature
     pub(crate) magic: u64,
+    /// Size of device in bytes when formatted, locating backup superblocks
+    pub(crate) device_size: u64,
+    /// Version of on-disk format, zero for filesystems predating it
+    pub(crate) version: u32,
+    /// Features which implementations not knowing them may safely ignore
+    pub(crate) compat_flags: u32,
+    /// Features which implementations not knowing them must not mount
+    pub(crate) incompat_flags: u32,
+    /// Raw [MountOptions] applied to every mount
+    pub(crate) default_options: u32,
+    /// Name of filesystem, padded with zeros
+    pub(crate) label: [u8; LABEL_SIZE],
+    /// Unique identifier of filesystem, zero if not assigned
+    pub uuid: [u8; 16],
+    /// Percentage of blocks reserved for privileged users
+    pub(crate) reserved_percent: u8,
+    /// [STATE_CLEAN] once unmounted, [STATE_MOUNTED] while mounted read-write
+    pub(crate) state: u8,
+    /// Mounts after which checking the filesystem is recommended, zero to never check
+    pub(crate) max_mount_count: u16,
+    /// Mounts since the filesystem was last checked
+    pub(crate) mount_count: u16,
+    /// Block following the one allocated last, where search for a free block starts
+    pub(crate) block_allocation_hint: u64,
+    /// Sizes in bytes of concatenated devices in order, zero past the last one
+    pub(crate) member_sizes: [u64; MAX_MEMBERS],
+    /// Raw [CompressionAlgorithm] of newly written blocks
+    pub(crate) compression_algorithm: u8,
+    /// First block of the table of shared chains, [NULL_BLOCK] if there is none
+    pub(crate) share_table_block: u64,
+    /// Bytes of the table of shared chains
+    pub(crate) share_table_size: u64,
+    /// Seconds since the epoch when scrubbing last verified all blocks, zero if never
+    pub(crate) last_scrub: u64,
+    /// First inode of the list of inodes unlinked while open, zero if it is empty
+    pub(crate) orphan_inode: u64,
+    /// Number of chunks of inodes allocated from block region
+    pub(crate) inode_chunk_count: u32,
+    /// First blocks of chunks of inodes in order of allocation, each holding
+    /// their bitmap followed by inodes
+    pub(crate) inode_chunks: [u64; MAX_INODE_CHUNKS],
+    /// Directory holding snapshots of the directory tree, zero if there is none
+    pub(crate) snapshot_inode: u64,
     #[doc(hidden)]
-    pub(crate) __padding_2: [u8; 960],
+    pub(crate) __padding_3: [u8; 653],
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct Inode {
     /// Inode's index
-    pub(crate) index: u64,
+    pub index: u64,
     /// File mode (permissions)
-    pub(crate) mode: u16,
+    pub mode: u16,
     /// File type
-    pub(crate) r#type: FileType,
+    pub r#type: FileType,
     /// File size in bytes
-    pub(crate) size: u64,
+    pub size: u64,
     /// Owner UID
-    pub(crate) uid: u32,
+    pub uid: u32,
     /// Owner GID
-    pub(crate) gid: u32,
+    pub gid: u32,
     /// Last access timestamp in seconds
-    pub(crate) atime: u64,
+    pub atime: u64,
     /// Last metadata modification timestamp in seconds
-    pub(crate) ctime: u64,
+    pub ctime: u64,
     /// Last data modification timestamp in seconds
-    pub(crate) mtime: u64,
+    pub mtime: u64,
     /// Deletion timestamp in seconds ([`u64::MAX`](core::u64::MAX) if not deleted)
-    pub(crate) dtime: u64,
+    pub dtime: u64,
     /// Occupied block count
-    pub(crate) block_count: u64,
+    pub block_count: u64,
     /// Raw slice for additional optional metadata
-    pub(crate) metadata: [u64; METADATA_IN_INODE],
+    pub metadata: [u64; METADATA_IN_INODE],
+    /// Checksum of the inode computed with this field set to zero
+    pub(crate) checksum: u32,
     #[doc(hidden)]
-    pub(crate) __padding_1: [bool; 5],
+    pub(crate) __padding_1: [bool; 1],
     /// Index of file's first block. Set to
     /// Every extra block references next in sequence in its first 8 bytes.
-    pub(crate) first_block: u64,
-    pub(crate) last_block: u64,
+    pub first_block: u64,
+    pub last_block: u64,
+    /// Nanoseconds of last access timestamp
+    pub(crate) atime_nsec: u32,
+    /// Nanoseconds of last metadata modification timestamp
+    pub(crate) ctime_nsec: u32,
+    /// Nanoseconds of last data modification timestamp
+    pub(crate) mtime_nsec: u32,
+    /// Nanoseconds of creation timestamp
+    pub(crate) crtime_nsec: u32,
+    /// Creation timestamp in seconds
+    pub crtime: u64,
+    /// Raw flags such as [FLAG_IMMUTABLE], always zero in classic inodes
+    pub flags: u32,
+    #[doc(hidden)]
+    pub(crate) __padding_2: [u8; 100],
 }
 
 #[derive(Debug, Clone)]
@@ -95,7 +279,10 @@ pub struct Block {
     /// Block's index
     pub(crate) index: u64,
     /// Raw data as bytes
-    pub(crate) data: Vec<u8>,
+    pub data: Vec<u8>,
+    /// Whether block may be stored compressed, unless it holds data of a
+    /// file with [FLAG_NOCOMPRESS]
+    pub(crate) compress: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -106,6 +293,12 @@ pub struct Bitmap<T: AsBitmap> {
     pub count: u64,
     /// Position
     pub position: u64,
+    /// Chunks of bitfield modified since it was last loaded or flushed
+    dirty: BTreeSet<usize>,
+    /// Chunks of bitfield stored at `position`
+    base_chunks: usize,
+    /// Positions of further chunks of bitfield, appended by [Bitmap::extend]
+    extensions: Vec<u64>,
     #[doc(hidden)]
     __type: PhantomData<T>,
 }
@@ -0,0 +1,146 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::Error;
+
+/// Default mount options recorded in [Superblock](super::Superblock)
+///
+/// Set when formatting or with `tananfs tune`, so every mount of an image
+/// behaves the same without repeating them on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MountOptions(u32);
+
+impl MountOptions {
+    /// Do not update access time when reading files
+    pub const NOATIME: Self = Self(1 << 0);
+    /// Compress data blocks
+    pub const COMPRESS: Self = Self(1 << 1);
+    /// Look up names case-insensitively, reserved as lookup ignores it
+    pub const CASEFOLD: Self = Self(1 << 2);
+    /// Allocate blocks on every write instead of when written data is flushed
+    pub const NODELALLOC: Self = Self(1 << 3);
+    /// Do not prefetch blocks following sequential reads of regular files
+    pub const NOREADAHEAD: Self = Self(1 << 4);
+    /// Discard blocks released since the last flush on the backing device
+    pub const DISCARD: Self = Self(1 << 5);
+    /// Update access time on every read of a file, unless [NOATIME](Self::NOATIME)
+    /// is set as well, instead of only once it is older than modification
+    /// or a day
+    pub const STRICTATIME: Self = Self(1 << 6);
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::NOATIME, "noatime"),
+        (Self::COMPRESS, "compress"),
+        (Self::CASEFOLD, "casefold"),
+        (Self::NODELALLOC, "nodelalloc"),
+        (Self::NOREADAHEAD, "noreadahead"),
+        (Self::DISCARD, "discard"),
+        (Self::STRICTATIME, "strictatime"),
+    ];
+
+    /// Options of raw value, keeping ones unknown to this implementation
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, option: Self) -> bool {
+        self.0 & option.0 == option.0
+    }
+
+    pub fn set(&mut self, option: Self, enabled: bool) {
+        match enabled {
+            true => self.0 |= option.0,
+            false => self.0 &= !option.0,
+        }
+    }
+
+    /// Apply change such as `+noatime` or `-casefold`, enabling option without a sign
+    ///
+    /// Enabling [CASEFOLD](Self::CASEFOLD) fails with [Error::Incompatible]
+    /// until names are looked up case-insensitively, while disabling it
+    /// clears the option from images which have it.
+    pub fn apply(&mut self, change: &str) -> Result<(), Error> {
+        let (enabled, name) = match change.split_at(change.len().min(1)) {
+            ("-", name) => (false, name),
+            ("+", name) => (true, name),
+            _ => (true, change),
+        };
+        let option = Self::NAMES
+            .iter()
+            .find(|(_, known)| *known == name)
+            .ok_or(Error::NotFound)?
+            .0;
+        if enabled && option == Self::CASEFOLD {
+            return Err(Error::Incompatible);
+        }
+        self.set(option, enabled);
+        Ok(())
+    }
+}
+
+impl FromStr for MountOptions {
+    type Err = Error;
+
+    /// Comma-separated list of option names
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = Self::default();
+        for name in s.split(',').filter(|name| !name.is_empty()) {
+            options.apply(name)?;
+        }
+        Ok(options)
+    }
+}
+
+impl Display for MountOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<String> = Self::NAMES
+            .iter()
+            .filter(|(option, _)| self.contains(*option))
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let known = Self::NAMES
+            .iter()
+            .fold(0, |bits, (option, _)| bits | option.0);
+        if self.0 & !known != 0 {
+            names.push(format!("{:#x}", self.0 & !known));
+        }
+        match names.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", names.join(",")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MountOptions;
+    use crate::Error;
+
+    #[test]
+    fn parse_and_apply() {
+        let mut options: MountOptions = "noatime".parse().unwrap();
+        options.set(MountOptions::CASEFOLD, true);
+        assert!(options.contains(MountOptions::NOATIME));
+        assert!(!options.contains(MountOptions::COMPRESS));
+        assert_eq!(options.to_string(), "noatime,casefold");
+        options.apply("-noatime").unwrap();
+        options.apply("+compress").unwrap();
+        options.apply("discard").unwrap();
+        assert_eq!(options.to_string(), "compress,casefold,discard");
+        assert!(matches!(options.apply("-sync"), Err(Error::NotFound)));
+        assert!(matches!(
+            options.apply("+casefold"),
+            Err(Error::Incompatible)
+        ));
+        options.apply("-casefold").unwrap();
+        assert_eq!(options.to_string(), "compress,discard");
+        assert_eq!(MountOptions::default().to_string(), "none");
+        assert_eq!(
+            MountOptions::from_bits(1 << 8 | 1).to_string(),
+            "noatime,0x100"
+        );
+    }
+}
@@ -3,14 +3,43 @@ use std::{
     io::{Read, Seek, SeekFrom, Write},
 };
 
+use log::{error, info, warn};
+
 use super::*;
 use crate::Error;
 
 impl Superblock {
     pub fn new(capacity: u64, block_size: u32) -> Self {
+        Self::new_aligned(capacity, block_size, block_size)
+    }
+
+    /// Superblock with block region starting at a multiple of `alignment` bytes
+    pub fn new_aligned(capacity: u64, block_size: u32, alignment: u32) -> Self {
+        Self::with_inode_ratio(capacity, block_size, alignment, DATA_PER_INODE)
+    }
+
+    /// Superblock with an inode for every `bytes_per_inode` bytes of capacity
+    pub fn with_inode_ratio(
+        capacity: u64,
+        block_size: u32,
+        alignment: u32,
+        bytes_per_inode: u64,
+    ) -> Self {
         debug_assert!(block_size.next_power_of_two() == block_size);
-        let capacity = Self::usable_capacity(capacity, block_size);
-        let inode_count = capacity / DATA_PER_INODE;
+        debug_assert!(alignment.next_power_of_two() == alignment);
+        let alignment = alignment.max(block_size);
+        let device_size = capacity;
+        let journal_blocks = Self::journal_blocks_for(capacity, block_size);
+        let capacity = Self::backups_start(device_size, BACKUP_SUPERBLOCKS);
+        let capacity = Self::usable_capacity(
+            capacity,
+            block_size,
+            alignment,
+            journal_blocks,
+            bytes_per_inode,
+            true,
+        );
+        let inode_count = capacity / bytes_per_inode;
         let block_count = capacity / block_size as u64;
         Self {
             inode_count,
@@ -18,56 +47,479 @@ impl Superblock {
             block_count,
             blocks_free: block_count,
             block_size,
-            __padding_1: [0; 20],
+            checksum_algorithm: ChecksumAlgorithm::default() as u8,
+            data_alignment: alignment,
+            journal_blocks,
+            block_checksums: 1,
+            checksum: 0,
+            backup_superblocks: BACKUP_SUPERBLOCKS,
+            __padding_1: [0; 5],
             magic: MAGIC_SIGNATURE,
-            __padding_2: [0; 960],
+            device_size,
+            version: FORMAT_VERSION,
+            compat_flags: COMPAT_BACKUP_SUPERBLOCKS,
+            // Quotas cost a charge on every allocation, so they are kept only on request
+            incompat_flags: match journal_blocks {
+                0 => {
+                    INCOMPAT_SUPPORTED
+                        & !(INCOMPAT_JOURNAL
+                            | INCOMPAT_JOURNAL_HISTORY
+                            | INCOMPAT_QUOTA
+                            | INCOMPAT_SPANNED
+                            | INCOMPAT_COMPRESSION
+                            | INCOMPAT_REFLINK
+                            | INCOMPAT_SNAPSHOTS)
+                }
+                _ => {
+                    INCOMPAT_SUPPORTED
+                        & !(INCOMPAT_QUOTA
+                            | INCOMPAT_SPANNED
+                            | INCOMPAT_COMPRESSION
+                            | INCOMPAT_REFLINK
+                            | INCOMPAT_SNAPSHOTS)
+                }
+            },
+            default_options: 0,
+            label: [0; LABEL_SIZE],
+            uuid: [0; 16],
+            reserved_percent: 0,
+            state: STATE_CLEAN,
+            max_mount_count: 0,
+            mount_count: 0,
+            block_allocation_hint: 0,
+            member_sizes: [0; MAX_MEMBERS],
+            compression_algorithm: CompressionAlgorithm::None as u8,
+            share_table_block: NULL_BLOCK,
+            share_table_size: 0,
+            last_scrub: 0,
+            orphan_inode: 0,
+            inode_chunk_count: 0,
+            inode_chunks: [NULL_BLOCK; MAX_INODE_CHUNKS],
+            snapshot_inode: 0,
+            __padding_3: [0; 653],
         }
     }
 
+    /// Load primary superblock, falling back to a backup if it is damaged
     pub(crate) fn load<D: Read + Seek>(
         block_device: &mut D,
         block_size: u32,
     ) -> Result<Self, Error> {
-        let position = block_size as u64;
+        let superblock = Self::read_at(block_device, block_size as u64)?;
+        if superblock.is_valid() {
+            return Ok(superblock);
+        }
+        warn!("Primary superblock is damaged, looking for a backup");
+        Self::load_backup(block_device)?.ok_or(Error::Corruption)
+    }
+
+    /// Load first valid backup superblock, located by size of `block_device`
+    pub(crate) fn load_backup<D: Read + Seek + ?Sized>(
+        block_device: &mut D,
+    ) -> Result<Option<Self>, Error> {
+        let device_size = block_device.seek(SeekFrom::End(0))?;
+        let end = Self::backups_start(device_size, 0);
+        let superblock_size = std::mem::size_of::<Self>() as u64;
+        for backup in 1..=BACKUP_SUPERBLOCKS as u64 {
+            let Some(position) = end.checked_sub(backup * superblock_size) else {
+                break;
+            };
+            let superblock = Self::read_at(block_device, position)?;
+            if superblock.is_valid() && { superblock.device_size } == device_size {
+                info!("Using backup superblock {backup} at {position}");
+                return Ok(Some(superblock));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_at<D: Read + Seek + ?Sized>(
+        block_device: &mut D,
+        position: u64,
+    ) -> Result<Self, Error> {
         block_device.seek(SeekFrom::Start(position))?;
         let mut superblock_raw = [0u8; std::mem::size_of::<Self>() / std::mem::size_of::<u8>()];
         block_device.read_exact(&mut superblock_raw)?;
         Ok(unsafe { *(superblock_raw.as_ptr() as *const Self) })
     }
 
+    /// Write primary superblock and all its backups
     pub(crate) fn flush<D: Write + Seek>(&self, block_device: &mut D) -> Result<(), Error> {
-        let position = self.block_size as u64;
-        block_device.seek(SeekFrom::Start(position))?;
-        let superblock_raw = unsafe {
+        let mut superblock = *self;
+        superblock.checksum = self.compute_checksum();
+        let primary = self.block_size as u64;
+        for position in std::iter::once(primary).chain(self.backup_positions()) {
+            block_device.seek(SeekFrom::Start(position))?;
+            block_device.write_all(superblock.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
             core::slice::from_raw_parts(
                 self as *const Self as *const u8,
                 std::mem::size_of::<Self>(),
             )
+        }
+    }
+
+    /// CRC32C of superblock, independent of [ChecksumAlgorithm] it records
+    pub(crate) fn compute_checksum(&self) -> u32 {
+        let mut superblock = *self;
+        superblock.checksum = 0;
+        ChecksumAlgorithm::Crc32c
+            .checksummer()
+            .checksum(superblock.as_bytes())
+    }
+
+    /// Check magic signature and checksum, if superblock has one
+    pub(crate) fn is_valid(&self) -> bool {
+        self.magic == MAGIC_SIGNATURE
+            && (self.checksum == 0 || self.checksum == self.compute_checksum())
+    }
+
+    /// Check whether this implementation can mount the filesystem
+    ///
+    /// Fails if unknown incompatible features are set, and returns `false` if
+    /// the filesystem may only be read, as it was made by a newer version.
+    pub(crate) fn check_compatibility(&self) -> Result<bool, Error> {
+        let unknown = self.incompat_flags & !INCOMPAT_SUPPORTED;
+        if unknown != 0 {
+            error!("Unsupported incompatible features {unknown:#x}");
+            return Err(Error::Incompatible);
+        }
+        let unknown = self.compat_flags & !COMPAT_SUPPORTED;
+        if unknown != 0 {
+            info!("Ignoring unsupported compatible features {unknown:#x}");
+        }
+        if self.version > FORMAT_VERSION {
+            warn!(
+                "Filesystem format version {} is newer than {FORMAT_VERSION}, mounting read-only",
+                { self.version }
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Start of `backups` superblocks at the end of device
+    fn backups_start(device_size: u64, backups: u8) -> u64 {
+        let end = device_size / BACKUP_ALIGNMENT * BACKUP_ALIGNMENT;
+        end - backups as u64 * std::mem::size_of::<Self>() as u64
+    }
+
+    /// Positions of backup superblocks, counting from the end of device
+    pub(crate) fn backup_positions(&self) -> Vec<u64> {
+        if self.device_size == 0 {
+            return Vec::new();
+        }
+        let end = Self::backups_start(self.device_size, 0);
+        (1..=self.backup_superblocks as u64)
+            .map(|backup| end - backup * std::mem::size_of::<Self>() as u64)
+            .collect()
+    }
+
+    /// Checksum algorithm chosen when formatting
+    pub(crate) fn checksum_algorithm(&self) -> Result<ChecksumAlgorithm, Error> {
+        ChecksumAlgorithm::try_from(self.checksum_algorithm)
+    }
+
+    /// Compression of newly written blocks
+    pub(crate) fn compression(&self) -> Result<CompressionAlgorithm, Error> {
+        match self.incompat_flags & INCOMPAT_COMPRESSION {
+            0 => Ok(CompressionAlgorithm::None),
+            _ => CompressionAlgorithm::try_from(self.compression_algorithm),
+        }
+    }
+
+    /// Compress newly written blocks with `algorithm`, failing if the
+    /// filesystem has no checksums of blocks to tag compressed ones with
+    ///
+    /// Once enabled, the feature stays on even if compression is switched
+    /// off, as blocks compressed before remain on the filesystem.
+    pub(crate) fn set_compression(&mut self, algorithm: CompressionAlgorithm) -> Result<(), Error> {
+        if algorithm != CompressionAlgorithm::None {
+            if self.block_checksums == 0 {
+                return Err(Error::Incompatible);
+            }
+            self.incompat_flags |= INCOMPAT_COMPRESSION;
+        }
+        self.compression_algorithm = algorithm as u8;
+        Ok(())
+    }
+
+    /// Label as text, without trailing zeros
+    pub(crate) fn label(&self) -> String {
+        let length = self
+            .label
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..length]).into_owned()
+    }
+
+    /// Set label, failing if it does not fit
+    pub(crate) fn set_label(&mut self, label: &str) -> Result<(), Error> {
+        if label.len() > LABEL_SIZE || label.contains('\0') {
+            return Err(Error::InvalidArgument);
+        }
+        self.label = [0; LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
+
+    /// Identifier formatted as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`
+    pub fn uuid(&self) -> String {
+        let hex: String = self.uuid.iter().map(|byte| format!("{byte:02x}")).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+
+    /// Set identifier from its hexadecimal form, ignoring dashes
+    pub(crate) fn set_uuid(&mut self, uuid: &str) -> Result<(), Error> {
+        let hex: Vec<u8> = uuid.bytes().filter(|&c| c != b'-').collect();
+        if hex.len() != 32 {
+            return Err(Error::InvalidArgument);
+        }
+        for (byte, pair) in self.uuid.iter_mut().zip(hex.chunks(2)) {
+            let pair = std::str::from_utf8(pair)?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| Error::InvalidArgument)?;
+        }
+        Ok(())
+    }
+
+    /// Set percentage of blocks reserved for privileged users
+    pub(crate) fn set_reserved_percent(&mut self, percent: u64) -> Result<(), Error> {
+        if percent > MAX_RESERVED_PERCENT as u64 {
+            return Err(Error::InvalidArgument);
+        }
+        self.reserved_percent = percent as u8;
+        Ok(())
+    }
+
+    /// Names of enabled features
+    pub(crate) fn features(&self) -> Vec<&'static str> {
+        let backups = (self.compat_flags & COMPAT_BACKUP_SUPERBLOCKS != 0).then_some("backups");
+        let incompat = INCOMPAT_NAMES
+            .iter()
+            .filter(|(_, flag)| self.incompat_flags & flag != 0)
+            .map(|(name, _)| *name);
+        backups.into_iter().chain(incompat).collect()
+    }
+
+    /// Enable or disable feature of an unmounted filesystem on a device of
+    /// `device_size` bytes, failing with [Error::Incompatible] if data already
+    /// on the filesystem would have to be rewritten
+    pub(crate) fn set_feature(
+        &mut self,
+        name: &str,
+        enabled: bool,
+        device_size: u64,
+    ) -> Result<(), Error> {
+        if name == "backups" {
+            match enabled {
+                true if self.backup_superblocks != 0 => {}
+                true => {
+                    if !self.fits_backups(device_size) {
+                        return Err(Error::OutOfMemory);
+                    }
+                    self.device_size = device_size;
+                    self.backup_superblocks = BACKUP_SUPERBLOCKS;
+                    self.compat_flags |= COMPAT_BACKUP_SUPERBLOCKS;
+                }
+                false => {
+                    self.backup_superblocks = 0;
+                    self.compat_flags &= !COMPAT_BACKUP_SUPERBLOCKS;
+                }
+            }
+            return Ok(());
+        }
+        let (_, flag) = INCOMPAT_NAMES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .ok_or(Error::NotFound)?;
+        if (self.incompat_flags & flag != 0) == enabled {
+            return Ok(());
+        }
+        match *flag {
+            INCOMPAT_COMPRESSION if enabled => {
+                return self.set_compression(CompressionAlgorithm::Lz4);
+            }
+            INCOMPAT_QUOTA => {}
+            // Journal of an unmounted filesystem is empty, history only takes half of it
+            INCOMPAT_JOURNAL_HISTORY if !enabled || self.journal_blocks > 0 => {}
+            // Flat directories remain readable, indexed ones would not be
+            INCOMPAT_DIRECTORY_INDEX if enabled => {}
+            // Files share nothing until they are cloned
+            INCOMPAT_REFLINK if enabled => {}
+            // Chunks are only allocated once the inode table is full
+            INCOMPAT_INODE_CHUNKS if enabled || self.inode_chunk_count == 0 => {}
+            // Directory of snapshots is only created along with the first one
+            INCOMPAT_SNAPSHOTS if enabled || self.snapshot_inode == 0 => {}
+            _ => return Err(Error::Incompatible),
+        }
+        self.incompat_flags ^= *flag;
+        Ok(())
+    }
+
+    /// Whether backup superblocks fit between block region and end of device
+    pub(crate) fn fits_backups(&self, device_size: u64) -> bool {
+        device_size >= BACKUP_ALIGNMENT * 2
+            && Self::backups_start(device_size, BACKUP_SUPERBLOCKS) >= self.block_region_end()
+    }
+
+    /// Superblock with the most blocks fitting on a device of `device_size`
+    /// bytes, keeping every region before the block bitmap in place
+    pub(crate) fn grown(&self, device_size: u64) -> Result<Self, Error> {
+        let mut grown = self.resized(device_size);
+        if grown.block_count < self.block_count {
+            return Err(Error::InvalidArgument);
+        }
+        grown.blocks_free += grown.block_count - self.block_count;
+        Ok(grown)
+    }
+
+    /// Superblock of the same filesystem with fewer blocks, fitting in
+    /// `device_size` bytes, whose removed blocks must all be free
+    pub(crate) fn shrunk(&self, device_size: u64) -> Result<Self, Error> {
+        let mut shrunk = self.resized(device_size);
+        if shrunk.block_count >= self.block_count || shrunk.block_count == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        shrunk.blocks_free =
+            { self.blocks_free }.saturating_sub(self.block_count - shrunk.block_count);
+        Ok(shrunk)
+    }
+
+    /// Same filesystem with as many blocks as fit in `device_size` bytes
+    fn resized(&self, device_size: u64) -> Self {
+        let end = match self.backup_superblocks {
+            0 => device_size,
+            backups => Self::backups_start(device_size, backups),
         };
-        block_device.write_all(superblock_raw)?;
+        let mut resized = *self;
+        if resized.device_size != 0 {
+            resized.device_size = device_size;
+        }
+        // Bitmap and alignment are ignored, so the estimate only shrinks
+        let fixed = self.inode_region_start() + self.inode_size() * self.inode_count;
+        let per_block = self.block_size as u64
+            + match self.block_checksums {
+                0 => 0,
+                _ => BLOCK_CHECKSUM_SIZE,
+            };
+        resized.block_count = end.saturating_sub(fixed) / per_block;
+        while resized.block_count > 0 && resized.block_region_end() > end {
+            resized.block_count -= 1;
+        }
+        resized
+    }
+
+    /// Sizes of concatenated devices the filesystem spans, empty if it is
+    /// kept on a single device
+    pub(crate) fn members(&self) -> Vec<u64> {
+        match self.incompat_flags & INCOMPAT_SPANNED {
+            0 => Vec::new(),
+            _ => { self.member_sizes }
+                .iter()
+                .copied()
+                .take_while(|&size| size != 0)
+                .collect(),
+        }
+    }
+
+    /// Record sizes of concatenated devices of a new filesystem, failing if
+    /// there are too many of them
+    pub(crate) fn set_members(&mut self, sizes: &[u64]) -> Result<(), Error> {
+        if sizes.len() > MAX_MEMBERS || sizes.contains(&0) {
+            return Err(Error::InvalidArgument);
+        }
+        self.member_sizes = [0; MAX_MEMBERS];
+        self.incompat_flags &= !INCOMPAT_SPANNED;
+        // A single device is not recorded, so it may be moved to a larger one
+        if sizes.len() > 1 {
+            let mut member_sizes = [0; MAX_MEMBERS];
+            member_sizes[..sizes.len()].copy_from_slice(sizes);
+            self.member_sizes = member_sizes;
+            self.incompat_flags |= INCOMPAT_SPANNED;
+        }
         Ok(())
     }
 
-    pub(super) fn usable_capacity(capacity: u64, block_size: u32) -> u64 {
+    /// Whether filesystem was unmounted cleanly, or never mounted
+    pub(crate) fn is_clean(&self) -> bool {
+        self.state == STATE_CLEAN
+    }
+
+    /// Mount options applied to every mount
+    pub(crate) fn default_options(&self) -> MountOptions {
+        MountOptions::from_bits(self.default_options)
+    }
+
+    /// Bytes of every inode in inode region
+    pub(crate) fn inode_size(&self) -> u64 {
+        match self.incompat_flags & INCOMPAT_EXTENDED_INODES {
+            0 => CLASSIC_INODE_SIZE,
+            _ => std::mem::size_of::<Inode>() as u64,
+        }
+    }
+
+    /// Alignment of block region in bytes
+    pub(crate) fn data_alignment(&self) -> u32 {
+        self.data_alignment.max(self.block_size)
+    }
+
+    /// Size of journal in blocks for a new filesystem
+    fn journal_blocks_for(capacity: u64, block_size: u32) -> u32 {
+        ((capacity / JOURNAL_FRACTION).min(MAX_JOURNAL_SIZE) / block_size as u64) as u32
+    }
+
+    pub(super) fn usable_capacity(
+        capacity: u64,
+        block_size: u32,
+        alignment: u32,
+        journal_blocks: u32,
+        bytes_per_inode: u64,
+        block_checksums: bool,
+    ) -> u64 {
         debug_assert!(capacity > block_size as u64);
-        let block_size = block_size as u64;
-        let boot_sector = block_size;
+        let boot_sector = block_size as u64;
         let superblock = std::mem::size_of::<Self>() as u64;
+        let bitmaps_start = match journal_blocks {
+            0 => boot_sector + superblock,
+            _ => {
+                Self::align_to_block_start(boot_sector + superblock, block_size)
+                    + journal_blocks as u64 * block_size as u64
+            }
+        };
+        let block_size = block_size as u64;
         let inode = std::mem::size_of::<Inode>() as u64;
-        let after_superblock = capacity - boot_sector - superblock;
-        let max_inodes = after_superblock / DATA_PER_INODE;
+        let after_superblock = capacity - bitmaps_start;
+        let max_inodes = after_superblock / bytes_per_inode;
         let max_blocks = (after_superblock - max_inodes * inode) / block_size;
         let bitmaps =
             Bitmap::<Inode>::size_in_bytes(max_inodes) + Bitmap::<Block>::size_in_bytes(max_blocks);
-        let align = |byte| Self::align_to_block_start(byte, block_size as u32);
-        let before_blocks = align(boot_sector + superblock + bitmaps + max_inodes * inode);
+        let checksums = match block_checksums {
+            true => max_blocks * BLOCK_CHECKSUM_SIZE,
+            false => 0,
+        };
+        let inodes_end = bitmaps_start + bitmaps + max_inodes * inode;
+        let before_checksums = Self::align_to_block_start(inodes_end, block_size as u32);
+        let before_blocks = Self::align_to_block_start(before_checksums + checksums, alignment);
         debug_assert!(capacity > before_blocks);
         (capacity / block_size) * block_size - before_blocks
     }
 
     pub(super) fn align_to_block_start(position: u64, block_size: u32) -> u64 {
         let block_size = block_size as u64;
-        if position % block_size == 0 {
+        if position.is_multiple_of(block_size) {
             position
         } else {
             let padding = block_size - (position % block_size);
@@ -79,9 +531,21 @@ impl Superblock {
         Self::align_to_block_start(position, self.block_size)
     }
 
+    pub(crate) fn journal_region_start(&self) -> u64 {
+        let boot_sector = self.block_size as u64;
+        self.align(boot_sector + std::mem::size_of::<Self>() as u64)
+    }
+
+    pub(crate) fn journal_size(&self) -> u64 {
+        self.journal_blocks as u64 * self.block_size as u64
+    }
+
     pub(super) fn bitmap_region_start(&self) -> u64 {
         let boot_sector = self.block_size as u64;
-        boot_sector + std::mem::size_of::<Self>() as u64
+        match self.journal_blocks {
+            0 => boot_sector + std::mem::size_of::<Self>() as u64,
+            _ => self.journal_region_start() + self.journal_size(),
+        }
     }
 
     pub(super) fn inode_region_start(&self) -> u64 {
@@ -91,25 +555,128 @@ impl Superblock {
         Self::align_to_block_start(byte, self.block_size)
     }
 
-    pub(super) fn block_region_start(&self) -> u64 {
-        let byte =
-            self.inode_region_start() + std::mem::size_of::<Inode>() as u64 * self.inode_count;
-        Self::align_to_block_start(byte, self.block_size)
+    pub(super) fn checksum_region_start(&self) -> u64 {
+        let byte = self.inode_region_start() + self.inode_size() * self.inode_count;
+        self.align(byte)
+    }
+
+    pub(super) fn checksum_region_size(&self) -> u64 {
+        match self.block_checksums {
+            0 => 0,
+            _ => self.block_count * BLOCK_CHECKSUM_SIZE,
+        }
+    }
+
+    pub(crate) fn block_region_start(&self) -> u64 {
+        let byte = self.checksum_region_start() + self.checksum_region_size();
+        Self::align_to_block_start(byte, self.data_alignment())
     }
 
-    pub(super) fn block_region_end(&self) -> u64 {
+    pub(crate) fn block_region_end(&self) -> u64 {
         self.block_region_start() + self.block_size as u64 * self.block_count
     }
 
-    pub(super) fn inode_position(&self, index: u64) -> Result<u64, Error> {
-        let position = self.inode_region_start() + index * std::mem::size_of::<Inode>() as u64;
-        if position < self.block_region_start() {
+    /// Start and length of inode, checksum and block regions, which follow
+    /// the bitmaps and move with the size of block bitmap
+    pub(crate) fn relocatable_regions(&self) -> [(u64, u64); 3] {
+        [
+            (
+                self.inode_region_start(),
+                self.inode_size() * self.inode_count,
+            ),
+            (self.checksum_region_start(), self.checksum_region_size()),
+            (
+                self.block_region_start(),
+                self.block_size as u64 * self.block_count,
+            ),
+        ]
+    }
+
+    pub(crate) fn inode_position(&self, index: u64) -> Result<u64, Error> {
+        if let Some(offset) = index.checked_sub(self.first_chunk_inode()) {
+            let first = self.inode_chunk((offset / INODE_CHUNK_SIZE) as usize)?;
+            let within = offset % INODE_CHUNK_SIZE * self.inode_size();
+            return Ok(self.block_position(first + 1)? + within);
+        }
+        let position = self.inode_region_start() + index * self.inode_size();
+        if position < self.checksum_region_start() {
             Ok(position)
         } else {
             Err(Error::OutOfBounds)
         }
     }
 
+    /// Index of the first inode of chunks, past all indexes of the bitmap in
+    /// bitmap region
+    pub(crate) fn first_chunk_inode(&self) -> u64 {
+        Bitmap::<Inode>::size_in_bytes(self.inode_count) * BITS_IN_BYTE
+    }
+
+    /// Count of inodes in inode region and in chunks
+    pub(crate) fn total_inodes(&self) -> u64 {
+        self.inode_count + self.inode_chunk_count as u64 * INODE_CHUNK_SIZE
+    }
+
+    /// Indexes of inodes in inode region and in chunks
+    pub(crate) fn inode_indexes(&self) -> impl Iterator<Item = u64> {
+        let first = self.first_chunk_inode();
+        let chunked = self.inode_chunk_count as u64 * INODE_CHUNK_SIZE;
+        (0..self.inode_count).chain(first..first + chunked)
+    }
+
+    /// Blocks of every chunk of inodes, the first of which holds its bitmap
+    pub(crate) fn inode_chunk_blocks(&self) -> u64 {
+        1 + (INODE_CHUNK_SIZE * self.inode_size()).div_ceil(self.block_size as u64)
+    }
+
+    /// First block of chunk of inodes `chunk`
+    pub(crate) fn inode_chunk(&self, chunk: usize) -> Result<u64, Error> {
+        { self.inode_chunks }
+            .get(chunk)
+            .copied()
+            .filter(|_| chunk < self.inode_chunk_count as usize)
+            .ok_or(Error::OutOfBounds)
+    }
+
+    /// Position of bitmap of chunk of inodes `chunk`
+    pub(crate) fn inode_chunk_bitmap(&self, chunk: usize) -> Result<u64, Error> {
+        self.block_position(self.inode_chunk(chunk)?)
+    }
+
+    /// Check that all recorded chunks of inodes lie within the block region
+    pub(crate) fn check_inode_chunks(&self) -> Result<(), Error> {
+        let length = self.inode_chunk_blocks();
+        for chunk in 0..self.inode_chunk_count as usize {
+            let first = self.inode_chunk(chunk).map_err(|_| Error::Corruption)?;
+            if first.saturating_add(length) > self.block_count {
+                error!("Chunk {chunk} of inodes lies past the block region");
+                return Err(Error::Corruption);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether block `index` belongs to a chunk of inodes
+    pub(crate) fn in_inode_chunk(&self, index: u64) -> bool {
+        let length = self.inode_chunk_blocks();
+        (0..MAX_INODE_CHUNKS)
+            .map_while(|chunk| self.inode_chunk(chunk).ok())
+            .any(|first| (first..first + length).contains(&index))
+    }
+
+    /// Position of block's checksum, if filesystem has them
+    pub(super) fn block_checksum_position(&self, index: u64) -> Result<Option<u64>, Error> {
+        if self.block_checksums == 0 {
+            return Ok(None);
+        }
+        match index < self.block_count {
+            true => Ok(Some(
+                self.checksum_region_start() + index * BLOCK_CHECKSUM_SIZE,
+            )),
+            false => Err(Error::OutOfBounds),
+        }
+    }
+
     pub(super) fn block_position(&self, index: u64) -> Result<u64, Error> {
         let position = self.block_region_start() + index * self.block_size as u64;
         if position < self.block_region_end() {
@@ -128,6 +695,41 @@ impl Display for Superblock {
         writeln!(f, "    block_count: {},", { self.block_count })?;
         writeln!(f, "    blocks_free: {},", { self.blocks_free })?;
         writeln!(f, "    block_size: {},", { self.block_size })?;
+        match self.checksum_algorithm() {
+            Ok(algorithm) => writeln!(f, "    checksum_algorithm: {algorithm},")?,
+            Err(_) => writeln!(f, "    checksum_algorithm: {},", {
+                self.checksum_algorithm
+            })?,
+        }
+        writeln!(f, "    data_alignment: {},", self.data_alignment())?;
+        writeln!(f, "    journal_blocks: {},", { self.journal_blocks })?;
+        writeln!(f, "    block_checksums: {},", self.block_checksums != 0)?;
+        writeln!(f, "    checksum: {:#010x},", { self.checksum })?;
+        writeln!(f, "    backup_superblocks: {},", self.backup_superblocks)?;
+        writeln!(f, "    device_size: {},", { self.device_size })?;
+        writeln!(f, "    version: {},", { self.version })?;
+        writeln!(f, "    compat_flags: {:#x},", { self.compat_flags })?;
+        writeln!(f, "    incompat_flags: {:#x},", { self.incompat_flags })?;
+        writeln!(f, "    default_options: {},", self.default_options())?;
+        writeln!(f, "    label: {:?},", self.label())?;
+        writeln!(f, "    uuid: {},", self.uuid())?;
+        writeln!(f, "    reserved_percent: {},", self.reserved_percent)?;
+        writeln!(f, "    clean: {},", self.is_clean())?;
+        writeln!(f, "    max_mount_count: {},", { self.max_mount_count })?;
+        writeln!(f, "    mount_count: {},", { self.mount_count })?;
+        writeln!(f, "    block_allocation_hint: {},", {
+            self.block_allocation_hint
+        })?;
+        writeln!(f, "    members: {:?},", self.members())?;
+        match self.compression() {
+            Ok(algorithm) => writeln!(f, "    compression: {algorithm},")?,
+            Err(_) => writeln!(f, "    compression: {},", self.compression_algorithm)?,
+        }
+        writeln!(f, "    share_table_size: {},", { self.share_table_size })?;
+        writeln!(f, "    last_scrub: {},", { self.last_scrub })?;
+        writeln!(f, "    orphan_inode: {},", { self.orphan_inode })?;
+        writeln!(f, "    inode_chunk_count: {},", { self.inode_chunk_count })?;
+        writeln!(f, "    snapshot_inode: {},", { self.snapshot_inode })?;
         writeln!(f, "    magic: {}", { self.magic })?;
         write!(f, "}}")?;
         Ok(())
@@ -136,7 +738,10 @@ impl Display for Superblock {
 
 #[cfg(test)]
 mod tests {
-    use crate::structs::{Bitmap, Block, Inode};
+    use crate::{
+        structs::{Bitmap, Block, Inode, DATA_PER_INODE, FORMAT_VERSION},
+        Error,
+    };
 
     use super::Superblock;
 
@@ -150,15 +755,36 @@ mod tests {
         for block_exp in 9..=14 {
             let block_size = 1u64 << block_exp;
             assert_eq!(
-                Superblock::usable_capacity(1_000_000, block_size as u32) % block_size,
+                Superblock::usable_capacity(
+                    1_000_000,
+                    block_size as u32,
+                    block_size as u32,
+                    0,
+                    DATA_PER_INODE,
+                    false
+                ) % block_size,
                 0
             );
             assert_eq!(
-                Superblock::usable_capacity(10_000_000, block_size as u32) % block_size,
+                Superblock::usable_capacity(
+                    10_000_000,
+                    block_size as u32,
+                    block_size as u32,
+                    8,
+                    DATA_PER_INODE,
+                    true
+                ) % block_size,
                 0
             );
             assert_eq!(
-                Superblock::usable_capacity(1_000_000_000, block_size as u32) % block_size,
+                Superblock::usable_capacity(
+                    1_000_000_000,
+                    block_size as u32,
+                    block_size as u32,
+                    1024,
+                    DATA_PER_INODE,
+                    true
+                ) % block_size,
                 0
             );
         }
@@ -177,23 +803,87 @@ mod tests {
     fn regions() {
         for block_exp in 9..=14 {
             let block_size = 1u64 << block_exp;
-            let superblock = Superblock::new(100_000_000, block_size as u32);
+            let mut superblock = Superblock::new(100_000_000, block_size as u32);
+            let after_superblock = block_size + std::mem::size_of::<Superblock>() as u64;
+            assert_eq!(
+                superblock.journal_region_start(),
+                superblock.align(after_superblock)
+            );
+            assert!(superblock.journal_size() > 0);
             assert_eq!(
                 superblock.bitmap_region_start(),
-                block_size + std::mem::size_of::<Superblock>() as u64
+                superblock.journal_region_start() + superblock.journal_size()
             );
-            let inodes = block_size
-                + std::mem::size_of::<Superblock>() as u64
-                + (Bitmap::<Inode>::size_in_bytes(superblock.inode_count)
-                    + Bitmap::<Block>::size_in_bytes(superblock.block_count))
-                    as u64;
+            let journal_blocks = superblock.journal_blocks;
+            superblock.journal_blocks = 0;
+            assert_eq!(superblock.bitmap_region_start(), after_superblock);
+            superblock.journal_blocks = journal_blocks;
+            let inodes = superblock.bitmap_region_start()
+                + Bitmap::<Inode>::size_in_bytes(superblock.inode_count)
+                + Bitmap::<Block>::size_in_bytes(superblock.block_count);
             assert_eq!(superblock.inode_region_start(), superblock.align(inodes));
-            let blocks = inodes + superblock.inode_count * std::mem::size_of::<Inode>() as u64;
+            let checksums = inodes + superblock.inode_count * std::mem::size_of::<Inode>() as u64;
+            assert_eq!(
+                superblock.checksum_region_start(),
+                superblock.align(checksums)
+            );
+            let blocks = superblock.align(checksums) + superblock.block_count * 4;
             assert_eq!(superblock.block_region_start(), superblock.align(blocks));
             assert_eq!(
                 superblock.block_region_end(),
                 superblock.align(blocks) + superblock.block_count * block_size
             );
+            assert!(superblock.block_region_end() <= 100_000_000);
+        }
+    }
+
+    #[test]
+    fn aligned_block_region() {
+        let alignment = 1 << 20;
+        for block_exp in 9..=12 {
+            let block_size = 1u32 << block_exp;
+            let superblock = Superblock::new_aligned(100_000_000, block_size, alignment);
+            assert_eq!(superblock.block_region_start() % alignment as u64, 0);
+            assert!(superblock.block_region_end() <= 100_000_000);
+            assert_eq!(superblock.inode_region_start() % block_size as u64, 0);
+        }
+        let mut legacy = Superblock::new(100_000_000, 512);
+        legacy.data_alignment = 0;
+        assert_eq!(legacy.data_alignment(), 512);
+        assert_eq!(legacy.block_region_start() % 512, 0);
+    }
+
+    #[test]
+    fn inode_ratio() {
+        let default = Superblock::new(100_000_000, 1024);
+        for bytes_per_inode in [1024, DATA_PER_INODE, 1 << 20] {
+            let superblock = Superblock::with_inode_ratio(100_000_000, 1024, 1024, bytes_per_inode);
+            assert!(superblock.block_region_end() <= 100_000_000);
+            let (inodes, blocks) = (superblock.inode_count, superblock.block_count);
+            assert_eq!(inodes, blocks * 1024 / bytes_per_inode);
+            match bytes_per_inode {
+                DATA_PER_INODE => assert_eq!(inodes, { default.inode_count }),
+                1024 => assert!(blocks < default.block_count),
+                _ => assert!(blocks > default.block_count),
+            }
         }
     }
+
+    #[test]
+    fn compatibility() {
+        let mut superblock = Superblock::new(100_000_000, 512);
+        assert!(superblock.check_compatibility().unwrap());
+        superblock.compat_flags |= 1 << 31;
+        assert!(superblock.check_compatibility().unwrap());
+        superblock.version = FORMAT_VERSION + 1;
+        assert!(!superblock.check_compatibility().unwrap());
+        superblock.incompat_flags |= 1 << 31;
+        assert!(matches!(
+            superblock.check_compatibility(),
+            Err(Error::Incompatible)
+        ));
+        let mut legacy = Superblock::new(100_000_000, 512);
+        (legacy.version, legacy.compat_flags, legacy.incompat_flags) = (0, 0, 0);
+        assert!(legacy.check_compatibility().unwrap());
+    }
 }
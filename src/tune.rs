@@ -0,0 +1,275 @@
+//! Changing parameters of an unmounted filesystem
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::devices::fence::{self, Access};
+use crate::filesystem::Filesystem;
+use crate::structs::{Superblock, INCOMPAT_JOURNAL_HISTORY, INCOMPAT_QUOTA};
+use crate::Error;
+
+/// Apply `changes` to parameters of filesystem on `device_path`
+///
+/// Every change is either `name=value` or a default mount option prefixed
+/// with `+` to enable or `-` to disable it. Without changes, current
+/// parameters are only printed.
+pub fn tune(device_path: &str, changes: &[String]) -> Result<(), Error> {
+    let mut device = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(device_path)?;
+    if fence::acquire(&device)? != Access::ReadWrite {
+        return Err(Error::Busy);
+    }
+    let device_size = device.seek(SeekFrom::End(0))?;
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let mut fs = Filesystem::load(Box::new(device), block_size)?;
+    if fs.read_only {
+        return Err(Error::ReadOnly);
+    }
+    let backups = fs.superblock.backup_positions();
+    let features = fs.superblock.incompat_flags;
+    let mut superblock = fs.superblock;
+    for change in changes {
+        apply(&mut superblock, change, device_size)?;
+    }
+    if !changes.is_empty() {
+        info!("Changing parameters of {device_path}");
+        let quotas = superblock.incompat_flags & INCOMPAT_QUOTA != 0;
+        superblock.incompat_flags &= !INCOMPAT_QUOTA;
+        superblock.incompat_flags |= fs.superblock.incompat_flags & INCOMPAT_QUOTA;
+        fs.superblock = superblock;
+        fs.options = superblock.default_options();
+        // Usage of users and groups is counted by walking the directory tree
+        let shared = Arc::new(Mutex::new(fs));
+        Filesystem::set_quotas(&shared, quotas)?;
+        fs = Arc::into_inner(shared)
+            .ok_or(Error::ThreadSync)?
+            .into_inner()?;
+        superblock = fs.superblock;
+        fs.force_flush()?;
+        // Records kept before history was disabled miss every later transaction
+        if (features ^ superblock.incompat_flags) & INCOMPAT_JOURNAL_HISTORY != 0 {
+            fs.forget_history()?;
+        }
+        // Stale backups would be picked up if primary superblock got damaged
+        for position in backups {
+            if !superblock.backup_positions().contains(&position) {
+                fs.device.seek(SeekFrom::Start(position))?;
+                fs.device
+                    .write_all(&[0; std::mem::size_of::<Superblock>()])?;
+            }
+        }
+    }
+    print(&superblock);
+    if let Ok((oldest, last)) = fs.journal_history() {
+        println!("Journal transactions: {oldest}-{last}");
+    }
+    Ok(())
+}
+
+/// Print parameters and usage of filesystem on `device_path`
+///
+/// Only the superblock is read, so the filesystem may be mounted.
+pub fn info(device_path: &str) -> Result<(), Error> {
+    let mut device = std::fs::File::open(device_path)?;
+    let block_size = Filesystem::detect_existing(&mut device)?.ok_or(Error::NotFound)?;
+    let superblock = Superblock::load(&mut device, block_size)?;
+    println!("Block size: {block_size}");
+    println!(
+        "Blocks: {} used of {}",
+        superblock.block_count - superblock.blocks_free,
+        { superblock.block_count }
+    );
+    println!(
+        "Inodes: {} used of {}",
+        superblock.total_inodes() - superblock.inodes_free,
+        superblock.total_inodes()
+    );
+    print(&superblock);
+    Ok(())
+}
+
+/// Apply a single change to `superblock` of filesystem on device of `device_size` bytes
+pub fn apply(superblock: &mut Superblock, change: &str, device_size: u64) -> Result<(), Error> {
+    let Some((name, value)) = change.split_once('=') else {
+        let mut options = superblock.default_options();
+        options.apply(change)?;
+        superblock.default_options = options.bits();
+        return Ok(());
+    };
+    let number = || value.parse::<u16>().map_err(|_| Error::InvalidArgument);
+    match name {
+        "label" => superblock.set_label(value)?,
+        "uuid" if value == "random" => superblock.uuid = random_uuid()?,
+        "uuid" => superblock.set_uuid(value)?,
+        "reserved" => superblock.set_reserved_percent(number()? as u64)?,
+        "max_mounts" => superblock.max_mount_count = number()?,
+        "mounts" => superblock.mount_count = number()?,
+        "compression" => superblock.set_compression(value.parse()?)?,
+        "feature" => match (value.strip_prefix('+'), value.strip_prefix('-')) {
+            (Some(feature), _) => superblock.set_feature(feature, true, device_size)?,
+            (_, Some(feature)) => superblock.set_feature(feature, false, device_size)?,
+            _ => return Err(Error::InvalidArgument),
+        },
+        _ => return Err(Error::NotFound),
+    }
+    Ok(())
+}
+
+/// Random version 4 identifier
+pub fn random_uuid() -> Result<[u8; 16], Error> {
+    let mut uuid = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0F) | 0x40;
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    Ok(uuid)
+}
+
+fn print(superblock: &Superblock) {
+    println!("Label: {}", superblock.label());
+    println!("UUID: {}", superblock.uuid());
+    println!("Reserved blocks: {}%", superblock.reserved_percent);
+    println!("Mount count: {}/{}", { superblock.mount_count }, {
+        superblock.max_mount_count
+    });
+    println!(
+        "State: {}",
+        match superblock.is_clean() {
+            true => "clean",
+            false => "mounted or not unmounted cleanly",
+        }
+    );
+    println!("Backup superblocks: {}", superblock.backup_superblocks);
+    println!(
+        "Quotas: {}",
+        match superblock.incompat_flags & INCOMPAT_QUOTA {
+            0 => "disabled",
+            _ => "enabled",
+        }
+    );
+    if let Ok(algorithm) = superblock.compression() {
+        println!("Compression: {algorithm}");
+    }
+    match superblock.last_scrub {
+        0 => println!("Last scrub: never"),
+        seconds => println!("Last scrub: {seconds} (seconds since epoch)"),
+    }
+    println!("Features: {}", superblock.features().join(" "));
+    println!("Default mount options: {}", superblock.default_options());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{apply, tune};
+    use crate::{
+        filesystem::{Filesystem, QuotaKind},
+        filetypes::Owner,
+        structs::{CompressionAlgorithm, MountOptions, Superblock},
+        Error,
+    };
+
+    #[test]
+    fn parameters() {
+        let mut superblock = Superblock::new(1_000_000, 1024);
+        apply(&mut superblock, "label=backup", 1_000_000).unwrap();
+        assert_eq!(superblock.label(), "backup");
+        assert!(matches!(
+            apply(&mut superblock, "label=seventeen-bytes!!", 1_000_000),
+            Err(Error::InvalidArgument)
+        ));
+        apply(
+            &mut superblock,
+            "uuid=0123abcd-4567-89ef-0123-456789abcdef",
+            1_000_000,
+        )
+        .unwrap();
+        assert_eq!(superblock.uuid(), "0123abcd-4567-89ef-0123-456789abcdef");
+        apply(&mut superblock, "uuid=random", 1_000_000).unwrap();
+        assert_eq!(superblock.uuid[6] >> 4, 4);
+        apply(&mut superblock, "reserved=5", 1_000_000).unwrap();
+        assert_eq!(superblock.reserved_percent, 5);
+        assert!(apply(&mut superblock, "reserved=90", 1_000_000).is_err());
+        apply(&mut superblock, "max_mounts=20", 1_000_000).unwrap();
+        assert_eq!({ superblock.max_mount_count }, 20);
+        apply(&mut superblock, "feature=-backups", 1_000_000).unwrap();
+        assert!(superblock.backup_positions().is_empty());
+        apply(&mut superblock, "feature=+backups", 1_000_000).unwrap();
+        assert_eq!(superblock.backup_positions().len(), 2);
+        assert!(matches!(
+            apply(&mut superblock, "feature=+bigalloc", 1_000_000),
+            Err(Error::NotFound)
+        ));
+        apply(&mut superblock, "feature=-journal_history", 1_000_000).unwrap();
+        assert!(!superblock.features().contains(&"journal_history"));
+        apply(&mut superblock, "feature=+journal_history", 1_000_000).unwrap();
+        apply(&mut superblock, "feature=+dir_index", 1_000_000).unwrap();
+        apply(&mut superblock, "compression=lz4", 1_000_000).unwrap();
+        assert_eq!(superblock.compression().unwrap(), CompressionAlgorithm::Lz4);
+        assert!(apply(&mut superblock, "compression=zstd", 1_000_000).is_err());
+        for unsafe_change in [
+            "feature=-compression",
+            "feature=-dir_index",
+            "feature=-wide_names",
+            "feature=-journal",
+        ] {
+            assert!(matches!(
+                apply(&mut superblock, unsafe_change, 1_000_000),
+                Err(Error::Incompatible)
+            ));
+        }
+        assert!(matches!(
+            apply(&mut superblock, "feature=quota", 1_000_000),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            apply(&mut superblock, "color=blue", 1_000_000),
+            Err(Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn default_options() {
+        let path = std::env::temp_dir().join(format!("tananfs-tune-{}", std::process::id()));
+        let device = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        device.set_len(1_000_000).unwrap();
+        let fs =
+            Filesystem::new(Box::new(device), 1_000_000, 1024).with_options(MountOptions::CASEFOLD);
+        let fs = Arc::new(Mutex::new(fs));
+        Filesystem::format(&fs, Owner::default()).unwrap();
+        drop(fs);
+
+        let path_str = path.to_str().unwrap();
+        let changes = [
+            "+noatime".to_owned(),
+            "-casefold".to_owned(),
+            "feature=-backups".to_owned(),
+            "feature=+quota".to_owned(),
+        ];
+        tune(path_str, &changes).unwrap();
+        assert!(matches!(
+            tune(path_str, &["+sync".to_owned()]),
+            Err(Error::NotFound)
+        ));
+        let mut device = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        assert!(Superblock::load_backup(&mut device).unwrap().is_none());
+        let fs = Filesystem::load(Box::new(device), 1024).unwrap();
+        assert_eq!(fs.options, MountOptions::NOATIME);
+        assert_eq!(fs.quotas.get(QuotaKind::User, 0).inodes, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}